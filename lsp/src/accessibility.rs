@@ -0,0 +1,184 @@
+//! A text alternative for rendered diagrams: the root `<svg>` becomes an
+//! image labelled by a `<title>` (the diagram's title) and a `<desc>`
+//! summarizing what it shows, so screen readers have something to read
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use crate::anchors::diagram_title;
+use crate::diagnostics::diagram_keyword;
+use crate::split::flowchart_graph;
+use crate::stable_hash;
+use crate::watermark::escape;
+
+static SVG_OPEN_TAG: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?s)<svg\b[^>]*>").expect("svg open tag regex"));
+
+/// Attributes of the root element replaced by the ones added here
+static REPLACED_ATTRIBUTES: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"\s(?:role|aria-labelledby)\s*=\s*(?:"[^"]*"|'[^']*')"#).expect("root attribute regex")
+});
+
+/// Marks a root element that already has its text alternative
+const MARKER: &str = "data-mermaid-a11y";
+
+/// Nodes named in a summary; the rest are only counted
+const MAX_LISTED_NODES: usize = 10;
+
+/// `svg` labelled with a `<title>` and `<desc>` for the diagram of `code`.
+/// Ids already in the SVG are left as they are, and an SVG that has been
+/// through here before is returned unchanged.
+pub fn describe(svg: &str, code: &str) -> String {
+    let Some(open) = SVG_OPEN_TAG.find(svg) else {
+        return svg.to_string();
+    };
+    if open.as_str().contains(MARKER) {
+        return svg.to_string();
+    }
+
+    let prefix = unused_id(svg, &format!("mermaid-a11y-{:016x}", stable_hash::hash(code)));
+    let title = diagram_title(code).unwrap_or_else(|| type_name(diagram_keyword(code)));
+
+    let tag = open.as_str();
+    let close = if tag.ends_with("/>") { tag.len() - 2 } else { tag.len() - 1 };
+    let attributes = REPLACED_ATTRIBUTES.replace_all(&tag[..close], "");
+    let mut described = String::with_capacity(svg.len() + 512);
+    described.push_str(&svg[..open.start()]);
+    described.push_str(&format!(
+        r#"{attributes} role="img" aria-labelledby="{prefix}-title {prefix}-desc" {MARKER}="true">"#
+    ));
+    described.push_str(&format!(
+        r#"<title id="{prefix}-title">{}</title><desc id="{prefix}-desc">{}</desc>"#,
+        escape(&title),
+        escape(&summary(code))
+    ));
+    if tag.ends_with("/>") {
+        described.push_str("</svg>");
+    }
+    described.push_str(&svg[open.end()..]);
+    described
+}
+
+/// `base`, or `base` with a number after it, such that no id in `svg` starts with it
+fn unused_id(svg: &str, base: &str) -> String {
+    let taken = |prefix: &str| svg.contains(&format!("\"{prefix}-"));
+    (1..)
+        .map(|n| if n == 1 { base.to_string() } else { format!("{base}-{n}") })
+        .find(|prefix| !taken(prefix))
+        .expect("an unused id")
+}
+
+/// What the diagram shows: its nodes and edges for flowcharts, how many
+/// statements it has otherwise
+pub fn summary(code: &str) -> String {
+    let keyword = diagram_keyword(code);
+    let name = type_name(keyword);
+    if matches!(keyword, "graph" | "flowchart") {
+        let (nodes, edges) = flowchart_graph(code);
+        let mut summary = format!("{name} with {} and {}", count(nodes.len(), "node"), count(edges, "edge"));
+        if !nodes.is_empty() {
+            let listed: Vec<&str> = nodes.iter().take(MAX_LISTED_NODES).map(String::as_str).collect();
+            summary.push_str(&format!("; nodes: {}", listed.join(", ")));
+            if nodes.len() > MAX_LISTED_NODES {
+                summary.push_str(&format!(" and {} more", nodes.len() - MAX_LISTED_NODES));
+            }
+        }
+        return summary;
+    }
+    let statements = code
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| line.split_whitespace().next() != Some(keyword))
+        .skip(1)
+        .filter(|line| !line.is_empty() && !line.starts_with("%%"))
+        .count();
+    format!("{name} with {}", count(statements, "statement"))
+}
+
+/// Name of the diagram type that `keyword` starts
+fn type_name(keyword: &str) -> String {
+    let name = match keyword {
+        "graph" | "flowchart" => "Flowchart",
+        "sequenceDiagram" => "Sequence diagram",
+        "classDiagram" => "Class diagram",
+        "stateDiagram" | "stateDiagram-v2" => "State diagram",
+        "erDiagram" => "Entity relationship diagram",
+        "journey" => "User journey",
+        "gantt" => "Gantt chart",
+        "pie" => "Pie chart",
+        "gitGraph" => "Git graph",
+        "mindmap" => "Mind map",
+        "timeline" => "Timeline",
+        "unknown" => "Mermaid diagram",
+        other => return format!("{other} diagram"),
+    };
+    name.to_string()
+}
+
+fn count(n: usize, noun: &str) -> String {
+    match n {
+        1 => format!("1 {noun}"),
+        n => format!("{n} {noun}s"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SVG: &str = r#"<svg id="my-svg" width="100%" xmlns="http://www.w3.org/2000/svg" role="graphics-document document" aria-roledescription="flowchart-v2"><g/></svg>"#;
+
+    #[test]
+    fn labels_the_root_with_a_title_and_description() {
+        let code = "---\ntitle: Login <flow>\n---\nflowchart TD\n  A[Start] --> B{Valid?}\n  B -->|yes| C & D\n  %% a comment";
+        let described = describe(SVG, code);
+        let doc = roxmltree::Document::parse(&described).expect("well-formed SVG");
+        let root = doc.root_element();
+        assert_eq!(root.attribute("id"), Some("my-svg"));
+        assert_eq!(root.attribute("role"), Some("img"));
+        assert_eq!(root.attribute("aria-roledescription"), Some("flowchart-v2"));
+
+        let labelled_by: Vec<&str> = root.attribute("aria-labelledby").unwrap().split(' ').collect();
+        let text_of = |id: &str| {
+            let element = doc.descendants().find(|n| n.attribute("id") == Some(id)).unwrap();
+            (element.tag_name().name().to_string(), element.text().unwrap().to_string())
+        };
+        assert_eq!(text_of(labelled_by[0]), ("title".to_string(), "Login <flow>".to_string()));
+        assert_eq!(
+            text_of(labelled_by[1]),
+            ("desc".to_string(), "Flowchart with 4 nodes and 3 edges; nodes: Start, Valid?, C, D".to_string())
+        );
+        // The title comes first among the root's children
+        assert_eq!(root.first_element_child().unwrap().tag_name().name(), "title");
+    }
+
+    #[test]
+    fn describing_again_changes_nothing() {
+        let described = describe(SVG, "graph TD\n  A-->B");
+        assert_eq!(describe(&described, "graph TD\n  A-->B"), described);
+        assert_eq!(described.matches("<title").count(), 1);
+        assert_eq!(describe("<g/>", "graph TD"), "<g/>");
+    }
+
+    #[test]
+    fn ids_already_in_the_svg_are_avoided() {
+        let plain = describe(SVG, "graph TD");
+        let prefix = plain.split("<title id=\"").nth(1).unwrap().split("-title").next().unwrap();
+        let crowded = SVG.replace("<g/>", &format!("<g id=\"{prefix}-title\"/>"));
+        let described = describe(&crowded, "graph TD");
+        assert!(described.contains(&format!("<g id=\"{prefix}-title\"/>")), "{described}");
+        assert!(described.contains(&format!("<title id=\"{prefix}-2-title\">")), "{described}");
+    }
+
+    #[test]
+    fn summarizes_by_type_and_truncates_long_node_lists() {
+        let many: String = (0..12).map(|n| format!("\n  N{n} --> N{}", n + 1)).collect();
+        assert_eq!(
+            summary(&format!("graph LR{many}")),
+            "Flowchart with 13 nodes and 12 edges; nodes: N0, N1, N2, N3, N4, N5, N6, N7, N8, N9 and 3 more"
+        );
+        assert_eq!(
+            summary("sequenceDiagram\n  %% greeting\n  A->>B: hi\n\n  B-->>A: hello"),
+            "Sequence diagram with 2 statements"
+        );
+        assert_eq!(summary("xychart-beta\n  bar [1, 2]"), "xychart-beta diagram with 1 statement");
+    }
+}
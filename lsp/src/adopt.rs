@@ -0,0 +1,187 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::image_target;
+
+/// Comment formats other tools leave next to a rendered diagram, each naming
+/// the diagram's source file in its `source` group
+static BUILTIN_PATTERNS: Lazy<Vec<(&'static str, Regex)>> = Lazy::new(|| {
+    [
+        // <!-- mermaid source: diagrams/flow.mmd -->
+        ("mermaid-source-comment", r"^<!--\s*mermaid[ -]source:\s*(?P<source>\S+?)\s*-->$"),
+        // [mermaid]: # (diagrams/flow.mmd)  or  [mermaid]: # "diagrams/flow.mmd"
+        ("link-reference", r#"^\[mermaid\]:\s*#\s*[("](?P<source>[^)"]+)[)"]$"#),
+        // <!-- mermaid src="diagrams/flow.mmd" -->
+        ("mermaid-src-attribute", r#"^<!--\s*mermaid\s+src=["']?(?P<source>[^"'\s]+)["']?\s*-->$"#),
+    ]
+    .into_iter()
+    .map(|(name, pattern)| (name, Regex::new(pattern).expect("builtin adopt pattern")))
+    .collect()
+});
+
+/// Name of the format whose source sits inline, in a commented-out fence
+pub const INLINE_FORMAT: &str = "commented-fence";
+
+/// Where a foreign block keeps its diagram source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ForeignSource {
+    /// Path of a source file, as written in the document
+    File(String),
+    /// The mermaid code itself
+    Inline(String),
+}
+
+/// A diagram rendered by another tool
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForeignBlock {
+    /// Name of the matching format
+    pub format: String,
+    pub start_line: usize,
+    /// Last line of the block: the image if there is one
+    pub end_line: usize,
+    pub source: ForeignSource,
+    /// Link target of the image that follows the source
+    pub image: Option<String>,
+}
+
+/// The built-in patterns plus `custom` ones (regexes with a `source` group,
+/// named `custom-1`, `custom-2`, ...). Invalid custom patterns are reported.
+pub fn patterns(custom: &[String]) -> Result<Vec<(String, Regex)>, String> {
+    let mut patterns: Vec<(String, Regex)> = BUILTIN_PATTERNS
+        .iter()
+        .map(|(name, regex)| (name.to_string(), regex.clone()))
+        .collect();
+    for (i, pattern) in custom.iter().enumerate() {
+        let regex = Regex::new(pattern).map_err(|e| format!("invalid adoptPatterns entry `{pattern}`: {e}"))?;
+        if !regex.capture_names().any(|name| name == Some("source")) {
+            return Err(format!("adoptPatterns entry `{pattern}` has no `(?P<source>...)` group"));
+        }
+        patterns.push((format!("custom-{}", i + 1), regex));
+    }
+    Ok(patterns)
+}
+
+/// Find every block rendered by another tool: a source comment (or a
+/// commented-out mermaid fence) and the image after it, if any
+pub fn find_foreign_blocks(lines: &[&str], patterns: &[(String, Regex)]) -> Vec<ForeignBlock> {
+    let mut blocks = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let trimmed = lines[i].trim();
+        let found = patterns
+            .iter()
+            .find_map(|(name, regex)| {
+                let source = regex.captures(trimmed)?.name("source")?.as_str().to_string();
+                Some((name.clone(), ForeignSource::File(source), i))
+            })
+            .or_else(|| commented_fence(lines, i).map(|(code, end)| (INLINE_FORMAT.to_string(), ForeignSource::Inline(code), end)));
+        let Some((format, source, source_end)) = found else {
+            i += 1;
+            continue;
+        };
+
+        let mut end_line = source_end;
+        let mut image = None;
+        if let Some(j) = (source_end + 1..lines.len()).find(|&j| !lines[j].trim().is_empty()) {
+            if let Some(target) = image_target(lines[j].trim()) {
+                end_line = j;
+                image = Some(target.to_string());
+            }
+        }
+        blocks.push(ForeignBlock {
+            format,
+            start_line: i,
+            end_line,
+            source,
+            image,
+        });
+        i = end_line + 1;
+    }
+    blocks
+}
+
+/// A mermaid fence inside an HTML comment starting at line `start`: its code
+/// and the line closing the comment
+fn commented_fence(lines: &[&str], start: usize) -> Option<(String, usize)> {
+    let opening = lines[start].trim();
+    let rest = opening.strip_prefix("<!--")?.trim();
+    let fence_line = if rest.is_empty() { start + 1 } else { start };
+    let fence = if fence_line == start { rest } else { lines.get(fence_line)?.trim() };
+    if fence != "```mermaid" {
+        return None;
+    }
+    let close = (fence_line + 1..lines.len()).find(|&j| lines[j].trim() == "```")?;
+    let comment_end = lines.get(close + 1).map(|l| l.trim());
+    let end = match comment_end {
+        Some("-->") => close + 1,
+        _ => return None,
+    };
+    Some((lines[fence_line + 1..close].join("\n"), end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(doc: &str) -> Vec<ForeignBlock> {
+        let lines: Vec<&str> = doc.lines().collect();
+        find_foreign_blocks(&lines, &patterns(&[]).unwrap())
+    }
+
+    #[test]
+    fn recognizes_mermaid_source_comments() {
+        let blocks = find("# T\n<!-- mermaid source: diagrams/flow.mmd -->\n\n![flow](img/flow.svg)\nafter\n");
+        assert_eq!(
+            blocks,
+            vec![ForeignBlock {
+                format: "mermaid-source-comment".to_string(),
+                start_line: 1,
+                end_line: 3,
+                source: ForeignSource::File("diagrams/flow.mmd".to_string()),
+                image: Some("img/flow.svg".to_string()),
+            }]
+        );
+        assert_eq!(find("<!-- mermaid-source: a.mmd -->")[0].source, ForeignSource::File("a.mmd".to_string()));
+    }
+
+    #[test]
+    fn recognizes_link_references() {
+        for doc in ["[mermaid]: # (diagrams/seq.mmd)\n![](seq.png)", "[mermaid]: # \"diagrams/seq.mmd\"\n![](seq.png)"] {
+            let blocks = find(doc);
+            assert_eq!(blocks[0].format, "link-reference");
+            assert_eq!(blocks[0].source, ForeignSource::File("diagrams/seq.mmd".to_string()));
+            assert_eq!(blocks[0].image.as_deref(), Some("seq.png"));
+        }
+    }
+
+    #[test]
+    fn recognizes_src_attributes() {
+        let blocks = find("<!-- mermaid src=\"docs/er.mmd\" -->\n![ER](docs/er.svg)");
+        assert_eq!(blocks[0].format, "mermaid-src-attribute");
+        assert_eq!(blocks[0].source, ForeignSource::File("docs/er.mmd".to_string()));
+    }
+
+    #[test]
+    fn recognizes_commented_out_fences() {
+        let blocks = find("<!--\n```mermaid\ngraph TD\n  A-->B\n```\n-->\n![diagram](out/diagram.svg)\n");
+        assert_eq!(blocks[0].format, INLINE_FORMAT);
+        assert_eq!(blocks[0].source, ForeignSource::Inline("graph TD\n  A-->B".to_string()));
+        assert_eq!((blocks[0].start_line, blocks[0].end_line), (0, 6));
+
+        // Without an image the source alone is the block
+        let bare = find("<!-- ```mermaid\ngraph LR\n```\n-->\n\ntext");
+        assert_eq!((bare[0].start_line, bare[0].end_line, bare[0].image.clone()), (0, 3, None));
+        assert!(find("<!--\nnot a diagram\n-->").is_empty());
+    }
+
+    #[test]
+    fn custom_patterns_need_a_source_group() {
+        let custom = vec![r"^<!-- diagram: (?P<source>\S+) -->$".to_string()];
+        let lines = ["<!-- diagram: a.mmd -->", "![](a.svg)"];
+        let blocks = find_foreign_blocks(&lines, &patterns(&custom).unwrap());
+        assert_eq!(blocks[0].format, "custom-1");
+
+        assert!(patterns(&["<!-- (\\S+) -->".to_string()]).unwrap_err().contains("source"));
+        assert!(patterns(&["(".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::HashSet;
+
+/// Prefix of every anchor id, so links read `#diagram-auth-flow`
+const ANCHOR_PREFIX: &str = "diagram-";
+
+/// An anchor line emitted above a rendered block
+static ANCHOR_LINE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"^\s*<a id="(diagram-[^"]*)"></a>\s*$"#).expect("anchor line regex")
+});
+
+/// `title: ...` in a `---` frontmatter block, or a `title`/`accTitle` statement
+/// (also `pie title ...`)
+static TITLE_LINE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^\s*(?:pie\s+(?:showData\s+)?)?(?:title\s*:|title\s|accTitle\s*:)\s*(.+?)\s*$")
+        .expect("title regex")
+});
+
+/// The HTML anchor placed above a rendered diagram
+pub fn anchor_line(id: &str) -> String {
+    format!("<a id=\"{id}\"></a>")
+}
+
+/// The anchor id on `line`, if it is an anchor line
+pub fn parse_anchor_line(line: &str) -> Option<&str> {
+    ANCHOR_LINE
+        .captures(line)
+        .and_then(|caps| caps.get(1))
+        .map(|id| id.as_str())
+}
+
+/// Title of a diagram: frontmatter `title:`, or a `title`/`accTitle:` line
+pub fn diagram_title(code: &str) -> Option<String> {
+    code.lines()
+        .filter(|line| !line.trim_start().starts_with("%%"))
+        .find_map(|line| TITLE_LINE.captures(line))
+        .map(|caps| caps[1].trim_matches(|c| c == '"' || c == '\'').to_string())
+        .filter(|title| !title.is_empty())
+}
+
+/// Lowercase, with runs of anything but letters and digits turned into `-`
+pub fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+/// Anchor ids for diagrams in document order, avoiding ids in `taken`.
+///
+/// Untitled diagrams are named after their type (`diagram-flowchart`);
+/// repeats get `-2`, `-3`, ...
+pub fn assign_anchors<'a>(
+    codes: impl IntoIterator<Item = &'a str>,
+    mut taken: HashSet<String>,
+) -> Vec<String> {
+    codes
+        .into_iter()
+        .map(|code| {
+            let name = diagram_title(code)
+                .or_else(|| {
+                    code.lines()
+                        .map(str::trim)
+                        .find(|l| !l.is_empty() && !l.starts_with("%%") && !l.starts_with("---"))
+                        .and_then(|l| l.split_whitespace().next())
+                        .map(str::to_string)
+                })
+                .unwrap_or_default();
+            let slug = slugify(&name);
+            let base = if slug.is_empty() {
+                ANCHOR_PREFIX.trim_end_matches('-').to_string()
+            } else {
+                format!("{ANCHOR_PREFIX}{slug}")
+            };
+
+            let mut id = base.clone();
+            let mut n = 2;
+            while taken.contains(&id) {
+                id = format!("{base}-{n}");
+                n += 1;
+            }
+            taken.insert(id.clone());
+            id
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anchor_lines_round_trip() {
+        let line = anchor_line("diagram-auth-flow");
+        assert_eq!(line, r#"<a id="diagram-auth-flow"></a>"#);
+        assert_eq!(parse_anchor_line(&line), Some("diagram-auth-flow"));
+        assert_eq!(parse_anchor_line(r#"<a id="intro"></a>"#), None);
+    }
+
+    #[test]
+    fn finds_titles() {
+        assert_eq!(
+            diagram_title("---\ntitle: Auth Flow\n---\nflowchart TD\n  A --> B").as_deref(),
+            Some("Auth Flow")
+        );
+        assert_eq!(diagram_title("gantt\n  title Release plan").as_deref(), Some("Release plan"));
+        assert_eq!(diagram_title("pie title \"Pets\"\n  \"Dogs\" : 3").as_deref(), Some("Pets"));
+        assert_eq!(
+            diagram_title("sequenceDiagram\n  accTitle: Login sequence").as_deref(),
+            Some("Login sequence")
+        );
+        assert_eq!(diagram_title("graph TD\n  A --> B"), None);
+    }
+
+    #[test]
+    fn slugs_are_url_friendly() {
+        assert_eq!(slugify("Auth Flow (v2)!"), "auth-flow-v2");
+        assert_eq!(slugify("  --Über  Ablauf--"), "über-ablauf");
+        assert_eq!(slugify("???"), "");
+    }
+
+    #[test]
+    fn collisions_are_numbered() {
+        let taken = HashSet::from(["diagram-auth-flow".to_string()]);
+        let codes = [
+            "---\ntitle: Auth flow\n---\ngraph TD",
+            "---\ntitle: Auth flow\n---\ngraph TD",
+            "graph TD\n  A --> B",
+            "flowchart LR\n  A --> B",
+            "graph TD\n  C --> D",
+        ];
+        assert_eq!(
+            assign_anchors(codes, taken),
+            vec![
+                "diagram-auth-flow-2",
+                "diagram-auth-flow-3",
+                "diagram-graph",
+                "diagram-flowchart",
+                "diagram-graph-2",
+            ]
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use std::{fmt, str::FromStr};
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::error::{ServerError, ServerResult};
+
+/// `{...}` attribute block in a fence's info string
+static ATTRIBUTES: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\{([^}]*)\}").expect("fence attribute regex"));
+
+/// Renderer a diagram is sent to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// mermaid-cli (or npx for a pinned version)
+    Mmdc,
+    /// In-process renderer for simple diagrams, not included in this build
+    Native,
+}
+
+impl Backend {
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Mmdc => "mmdc",
+            Backend::Native => "native",
+        }
+    }
+
+    /// Whether this build can render with the backend
+    pub fn is_available(self) -> bool {
+        matches!(self, Backend::Mmdc)
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "mmdc" => Ok(Backend::Mmdc),
+            "native" => Ok(Backend::Native),
+            other => Err(format!("unknown render backend `{other}` (expected mmdc or native)")),
+        }
+    }
+}
+
+/// What to do with a fence whose requested backend can't be used
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendFallback {
+    /// Render with mmdc and warn
+    #[default]
+    Mmdc,
+    /// Fail the render
+    Error,
+}
+
+/// Backend requested by a fence's info string (the text after ```` ```mermaid ````),
+/// e.g. `{backend=native}`. None when the fence doesn't ask for one.
+pub fn requested(info: &str) -> Result<Option<Backend>, String> {
+    let Some(attributes) = attributes(info) else {
+        return Ok(None);
+    };
+    attributes
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter_map(|attr| attr.split_once('='))
+        .find(|(key, _)| key.trim() == "backend")
+        .map(|(_, value)| value.trim().trim_matches(|c| c == '"' || c == '\'').parse())
+        .transpose()
+}
+
+/// Text inside the `{...}` attribute list of a fence's info string
+pub fn attributes(info: &str) -> Option<&str> {
+    ATTRIBUTES.captures(info).and_then(|c| c.get(1)).map(|m| m.as_str())
+}
+
+/// Why the backend a fence requests can't be used, if it can't
+fn problem(info: &str) -> Option<String> {
+    match requested(info) {
+        Ok(Some(backend)) if !backend.is_available() => {
+            Some(format!("the {backend} render backend is not available in this build"))
+        }
+        Ok(_) => None,
+        Err(e) => Some(e),
+    }
+}
+
+/// Backend a fence renders with: the requested one, else mmdc. An unusable
+/// request falls back to mmdc unless `unavailableBackend` is `error`.
+pub fn select(info: &str, config: &Config) -> ServerResult<Backend> {
+    if let Some(problem) = problem(info) {
+        return match config.unavailable_backend {
+            BackendFallback::Mmdc => Ok(Backend::Mmdc),
+            BackendFallback::Error => Err(ServerError::ValidationFailed(problem)),
+        };
+    }
+    Ok(requested(info).ok().flatten().unwrap_or(Backend::Mmdc))
+}
+
+/// The fence's backend problem as an error to attach to it: a warning when
+/// rendering falls back to mmdc, an error when it fails instead
+pub fn check(info: &str, config: &Config) -> Option<ServerError> {
+    let problem = problem(info)?;
+    Some(match config.unavailable_backend {
+        BackendFallback::Mmdc => ServerError::ToolNotFound(format!("{problem}; rendering with mmdc")),
+        BackendFallback::Error => ServerError::ValidationFailed(problem),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_backend_attribute() {
+        assert_eq!(requested(""), Ok(None));
+        assert_eq!(requested(" {backend=native}"), Ok(Some(Backend::Native)));
+        assert_eq!(requested("{ theme=dark, backend=\"MMDC\" }"), Ok(Some(Backend::Mmdc)));
+        assert_eq!(requested("{theme=dark}"), Ok(None));
+        assert!(requested("{backend=canvas}").unwrap_err().contains("canvas"));
+    }
+
+    #[test]
+    fn unavailable_backends_fall_back_or_fail() {
+        let config = Config::default();
+        assert_eq!(select("{backend=mmdc}", &config).unwrap(), Backend::Mmdc);
+        assert_eq!(select("{backend=native}", &config).unwrap(), Backend::Mmdc);
+        assert!(check("{backend=mmdc}", &config).is_none());
+        assert!(matches!(check("{backend=native}", &config), Some(ServerError::ToolNotFound(_))));
+
+        let strict = Config {
+            unavailable_backend: BackendFallback::Error,
+            ..Config::default()
+        };
+        assert!(matches!(select("{backend=native}", &strict), Err(ServerError::ValidationFailed(_))));
+        assert!(matches!(check("{backend=nope}", &strict), Some(ServerError::ValidationFailed(_))));
+    }
+}
@@ -0,0 +1,683 @@
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
+    ffi::OsString,
+    fmt, fs,
+    io,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crate::paths;
+use crate::stable_hash::{self, StableHasher};
+
+const INDEX_FILE: &str = "index.json";
+/// Advisory lock shared by every server writing to the same cache directory
+const LOCK_FILE: &str = ".lock";
+/// Number of render locks keys are spread over (`.render-<n>.lock`), so the
+/// lock files stay few however many diagrams are cached
+const RENDER_LOCK_STRIPES: u64 = 64;
+const INDEX_VERSION: u32 = 1;
+const ENTRY_PREFIX: &str = "mermaid_";
+const ENTRY_SUFFIX: &str = ".svg";
+/// Directory under the user's cache directory holding each workspace's cache
+const USER_CACHE_DIR: &str = "mermaid-lsp";
+
+/// Keys of every cache opened this session, by directory, kept in step with
+/// the index so [`is_cached`] needn't touch the disk
+static KNOWN_KEYS: Lazy<Mutex<HashMap<PathBuf, HashSet<String>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn known_keys() -> std::sync::MutexGuard<'static, HashMap<PathBuf, HashSet<String>>> {
+    KNOWN_KEYS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Numbers temp files within this process
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A temp file next to `path`, named after this process and a counter so that
+/// concurrent writers (two servers on one project, or two threads) never share one
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}.{}.tmp", process::id(), TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+    path.with_file_name(name)
+}
+
+/// Take the cache directory's lock, waiting for other writers; released when
+/// the returned file is dropped
+fn lock(dir: &Path) -> io::Result<fs::File> {
+    lock_file(&dir.join(LOCK_FILE))
+}
+
+/// Take the advisory lock on `path`, creating it if needed and waiting while
+/// another process or thread holds it
+fn lock_file(path: &Path) -> io::Result<fs::File> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(fs::TryLockError::WouldBlock) => {
+            debug!("{} is held by another server, waiting", path.display());
+            file.lock()?;
+        }
+        Err(fs::TryLockError::Error(e)) => return Err(e),
+    }
+    Ok(file)
+}
+
+/// Whether `key` is in the cache in `dir`, from memory. The index is read only
+/// the first time a directory is seen; a missing directory is an empty cache.
+pub fn is_cached(dir: &Path, key: &str) -> bool {
+    if let Some(keys) = known_keys().get(&paths::simplify(dir)) {
+        return keys.contains(key);
+    }
+    if !dir.is_dir() {
+        return false;
+    }
+    DiagramCache::open(dir).is_ok_and(|cache| cache.index.entries.contains_key(key))
+}
+
+/// The platform's per-user cache directory, from the environment read by
+/// `var`: `%LOCALAPPDATA%` on Windows, `~/Library/Caches` on macOS, and
+/// `$XDG_CACHE_HOME` (or `~/.cache`) elsewhere
+fn user_cache_home(var: impl Fn(&str) -> Option<OsString>) -> Option<PathBuf> {
+    let path = |name| var(name).map(PathBuf::from).filter(|path| path.is_absolute());
+    if cfg!(windows) {
+        path("LOCALAPPDATA")
+    } else if cfg!(target_os = "macos") {
+        path("HOME").map(|home| home.join("Library/Caches"))
+    } else {
+        path("XDG_CACHE_HOME").or_else(|| path("HOME").map(|home| home.join(".cache")))
+    }
+}
+
+/// Cache directory of the workspace at `root` under `home`, named after a
+/// hash of the root's path so every workspace gets its own
+fn workspace_cache_dir(home: &Path, root: &Path) -> PathBuf {
+    let root = paths::simplify(root);
+    home.join(USER_CACHE_DIR).join(format!("{:016x}", stable_hash::hash(root.as_os_str().as_encoded_bytes())))
+}
+
+/// Cache directory of the workspace at `root` in the user's cache directory;
+/// None when the platform has none. The root is resolved first, so a
+/// workspace opened through a symlink shares its target's cache.
+pub fn user_cache_dir(root: &Path) -> Option<PathBuf> {
+    user_cache_home(|name| env::var_os(name)).map(|home| workspace_cache_dir(&home, &paths::resolve(root)))
+}
+
+/// Total size in bytes of the files in `dir`, 0 when it doesn't exist
+pub fn dir_size(dir: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .filter_map(|e| e.metadata().ok())
+        .filter(fs::Metadata::is_file)
+        .map(|meta| meta.len())
+        .sum()
+}
+
+/// Metadata recorded for each cached SVG
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct IndexEntry {
+    size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheIndex {
+    version: u32,
+    entries: BTreeMap<String, IndexEntry>,
+}
+
+impl Default for CacheIndex {
+    fn default() -> Self {
+        Self {
+            version: INDEX_VERSION,
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+/// Stand-in for the mermaid-cli version when `mmdc --version` gives no answer
+pub const UNKNOWN_MMDC_VERSION: &str = "unknown";
+
+/// Cache key of a rendered diagram: its source plus everything that changes the
+/// output for the same source (this crate's and mermaid-cli's versions)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentHash(u64);
+
+impl ContentHash {
+    pub fn new(code: &str, mmdc_version: &str) -> Self {
+        Self(StableHasher::new().write(code).write(env!("CARGO_PKG_VERSION")).write(mmdc_version).finish())
+    }
+}
+
+impl fmt::Display for ContentHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Result of checking every entry in the cache
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub removed: Vec<String>,
+}
+
+/// Content-addressed store of rendered SVGs (`.mermaid/.cache`). Several
+/// servers may share one directory: entries and the index are written through
+/// temp files under the directory's lock, and each write merges the index on disk.
+pub struct DiagramCache {
+    dir: PathBuf,
+    index: CacheIndex,
+}
+
+impl DiagramCache {
+    /// Open the cache in `dir`, rebuilding the index if it is missing or corrupt
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let index_path = dir.join(INDEX_FILE);
+
+        let index = match Self::read_index(dir) {
+            Some(Ok(index)) => index,
+            Some(Err(())) => {
+                let _lock = lock(dir)?;
+                // Another server may have repaired it while we waited
+                match Self::read_index(dir) {
+                    Some(Ok(index)) => index,
+                    _ => {
+                        warn!("Cache index {} is corrupt, rebuilding it", index_path.display());
+                        let index = Self::rebuild_index(dir);
+                        Self::write_index(dir, &index);
+                        index
+                    }
+                }
+            }
+            None => Self::rebuild_index(dir),
+        };
+
+        // One entry per directory however it is spelled (e.g. with or without
+        // the Windows `\\?\` prefix)
+        let dir = paths::simplify(dir);
+        known_keys().insert(dir.clone(), index.entries.keys().cloned().collect());
+        Ok(Self {
+            dir,
+            index,
+        })
+    }
+
+    /// Path of the cache file for `key`
+    pub fn get_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{ENTRY_PREFIX}{key}{ENTRY_SUFFIX}"))
+    }
+
+    /// Read a cached SVG. Entries failing the integrity check are deleted and
+    /// reported as a miss.
+    pub fn get(&mut self, key: &str) -> Option<String> {
+        let path = self.get_path(key);
+        let svg = fs::read_to_string(&path).ok()?;
+        if self.check_entry(key, &svg).is_ok() {
+            return Some(svg);
+        }
+
+        // Another server may have replaced or removed the entry since our index
+        // was read: look again under the lock before discarding anything
+        let _lock = self.lock_or_warn();
+        self.reload_index();
+        let svg = fs::read_to_string(&path).ok();
+        match svg.as_deref().map(|svg| self.check_entry(key, svg)) {
+            Some(Ok(())) => svg,
+            Some(Err(reason)) => {
+                warn!("Discarding corrupt cache entry {}: {reason}", path.display());
+                self.remove_locked(key);
+                None
+            }
+            None => {
+                self.remove_locked(key);
+                None
+            }
+        }
+    }
+
+    /// Lock `key` for rendering until the returned file is dropped. A server
+    /// that misses the cache takes it, then looks again before rendering: if
+    /// another server rendered the same diagram meanwhile, it reads that result
+    /// instead of running the renderer a second time.
+    pub fn lock_key(&self, key: &str) -> io::Result<fs::File> {
+        let stripe = stable_hash::hash(key) % RENDER_LOCK_STRIPES;
+        lock_file(&self.dir.join(format!(".render-{stripe}.lock")))
+    }
+
+    /// Store an SVG under `key`
+    pub fn put(&mut self, key: &str, svg: &str) -> io::Result<()> {
+        let path = self.get_path(key);
+        let tmp = temp_path(&path);
+        fs::write(&tmp, svg)?;
+
+        let _lock = lock(&self.dir)?;
+        if let Err(e) = fs::rename(&tmp, &path) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+        self.reload_index();
+        self.index.entries.insert(
+            key.to_string(),
+            IndexEntry {
+                size: svg.len() as u64,
+            },
+        );
+        Self::write_index(&self.dir, &self.index);
+        if let Some(keys) = known_keys().get_mut(&self.dir) {
+            keys.insert(key.to_string());
+        }
+        Ok(())
+    }
+
+    /// Check every entry on disk and in the index, removing the corrupt ones
+    pub fn verify(&mut self) -> VerifyReport {
+        let mut keys: Vec<String> = self.index.entries.keys().cloned().collect();
+        for key in Self::keys_on_disk(&self.dir) {
+            if !self.index.entries.contains_key(&key) {
+                keys.push(key);
+            }
+        }
+
+        let _lock = self.lock_or_warn();
+        self.reload_index();
+        let mut report = VerifyReport::default();
+        for key in keys {
+            report.checked += 1;
+            let valid = fs::read_to_string(self.get_path(&key))
+                .map_err(|e| e.to_string())
+                .and_then(|svg| self.check_entry(&key, &svg));
+            if valid.is_err() {
+                self.remove_locked(&key);
+                report.removed.push(key);
+            }
+        }
+        report
+    }
+
+    fn check_entry(&self, key: &str, svg: &str) -> Result<(), String> {
+        if svg.trim().is_empty() {
+            return Err("empty file".to_string());
+        }
+        if !looks_like_svg(svg) {
+            return Err("not an SVG document".to_string());
+        }
+        if let Some(entry) = self.index.entries.get(key) {
+            if entry.size != svg.len() as u64 {
+                return Err(format!("size {} does not match recorded {}", svg.len(), entry.size));
+            }
+        }
+        Ok(())
+    }
+
+    /// Take the directory's lock for a cleanup that can also run without it
+    fn lock_or_warn(&self) -> Option<fs::File> {
+        lock(&self.dir)
+            .inspect_err(|e| warn!("Failed to lock cache {}: {e}", self.dir.display()))
+            .ok()
+    }
+
+    /// Replace the in-memory index with the one on disk, which other servers
+    /// may have added to. Call with the lock held.
+    fn reload_index(&mut self) {
+        if let Some(Ok(index)) = Self::read_index(&self.dir) {
+            known_keys().insert(self.dir.clone(), index.entries.keys().cloned().collect());
+            self.index = index;
+        }
+    }
+
+    /// Delete an entry and its index record. Call with the lock held.
+    fn remove_locked(&mut self, key: &str) {
+        let _ = fs::remove_file(self.get_path(key));
+        if let Some(keys) = known_keys().get_mut(&self.dir) {
+            keys.remove(key);
+        }
+        if self.index.entries.remove(key).is_some() {
+            Self::write_index(&self.dir, &self.index);
+        }
+    }
+
+    fn keys_on_disk(dir: &Path) -> Vec<String> {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return Vec::new();
+        };
+        entries
+            .flatten()
+            .filter_map(|e| {
+                let name = e.file_name().to_string_lossy().to_string();
+                name.strip_prefix(ENTRY_PREFIX)?
+                    .strip_suffix(ENTRY_SUFFIX)
+                    .map(str::to_string)
+            })
+            .collect()
+    }
+
+    /// The index on disk: None when there is none, `Err` when it is corrupt
+    fn read_index(dir: &Path) -> Option<Result<CacheIndex, ()>> {
+        let text = fs::read_to_string(dir.join(INDEX_FILE)).ok()?;
+        Some(match serde_json::from_str::<CacheIndex>(&text) {
+            Ok(index) if index.version == INDEX_VERSION => Ok(index),
+            _ => Err(()),
+        })
+    }
+
+    /// Recreate the index from the files present in the directory
+    fn rebuild_index(dir: &Path) -> CacheIndex {
+        let mut index = CacheIndex::default();
+        for key in Self::keys_on_disk(dir) {
+            let path = dir.join(format!("{ENTRY_PREFIX}{key}{ENTRY_SUFFIX}"));
+            if let Ok(meta) = fs::metadata(&path) {
+                index.entries.insert(key, IndexEntry { size: meta.len() });
+            }
+        }
+        index
+    }
+
+    fn write_index(dir: &Path, index: &CacheIndex) {
+        let path = dir.join(INDEX_FILE);
+        match serde_json::to_string_pretty(index) {
+            Ok(json) => {
+                // Readers never see a half-written index
+                let tmp = temp_path(&path);
+                if let Err(e) = fs::write(&tmp, json).and_then(|()| fs::rename(&tmp, &path)) {
+                    let _ = fs::remove_file(&tmp);
+                    warn!("Failed to write cache index {}: {e}", path.display());
+                }
+            }
+            Err(e) => warn!("Failed to serialize cache index: {e}"),
+        }
+    }
+}
+
+/// Whether the text starts like an SVG document (optional BOM, XML prolog, comments)
+fn looks_like_svg(text: &str) -> bool {
+    let mut rest = text.trim_start_matches('\u{feff}').trim_start();
+    loop {
+        if rest.starts_with("<svg") {
+            return true;
+        }
+        let end = if rest.starts_with("<?xml") {
+            rest.find("?>").map(|i| i + 2)
+        } else if rest.starts_with("<!--") {
+            rest.find("-->").map(|i| i + 3)
+        } else if rest.starts_with("<!DOCTYPE") {
+            rest.find('>').map(|i| i + 1)
+        } else {
+            None
+        };
+        match end {
+            Some(end) => rest = rest[end..].trim_start(),
+            None => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SVG: &str = r#"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg"></svg>"#;
+
+    #[test]
+    fn content_hash_depends_on_mmdc_version() {
+        let code = "graph TD\n  A --> B";
+        assert_eq!(ContentHash::new(code, "10.9.1"), ContentHash::new(code, "10.9.1"));
+        assert_ne!(ContentHash::new(code, "10.9.1"), ContentHash::new(code, "11.0.0"));
+        assert_ne!(
+            ContentHash::new(code, "10.9.1"),
+            ContentHash::new(code, UNKNOWN_MMDC_VERSION)
+        );
+        assert_ne!(ContentHash::new(code, "10.9.1"), ContentHash::new("graph LR", "10.9.1"));
+    }
+
+    #[test]
+    fn is_cached_follows_puts_and_removals_in_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_dir = dir.path().join(".cache");
+        assert!(!is_cached(&cache_dir, "42"));
+        assert!(!cache_dir.exists());
+
+        let mut cache = DiagramCache::open(&cache_dir).unwrap();
+        cache.put("42", SVG).unwrap();
+        assert!(is_cached(&cache_dir, "42"));
+
+        // A corrupt entry is dropped from memory as well as from disk
+        fs::write(cache.get_path("42"), "garbage").unwrap();
+        assert!(cache.get("42").is_none());
+        assert!(!is_cached(&cache_dir, "42"));
+    }
+
+    #[test]
+    fn user_cache_dir_follows_the_platform() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| vars.iter().find(|(var, _)| *var == name).map(|(_, value)| OsString::from(value))
+        };
+        if cfg!(windows) {
+            let home = user_cache_home(env(&[("LOCALAPPDATA", r"C:\Users\me\AppData\Local")]));
+            assert_eq!(home, Some(PathBuf::from(r"C:\Users\me\AppData\Local")));
+        } else if cfg!(target_os = "macos") {
+            assert_eq!(user_cache_home(env(&[("HOME", "/Users/me")])), Some(PathBuf::from("/Users/me/Library/Caches")));
+        } else {
+            let xdg = user_cache_home(env(&[("XDG_CACHE_HOME", "/var/cache/me"), ("HOME", "/home/me")]));
+            assert_eq!(xdg, Some(PathBuf::from("/var/cache/me")));
+            // A relative XDG_CACHE_HOME is ignored, as the spec says
+            let relative = user_cache_home(env(&[("XDG_CACHE_HOME", "cache"), ("HOME", "/home/me")]));
+            assert_eq!(relative, Some(PathBuf::from("/home/me/.cache")));
+        }
+        assert_eq!(user_cache_home(env(&[])), None);
+
+        let home = Path::new("/cache");
+        let one = workspace_cache_dir(home, Path::new("/work/one"));
+        assert!(one.starts_with("/cache/mermaid-lsp"), "{}", one.display());
+        assert_eq!(one, workspace_cache_dir(home, Path::new("/work/one")));
+        assert_ne!(one, workspace_cache_dir(home, Path::new("/work/two")));
+    }
+
+    #[test]
+    fn dir_size_sums_the_files() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(dir_size(&dir.path().join("missing")), 0);
+        let mut cache = DiagramCache::open(dir.path()).unwrap();
+        cache.put("42", SVG).unwrap();
+        let index = fs::metadata(dir.path().join(INDEX_FILE)).unwrap().len();
+        assert_eq!(dir_size(dir.path()), SVG.len() as u64 + index);
+    }
+
+    #[test]
+    fn round_trips_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = DiagramCache::open(dir.path()).unwrap();
+        cache.put("42", SVG).unwrap();
+
+        let mut reopened = DiagramCache::open(dir.path()).unwrap();
+        assert_eq!(reopened.get("42").as_deref(), Some(SVG));
+        assert!(reopened.get("7").is_none());
+    }
+
+    #[test]
+    fn zero_byte_entry_is_a_miss_and_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = DiagramCache::open(dir.path()).unwrap();
+        fs::write(cache.get_path("1"), "").unwrap();
+
+        assert!(cache.get("1").is_none());
+        assert!(!cache.get_path("1").exists());
+    }
+
+    #[test]
+    fn size_mismatch_and_non_svg_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = DiagramCache::open(dir.path()).unwrap();
+        cache.put("1", SVG).unwrap();
+        fs::write(cache.get_path("1"), &SVG[..SVG.len() - 6]).unwrap();
+        assert!(cache.get("1").is_none());
+
+        fs::write(cache.get_path("2"), "PK\u{3}\u{4} not an svg").unwrap();
+        assert!(cache.get("2").is_none());
+    }
+
+    #[test]
+    fn rebuilds_corrupt_index_from_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("mermaid_5.svg"), SVG).unwrap();
+        fs::write(dir.path().join(INDEX_FILE), "{ not json").unwrap();
+
+        let mut cache = DiagramCache::open(dir.path()).unwrap();
+        assert_eq!(cache.get("5").as_deref(), Some(SVG));
+
+        let index: CacheIndex =
+            serde_json::from_str(&fs::read_to_string(dir.path().join(INDEX_FILE)).unwrap())
+                .unwrap();
+        assert_eq!(index.entries["5"].size, SVG.len() as u64);
+    }
+
+    #[test]
+    fn verify_removes_corrupt_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = DiagramCache::open(dir.path()).unwrap();
+        cache.put("good", SVG).unwrap();
+        cache.put("truncated", SVG).unwrap();
+        fs::write(cache.get_path("truncated"), "<svg").unwrap();
+        fs::write(cache.get_path("empty"), "").unwrap();
+
+        let report = cache.verify();
+        assert_eq!(report.checked, 3);
+        let mut removed = report.removed.clone();
+        removed.sort();
+        assert_eq!(removed, vec!["empty", "truncated"]);
+        assert!(cache.get_path("good").exists());
+        assert!(!cache.get_path("empty").exists());
+    }
+
+    #[test]
+    fn concurrent_caches_keep_a_consistent_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let svg_for = |key: &str| format!(r#"<svg xmlns="http://www.w3.org/2000/svg"><text>{key}</text></svg>"#);
+
+        let writers: Vec<_> = (0..2)
+            .map(|writer| {
+                let dir = dir.path().to_path_buf();
+                std::thread::spawn(move || {
+                    let mut cache = DiagramCache::open(&dir).unwrap();
+                    for i in 0..50 {
+                        // Each writer has its own keys and shares every third one
+                        let key = if i % 3 == 0 { format!("shared{i}") } else { format!("w{writer}_{i}") };
+                        cache.put(&key, &svg_for(&key)).unwrap();
+                        assert_eq!(cache.get(&key), Some(svg_for(&key)), "{key}");
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let mut cache = DiagramCache::open(dir.path()).unwrap();
+        assert_eq!(cache.index.entries.len(), 17 + 2 * 33);
+        assert!(cache.verify().removed.is_empty());
+        for key in cache.index.entries.keys().cloned().collect::<Vec<_>>() {
+            assert_eq!(cache.get(&key), Some(svg_for(&key)));
+        }
+        let leftovers: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .flatten()
+            .filter(|e| e.file_name().to_string_lossy().ends_with(".tmp"))
+            .collect();
+        assert!(leftovers.is_empty(), "{leftovers:?}");
+    }
+
+    #[test]
+    fn concurrent_renders_of_one_key_render_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let renders = std::sync::Arc::new(AtomicU64::new(0));
+
+        let servers: Vec<_> = (0..4)
+            .map(|_| {
+                let dir = dir.path().to_path_buf();
+                let renders = renders.clone();
+                std::thread::spawn(move || {
+                    let mut cache = DiagramCache::open(&dir).unwrap();
+                    if let Some(svg) = cache.get("42") {
+                        return svg;
+                    }
+                    let _rendering = cache.lock_key("42").unwrap();
+                    if let Some(svg) = cache.get("42") {
+                        return svg;
+                    }
+                    renders.fetch_add(1, Ordering::SeqCst);
+                    std::thread::sleep(std::time::Duration::from_millis(50));
+                    cache.put("42", SVG).unwrap();
+                    SVG.to_string()
+                })
+            })
+            .collect();
+        for server in servers {
+            assert_eq!(server.join().unwrap(), SVG);
+        }
+        assert_eq!(renders.load(Ordering::SeqCst), 1);
+    }
+
+    /// Directory the child process of `processes_share_one_cache` writes to
+    const CHILD_CACHE_ENV: &str = "MERMAID_CACHE_TEST_CHILD_DIR";
+
+    fn write_and_read_shared_keys(dir: &Path, writer: &str) {
+        let mut cache = DiagramCache::open(dir).unwrap();
+        for i in 0..100 {
+            // Both processes write every key; a reader sees either nothing or a whole SVG
+            let key = format!("k{}", i % 10);
+            let svg = format!(r#"<svg xmlns="http://www.w3.org/2000/svg"><text>{key}</text></svg>"#);
+            cache.put(&key, &svg).unwrap();
+            assert_eq!(cache.get(&key), Some(svg), "{writer} {key}");
+        }
+    }
+
+    /// The other process of `processes_share_one_cache`; does nothing when run
+    /// on its own
+    #[test]
+    fn child_cache_writer() {
+        if let Some(dir) = std::env::var_os(CHILD_CACHE_ENV) {
+            write_and_read_shared_keys(Path::new(&dir), "child");
+        }
+    }
+
+    #[test]
+    fn processes_share_one_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut child = process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "cache::tests::child_cache_writer", "--quiet"])
+            .env(CHILD_CACHE_ENV, dir.path())
+            .stdout(process::Stdio::null())
+            .spawn()
+            .unwrap();
+        write_and_read_shared_keys(dir.path(), "parent");
+        assert!(child.wait().unwrap().success());
+
+        let mut cache = DiagramCache::open(dir.path()).unwrap();
+        assert_eq!(cache.index.entries.len(), 10);
+        assert!(cache.verify().removed.is_empty());
+    }
+
+    #[test]
+    fn detects_svg_documents() {
+        assert!(looks_like_svg("\u{feff}  <svg></svg>"));
+        assert!(looks_like_svg("<?xml version=\"1.0\"?>\n<!-- c --><!DOCTYPE svg><svg/>"));
+        assert!(!looks_like_svg("<html></html>"));
+        assert!(!looks_like_svg("<?xml version=\"1.0\"?>"));
+    }
+}
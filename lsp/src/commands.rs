@@ -0,0 +1,777 @@
+use log::info;
+use lsp_server::{Connection, Message, Request};
+use lsp_types::{Diagnostic, ExecuteCommandParams, MessageType, WorkspaceEdit};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+};
+use url::Url;
+
+use crate::config::Config;
+use crate::document_source::{self, DocumentSource, Origin};
+use crate::error::{ServerError, ServerResult};
+use crate::memo::CheckedDocument;
+use crate::outcome::{self, CommandOutcome, Status};
+use crate::outgoing::OutgoingRequests;
+use crate::position::PositionEncoding;
+use crate::publisher::Publisher;
+use crate::status::FenceState;
+use crate::{diagnostics, live, render};
+use crate::{
+    adopt_rendered_blocks, apply_edit, begin_progress, cache_summary, check_links, checked_document, code_hash,
+    compare_to_reference, copy_as_markdown, create_edit_all_sources, create_format_all_edit, create_inline_svg_edit,
+    create_render_all_edit, create_render_edit, create_render_fences_edit, create_source_edit, doc_base_dir,
+    document_config, document_diagnostics, end_progress, failed_fences, fence_edits, fence_for_argument,
+    find_all_mermaid_fences, find_all_rendered_blocks, generate_index, is_missing_tool, normalized_type,
+    offer_mmdc_install, poll_cancelled, preview_fences, publish_diagnostics, publish_fence_status, read_only_reason,
+    record_render, remove_files, render_comparison, render_files, render_steps, report_progress, requested_types,
+    rerender_blocks, rerender_summary, revert_last_render, set_option, show_message, single_render_fence,
+    source_candidates, stats, verify_cache, write_disk_document, ClientInfo, FenceStatusContext, MermaidFence,
+    Outgoing, PendingEdit, ServerState, DISK_COMMANDS, JOURNALED_COMMANDS, NOT_IN_FENCE,
+};
+
+/// Commands that write nothing to disk, so they work for documents of any scheme
+const READ_ONLY_COMMANDS: &[&str] = &["mermaid.copyAsMarkdown", "mermaid.liveEditorLink", "mermaid.formatAll"];
+
+/// Run a command and answer with its [`CommandOutcome`]
+pub fn handle_execute_command(
+    connection: &Connection,
+    req: &Request,
+    state: &mut ServerState,
+) -> ServerResult<Value> {
+    let params: ExecuteCommandParams = serde_json::from_value(req.params.clone())?;
+    // Server-wide commands, not tied to a document
+    match params.command.as_str() {
+        "mermaid.getOptions" => return CommandOutcome::ok("Mermaid: effective options", &state.config).to_value(),
+        "mermaid.doctor" => return doctor(state, params.arguments.first()).to_value(),
+        "mermaid.stats" => return session_stats(state, params.arguments.first()).to_value(),
+        "mermaid.resetLimits" => {
+            render::reset_render_limits();
+            return CommandOutcome::ok("Mermaid: render count reset", outcome::Empty {}).to_value();
+        }
+        "mermaid.renderFiles" => return render_workspace_files(state, &params.arguments)?.to_value(),
+        "mermaid.setOption" => return set_option(connection, state, params.arguments.first()),
+        _ => {}
+    }
+    let ServerState {
+        documents,
+        versions,
+        client,
+        config,
+        render_failures,
+        checked,
+        recency,
+        published,
+        outgoing,
+        deferred,
+        workspace_root,
+        rc_files,
+    } = state;
+
+    let uri: Url = match params.arguments.first() {
+        Some(value) => serde_json::from_value(value.clone())?,
+        None => {
+            return Err(ServerError::InvalidParams(format!(
+                "{} expects a document URI",
+                params.command
+            )))
+        }
+    };
+    let document = if DISK_COMMANDS.contains(&params.command.as_str()) {
+        DocumentSource::new(documents, workspace_root.as_deref()).load(&uri)?
+    } else {
+        let text = documents.get(&uri).ok_or_else(|| ServerError::DocumentNotFound(uri.clone()))?;
+        document_source::SourcedDocument {
+            text: text.into(),
+            origin: Origin::Open,
+        }
+    };
+    let doc: &str = &document.text;
+    // Edits to files that aren't open are applied here, in UTF-8
+    let encoding = if document.is_open() {
+        recency.touch(&uri);
+        client.position_encoding
+    } else {
+        PositionEncoding::Utf8
+    };
+    let lines: Vec<&str> = doc.lines().collect();
+    let config = &*document_config(rc_files, config, &uri, workspace_root.as_deref());
+
+    // Fail up front, naming the scheme, rather than per fence
+    if !READ_ONLY_COMMANDS.contains(&params.command.as_str()) {
+        doc_base_dir(&uri)?;
+        // Nothing may be written in read-only locations: render commands show
+        // previews instead, the others fail before touching the disk
+        if let Some(reason) = read_only_reason(&uri, config) {
+            let dry_run = params.arguments.get(1).and_then(Value::as_bool).unwrap_or(false);
+            match params.command.as_str() {
+                "mermaid.renderSingle" | "mermaid.renderAllLightweight" => {
+                    return read_only_previews(connection, &params, &lines, reason, config)?.to_value();
+                }
+                "mermaid.editSingleSource" | "mermaid.editAllSources" | "mermaid.checkLinks" => {}
+                "mermaid.adoptRenderedBlocks" if dry_run => {}
+                _ => return Err(ServerError::ReadOnly(reason)),
+            }
+        }
+    }
+
+    let command = DocumentCommand {
+        connection,
+        req,
+        arguments: &params.arguments,
+        uri: &uri,
+        doc,
+        lines: &lines,
+        config,
+        encoding,
+        documents,
+        versions,
+        client,
+        render_failures,
+        checked,
+        published,
+        outgoing,
+        deferred,
+        workspace_root: workspace_root.as_deref(),
+    };
+    let answer = match params.command.as_str() {
+        "mermaid.renderSingle" => render_single(command),
+        "mermaid.renderAllLightweight" => render_all(command),
+        "mermaid.renderByType" => render_by_type(command),
+        "mermaid.editSingleSource" => edit_single_source(command),
+        "mermaid.editAllSources" => edit_all_sources(command),
+        "mermaid.formatAll" => format_all(command),
+        "mermaid.renderComparison" => comparison(command),
+        "mermaid.compareToReference" => reference_comparison(command),
+        "mermaid.embedSvgInline" => embed_svg_inline(command),
+        "mermaid.rerenderFromSources" => rerender_from_sources(command),
+        "mermaid.checkLinks" => links(command),
+        "mermaid.adoptRenderedBlocks" => adopt(command),
+        "mermaid.generateIndex" => index(command),
+        "mermaid.renderSteps" => steps(command),
+        "mermaid.copyAsMarkdown" => markdown(command),
+        "mermaid.liveEditorLink" => live_editor_link(command),
+        "mermaid.revertLastRender" => revert(command),
+        "mermaid.verifyCache" => cache_verification(command),
+        other => Err(ServerError::InvalidParams(format!("unknown command: {other}"))),
+    }?;
+    let (edit, cleanup, mut outcome) = match answer {
+        Answer::Outcome(outcome) => return outcome.to_value(),
+        Answer::Edit { edit, cleanup, outcome } => (edit, cleanup, outcome),
+    };
+
+    if let Some(edit) = edit.as_ref().filter(|_| JOURNALED_COMMANDS.contains(&params.command.as_str())) {
+        record_render(&uri, &lines, edit, config);
+    }
+
+    if let Origin::Disk(file) = &document.origin {
+        let summary = write_disk_document(&uri, doc, file, edit, &cleanup);
+        if let Some(error) = &summary.error {
+            outcome.status = Status::Failed;
+            outcome.message = format!("Mermaid: {} was not written: {error}", uri);
+        }
+        outcome.extend(summary);
+        return outcome.to_value();
+    }
+
+    // Clients without applyEdit support receive the edit's fields in the result
+    match edit {
+        Some(workspace_edit) if client.apply_edit => {
+            let text_edits = workspace_edit
+                .changes
+                .as_ref()
+                .and_then(|changes| changes.get(&uri))
+                .cloned()
+                .unwrap_or_default();
+            let pending = PendingEdit {
+                fences: fence_edits(&lines, &text_edits),
+                uri: uri.clone(),
+                version: versions.get(&uri).copied(),
+                attempts: 0,
+                cleanup,
+            };
+            apply_edit(connection, client, outgoing, pending, text_edits)?;
+        }
+        Some(workspace_edit) => {
+            remove_files(&cleanup);
+            outcome.extend(workspace_edit);
+        }
+        None => {}
+    }
+    outcome.to_value()
+}
+
+/// A command on one document: the document as read for it, and the parts of
+/// the server state commands use
+struct DocumentCommand<'a> {
+    connection: &'a Connection,
+    req: &'a Request,
+    /// The command's arguments, the document URI first
+    arguments: &'a [Value],
+    uri: &'a Url,
+    doc: &'a str,
+    lines: &'a [&'a str],
+    /// The session's options with the document's `.mermaidrc` files applied
+    config: &'a Config,
+    /// Unit of the positions in the edits the command makes
+    encoding: PositionEncoding,
+    documents: &'a HashMap<Url, String>,
+    versions: &'a HashMap<Url, i32>,
+    client: &'a ClientInfo,
+    render_failures: &'a mut HashMap<Url, HashMap<u64, Diagnostic>>,
+    checked: &'a mut HashMap<Url, CheckedDocument>,
+    published: &'a mut Publisher,
+    outgoing: &'a mut OutgoingRequests<Outgoing>,
+    deferred: &'a mut VecDeque<Message>,
+    workspace_root: Option<&'a Path>,
+}
+
+/// What a document command answers with
+enum Answer {
+    /// The command's outcome, as it is
+    Outcome(CommandOutcome),
+    /// An edit of the document to apply first: the outcome is extended with
+    /// what applying it did, and `cleanup` deleted once it is applied
+    Edit {
+        edit: Option<WorkspaceEdit>,
+        cleanup: Vec<PathBuf>,
+        outcome: CommandOutcome,
+    },
+}
+
+impl Answer {
+    fn outcome(outcome: CommandOutcome<impl Serialize>) -> ServerResult<Self> {
+        Ok(Self::Outcome(outcome.erase()))
+    }
+
+    fn edit(edit: Option<WorkspaceEdit>, outcome: CommandOutcome<impl Serialize>) -> ServerResult<Self> {
+        Ok(Self::Edit {
+            edit,
+            cleanup: Vec::new(),
+            outcome: outcome.erase(),
+        })
+    }
+}
+
+// ─── Server-wide commands ───────────────────────────────────────────────────
+
+fn doctor(state: &ServerState, argument: Option<&Value>) -> CommandOutcome {
+    let mut report = render::doctor(&state.config);
+    report["cache"] = cache_summary(argument, state);
+    CommandOutcome::ok("Mermaid: doctor report", report)
+}
+
+fn session_stats(state: &ServerState, argument: Option<&Value>) -> CommandOutcome {
+    let mut stats = stats(state);
+    stats["cache"] = cache_summary(argument, state);
+    CommandOutcome::ok("Mermaid: session statistics", stats)
+}
+
+fn render_workspace_files(state: &mut ServerState, arguments: &[Value]) -> ServerResult<CommandOutcome> {
+    let summaries = render_files(
+        arguments,
+        state.workspace_root.as_deref(),
+        &state.documents,
+        &state.config,
+        &mut state.rc_files,
+    )?;
+    let rendered: usize = summaries.iter().map(|s| s.rendered).sum();
+    let failed = summaries.iter().map(|s| s.failed.len() + usize::from(s.error.is_some())).sum();
+    let message = format!("Mermaid: rendered {rendered} diagrams in {} files, {failed} failed", summaries.len());
+    let files = outcome::Files { files: summaries };
+    Ok(CommandOutcome::new(Status::from_counts(rendered, failed), message, files).erase())
+}
+
+// ─── Document commands ──────────────────────────────────────────────────────
+
+/// What the render commands answer for a document in a read-only location:
+/// previews of the diagrams they would have rendered
+fn read_only_previews(
+    connection: &Connection,
+    params: &ExecuteCommandParams,
+    lines: &[&str],
+    reason: String,
+    config: &Config,
+) -> ServerResult<CommandOutcome> {
+    let fences: Vec<MermaidFence> = if params.command == "mermaid.renderSingle" {
+        let Some(fence) = single_render_fence(lines, &params.arguments)? else {
+            return Ok(unchanged(connection, NOT_IN_FENCE, outcome::Rendered::default())?.erase());
+        };
+        if let Some(e) = fence.empty_reason() {
+            return Ok(unchanged(connection, &e.to_string(), outcome::Rendered::default())?.erase());
+        }
+        vec![fence]
+    } else {
+        find_all_mermaid_fences(lines)
+            .into_iter()
+            .filter(|fence| !fence.ignored && fence.empty_reason().is_none())
+            .collect()
+    };
+    let message = format!("Mermaid: {reason}, so diagrams were not rendered in place; returning previews instead");
+    show_message(connection, MessageType::WARNING, &message)?;
+    let previews = outcome::Previews {
+        read_only: reason,
+        previews: preview_fences(&fences, config),
+    };
+    Ok(CommandOutcome::new(Status::Unchanged, message, previews).erase())
+}
+
+fn render_single(command: DocumentCommand) -> ServerResult<Answer> {
+    let DocumentCommand {
+        connection,
+        arguments,
+        uri,
+        doc,
+        lines,
+        config,
+        encoding,
+        versions,
+        client,
+        render_failures,
+        published,
+        outgoing,
+        ..
+    } = command;
+    let Some(fence) = single_render_fence(lines, arguments)? else {
+        return Answer::outcome(unchanged(connection, NOT_IN_FENCE, outcome::Rendered::default())?);
+    };
+    if let Some(e) = fence.empty_reason() {
+        return Answer::outcome(unchanged(connection, &e.to_string(), outcome::Rendered::default())?);
+    }
+    let hash = code_hash(&fence.code);
+    let context = FenceStatusContext {
+        uri,
+        version: versions.get(uri).copied(),
+        failures: render_failures.get(uri),
+        encoding,
+    };
+    let rendering = HashMap::from([(hash, FenceState::Rendering)]);
+    publish_fence_status(connection, client, published, &context, doc, &rendering, config)?;
+    let rendered = create_render_edit(uri, doc, lines, &fence, config, encoding);
+    let state = match &rendered {
+        Ok(_) => FenceState::Rendered,
+        Err(e) => FenceState::Error { message: e.to_string() },
+    };
+    publish_fence_status(connection, client, published, &context, doc, &HashMap::from([(hash, state)]), config)?;
+    if matches!(rendered, Err(ServerError::ToolNotFound(_))) {
+        if let Some(guidance) = offer_mmdc_install(connection, client, outgoing, config)? {
+            return Answer::outcome(guidance);
+        }
+    }
+    let edit = rendered?;
+    let files = rendered_files(uri, Some(&edit));
+    let result = outcome::Rendered {
+        rendered: 1,
+        failed: 0,
+        files,
+    };
+    let message = format!("Mermaid: rendered the diagram at line {}", fence.start_line + 1);
+    Answer::edit(Some(edit), CommandOutcome::ok(message, result))
+}
+
+fn render_all(command: DocumentCommand) -> ServerResult<Answer> {
+    let DocumentCommand {
+        connection,
+        req,
+        uri,
+        doc,
+        lines,
+        config,
+        encoding,
+        versions,
+        client,
+        render_failures,
+        checked,
+        published,
+        outgoing,
+        deferred,
+        ..
+    } = command;
+    let (ignored, fences): (Vec<MermaidFence>, Vec<MermaidFence>) =
+        find_all_mermaid_fences(lines).into_iter().partition(|fence| fence.ignored);
+    let (empty, fences): (Vec<MermaidFence>, Vec<MermaidFence>) =
+        fences.into_iter().partition(|fence| fence.empty_reason().is_some());
+    let rendering = fences
+        .iter()
+        .map(|fence| (code_hash(&fence.code), FenceState::Rendering))
+        .collect();
+    let context = FenceStatusContext {
+        uri,
+        version: versions.get(uri).copied(),
+        failures: render_failures.get(uri),
+        encoding,
+    };
+    publish_fence_status(connection, client, published, &context, doc, &rendering, config)?;
+
+    let progress = begin_progress(connection, client, outgoing, "Rendering Mermaid diagrams")?;
+    let newline = config.mmd_line_ending.newline(doc);
+    let rendered = create_render_all_edit(uri, lines, newline, config, encoding, |done, total| {
+        if let Some(token) = &progress {
+            let _ = report_progress(connection, token.clone(), done, total);
+        }
+        !poll_cancelled(connection, deferred, &req.id, progress.as_ref())
+    });
+    if let Some(token) = progress {
+        end_progress(connection, token)?;
+    }
+    if matches!(rendered, Err(ServerError::Cancelled)) {
+        info!("Render all cancelled for {uri}");
+    }
+    if rendered.is_err() {
+        publish_fence_status(connection, client, published, &context, doc, &HashMap::new(), config)?;
+    }
+
+    let (edit, failures) = rendered?;
+    let missing_tool = edit.is_none() && failures.values().any(is_missing_tool);
+    let done = fences
+        .iter()
+        .map(|fence| code_hash(&fence.code))
+        .filter(|hash| !failures.contains_key(hash))
+        .map(|hash| (hash, FenceState::Rendered))
+        .collect();
+    let context = FenceStatusContext {
+        failures: Some(&failures),
+        ..context
+    };
+    publish_fence_status(connection, client, published, &context, doc, &done, config)?;
+
+    let failed = failed_fences(&fences, &failures);
+    let result = outcome::Rendered {
+        rendered: fences.len() - failed,
+        failed,
+        files: rendered_files(uri, edit.as_ref()),
+    };
+    render_failures.insert(uri.clone(), failures);
+    if client.publish_diagnostics {
+        let diagnostics = document_diagnostics(doc, render_failures.get(uri), encoding, config);
+        checked.insert(uri.clone(), checked_document(doc, diagnostics.clone()));
+        publish_diagnostics(connection, published, uri, diagnostics)?;
+    }
+    if !ignored.is_empty() {
+        show_message(
+            connection,
+            MessageType::INFO,
+            &format!("Mermaid: skipped {} ignored diagrams", ignored.len()),
+        )?;
+    }
+    if !empty.is_empty() {
+        show_message(
+            connection,
+            MessageType::INFO,
+            &format!("Mermaid: skipped {} empty mermaid blocks", empty.len()),
+        )?;
+    }
+    if missing_tool {
+        if let Some(guidance) = offer_mmdc_install(connection, client, outgoing, config)? {
+            return Answer::outcome(guidance);
+        }
+    }
+    let mut message = format!("Mermaid: rendered {} diagrams", result.rendered);
+    if result.failed > 0 {
+        message.push_str(&format!(", {} failed", result.failed));
+    }
+    let status = Status::from_counts(result.rendered, result.failed);
+    Answer::edit(edit, CommandOutcome::new(status, message, result))
+}
+
+fn render_by_type(command: DocumentCommand) -> ServerResult<Answer> {
+    let DocumentCommand {
+        connection,
+        arguments,
+        uri,
+        doc,
+        lines,
+        config,
+        encoding,
+        client,
+        render_failures,
+        checked,
+        published,
+        outgoing,
+        ..
+    } = command;
+    let types = requested_types(arguments.get(1))?;
+    let normalized: Vec<String> = types.iter().map(|name| normalized_type(name)).collect();
+    let wanted = |code: &str| normalized.contains(&normalized_type(diagnostics::diagram_keyword(code)));
+    let (fences, other): (Vec<MermaidFence>, Vec<MermaidFence>) = find_all_mermaid_fences(lines)
+        .into_iter()
+        .filter(|fence| !fence.ignored && fence.empty_reason().is_none())
+        .partition(|fence| wanted(&fence.code));
+    let newline = config.mmd_line_ending.newline(doc);
+    let (edit, failures) = create_render_fences_edit(uri, lines, &fences, newline, config, encoding, |_, _| true)?;
+    let blocks = rerender_blocks(uri, lines, wanted, config)?;
+
+    let fences_failed = failed_fences(&fences, &failures);
+    let rendered = fences.len() - fences_failed + blocks.rerendered.len();
+    let failed = fences_failed + blocks.failed.len();
+    let missing_tool = rendered == 0 && failures.values().any(is_missing_tool);
+    render_failures.entry(uri.clone()).or_default().extend(failures);
+    if client.publish_diagnostics {
+        let diagnostics = document_diagnostics(doc, render_failures.get(uri), encoding, config);
+        checked.insert(uri.clone(), checked_document(doc, diagnostics.clone()));
+        publish_diagnostics(connection, published, uri, diagnostics)?;
+    }
+    let mut summary = format!(
+        "Mermaid: rendered {rendered} {} diagrams, skipped {} of other types",
+        types.join("/"),
+        other.len() + blocks.skipped
+    );
+    if failed > 0 {
+        summary.push_str(&format!(", {failed} failed"));
+    }
+    if missing_tool {
+        if let Some(guidance) = offer_mmdc_install(connection, client, outgoing, config)? {
+            return Answer::outcome(guidance);
+        }
+    }
+    show_message(connection, MessageType::INFO, &summary)?;
+    let mut files = rendered_files(uri, edit.as_ref());
+    files.extend(blocks.rerendered);
+    let result = outcome::Rendered { rendered, failed, files };
+    Answer::edit(edit, CommandOutcome::new(Status::from_counts(rendered, failed), summary, result))
+}
+
+fn edit_single_source(command: DocumentCommand) -> ServerResult<Answer> {
+    let DocumentCommand {
+        arguments,
+        uri,
+        doc,
+        lines,
+        encoding,
+        ..
+    } = command;
+    let blocks = find_all_rendered_blocks(lines);
+    let block = match arguments.get(1).filter(|value| !value.is_null()) {
+        Some(value) => {
+            let line = value
+                .as_u64()
+                .ok_or_else(|| ServerError::InvalidParams(format!("Expected a line number, got {value}")))?
+                as usize;
+            let block = blocks
+                .iter()
+                .find(|block| (block.start_line..=block.end_line).contains(&line))
+                .ok_or_else(|| ServerError::InvalidParams(format!("No rendered diagram at line {}", line + 1)))?;
+            Some(block)
+        }
+        // Several to choose from: let the client ask which
+        None if blocks.len() > 1 => {
+            let candidates = source_candidates(&doc_base_dir(uri)?, &blocks);
+            let message = format!("Mermaid: {} rendered diagrams; choose one to restore", candidates.len());
+            return Answer::outcome(CommandOutcome::new(Status::Unchanged, message, outcome::Candidates { candidates }));
+        }
+        None => blocks.first(),
+    };
+    let edit = block.map(|rb| create_source_edit(uri, doc, lines, rb, encoding)).transpose()?;
+    let outcome = restored_outcome(uri, edit.as_ref());
+    Answer::edit(edit, outcome)
+}
+
+fn edit_all_sources(command: DocumentCommand) -> ServerResult<Answer> {
+    let edit = create_edit_all_sources(command.uri, command.doc, command.lines, command.encoding)?;
+    let outcome = restored_outcome(command.uri, edit.as_ref());
+    Answer::edit(edit, outcome)
+}
+
+fn format_all(command: DocumentCommand) -> ServerResult<Answer> {
+    let edit = create_format_all_edit(command.uri, command.lines, command.encoding);
+    let formatted = edit_count(command.uri, edit.as_ref());
+    let (status, message) = match formatted {
+        0 => (Status::Unchanged, "Mermaid: every flowchart is already formatted".to_string()),
+        n => (Status::Ok, format!("Mermaid: formatted {n} flowcharts")),
+    };
+    Answer::edit(edit, CommandOutcome::new(status, message, outcome::Formatted { formatted }))
+}
+
+fn comparison(command: DocumentCommand) -> ServerResult<Answer> {
+    let result = render_comparison(command.uri, command.doc, &command.arguments[1..], command.config)?;
+    Answer::outcome(CommandOutcome::ok("Mermaid: comparison rendered", result))
+}
+
+fn reference_comparison(command: DocumentCommand) -> ServerResult<Answer> {
+    let DocumentCommand {
+        arguments,
+        uri,
+        lines,
+        config,
+        workspace_root,
+        ..
+    } = command;
+    let compared = compare_to_reference(uri, lines, &arguments[1..], workspace_root, config)?;
+    let (status, verdict) = if compared.passed { (Status::Ok, "passed") } else { (Status::Failed, "failed") };
+    let message = format!(
+        "Mermaid: {:.2}% of the pixels differ from the reference (threshold {}%): {verdict}",
+        compared.difference, compared.threshold
+    );
+    Answer::outcome(CommandOutcome::new(status, message, compared))
+}
+
+fn embed_svg_inline(command: DocumentCommand) -> ServerResult<Answer> {
+    let DocumentCommand {
+        arguments,
+        uri,
+        doc,
+        lines,
+        config,
+        encoding,
+        ..
+    } = command;
+    let fence = fence_for_argument(lines, arguments.get(1))?;
+    let newline = config.mmd_line_ending.newline(doc);
+    let edit = create_inline_svg_edit(uri, lines, &fence, newline, config, encoding)?;
+    let result = outcome::Rendered {
+        rendered: 1,
+        failed: 0,
+        files: rendered_files(uri, Some(&edit)),
+    };
+    let message = format!("Mermaid: embedded the diagram at line {} as SVG", fence.start_line + 1);
+    Answer::edit(Some(edit), CommandOutcome::ok(message, result))
+}
+
+fn rerender_from_sources(command: DocumentCommand) -> ServerResult<Answer> {
+    let rerenders = rerender_blocks(command.uri, command.lines, |_| true, command.config)?;
+    let kind = if rerenders.failed.is_empty() { MessageType::INFO } else { MessageType::WARNING };
+    let summary = rerender_summary(&rerenders);
+    show_message(command.connection, kind, &summary)?;
+    let status = Status::from_counts(rerenders.rerendered.len(), rerenders.failed.len());
+    Answer::outcome(CommandOutcome::new(status, summary, rerenders))
+}
+
+fn links(command: DocumentCommand) -> ServerResult<Answer> {
+    let rerender = command.arguments.get(1).and_then(Value::as_bool).unwrap_or(false);
+    let result = check_links(command.uri, command.lines, rerender, command.config)?;
+    let broken = result["broken"].as_array().map_or(0, Vec::len);
+    let message = format!("Mermaid: {broken} broken links");
+    Answer::outcome(CommandOutcome::ok(message, result))
+}
+
+fn adopt(command: DocumentCommand) -> ServerResult<Answer> {
+    let DocumentCommand {
+        connection,
+        arguments,
+        uri,
+        doc,
+        lines,
+        config,
+        encoding,
+        ..
+    } = command;
+    let dry_run = arguments.get(1).and_then(Value::as_bool).unwrap_or(false);
+    let newline = config.mmd_line_ending.newline(doc);
+    let (adopted, edit) = adopt_rendered_blocks(uri, lines, dry_run, newline, config, encoding)?;
+    let failed = adopted.iter().filter(|a| a.error.is_some()).count();
+    let result = outcome::Adopted {
+        dry_run,
+        adopted: adopted.len() - failed,
+        failed,
+        blocks: adopted,
+    };
+    let message = format!("Mermaid: adopted {} blocks, {failed} could not be converted", result.adopted);
+    let status = Status::from_counts(result.adopted, failed);
+    if dry_run {
+        return Answer::outcome(CommandOutcome::new(status, message, result));
+    }
+    if failed > 0 {
+        show_message(connection, MessageType::WARNING, &message)?;
+    }
+    Answer::edit(edit, CommandOutcome::new(status, message, result))
+}
+
+fn index(command: DocumentCommand) -> ServerResult<Answer> {
+    let at = command.arguments.get(1).and_then(Value::as_u64).map(|line| line as usize);
+    let (figures, edit) = generate_index(command.uri, command.lines, at, command.encoding);
+    let status = if edit.is_some() { Status::Ok } else { Status::Unchanged };
+    let message = format!("Mermaid: listed {} figures", figures.len());
+    let result = outcome::Indexed { figures: figures.len() };
+    Answer::edit(edit, CommandOutcome::new(status, message, result))
+}
+
+fn steps(command: DocumentCommand) -> ServerResult<Answer> {
+    let result = render_steps(command.uri, command.lines, command.arguments.get(1), command.config)?;
+    let steps = result["files"].as_array().map_or(0, Vec::len);
+    Answer::outcome(CommandOutcome::ok(format!("Mermaid: rendered {steps} steps"), result))
+}
+
+fn markdown(command: DocumentCommand) -> ServerResult<Answer> {
+    let markdown = copy_as_markdown(command.lines, command.arguments.get(1), command.config)?;
+    Answer::outcome(CommandOutcome::ok("Mermaid: markdown image", outcome::Markdown { markdown }))
+}
+
+fn live_editor_link(command: DocumentCommand) -> ServerResult<Answer> {
+    let fence = fence_for_argument(command.lines, command.arguments.get(1))?;
+    let url = live::editor_link(&fence.code, &command.config.theme);
+    Answer::outcome(CommandOutcome::ok("Mermaid: Live Editor link", outcome::Link { url }))
+}
+
+fn revert(command: DocumentCommand) -> ServerResult<Answer> {
+    let DocumentCommand {
+        connection,
+        uri,
+        doc,
+        lines,
+        config,
+        encoding,
+        documents,
+        ..
+    } = command;
+    let Some((edit, files)) = revert_last_render(uri, doc, lines, documents, config, encoding)? else {
+        return Answer::outcome(unchanged(connection, "nothing to revert", outcome::Reverted::default())?);
+    };
+    let result = outcome::Reverted {
+        reverted: edit_count(uri, Some(&edit)),
+        deleted_files: files.len(),
+        freed_bytes: files.iter().filter_map(|f| fs::metadata(f).ok()).map(|m| m.len()).sum(),
+    };
+    let message = format!("Mermaid: reverted {} rendered diagrams", result.reverted);
+    Ok(Answer::Edit {
+        edit: Some(edit),
+        cleanup: files,
+        outcome: CommandOutcome::ok(message, result).erase(),
+    })
+}
+
+fn cache_verification(command: DocumentCommand) -> ServerResult<Answer> {
+    let report = verify_cache(command.uri, command.config)?;
+    let message = format!(
+        "Mermaid cache: checked {} entries, removed {} corrupt",
+        report.checked,
+        report.removed.len()
+    );
+    show_message(command.connection, MessageType::INFO, &message)?;
+    Answer::outcome(CommandOutcome::ok(message, report))
+}
+
+/// Tell the user why a command changed nothing, and answer with that
+fn unchanged<T: Serialize>(connection: &Connection, reason: &str, result: T) -> ServerResult<CommandOutcome<T>> {
+    let message = format!("Mermaid: {reason}");
+    show_message(connection, MessageType::INFO, &message)?;
+    Ok(CommandOutcome::new(Status::Unchanged, message, result))
+}
+
+/// Number of text edits `edit` makes to `uri`
+fn edit_count(uri: &Url, edit: Option<&WorkspaceEdit>) -> usize {
+    edit.and_then(|edit| edit.changes.as_ref()?.get(uri)).map_or(0, Vec::len)
+}
+
+/// The files the rendered blocks an edit writes link to: their images, or
+/// the `.mmd` source of those with inline SVG
+fn rendered_files(uri: &Url, edit: Option<&WorkspaceEdit>) -> Vec<String> {
+    edit.and_then(|edit| edit.changes.as_ref()?.get(uri))
+        .into_iter()
+        .flatten()
+        .flat_map(|text_edit| find_all_rendered_blocks(&text_edit.new_text.lines().collect::<Vec<_>>()))
+        .map(|block| block.image.map_or(block.source_file, |(_, link)| link))
+        .collect()
+}
+
+/// The outcome of the edit-source commands, one text edit per restored fence
+fn restored_outcome(uri: &Url, edit: Option<&WorkspaceEdit>) -> CommandOutcome {
+    let restored = edit_count(uri, edit);
+    let (status, message) = match restored {
+        0 => (Status::Unchanged, "Mermaid: no rendered diagrams to restore".to_string()),
+        n => (Status::Ok, format!("Mermaid: restored {n} diagrams to their fences")),
+    };
+    CommandOutcome::new(status, message, outcome::Restored { restored }).erase()
+}
@@ -0,0 +1,157 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::error::{ServerError, ServerResult};
+
+static SVG_OPEN_TAG: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?s)<svg\b[^>]*>").expect("svg open tag regex"));
+
+static XML_PROLOG: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?s)<\?xml.*?\?>|<!DOCTYPE[^>]*>").expect("xml prolog regex"));
+
+/// Horizontal space between composed diagrams, the divider sits in the middle
+pub const DIVIDER_GAP: f64 = 40.0;
+
+/// Width and height of an SVG document, taken from its root element
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SvgSize {
+    pub width: f64,
+    pub height: f64,
+}
+
+/// The area an SVG document draws in, in user units
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SvgBounds {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+/// Read the size of an SVG from its root `viewBox`, falling back to width/height
+pub fn svg_size(svg: &str) -> Option<SvgSize> {
+    svg_bounds(svg).map(|bounds| SvgSize {
+        width: bounds.width,
+        height: bounds.height,
+    })
+}
+
+/// Read the bounds of an SVG from its root `viewBox`, falling back to
+/// width/height from the origin
+pub fn svg_bounds(svg: &str) -> Option<SvgBounds> {
+    let tag = SVG_OPEN_TAG.find(svg)?.as_str();
+
+    if let Some(view_box) = attr(tag, "viewBox") {
+        let parts: Vec<f64> = view_box
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|p| !p.is_empty())
+            .filter_map(|p| p.parse().ok())
+            .collect();
+        if let [x, y, width, height] = parts[..] {
+            if width > 0.0 && height > 0.0 {
+                return Some(SvgBounds { x, y, width, height });
+            }
+        }
+    }
+
+    let width = attr(tag, "width").and_then(|v| parse_length(&v))?;
+    let height = attr(tag, "height").and_then(|v| parse_length(&v))?;
+    Some(SvgBounds {
+        x: 0.0,
+        y: 0.0,
+        width,
+        height,
+    })
+}
+
+/// Lay SVGs out left to right, separated by a vertical divider
+pub fn compose_horizontal(svgs: &[&str]) -> ServerResult<String> {
+    if svgs.is_empty() {
+        return Err(ServerError::InvalidParams("Nothing to compose".to_string()));
+    }
+
+    let sizes = svgs
+        .iter()
+        .enumerate()
+        .map(|(i, svg)| {
+            svg_size(svg)
+                .ok_or_else(|| ServerError::RenderFailed(format!("Diagram {} has no usable size", i + 1)))
+        })
+        .collect::<ServerResult<Vec<_>>>()?;
+
+    let height = sizes.iter().map(|s| s.height).fold(0.0, f64::max);
+    let width = sizes.iter().map(|s| s.width).sum::<f64>()
+        + DIVIDER_GAP * (svgs.len() as f64 - 1.0);
+
+    let mut body = String::new();
+    let mut x = 0.0;
+    for (i, (svg, size)) in svgs.iter().zip(&sizes).enumerate() {
+        if i > 0 {
+            let divider_x = x - DIVIDER_GAP / 2.0;
+            body.push_str(&format!(
+                r##"<line x1="{divider_x:.2}" y1="0" x2="{divider_x:.2}" y2="{height:.2}" stroke="#999" stroke-width="1" stroke-dasharray="4 4"/>"##
+            ));
+        }
+        body.push_str(&format!(
+            r#"<svg x="{x:.2}" y="0" width="{:.2}" height="{:.2}">{}</svg>"#,
+            size.width,
+            size.height,
+            XML_PROLOG.replace_all(svg, "").trim()
+        ));
+        x += size.width + DIVIDER_GAP;
+    }
+
+    Ok(format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width:.2}" height="{height:.2}" viewBox="0 0 {width:.2} {height:.2}">{body}</svg>"#
+    ))
+}
+
+fn attr(tag: &str, name: &str) -> Option<String> {
+    let pattern = format!(r#"\s{}\s*=\s*["']([^"']*)["']"#, regex::escape(name));
+    let re = Regex::new(&pattern).ok()?;
+    re.captures(tag).map(|c| c[1].to_string())
+}
+
+/// Parse an absolute SVG length such as `120` or `120px`
+fn parse_length(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let number = value.strip_suffix("px").unwrap_or(value);
+    number.parse().ok().filter(|n: &f64| *n > 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_size_from_view_box_or_attributes() {
+        assert_eq!(
+            svg_size(r#"<svg width="100%" viewBox="-8 -8 120 60"></svg>"#),
+            Some(SvgSize { width: 120.0, height: 60.0 })
+        );
+        assert_eq!(
+            svg_size(r#"<svg width="80px" height="30"></svg>"#),
+            Some(SvgSize { width: 80.0, height: 30.0 })
+        );
+        assert_eq!(svg_size(r#"<svg width="100%"></svg>"#), None);
+    }
+
+    #[test]
+    fn composes_side_by_side_with_divider() {
+        let left = r#"<?xml version="1.0"?><svg viewBox="0 0 100 50"><rect/></svg>"#;
+        let right = r#"<svg width="200" height="80"><circle/></svg>"#;
+        let svg = compose_horizontal(&[left, right]).unwrap();
+
+        assert!(svg.starts_with(r#"<svg xmlns="http://www.w3.org/2000/svg" width="340.00" height="80.00""#));
+        assert!(svg.contains(r#"<svg x="0.00" y="0" width="100.00" height="50.00">"#));
+        assert!(svg.contains(r#"<svg x="140.00" y="0" width="200.00" height="80.00">"#));
+        assert!(svg.contains(r#"<line x1="120.00" y1="0" x2="120.00" y2="80.00""#));
+        assert!(!svg.contains("<?xml"));
+    }
+
+    #[test]
+    fn rejects_children_without_size() {
+        assert!(compose_horizontal(&["<svg></svg>", "<svg></svg>"]).is_err());
+        assert!(compose_horizontal(&[]).is_err());
+    }
+}
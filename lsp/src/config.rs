@@ -0,0 +1,374 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::backend::BackendFallback;
+use crate::logging::LogFormat;
+use crate::naming::DEFAULT_FILE_NAME_TEMPLATE;
+
+/// Server settings, read from the client's `initializationOptions`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Config {
+    /// Pin rendering to a specific `@mermaid-js/mermaid-cli` version (run via npx when
+    /// the installed mmdc doesn't match)
+    pub mermaid_cli_version: Option<String>,
+    /// Log output format (`text` or `json`), overriding `MERMAID_LSP_LOG_FORMAT`
+    pub log_format: Option<LogFormat>,
+    /// Number of fences rendered between progress reports / cancellation checks
+    /// during "Render All"
+    pub render_chunk_size: usize,
+    /// Hosts (and subdomains) external links/images in rendered SVGs may point to.
+    /// Empty allows every host.
+    pub allowed_link_hosts: Vec<String>,
+    /// Strip all external links/images from rendered SVGs
+    pub block_external_links: bool,
+    /// Directory for rendered SVG/.mmd files, absolute or relative to the document's
+    /// directory. Defaults to `.mermaid`.
+    pub output_dir: Option<String>,
+    /// Put an `<a id="diagram-<slug>">` anchor above each rendered diagram, named
+    /// after its title, so it can be linked to
+    pub diagram_anchors: bool,
+    /// Shrink rendered SVGs (comments, whitespace, number precision, unused
+    /// and duplicate definitions) after sanitization
+    pub optimize_svg: bool,
+    /// Command (program and arguments, no shell) that rewrites each sanitized SVG
+    /// from stdin to stdout. Empty disables the hook.
+    pub post_process_command: Vec<String>,
+    /// Time limit for one `post_process_command` run
+    pub post_process_timeout_ms: u64,
+    /// Time limit for rendering one fence with mmdc; a fence that takes
+    /// longer fails and the rest of the batch still renders. 0 waits forever.
+    pub fence_render_timeout_ms: u64,
+    /// Semantic checks to skip (`duplicate-node-id`, `gantt`)
+    pub disabled_checks: Vec<String>,
+    /// What to do when a fence's `{backend=...}` can't be used: render with
+    /// mmdc (`mmdc`) or fail (`error`)
+    pub unavailable_backend: BackendFallback,
+    /// Line endings of the `.mmd` source files written next to rendered diagrams
+    pub mmd_line_ending: LineEnding,
+    /// Mermaid theme passed to mmdc (`default`, `neutral`, `dark`, `forest`, `base`)
+    pub theme: String,
+    /// Background color of rendered SVGs, any CSS color or `transparent`
+    pub background: String,
+    /// Pass the output format to mmdc with `-e` instead of leaving it to infer
+    /// it from the output file's extension
+    pub mmdc_output_format_flag: bool,
+    /// Extra comment patterns for `mermaid.adoptRenderedBlocks`, regexes
+    /// matching one line with the source file path in a `source` group
+    pub adopt_patterns: Vec<String>,
+    /// Soft cap on the state derived from open documents (fence indexes and
+    /// diagnostics); beyond it the least recently used documents' is dropped
+    /// and recomputed when needed. Document text is always kept.
+    pub derived_state_cap_bytes: usize,
+    /// What to check is gitignored after the first render into a git worktree,
+    /// offering to add it to `.gitignore`: `cache`, `outputDir` or `off`
+    pub gitignore_check: GitignoreCheck,
+    /// Fetch the diagram source of fences with a `url=` attribute. Off by
+    /// default, since it makes the server reach the network.
+    pub remote_sources: bool,
+    /// Hosts (and subdomains) remote diagram sources may be fetched from.
+    /// Empty allows none.
+    pub remote_source_hosts: Vec<String>,
+    /// Time limit for fetching one remote diagram source
+    pub remote_source_timeout_ms: u64,
+    /// Largest remote diagram source accepted, in bytes
+    pub remote_source_max_bytes: u64,
+    /// Language of code action titles and prompts, as a tag like `ja` or
+    /// `ja-JP`. Untranslated languages and strings are shown in English.
+    pub locale: String,
+    /// Show a failed diagram's preview as an image of the error instead of
+    /// leaving it out. Never written next to the document.
+    pub error_placeholder: bool,
+    /// Name of a rendered diagram's SVG and `.mmd` files, without extension,
+    /// with `{doc}`, `{hash}`, `{index}`, `{title}` and `{timestamp}` placeholders
+    pub file_name_template: String,
+    /// Render commands remembered per output directory for
+    /// `mermaid.revertLastRender`; 0 remembers none
+    pub render_journal_max_entries: usize,
+    /// Days a render stays revertible
+    pub render_journal_max_age_days: u64,
+    /// Diagrams the server renders before refusing until `mermaid.resetLimits`;
+    /// cache hits don't count. Unset renders without limit.
+    pub max_renders_per_session: Option<usize>,
+    /// Iconify JSON icon packs for architecture and flowchart icons: `.json`
+    /// files, or directories of them, inside the workspace (relative to it
+    /// unless absolute)
+    pub icon_packs: Vec<String>,
+    /// Arguments appended to mmdc's command line after the ones the server
+    /// manages, for flags it doesn't wrap. May not replace the input, output
+    /// or config file.
+    pub extra_mmdc_args: Vec<String>,
+    /// mermaid's `securityLevel` for mmdc renders, overriding the bundled
+    /// config. `loose` lets diagrams carry HTML and click handlers, which the
+    /// sanitizer then has to strip.
+    pub security_level: SecurityLevel,
+    /// Text laid over every rendered diagram, e.g. `DRAFT`. Unset renders
+    /// without one.
+    pub watermark: Option<Watermark>,
+    /// Where rendered SVGs are cached for reuse: under the output directory
+    /// (`project`) or in the platform's cache directory, one per workspace
+    /// (`user`). Switching starts with an empty cache.
+    pub cache_location: CacheLocation,
+    /// Mermaid text rendered before every diagram's own, typically a shared
+    /// `%%{init}%%` directive. Goes after a diagram's frontmatter, and not
+    /// again into a diagram that already has it.
+    pub preamble: Option<String>,
+    /// Write the preamble into the `.mmd` sources too, so they render the
+    /// same without this configuration
+    pub preamble_in_source: bool,
+    /// Values of the `{{NAME}}` placeholders in diagram code
+    pub variables: BTreeMap<String, String>,
+    /// Environment variables `{{env:NAME}}` placeholders may read
+    pub env_variables: Vec<String>,
+}
+
+/// A rotated, semi-transparent text centered over rendered diagrams
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Watermark {
+    pub text: String,
+    /// From 0 (invisible) to 1 (opaque)
+    pub opacity: f64,
+    /// Clockwise rotation in degrees
+    pub angle: f64,
+}
+
+impl Default for Watermark {
+    fn default() -> Self {
+        Self {
+            text: "DRAFT".to_string(),
+            opacity: 0.15,
+            angle: -30.0,
+        }
+    }
+}
+
+/// How much mermaid trusts the diagram source
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecurityLevel {
+    /// HTML in labels is encoded and click handlers are disabled
+    #[default]
+    Strict,
+    /// HTML and click handlers are allowed
+    Loose,
+    /// HTML is allowed, but not scripts
+    Antiscript,
+    /// Diagrams are rendered in a sandboxed iframe
+    Sandbox,
+}
+
+impl SecurityLevel {
+    /// The value mermaid's config takes
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Strict => "strict",
+            Self::Loose => "loose",
+            Self::Antiscript => "antiscript",
+            Self::Sandbox => "sandbox",
+        }
+    }
+}
+
+/// Where the render cache lives
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheLocation {
+    /// `.cache` under the output directory
+    #[default]
+    Project,
+    /// `mermaid-lsp/<workspace hash>` under the user's cache directory
+    User,
+}
+
+impl CacheLocation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Project => "project",
+            Self::User => "user",
+        }
+    }
+}
+
+/// Generated files that should be ignored by git
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum GitignoreCheck {
+    /// The render cache under the output directory
+    #[default]
+    Cache,
+    /// The whole output directory, rendered SVGs and sources included
+    OutputDir,
+    /// Don't check
+    Off,
+}
+
+/// Line ending style for generated files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+    /// Whatever the document uses (CRLF if any of its lines end in it)
+    #[default]
+    Auto,
+}
+
+impl LineEnding {
+    /// The newline to write for a file generated from `doc`
+    pub fn newline(self, doc: &str) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+            LineEnding::Auto if doc.contains("\r\n") => "\r\n",
+            LineEnding::Auto => "\n",
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            mermaid_cli_version: None,
+            log_format: None,
+            render_chunk_size: 8,
+            allowed_link_hosts: Vec::new(),
+            block_external_links: false,
+            output_dir: None,
+            diagram_anchors: false,
+            optimize_svg: false,
+            post_process_command: Vec::new(),
+            post_process_timeout_ms: 10_000,
+            fence_render_timeout_ms: 60_000,
+            disabled_checks: Vec::new(),
+            unavailable_backend: BackendFallback::Mmdc,
+            mmd_line_ending: LineEnding::Auto,
+            theme: "default".to_string(),
+            background: "white".to_string(),
+            mmdc_output_format_flag: true,
+            adopt_patterns: Vec::new(),
+            derived_state_cap_bytes: 32 * 1024 * 1024,
+            gitignore_check: GitignoreCheck::Cache,
+            remote_sources: false,
+            remote_source_hosts: Vec::new(),
+            remote_source_timeout_ms: 10_000,
+            remote_source_max_bytes: 1024 * 1024,
+            locale: "en".to_string(),
+            error_placeholder: false,
+            file_name_template: DEFAULT_FILE_NAME_TEMPLATE.to_string(),
+            render_journal_max_entries: 20,
+            render_journal_max_age_days: 7,
+            max_renders_per_session: None,
+            icon_packs: Vec::new(),
+            extra_mmdc_args: Vec::new(),
+            security_level: SecurityLevel::Strict,
+            watermark: None,
+            cache_location: CacheLocation::Project,
+            preamble: None,
+            preamble_in_source: false,
+            variables: BTreeMap::new(),
+            env_variables: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Parse initialization options, falling back to defaults on invalid input
+    pub fn from_init_options(options: Option<&Value>) -> Self {
+        match options {
+            Some(value) if !value.is_null() => serde_json::from_value(value.clone())
+                .unwrap_or_else(|e| {
+                    warn!("Ignoring invalid initializationOptions: {e}");
+                    Self::default()
+                }),
+            _ => Self::default(),
+        }
+    }
+
+    /// Set the option named `key` (camelCase, as in initialization options) to
+    /// `value`. Unknown keys and values of the wrong type leave the config unchanged.
+    pub fn set_option(&mut self, key: &str, value: Value) -> Result<(), String> {
+        let mut options = serde_json::to_value(&*self).map_err(|e| e.to_string())?;
+        let Some(fields) = options.as_object_mut() else {
+            return Err("config is not an object".to_string());
+        };
+        if !fields.contains_key(key) {
+            let mut known: Vec<&str> = fields.keys().map(String::as_str).collect();
+            known.sort_unstable();
+            return Err(format!("unknown option `{key}` (known options: {})", known.join(", ")));
+        }
+        fields.insert(key.to_string(), value);
+        *self = serde_json::from_value(options).map_err(|e| format!("invalid value for `{key}`: {e}"))?;
+        Ok(())
+    }
+
+    /// A warning about settings that weaken rendering's defenses, for the user
+    pub fn security_warning(&self) -> Option<String> {
+        (self.security_level == SecurityLevel::Loose).then(|| {
+            "`securityLevel` is `loose`: diagrams may contain HTML and click handlers, and only the SVG sanitizer stands between them and the preview".to_string()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_camel_case_options() {
+        let value = serde_json::json!({ "mermaidCliVersion": "10.9.1", "logFormat": "json" });
+        let config = Config::from_init_options(Some(&value));
+        assert_eq!(config.mermaid_cli_version.as_deref(), Some("10.9.1"));
+        assert_eq!(config.log_format, Some(LogFormat::Json));
+    }
+
+    #[test]
+    fn line_ending_follows_the_document_in_auto_mode() {
+        assert_eq!(LineEnding::Auto.newline("a\r\nb\r\n"), "\r\n");
+        assert_eq!(LineEnding::Auto.newline("a\nb\n"), "\n");
+        assert_eq!(LineEnding::Lf.newline("a\r\nb"), "\n");
+        assert_eq!(LineEnding::Crlf.newline("a\nb"), "\r\n");
+        let value = serde_json::json!({ "mmdLineEnding": "crlf" });
+        assert_eq!(Config::from_init_options(Some(&value)).mmd_line_ending, LineEnding::Crlf);
+    }
+
+    #[test]
+    fn set_option_validates_key_and_value() {
+        let mut config = Config::default();
+        config.set_option("theme", serde_json::json!("dark")).unwrap();
+        assert_eq!(config.theme, "dark");
+
+        let unknown = config.set_option("colour", serde_json::json!("red")).unwrap_err();
+        assert!(unknown.contains("`colour`") && unknown.contains("theme"), "{unknown}");
+        let wrong_type = config.set_option("renderChunkSize", serde_json::json!("many")).unwrap_err();
+        assert!(wrong_type.contains("renderChunkSize"), "{wrong_type}");
+        assert_eq!(config.render_chunk_size, 8);
+        assert_eq!(config.theme, "dark");
+    }
+
+    #[test]
+    fn security_level_is_strict_unless_chosen() {
+        let config = Config::default();
+        assert_eq!(config.security_level, SecurityLevel::Strict);
+        assert_eq!(config.security_warning(), None);
+
+        let loose = Config::from_init_options(Some(&serde_json::json!({ "securityLevel": "loose" })));
+        assert_eq!(loose.security_level, SecurityLevel::Loose);
+        assert!(loose.security_warning().unwrap().contains("`loose`"));
+        let sandbox = Config::from_init_options(Some(&serde_json::json!({ "securityLevel": "sandbox" })));
+        assert_eq!(sandbox.security_warning(), None);
+        let mut config = Config::default();
+        assert!(config.set_option("securityLevel", serde_json::json!("unsafe")).is_err());
+    }
+
+    #[test]
+    fn defaults_when_missing_or_invalid() {
+        assert!(Config::from_init_options(None).mermaid_cli_version.is_none());
+        let invalid = serde_json::json!({ "mermaidCliVersion": 42 });
+        assert!(Config::from_init_options(Some(&invalid))
+            .mermaid_cli_version
+            .is_none());
+    }
+}
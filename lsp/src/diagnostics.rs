@@ -0,0 +1,332 @@
+use lsp_types::{Diagnostic, DiagnosticSeverity, NumberOrString, Position, Range};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::{collections::HashMap, path::Path};
+
+use crate::config::Config;
+use crate::position::PositionEncoding;
+
+mod gantt;
+
+/// A node id followed by the opening bracket of its shape, e.g. `A[` or `db[(`
+static NODE_DECLARATION: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?:^|[\s;&>|-])([\p{L}_][\p{L}\p{N}_]*)(\(\(\(|\(\(|\[\[|\[\(|\(\[|\{\{|\[/|\[\\|[\[\(\{>])")
+        .expect("node declaration regex")
+});
+
+/// Lines that mention node ids without declaring shapes
+pub const NON_DECLARATION_KEYWORDS: &[&str] =
+    &["subgraph", "style", "classDef", "class", "click", "linkStyle", "direction"];
+
+/// Name of each check, as listed in the `disabledChecks` option
+pub const DUPLICATE_NODE_ID_CHECK: &str = "duplicate-node-id";
+pub const GANTT_CHECK: &str = "gantt";
+pub const MISMATCHED_BLOCK_CHECK: &str = "mismatched-rendered-block";
+
+/// Kind of diagram, from the keyword on its first meaningful line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagramType {
+    Flowchart,
+    Gantt,
+    Other,
+}
+
+impl DiagramType {
+    pub fn detect(code: &str) -> Option<Self> {
+        let keyword = code
+            .lines()
+            .map(str::trim)
+            .find(|l| !l.is_empty() && !l.starts_with("%%"))
+            .and_then(|l| l.split_whitespace().next())?;
+        Some(match keyword {
+            "graph" | "flowchart" => Self::Flowchart,
+            "gantt" => Self::Gantt,
+            _ => Self::Other,
+        })
+    }
+}
+
+/// The keyword naming the diagram type, after any front matter and comments
+pub fn diagram_keyword(code: &str) -> &str {
+    let mut lines = code.lines().map(str::trim).peekable();
+    if lines.peek() == Some(&"---") {
+        lines.next();
+        lines.by_ref().find(|line| *line == "---");
+    }
+    lines
+        .find(|line| !line.is_empty() && !line.starts_with("%%"))
+        .and_then(|line| line.split_whitespace().next())
+        .unwrap_or("unknown")
+}
+
+/// Run the semantic checks for one diagram.
+///
+/// `first_line` is the document line of the first line of `code`.
+pub fn check_diagram(
+    code: &str,
+    first_line: usize,
+    encoding: PositionEncoding,
+    config: &Config,
+) -> Vec<Diagnostic> {
+    let enabled = |check: &str| !config.disabled_checks.iter().any(|c| c == check);
+    match DiagramType::detect(code) {
+        Some(DiagramType::Flowchart) if enabled(DUPLICATE_NODE_ID_CHECK) => {
+            duplicate_node_labels(code, first_line, encoding)
+        }
+        Some(DiagramType::Gantt) if enabled(GANTT_CHECK) => gantt::check(code, first_line, encoding),
+        _ => Vec::new(),
+    }
+}
+
+/// Warn when a rendered block's image and its source comment name different
+/// diagrams (their base names differ), e.g. after one was edited by hand:
+/// "Edit Mermaid Source" would open another diagram than the one shown.
+/// `line` is the image line, document line `line_no`; the warning covers the
+/// image's link target.
+pub fn check_rendered_block(
+    source_file: &str,
+    image: &str,
+    line_no: usize,
+    line: &str,
+    encoding: PositionEncoding,
+    config: &Config,
+) -> Option<Diagnostic> {
+    if config.disabled_checks.iter().any(|c| c == MISMATCHED_BLOCK_CHECK) {
+        return None;
+    }
+    let source_name = Path::new(source_file).file_stem()?;
+    let image_name = Path::new(image).file_stem()?;
+    if source_name == image_name {
+        return None;
+    }
+    let start = line.find(image)?;
+    Some(warning(
+        line_no,
+        line,
+        start,
+        start + image.len(),
+        MISMATCHED_BLOCK_CHECK,
+        format!(
+            "Image `{image}` doesn't match the block's source `{source_file}`; \
+             re-render the block to realign them"
+        ),
+        encoding,
+    ))
+}
+
+/// A warning covering bytes `start..end` of `line` (document line `line_no`)
+fn warning(
+    line_no: usize,
+    line: &str,
+    start: usize,
+    end: usize,
+    code: &str,
+    message: String,
+    encoding: PositionEncoding,
+) -> Diagnostic {
+    Diagnostic {
+        range: Range::new(
+            Position::new(line_no as u32, encoding.line_len(&line[..start])),
+            Position::new(line_no as u32, encoding.line_len(&line[..end])),
+        ),
+        severity: Some(DiagnosticSeverity::WARNING),
+        code: Some(NumberOrString::String(code.to_string())),
+        source: Some("mermaid".to_string()),
+        message,
+        ..Default::default()
+    }
+}
+
+/// A node declared with a label, e.g. `A[One]`
+pub struct NodeDeclaration<'a> {
+    pub id: &'a str,
+    pub label: &'a str,
+    /// Byte range of the whole declaration within its line
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Warn when a flowchart node id is declared again with a different label;
+/// mmdc keeps the first label and the later one silently disappears.
+fn duplicate_node_labels(code: &str, first_line: usize, encoding: PositionEncoding) -> Vec<Diagnostic> {
+    let mut first_seen: HashMap<&str, (&str, usize)> = HashMap::new();
+    let mut diagnostics = Vec::new();
+
+    // Skip the `graph TD` header
+    for (i, line) in code.lines().enumerate().skip(1) {
+        let line_no = first_line + i;
+        for decl in node_declarations(line) {
+            match first_seen.get(decl.id) {
+                None => {
+                    first_seen.insert(decl.id, (decl.label, line_no));
+                }
+                Some(&(label, _)) if label == decl.label => {}
+                Some(&(label, seen_line)) => diagnostics.push(warning(
+                    line_no,
+                    line,
+                    decl.start,
+                    decl.end,
+                    DUPLICATE_NODE_ID_CHECK,
+                    format!(
+                        "Node `{}` is already defined as \"{label}\" on line {}; \"{}\" will be ignored",
+                        decl.id,
+                        seen_line + 1,
+                        decl.label
+                    ),
+                    encoding,
+                )),
+            }
+        }
+    }
+
+    diagnostics
+}
+
+/// Find the labelled node declarations on one flowchart line
+pub fn node_declarations(line: &str) -> Vec<NodeDeclaration<'_>> {
+    let code = match line.find("%%") {
+        Some(i) => &line[..i],
+        None => line,
+    };
+    let first_word = code.split_whitespace().next().unwrap_or("");
+    if NON_DECLARATION_KEYWORDS.contains(&first_word) {
+        return Vec::new();
+    }
+
+    let mut declarations = Vec::new();
+    let mut search_from = 0;
+    while let Some(caps) = NODE_DECLARATION.captures_at(code, search_from) {
+        let id = caps.get(1).expect("id group");
+        let open = caps.get(2).expect("shape group");
+        // Tolerate unknown or unclosed shapes: stop scanning this line
+        let Some(close) = closer(open.as_str()) else {
+            break;
+        };
+        let Some(label_len) = find_closer(&code[open.end()..], close) else {
+            break;
+        };
+        let label = code[open.end()..open.end() + label_len].trim();
+        let end = open.end() + label_len + close.len();
+        declarations.push(NodeDeclaration {
+            id: id.as_str(),
+            label: label.trim_matches('"'),
+            start: id.start(),
+            end,
+        });
+        search_from = end;
+    }
+    declarations
+}
+
+fn closer(open: &str) -> Option<&'static str> {
+    Some(match open {
+        "(((" => ")))",
+        "((" => "))",
+        "[[" => "]]",
+        "[(" => ")]",
+        "([" => "])",
+        "{{" => "}}",
+        "[/" | "[\\" => "]",
+        "[" | ">" => "]",
+        "(" => ")",
+        "{" => "}",
+        _ => return None,
+    })
+}
+
+/// Byte length of the label before the shape's closing bracket
+fn find_closer(rest: &str, closer: &str) -> Option<usize> {
+    if let Some(quoted) = rest.strip_prefix('"') {
+        // Quoted labels may contain brackets
+        let end_quote = quoted.find('"')? + 2;
+        return rest[end_quote..].find(closer).map(|i| end_quote + i);
+    }
+    rest.find(closer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warns_on_conflicting_labels() {
+        let code = "flowchart TD\n  A[One] --> B\n  B --> A[Two]";
+        let diagnostics = check_diagram(code, 10, PositionEncoding::Utf16, &Config::default());
+
+        assert_eq!(diagnostics.len(), 1);
+        let diagnostic = &diagnostics[0];
+        assert_eq!(diagnostic.severity, Some(DiagnosticSeverity::WARNING));
+        assert_eq!(diagnostic.range.start, Position::new(12, 8));
+        assert_eq!(diagnostic.range.end, Position::new(12, 14));
+        assert!(diagnostic.message.contains("line 12"));
+    }
+
+    #[test]
+    fn warns_when_image_and_source_disagree() {
+        let line = "![Mermaid Diagram](.mermaid/doc_diagram_b.svg)";
+        let config = Config::default();
+        let check = |source: &str, image: &str| {
+            check_rendered_block(source, image, 4, line, PositionEncoding::Utf16, &config)
+        };
+        let diagnostic = check(".mermaid/doc_diagram_a.mmd", ".mermaid/doc_diagram_b.svg").unwrap();
+        assert_eq!(diagnostic.code, Some(NumberOrString::String(MISMATCHED_BLOCK_CHECK.to_string())));
+        assert_eq!(diagnostic.range, Range::new(Position::new(4, 19), Position::new(4, 45)));
+        assert!(diagnostic.message.contains("doc_diagram_a.mmd"));
+
+        assert_eq!(check(".mermaid/doc_diagram_b.mmd", ".mermaid/doc_diagram_b.svg"), None);
+        // Only base names count: the output directory may have moved
+        assert_eq!(check("diagrams/doc_diagram_b.mmd", ".mermaid/doc_diagram_b.svg"), None);
+
+        let config = Config {
+            disabled_checks: vec![MISMATCHED_BLOCK_CHECK.to_string()],
+            ..Config::default()
+        };
+        let disabled = check_rendered_block("a.mmd", ".mermaid/doc_diagram_b.svg", 4, line, PositionEncoding::Utf16, &config);
+        assert_eq!(disabled, None);
+    }
+
+    #[test]
+    fn consistent_reuse_is_fine() {
+        let code = "graph LR\n  A[One] --> B((Round))\n  B((Round)) --> A[One]\n  A --> C{\"x [y]\"}\n  C --> A";
+        assert!(check_diagram(code, 0, PositionEncoding::Utf16, &Config::default()).is_empty());
+    }
+
+    #[test]
+    fn parses_shapes_and_ignores_non_declarations() {
+        let decls = node_declarations("  db[(Database)] --> q{{Hex}} & s([Stadium]) %% A[Nope]");
+        let found: Vec<(&str, &str)> = decls.iter().map(|d| (d.id, d.label)).collect();
+        assert_eq!(found, vec![("db", "Database"), ("q", "Hex"), ("s", "Stadium")]);
+
+        assert!(node_declarations("  style A fill:#f9f").is_empty());
+        assert!(node_declarations("  A[unclosed --> B").is_empty());
+    }
+
+    #[test]
+    fn measures_ranges_in_the_negotiated_encoding() {
+        let code = "graph TD\n  図[A] --> 図[B]";
+        let utf8 = check_diagram(code, 0, PositionEncoding::Utf8, &Config::default());
+        let utf16 = check_diagram(code, 0, PositionEncoding::Utf16, &Config::default());
+        assert_eq!(utf8[0].range.start.character, 13);
+        assert_eq!(utf16[0].range.start.character, 11);
+    }
+
+    #[test]
+    fn checks_can_be_disabled() {
+        let config = Config {
+            disabled_checks: vec![GANTT_CHECK.to_string()],
+            ..Config::default()
+        };
+        let gantt = "gantt\n  section Empty";
+        assert!(!check_diagram(gantt, 0, PositionEncoding::Utf16, &Config::default()).is_empty());
+        assert!(check_diagram(gantt, 0, PositionEncoding::Utf16, &config).is_empty());
+
+        let flowchart = "graph TD\n  A[One] --> A[Two]";
+        assert_eq!(check_diagram(flowchart, 0, PositionEncoding::Utf16, &config).len(), 1);
+    }
+
+    #[test]
+    fn other_diagram_types_are_not_checked() {
+        let code = "sequenceDiagram\n  A->>B: [One]\n  A->>B: [Two]";
+        assert!(check_diagram(code, 0, PositionEncoding::Utf16, &Config::default()).is_empty());
+    }
+}
@@ -0,0 +1,385 @@
+use chrono::format::{self, Parsed, StrftimeItems};
+use lsp_types::Diagnostic;
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use super::{warning, GANTT_CHECK};
+use crate::position::PositionEncoding;
+
+/// Mermaid's default when a chart has no `dateFormat`
+const DEFAULT_DATE_FORMAT: &str = "YYYY-MM-DD";
+
+static DURATION: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^\d+(?:\.\d+)?(?:ms|s|m|h|d|w|y)$").expect("duration regex"));
+
+/// Statements that look like `keyword value` rather than `Task : ...`
+const KEYWORDS: &[&str] = &[
+    "title",
+    "dateFormat",
+    "axisFormat",
+    "tickInterval",
+    "excludes",
+    "includes",
+    "todayMarker",
+    "weekday",
+    "weekend",
+    "inclusiveEndDates",
+    "topAxis",
+    "displayMode",
+    "accTitle",
+    "accDescr",
+    "click",
+];
+
+const TASK_TAGS: &[&str] = &["active", "done", "crit", "milestone"];
+
+/// A `section` header and how many tasks follow it
+struct Section<'a> {
+    line_no: usize,
+    line: &'a str,
+    name: &'a str,
+    tasks: usize,
+}
+
+/// Check `dateFormat`/`axisFormat`, task dates and durations, and empty sections
+pub fn check(code: &str, first_line: usize, encoding: PositionEncoding) -> Vec<Diagnostic> {
+    let lines: Vec<&str> = code.lines().collect();
+    let mut diagnostics = Vec::new();
+
+    let date_format_line = lines
+        .iter()
+        .find_map(|l| l.trim().strip_prefix("dateFormat"))
+        .map(str::trim);
+    if date_format_line.is_none() {
+        if let Some((i, line)) = lines.iter().enumerate().find(|(_, l)| l.trim() == "gantt") {
+            let start = line.len() - line.trim_start().len();
+            diagnostics.push(warning(
+                first_line + i,
+                line,
+                start,
+                line.trim_end().len(),
+                GANTT_CHECK,
+                format!("Gantt chart has no dateFormat; dates are read as {DEFAULT_DATE_FORMAT}"),
+                encoding,
+            ));
+        }
+    }
+    let date_format = date_format_line.unwrap_or(DEFAULT_DATE_FORMAT);
+    // Unsupported dayjs tokens disable date validation rather than guessing
+    let strftime = to_strftime(date_format);
+
+    let mut section: Option<Section> = None;
+    for (i, line) in lines.iter().enumerate() {
+        let line_no = first_line + i;
+        let code_part = line.split("%%").next().unwrap_or("");
+        let trimmed = code_part.trim();
+        let indent = code_part.len() - code_part.trim_start().len();
+        let keyword = trimmed.split_whitespace().next().unwrap_or("");
+
+        if trimmed.is_empty() || keyword == "gantt" {
+            continue;
+        }
+        if keyword == "section" {
+            diagnostics.extend(empty_section(section.take(), encoding));
+            section = Some(Section {
+                line_no,
+                line,
+                name: trimmed["section".len()..].trim(),
+                tasks: 0,
+            });
+            continue;
+        }
+        if keyword == "axisFormat" {
+            let value = trimmed["axisFormat".len()..].trim();
+            if !is_valid_axis_format(value) {
+                let start = indent + trimmed.find(value).unwrap_or(0);
+                diagnostics.push(warning(
+                    line_no,
+                    line,
+                    start,
+                    start + value.len(),
+                    GANTT_CHECK,
+                    format!("axisFormat `{value}` is not a valid d3 time format (e.g. %Y-%m-%d)"),
+                    encoding,
+                ));
+            }
+            continue;
+        }
+        if KEYWORDS.contains(&keyword) {
+            continue;
+        }
+
+        // Task line: `Name : [tags,] [id,] [start,] end-or-duration`
+        let Some(colon) = code_part.find(':') else {
+            continue;
+        };
+        if let Some(section) = section.as_mut() {
+            section.tasks += 1;
+        }
+        let Some(strftime) = strftime.as_deref() else {
+            continue;
+        };
+        diagnostics.extend(check_task(
+            line_no,
+            line,
+            colon + 1,
+            &code_part[colon + 1..],
+            date_format,
+            strftime,
+            encoding,
+        ));
+    }
+    diagnostics.extend(empty_section(section, encoding));
+
+    diagnostics
+}
+
+fn empty_section(section: Option<Section>, encoding: PositionEncoding) -> Option<Diagnostic> {
+    let section = section.filter(|s| s.tasks == 0)?;
+    let start = section.line.len() - section.line.trim_start().len();
+    Some(warning(
+        section.line_no,
+        section.line,
+        start,
+        section.line.trim_end().len(),
+        GANTT_CHECK,
+        format!("Section `{}` has no tasks", section.name),
+        encoding,
+    ))
+}
+
+/// Validate the start and end of one task. `offset` is the byte offset of
+/// `metadata` within `line`.
+fn check_task(
+    line_no: usize,
+    line: &str,
+    offset: usize,
+    metadata: &str,
+    date_format: &str,
+    strftime: &str,
+    encoding: PositionEncoding,
+) -> Vec<Diagnostic> {
+    // Split on commas, keeping each item's byte range within the line
+    let mut items = Vec::new();
+    let mut pos = offset;
+    for raw in metadata.split(',') {
+        let lead = raw.len() - raw.trim_start().len();
+        let value = raw.trim();
+        items.push((value, pos + lead));
+        pos += raw.len() + 1;
+    }
+    let items: Vec<(&str, usize)> = items
+        .into_iter()
+        .skip_while(|(value, _)| TASK_TAGS.contains(value))
+        .collect();
+
+    let mut diagnostics = Vec::new();
+    let mut flag = |value: &str, start: usize, message: String| {
+        diagnostics.push(warning(
+            line_no,
+            line,
+            start,
+            start + value.len(),
+            GANTT_CHECK,
+            message,
+            encoding,
+        ));
+    };
+
+    let (start, end) = match items.as_slice() {
+        [] | [("", _)] => {
+            let start = line.len() - line.trim_start().len();
+            flag(
+                line[start..].trim_end(),
+                start,
+                "Task has no start date or duration".to_string(),
+            );
+            return diagnostics;
+        }
+        [end] => (None, *end),
+        [.., start, end] => (Some(*start), *end),
+    };
+
+    if let Some((value, pos)) = start {
+        if !value.starts_with("after ") && !matches_date_format(value, strftime) {
+            flag(
+                value,
+                pos,
+                format!("Start `{value}` does not match dateFormat `{date_format}`"),
+            );
+        }
+    }
+
+    let (value, pos) = end;
+    if !DURATION.is_match(value)
+        && !value.starts_with("until ")
+        && !matches_date_format(value, strftime)
+    {
+        flag(
+            value,
+            pos,
+            format!(
+                "`{value}` is neither a duration (e.g. 3d) nor a date in dateFormat `{date_format}`"
+            ),
+        );
+    }
+
+    diagnostics
+}
+
+/// Whether `value` parses with the strftime format, as a real calendar date
+/// when the format has year, month and day
+fn matches_date_format(value: &str, strftime: &str) -> bool {
+    let mut parsed = Parsed::new();
+    if format::parse(&mut parsed, value, StrftimeItems::new(strftime)).is_err() {
+        return false;
+    }
+    let full_date = ["%d", "%m"].iter().all(|f| strftime.contains(f))
+        && (strftime.contains("%Y") || strftime.contains("%y"));
+    !full_date || parsed.to_naive_date().is_ok()
+}
+
+/// Translate a dayjs format (as used by `dateFormat`) to chrono's strftime syntax.
+/// None if it uses tokens we don't support.
+fn to_strftime(format: &str) -> Option<String> {
+    const TOKENS: &[(&str, &str)] = &[
+        ("YYYY", "%Y"),
+        ("YY", "%y"),
+        ("MMMM", "%B"),
+        ("MMM", "%b"),
+        ("MM", "%m"),
+        ("M", "%m"),
+        ("DD", "%d"),
+        ("D", "%d"),
+        ("HH", "%H"),
+        ("H", "%H"),
+        ("hh", "%I"),
+        ("h", "%I"),
+        ("mm", "%M"),
+        ("m", "%M"),
+        ("ss", "%S"),
+        ("s", "%S"),
+        ("SSS", "%3f"),
+        ("A", "%p"),
+        ("a", "%p"),
+        ("X", "%s"),
+        ("ZZ", "%z"),
+        ("Z", "%:z"),
+    ];
+
+    let mut out = String::new();
+    let mut rest = format;
+    while let Some(c) = rest.chars().next() {
+        if c == '[' {
+            // `[literal]` escapes
+            let end = rest.find(']')?;
+            out.push_str(&rest[1..end].replace('%', "%%"));
+            rest = &rest[end + 1..];
+        } else if c.is_ascii_alphabetic() {
+            let (token, strftime) = TOKENS.iter().find(|(t, _)| rest.starts_with(t))?;
+            out.push_str(strftime);
+            rest = &rest[token.len()..];
+        } else {
+            if c == '%' {
+                out.push('%');
+            }
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    Some(out)
+}
+
+/// d3-time-format specifiers, optionally padded with `-`, `_` or `0`
+fn is_valid_axis_format(format: &str) -> bool {
+    const SPECIFIERS: &str = "aAbBcdefgGHIjLmMpqQsSuUVwWxXyYZ%";
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            continue;
+        }
+        let mut spec = chars.next();
+        if matches!(spec, Some('-' | '_' | '0')) {
+            spec = chars.next();
+        }
+        match spec {
+            Some(s) if SPECIFIERS.contains(s) => {}
+            _ => return false,
+        }
+    }
+    !format.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_types::Position;
+
+    fn messages(code: &str) -> Vec<(u32, u32, u32, String)> {
+        check(code, 0, PositionEncoding::Utf16)
+            .into_iter()
+            .map(|d| {
+                (
+                    d.range.start.line,
+                    d.range.start.character,
+                    d.range.end.character,
+                    d.message,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn valid_chart_has_no_warnings() {
+        let code = "gantt\n  dateFormat YYYY-MM-DD\n  axisFormat %m/%d\n  section Build\n  Design :done, des1, 2024-01-06, 2024-01-08\n  Code   :active, after des1, 3d\n  Ship   :milestone, 1d %% launch";
+        assert!(messages(code).is_empty());
+    }
+
+    #[test]
+    fn warns_when_date_format_is_missing() {
+        let found = messages("gantt\n  section A\n  Task :2024-01-01, 1d");
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].0, found[0].1, found[0].2), (0, 0, 5));
+        assert!(found[0].3.contains("dateFormat"));
+    }
+
+    #[test]
+    fn flags_dates_not_matching_the_format() {
+        let code = "gantt\n  dateFormat DD.MM.YYYY\n  Task :t1, 2024-01-06, 31.02.2024\n  Next :t2, 06.01.2024, 5x";
+        let found = messages(code);
+        assert_eq!(found.len(), 3);
+        // `2024-01-06` starts after `  Task :t1, `
+        assert_eq!((found[0].0, found[0].1, found[0].2), (2, 12, 22));
+        assert!(found[0].3.contains("DD.MM.YYYY"));
+        // 31 February is not a date
+        assert_eq!((found[1].0, found[1].1, found[1].2), (2, 24, 34));
+        assert_eq!((found[2].0, found[2].1, found[2].2), (3, 24, 26));
+    }
+
+    #[test]
+    fn warns_on_empty_sections_and_bad_axis_format() {
+        let code = "gantt\n  dateFormat YYYY-MM-DD\n  axisFormat %Y-%k\n  section Empty\n  section Full\n  Task :2024-01-01, 1d\n  section Trailing";
+        let found = messages(code);
+        assert_eq!(found.len(), 3);
+        assert_eq!((found[0].0, found[0].1, found[0].2), (2, 13, 18));
+        assert_eq!(found[1].0, 3);
+        assert!(found[1].3.contains("`Empty`"));
+        assert_eq!(found[2].0, 6);
+    }
+
+    #[test]
+    fn translates_dayjs_formats() {
+        assert_eq!(to_strftime("YYYY-MM-DD HH:mm").as_deref(), Some("%Y-%m-%d %H:%M"));
+        assert_eq!(to_strftime("[Q]D/M/YY").as_deref(), Some("Q%d/%m/%y"));
+        assert_eq!(to_strftime("Do MMM"), None);
+        assert!(matches_date_format("2024-02-29", "%Y-%m-%d"));
+        assert!(!matches_date_format("2023-02-29", "%Y-%m-%d"));
+    }
+
+    #[test]
+    fn ranges_use_document_lines() {
+        let diagnostics = check("gantt\n  section A", 7, PositionEncoding::Utf16);
+        assert_eq!(diagnostics[0].range.start, Position::new(7, 0));
+        assert_eq!(diagnostics[1].range.start, Position::new(8, 2));
+    }
+}
@@ -0,0 +1,254 @@
+use lsp_types::TextEdit;
+use std::{
+    borrow::Cow,
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use url::Url;
+
+use crate::error::{ServerError, ServerResult};
+use crate::paths;
+use crate::scheme::{self, DocumentLocation};
+
+/// Largest file commands read from disk
+pub const MAX_DISK_DOCUMENT_BYTES: u64 = 8 * 1024 * 1024;
+
+const UTF8_BOM: &str = "\u{feff}";
+
+/// Resolves a command's document URI to its text: the editor's buffer when
+/// the document is open, so unsaved edits count, otherwise the file on disk,
+/// which must be inside the workspace
+pub struct DocumentSource<'a> {
+    open: &'a HashMap<Url, String>,
+    workspace_root: Option<&'a Path>,
+    max_bytes: u64,
+}
+
+/// A document's text and where it came from
+#[derive(Debug)]
+pub struct SourcedDocument<'a> {
+    pub text: Cow<'a, str>,
+    pub origin: Origin,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Origin {
+    /// The editor's buffer, changed through the client
+    Open,
+    /// A file that isn't open, changed by writing it back
+    Disk(DiskFile),
+}
+
+/// A file as it was read, to tell whether it changed before it is written back
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiskFile {
+    pub path: PathBuf,
+    modified: Option<SystemTime>,
+    hash: u64,
+    bom: bool,
+}
+
+impl<'a> DocumentSource<'a> {
+    pub fn new(open: &'a HashMap<Url, String>, workspace_root: Option<&'a Path>) -> Self {
+        Self {
+            open,
+            workspace_root,
+            max_bytes: MAX_DISK_DOCUMENT_BYTES,
+        }
+    }
+
+    pub fn load(&self, uri: &Url) -> ServerResult<SourcedDocument<'a>> {
+        if let Some(text) = self.open.get(uri) {
+            return Ok(SourcedDocument {
+                text: Cow::Borrowed(text),
+                origin: Origin::Open,
+            });
+        }
+        let (text, file) = self.read_from_disk(uri)?;
+        Ok(SourcedDocument {
+            text: Cow::Owned(text),
+            origin: Origin::Disk(file),
+        })
+    }
+
+    /// The text of the file at `uri` as it is on disk, whether or not it is open
+    pub fn read_from_disk(&self, uri: &Url) -> ServerResult<(String, DiskFile)> {
+        let DocumentLocation::Local(path) = scheme::classify(uri) else {
+            return Err(ServerError::DocumentNotFound(uri.clone()));
+        };
+        let path = match path.canonicalize() {
+            Ok(path) => paths::simplify(&path),
+            // Neither open nor on disk
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(ServerError::DocumentNotFound(uri.clone())),
+            Err(e) => return Err(ServerError::io(format!("Failed to read {}", path.display()))(e)),
+        };
+        let root = self
+            .workspace_root
+            .and_then(|root| root.canonicalize().ok())
+            .map(|root| paths::simplify(&root))
+            .ok_or_else(|| ServerError::InvalidParams("no workspace folder to read files from".to_string()))?;
+        if !path.starts_with(&root) {
+            return Err(ServerError::InvalidParams(format!("{} is outside the workspace", path.display())));
+        }
+        read_file(&path, self.max_bytes)
+    }
+}
+
+impl SourcedDocument<'_> {
+    pub fn is_open(&self) -> bool {
+        self.origin == Origin::Open
+    }
+}
+
+impl DiskFile {
+    /// Replace the file's text with `text`, unless it changed since it was
+    /// read. A byte order mark it had is kept.
+    pub fn write(&self, text: &str) -> ServerResult<()> {
+        let bytes = fs::read(&self.path).map_err(ServerError::io(format!("Failed to read {}", self.path.display())))?;
+        let modified = fs::metadata(&self.path).and_then(|meta| meta.modified()).ok();
+        if modified != self.modified || hash_bytes(&bytes) != self.hash {
+            return Err(ServerError::ChangedOnDisk(self.path.clone()));
+        }
+        let text = if self.bom { Cow::Owned(format!("{UTF8_BOM}{text}")) } else { Cow::Borrowed(text) };
+        fs::write(&self.path, text.as_bytes()).map_err(ServerError::io(format!("Failed to write {}", self.path.display())))
+    }
+}
+
+/// The text of the file at `path`, which must be UTF-8 (a byte order mark is
+/// dropped) and at most `max_bytes` long
+fn read_file(path: &Path, max_bytes: u64) -> ServerResult<(String, DiskFile)> {
+    let meta = fs::metadata(path).map_err(ServerError::io(format!("Failed to read {}", path.display())))?;
+    if meta.len() > max_bytes {
+        return Err(ServerError::InvalidParams(format!(
+            "{} is larger than {max_bytes} bytes",
+            path.display()
+        )));
+    }
+    let bytes = fs::read(path).map_err(ServerError::io(format!("Failed to read {}", path.display())))?;
+    let file = DiskFile {
+        path: path.to_path_buf(),
+        modified: meta.modified().ok(),
+        hash: hash_bytes(&bytes),
+        bom: bytes.starts_with(UTF8_BOM.as_bytes()),
+    };
+    let text = String::from_utf8(bytes)
+        .map_err(|_| ServerError::InvalidParams(format!("{} is not UTF-8 text", path.display())))?;
+    let text = match text.strip_prefix(UTF8_BOM) {
+        Some(rest) => rest.to_string(),
+        None => text,
+    };
+    Ok((text, file))
+}
+
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// `text` with `edits` applied, their positions counted in UTF-8 bytes
+pub fn apply_edits(text: &str, edits: &[TextEdit]) -> String {
+    let line_starts: Vec<usize> = std::iter::once(0).chain(text.match_indices('\n').map(|(i, _)| i + 1)).collect();
+    let offset = |line: u32, character: u32| {
+        let Some(&start) = line_starts.get(line as usize) else {
+            return text.len();
+        };
+        let end = line_starts.get(line as usize + 1).map_or(text.len(), |next| next - 1);
+        let content = text[start..end].strip_suffix('\r').unwrap_or(&text[start..end]);
+        let mut at = (character as usize).min(content.len());
+        while !content.is_char_boundary(at) {
+            at -= 1;
+        }
+        start + at
+    };
+    let mut edits: Vec<(usize, usize, &str)> = edits
+        .iter()
+        .map(|edit| {
+            let (start, end) = (edit.range.start, edit.range.end);
+            (offset(start.line, start.character), offset(end.line, end.character), edit.new_text.as_str())
+        })
+        .collect();
+    edits.sort_by_key(|&(start, end, _)| std::cmp::Reverse((start, end)));
+    let mut out = text.to_string();
+    for (start, end, new_text) in edits {
+        out.replace_range(start..end.max(start), new_text);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_types::{Position, Range};
+
+    fn file_uri(path: &Path) -> Url {
+        Url::from_file_path(path).unwrap()
+    }
+
+    #[test]
+    fn prefers_the_editor_buffer_to_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.md");
+        fs::write(&path, "on disk\n").unwrap();
+        let open = HashMap::from([(file_uri(&path), "unsaved\n".to_string())]);
+        let doc = DocumentSource::new(&open, Some(dir.path())).load(&file_uri(&path)).unwrap();
+        assert_eq!(doc.text, "unsaved\n");
+        assert!(doc.is_open());
+
+        let closed = HashMap::new();
+        let source = DocumentSource::new(&closed, Some(dir.path()));
+        let doc = source.load(&file_uri(&path)).unwrap();
+        assert_eq!(doc.text, "on disk\n");
+        assert!(!doc.is_open());
+        let untitled = Url::parse("untitled:Untitled-1").unwrap();
+        assert!(matches!(source.load(&untitled), Err(ServerError::DocumentNotFound(_))));
+
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("b.md"), "elsewhere\n").unwrap();
+        let error = source.load(&file_uri(&outside.path().join("b.md"))).unwrap_err();
+        assert!(error.to_string().contains("outside the workspace"), "{error}");
+    }
+
+    #[test]
+    fn reads_utf8_with_or_without_a_bom_and_within_the_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let bom = dir.path().join("bom.md");
+        fs::write(&bom, "\u{feff}# Title\n").unwrap();
+        let (text, file) = read_file(&bom, MAX_DISK_DOCUMENT_BYTES).unwrap();
+        assert_eq!(text, "# Title\n");
+        file.write("# Changed\n").unwrap();
+        assert_eq!(fs::read_to_string(&bom).unwrap(), "\u{feff}# Changed\n");
+
+        let latin1 = dir.path().join("latin1.md");
+        fs::write(&latin1, b"caf\xe9\n").unwrap();
+        let error = read_file(&latin1, MAX_DISK_DOCUMENT_BYTES).unwrap_err();
+        assert!(error.to_string().contains("not UTF-8"), "{error}");
+        let error = read_file(&bom, 4).unwrap_err();
+        assert!(error.to_string().contains("larger than 4 bytes"), "{error}");
+    }
+
+    #[test]
+    fn refuses_to_write_back_a_file_changed_since_it_was_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.md");
+        fs::write(&path, "first\n").unwrap();
+        let (_, file) = read_file(&path, MAX_DISK_DOCUMENT_BYTES).unwrap();
+        fs::write(&path, "someone else\n").unwrap();
+        assert!(matches!(file.write("mine\n"), Err(ServerError::ChangedOnDisk(_))));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "someone else\n");
+    }
+
+    #[test]
+    fn applies_edits_at_utf8_positions() {
+        let edit = |from: (u32, u32), to: (u32, u32), text: &str| {
+            TextEdit::new(Range::new(Position::new(from.0, from.1), Position::new(to.0, to.1)), text.to_string())
+        };
+        let text = "# 図\r\nkeep\r\nold\r\n";
+        let edits = [edit((2, 0), (2, 3), "new"), edit((0, 2), (0, 5), "Figure"), edit((3, 0), (3, 0), "end\r\n")];
+        assert_eq!(apply_edits(text, &edits), "# Figure\r\nkeep\r\nnew\r\nend\r\n");
+    }
+}
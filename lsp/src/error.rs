@@ -0,0 +1,295 @@
+use lsp_server::{ErrorCode, RequestId, Response};
+use lsp_types::{Diagnostic, DiagnosticSeverity, MessageType, NumberOrString, Range};
+use std::{io, path::PathBuf};
+use thiserror::Error;
+use url::Url;
+
+pub type ServerResult<T> = Result<T, ServerError>;
+
+/// Failures of request handlers and the render/edit helpers
+#[derive(Debug, Error)]
+pub enum ServerError {
+    /// The request refers to a document the client hasn't opened
+    #[error("document is not open: {0}")]
+    DocumentNotFound(Url),
+    /// The document has no path on disk to write `.mermaid/` next to
+    #[error("cannot write rendered files: {}", crate::scheme::unsupported_reason(.0))]
+    NotLocalFile(Url),
+    /// The document or its output directory can't be written
+    #[error("cannot write rendered files: {0}")]
+    ReadOnly(String),
+    /// Missing or malformed request arguments
+    #[error("invalid parameters: {0}")]
+    InvalidParams(String),
+    /// mmdc (or npx for a pinned version) could not be found
+    #[error("{0}")]
+    ToolNotFound(String),
+    /// The diagram source was rejected before rendering
+    #[error("invalid diagram: {0}")]
+    ValidationFailed(String),
+    /// mmdc ran but didn't produce a diagram
+    #[error("rendering failed: {0}")]
+    RenderFailed(String),
+    /// The rendered SVG was rejected by the sanitizer
+    #[error("unsafe SVG: {0}")]
+    UnsafeSvg(String),
+    /// `maxRendersPerSession` diagrams were already rendered
+    #[error("render limit reached: {0} diagrams were rendered this session (maxRendersPerSession); run mermaid.resetLimits to render more")]
+    RenderLimitReached(usize),
+    /// A fence's remote diagram source couldn't be fetched, or may not be
+    #[error("cannot fetch diagram source {url}: {reason}")]
+    FetchFailed { url: String, reason: String },
+    /// A file read from disk changed before the edit to it was written
+    #[error("{} changed on disk since it was read; run the command again", .0.display())]
+    ChangedOnDisk(PathBuf),
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+    /// The client cancelled the request
+    #[error("request cancelled")]
+    Cancelled,
+    /// The connection to the client is gone
+    #[error("connection to the client closed")]
+    Disconnected,
+}
+
+impl ServerError {
+    /// Wrap an I/O error with what we were doing, for use with `map_err`
+    pub fn io(context: impl Into<String>) -> impl FnOnce(io::Error) -> Self {
+        let context = context.into();
+        move |source| Self::Io { context, source }
+    }
+
+    /// Short stable identifier, used as the diagnostic code
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::DocumentNotFound(_) => "document-not-found",
+            Self::NotLocalFile(_) => "not-local-file",
+            Self::ReadOnly(_) => "read-only",
+            Self::InvalidParams(_) => "invalid-params",
+            Self::ToolNotFound(_) => "tool-not-found",
+            Self::ValidationFailed(_) => "validation-failed",
+            Self::RenderFailed(_) => "render-failed",
+            Self::UnsafeSvg(_) => "unsafe-svg",
+            Self::RenderLimitReached(_) => "render-limit-reached",
+            Self::FetchFailed { .. } => "fetch-failed",
+            Self::ChangedOnDisk(_) => "changed-on-disk",
+            Self::Io { .. } => "io",
+            Self::Cancelled => "cancelled",
+            Self::Disconnected => "disconnected",
+        }
+    }
+
+    /// JSON-RPC error code for a failed request
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::DocumentNotFound(_) | Self::NotLocalFile(_) | Self::InvalidParams(_) => {
+                ErrorCode::InvalidParams
+            }
+            Self::ReadOnly(_)
+            | Self::ToolNotFound(_)
+            | Self::ValidationFailed(_)
+            | Self::RenderFailed(_)
+            | Self::UnsafeSvg(_)
+            | Self::RenderLimitReached(_)
+            | Self::FetchFailed { .. } => ErrorCode::RequestFailed,
+            Self::ChangedOnDisk(_) => ErrorCode::ContentModified,
+            Self::Cancelled => ErrorCode::RequestCanceled,
+            Self::Io { .. } | Self::Disconnected => ErrorCode::InternalError,
+        }
+    }
+
+    /// Severity when the error is attached to a diagram as a diagnostic
+    pub fn severity(&self) -> DiagnosticSeverity {
+        match self {
+            Self::ValidationFailed(_)
+            | Self::RenderFailed(_)
+            | Self::UnsafeSvg(_)
+            | Self::FetchFailed { .. } => DiagnosticSeverity::ERROR,
+            Self::DocumentNotFound(_)
+            | Self::NotLocalFile(_)
+            | Self::ReadOnly(_)
+            | Self::InvalidParams(_)
+            | Self::ToolNotFound(_)
+            | Self::RenderLimitReached(_)
+            | Self::ChangedOnDisk(_)
+            | Self::Io { .. }
+            | Self::Disconnected => DiagnosticSeverity::WARNING,
+            Self::Cancelled => DiagnosticSeverity::INFORMATION,
+        }
+    }
+
+    /// How to show the error to the user; None for errors that need no message
+    pub fn message_type(&self) -> Option<MessageType> {
+        match self {
+            Self::Cancelled | Self::Disconnected => None,
+            Self::DocumentNotFound(_)
+            | Self::NotLocalFile(_)
+            | Self::ReadOnly(_)
+            | Self::InvalidParams(_)
+            | Self::ToolNotFound(_)
+            | Self::RenderLimitReached(_)
+            | Self::ChangedOnDisk(_) => Some(MessageType::WARNING),
+            Self::ValidationFailed(_)
+            | Self::RenderFailed(_)
+            | Self::UnsafeSvg(_)
+            | Self::FetchFailed { .. }
+            | Self::Io { .. } => Some(MessageType::ERROR),
+        }
+    }
+
+    /// Error response for the request `id`
+    pub fn to_response(&self, id: RequestId) -> Response {
+        Response::new_err(id, self.code() as i32, self.to_string())
+    }
+
+    /// Diagnostic covering `range` (usually the failing fence)
+    pub fn to_diagnostic(&self, range: Range) -> Diagnostic {
+        Diagnostic {
+            range,
+            severity: Some(self.severity()),
+            code: Some(NumberOrString::String(self.kind().to_string())),
+            source: Some("mermaid".to_string()),
+            message: self.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<serde_json::Error> for ServerError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::InvalidParams(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_types::Position;
+
+    fn all_variants() -> Vec<ServerError> {
+        let uri = Url::parse("file:///docs/a.md").unwrap();
+        vec![
+            ServerError::DocumentNotFound(uri.clone()),
+            ServerError::NotLocalFile(uri),
+            ServerError::ReadOnly("/nix/store/docs is read-only".to_string()),
+            ServerError::InvalidParams("expected a URI".to_string()),
+            ServerError::ToolNotFound("mmdc not found".to_string()),
+            ServerError::ValidationFailed("Mermaid code is empty".to_string()),
+            ServerError::RenderFailed("Parse error on line 2".to_string()),
+            ServerError::UnsafeSvg("contains <script>".to_string()),
+            ServerError::RenderLimitReached(100),
+            ServerError::FetchFailed {
+                url: "https://example.com/flow.mmd".to_string(),
+                reason: "HTTP 404".to_string(),
+            },
+            ServerError::ChangedOnDisk(PathBuf::from("/docs/a.md")),
+            ServerError::io("Failed to write SVG")(io::Error::other("disk full")),
+            ServerError::Cancelled,
+            ServerError::Disconnected,
+        ]
+    }
+
+    #[test]
+    fn maps_each_variant_to_a_response_code() {
+        let codes: Vec<i32> = all_variants().iter().map(|e| e.code() as i32).collect();
+        assert_eq!(
+            codes,
+            vec![
+                ErrorCode::InvalidParams as i32,
+                ErrorCode::InvalidParams as i32,
+                ErrorCode::RequestFailed as i32,
+                ErrorCode::InvalidParams as i32,
+                ErrorCode::RequestFailed as i32,
+                ErrorCode::RequestFailed as i32,
+                ErrorCode::RequestFailed as i32,
+                ErrorCode::RequestFailed as i32,
+                ErrorCode::RequestFailed as i32,
+                ErrorCode::RequestFailed as i32,
+                ErrorCode::ContentModified as i32,
+                ErrorCode::InternalError as i32,
+                ErrorCode::RequestCanceled as i32,
+                ErrorCode::InternalError as i32,
+            ]
+        );
+    }
+
+    #[test]
+    fn maps_each_variant_to_a_severity() {
+        let severities: Vec<DiagnosticSeverity> =
+            all_variants().iter().map(ServerError::severity).collect();
+        assert_eq!(
+            severities,
+            vec![
+                DiagnosticSeverity::WARNING,
+                DiagnosticSeverity::WARNING,
+                DiagnosticSeverity::WARNING,
+                DiagnosticSeverity::WARNING,
+                DiagnosticSeverity::WARNING,
+                DiagnosticSeverity::ERROR,
+                DiagnosticSeverity::ERROR,
+                DiagnosticSeverity::ERROR,
+                DiagnosticSeverity::WARNING,
+                DiagnosticSeverity::ERROR,
+                DiagnosticSeverity::WARNING,
+                DiagnosticSeverity::WARNING,
+                DiagnosticSeverity::INFORMATION,
+                DiagnosticSeverity::WARNING,
+            ]
+        );
+    }
+
+    #[test]
+    fn maps_each_variant_to_a_message_type() {
+        let types: Vec<Option<MessageType>> =
+            all_variants().iter().map(ServerError::message_type).collect();
+        assert_eq!(
+            types,
+            vec![
+                Some(MessageType::WARNING),
+                Some(MessageType::WARNING),
+                Some(MessageType::WARNING),
+                Some(MessageType::WARNING),
+                Some(MessageType::WARNING),
+                Some(MessageType::ERROR),
+                Some(MessageType::ERROR),
+                Some(MessageType::ERROR),
+                Some(MessageType::WARNING),
+                Some(MessageType::ERROR),
+                Some(MessageType::WARNING),
+                Some(MessageType::ERROR),
+                None,
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn builds_responses_and_diagnostics() {
+        let error = ServerError::RenderFailed("Parse error on line 2".to_string());
+
+        let resp = error.to_response(RequestId::from(7));
+        let resp_error = resp.error.unwrap();
+        assert_eq!(resp_error.code, ErrorCode::RequestFailed as i32);
+        assert_eq!(resp_error.message, "rendering failed: Parse error on line 2");
+
+        let range = Range::new(Position::new(2, 0), Position::new(5, 3));
+        let diagnostic = error.to_diagnostic(range);
+        assert_eq!(diagnostic.range, range);
+        assert_eq!(diagnostic.severity, Some(DiagnosticSeverity::ERROR));
+        assert_eq!(
+            diagnostic.code,
+            Some(NumberOrString::String("render-failed".to_string()))
+        );
+        assert_eq!(diagnostic.source.as_deref(), Some("mermaid"));
+    }
+
+    #[test]
+    fn io_errors_keep_their_context() {
+        let error = ServerError::io("Failed to write SVG")(io::Error::other("disk full"));
+        assert_eq!(error.to_string(), "Failed to write SVG: disk full");
+    }
+}
@@ -0,0 +1,87 @@
+/// Lines around the section written by `mermaid.generateIndex`; everything
+/// between them is replaced when the index is generated again
+pub const INDEX_START: &str = "<!-- mermaid-index -->";
+pub const INDEX_END: &str = "<!-- /mermaid-index -->";
+
+const INDEX_HEADING: &str = "## List of Figures";
+
+/// A rendered diagram's entry in the index
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Figure {
+    /// 1-based, in document order
+    pub number: usize,
+    /// Id of the anchor above the diagram
+    pub anchor: String,
+    /// Title from the diagram's source, if it has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+}
+
+impl Figure {
+    /// `Figure 2: Auth flow`, or `Figure 2` for an untitled diagram
+    pub fn label(&self) -> String {
+        match &self.title {
+            Some(title) => format!("Figure {}: {title}", self.number),
+            None => format!("Figure {}", self.number),
+        }
+    }
+}
+
+/// The index section, markers included
+pub fn index_section(figures: &[Figure]) -> String {
+    let mut section = format!("{INDEX_START}\n{INDEX_HEADING}\n\n");
+    for figure in figures {
+        let label = figure.label().replace('[', "\\[").replace(']', "\\]");
+        section.push_str(&format!("{}. [{label}](#{})\n", figure.number, figure.anchor));
+    }
+    if figures.is_empty() {
+        section.push_str("No rendered diagrams.\n");
+    }
+    section.push_str(INDEX_END);
+    section
+}
+
+/// First and last line of an index written before
+pub fn find_index_section(lines: &[&str]) -> Option<(usize, usize)> {
+    let start = lines.iter().position(|line| line.trim() == INDEX_START)?;
+    let end = (start + 1..lines.len()).find(|&i| lines[i].trim() == INDEX_END)?;
+    Some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn figure(number: usize, anchor: &str, title: Option<&str>) -> Figure {
+        Figure {
+            number,
+            anchor: anchor.to_string(),
+            title: title.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn lists_figures_with_links() {
+        let section = index_section(&[
+            figure(1, "diagram-auth-flow", Some("Auth [v2] flow")),
+            figure(2, "diagram-flowchart", None),
+        ]);
+        assert_eq!(
+            section,
+            "<!-- mermaid-index -->\n## List of Figures\n\n\
+             1. [Figure 1: Auth \\[v2\\] flow](#diagram-auth-flow)\n\
+             2. [Figure 2](#diagram-flowchart)\n\
+             <!-- /mermaid-index -->"
+        );
+        assert!(index_section(&[]).contains("No rendered diagrams."));
+    }
+
+    #[test]
+    fn finds_a_previous_index() {
+        let section = index_section(&[figure(1, "diagram", None)]);
+        let doc = format!("# Doc\n\n{section}\n\nText\n");
+        let lines: Vec<&str> = doc.lines().collect();
+        assert_eq!(find_index_section(&lines), Some((2, 6)));
+        assert_eq!(find_index_section(&["# Doc", INDEX_START, "unterminated"]), None);
+    }
+}
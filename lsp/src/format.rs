@@ -0,0 +1,166 @@
+use crate::diagnostics::DiagramType;
+use crate::quickfix::straighten_quotes;
+
+/// Indentation of one nesting level: the statements under the header, and
+/// each `subgraph` inside them
+const INDENT: &str = "    ";
+
+/// Characters a flowchart link is drawn with
+const LINK_CHARS: &[char] = &['-', '=', '.', '~', '<', '>'];
+
+/// Statements whose arguments aren't nodes and links, such as the dashes in
+/// `stroke-dasharray`, and are only reindented
+const NON_LINK_STATEMENTS: &[&str] = &["classDef", "class", "style", "linkStyle", "click", "direction", "accTitle", "accDescr"];
+
+/// `code` in the canonical style, or None for diagram types the formatter
+/// doesn't know: statements indented by nesting level, one space around each
+/// link, straight quotes, no trailing whitespace and no runs of blank lines.
+/// Front matter and comment text are kept as written.
+pub fn format_diagram(code: &str) -> Option<String> {
+    let lines: Vec<&str> = code.lines().collect();
+    let mut out: Vec<String> = Vec::new();
+    let mut rest = &lines[..];
+    if lines.first().is_some_and(|line| line.trim() == "---") {
+        if let Some(close) = lines.iter().skip(1).position(|line| line.trim() == "---") {
+            out.extend(lines[..close + 2].iter().map(|line| line.trim_end().to_string()));
+            rest = &lines[close + 2..];
+        }
+    }
+    if DiagramType::detect(&rest.join("\n")) != Some(DiagramType::Flowchart) {
+        return None;
+    }
+
+    let mut header_seen = false;
+    let mut depth = 0usize;
+    let mut blank = false;
+    for line in rest {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            blank = !out.is_empty();
+            continue;
+        }
+        if std::mem::take(&mut blank) {
+            out.push(String::new());
+        }
+        let keyword = trimmed.split_whitespace().next().unwrap_or_default();
+        let (level, statement) = if trimmed.starts_with("%%") {
+            (if header_seen { depth + 1 } else { 0 }, trimmed.to_string())
+        } else if !header_seen {
+            header_seen = true;
+            (0, trimmed.split_whitespace().collect::<Vec<_>>().join(" "))
+        } else {
+            if keyword == "end" {
+                depth = depth.saturating_sub(1);
+            }
+            let straight = straighten_quotes(trimmed).unwrap_or_else(|| trimmed.to_string());
+            let statement = if NON_LINK_STATEMENTS.contains(&keyword) { straight } else { space_links(&straight) };
+            (depth + 1, statement)
+        };
+        out.push(format!("{}{statement}", INDENT.repeat(level)));
+        if keyword == "subgraph" {
+            depth += 1;
+        }
+    }
+    Some(out.join("\n"))
+}
+
+/// `statement` with exactly one space on each side of its links (and their
+/// `|label|`). Links inside labels and quotes are left alone, and so is a
+/// statement whose links can't be told apart from the nodes.
+fn space_links(statement: &str) -> String {
+    let chars: Vec<char> = statement.chars().collect();
+    let mut out = String::new();
+    let mut depth = 0usize;
+    let mut quoted = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '"' => quoted = !quoted,
+            _ if quoted => {}
+            '[' | '(' | '{' => depth += 1,
+            ']' | ')' | '}' => depth = depth.saturating_sub(1),
+            _ if depth == 0 && LINK_CHARS.contains(&c) => {
+                let end = i + chars[i..].iter().take_while(|c| LINK_CHARS.contains(c)).count();
+                let run: String = chars[i..end].iter().collect();
+                if is_link(&run) {
+                    let mut end = end;
+                    let mut link = run;
+                    // Circle and cross ends: `--o`, `--x`
+                    if link.ends_with(['-', '=']) && matches!(chars.get(end), Some('o' | 'x')) {
+                        match chars.get(end + 1) {
+                            None => {}
+                            Some(next) if next.is_whitespace() => {}
+                            // `A--oB` could be a link to `oB` or a circle link to `B`
+                            Some(_) => return statement.to_string(),
+                        }
+                        link.push(chars[end]);
+                        end += 1;
+                    }
+                    let mut after = end + chars[end..].iter().take_while(|c| c.is_whitespace()).count();
+                    if chars.get(after) == Some(&'|') {
+                        let Some(close) = chars[after + 1..].iter().position(|&c| c == '|') else {
+                            return statement.to_string();
+                        };
+                        link.push_str(&chars[after..after + close + 2].iter().collect::<String>());
+                        after += close + 2;
+                        after += chars[after..].iter().take_while(|c| c.is_whitespace()).count();
+                    }
+                    let kept = out.trim_end().len();
+                    out.truncate(kept);
+                    if !out.is_empty() {
+                        out.push(' ');
+                    }
+                    out.push_str(&link);
+                    if after < chars.len() {
+                        out.push(' ');
+                    }
+                    i = after;
+                    continue;
+                }
+                out.extend(&chars[i..end]);
+                i = end;
+                continue;
+            }
+            _ => {}
+        }
+        out.push(c);
+        i += 1;
+    }
+    out
+}
+
+/// Whether a run of link characters draws a link rather than, say, the dash
+/// in a node id
+fn is_link(run: &str) -> bool {
+    run.len() >= 2 && ["--", "==", "-.", ".-", "~~~"].iter().any(|link| run.contains(link))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_indentation_links_and_quotes() {
+        let messy = "graph   TD\n\n\n  A-->B\n\tB -->|yes|C\n  C--  text  -->D\n      subgraph api [API]\n  D==>E\n  E-.->F\n end\n  F --o G\n  %% keep  this\n  G[\u{201C}a-->b\u{201D}]~~~H   \n\n";
+        let formatted = format_diagram(messy).unwrap();
+        assert_eq!(
+            formatted,
+            "graph TD\n\n    A --> B\n    B -->|yes| C\n    C -- text --> D\n    subgraph api [API]\n        D ==> E\n        E -.-> F\n    end\n    F --o G\n    %% keep  this\n    G[\"a-->b\"] ~~~ H"
+        );
+        assert_eq!(format_diagram(&formatted).as_deref(), Some(formatted.as_str()));
+    }
+
+    #[test]
+    fn leaves_unknown_types_and_ambiguous_links_alone() {
+        assert_eq!(format_diagram("sequenceDiagram\n  A->>B: hi"), None);
+        assert_eq!(format_diagram("gantt\n  title x"), None);
+        // Node ids with dashes, styles and unclear circle links
+        assert_eq!(
+            format_diagram("flowchart LR\n  my-node-->b\n  style a stroke-dasharray: 5--5\n  a--ob").unwrap(),
+            "flowchart LR\n    my-node --> b\n    style a stroke-dasharray: 5--5\n    a--ob"
+        );
+        let front_matter = "---\ntitle: Flow  \n---\ngraph TD\nA-->B";
+        assert_eq!(format_diagram(front_matter).unwrap(), "---\ntitle: Flow\n---\ngraph TD\n    A --> B");
+    }
+}
@@ -0,0 +1,341 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use log::warn;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+use crate::config::{Config, GitignoreCheck};
+use crate::i18n::{text, Text};
+use crate::paths;
+
+const STORE_FILE: &str = "gitignore-checked.json";
+const STORE_VERSION: u32 = 1;
+
+/// Comment written above the entry appended to `.gitignore`
+const ENTRY_COMMENT: &str = "# Mermaid Preview render cache";
+
+/// Worktrees checked so far, and the output directories waiting to be checked
+static CHECKS: Lazy<Mutex<GitignoreChecks>> = Lazy::new(|| Mutex::new(GitignoreChecks::new(None)));
+
+/// The session's gitignore checks
+pub fn checks() -> MutexGuard<'static, GitignoreChecks> {
+    CHECKS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Answer to the prompt offering to ignore the generated files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Answer {
+    Add,
+    Skip,
+}
+
+impl Answer {
+    pub const ALL: [Answer; 2] = [Answer::Add, Answer::Skip];
+
+    /// Button text in the prompt
+    pub fn label(self) -> &'static str {
+        text(match self {
+            Answer::Add => Text::GitignoreAdd,
+            Answer::Skip => Text::GitignoreSkip,
+        })
+    }
+
+    pub fn from_label(label: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|answer| answer.label() == label)
+    }
+}
+
+/// A `.gitignore` entry offered for a worktree whose generated files git
+/// doesn't ignore
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Suggestion {
+    /// The directory that should be ignored
+    pub dir: PathBuf,
+    /// `.gitignore` at the root of the worktree
+    pub gitignore: PathBuf,
+    /// Pattern to append to it
+    pub entry: String,
+}
+
+impl Suggestion {
+    /// Text appended to a `.gitignore` with `existing` as its content
+    pub fn appended_text(&self, existing: &str) -> String {
+        let separator = if existing.is_empty() || existing.ends_with('\n') { "" } else { "\n" };
+        format!("{separator}{ENTRY_COMMENT}\n{}\n", self.entry)
+    }
+}
+
+/// Contents of the store file
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StoreFile {
+    version: u32,
+    /// Roots of the worktrees already checked
+    checked: BTreeSet<String>,
+}
+
+/// Whether the files rendered into each git worktree are ignored. A worktree
+/// is checked after the first render into it, once: the answer (or the lack of
+/// need for one) is remembered across sessions.
+#[derive(Debug)]
+pub struct GitignoreChecks {
+    /// Where checked worktrees are saved; None keeps them for the session
+    file: Option<PathBuf>,
+    checked: BTreeSet<String>,
+    /// Output directories rendered into since the last `take_suggestions`
+    rendered: Vec<PathBuf>,
+}
+
+impl GitignoreChecks {
+    /// Checks saving the worktrees checked in `dir`
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        let file = dir.map(|dir| dir.join(STORE_FILE));
+        let checked = file
+            .as_ref()
+            .and_then(|file| fs::read_to_string(file).ok())
+            .and_then(|json| serde_json::from_str::<StoreFile>(&json).ok())
+            .filter(|store| store.version == STORE_VERSION)
+            .map(|store| store.checked)
+            .unwrap_or_default();
+        Self {
+            file,
+            checked,
+            rendered: Vec::new(),
+        }
+    }
+
+    /// Remember that diagrams were rendered into `output_dir`
+    pub fn note_render(&mut self, output_dir: &Path) {
+        if !self.rendered.iter().any(|dir| dir == output_dir) {
+            self.rendered.push(output_dir.to_path_buf());
+        }
+    }
+
+    /// Check the worktrees rendered into since the last call that weren't
+    /// checked before, returning an entry for each one that doesn't ignore
+    /// its generated files
+    pub fn take_suggestions(&mut self, config: &Config) -> Vec<Suggestion> {
+        let rendered = std::mem::take(&mut self.rendered);
+        if config.gitignore_check == GitignoreCheck::Off {
+            return Vec::new();
+        }
+        let mut suggestions = Vec::new();
+        let checked_before = self.checked.len();
+        for output_dir in rendered {
+            let Some(worktree) = worktree_of(&output_dir) else {
+                continue;
+            };
+            if !self.checked.insert(worktree.display().to_string()) {
+                continue;
+            }
+            suggestions.extend(suggest(&worktree, &output_dir, config));
+        }
+        if self.checked.len() != checked_before {
+            self.save();
+        }
+        suggestions
+    }
+
+    fn save(&self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let store = StoreFile {
+            version: STORE_VERSION,
+            checked: self.checked.clone(),
+        };
+        let saved = serde_json::to_string_pretty(&store)
+            .map_err(|e| e.to_string())
+            .and_then(|json| {
+                if let Some(dir) = file.parent() {
+                    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+                }
+                fs::write(file, json).map_err(|e| e.to_string())
+            });
+        if let Err(e) = saved {
+            warn!("Failed to save checked worktrees to {}: {e}", file.display());
+        }
+    }
+}
+
+/// Root of the git worktree containing `path`: its closest ancestor with a
+/// `.git` directory (or file, in linked worktrees and submodules)
+pub fn worktree_of(path: &Path) -> Option<PathBuf> {
+    let path = paths::resolve(path);
+    path.ancestors()
+        .find(|dir| dir.join(".git").exists())
+        .map(Path::to_path_buf)
+}
+
+/// The entry to offer when git doesn't ignore the files rendered into
+/// `output_dir`, by `gitignoreCheck`: its `.cache` subdirectory, or all of it
+pub fn suggest(worktree: &Path, output_dir: &Path, config: &Config) -> Option<Suggestion> {
+    let output_dir = paths::resolve(output_dir);
+    let (dir, suffix) = match config.gitignore_check {
+        GitignoreCheck::Off => return None,
+        GitignoreCheck::Cache => (output_dir.join(".cache"), ".cache/"),
+        GitignoreCheck::OutputDir => (output_dir.clone(), ""),
+    };
+    let relative = output_dir.strip_prefix(worktree).ok()?;
+    if relative.as_os_str().is_empty() || is_ignored(worktree, &dir) {
+        return None;
+    }
+    // A bare directory name, like the default `.mermaid`, is created next to
+    // every rendered document, so the entry matches it at any depth
+    let configured = config.output_dir.as_deref().unwrap_or(".mermaid");
+    let mut components = Path::new(configured).components();
+    let entry = match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => match suffix {
+            "" => format!("{}/", name.to_string_lossy()),
+            _ => format!("**/{}/{suffix}", name.to_string_lossy()),
+        },
+        _ => format!("/{}/{suffix}", relative.to_string_lossy().replace('\\', "/")),
+    };
+    Some(Suggestion {
+        dir,
+        gitignore: worktree.join(".gitignore"),
+        entry,
+    })
+}
+
+/// Whether git ignores the directory `dir` inside `worktree`, going by the
+/// user's global excludes, `.git/info/exclude` and the `.gitignore` files
+/// from the worktree's root down to `dir`
+pub fn is_ignored(worktree: &Path, dir: &Path) -> bool {
+    let Ok(relative) = dir.strip_prefix(worktree) else {
+        return false;
+    };
+    let mut exclude = GitignoreBuilder::new(worktree);
+    exclude.add(worktree.join(".git/info/exclude"));
+    let mut matchers = vec![
+        (PathBuf::new(), Gitignore::global().0),
+        (PathBuf::new(), exclude.build().unwrap_or_else(|_| Gitignore::empty())),
+    ];
+    // Deeper `.gitignore` files take precedence, so they come last
+    let mut base = PathBuf::new();
+    for component in Some(Component::CurDir).into_iter().chain(relative.components()) {
+        if let Component::Normal(name) = component {
+            base.push(name);
+        }
+        let file = worktree.join(&base).join(".gitignore");
+        if base.as_path() != relative && file.is_file() {
+            matchers.push((base.clone(), Gitignore::new(&file).0));
+        }
+    }
+
+    let mut ignored = false;
+    for (base, matcher) in &matchers {
+        let Ok(path) = relative.strip_prefix(base) else {
+            continue;
+        };
+        match matcher.matched_path_or_any_parents(path, true) {
+            Match::Ignore(_) => ignored = true,
+            Match::Whitelist(_) => ignored = false,
+            Match::None => {}
+        }
+    }
+    ignored
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A worktree with a document rendered into `docs/.mermaid`
+    fn worktree() -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join(".git/info")).unwrap();
+        fs::create_dir_all(root.join("docs/.mermaid/.cache")).unwrap();
+        (dir, root)
+    }
+
+    fn config(check: GitignoreCheck, output_dir: Option<&str>) -> Config {
+        Config {
+            gitignore_check: check,
+            output_dir: output_dir.map(str::to_string),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn follows_gitignore_rules() {
+        let (_dir, root) = worktree();
+        let cache = root.join("docs/.mermaid/.cache");
+        assert!(!is_ignored(&root, &cache));
+
+        fs::write(root.join(".gitignore"), "# generated\n.cache/\n").unwrap();
+        assert!(is_ignored(&root, &cache));
+        // A deeper .gitignore wins
+        fs::write(root.join("docs/.gitignore"), "!.cache/\n").unwrap();
+        assert!(!is_ignored(&root, &cache));
+        fs::remove_file(root.join("docs/.gitignore")).unwrap();
+
+        // Ignoring a parent ignores everything in it; anchored patterns are
+        // relative to their .gitignore
+        fs::write(root.join(".gitignore"), "/docs/.mermaid/\n").unwrap();
+        assert!(is_ignored(&root, &cache));
+        fs::write(root.join(".gitignore"), "/.mermaid/\n").unwrap();
+        assert!(!is_ignored(&root, &cache));
+        fs::write(root.join(".git/info/exclude"), "**/.mermaid/.cache/\n").unwrap();
+        assert!(is_ignored(&root, &cache));
+    }
+
+    #[test]
+    fn suggests_an_entry_matching_the_output_dir() {
+        let (_dir, root) = worktree();
+        let output_dir = root.join("docs/.mermaid");
+
+        let cache = suggest(&root, &output_dir, &config(GitignoreCheck::Cache, None)).unwrap();
+        assert_eq!(cache.entry, "**/.mermaid/.cache/");
+        assert_eq!(cache.gitignore, root.join(".gitignore"));
+        let whole = suggest(&root, &output_dir, &config(GitignoreCheck::OutputDir, None)).unwrap();
+        assert_eq!(whole.entry, ".mermaid/");
+        let nested = suggest(&root, &output_dir, &config(GitignoreCheck::Cache, Some("../docs/.mermaid"))).unwrap();
+        assert_eq!(nested.entry, "/docs/.mermaid/.cache/");
+        assert_eq!(suggest(&root, &output_dir, &config(GitignoreCheck::Off, None)), None);
+
+        // Appending the entry is enough to silence the check
+        fs::write(root.join(".gitignore"), "target").unwrap();
+        let appended = cache.appended_text("target");
+        assert_eq!(appended, "\n# Mermaid Preview render cache\n**/.mermaid/.cache/\n");
+        fs::write(root.join(".gitignore"), format!("target{appended}")).unwrap();
+        assert_eq!(suggest(&root, &output_dir, &config(GitignoreCheck::Cache, None)), None);
+        // Outside any worktree there's nothing to check
+        assert_eq!(suggest(&root, Path::new("/tmp/diagrams"), &config(GitignoreCheck::Cache, None)), None);
+    }
+
+    #[test]
+    fn each_worktree_is_checked_once() {
+        let (_dir, root) = worktree();
+        let store = tempfile::tempdir().unwrap();
+        let output_dir = root.join("docs/.mermaid");
+        let cache = config(GitignoreCheck::Cache, None);
+
+        let mut checks = GitignoreChecks::new(Some(store.path().to_path_buf()));
+        assert!(checks.take_suggestions(&cache).is_empty());
+        checks.note_render(&output_dir);
+        checks.note_render(&output_dir);
+        assert_eq!(checks.take_suggestions(&cache).len(), 1);
+        checks.note_render(&output_dir);
+        assert!(checks.take_suggestions(&cache).is_empty());
+
+        // Remembered by the next session
+        let mut next = GitignoreChecks::new(Some(store.path().to_path_buf()));
+        next.note_render(&output_dir);
+        assert!(next.take_suggestions(&cache).is_empty());
+
+        // Disabled, renders aren't checked (nor remembered as checked)
+        let mut disabled = GitignoreChecks::new(None);
+        disabled.note_render(&output_dir);
+        assert!(disabled.take_suggestions(&config(GitignoreCheck::Off, None)).is_empty());
+        disabled.note_render(&output_dir);
+        assert_eq!(disabled.take_suggestions(&cache).len(), 1);
+    }
+}
@@ -0,0 +1,151 @@
+use std::cell::Cell;
+
+/// User-facing strings shown in the editor's UI, looked up by key so they can
+/// be translated in one place
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Text {
+    RenderDiagram,
+    /// The fence's SVG is already cached, so rendering is instant
+    RenderDiagramCached,
+    /// No render backend is available for the fence
+    RenderDiagramUnavailable,
+    EditSource,
+    RenderAll,
+    EditAllSources,
+    SplitSubgraph,
+    /// Mark the fence as ignored
+    IgnoreDiagram,
+    /// Remove the fence's ignore marker
+    UnignoreDiagram,
+    TrustAllow,
+    TrustDeny,
+    TrustAlwaysAllow,
+    /// Append the suggested entry to `.gitignore`
+    GitignoreAdd,
+    GitignoreSkip,
+    /// Label of the server's `workspace/applyEdit` requests, shown in undo history
+    EditLabel,
+    /// Label of the edit appending to `.gitignore`
+    GitignoreEditLabel,
+    /// Replace typographic quotes in a fence with straight ones
+    FixSmartQuotes,
+    /// Turn a flowchart's bare `->` links into `-->`
+    FixFlowchartArrows,
+    /// Insert `flowchart TD` above a headerless flowchart
+    AddFlowchartHeader,
+    RealignRenderedBlock,
+}
+
+/// Languages the UI strings are translated to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Locale {
+    #[default]
+    En,
+    Ja,
+}
+
+impl Locale {
+    /// The locale for a tag such as `ja` or `ja-JP`; English for the rest
+    pub fn from_tag(tag: &str) -> Self {
+        let language = tag.split(['-', '_']).next().unwrap_or_default();
+        match language.to_ascii_lowercase().as_str() {
+            "ja" => Locale::Ja,
+            _ => Locale::En,
+        }
+    }
+}
+
+thread_local! {
+    /// Locale of the session served on this thread
+    static LOCALE: Cell<Locale> = const { Cell::new(Locale::En) };
+}
+
+/// Show UI strings in `locale` from now on
+pub fn set_locale(locale: Locale) {
+    LOCALE.with(|current| current.set(locale));
+}
+
+/// The text for `key` in the session's locale
+pub fn text(key: Text) -> &'static str {
+    text_in(LOCALE.with(Cell::get), key)
+}
+
+/// The text for `key` in `locale`, in English when it has no translation
+pub fn text_in(locale: Locale, key: Text) -> &'static str {
+    match locale {
+        Locale::En => None,
+        Locale::Ja => japanese(key),
+    }
+    .unwrap_or_else(|| english(key))
+}
+
+fn english(key: Text) -> &'static str {
+    match key {
+        Text::RenderDiagram => "Render Mermaid Diagram",
+        Text::RenderDiagramCached => "Render Mermaid Diagram (cached)",
+        Text::RenderDiagramUnavailable => "Render Mermaid Diagram (mermaid-cli not found)",
+        Text::EditSource => "Edit Mermaid Source",
+        Text::RenderAll => "Render All Mermaid Diagrams",
+        Text::EditAllSources => "Edit All Mermaid Sources",
+        Text::SplitSubgraph => "Split Subgraph into Separate Diagram",
+        Text::IgnoreDiagram => "Ignore Mermaid Diagram",
+        Text::UnignoreDiagram => "Unignore Mermaid Diagram",
+        Text::TrustAllow => "Allow",
+        Text::TrustDeny => "Deny",
+        Text::TrustAlwaysAllow => "Always Allow",
+        Text::GitignoreAdd => "Add to .gitignore",
+        Text::GitignoreSkip => "Don't Add",
+        Text::EditLabel => "Mermaid",
+        Text::GitignoreEditLabel => "Mermaid: ignore generated files",
+        Text::FixSmartQuotes => "Replace Smart Quotes with Straight Quotes",
+        Text::FixFlowchartArrows => "Replace `->` with `-->`",
+        Text::AddFlowchartHeader => "Add `flowchart TD` Header",
+        Text::RealignRenderedBlock => "Re-render Mermaid Diagram to Match Its Source",
+    }
+}
+
+fn japanese(key: Text) -> Option<&'static str> {
+    Some(match key {
+        Text::RenderDiagram => "Mermaid 図をレンダリング",
+        Text::RenderDiagramCached => "Mermaid 図をレンダリング (キャッシュ済み)",
+        Text::RenderDiagramUnavailable => "Mermaid 図をレンダリング (mermaid-cli が見つかりません)",
+        Text::EditSource => "Mermaid ソースを編集",
+        Text::RenderAll => "すべての Mermaid 図をレンダリング",
+        Text::EditAllSources => "すべての Mermaid ソースを編集",
+        Text::SplitSubgraph => "サブグラフを別の図に分割",
+        Text::IgnoreDiagram => "Mermaid 図を無視",
+        Text::UnignoreDiagram => "Mermaid 図の無視を解除",
+        Text::TrustAllow => "許可",
+        Text::TrustDeny => "拒否",
+        Text::TrustAlwaysAllow => "常に許可",
+        Text::GitignoreAdd => ".gitignore に追加",
+        Text::GitignoreSkip => "追加しない",
+        Text::FixSmartQuotes => "スマートクォートを通常の引用符に置換",
+        Text::FixFlowchartArrows => "`->` を `-->` に置換",
+        Text::AddFlowchartHeader => "`flowchart TD` ヘッダーを追加",
+        Text::RealignRenderedBlock => "ソースに合わせて Mermaid 図を再レンダリング",
+        Text::EditLabel | Text::GitignoreEditLabel => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_locale_tags() {
+        assert_eq!(Locale::from_tag("ja"), Locale::Ja);
+        assert_eq!(Locale::from_tag("ja-JP"), Locale::Ja);
+        assert_eq!(Locale::from_tag("JA_jp"), Locale::Ja);
+        assert_eq!(Locale::from_tag("en-US"), Locale::En);
+        assert_eq!(Locale::from_tag("fr"), Locale::En);
+        assert_eq!(Locale::from_tag(""), Locale::En);
+    }
+
+    #[test]
+    fn untranslated_keys_fall_back_to_english() {
+        assert_eq!(text_in(Locale::Ja, Text::EditSource), "Mermaid ソースを編集");
+        assert_eq!(text_in(Locale::Ja, Text::EditLabel), "Mermaid");
+        assert_eq!(text_in(Locale::En, Text::EditSource), "Edit Mermaid Source");
+    }
+}
@@ -0,0 +1,245 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use url::Url;
+
+use crate::error::{ServerError, ServerResult};
+use crate::stable_hash::StableHasher;
+
+/// `service db(logos:postgresql)[Database]` and `group api(cloud)[API]` in
+/// architecture diagrams
+static ARCHITECTURE_ICON: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?m)^\s*(?:service|group|junction)\s+[\w-]+\s*\(\s*([^)\s]+)\s*\)").expect("architecture icon regex")
+});
+
+/// `@{ icon: "fa:user" }` node shapes in flowcharts
+static SHAPE_ICON: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"\bicon\s*:\s*["']([^"']+)["']"#).expect("shape icon regex"));
+
+/// An Iconify JSON icon pack: the icon names it has under its prefix
+#[derive(Debug, Clone, PartialEq)]
+pub struct IconPack {
+    pub prefix: String,
+    pub path: PathBuf,
+    icons: Vec<String>,
+}
+
+impl IconPack {
+    fn has(&self, name: &str) -> bool {
+        self.icons.iter().any(|icon| icon == name)
+    }
+}
+
+#[derive(Deserialize)]
+struct PackFile {
+    prefix: String,
+    icons: HashMap<String, Value>,
+    #[serde(default)]
+    aliases: HashMap<String, Value>,
+}
+
+/// The Iconify JSON files `entries` name: each a `.json` file or a directory
+/// of them, relative to `root` unless absolute. Every file must be inside
+/// `root`, and parse as an icon pack with a prefix no other pack has.
+pub fn load_packs(entries: &[String], root: &Path) -> Result<Vec<IconPack>, String> {
+    let root = fs::canonicalize(root).map_err(|e| format!("cannot resolve the workspace {}: {e}", root.display()))?;
+    let mut packs: Vec<IconPack> = Vec::new();
+    for file in pack_files(entries, &root)? {
+        let json = fs::read_to_string(&file).map_err(|e| format!("cannot read {}: {e}", file.display()))?;
+        let parsed: PackFile = serde_json::from_str(&json)
+            .map_err(|e| format!("{} is not an Iconify JSON icon pack: {e}", file.display()))?;
+        if parsed.prefix.is_empty() {
+            return Err(format!("{} has an empty prefix", file.display()));
+        }
+        if let Some(other) = packs.iter().find(|pack| pack.prefix == parsed.prefix) {
+            return Err(format!(
+                "{} and {} both have the prefix `{}`",
+                other.path.display(),
+                file.display(),
+                parsed.prefix
+            ));
+        }
+        let mut icons: Vec<String> = parsed.icons.into_keys().chain(parsed.aliases.into_keys()).collect();
+        icons.sort_unstable();
+        packs.push(IconPack {
+            prefix: parsed.prefix,
+            path: file,
+            icons,
+        });
+    }
+    Ok(packs)
+}
+
+/// The pack files `entries` name, checked to exist inside `root`
+fn pack_files(entries: &[String], root: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = fs::canonicalize(root.join(entry)).map_err(|e| format!("icon pack `{entry}`: {e}"))?;
+        if !path.starts_with(root) {
+            return Err(format!("icon pack `{entry}` is outside the workspace {}", root.display()));
+        }
+        if path.is_dir() {
+            let mut listed: Vec<PathBuf> = fs::read_dir(&path)
+                .map_err(|e| format!("icon pack `{entry}`: {e}"))?
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|file| file.is_file() && file.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json")))
+                .collect();
+            if listed.is_empty() {
+                return Err(format!("icon pack directory `{entry}` has no .json files"));
+            }
+            listed.sort();
+            files.extend(listed);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// mmdc's arguments registering `packs`
+pub fn mmdc_args(packs: &[IconPack]) -> Vec<OsString> {
+    if packs.is_empty() {
+        return Vec::new();
+    }
+    let mut args: Vec<OsString> = vec!["--iconPacksNamesAndUrls".into()];
+    for pack in packs {
+        let url = Url::from_file_path(&pack.path).map_or_else(|()| pack.path.display().to_string(), String::from);
+        args.push(format!("{}#{url}", pack.prefix).into());
+    }
+    args
+}
+
+/// Fail for the first prefixed icon `code` uses that isn't in `packs`.
+/// Unprefixed names are mermaid's built-in icons.
+pub fn check_icons(code: &str, packs: &[IconPack]) -> ServerResult<()> {
+    let used = ARCHITECTURE_ICON
+        .captures_iter(code)
+        .chain(SHAPE_ICON.captures_iter(code))
+        .map(|captures| captures.get(1).map_or("", |m| m.as_str()));
+    for icon in used {
+        let Some((prefix, name)) = icon.split_once(':') else {
+            continue;
+        };
+        let message = match packs.iter().find(|pack| pack.prefix == prefix) {
+            Some(pack) if pack.has(name) => continue,
+            Some(pack) => format!("icon `{icon}` is not in the icon pack {}", pack.path.display()),
+            None if packs.is_empty() => {
+                format!("icon `{icon}` needs an icon pack with the prefix `{prefix}`; add one to `iconPacks`")
+            }
+            None => {
+                let prefixes: Vec<&str> = packs.iter().map(|pack| pack.prefix.as_str()).collect();
+                format!(
+                    "icon `{icon}` is not in the configured icon packs (prefixes: {})",
+                    prefixes.join(", ")
+                )
+            }
+        };
+        return Err(ServerError::ValidationFailed(message));
+    }
+    Ok(())
+}
+
+/// Changes whenever a configured pack file is added, removed or modified, for
+/// cache keys; reading only the files' metadata
+pub fn fingerprint(entries: &[String], root: &Path) -> u64 {
+    let mut hasher = StableHasher::new();
+    let root = fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+    match pack_files(entries, &root) {
+        Ok(files) => {
+            for file in files {
+                hasher.write(file.as_os_str().as_encoded_bytes());
+                if let Ok(meta) = fs::metadata(&file) {
+                    let modified = meta.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+                    hasher.write_u64(meta.len()).write_u64(modified.map_or(0, |since| since.as_nanos() as u64));
+                }
+            }
+        }
+        Err(e) => {
+            hasher.write(e);
+        }
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOGOS: &str = r#"{ "prefix": "logos", "icons": { "postgresql": { "body": "<path/>" } }, "aliases": { "postgres": { "parent": "postgresql" } } }"#;
+
+    fn workspace() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("icons")).unwrap();
+        fs::write(dir.path().join("icons/logos.json"), LOGOS).unwrap();
+        fs::write(dir.path().join("icons/mdi.json"), r#"{ "prefix": "mdi", "icons": { "home": {} } }"#).unwrap();
+        dir
+    }
+
+    #[test]
+    fn assembles_mmdc_args_from_files_and_directories() {
+        let dir = workspace();
+        let packs = load_packs(&["icons".to_string()], dir.path()).unwrap();
+        assert_eq!(packs.iter().map(|pack| pack.prefix.as_str()).collect::<Vec<_>>(), ["logos", "mdi"]);
+        let args = mmdc_args(&packs);
+        assert_eq!(args[0], "--iconPacksNamesAndUrls");
+        let logos = args[1].to_string_lossy();
+        assert!(logos.starts_with("logos#file:///") && logos.ends_with("/icons/logos.json"), "{logos}");
+        assert!(args[2].to_string_lossy().starts_with("mdi#file:///"));
+
+        let one = load_packs(&["icons/mdi.json".to_string()], dir.path()).unwrap();
+        assert_eq!(one.len(), 1);
+        assert!(mmdc_args(&[]).is_empty());
+    }
+
+    #[test]
+    fn rejects_packs_outside_the_workspace_or_unparsable() {
+        let dir = workspace();
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("x.json"), LOGOS).unwrap();
+        let error = load_packs(&[outside.path().join("x.json").display().to_string()], dir.path()).unwrap_err();
+        assert!(error.contains("outside the workspace"), "{error}");
+        assert!(load_packs(&["../x.json".to_string()], dir.path()).is_err());
+        assert!(load_packs(&["icons/missing.json".to_string()], dir.path()).is_err());
+
+        fs::write(dir.path().join("broken.json"), "{ \"prefix\": ").unwrap();
+        let error = load_packs(&["broken.json".to_string()], dir.path()).unwrap_err();
+        assert!(error.contains("not an Iconify JSON icon pack"), "{error}");
+        let error = load_packs(&["icons".to_string(), "icons/logos.json".to_string()], dir.path()).unwrap_err();
+        assert!(error.contains("both have the prefix `logos`"), "{error}");
+    }
+
+    #[test]
+    fn names_icons_missing_from_the_packs() {
+        let dir = workspace();
+        let packs = load_packs(&["icons/logos.json".to_string()], dir.path()).unwrap();
+        let diagram = "architecture-beta\n  group api(cloud)[API]\n  service db(logos:postgresql)[Database] in api\n  service cache(logos:postgres)[Cache]\n";
+        check_icons(diagram, &packs).unwrap();
+        check_icons("flowchart TD\n  A@{ icon: \"logos:postgresql\", label: \"DB\" }", &packs).unwrap();
+
+        let missing = check_icons("architecture-beta\n  service db(logos:mysql)[DB]", &packs).unwrap_err();
+        assert!(matches!(missing, ServerError::ValidationFailed(_)));
+        assert!(missing.to_string().contains("icon `logos:mysql` is not in the icon pack"), "{missing}");
+        let unknown = check_icons("flowchart TD\n  A@{ icon: 'fa:user' }", &packs).unwrap_err();
+        assert!(unknown.to_string().contains("(prefixes: logos)"), "{unknown}");
+        let unconfigured = check_icons("architecture-beta\n  service db(logos:postgresql)[DB]", &[]).unwrap_err();
+        assert!(unconfigured.to_string().contains("add one to `iconPacks`"), "{unconfigured}");
+    }
+
+    #[test]
+    fn fingerprint_follows_the_pack_files() {
+        let dir = workspace();
+        let entries = ["icons".to_string()];
+        let before = fingerprint(&entries, dir.path());
+        assert_eq!(fingerprint(&entries, dir.path()), before);
+        fs::write(dir.path().join("icons/extra.json"), r#"{ "prefix": "extra", "icons": {} }"#).unwrap();
+        assert_ne!(fingerprint(&entries, dir.path()), before);
+    }
+}
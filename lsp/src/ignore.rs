@@ -0,0 +1,64 @@
+use crate::backend;
+
+/// Comment that, on the line right above a fence, marks it as ignored
+pub const IGNORE_COMMENT: &str = "<!-- mermaid-ignore -->";
+
+/// Attribute that marks a fence as ignored: ```` ```mermaid {ignore} ````
+const IGNORE_ATTRIBUTE: &str = "ignore";
+
+fn is_separator(c: char) -> bool {
+    c.is_whitespace() || c == ','
+}
+
+/// Whether the fence's info string has the `ignore` attribute
+pub fn has_ignore_attribute(info: &str) -> bool {
+    backend::attributes(info).is_some_and(|attributes| attributes.split(is_separator).any(|a| a == IGNORE_ATTRIBUTE))
+}
+
+/// Whether `line` is the ignore comment
+pub fn is_ignore_comment(line: &str) -> bool {
+    line.trim() == IGNORE_COMMENT
+}
+
+/// The info string without the `ignore` attribute, dropping the `{}` when
+/// nothing else is left in it
+pub fn without_ignore_attribute(info: &str) -> String {
+    let Some(attributes) = backend::attributes(info) else {
+        return info.to_string();
+    };
+    let kept: Vec<&str> = attributes
+        .split(is_separator)
+        .filter(|a| !a.is_empty() && *a != IGNORE_ATTRIBUTE)
+        .collect();
+    let replacement = if kept.is_empty() { String::new() } else { format!("{{{}}}", kept.join(" ")) };
+    info.replacen(&format!("{{{attributes}}}"), &replacement, 1).trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_ignore_attribute() {
+        assert!(has_ignore_attribute("{ignore}"));
+        assert!(has_ignore_attribute("{backend=mmdc ignore}"));
+        assert!(has_ignore_attribute("{ignore, backend=mmdc}"));
+        assert!(!has_ignore_attribute("{backend=mmdc}"));
+        assert!(!has_ignore_attribute("ignore"));
+        assert!(!has_ignore_attribute("{ignored}"));
+    }
+
+    #[test]
+    fn removes_only_the_ignore_attribute() {
+        assert_eq!(without_ignore_attribute("{ignore}"), "");
+        assert_eq!(without_ignore_attribute("{backend=mmdc ignore}"), "{backend=mmdc}");
+        assert_eq!(without_ignore_attribute("{ignore, backend=mmdc}"), "{backend=mmdc}");
+        assert_eq!(without_ignore_attribute("{backend=mmdc}"), "{backend=mmdc}");
+    }
+
+    #[test]
+    fn recognizes_the_comment() {
+        assert!(is_ignore_comment("  <!-- mermaid-ignore -->"));
+        assert!(!is_ignore_comment("<!-- mermaid-source-file:a.mmd -->"));
+    }
+}
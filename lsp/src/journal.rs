@@ -0,0 +1,262 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::cache;
+
+/// Journal of recent render operations, in the cache directory
+const JOURNAL_FILE: &str = "render-journal.json";
+const JOURNAL_VERSION: u32 = 1;
+
+/// Lines of context kept on each side of a rendered block, to find it again
+/// after the document around it changed
+pub const CONTEXT_LINES: usize = 2;
+
+/// Largest journal kept on disk; the oldest operations go first beyond it
+const MAX_JOURNAL_BYTES: usize = 1024 * 1024;
+
+/// How many operations are kept, and for how long
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    pub max_entries: usize,
+    pub max_age_secs: u64,
+}
+
+/// One render command's changes to a document
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RenderRecord {
+    /// URI of the rendered document
+    pub document: String,
+    /// When the render ran (unix seconds)
+    pub recorded_at: u64,
+    pub regions: Vec<RenderedRegion>,
+}
+
+/// One fence replaced by its rendered block
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RenderedRegion {
+    /// Line the fence started on when it was rendered
+    pub line: usize,
+    /// The fence's text, restored on revert
+    pub original: String,
+    /// The rendered block that replaced it
+    pub rendered: String,
+    /// Up to [`CONTEXT_LINES`] lines above and below the fence
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+    /// Files the rendered block links to, written by the render
+    pub files: Vec<PathBuf>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct JournalFile {
+    version: u32,
+    /// Oldest first
+    records: Vec<RenderRecord>,
+}
+
+/// The render journal of one cache directory
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    pub fn new(cache_dir: &Path) -> Self {
+        Self {
+            path: cache_dir.join(JOURNAL_FILE),
+        }
+    }
+
+    /// Add an operation, dropping the ones past `retention`. A corrupt journal
+    /// is replaced.
+    pub fn record(&self, record: RenderRecord, retention: Retention) -> io::Result<()> {
+        let mut journal = self.read().unwrap_or_else(|| JournalFile {
+            version: JOURNAL_VERSION,
+            records: Vec::new(),
+        });
+        journal.records.push(record);
+        let now = journal.records.last().map_or(0, |r| r.recorded_at);
+        prune(&mut journal.records, retention, now);
+        self.write(&mut journal)
+    }
+
+    /// The latest operation on `document` within `retention`, if any. A
+    /// missing or corrupt journal has none.
+    pub fn latest(&self, document: &str, retention: Retention, now: u64) -> Option<RenderRecord> {
+        let mut journal = self.read()?;
+        prune(&mut journal.records, retention, now);
+        journal.records.into_iter().rev().find(|r| r.document == document)
+    }
+
+    /// Files linked from the recorded operations, which may still be in their documents
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.read()
+            .map(|journal| journal.records.into_iter().flat_map(|r| r.regions).flat_map(|r| r.files).collect())
+            .unwrap_or_default()
+    }
+
+    /// Forget `record`, once it has been reverted
+    pub fn remove(&self, record: &RenderRecord) -> io::Result<()> {
+        let Some(mut journal) = self.read() else {
+            return Ok(());
+        };
+        journal.records.retain(|r| r != record);
+        self.write(&mut journal)
+    }
+
+    fn read(&self) -> Option<JournalFile> {
+        let json = fs::read_to_string(&self.path).ok()?;
+        match serde_json::from_str::<JournalFile>(&json) {
+            Ok(journal) if journal.version == JOURNAL_VERSION => Some(journal),
+            _ => {
+                warn!("Ignoring corrupt render journal {}", self.path.display());
+                None
+            }
+        }
+    }
+
+    fn write(&self, journal: &mut JournalFile) -> io::Result<()> {
+        let mut json = serde_json::to_string(&*journal)?;
+        while json.len() > MAX_JOURNAL_BYTES && !journal.records.is_empty() {
+            journal.records.remove(0);
+            json = serde_json::to_string(&*journal)?;
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Readers never see a half-written journal
+        let tmp = cache::temp_path(&self.path);
+        fs::write(&tmp, json).and_then(|()| fs::rename(&tmp, &self.path)).inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })
+    }
+}
+
+/// Drop operations older than the retention age, then the oldest beyond its count
+fn prune(records: &mut Vec<RenderRecord>, retention: Retention, now: u64) {
+    records.retain(|r| now.saturating_sub(r.recorded_at) <= retention.max_age_secs);
+    let excess = records.len().saturating_sub(retention.max_entries);
+    records.drain(..excess);
+}
+
+/// Where `region`'s rendered block starts in `lines` now, other than at the
+/// `taken` lines: among the places its text appears, the one whose
+/// surroundings match the recorded context best, then the one nearest its old
+/// line
+pub fn locate(lines: &[&str], region: &RenderedRegion, taken: &[usize]) -> Option<usize> {
+    let rendered: Vec<&str> = region.rendered.lines().collect();
+    if rendered.is_empty() || rendered.len() > lines.len() {
+        return None;
+    }
+    (0..=lines.len() - rendered.len())
+        .filter(|start| !taken.contains(start))
+        .filter(|&start| lines[start..start + rendered.len()] == rendered[..])
+        .max_by_key(|&start| {
+            let end = start + rendered.len();
+            let above = region
+                .before
+                .iter()
+                .rev()
+                .zip(lines[..start].iter().rev())
+                .take_while(|(recorded, line)| recorded.as_str() == **line)
+                .count();
+            let below = region
+                .after
+                .iter()
+                .zip(&lines[end..])
+                .take_while(|(recorded, line)| recorded.as_str() == **line)
+                .count();
+            (above + below, std::cmp::Reverse(start.abs_diff(region.line)))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RETENTION: Retention = Retention {
+        max_entries: 3,
+        max_age_secs: 100,
+    };
+
+    fn record(document: &str, recorded_at: u64) -> RenderRecord {
+        RenderRecord {
+            document: document.to_string(),
+            recorded_at,
+            regions: vec![region(0, "```mermaid\ngraph TD\n```", &[], &[])],
+        }
+    }
+
+    fn region(line: usize, rendered: &str, before: &[&str], after: &[&str]) -> RenderedRegion {
+        RenderedRegion {
+            line,
+            original: "```mermaid\ngraph TD\n```".to_string(),
+            rendered: rendered.to_string(),
+            before: before.iter().map(|s| s.to_string()).collect(),
+            after: after.iter().map(|s| s.to_string()).collect(),
+            files: vec![PathBuf::from("/tmp/.mermaid/a.svg")],
+        }
+    }
+
+    #[test]
+    fn keeps_recent_operations_per_document() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::new(dir.path());
+        assert_eq!(journal.latest("file:///a.md", RETENTION, 0), None);
+
+        for (document, at) in [("file:///a.md", 10), ("file:///b.md", 20), ("file:///a.md", 30)] {
+            journal.record(record(document, at), RETENTION).unwrap();
+        }
+        assert_eq!(journal.latest("file:///a.md", RETENTION, 30).unwrap().recorded_at, 30);
+        journal.remove(&record("file:///a.md", 30)).unwrap();
+        assert_eq!(journal.latest("file:///a.md", RETENTION, 30).unwrap().recorded_at, 10);
+
+        // Expired by age, then pushed out by count
+        assert_eq!(journal.latest("file:///a.md", RETENTION, 111), None);
+        for at in 40..43 {
+            journal.record(record("file:///c.md", at), RETENTION).unwrap();
+        }
+        assert_eq!(journal.latest("file:///b.md", RETENTION, 43), None);
+    }
+
+    #[test]
+    fn corrupt_journals_have_nothing_to_revert() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::new(dir.path());
+        fs::write(dir.path().join(JOURNAL_FILE), "{ \"version\": 1, \"records\": [ {").unwrap();
+        assert_eq!(journal.latest("file:///a.md", RETENTION, 0), None);
+        journal.remove(&record("file:///a.md", 0)).unwrap();
+
+        // Recording starts over
+        journal.record(record("file:///a.md", 5), RETENTION).unwrap();
+        assert_eq!(journal.latest("file:///a.md", RETENTION, 5).unwrap().recorded_at, 5);
+    }
+
+    #[test]
+    fn locates_blocks_by_their_context() {
+        let block = "<!-- mermaid-source-file:.mermaid/a.mmd -->\n\n![Mermaid Diagram](.mermaid/a.svg)";
+        let lines = vec![
+            "# Intro",
+            "<!-- mermaid-source-file:.mermaid/a.mmd -->",
+            "",
+            "![Mermaid Diagram](.mermaid/a.svg)",
+            "# Usage",
+            "More text",
+            "<!-- mermaid-source-file:.mermaid/a.mmd -->",
+            "",
+            "![Mermaid Diagram](.mermaid/a.svg)",
+            "The end",
+        ];
+        assert_eq!(locate(&lines, &region(0, block, &["# Usage", "More text"], &["The end"]), &[]), Some(6));
+        assert_eq!(locate(&lines, &region(0, block, &["# Intro"], &["# Usage"]), &[]), Some(1));
+        // Without matching context the nearest copy wins
+        assert_eq!(locate(&lines, &region(9, block, &["gone"], &[]), &[]), Some(6));
+        assert_eq!(locate(&lines, &region(9, block, &["gone"], &[]), &[6]), Some(1));
+        assert_eq!(locate(&lines, &region(0, "![Mermaid Diagram](.mermaid/b.svg)", &[], &[]), &[]), None);
+    }
+}
@@ -0,0 +1,314 @@
+use lsp_types::{Diagnostic, DiagnosticSeverity};
+use serde::Serialize;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::config::Config;
+use crate::position::PositionEncoding;
+
+/// Version of the `--format=json` output; bump on incompatible changes
+pub const LINT_SCHEMA_VERSION: u32 = 1;
+
+/// Exit codes of `mermaid-lsp --lint`
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_ERRORS: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+
+/// Directories never searched for markdown files
+const SKIPPED_DIRS: &[&str] = &["node_modules", "target"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// `file:line:col: severity: message`, one per line
+    #[default]
+    Text,
+    Json,
+}
+
+/// Command line of `mermaid-lsp --lint [--format=text|json] [PATH...]`
+#[derive(Debug, PartialEq)]
+pub struct LintArgs {
+    pub format: OutputFormat,
+    /// Files or directories to lint; the current directory when empty
+    pub paths: Vec<PathBuf>,
+}
+
+impl LintArgs {
+    /// Parse the arguments following the program name
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut format = OutputFormat::default();
+        let mut paths = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let value = match arg.as_str() {
+                "--lint" => continue,
+                "--format" => Some(args.next().ok_or("--format expects a value")?.as_str()),
+                other => other.strip_prefix("--format="),
+            };
+            match value {
+                Some("json") => format = OutputFormat::Json,
+                Some("text") => format = OutputFormat::Text,
+                Some(other) => return Err(format!("Unknown format `{other}` (expected text or json)")),
+                None if arg.starts_with("--") => return Err(format!("Unknown option `{arg}`")),
+                None => paths.push(PathBuf::from(arg)),
+            }
+        }
+        Ok(Self { format, paths })
+    }
+}
+
+/// Lint results for one markdown file
+#[derive(Debug, Serialize)]
+pub struct LintEntry {
+    pub file: String,
+    pub diagnostics: Vec<LintDiagnostic>,
+}
+
+/// One diagnostic, with 1-based line and column (UTF-16 code units, as in LSP)
+#[derive(Debug, Serialize)]
+pub struct LintDiagnostic {
+    pub line: u32,
+    pub col: u32,
+    pub severity: &'static str,
+    pub message: String,
+}
+
+impl From<&Diagnostic> for LintDiagnostic {
+    fn from(diagnostic: &Diagnostic) -> Self {
+        let severity = match diagnostic.severity {
+            Some(DiagnosticSeverity::ERROR) => "error",
+            Some(DiagnosticSeverity::INFORMATION) => "info",
+            Some(DiagnosticSeverity::HINT) => "hint",
+            _ => "warning",
+        };
+        Self {
+            line: diagnostic.range.start.line + 1,
+            col: diagnostic.range.start.character + 1,
+            severity,
+            message: diagnostic.message.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct LintReport<'a> {
+    version: u32,
+    files: &'a [LintEntry],
+}
+
+/// Run the linter and return the process exit code
+pub fn run(args: &[String]) -> i32 {
+    let args = match LintArgs::parse(args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("mermaid-lsp: {e}");
+            eprintln!("usage: mermaid-lsp --lint [--format=text|json] [PATH...]");
+            return EXIT_USAGE;
+        }
+    };
+
+    let roots = if args.paths.is_empty() {
+        vec![PathBuf::from(".")]
+    } else {
+        args.paths
+    };
+    let mut files = Vec::new();
+    for root in &roots {
+        if let Err(e) = collect_markdown_files(root, &mut files) {
+            eprintln!("mermaid-lsp: {}: {e}", root.display());
+            return EXIT_USAGE;
+        }
+    }
+
+    let entries = match lint_files(&files) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("mermaid-lsp: {e}");
+            return EXIT_USAGE;
+        }
+    };
+    print!("{}", format_report(&entries, args.format));
+
+    let has_errors = entries
+        .iter()
+        .flat_map(|entry| &entry.diagnostics)
+        .any(|d| d.severity == "error");
+    if has_errors {
+        EXIT_ERRORS
+    } else {
+        EXIT_OK
+    }
+}
+
+/// Lint each file; files without mermaid problems are still listed
+pub fn lint_files(files: &[PathBuf]) -> io::Result<Vec<LintEntry>> {
+    let config = Config::default();
+    files
+        .iter()
+        .map(|file| {
+            let text = fs::read_to_string(file)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", file.display())))?;
+            let diagnostics =
+                crate::document_diagnostics(&text, None, PositionEncoding::Utf16, &config);
+            Ok(LintEntry {
+                file: file.to_string_lossy().replace('\\', "/"),
+                diagnostics: diagnostics.iter().map(LintDiagnostic::from).collect(),
+            })
+        })
+        .collect()
+}
+
+/// Render the report in the requested format
+pub fn format_report(entries: &[LintEntry], format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Json => {
+            let report = LintReport {
+                version: LINT_SCHEMA_VERSION,
+                files: entries,
+            };
+            let mut json = serde_json::to_string_pretty(&report).expect("lint report serializes");
+            json.push('\n');
+            json
+        }
+        OutputFormat::Text => {
+            let mut text = String::new();
+            for entry in entries {
+                for d in &entry.diagnostics {
+                    text.push_str(&format!(
+                        "{}:{}:{}: {}: {}\n",
+                        entry.file, d.line, d.col, d.severity, d.message
+                    ));
+                }
+            }
+            let problems: usize = entries.iter().map(|e| e.diagnostics.len()).sum();
+            text.push_str(&format!("{problems} problem(s) in {} file(s)\n", entries.len()));
+            text
+        }
+    }
+}
+
+/// Markdown files under `path` (or `path` itself), sorted, skipping hidden and
+/// build directories
+pub fn collect_markdown_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if path.is_file() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+
+    let mut entries: Vec<PathBuf> = fs::read_dir(path)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .collect();
+    entries.sort();
+    for entry in entries {
+        let name = entry.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        if entry.is_dir() {
+            if !name.starts_with('.') && !SKIPPED_DIRS.contains(&name) {
+                collect_markdown_files(&entry, files)?;
+            }
+        } else if matches!(
+            entry.extension().and_then(|e| e.to_str()),
+            Some("md" | "markdown")
+        ) {
+            files.push(entry);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALID: &str = "# Valid\n\n```mermaid\ngraph TD\n  A[One] --> B[Two]\n```\n";
+    const INVALID: &str = "# Invalid\n\n```mermaid\nflowchart LR\n  A[One] --> A[Two]\n```\n\n```mermaid\ngraph TD\n";
+
+    fn fixture() -> (tempfile::TempDir, Vec<PathBuf>) {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("docs/.mermaid")).unwrap();
+        fs::write(dir.path().join("docs/valid.md"), VALID).unwrap();
+        fs::write(dir.path().join("docs/invalid.md"), INVALID).unwrap();
+        fs::write(dir.path().join("docs/.mermaid/ignored.md"), INVALID).unwrap();
+        fs::write(dir.path().join("notes.txt"), INVALID).unwrap();
+
+        let mut files = Vec::new();
+        collect_markdown_files(dir.path(), &mut files).unwrap();
+        (dir, files)
+    }
+
+    #[test]
+    fn parses_arguments() {
+        let args = |list: &[&str]| {
+            LintArgs::parse(&list.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+        };
+        assert_eq!(
+            args(&["--lint", "--format=json", "docs"]).unwrap(),
+            LintArgs {
+                format: OutputFormat::Json,
+                paths: vec![PathBuf::from("docs")],
+            }
+        );
+        assert_eq!(args(&["--lint", "--format", "text"]).unwrap().format, OutputFormat::Text);
+        assert!(args(&["--lint", "--format=xml"]).is_err());
+        assert!(args(&["--lint", "--fix"]).is_err());
+    }
+
+    #[test]
+    fn finds_markdown_files_only() {
+        let (dir, files) = fixture();
+        let names: Vec<_> = files
+            .iter()
+            .map(|f| f.strip_prefix(dir.path()).unwrap().to_path_buf())
+            .collect();
+        assert_eq!(
+            names,
+            vec![PathBuf::from("docs/invalid.md"), PathBuf::from("docs/valid.md")]
+        );
+    }
+
+    #[test]
+    fn json_report_has_a_stable_schema() {
+        let (_dir, files) = fixture();
+        let entries = lint_files(&files).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&format_report(&entries, OutputFormat::Json)).unwrap();
+
+        assert_eq!(json["version"], LINT_SCHEMA_VERSION);
+        let files = json["files"].as_array().unwrap();
+        assert_eq!(files.len(), 2);
+
+        let invalid = &files[0];
+        assert!(invalid["file"].as_str().unwrap().ends_with("docs/invalid.md"));
+        let diagnostics = invalid["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(
+            diagnostics[0],
+            serde_json::json!({
+                "line": 5,
+                "col": 14,
+                "severity": "warning",
+                "message": diagnostics[0]["message"],
+            })
+        );
+        assert_eq!(diagnostics[1]["line"], 8);
+        assert_eq!(diagnostics[1]["severity"], "error");
+
+        let valid = &files[1];
+        assert!(valid["file"].as_str().unwrap().ends_with("docs/valid.md"));
+        assert_eq!(valid["diagnostics"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn text_report_lists_each_problem() {
+        let (_dir, files) = fixture();
+        let entries = lint_files(&files).unwrap();
+        let text = format_report(&entries, OutputFormat::Text);
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains("invalid.md:5:14: warning: "));
+        assert!(lines[1].contains("invalid.md:8:1: error: "));
+        assert_eq!(lines[2], "2 problem(s) in 2 file(s)");
+    }
+}
@@ -0,0 +1,68 @@
+use base64::prelude::*;
+use flate2::{write::ZlibEncoder, Compression};
+use serde::Serialize;
+use std::io::Write;
+
+/// Where the Mermaid Live Editor opens a diagram from its URL fragment
+const EDITOR_URL: &str = "https://mermaid.live/edit#pako:";
+
+/// The editor's state, as it serializes it into the URL
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EditorState<'a> {
+    code: &'a str,
+    /// Mermaid config, as the JSON text shown in the editor's config tab
+    mermaid: String,
+    auto_sync: bool,
+    update_diagram: bool,
+}
+
+/// Link opening `code` in the Mermaid Live Editor: the editor state as JSON,
+/// zlib-deflated (as pako does) and base64url-encoded without padding
+pub fn editor_link(code: &str, theme: &str) -> String {
+    let state = EditorState {
+        code,
+        mermaid: serde_json::json!({ "theme": theme }).to_string(),
+        auto_sync: true,
+        update_diagram: true,
+    };
+    let json = serde_json::to_vec(&state).expect("editor state serializes");
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    let compressed = encoder
+        .write_all(&json)
+        .and_then(|()| encoder.finish())
+        .expect("deflating into memory");
+    format!("{EDITOR_URL}{}", BASE64_URL_SAFE_NO_PAD.encode(compressed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+
+    /// The editor state in a link, decoded as the editor does
+    fn decode(link: &str) -> serde_json::Value {
+        let payload = link.strip_prefix(EDITOR_URL).expect("live editor link");
+        let compressed = BASE64_URL_SAFE_NO_PAD.decode(payload).unwrap();
+        let mut json = String::new();
+        ZlibDecoder::new(&compressed[..]).read_to_string(&mut json).unwrap();
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn link_decodes_to_the_diagram() {
+        let code = "graph TD\n  A[\"Start ✓\"] -->|\"yes/no?\"| B{Done & dusted}\n  %% comment";
+        let state = decode(&editor_link(code, "dark"));
+        assert_eq!(state["code"], code);
+        assert_eq!(state["mermaid"], "{\"theme\":\"dark\"}");
+        assert_eq!(state["autoSync"], true);
+    }
+
+    #[test]
+    fn link_is_url_safe() {
+        let link = editor_link(&"sequenceDiagram\n  Alice->>Bob: ??>>~~\n".repeat(20), "default");
+        let payload = link.strip_prefix(EDITOR_URL).unwrap();
+        assert!(payload.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'), "{payload}");
+    }
+}
@@ -0,0 +1,160 @@
+use chrono::{SecondsFormat, Utc};
+use log::kv::{self, Key, VisitSource};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    env,
+    io::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Environment variable selecting the log format before initializationOptions arrive
+const LOG_FORMAT_ENV: &str = "MERMAID_LSP_LOG_FORMAT";
+
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Output format for log records written to stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// One log record in JSON mode
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JsonRecord {
+    pub timestamp: String,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub fields: Map<String, Value>,
+}
+
+/// Install the logger; `RUST_LOG` still controls filtering
+pub fn init() {
+    if let Ok(format) = env::var(LOG_FORMAT_ENV) {
+        if format.eq_ignore_ascii_case("json") {
+            set_format(LogFormat::Json);
+        }
+    }
+
+    env_logger::Builder::from_default_env()
+        .format(|buf, record| {
+            let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+            if JSON_OUTPUT.load(Ordering::Relaxed) {
+                writeln!(buf, "{}", json_line(record, timestamp))
+            } else {
+                writeln!(buf, "{}", text_line(record, &timestamp))
+            }
+        })
+        .init();
+}
+
+/// Switch the output format at runtime (e.g. from initializationOptions)
+pub fn set_format(format: LogFormat) {
+    JSON_OUTPUT.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+fn json_line(record: &log::Record, timestamp: String) -> String {
+    let json = JsonRecord {
+        timestamp,
+        level: record.level().to_string(),
+        target: record.target().to_string(),
+        message: record.args().to_string(),
+        fields: collect_fields(record),
+    };
+    serde_json::to_string(&json).unwrap_or_default()
+}
+
+fn text_line(record: &log::Record, timestamp: &str) -> String {
+    let mut line = format!(
+        "[{timestamp} {:<5} {}] {}",
+        record.level(),
+        record.target(),
+        record.args()
+    );
+    for (key, value) in collect_fields(record) {
+        match value {
+            Value::String(s) => line.push_str(&format!(" {key}={s}")),
+            other => line.push_str(&format!(" {key}={other}")),
+        }
+    }
+    line
+}
+
+/// Gather the structured key/values attached with `info!(key = value; "...")`
+fn collect_fields(record: &log::Record) -> Map<String, Value> {
+    struct Collector(Map<String, Value>);
+
+    impl<'kvs> VisitSource<'kvs> for Collector {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+            let json = if let Some(b) = value.to_bool() {
+                Value::Bool(b)
+            } else if let Some(n) = value.to_u64() {
+                Value::from(n)
+            } else if let Some(n) = value.to_i64() {
+                Value::from(n)
+            } else if let Some(n) = value.to_f64() {
+                Value::from(n)
+            } else {
+                Value::String(value.to_string())
+            };
+            self.0.insert(key.to_string(), json);
+            Ok(())
+        }
+    }
+
+    let mut collector = Collector(Map::new());
+    let _ = record.key_values().visit(&mut collector);
+    collector.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_line_round_trips() {
+        let fields = [
+            ("document", kv::Value::from("file:///docs/a.md")),
+            ("fence_line", kv::Value::from(4u64)),
+            ("cache_hit", kv::Value::from(true)),
+        ];
+        let line = json_line(
+            &log::Record::builder()
+                .args(format_args!("Rendered mermaid diagram"))
+                .level(log::Level::Info)
+                .target("mermaid_lsp")
+                .key_values(&fields)
+                .build(),
+            "2024-01-01T00:00:00.000Z".to_string(),
+        );
+
+        assert!(!line.contains('\n'));
+        let parsed: JsonRecord = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed.level, "INFO");
+        assert_eq!(parsed.target, "mermaid_lsp");
+        assert_eq!(parsed.message, "Rendered mermaid diagram");
+        assert_eq!(parsed.fields["document"], "file:///docs/a.md");
+        assert_eq!(parsed.fields["fence_line"], 4);
+        assert_eq!(parsed.fields["cache_hit"], true);
+    }
+
+    #[test]
+    fn text_line_appends_fields() {
+        let fields = [("duration_ms", kv::Value::from(12u64))];
+        let line = text_line(
+            &log::Record::builder()
+                .args(format_args!("done"))
+                .level(log::Level::Warn)
+                .target("mermaid_lsp")
+                .key_values(&fields)
+                .build(),
+            "2024-01-01T00:00:00.000Z",
+        );
+        assert_eq!(line, "[2024-01-01T00:00:00.000Z WARN  mermaid_lsp] done duration_ms=12");
+    }
+}
@@ -1,70 +1,354 @@
-use anyhow::{anyhow, Result};
+use anyhow::Result;
+use base64::prelude::*;
 use chrono::Local;
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use lsp_server::{Connection, Message, Notification, Request, Response};
 use lsp_types::*;
 use serde_json::Value;
 use std::{
-    collections::{hash_map::DefaultHasher, HashMap},
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     fs,
-    hash::{Hash, Hasher},
+    io::{BufRead, BufReader, Write},
     path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use url::Url;
 
+mod accessibility;
+mod adopt;
+mod anchors;
+mod backend;
+mod cache;
+mod commands;
+mod compose;
+mod config;
+mod diagnostics;
+mod document_source;
+mod error;
+mod figures;
+mod format;
+mod gitignore;
+mod i18n;
+mod icons;
+mod ignore;
+mod journal;
+mod lint;
+mod live;
+mod logging;
+mod mdx;
+mod memo;
+mod memory;
+mod mermaidrc;
+mod naming;
+mod optimize;
+mod outcome;
+mod outgoing;
+mod paths;
+mod placeholder;
+mod position;
+mod postprocess;
+mod publisher;
+mod quickfix;
 mod render;
+mod remote;
+mod render_cli;
+mod scheme;
+mod source_map;
+mod status;
+mod split;
+mod stable_hash;
+mod steps;
+mod trust;
+mod variables;
+mod verify;
+mod visual_diff;
+mod watermark;
+
+use backend::Backend;
+use cache::{ContentHash, DiagramCache};
+use config::{CacheLocation, Config, LineEnding};
+use document_source::{DiskFile, DocumentSource};
+use error::{ServerError, ServerResult};
+use figures::Figure;
+use i18n::{text, Text};
+use mdx::Syntax;
+use memo::{CheckedDocument, FenceKey, Recheck};
+use memory::{Recency, RetainedBytes};
+use mermaidrc::RcFiles;
+use outcome::{CommandOutcome, Status};
+use outgoing::OutgoingRequests;
+use position::PositionEncoding;
+use publisher::{Channel, Publisher};
+use scheme::DocumentLocation;
+use status::{FenceState, FenceStatus, FenceStatusParams};
 
 fn main() -> Result<()> {
-    env_logger::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // The extension's smoke test of a downloaded update
+    if args.iter().any(|a| a == "--version") {
+        println!("mermaid-lsp {}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+    if args.iter().any(|a| a == "--lint") {
+        std::process::exit(lint::run(&args));
+    }
+    if args.iter().any(|a| a == "--verify" || a.starts_with("--verify=")) {
+        logging::init();
+        std::process::exit(verify::run(&args));
+    }
+    if args.iter().any(|a| a.starts_with("--render")) {
+        logging::init();
+        std::process::exit(render_cli::run(&args));
+    }
+
+    logging::init();
     info!("Starting Mermaid LSP server");
 
     let (connection, io_threads) = Connection::stdio();
+    serve(connection)?;
+    io_threads.join()?;
+
+    Ok(())
+}
+
+/// File the server creates as it answers initialize; the extension sets it for an
+/// update it couldn't smoke-test, and must use the same name
+const STARTED_FILE_ENV: &str = "MERMAID_LSP_STARTED_FILE";
+
+/// Run the initialize handshake and the message loop on a connection
+fn serve(connection: Connection) -> Result<()> {
+    let (init_id, init_params) = connection.initialize_start()?;
+    let init: InitializeParams = serde_json::from_value(init_params)?;
+    let client = ClientInfo::from_capabilities(&init.capabilities);
 
     let server_capabilities = ServerCapabilities {
-        text_document_sync: Some(TextDocumentSyncCapability::Kind(
-            TextDocumentSyncKind::FULL,
-        )),
+        position_encoding: Some(client.position_encoding.kind()),
+        text_document_sync: Some(TextDocumentSyncCapability::Options(TextDocumentSyncOptions {
+            open_close: Some(true),
+            change: Some(TextDocumentSyncKind::FULL),
+            save: Some(TextDocumentSyncSaveOptions::Supported(true)),
+            ..Default::default()
+        })),
         code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
         execute_command_provider: Some(ExecuteCommandOptions {
             commands: vec![
                 "mermaid.renderSingle".to_string(),
                 "mermaid.renderAllLightweight".to_string(),
+                "mermaid.renderByType".to_string(),
+                "mermaid.rerenderFromSources".to_string(),
                 "mermaid.editSingleSource".to_string(),
                 "mermaid.editAllSources".to_string(),
+                "mermaid.renderComparison".to_string(),
+                "mermaid.compareToReference".to_string(),
+                "mermaid.verifyCache".to_string(),
+                "mermaid.copyAsMarkdown".to_string(),
+                "mermaid.liveEditorLink".to_string(),
+                "mermaid.renderSteps".to_string(),
+                "mermaid.embedSvgInline".to_string(),
+                "mermaid.checkLinks".to_string(),
+                "mermaid.adoptRenderedBlocks".to_string(),
+                "mermaid.getOptions".to_string(),
+                "mermaid.setOption".to_string(),
+                "mermaid.doctor".to_string(),
+                "mermaid.stats".to_string(),
+                "mermaid.renderFiles".to_string(),
+                "mermaid.generateIndex".to_string(),
+                "mermaid.revertLastRender".to_string(),
+                "mermaid.resetLimits".to_string(),
+                "mermaid.formatAll".to_string(),
             ],
             ..Default::default()
         }),
         ..Default::default()
     };
 
-    let init_params = connection.initialize(serde_json::to_value(server_capabilities)?)?;
-    let _init: InitializeParams = serde_json::from_value(init_params)?;
+    let init_result = InitializeResult {
+        capabilities: server_capabilities,
+        server_info: Some(ServerInfo {
+            name: "mermaid-lsp".to_string(),
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+        }),
+    };
+    // Before the client hears back, so the file is there once it has
+    if let Some(file) = std::env::var_os(STARTED_FILE_ENV) {
+        if let Err(e) = fs::write(&file, env!("CARGO_PKG_VERSION")) {
+            warn!("Failed to write {}: {e}", Path::new(&file).display());
+        }
+    }
+    connection.initialize_finish(init_id, serde_json::to_value(init_result)?)?;
+
+    let config = Config::from_init_options(init.initialization_options.as_ref());
+    if let Some(format) = config.log_format {
+        logging::set_format(format);
+    }
+    i18n::set_locale(i18n::Locale::from_tag(&config.locale));
+    info!("Mermaid LSP initialized ({client:?}, {config:?})");
+    if let Some(warning) = config.security_warning() {
+        show_message(&connection, MessageType::WARNING, &format!("Mermaid: {warning}"))?;
+    }
 
-    info!("Mermaid LSP initialized");
-    main_loop(connection)?;
-    io_threads.join()?;
+    let workspace_root = workspace_root(&init);
+    if workspace_root.is_none() {
+        info!("No workspace root; commands that write files other than open documents are off");
+    }
+    let trust_dir = std::env::var_os(trust::TRUST_DIR_ENV)
+        .map(PathBuf::from)
+        .or_else(|| std::env::current_exe().ok()?.parent().map(Path::to_path_buf));
+    *gitignore::checks() = gitignore::GitignoreChecks::new(trust_dir.clone());
+    *trust::store() = trust::TrustStore::new(workspace_root.clone(), trust_dir);
 
-    Ok(())
+    let mut state = ServerState {
+        documents: HashMap::new(),
+        client,
+        config,
+        versions: HashMap::new(),
+        render_failures: HashMap::new(),
+        checked: HashMap::new(),
+        recency: Recency::default(),
+        published: Publisher::default(),
+        outgoing: OutgoingRequests::default(),
+        deferred: VecDeque::new(),
+        workspace_root,
+        rc_files: RcFiles::default(),
+    };
+    main_loop(connection, &mut state)
 }
 
-/// Main message loop
-fn main_loop(connection: Connection) -> Result<()> {
-    let mut documents: HashMap<Url, String> = HashMap::new();
+/// The workspace the client opened: its first folder, else the older
+/// `rootUri` and `rootPath`. Without one, files that aren't open are neither
+/// read nor written.
+#[allow(deprecated)]
+fn workspace_root(init: &InitializeParams) -> Option<PathBuf> {
+    init.workspace_folders
+        .as_ref()
+        .and_then(|folders| folders.first())
+        .and_then(|folder| folder.uri.to_file_path().ok())
+        .or_else(|| init.root_uri.as_ref().and_then(|uri| uri.to_file_path().ok()))
+        .or_else(|| init.root_path.as_deref().filter(|path| !path.is_empty()).map(PathBuf::from))
+}
+
+/// Optional client features negotiated during initialize
+#[derive(Debug, Clone, Default)]
+struct ClientInfo {
+    /// Client accepts `workspace/applyEdit` requests
+    apply_edit: bool,
+    /// Client accepts versioned `documentChanges` in workspace edits
+    document_changes: bool,
+    /// Client accepts `create` operations in `documentChanges`
+    create_files: bool,
+    /// Client accepts `window/showMessageRequest` requests, for prompts with
+    /// buttons; `window/showMessage` notifications are always sent
+    show_message_request: bool,
+    /// Client accepts `window/workDoneProgress/create` requests
+    work_done_progress: bool,
+    /// Client handles `textDocument/publishDiagnostics` notifications
+    publish_diagnostics: bool,
+    /// Client asked for `mermaid/fenceStatus` notifications (`experimental.fenceStatus`)
+    fence_status: bool,
+    /// Negotiated unit for position character offsets
+    position_encoding: PositionEncoding,
+}
+
+impl ClientInfo {
+    fn from_capabilities(caps: &ClientCapabilities) -> Self {
+        let workspace = caps.workspace.as_ref();
+        let window = caps.window.as_ref();
+        Self {
+            apply_edit: workspace.and_then(|w| w.apply_edit).unwrap_or(false),
+            document_changes: workspace
+                .and_then(|w| w.workspace_edit.as_ref())
+                .and_then(|e| e.document_changes)
+                .unwrap_or(false),
+            create_files: workspace
+                .and_then(|w| w.workspace_edit.as_ref())
+                .and_then(|e| e.resource_operations.as_ref())
+                .is_some_and(|ops| ops.contains(&ResourceOperationKind::Create)),
+            show_message_request: window.and_then(|w| w.show_message.as_ref()).is_some(),
+            work_done_progress: window.and_then(|w| w.work_done_progress).unwrap_or(false),
+            publish_diagnostics: caps
+                .text_document
+                .as_ref()
+                .and_then(|t| t.publish_diagnostics.as_ref())
+                .is_some(),
+            fence_status: caps
+                .experimental
+                .as_ref()
+                .and_then(|e| e.get("fenceStatus"))
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+            position_encoding: PositionEncoding::negotiate(caps),
+        }
+    }
+}
+
+/// State shared by all handlers for the lifetime of the session
+struct ServerState {
+    documents: HashMap<Url, String>,
+    /// Latest version reported by the client for each open document
+    versions: HashMap<Url, i32>,
+    client: ClientInfo,
+    config: Config,
+    /// Diagnostics of fences that failed in the last "Render All", keyed by code hash
+    render_failures: HashMap<Url, HashMap<u64, Diagnostic>>,
+    /// Fences of each open document as of the last published diagnostics
+    checked: HashMap<Url, CheckedDocument>,
+    /// When each open document was last opened, edited or used by a request,
+    /// to drop the derived state of the least recently used ones first
+    recency: Recency,
+    /// What was last published for each open document, so unchanged
+    /// diagnostics and fence statuses aren't sent again
+    published: Publisher,
+    /// Requests sent to the client that await its answer
+    outgoing: OutgoingRequests<Outgoing>,
+    /// Messages read while a long-running request polled for cancellation
+    deferred: VecDeque<Message>,
+    /// The client's workspace, from [`workspace_root`]; never the directory the
+    /// server started in. Files outside it are never read or written unless
+    /// open, and without one no file that isn't open is.
+    workspace_root: Option<PathBuf>,
+    /// `.mermaidrc` files read for the documents' directories
+    rc_files: RcFiles,
+}
 
-    for msg in &connection.receiver {
+/// Main message loop
+fn main_loop(connection: Connection, state: &mut ServerState) -> Result<()> {
+    loop {
+        if !state.outgoing.is_empty() {
+            state.outgoing.expire(Instant::now());
+        }
+        let msg = match state.deferred.pop_front() {
+            Some(msg) => msg,
+            None => match connection.receiver.recv() {
+                Ok(msg) => msg,
+                Err(_) => break,
+            },
+        };
         match msg {
             Message::Request(req) => {
                 if connection.handle_shutdown(&req)? {
                     return Ok(());
                 }
-                if let Err(e) = handle_request(&connection, &req, &documents) {
+                if let Err(e) = handle_request(&connection, &req, state) {
                     error!("Error handling request {}: {e}", req.method);
                 }
+                if let Err(e) = ask_trust(&connection, state) {
+                    error!("Error asking to trust mmdc: {e}");
+                }
+                if let Err(e) = ask_gitignore(&connection, state) {
+                    error!("Error offering to gitignore generated files: {e}");
+                }
             }
             Message::Notification(not) => {
-                handle_notification(&not, &mut documents);
+                if let Err(e) = handle_notification(&connection, &not, state) {
+                    error!("Error handling notification {}: {e}", not.method);
+                }
+            }
+            Message::Response(resp) => {
+                if let Err(e) = handle_response(&connection, resp, state) {
+                    error!("Error handling response: {e}");
+                }
             }
-            Message::Response(_) => {}
         }
     }
 
@@ -73,613 +357,5986 @@ fn main_loop(connection: Connection) -> Result<()> {
 
 // ─── Notification handlers ──────────────────────────────────────────────────
 
-fn handle_notification(not: &Notification, documents: &mut HashMap<Url, String>) {
+fn handle_notification(
+    connection: &Connection,
+    not: &Notification,
+    state: &mut ServerState,
+) -> ServerResult<()> {
     match not.method.as_str() {
         "textDocument/didOpen" => {
             if let Ok(params) = serde_json::from_value::<DidOpenTextDocumentParams>(not.params.clone()) {
-                info!("Document opened: {}", params.text_document.uri);
-                documents.insert(params.text_document.uri, params.text_document.text);
+                let uri = params.text_document.uri;
+                info!("Document opened: {uri}");
+                state.documents.insert(uri.clone(), params.text_document.text);
+                state.versions.insert(uri.clone(), params.text_document.version);
+                state.recency.touch(&uri);
+                publish_document_diagnostics(connection, state, &uri)?;
+                publish_document_status(connection, state, &uri)?;
+                enforce_derived_state_cap(state, Some(&uri));
             }
         }
         "textDocument/didChange" => {
             if let Ok(params) = serde_json::from_value::<DidChangeTextDocumentParams>(not.params.clone()) {
                 if let Some(change) = params.content_changes.first() {
-                    documents.insert(params.text_document.uri, change.text.clone());
+                    let uri = params.text_document.uri;
+                    state.documents.insert(uri.clone(), change.text.clone());
+                    state.versions.insert(uri.clone(), params.text_document.version);
+                    state.recency.touch(&uri);
+                    publish_changed_document(connection, state, &uri)?;
+                    enforce_derived_state_cap(state, Some(&uri));
                 }
             }
         }
+        "textDocument/didSave" => {
+            if let Ok(params) = serde_json::from_value::<DidSaveTextDocumentParams>(not.params.clone()) {
+                publish_document_status(connection, state, &params.text_document.uri)?;
+            }
+        }
         "textDocument/didClose" => {
             if let Ok(params) = serde_json::from_value::<DidCloseTextDocumentParams>(not.params.clone()) {
-                documents.remove(&params.text_document.uri);
+                let uri = params.text_document.uri;
+                state.documents.remove(&uri);
+                state.versions.remove(&uri);
+                state.render_failures.remove(&uri);
+                state.checked.remove(&uri);
+                state.recency.forget(&uri);
+                if state.client.publish_diagnostics {
+                    publish_diagnostics(connection, &mut state.published, &uri, Vec::new())?;
+                }
+                state.published.forget(&uri);
             }
         }
         _ => {}
     }
+    Ok(())
 }
 
-// ─── Request handlers ───────────────────────────────────────────────────────
-
-fn handle_request(
+/// Publish the semantic checks and recorded render failures for an open document
+fn publish_document_diagnostics(
     connection: &Connection,
-    req: &Request,
-    documents: &HashMap<Url, String>,
-) -> Result<()> {
-    match req.method.as_str() {
-        "textDocument/codeAction" => handle_code_action(connection, req, documents),
-        "workspace/executeCommand" => handle_execute_command(connection, req, documents),
-        _ => {
-            let resp = Response::new_ok(req.id.clone(), Value::Null);
-            connection.sender.send(Message::Response(resp))?;
-            Ok(())
-        }
+    state: &mut ServerState,
+    uri: &Url,
+) -> ServerResult<()> {
+    let Some(doc) = state.documents.get(uri) else {
+        return Ok(());
+    };
+    let diagnostics = if state.client.publish_diagnostics {
+        let config = document_config(&mut state.rc_files, &state.config, uri, state.workspace_root.as_deref());
+        document_diagnostics(doc, state.render_failures.get(uri), state.client.position_encoding, &config)
+    } else {
+        Vec::new()
+    };
+    state.checked.insert(uri.clone(), checked_document(doc, diagnostics.clone()));
+    if !state.client.publish_diagnostics {
+        return Ok(());
     }
+    publish_diagnostics(connection, &mut state.published, uri, diagnostics)
 }
 
-// ─── Code Actions ───────────────────────────────────────────────────────────
-
-fn handle_code_action(
-    connection: &Connection,
-    req: &Request,
-    documents: &HashMap<Url, String>,
-) -> Result<()> {
-    let params: CodeActionParams = serde_json::from_value(req.params.clone())?;
-    let uri = &params.text_document.uri;
-    let cursor_line = params.range.start.line as usize;
-
-    let doc = documents
-        .get(uri)
-        .ok_or_else(|| anyhow!("Document not found: {uri}"))?;
+/// After an edit, check and publish only what the edit can have changed: an
+/// edit to prose alone leaves every fence's hash and position as they were and
+/// needs nothing, one that moves fences only moves their diagnostics
+fn publish_changed_document(connection: &Connection, state: &mut ServerState, uri: &Url) -> ServerResult<()> {
+    let Some(doc) = state.documents.get(uri) else {
+        return Ok(());
+    };
     let lines: Vec<&str> = doc.lines().collect();
-
-    let mut actions: Vec<CodeActionOrCommand> = Vec::new();
-
-    // Check if cursor is inside a ```mermaid block
-    if let Some(fence) = find_mermaid_fence(&lines, cursor_line) {
-        // Offer "Render Mermaid Diagram"
-        if let Some(edit) = create_render_edit(uri, doc, &lines, &fence) {
-            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
-                title: "Render Mermaid Diagram".to_string(),
-                kind: Some(CodeActionKind::QUICKFIX),
-                edit: Some(edit),
-                ..Default::default()
-            }));
+    let recheck = state
+        .checked
+        .get(uri)
+        .map_or(Recheck::Changed, |checked| checked.recheck(&fence_keys(&lines), find_unclosed_mermaid_fence(&lines)));
+    match recheck {
+        Recheck::Unchanged => {
+            debug!("No fence changed in {uri}");
+            Ok(())
+        }
+        Recheck::Moved(diagnostics) => {
+            state.checked.insert(uri.clone(), checked_document(doc, diagnostics.clone()));
+            if state.client.publish_diagnostics {
+                publish_diagnostics(connection, &mut state.published, uri, diagnostics)?;
+            }
+            publish_document_status(connection, state, uri)
+        }
+        Recheck::Changed => {
+            publish_document_diagnostics(connection, state, uri)?;
+            publish_document_status(connection, state, uri)
         }
     }
+}
 
-    // Check if cursor is on a mermaid-source-file comment or image reference
-    if let Some(edit) = find_source_edit_at_cursor(uri, doc, &lines, cursor_line) {
-        actions.push(CodeActionOrCommand::CodeAction(CodeAction {
-            title: "Edit Mermaid Source".to_string(),
-            kind: Some(CodeActionKind::REFACTOR),
-            edit: Some(edit),
-            ..Default::default()
-        }));
+/// Bytes held for the open documents, by category
+fn retained_bytes(state: &ServerState) -> RetainedBytes {
+    let mut bytes = RetainedBytes {
+        text: state.documents.values().map(String::len).sum(),
+        ..Default::default()
+    };
+    for checked in state.checked.values() {
+        let (fence_index, diagnostics) = checked.retained_bytes();
+        bytes.fence_index += fence_index;
+        bytes.diagnostics += diagnostics;
     }
+    bytes.render_failures = state
+        .render_failures
+        .values()
+        .flat_map(HashMap::values)
+        .map(memory::diagnostic_bytes)
+        .sum();
+    bytes
+}
 
-    // Always offer bulk operations if the document has mermaid content
-    let has_mermaid_blocks = lines
-        .iter()
-        .any(|l| l.trim_start().starts_with("```mermaid"));
-    let has_rendered = lines
-        .iter()
-        .any(|l| l.contains("<!-- mermaid-source-file:"));
-
-    if has_mermaid_blocks {
-        if let Some(edit) = create_render_all_edit(uri, doc, &lines) {
-            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
-                title: "Render All Mermaid Diagrams".to_string(),
-                kind: Some(CodeActionKind::SOURCE),
-                edit: Some(edit),
-                ..Default::default()
-            }));
+/// Drop the derived state of the least recently used documents until it fits
+/// `derivedStateCapBytes`. `current`'s is kept; the others' is recomputed on
+/// their next change.
+fn enforce_derived_state_cap(state: &mut ServerState, current: Option<&Url>) {
+    let cap = state.config.derived_state_cap_bytes;
+    let mut derived = retained_bytes(state).derived();
+    while derived > cap {
+        let Some(uri) = state.recency.least_recent(state.checked.keys().filter(|uri| Some(*uri) != current)).cloned() else {
+            break;
+        };
+        if let Some(checked) = state.checked.remove(&uri) {
+            let (fence_index, diagnostics) = checked.retained_bytes();
+            derived -= fence_index + diagnostics;
+            debug!("Dropped derived state of {uri} ({} bytes)", fence_index + diagnostics);
         }
     }
+}
 
-    if has_rendered {
-        if let Some(edit) = create_edit_all_sources(uri, doc, &lines) {
-            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
-                title: "Edit All Mermaid Sources".to_string(),
-                kind: Some(CodeActionKind::SOURCE),
-                edit: Some(edit),
-                ..Default::default()
-            }));
-        }
-    }
+/// Result of `mermaid.stats`
+fn stats(state: &ServerState) -> Value {
+    let bytes = retained_bytes(state);
+    let mut retained = serde_json::to_value(bytes).unwrap_or_default();
+    retained["total"] = bytes.total().into();
+    serde_json::json!({
+        "documents": state.documents.len(),
+        "documentsWithDerivedState": state.checked.len(),
+        "retainedBytes": retained,
+        "derivedStateCapBytes": state.config.derived_state_cap_bytes,
+        "sessionRenders": render::session_renders(),
+    })
+}
 
-    let resp = Response::new_ok(req.id.clone(), serde_json::to_value(actions)?);
-    connection.sender.send(Message::Response(resp))?;
-    Ok(())
+/// What the diagnostics of each fence in the document depend on
+fn fence_keys(lines: &[&str]) -> Vec<FenceKey> {
+    find_all_mermaid_fences(lines)
+        .into_iter()
+        .map(|fence| FenceKey {
+            hash: code_hash(&fence.code),
+            info: fence.info,
+            ignored: fence.ignored,
+            start_line: fence.start_line,
+            end_line: fence.end_line,
+        })
+        .collect()
+}
+
+/// `doc` as checked, with the diagnostics published for it
+fn checked_document(doc: &str, diagnostics: Vec<Diagnostic>) -> CheckedDocument {
+    let lines: Vec<&str> = doc.lines().collect();
+    CheckedDocument::new(fence_keys(&lines), find_unclosed_mermaid_fence(&lines), diagnostics)
+}
+
+/// Publish the render state of an open document's fences
+fn publish_document_status(connection: &Connection, state: &mut ServerState, uri: &Url) -> ServerResult<()> {
+    let Some(doc) = state.documents.get(uri) else {
+        return Ok(());
+    };
+    let status = FenceStatusContext {
+        uri,
+        version: state.versions.get(uri).copied(),
+        failures: state.render_failures.get(uri),
+        encoding: state.client.position_encoding,
+    };
+    let config = document_config(&mut state.rc_files, &state.config, uri, state.workspace_root.as_deref());
+    publish_fence_status(connection, &state.client, &mut state.published, &status, doc, &HashMap::new(), &config)
 }
 
-// ─── Execute Command ────────────────────────────────────────────────────────
+/// The document a `mermaid/fenceStatus` notification describes
+struct FenceStatusContext<'a> {
+    uri: &'a Url,
+    version: Option<i32>,
+    failures: Option<&'a HashMap<u64, Diagnostic>>,
+    encoding: PositionEncoding,
+}
 
-fn handle_execute_command(
+/// Send `mermaid/fenceStatus` for `doc` to a client that asked for it, unless
+/// its fences are as last sent. `overrides` (by code hash) replace the computed
+/// state of fences a command is rendering or just rendered.
+fn publish_fence_status(
     connection: &Connection,
-    req: &Request,
-    documents: &HashMap<Url, String>,
-) -> Result<()> {
-    let params: ExecuteCommandParams = serde_json::from_value(req.params.clone())?;
-
-    match params.command.as_str() {
-        "mermaid.renderSingle" | "mermaid.renderAllLightweight" => {
-            if let Some(uri_val) = params.arguments.first() {
-                let uri: Url = serde_json::from_value(uri_val.clone())?;
-                if let Some(doc) = documents.get(&uri) {
-                    let lines: Vec<&str> = doc.lines().collect();
-                    let edit = if params.command == "mermaid.renderAllLightweight" {
-                        create_render_all_edit(&uri, doc, &lines)
-                    } else {
-                        // Find first mermaid block
-                        find_all_mermaid_fences(&lines)
-                            .first()
-                            .and_then(|fence| create_render_edit(&uri, doc, &lines, fence))
-                    };
-
-                    if let Some(workspace_edit) = edit {
-                        apply_edit(connection, workspace_edit)?;
-                    }
-                }
-            }
-        }
-        "mermaid.editSingleSource" | "mermaid.editAllSources" => {
-            if let Some(uri_val) = params.arguments.first() {
-                let uri: Url = serde_json::from_value(uri_val.clone())?;
-                if let Some(doc) = documents.get(&uri) {
-                    let lines: Vec<&str> = doc.lines().collect();
-                    let edit = if params.command == "mermaid.editAllSources" {
-                        create_edit_all_sources(&uri, doc, &lines)
-                    } else {
-                        find_all_rendered_blocks(&lines)
-                            .first()
-                            .and_then(|rb| create_source_edit(&uri, doc, &lines, rb))
-                    };
+    client: &ClientInfo,
+    published: &mut Publisher,
+    context: &FenceStatusContext,
+    doc: &str,
+    overrides: &HashMap<u64, FenceState>,
+    config: &Config,
+) -> ServerResult<()> {
+    if !client.fence_status {
+        return Ok(());
+    }
+    let params = FenceStatusParams {
+        uri: context.uri.clone(),
+        version: context.version,
+        fences: fence_statuses(context, doc, overrides, config),
+    };
+    // The version alone changing says nothing new about the fences
+    if !published.should_send(context.uri, Channel::FenceStatus, &params.fences) {
+        return Ok(());
+    }
+    let not = Notification::new(status::METHOD.to_string(), serde_json::to_value(params)?);
+    send(connection, Message::Notification(not))
+}
 
-                    if let Some(workspace_edit) = edit {
-                        apply_edit(connection, workspace_edit)?;
-                    }
-                }
+/// Render state of each fence: a command's override, else the recorded render
+/// failure, else whether the SVG is cached
+fn fence_statuses(
+    context: &FenceStatusContext,
+    doc: &str,
+    overrides: &HashMap<u64, FenceState>,
+    config: &Config,
+) -> Vec<FenceStatus> {
+    let lines: Vec<&str> = doc.lines().collect();
+    find_all_mermaid_fences(&lines)
+        .iter()
+        .map(|fence| {
+            let hash = code_hash(&fence.code);
+            let failure = context.failures.and_then(|failures| failures.get(&hash));
+            let state = match (overrides.get(&hash), failure) {
+                _ if fence.ignored => FenceState::Ignored,
+                (Some(state), _) => state.clone(),
+                (None, Some(failure)) => FenceState::Error {
+                    message: failure.message.clone(),
+                },
+                (None, None) if is_fence_cached(context.uri, fence, config) => FenceState::Cached,
+                (None, None) => FenceState::Unrendered,
+            };
+            FenceStatus {
+                range: line_range(&lines, fence.start_line, fence.end_line, context.encoding),
+                hash: format!("{hash:016x}"),
+                state,
             }
+        })
+        .collect()
+}
+
+/// Diagnostics for every fence: render failures follow their fence by code hash,
+/// so they stay attached when lines above are edited.
+fn document_diagnostics(
+    doc: &str,
+    render_failures: Option<&HashMap<u64, Diagnostic>>,
+    encoding: PositionEncoding,
+    config: &Config,
+) -> Vec<Diagnostic> {
+    let lines: Vec<&str> = doc.lines().collect();
+    let mut diagnostics = Vec::new();
+
+    for fence in find_all_mermaid_fences(&lines).iter().filter(|fence| !fence.ignored) {
+        let unresolved = variables::check(&fence.code, fence.start_line + 1, encoding, config);
+        // A fence with unresolved placeholders failed to render because of them
+        let render_failure = render_failures
+            .filter(|_| unresolved.is_empty())
+            .and_then(|f| f.get(&code_hash(&fence.code)));
+        if let Some(failure) = render_failure {
+            diagnostics.push(Diagnostic {
+                range: failure_range(&lines, fence, &failure.message, encoding, config),
+                ..failure.clone()
+            });
         }
-        _ => {
-            warn!("Unknown command: {}", params.command);
+        if let Some(problem) = backend::check(&fence.info, config) {
+            let range = line_range(&lines, fence.start_line, fence.start_line, encoding);
+            diagnostics.push(problem.to_diagnostic(range));
+        }
+        if let Some(problem) = remote::check(&fence.info, config) {
+            let range = line_range(&lines, fence.start_line, fence.start_line, encoding);
+            diagnostics.push(problem.to_diagnostic(range));
+        }
+        diagnostics.extend(unresolved);
+        diagnostics.extend(diagnostics::check_diagram(&fence.code, fence.start_line + 1, encoding, config));
+    }
+    for block in find_all_rendered_blocks(&lines) {
+        if let Some(diagnostic) = mismatched_block(&lines, &block, encoding, config) {
+            diagnostics.push(diagnostic);
         }
     }
+    if let Some(line) = find_unclosed_mermaid_fence(&lines) {
+        let range = line_range(&lines, line, line, encoding);
+        diagnostics.push(
+            ServerError::ValidationFailed("Mermaid code block is never closed".to_string())
+                .to_diagnostic(range),
+        );
+    }
 
-    let resp = Response::new_ok(req.id.clone(), Value::Null);
-    connection.sender.send(Message::Response(resp))?;
-    Ok(())
+    diagnostics
 }
 
-/// Send workspace/applyEdit request to the client
-fn apply_edit(connection: &Connection, edit: WorkspaceEdit) -> Result<()> {
-    let params = ApplyWorkspaceEditParams {
-        label: Some("Mermaid".to_string()),
-        edit,
-    };
+// ─── Request handlers ───────────────────────────────────────────────────────
 
-    let req = Request::new(
-        lsp_server::RequestId::from(format!("apply-edit-{}", Local::now().timestamp_millis())),
-        "workspace/applyEdit".to_string(),
-        serde_json::to_value(params)?,
-    );
+fn handle_request(connection: &Connection, req: &Request, state: &mut ServerState) -> ServerResult<()> {
+    let result = match req.method.as_str() {
+        "textDocument/codeAction" => handle_code_action(req, state),
+        "workspace/executeCommand" => commands::handle_execute_command(connection, req, state),
+        _ => Ok(Value::Null),
+    };
 
-    connection.sender.send(Message::Request(req))?;
-    Ok(())
+    let resp = match result {
+        Ok(value) => Response::new_ok(req.id.clone(), value),
+        Err(ServerError::Disconnected) => return Err(ServerError::Disconnected),
+        Err(e) => {
+            report_error(connection, req, &e)?;
+            e.to_response(req.id.clone())
+        }
+    };
+    send(connection, Message::Response(resp))
 }
 
-// ─── Mermaid block detection ────────────────────────────────────────────────
-
-/// A detected ```mermaid ... ``` code fence
-#[derive(Debug, Clone)]
-struct MermaidFence {
-    /// Line index of the opening ```mermaid
-    start_line: usize,
-    /// Line index of the closing ```
-    end_line: usize,
-    /// The mermaid code content (without the fences)
-    code: String,
+/// Tell the user about a failed command; other failed requests are only logged
+fn report_error(connection: &Connection, req: &Request, error: &ServerError) -> ServerResult<()> {
+    match error.message_type() {
+        Some(typ) if req.method == "workspace/executeCommand" => {
+            show_message(connection, typ, &format!("Mermaid: {error}"))
+        }
+        _ => {
+            warn!("{} failed: {error}", req.method);
+            Ok(())
+        }
+    }
 }
 
-/// Find a mermaid fence that contains the given cursor line
-fn find_mermaid_fence(lines: &[&str], cursor_line: usize) -> Option<MermaidFence> {
-    find_all_mermaid_fences(lines)
-        .into_iter()
-        .find(|fence| cursor_line >= fence.start_line && cursor_line <= fence.end_line)
+fn send(connection: &Connection, msg: Message) -> ServerResult<()> {
+    connection
+        .sender
+        .send(msg)
+        .map_err(|_| ServerError::Disconnected)
 }
 
-/// Find all ```mermaid fences in the document
-fn find_all_mermaid_fences(lines: &[&str]) -> Vec<MermaidFence> {
-    let mut fences = Vec::new();
-    let mut i = 0;
+// ─── Code Actions ───────────────────────────────────────────────────────────
 
-    while i < lines.len() {
-        let trimmed = lines[i].trim_start();
-        if trimmed.starts_with("```mermaid") && !trimmed.starts_with("````") {
-            let start = i;
-            i += 1;
-            // Find closing ```
-            while i < lines.len() {
-                let t = lines[i].trim_start();
-                if t == "```" || t.starts_with("```\r") {
-                    let code = lines[start + 1..i].join("\n");
-                    fences.push(MermaidFence {
-                        start_line: start,
-                        end_line: i,
-                        code,
-                    });
-                    break;
-                }
-                i += 1;
-            }
-        }
-        i += 1;
-    }
+fn handle_code_action(req: &Request, state: &mut ServerState) -> ServerResult<Value> {
+    let params: CodeActionParams = serde_json::from_value(req.params.clone())?;
+    let uri = &params.text_document.uri;
+    state.recency.touch(uri);
+    let cursor_line = params.range.start.line as usize;
 
-    fences
-}
+    let doc = state
+        .documents
+        .get(uri)
+        .ok_or_else(|| ServerError::DocumentNotFound(uri.clone()))?;
+    let config = document_config(&mut state.rc_files, &state.config, uri, state.workspace_root.as_deref());
+    let actions = code_actions(uri, doc, cursor_line, &config, state.client.position_encoding);
 
-/// A rendered mermaid block (comment + image reference)
-#[derive(Debug, Clone)]
-struct RenderedBlock {
-    /// Line of <!-- mermaid-source-file:... -->
-    comment_line: usize,
-    /// Line of the last line of this rendered block (image ref or blank line)
-    end_line: usize,
-    /// Path to the .mmd source file
-    source_file: String,
+    Ok(serde_json::to_value(actions)?)
 }
 
-/// Find all rendered mermaid blocks in the document
-fn find_all_rendered_blocks(lines: &[&str]) -> Vec<RenderedBlock> {
-    let mut blocks = Vec::new();
-    let mut i = 0;
+/// Compute the code actions for a cursor position.
+///
+/// Only the block under the cursor is rendered eagerly; bulk actions are commands
+/// so the cost of a request doesn't grow with the number of diagrams.
+fn code_actions(
+    uri: &Url,
+    doc: &str,
+    cursor_line: usize,
+    config: &Config,
+    encoding: PositionEncoding,
+) -> Vec<CodeActionOrCommand> {
+    // Every action writes rendered files next to the document
+    if let Err(e) = doc_base_dir(uri) {
+        debug!("No code actions for {uri}: {e}");
+        return Vec::new();
+    }
 
-    while i < lines.len() {
-        if let Some(source_file) = extract_source_file_path(lines[i]) {
-            let comment_line = i;
-            let mut end_line = i;
+    let lines: Vec<&str> = doc.lines().collect();
+    let fences = find_all_mermaid_fences(&lines);
+    let blocks = find_all_rendered_blocks(&lines);
+    let position = classify_position(doc, cursor_line);
+    let mut actions: Vec<CodeActionOrCommand> = Vec::new();
 
-            // Look ahead for blank line + image reference
-            let mut j = i + 1;
-            while j < lines.len() {
-                let trimmed = lines[j].trim();
-                if trimmed.is_empty() {
-                    j += 1;
-                    continue;
-                }
-                if trimmed.starts_with("![") && trimmed.contains("(.mermaid/") {
-                    end_line = j;
+    match position {
+        // Offer "Render Mermaid Diagram" inside a ```mermaid block
+        PositionContext::InFence { index } => {
+            let fence = &fences[index];
+            if fence.ignored {
+                return vec![ignore_toggle_action(uri, &lines, fence, encoding)];
+            }
+            // Rendering writes files; "Render All" still offers previews
+            match read_only_reason(uri, config).or_else(|| fence.empty_reason().map(|e| e.to_string())) {
+                Some(reason) => debug!("Not offering {}: {reason}", text(Text::RenderDiagram)),
+                None => actions.extend(render_action(uri, doc, &lines, fence, config, encoding)),
+            }
+            actions.extend(quick_fix_actions(uri, &lines, fence, encoding));
+            actions.extend(split_subgraph_action(uri, &lines, fence, cursor_line, encoding));
+            // MDX has no HTML comments to mark a component ignored with
+            if fence.syntax == Syntax::Markdown {
+                actions.push(ignore_toggle_action(uri, &lines, fence, encoding));
+            }
+        }
+        // Offer "Edit Mermaid Source" on a rendered block
+        PositionContext::InRenderedBlock { index } => {
+            let block = &blocks[index];
+            match create_source_edit(uri, doc, &lines, block, encoding) {
+                Ok(edit) => actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                    title: text(Text::EditSource).to_string(),
+                    kind: Some(CodeActionKind::REFACTOR),
+                    edit: Some(edit),
+                    ..Default::default()
+                })),
+                Err(e) => warn!("Not offering Edit Mermaid Source: {e}"),
+            }
+            if let Some(diagnostic) = mismatched_block(&lines, block, encoding, config) {
+                match create_realign_edit(uri, &lines, block, config, encoding) {
+                    Ok(edit) => actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                        title: text(Text::RealignRenderedBlock).to_string(),
+                        kind: Some(CodeActionKind::QUICKFIX),
+                        diagnostics: Some(vec![diagnostic]),
+                        edit: Some(edit),
+                        is_preferred: Some(true),
+                        ..Default::default()
+                    })),
+                    Err(e) => warn!("Not offering {}: {e}", text(Text::RealignRenderedBlock)),
                 }
-                break;
             }
-
-            blocks.push(RenderedBlock {
-                comment_line,
-                end_line,
-                source_file,
-            });
-
-            i = end_line + 1;
-        } else {
-            i += 1;
         }
+        PositionContext::Outside => {}
     }
 
-    blocks
-}
-
-/// Extract the source file path from a mermaid comment line
-fn extract_source_file_path(line: &str) -> Option<String> {
-    let trimmed = line.trim();
-    if trimmed.starts_with("<!-- mermaid-source-file:") && trimmed.ends_with("-->") {
-        let inner = trimmed
-            .strip_prefix("<!-- mermaid-source-file:")?
-            .strip_suffix("-->")?
-            .trim();
-        Some(inner.to_string())
-    } else {
-        None
+    // Bulk operations, unless the only block they'd touch is the one under
+    // the cursor, which has its own action
+    let renderable = |fence: &MermaidFence| !fence.ignored && fence.empty_reason().is_none();
+    let unrendered = fences.iter().filter(|fence| renderable(fence)).count();
+    let in_fence = matches!(position, PositionContext::InFence { index } if renderable(&fences[index]));
+    if offer_bulk_action(unrendered, in_fence) {
+        actions.push(bulk_action(
+            &format!("{} ({unrendered})", text(Text::RenderAll)),
+            "mermaid.renderAllLightweight",
+            uri,
+        ));
     }
-}
 
-// ─── Rendering edits ────────────────────────────────────────────────────────
+    let in_block = matches!(position, PositionContext::InRenderedBlock { .. });
+    if offer_bulk_action(blocks.len(), in_block) {
+        actions.push(bulk_action(
+            &format!("{} ({})", text(Text::EditAllSources), blocks.len()),
+            "mermaid.editAllSources",
+            uri,
+        ));
+    }
 
-/// Compute a hash for caching purposes
-fn code_hash(code: &str) -> u64 {
-    let mut hasher = DefaultHasher::new();
-    code.hash(&mut hasher);
-    hasher.finish()
+    actions
 }
 
-/// Get the document's base directory (where .mermaid/ will be created)
-fn doc_base_dir(uri: &Url) -> Option<PathBuf> {
-    uri.to_file_path().ok().and_then(|p| p.parent().map(|d| d.to_path_buf()))
+/// Whether a bulk action over `count` blocks adds anything: for two or more,
+/// or for one the cursor isn't on
+fn offer_bulk_action(count: usize, cursor_on_one: bool) -> bool {
+    count >= 2 || (count == 1 && !cursor_on_one)
 }
 
-/// Get a short name for the document (without extension)
-fn doc_short_name(uri: &Url) -> String {
-    uri.to_file_path()
-        .ok()
-        .and_then(|p| p.file_stem().map(|s| s.to_string_lossy().to_string()))
-        .unwrap_or_else(|| "document".to_string())
+/// Whether the fence's SVG is in the render cache (checked in memory)
+fn is_fence_cached(uri: &Url, fence: &MermaidFence, config: &Config) -> bool {
+    backend::select(&fence.info, config).is_ok_and(|backend| is_cached_with(uri, fence, backend, config))
 }
 
-/// Ensure the .mermaid directory exists
-fn ensure_mermaid_dir(base_dir: &Path) -> Result<PathBuf> {
-    let mermaid_dir = base_dir.join(".mermaid");
-    fs::create_dir_all(&mermaid_dir)?;
-    Ok(mermaid_dir)
+fn is_cached_with(uri: &Url, fence: &MermaidFence, backend: Backend, config: &Config) -> bool {
+    doc_base_dir(uri).is_ok_and(|base_dir| {
+        cache::is_cached(&cache_dir(&output_dir(&base_dir, config), config), &cache_key(&fence.code, backend, config))
+    })
 }
 
-/// Create a workspace edit that renders a single mermaid fence to SVG
-fn create_render_edit(
+/// "Render Mermaid Diagram", titled by what rendering will cost: instant when
+/// the fence's SVG is cached, disabled when no backend can render it. The cache
+/// is consulted in memory, so only an uncached fence is rendered eagerly.
+fn render_action(
     uri: &Url,
-    _doc: &str,
+    doc: &str,
     lines: &[&str],
     fence: &MermaidFence,
-) -> Option<WorkspaceEdit> {
+    config: &Config,
+    encoding: PositionEncoding,
+) -> Option<CodeActionOrCommand> {
+    let backend = backend::select(&fence.info, config)
+        .inspect_err(|e| warn!("Not offering {}: {e}", text(Text::RenderDiagram)))
+        .ok()?;
+    let cached = is_cached_with(uri, fence, backend, config);
+
+    if !cached {
+        if let Some(reason) = render::backend_unavailable(backend, config) {
+            return Some(CodeActionOrCommand::CodeAction(CodeAction {
+                title: text(Text::RenderDiagramUnavailable).to_string(),
+                kind: Some(CodeActionKind::QUICKFIX),
+                disabled: Some(CodeActionDisabled { reason }),
+                ..Default::default()
+            }));
+        }
+    }
+
+    let title = if cached { Text::RenderDiagramCached } else { Text::RenderDiagram };
+    match create_render_edit(uri, doc, lines, fence, config, encoding) {
+        Ok(edit) => Some(CodeActionOrCommand::CodeAction(CodeAction {
+            title: text(title).to_string(),
+            kind: Some(CodeActionKind::QUICKFIX),
+            edit: Some(edit),
+            ..Default::default()
+        })),
+        Err(e) => {
+            warn!("Not offering {}: {e}", text(Text::RenderDiagram));
+            None
+        }
+    }
+}
+
+/// Quick fixes for common mistakes in the fence body, such as smart quotes or
+/// a missing diagram header. A fence with a remote source has no body to fix.
+fn quick_fix_actions(
+    uri: &Url,
+    lines: &[&str],
+    fence: &MermaidFence,
+    encoding: PositionEncoding,
+) -> Vec<CodeActionOrCommand> {
+    if remote::source_url(&fence.info).is_some() {
+        return Vec::new();
+    }
+    quickfix::quick_fixes(&fence.code)
+        .into_iter()
+        .map(|fix| {
+            let first_line = fence.start_line + 1 + fix.first_line;
+            let last_line = fence.start_line + 1 + fix.last_line;
+            let range = line_range(lines, first_line, last_line, encoding);
+            let mut changes = HashMap::new();
+            changes.insert(uri.clone(), vec![TextEdit::new(range, fence.syntax.body(&fix.replacement).into_owned())]);
+            CodeActionOrCommand::CodeAction(CodeAction {
+                title: text(fix.title).to_string(),
+                kind: Some(CodeActionKind::QUICKFIX),
+                edit: Some(WorkspaceEdit::new(changes)),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+/// "Split Subgraph into Separate Diagram" when the cursor is inside a flowchart
+/// subgraph: the fence is replaced by the reduced diagram followed by an
+/// anchored fence holding the subgraph
+fn split_subgraph_action(
+    uri: &Url,
+    lines: &[&str],
+    fence: &MermaidFence,
+    cursor_line: usize,
+    encoding: PositionEncoding,
+) -> Option<CodeActionOrCommand> {
+    let code_line = cursor_line.checked_sub(fence.start_line + 1)?;
+    let taken = lines
+        .iter()
+        .filter_map(|line| anchors::parse_anchor_line(line))
+        .map(str::to_string)
+        .collect();
+    let split = split::split_subgraph(&fence.code, code_line, taken)?;
+
+    let opener = lines[fence.start_line].trim_end();
+    let closer = lines[fence.end_line].trim_end();
+    let replacement = format!(
+        "{opener}\n{}\n{closer}\n\n{}\n{opener}\n{}\n{closer}",
+        fence.syntax.body(&split.original),
+        anchors::anchor_line(&split.anchor),
+        fence.syntax.body(&split.extracted)
+    );
+    let range = line_range(lines, fence.start_line, fence.end_line, encoding);
+    let mut changes = HashMap::new();
+    changes.insert(uri.clone(), vec![TextEdit::new(range, replacement)]);
+
+    Some(CodeActionOrCommand::CodeAction(CodeAction {
+        title: text(Text::SplitSubgraph).to_string(),
+        kind: Some(CodeActionKind::REFACTOR_EXTRACT),
+        edit: Some(WorkspaceEdit::new(changes)),
+        ..Default::default()
+    }))
+}
+
+/// "Ignore Mermaid Diagram", adding `<!-- mermaid-ignore -->` above the fence,
+/// or for an ignored fence "Unignore Mermaid Diagram", removing both markers
+fn ignore_toggle_action(
+    uri: &Url,
+    lines: &[&str],
+    fence: &MermaidFence,
+    encoding: PositionEncoding,
+) -> CodeActionOrCommand {
+    let opener = lines[fence.start_line].trim_end();
+    let indent = &opener[..opener.len() - opener.trim_start().len()];
+    let (first_line, replacement, title) = if fence.ignored {
+        let comment = fence
+            .start_line
+            .checked_sub(1)
+            .filter(|&prev| ignore::is_ignore_comment(lines[prev]));
+        let new_opener = if ignore::has_ignore_attribute(&fence.info) {
+            let info = ignore::without_ignore_attribute(&fence.info);
+            if info.is_empty() { format!("{indent}```mermaid") } else { format!("{indent}```mermaid {info}") }
+        } else {
+            opener.to_string()
+        };
+        (comment.unwrap_or(fence.start_line), new_opener, Text::UnignoreDiagram)
+    } else {
+        (fence.start_line, format!("{indent}{}\n{opener}", ignore::IGNORE_COMMENT), Text::IgnoreDiagram)
+    };
+
+    let range = line_range(lines, first_line, fence.start_line, encoding);
+    let mut changes = HashMap::new();
+    changes.insert(uri.clone(), vec![TextEdit::new(range, replacement)]);
+    CodeActionOrCommand::CodeAction(CodeAction {
+        title: text(title).to_string(),
+        kind: Some(CodeActionKind::REFACTOR),
+        edit: Some(WorkspaceEdit::new(changes)),
+        ..Default::default()
+    })
+}
+
+/// A source action that runs a document-wide command when chosen
+fn bulk_action(title: &str, command: &str, uri: &Url) -> CodeActionOrCommand {
+    CodeActionOrCommand::CodeAction(CodeAction {
+        title: title.to_string(),
+        kind: Some(CodeActionKind::SOURCE),
+        command: Some(Command::new(
+            title.to_string(),
+            command.to_string(),
+            Some(vec![serde_json::json!(uri)]),
+        )),
+        ..Default::default()
+    })
+}
+
+// ─── Documents on disk ──────────────────────────────────────────────────────
+
+/// Commands that also work on files that aren't open, editing them on disk
+const DISK_COMMANDS: &[&str] = &[
+    "mermaid.adoptRenderedBlocks",
+    "mermaid.generateIndex",
+    "mermaid.checkLinks",
+    "mermaid.formatAll",
+    "mermaid.editAllSources",
+];
+
+/// What a command did to a file that isn't open
+#[derive(Debug, Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DiskEditSummary {
+    uri: String,
+    edits: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Write a command's edit of a file that isn't open straight to the file,
+/// unless it changed since it was read, and summarize what happened
+fn write_disk_document(
+    uri: &Url,
+    doc: &str,
+    file: &DiskFile,
+    edit: Option<WorkspaceEdit>,
+    cleanup: &[PathBuf],
+) -> DiskEditSummary {
+    let edits = edit
+        .and_then(|edit| edit.changes)
+        .and_then(|mut changes| changes.remove(uri))
+        .unwrap_or_default();
+    let mut summary = DiskEditSummary {
+        uri: uri.to_string(),
+        edits: edits.len(),
+        error: None,
+    };
+    if !edits.is_empty() {
+        match file.write(&document_source::apply_edits(doc, &edits)) {
+            Ok(()) => remove_files(cleanup),
+            Err(e) => {
+                warn!("Not writing {}: {e}", file.path.display());
+                summary.edits = 0;
+                summary.error = Some(e.to_string());
+            }
+        }
+    }
+    summary
+}
+
+// ─── Render journal ─────────────────────────────────────────────────────────
+
+/// Commands whose edits `mermaid.revertLastRender` can undo
+const JOURNALED_COMMANDS: &[&str] =
+    &["mermaid.renderSingle", "mermaid.renderAllLightweight", "mermaid.renderByType", "mermaid.embedSvgInline"];
+
+fn journal_retention(config: &Config) -> journal::Retention {
+    journal::Retention {
+        max_entries: config.render_journal_max_entries,
+        max_age_secs: config.render_journal_max_age_days.saturating_mul(24 * 60 * 60),
+    }
+}
+
+/// The render journal for the document's output directory
+fn render_journal(uri: &Url, config: &Config) -> ServerResult<journal::Journal> {
     let base_dir = doc_base_dir(uri)?;
-    let mermaid_dir = ensure_mermaid_dir(&base_dir).ok()?;
-    let doc_name = doc_short_name(uri);
-    let hash = code_hash(&fence.code);
+    Ok(journal::Journal::new(&project_cache_dir(&output_dir(&base_dir, config))))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Remember the fences a render edit replaces, their surroundings and the
+/// files the rendered blocks link to, so the render can be reverted later
+fn record_render(uri: &Url, lines: &[&str], edit: &WorkspaceEdit, config: &Config) {
+    if config.render_journal_max_entries == 0 {
+        return;
+    }
+    let edits = edit.changes.as_ref().and_then(|changes| changes.get(uri));
+    let (Some(edits), Ok(base_dir)) = (edits, doc_base_dir(uri)) else {
+        return;
+    };
+    let regions = edits
+        .iter()
+        .map(|edit| {
+            let start = edit.range.start.line as usize;
+            let end = (edit.range.end.line as usize).min(lines.len().saturating_sub(1));
+            let rendered: Vec<&str> = edit.new_text.lines().collect();
+            let files = find_all_rendered_blocks(&rendered)
+                .into_iter()
+                .flat_map(|block| std::iter::once(block.source_file).chain(block.image.map(|(_, link)| link)))
+                .map(|link| paths::logical_join(&base_dir, link))
+                .collect();
+            journal::RenderedRegion {
+                line: start,
+                original: lines[start..=end].join("\n"),
+                rendered: edit.new_text.clone(),
+                before: lines[start.saturating_sub(journal::CONTEXT_LINES)..start].iter().map(|l| l.to_string()).collect(),
+                after: lines[end + 1..(end + 1 + journal::CONTEXT_LINES).min(lines.len())]
+                    .iter()
+                    .map(|l| l.to_string())
+                    .collect(),
+                files,
+            }
+        })
+        .collect();
+    let record = journal::RenderRecord {
+        document: uri.to_string(),
+        recorded_at: unix_now(),
+        regions,
+    };
+    let journal = journal::Journal::new(&project_cache_dir(&output_dir(&base_dir, config)));
+    if let Err(e) = journal.record(record, journal_retention(config)) {
+        warn!("Failed to record the render of {uri} for reverting: {e}");
+    }
+}
+
+/// The edit restoring the fences of the document's latest journaled render,
+/// and the files that render wrote which nothing links to any more. None when
+/// there is nothing to revert, including when the journal is unreadable or
+/// none of the rendered blocks can be found.
+fn revert_last_render(
+    uri: &Url,
+    doc: &str,
+    lines: &[&str],
+    documents: &HashMap<Url, String>,
+    config: &Config,
+    encoding: PositionEncoding,
+) -> ServerResult<Option<(WorkspaceEdit, Vec<PathBuf>)>> {
+    let journal = render_journal(uri, config)?;
+    let Some(record) = journal.latest(uri.as_str(), journal_retention(config), unix_now()) else {
+        return Ok(None);
+    };
+    // Found or not, the operation can't be reverted twice
+    if let Err(e) = journal.remove(&record) {
+        warn!("Failed to update the render journal: {e}");
+    }
+
+    let newline = LineEnding::Auto.newline(doc);
+    let mut reverted: Vec<(usize, usize)> = Vec::new();
+    let mut edits = Vec::new();
+    let mut files = Vec::new();
+    for region in &record.regions {
+        let taken: Vec<usize> = reverted.iter().map(|&(start, _)| start).collect();
+        let Some(start) = journal::locate(lines, region, &taken) else {
+            info!("The block rendered from line {} of {uri} is gone; not reverting it", region.line + 1);
+            continue;
+        };
+        let end = start + region.rendered.lines().count() - 1;
+        reverted.push((start, end));
+        edits.push(TextEdit::new(line_range(lines, start, end, encoding), with_newlines(&region.original, newline)));
+        files.extend(region.files.iter().cloned());
+    }
+    if edits.is_empty() {
+        return Ok(None);
+    }
+
+    // Compared resolved, as documents may name the same file through symlinks
+    let referenced: HashSet<PathBuf> = linked_files(documents, uri, &reverted)
+        .into_iter()
+        .chain(journal.files())
+        .map(|file| paths::resolve(&file))
+        .collect();
+    // The journal is a file in the project: never delete outside the output directory
+    let output_dir = output_dir(&doc_base_dir(uri)?, config);
+    files.sort();
+    files.dedup();
+    files.retain(|file| paths::is_within(file, &output_dir) && !referenced.contains(&paths::resolve(file)));
+    info!("Reverting {} rendered blocks in {uri}", edits.len());
+    Ok(Some((WorkspaceEdit::new(HashMap::from([(uri.clone(), edits)])), files)))
+}
+
+/// Files linked from the rendered blocks of the open documents, leaving out the
+/// blocks of `uri` starting in the `skipped` line ranges
+fn linked_files(documents: &HashMap<Url, String>, uri: &Url, skipped: &[(usize, usize)]) -> HashSet<PathBuf> {
+    let mut linked = HashSet::new();
+    for (doc_uri, doc) in documents {
+        let Ok(base_dir) = doc_base_dir(doc_uri) else {
+            continue;
+        };
+        let lines: Vec<&str> = doc.lines().collect();
+        for block in find_all_rendered_blocks(&lines) {
+            if doc_uri == uri && skipped.iter().any(|&(start, end)| (start..=end).contains(&block.comment_line)) {
+                continue;
+            }
+            linked.insert(paths::logical_join(&base_dir, &block.source_file));
+            if let Some((_, link)) = block.image {
+                linked.insert(paths::logical_join(&base_dir, link));
+            }
+        }
+    }
+    linked
+}
+
+/// Delete files left behind by a reverted render
+fn remove_files(files: &[PathBuf]) {
+    for file in files {
+        match fs::remove_file(file) {
+            Ok(()) => info!("Removed {}", file.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove {}: {e}", file.display()),
+        }
+    }
+}
+
+/// Arguments of `mermaid.setOption`
+#[derive(Debug, serde::Deserialize)]
+struct OptionUpdate {
+    key: String,
+    value: Value,
+}
+
+/// Change one server option for the rest of the session and return the
+/// effective config. Render failures recorded under the old settings are
+/// dropped and diagnostics republished; the render cache needs no clearing
+/// because theme and background are part of its keys.
+fn set_option(connection: &Connection, state: &mut ServerState, argument: Option<&Value>) -> ServerResult<Value> {
+    let update: OptionUpdate = match argument {
+        Some(value) => serde_json::from_value(value.clone())?,
+        None => {
+            return Err(ServerError::InvalidParams(
+                "mermaid.setOption expects { \"key\", \"value\" }".to_string(),
+            ))
+        }
+    };
+    state
+        .config
+        .set_option(&update.key, update.value)
+        .map_err(ServerError::InvalidParams)?;
+    info!("Set option {} ({:?})", update.key, state.config);
+    if update.key == "securityLevel" {
+        if let Some(warning) = state.config.security_warning() {
+            show_message(connection, MessageType::WARNING, &format!("Mermaid: {warning}"))?;
+        }
+    }
+    if let Some(format) = state.config.log_format {
+        logging::set_format(format);
+    }
+    i18n::set_locale(i18n::Locale::from_tag(&state.config.locale));
+
+    state.render_failures.clear();
+    state.published.clear();
+    let uris: Vec<Url> = state.documents.keys().cloned().collect();
+    for uri in &uris {
+        publish_document_diagnostics(connection, state, uri)?;
+    }
+    enforce_derived_state_cap(state, None);
+    CommandOutcome::ok(format!("Mermaid: set {}", update.key), &state.config).to_value()
+}
+
+// ─── Requests to the client ─────────────────────────────────────────────────
+
+/// How long the user may take to answer a prompt
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// How long the client may take to answer an applyEdit or progress request
+const CLIENT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// What a request sent to the client is waiting for
+enum Outgoing {
+    Edit(PendingEdit),
+    /// A trust prompt, by the binary it asks about
+    TrustPrompt(PathBuf),
+    /// A prompt offering to gitignore generated files
+    GitignorePrompt(gitignore::Suggestion),
+    /// A prompt offering the command that installs mermaid-cli
+    InstallPrompt(String),
+    /// A request whose answer changes nothing
+    Untracked,
+}
+
+// ─── Trust prompts ──────────────────────────────────────────────────────────
+
+/// Ask about every project-local mmdc that resolution skipped because the
+/// user hasn't decided on it yet
+fn ask_trust(connection: &Connection, state: &mut ServerState) -> ServerResult<()> {
+    for binary in trust::store().take_prompts() {
+        if !state.client.show_message_request {
+            warn!(
+                "Not running mmdc at {}: it belongs to the workspace and the client can't ask for consent",
+                binary.display()
+            );
+            continue;
+        }
+        let params = ShowMessageRequestParams {
+            typ: MessageType::WARNING,
+            message: format!(
+                "This workspace contains its own mermaid-cli at {}. Allow Mermaid Preview to run it?",
+                binary.display()
+            ),
+            actions: Some(
+                trust::Decision::ALL
+                    .iter()
+                    .map(|decision| MessageActionItem {
+                        title: decision.label().to_string(),
+                        properties: HashMap::new(),
+                    })
+                    .collect(),
+            ),
+        };
+        let req = state.outgoing.request(
+            "window/showMessageRequest",
+            serde_json::to_value(params)?,
+            Outgoing::TrustPrompt(binary),
+            PROMPT_TIMEOUT,
+        );
+        send(connection, Message::Request(req))?;
+    }
+    Ok(())
+}
+
+/// Record the answer to a trust prompt. Dismissing it denies the binary for
+/// the session.
+fn handle_trust_answer(binary: &Path, resp: Response) {
+    let decision = resp
+        .result
+        .and_then(|v| serde_json::from_value::<Option<MessageActionItem>>(v).ok())
+        .flatten()
+        .and_then(|item| trust::Decision::from_label(&item.title))
+        .unwrap_or(trust::Decision::Deny);
+    info!("mmdc at {}: {decision:?}", binary.display());
+    trust::store().decide(binary, decision);
+    render::forget_resolved_mmdc();
+}
+
+// ─── Install prompts ────────────────────────────────────────────────────────
+
+/// The install prompt's action, answered with the install command to copy
+const COPY_INSTALL_COMMAND: &str = "Copy install command";
+
+/// [`ServerError::kind`] of a program that wasn't found
+const TOOL_NOT_FOUND: &str = "tool-not-found";
+
+/// Whether a fence failed because a program it needs wasn't found
+fn is_missing_tool(diagnostic: &Diagnostic) -> bool {
+    diagnostic.code == Some(NumberOrString::String(TOOL_NOT_FOUND.to_string()))
+}
+
+/// The result of a render command that failed for want of mmdc: how to
+/// install it, which the user is also offered to copy. None when mmdc isn't
+/// missing, so the command fails as usual.
+fn offer_mmdc_install(
+    connection: &Connection,
+    client: &ClientInfo,
+    outgoing: &mut OutgoingRequests<Outgoing>,
+    config: &Config,
+) -> ServerResult<Option<CommandOutcome<outcome::ToolNotFound>>> {
+    let Some(guidance) = render::install_guidance(config) else {
+        return Ok(None);
+    };
+    let message = format!(
+        "Mermaid Preview needs mermaid-cli (mmdc) to render diagrams. Install it with `{}`.",
+        guidance.install_hint
+    );
+    if client.show_message_request {
+        let params = ShowMessageRequestParams {
+            typ: MessageType::WARNING,
+            message,
+            actions: Some(vec![MessageActionItem {
+                title: COPY_INSTALL_COMMAND.to_string(),
+                properties: HashMap::new(),
+            }]),
+        };
+        let req = outgoing.request(
+            "window/showMessageRequest",
+            serde_json::to_value(params)?,
+            Outgoing::InstallPrompt(guidance.install_hint.clone()),
+            PROMPT_TIMEOUT,
+        );
+        send(connection, Message::Request(req))?;
+    } else {
+        warn!("{message}");
+    }
+    let result = outcome::ToolNotFound {
+        error: TOOL_NOT_FOUND,
+        install_hint: guidance.install_hint,
+        docs_url: guidance.docs_url,
+    };
+    Ok(Some(CommandOutcome::new(Status::Failed, guidance.message, result)))
+}
+
+/// Show the install command on its own, to copy, if the user asked for it
+fn handle_install_answer(connection: &Connection, command: &str, resp: Response) -> ServerResult<()> {
+    let copy = resp
+        .result
+        .and_then(|v| serde_json::from_value::<Option<MessageActionItem>>(v).ok())
+        .flatten()
+        .is_some_and(|item| item.title == COPY_INSTALL_COMMAND);
+    if !copy {
+        return Ok(());
+    }
+    show_message(connection, MessageType::INFO, command)
+}
+
+// ─── Gitignore prompts ──────────────────────────────────────────────────────
+
+/// Offer to gitignore the generated files of every worktree rendered into for
+/// the first time that doesn't ignore them yet
+fn ask_gitignore(connection: &Connection, state: &mut ServerState) -> ServerResult<()> {
+    let suggestions = gitignore::checks().take_suggestions(&state.config);
+    for suggestion in suggestions {
+        offer_gitignore_entry(connection, state, suggestion)?;
+    }
+    Ok(())
+}
+
+/// Ask whether to add `suggestion` to `.gitignore`, or log it for clients
+/// that can't ask
+fn offer_gitignore_entry(
+    connection: &Connection,
+    state: &mut ServerState,
+    suggestion: gitignore::Suggestion,
+) -> ServerResult<()> {
+    if !state.client.show_message_request {
+        warn!(
+            "{} is not gitignored; add `{}` to {} to keep rendered diagrams out of commits",
+            suggestion.dir.display(),
+            suggestion.entry,
+            suggestion.gitignore.display()
+        );
+        return Ok(());
+    }
+    let params = ShowMessageRequestParams {
+        typ: MessageType::INFO,
+        message: format!(
+            "Mermaid Preview writes generated files to {}, which git doesn't ignore. Add `{}` to {}?",
+            suggestion.dir.display(),
+            suggestion.entry,
+            suggestion.gitignore.display()
+        ),
+        actions: Some(
+            gitignore::Answer::ALL
+                .iter()
+                .map(|answer| MessageActionItem {
+                    title: answer.label().to_string(),
+                    properties: HashMap::new(),
+                })
+                .collect(),
+        ),
+    };
+    let req = state.outgoing.request(
+        "window/showMessageRequest",
+        serde_json::to_value(params)?,
+        Outgoing::GitignorePrompt(suggestion),
+        PROMPT_TIMEOUT,
+    );
+    send(connection, Message::Request(req))
+}
+
+/// Append the suggested entry to `.gitignore` if the user agreed: through the
+/// client, so the change can be undone, or directly when it can't create or
+/// edit the file
+fn handle_gitignore_answer(
+    connection: &Connection,
+    state: &mut ServerState,
+    suggestion: &gitignore::Suggestion,
+    resp: Response,
+) -> ServerResult<()> {
+    let answer = resp
+        .result
+        .and_then(|v| serde_json::from_value::<Option<MessageActionItem>>(v).ok())
+        .flatten()
+        .and_then(|item| gitignore::Answer::from_label(&item.title));
+    if answer != Some(gitignore::Answer::Add) {
+        return Ok(());
+    }
+
+    let uri = Url::from_file_path(&suggestion.gitignore).ok();
+    let exists = suggestion.gitignore.is_file();
+    let existing = match uri.as_ref().and_then(|uri| state.documents.get(uri)) {
+        Some(doc) => doc.clone(),
+        None if exists => fs::read_to_string(&suggestion.gitignore).map_err(ServerError::io(format!(
+            "Failed to read {}",
+            suggestion.gitignore.display()
+        )))?,
+        None => String::new(),
+    };
+    let new_text = suggestion.appended_text(&existing);
+    info!("Adding `{}` to {}", suggestion.entry, suggestion.gitignore.display());
+
+    let client = &state.client;
+    let through_client = client.apply_edit && client.document_changes && (exists || client.create_files);
+    let Some(uri) = uri.filter(|_| through_client) else {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&suggestion.gitignore)
+            .map_err(ServerError::io(format!("Failed to open {}", suggestion.gitignore.display())))?;
+        return file
+            .write_all(new_text.as_bytes())
+            .map_err(ServerError::io(format!("Failed to write {}", suggestion.gitignore.display())));
+    };
+
+    let last_line = existing.rsplit('\n').next().unwrap_or("");
+    let end = Position::new(
+        existing.matches('\n').count() as u32,
+        client.position_encoding.line_len(last_line),
+    );
+    let mut operations = Vec::new();
+    if !exists {
+        operations.push(DocumentChangeOperation::Op(ResourceOp::Create(CreateFile {
+            uri: uri.clone(),
+            options: Some(CreateFileOptions {
+                overwrite: Some(false),
+                ignore_if_exists: Some(true),
+            }),
+            annotation_id: None,
+        })));
+    }
+    operations.push(DocumentChangeOperation::Edit(TextDocumentEdit {
+        text_document: OptionalVersionedTextDocumentIdentifier { uri, version: None },
+        edits: vec![OneOf::Left(TextEdit::new(Range::new(end, end), new_text))],
+    }));
+    let params = ApplyWorkspaceEditParams {
+        label: Some(text(Text::GitignoreEditLabel).to_string()),
+        edit: WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Operations(operations)),
+            ..Default::default()
+        },
+    };
+    let req = state.outgoing.request(
+        "workspace/applyEdit",
+        serde_json::to_value(params)?,
+        Outgoing::Untracked,
+        CLIENT_REQUEST_TIMEOUT,
+    );
+    send(connection, Message::Request(req))
+}
+
+// ─── Applying edits ─────────────────────────────────────────────────────────
+
+/// How often a rejected render edit is rebuilt against the new document state
+const MAX_EDIT_RETRIES: usize = 3;
+
+/// An applyEdit sent to the client, kept until it answers
+#[derive(Debug, Clone)]
+struct PendingEdit {
+    uri: Url,
+    /// Document version the edit was computed against
+    version: Option<i32>,
+    /// The fences being replaced; empty when the edit isn't a render
+    fences: Vec<FenceEdit>,
+    attempts: usize,
+    /// Files to delete once the client applied the edit
+    cleanup: Vec<PathBuf>,
+}
+
+/// Replacement of one mermaid fence, identified by the hash of its code
+#[derive(Debug, Clone, PartialEq)]
+struct FenceEdit {
+    code_hash: u64,
+    start_line: usize,
+    new_text: String,
+}
+
+/// Send workspace/applyEdit request to the client, versioned when supported,
+/// keeping `pending` until it answers
+fn apply_edit(
+    connection: &Connection,
+    client: &ClientInfo,
+    outgoing: &mut OutgoingRequests<Outgoing>,
+    pending: PendingEdit,
+    edits: Vec<TextEdit>,
+) -> ServerResult<()> {
+    let edit = match pending.version {
+        Some(version) if client.document_changes => WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Edits(vec![TextDocumentEdit {
+                text_document: OptionalVersionedTextDocumentIdentifier {
+                    uri: pending.uri.clone(),
+                    version: Some(version),
+                },
+                edits: edits.into_iter().map(OneOf::Left).collect(),
+            }])),
+            ..Default::default()
+        },
+        _ => WorkspaceEdit::new(HashMap::from([(pending.uri.clone(), edits)])),
+    };
+    let params = ApplyWorkspaceEditParams {
+        label: Some(text(Text::EditLabel).to_string()),
+        edit,
+    };
+    let req = outgoing.request(
+        "workspace/applyEdit",
+        serde_json::to_value(params)?,
+        Outgoing::Edit(pending),
+        CLIENT_REQUEST_TIMEOUT,
+    );
+    send(connection, Message::Request(req))
+}
+
+/// Handle the client's answer to an applyEdit. A render rejected because the user
+/// kept typing is rebuilt against the current text and sent again.
+fn handle_response(
+    connection: &Connection,
+    resp: Response,
+    state: &mut ServerState,
+) -> ServerResult<()> {
+    let pending = match state.outgoing.take(&resp.id) {
+        Some(Outgoing::Edit(pending)) => pending,
+        Some(Outgoing::TrustPrompt(binary)) => {
+            handle_trust_answer(&binary, resp);
+            return Ok(());
+        }
+        Some(Outgoing::GitignorePrompt(suggestion)) => {
+            return handle_gitignore_answer(connection, state, &suggestion, resp);
+        }
+        Some(Outgoing::InstallPrompt(command)) => return handle_install_answer(connection, &command, resp),
+        Some(Outgoing::Untracked) | None => return Ok(()),
+    };
+    let applied = resp
+        .result
+        .and_then(|v| serde_json::from_value::<ApplyWorkspaceEditResponse>(v).ok())
+        .is_some_and(|r| r.applied);
+    if applied {
+        remove_files(&pending.cleanup);
+        return Ok(());
+    }
+
+    let current_version = state.versions.get(&pending.uri).copied();
+    let stale = current_version != pending.version;
+    if stale && !pending.fences.is_empty() && pending.attempts < MAX_EDIT_RETRIES {
+        let relocated = state.documents.get(&pending.uri).and_then(|doc| {
+            let lines: Vec<&str> = doc.lines().collect();
+            relocate_fence_edits(&lines, &pending.fences, state.client.position_encoding)
+        });
+        if let Some((fences, edits)) = relocated {
+            info!(
+                "Retrying render edit for {} against version {current_version:?}",
+                pending.uri
+            );
+            let retry = PendingEdit {
+                fences,
+                version: current_version,
+                attempts: pending.attempts + 1,
+                ..pending
+            };
+            apply_edit(connection, &state.client, &mut state.outgoing, retry, edits)?;
+            return Ok(());
+        }
+    }
+
+    show_message(
+        connection,
+        MessageType::WARNING,
+        "Mermaid: the document changed before the edit could be applied; run the command again",
+    )
+}
+
+/// Describe each edit that replaces a whole mermaid fence; empty unless all do
+fn fence_edits(lines: &[&str], edits: &[TextEdit]) -> Vec<FenceEdit> {
+    let fences = find_all_mermaid_fences(lines);
+    let fence_edits: Vec<FenceEdit> = edits
+        .iter()
+        .filter_map(|edit| {
+            let start_line = edit.range.start.line as usize;
+            let fence = fences.iter().find(|f| f.start_line == start_line)?;
+            Some(FenceEdit {
+                code_hash: code_hash(&fence.code),
+                start_line,
+                new_text: edit.new_text.clone(),
+            })
+        })
+        .collect();
+
+    if fence_edits.len() == edits.len() {
+        fence_edits
+    } else {
+        Vec::new()
+    }
+}
+
+/// Find the fence with the given code hash, nearest to where it used to start
+fn relocate_fence(lines: &[&str], code_hash_value: u64, near_line: usize) -> Option<MermaidFence> {
+    find_all_mermaid_fences(lines)
+        .into_iter()
+        .filter(|fence| code_hash(&fence.code) == code_hash_value)
+        .min_by_key(|fence| fence.start_line.abs_diff(near_line))
+}
+
+/// Rebuild fence edits against the current lines. None if any fence's code changed.
+fn relocate_fence_edits(
+    lines: &[&str],
+    fences: &[FenceEdit],
+    encoding: PositionEncoding,
+) -> Option<(Vec<FenceEdit>, Vec<TextEdit>)> {
+    let mut relocated = Vec::new();
+    for fence_edit in fences {
+        let fence = relocate_fence(lines, fence_edit.code_hash, fence_edit.start_line)?;
+        let range = line_range(lines, fence.start_line, fence.end_line, encoding);
+        relocated.push((
+            FenceEdit {
+                start_line: fence.start_line,
+                ..fence_edit.clone()
+            },
+            TextEdit::new(range, fence_edit.new_text.clone()),
+        ));
+    }
+
+    // Keep edits bottom-up so earlier replacements don't shift later ranges
+    relocated.sort_by_key(|(fence, _)| std::cmp::Reverse(fence.start_line));
+    Some(relocated.into_iter().unzip())
+}
+
+/// Show a message in the editor. Every client takes `window/showMessage`,
+/// whatever it says about `window/showMessageRequest`.
+fn show_message(connection: &Connection, typ: MessageType, message: &str) -> ServerResult<()> {
+    let params = ShowMessageParams {
+        typ,
+        message: message.to_string(),
+    };
+    let not = Notification::new("window/showMessage".to_string(), serde_json::to_value(params)?);
+    send(connection, Message::Notification(not))
+}
+
+/// Replace the diagnostics shown for a document, unless they are the ones
+/// last sent
+fn publish_diagnostics(
+    connection: &Connection,
+    published: &mut Publisher,
+    uri: &Url,
+    diagnostics: Vec<Diagnostic>,
+) -> ServerResult<()> {
+    if !published.should_send(uri, Channel::Diagnostics, &diagnostics) {
+        return Ok(());
+    }
+    let params = PublishDiagnosticsParams::new(uri.clone(), diagnostics, None);
+    let not = Notification::new(
+        "textDocument/publishDiagnostics".to_string(),
+        serde_json::to_value(params)?,
+    );
+    send(connection, Message::Notification(not))
+}
+
+/// Create a work-done progress token and report its start, if the client supports it
+fn begin_progress(
+    connection: &Connection,
+    client: &ClientInfo,
+    outgoing: &mut OutgoingRequests<Outgoing>,
+    title: &str,
+) -> ServerResult<Option<ProgressToken>> {
+    if !client.work_done_progress {
+        return Ok(None);
+    }
+
+    let token = ProgressToken::String(format!("mermaid-progress-{}", outgoing::next_number()));
+    let req = outgoing.request(
+        "window/workDoneProgress/create",
+        serde_json::to_value(WorkDoneProgressCreateParams {
+            token: token.clone(),
+        })?,
+        Outgoing::Untracked,
+        CLIENT_REQUEST_TIMEOUT,
+    );
+    send(connection, Message::Request(req))?;
+
+    send_progress(
+        connection,
+        token.clone(),
+        WorkDoneProgress::Begin(WorkDoneProgressBegin {
+            title: title.to_string(),
+            ..Default::default()
+        }),
+    )?;
+    Ok(Some(token))
+}
+
+/// Report how many of `total` items are done
+fn report_progress(
+    connection: &Connection,
+    token: ProgressToken,
+    done: usize,
+    total: usize,
+) -> ServerResult<()> {
+    let percentage = (done * 100).checked_div(total).unwrap_or(100) as u32;
+    send_progress(
+        connection,
+        token,
+        WorkDoneProgress::Report(WorkDoneProgressReport {
+            message: Some(format!("{done}/{total}")),
+            percentage: Some(percentage),
+            ..Default::default()
+        }),
+    )
+}
+
+/// Drain messages that arrived during a long-running request, deferring them for
+/// the main loop. Returns true if the request (or its progress) was cancelled.
+fn poll_cancelled(
+    connection: &Connection,
+    deferred: &mut VecDeque<Message>,
+    id: &lsp_server::RequestId,
+    token: Option<&ProgressToken>,
+) -> bool {
+    let mut cancelled = false;
+    while let Ok(msg) = connection.receiver.try_recv() {
+        if let Message::Notification(not) = &msg {
+            match not.method.as_str() {
+                "$/cancelRequest" => {
+                    if let Ok(params) = serde_json::from_value::<CancelParams>(not.params.clone()) {
+                        let cancelled_id = match params.id {
+                            NumberOrString::Number(n) => lsp_server::RequestId::from(n),
+                            NumberOrString::String(s) => lsp_server::RequestId::from(s),
+                        };
+                        if &cancelled_id == id {
+                            cancelled = true;
+                            continue;
+                        }
+                    }
+                }
+                "window/workDoneProgress/cancel" => {
+                    if let Ok(params) =
+                        serde_json::from_value::<WorkDoneProgressCancelParams>(not.params.clone())
+                    {
+                        if Some(&params.token) == token {
+                            cancelled = true;
+                            continue;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        deferred.push_back(msg);
+    }
+    cancelled
+}
+
+/// Report the end of a work-done progress started with `begin_progress`
+fn end_progress(connection: &Connection, token: ProgressToken) -> ServerResult<()> {
+    send_progress(
+        connection,
+        token,
+        WorkDoneProgress::End(WorkDoneProgressEnd::default()),
+    )
+}
+
+fn send_progress(
+    connection: &Connection,
+    token: ProgressToken,
+    value: WorkDoneProgress,
+) -> ServerResult<()> {
+    let params = ProgressParams {
+        token,
+        value: ProgressParamsValue::WorkDone(value),
+    };
+    let not = Notification::new("$/progress".to_string(), serde_json::to_value(params)?);
+    send(connection, Message::Notification(not))
+}
+
+// ─── Mermaid block detection ────────────────────────────────────────────────
+
+/// A detected ```mermaid ... ``` code fence, or a `<Mermaid chart={`...`} />`
+/// component in MDX
+#[derive(Debug, Clone)]
+struct MermaidFence {
+    /// Line index of the opening ```mermaid (or `<Mermaid chart={``)
+    start_line: usize,
+    /// Line index of the closing ``` (or `` `} /> ``)
+    end_line: usize,
+    /// The mermaid code content (without the fences)
+    code: String,
+    /// Rest of the opening line after ```mermaid, e.g. `{backend=native}`
+    info: String,
+    /// Marked with `{ignore}` or a preceding `<!-- mermaid-ignore -->`: not
+    /// checked, not rendered by bulk commands, and offered no actions but
+    /// "Unignore"
+    ignored: bool,
+    /// Fence or component; a component's code is unescaped from its template literal
+    syntax: Syntax,
+}
+
+impl MermaidFence {
+    /// Why the fence has nothing to render: no code, and no remote source
+    /// either. Render actions aren't offered for it and bulk renders skip it.
+    fn empty_reason(&self) -> Option<ServerError> {
+        if remote::source_url(&self.info).is_some() {
+            return None;
+        }
+        render::validate_code(&self.code).err()
+    }
+}
+
+/// Where a line sits relative to the document's mermaid content
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PositionContext {
+    /// Inside the `index`th ```mermaid fence, fence lines included
+    InFence { index: usize },
+    /// Inside the `index`th rendered block (as found by `find_all_rendered_blocks`)
+    InRenderedBlock { index: usize },
+    Outside,
+}
+
+/// Classify a line of a markdown document; the one place handlers decide
+/// whether the cursor is on mermaid content
+pub fn classify_position(markdown: &str, line: usize) -> PositionContext {
+    let lines: Vec<&str> = markdown.lines().collect();
+    classify_line(&lines, line)
+}
+
+fn classify_line(lines: &[&str], line: usize) -> PositionContext {
+    if let Some(index) = find_all_mermaid_fences(lines)
+        .iter()
+        .position(|fence| (fence.start_line..=fence.end_line).contains(&line))
+    {
+        return PositionContext::InFence { index };
+    }
+    if let Some(index) = find_all_rendered_blocks(lines)
+        .iter()
+        .position(|block| (block.start_line..=block.end_line).contains(&line))
+    {
+        return PositionContext::InRenderedBlock { index };
+    }
+    PositionContext::Outside
+}
+
+/// Find a mermaid fence that contains the given cursor line
+fn find_mermaid_fence(lines: &[&str], cursor_line: usize) -> Option<MermaidFence> {
+    match classify_line(lines, cursor_line) {
+        PositionContext::InFence { index } => find_all_mermaid_fences(lines).into_iter().nth(index),
+        _ => None,
+    }
+}
+
+/// Find all ```mermaid fences (and MDX mermaid components) in the document
+fn find_all_mermaid_fences(lines: &[&str]) -> Vec<MermaidFence> {
+    let mut fences = Vec::new();
+    let mut i = 0;
+
+    while i < lines.len() {
+        let trimmed = lines[i].trim_start();
+        if mdx::is_component_opener(trimmed) {
+            let start = i;
+            if let Some(end) = (start + 1..lines.len()).find(|&j| mdx::is_component_closer(lines[j])) {
+                fences.push(MermaidFence {
+                    start_line: start,
+                    end_line: end,
+                    code: mdx::unescape_template(&lines[start + 1..end].join("\n")),
+                    info: String::new(),
+                    ignored: false,
+                    syntax: Syntax::Component,
+                });
+                i = end;
+            }
+        } else if let Some(ticks) = fence_opener_ticks(trimmed) {
+            let start = i;
+            let indent = lines[i].len() - trimmed.len();
+            i += 1;
+            // Find closing ```
+            while i < lines.len() {
+                if is_fence_closer(lines[i], ticks, indent) {
+                    let code = lines[start + 1..i].join("\n");
+                    let info = trimmed[ticks + "mermaid".len()..].trim().to_string();
+                    let ignored = ignore::has_ignore_attribute(&info)
+                        || start.checked_sub(1).is_some_and(|prev| ignore::is_ignore_comment(lines[prev]));
+                    fences.push(MermaidFence {
+                        start_line: start,
+                        end_line: i,
+                        code,
+                        info,
+                        ignored,
+                        syntax: Syntax::Markdown,
+                    });
+                    break;
+                }
+                i += 1;
+            }
+        }
+        i += 1;
+    }
+
+    fences
+}
+
+/// Whether `line` closes a fence opened with `opener_ticks` backticks,
+/// indented by `opener_indent`. As in CommonMark, it takes at least as many
+/// backticks, followed only by spaces, and no more than three spaces of
+/// indent beyond the opener's (a fence in a list item keeps the item's); a
+/// line like ```` ``` <!-- end --> ```` is fence content.
+fn is_fence_closer(line: &str, opener_ticks: usize, opener_indent: usize) -> bool {
+    let t = line.trim_start();
+    let ticks = t.len() - t.trim_start_matches('`').len();
+    ticks >= opener_ticks && line.len() - t.len() <= opener_indent + 3 && t[ticks..].trim().is_empty()
+}
+
+/// Check that `fence` still spans exactly one closed mermaid fence of `lines`
+/// before an edit replaces it, so a stale or misdetected range can't swallow
+/// the prose around it
+fn verify_fence_range(lines: &[&str], fence: &MermaidFence) -> ServerResult<()> {
+    let opener = |line: &str| match fence.syntax {
+        Syntax::Markdown => is_fence_opener(line),
+        Syntax::Component => mdx::is_component_opener(line),
+    };
+    let start = lines.get(fence.start_line).copied().unwrap_or_default();
+    let (ticks, indent) = (fence_opener_ticks(start).unwrap_or(3), start.len() - start.trim_start().len());
+    let closer = |line: &str| match fence.syntax {
+        Syntax::Markdown => is_fence_closer(line, ticks, indent),
+        Syntax::Component => mdx::is_component_closer(line),
+    };
+    let opens = lines.get(fence.start_line).is_some_and(|line| opener(line));
+    let closes = fence.end_line > fence.start_line
+        && lines.get(fence.end_line).is_some_and(|line| closer(line))
+        && !lines[fence.start_line + 1..fence.end_line].iter().any(|line| closer(line));
+    if opens && closes {
+        return Ok(());
+    }
+    let message = format!(
+        "lines {}-{} are not a closed mermaid fence",
+        fence.start_line + 1,
+        fence.end_line + 1
+    );
+    error!("Refusing to replace {message}");
+    Err(ServerError::InvalidParams(message))
+}
+
+/// Whether `line` opens a ```mermaid fence
+fn is_fence_opener(line: &str) -> bool {
+    fence_opener_ticks(line).is_some()
+}
+
+/// How many backticks open the ```mermaid fence on `line` (three or more)
+fn fence_opener_ticks(line: &str) -> Option<usize> {
+    let t = line.trim_start();
+    let ticks = t.len() - t.trim_start_matches('`').len();
+    (ticks >= 3 && t[ticks..].starts_with("mermaid")).then_some(ticks)
+}
+
+/// Line of a ```mermaid opener that has no closing ``` (only the last one can),
+/// or of a mermaid component whose chart isn't closed
+fn find_unclosed_mermaid_fence(lines: &[&str]) -> Option<usize> {
+    let last_closed = find_all_mermaid_fences(lines).last().map(|f| f.end_line);
+    lines
+        .iter()
+        .enumerate()
+        .skip(last_closed.map_or(0, |end| end + 1))
+        .find(|(_, line)| is_fence_opener(line) || mdx::is_component_opener(line))
+        .map(|(i, _)| i)
+}
+
+/// A rendered mermaid block (comment + image reference or inline `<svg>`)
+#[derive(Debug, Clone)]
+struct RenderedBlock {
+    /// First line of the block: its `<details>` wrapper or anchor if any, else the comment
+    start_line: usize,
+    /// Line of <!-- mermaid-source-file:... -->
+    comment_line: usize,
+    /// Line of the last line of this rendered block (image ref, `</svg>`, `</details>` or blank line)
+    end_line: usize,
+    /// Path to the .mmd source file
+    source_file: String,
+    /// Info string of the fence it was rendered from, e.g. `{theme=dark}`
+    info: String,
+    /// Line and link target of the `![...](...)` image (or `<img>`), if the
+    /// block has one
+    image: Option<(usize, String)>,
+    /// Rendered from a fence or, with an `<img>`, from an MDX component
+    syntax: Syntax,
+}
+
+/// Find all rendered mermaid blocks in the document
+fn find_all_rendered_blocks(lines: &[&str]) -> Vec<RenderedBlock> {
+    let mut blocks: Vec<RenderedBlock> = Vec::new();
+    let mut i = 0;
+
+    while i < lines.len() {
+        if let Some((source_file, info)) = extract_source_comment(lines[i]) {
+            let comment_line = i;
+            let mut end_line = i;
+            let mut image = None;
+            let mut syntax = Syntax::Markdown;
+            let mut start_line = match i.checked_sub(1) {
+                Some(prev) if anchors::parse_anchor_line(lines[prev]).is_some() => prev,
+                _ => i,
+            };
+
+            // Look ahead for blank line + image reference
+            let mut j = i + 1;
+            while j < lines.len() {
+                let trimmed = lines[j].trim();
+                if trimmed.is_empty() {
+                    j += 1;
+                    continue;
+                }
+                // The image follows its source comment, wherever the output dir is
+                if trimmed.starts_with("![") && trimmed.contains("](") {
+                    end_line = j;
+                    image = image_target(trimmed).map(|target| (j, target.to_string()));
+                }
+                // ...as JSX, for an MDX component
+                if trimmed.starts_with("<img") {
+                    if let Some(src) = mdx::image_src(trimmed) {
+                        end_line = j;
+                        image = Some((j, src));
+                        syntax = Syntax::Component;
+                    }
+                }
+                // ...or the SVG itself, embedded by `mermaid.embedSvgInline`
+                if trimmed.starts_with("<svg") {
+                    if let Some(close) = (j..lines.len()).find(|&k| lines[k].contains("</svg>")) {
+                        end_line = close;
+                    }
+                }
+                break;
+            }
+
+            // A block wrapped in <details> is restored without its wrapper
+            let floor = blocks.last().map_or(0, |b| b.end_line + 1);
+            if let Some((open, close)) = details_wrapper(lines, start_line, end_line, floor) {
+                start_line = open;
+                end_line = close;
+            }
+
+            blocks.push(RenderedBlock {
+                start_line,
+                comment_line,
+                end_line,
+                source_file,
+                info,
+                image,
+                syntax,
+            });
+
+            i = end_line + 1;
+        } else {
+            i += 1;
+        }
+    }
+
+    blocks
+}
+
+/// The `<details>` ... `</details>` lines around lines `start..=end`, if nothing
+/// but blank lines and a `<summary>` sits between them and the block.
+/// The opening tag is searched no higher than `floor`.
+fn details_wrapper(lines: &[&str], start: usize, end: usize, floor: usize) -> Option<(usize, usize)> {
+    let is_summary = |t: &str| t.starts_with("<summary") && t.ends_with("</summary>");
+    // `<details>`, `<details open>`, or either followed by its `<summary>`
+    let is_open = |t: &str| match t.split_once('>') {
+        Some((tag, rest)) if tag == "<details" || tag.starts_with("<details ") => {
+            rest.is_empty() || is_summary(rest)
+        }
+        _ => false,
+    };
+
+    let mut open = None;
+    let mut seen_summary = false;
+    for line in (floor..start).rev() {
+        let trimmed = lines[line].trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        if is_summary(trimmed) && !seen_summary {
+            seen_summary = true;
+            continue;
+        }
+        if is_open(trimmed) {
+            open = Some(line);
+        }
+        break;
+    }
+    let open = open?;
+
+    let close = (end + 1..lines.len()).find(|&line| !lines[line].trim().is_empty())?;
+    (lines[close].trim() == "</details>").then_some((open, close))
+}
+
+/// Link target of a markdown image line `![alt](target "title")`
+fn image_target(line: &str) -> Option<&str> {
+    let (_, rest) = line.split_once("](")?;
+    let inner = &rest[..rest.rfind(')')?];
+    let target = match inner.strip_prefix('<') {
+        Some(bracketed) => bracketed.split_once('>')?.0,
+        None => inner.split_whitespace().next()?,
+    };
+    (!target.is_empty()).then_some(target)
+}
+
+/// Marks the fence's info string in a source comment
+const FENCE_INFO_MARKER: &str = " fence-info:";
+
+/// What a source comment starts with, inside the HTML or JSX comment
+const SOURCE_FILE_MARKER: &str = "mermaid-source-file:";
+
+/// Extract the source file path and fence info string from a mermaid comment
+/// line: an HTML comment, or a JSX one in MDX
+fn extract_source_comment(line: &str) -> Option<(String, String)> {
+    let trimmed = line.trim();
+    let inner = match mdx::comment_text(trimmed) {
+        Some(text) => text,
+        None => trimmed.strip_prefix("<!--")?.strip_suffix("-->")?.trim(),
+    };
+    let inner = inner.strip_prefix(SOURCE_FILE_MARKER)?.trim();
+    let (path, info) = inner.split_once(FENCE_INFO_MARKER).unwrap_or((inner, ""));
+    Some((path.trim_end().to_string(), info.trim().to_string()))
+}
+
+/// The comment line of a rendered block, a JSX comment in MDX documents
+/// (`jsx`), which have no HTML comments. The fence's info string is kept so
+/// restoring the source gives back the same opening line; one containing
+/// `--`, which can't appear in an HTML comment, is dropped.
+fn source_comment(relative_mmd: &str, info: &str, jsx: bool) -> String {
+    let text = if info.is_empty() || info.contains("--") {
+        format!("{SOURCE_FILE_MARKER}{relative_mmd}")
+    } else {
+        format!("{SOURCE_FILE_MARKER}{relative_mmd}{FENCE_INFO_MARKER}{info}")
+    };
+    if jsx {
+        mdx::comment(&text)
+    } else {
+        format!("<!-- {text} -->")
+    }
+}
+
+/// Opening line of a restored fence, opened with `ticks`
+fn fence_opening(ticks: &str, info: &str) -> String {
+    if info.is_empty() {
+        format!("{ticks}mermaid")
+    } else {
+        format!("{ticks}mermaid {info}")
+    }
+}
+
+/// Backticks of a fence around `code`: three, or one more than the longest
+/// line of only backticks in it, so that line doesn't close the fence
+fn fence_ticks(code: &str) -> String {
+    let longest = code
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && line.bytes().all(|b| b == b'`'))
+        .map(str::len)
+        .max()
+        .unwrap_or(0);
+    "`".repeat((longest + 1).max(3))
+}
+
+// ─── Rendering edits ────────────────────────────────────────────────────────
+
+/// Compute a hash for caching purposes
+fn code_hash(code: &str) -> u64 {
+    stable_hash::hash(code)
+}
+
+/// How many of `fences` failed. `failures` has one entry per distinct code,
+/// which fences with the same code share.
+fn failed_fences(fences: &[MermaidFence], failures: &HashMap<u64, Diagnostic>) -> usize {
+    fences.iter().filter(|fence| failures.contains_key(&code_hash(&fence.code))).count()
+}
+
+/// Get the document's base directory (where relative output dirs are resolved).
+/// Fails for documents that aren't local files; see [`scheme::classify`].
+/// Windows long paths lose their `\\?\` prefix, so links and the output
+/// directory are computed from the plain form. Symlinks are kept: files are
+/// placed by the path the client uses, and only containment checks resolve it.
+fn doc_base_dir(uri: &Url) -> ServerResult<PathBuf> {
+    match scheme::classify(uri) {
+        DocumentLocation::Local(path) => path.parent().map(paths::simplify),
+        DocumentLocation::Untitled | DocumentLocation::Virtual => None,
+    }
+    .ok_or_else(|| ServerError::NotLocalFile(uri.clone()))
+}
+
+/// The session's options with the `.mermaidrc` files from the workspace root
+/// down to `uri`'s directory applied. Documents that aren't local files get
+/// the session's as they are.
+fn document_config<'a>(
+    rc_files: &mut RcFiles,
+    config: &'a Config,
+    uri: &Url,
+    workspace_root: Option<&Path>,
+) -> Cow<'a, Config> {
+    match doc_base_dir(uri) {
+        Ok(dir) => rc_files.config_for(config, &dir, workspace_root.map(paths::simplify).as_deref()),
+        Err(_) => Cow::Borrowed(config),
+    }
+}
+
+/// Get a short name for the document (without extension), safe to start
+/// generated file names with; see [`naming::safe_stem`]
+fn doc_short_name(uri: &Url) -> String {
+    uri.to_file_path()
+        .ok()
+        .and_then(|p| p.file_stem().map(|s| naming::safe_stem(&s.to_string_lossy())))
+        .unwrap_or_else(|| "document".to_string())
+}
+
+/// Why the document can't be rendered in place, checked before anything is
+/// written; None for writable (and non-local) documents
+fn read_only_reason(uri: &Url, config: &Config) -> Option<String> {
+    let DocumentLocation::Local(path) = scheme::classify(uri) else {
+        return None;
+    };
+    paths::read_only_reason(&path, &output_dir(path.parent()?, config))
+}
+
+/// Output directory used when `outputDir` is not set
+const DEFAULT_OUTPUT_DIR: &str = ".mermaid";
+
+/// Directory rendered files are written to: `outputDir` (absolute, or relative to
+/// the document's directory), `.mermaid` by default. Relative to the directory
+/// as the document's path names it, see [`paths::logical_join`].
+fn output_dir(base_dir: &Path, config: &Config) -> PathBuf {
+    let dir = config.output_dir.as_deref().unwrap_or(DEFAULT_OUTPUT_DIR);
+    paths::logical_join(base_dir, dir)
+}
+
+/// Ensure the output directory exists
+fn ensure_mermaid_dir(base_dir: &Path, config: &Config) -> ServerResult<PathBuf> {
+    let mermaid_dir = output_dir(base_dir, config);
+    fs::create_dir_all(&mermaid_dir).map_err(ServerError::io(format!(
+        "Failed to create {}",
+        mermaid_dir.display()
+    )))?;
+    gitignore::checks().note_render(&mermaid_dir);
+    Ok(mermaid_dir)
+}
+
+/// The `.cache` directory under a `.mermaid` directory, holding the render
+/// journal and, with `cacheLocation: project`, the diagram cache
+fn project_cache_dir(mermaid_dir: &Path) -> PathBuf {
+    mermaid_dir.join(".cache")
+}
+
+/// The diagram cache directory for a `.mermaid` directory, by `cacheLocation`:
+/// [`project_cache_dir`], or the workspace's directory in the user's cache
+/// directory (the output directory's when there is no workspace). Falls back
+/// to the project when the platform has no cache directory.
+fn cache_dir(mermaid_dir: &Path, config: &Config) -> PathBuf {
+    match config.cache_location {
+        CacheLocation::Project => project_cache_dir(mermaid_dir),
+        CacheLocation::User => {
+            let root = trust::store().root().map(Path::to_path_buf);
+            cache::user_cache_dir(root.as_deref().unwrap_or(mermaid_dir)).unwrap_or_else(|| {
+                debug!("No user cache directory, caching under {}", mermaid_dir.display());
+                project_cache_dir(mermaid_dir)
+            })
+        }
+    }
+}
+
+/// Where the diagram cache is and how much it holds, for `mermaid.doctor` and
+/// `mermaid.stats`. Project caches are per output directory: this is the one
+/// of the document in `argument`, or of the workspace root without one.
+fn cache_summary(argument: Option<&Value>, state: &ServerState) -> Value {
+    let base_dir = argument
+        .and_then(|value| serde_json::from_value::<Url>(value.clone()).ok())
+        .and_then(|uri| doc_base_dir(&uri).ok())
+        .or_else(|| state.workspace_root.clone());
+    let dir = base_dir.map(|base_dir| cache_dir(&output_dir(&base_dir, &state.config), &state.config));
+    serde_json::json!({
+        "location": state.config.cache_location.as_str(),
+        "dir": dir,
+        "sizeBytes": dir.as_deref().map_or(0, cache::dir_size),
+    })
+}
+
+/// Open the diagram cache for a `.mermaid` directory
+fn open_cache(mermaid_dir: &Path, config: &Config) -> ServerResult<DiagramCache> {
+    DiagramCache::open(&cache_dir(mermaid_dir, config))
+        .map_err(ServerError::io("Failed to open the diagram cache"))
+}
+
+/// Cache key of `code` rendered by `backend`
+fn cache_key(code: &str, backend: Backend, config: &Config) -> String {
+    // Keyed by the values of its placeholders, so a changed variable renders again
+    let code = variables::substitute(code, config).unwrap_or(Cow::Borrowed(code));
+    ContentHash::new(&code, &render::backend_cache_version(backend, config)).to_string()
+}
+
+/// Render mermaid code with `backend`, reusing the diagram cache when the same code
+/// was rendered by the same backend before. Returns the SVG and whether it came
+/// from the cache.
+fn render_cached(
+    mermaid_dir: &Path,
+    code: &str,
+    backend: Backend,
+    config: &Config,
+) -> ServerResult<(String, bool)> {
+    let key = cache_key(code, backend, config);
+    let mut cache = open_cache(mermaid_dir, config)?;
+
+    if let Some(svg) = cache.get(&key) {
+        debug!("Using cached SVG for hash {key}");
+        // Entries cached before diagrams were described get their text now
+        return Ok((accessibility::describe(&svg, code), true));
+    }
+    // Another server may be rendering the same diagram: wait for it and use its SVG
+    let _rendering = cache
+        .lock_key(&key)
+        .inspect_err(|e| warn!("Failed to lock hash {key} for rendering: {e}"))
+        .ok();
+    if let Some(svg) = cache.get(&key) {
+        debug!("Using SVG for hash {key} rendered by another server");
+        return Ok((svg, true));
+    }
+
+    debug!("Rendering mermaid diagram...");
+    let svg = render::render_with(backend, code, config)?;
+    if let Err(e) = cache.put(&key, &svg) {
+        warn!("Failed to cache SVG for hash {key}: {e}");
+    }
+    Ok((svg, false))
+}
+
+/// Run the integrity check over the document's cache directory
+fn verify_cache(uri: &Url, config: &Config) -> ServerResult<cache::VerifyReport> {
+    let base_dir = doc_base_dir(uri)?;
+    let mut cache = open_cache(&output_dir(&base_dir, config), config)?;
+    Ok(cache.verify())
+}
+
+/// Create a workspace edit that renders a single mermaid fence to SVG
+fn create_render_edit(
+    uri: &Url,
+    doc: &str,
+    lines: &[&str],
+    fence: &MermaidFence,
+    config: &Config,
+    encoding: PositionEncoding,
+) -> ServerResult<WorkspaceEdit> {
+    let anchor = fence_anchors(lines, config).remove(&fence.start_line);
+    let newline = config.mmd_line_ending.newline(doc);
+    let text_edit = render_fence_edit(uri, lines, fence, anchor.as_deref(), newline, config, encoding)?;
+
+    let mut changes = HashMap::new();
+    changes.insert(uri.clone(), vec![text_edit]);
+
+    Ok(WorkspaceEdit::new(changes))
+}
+
+/// Anchor ids for the document's fences (by start line) when `diagramAnchors`
+/// is enabled. Ids already used by rendered blocks are skipped, and the rest are
+/// assigned in document order so rendering one or all fences agrees. Fences
+/// that already have an anchor line above them keep it.
+fn fence_anchors(lines: &[&str], config: &Config) -> HashMap<usize, String> {
+    if !config.diagram_anchors {
+        return HashMap::new();
+    }
+    let taken = lines
+        .iter()
+        .filter_map(|line| anchors::parse_anchor_line(line))
+        .map(str::to_string)
+        .collect();
+    let has_anchor = |fence: &MermaidFence| {
+        fence
+            .start_line
+            .checked_sub(1)
+            .is_some_and(|prev| anchors::parse_anchor_line(lines[prev]).is_some())
+    };
+    let fences: Vec<MermaidFence> = find_all_mermaid_fences(lines)
+        .into_iter()
+        .filter(|fence| !fence.ignored && fence.empty_reason().is_none() && !has_anchor(fence))
+        .collect();
+    let ids = anchors::assign_anchors(fences.iter().map(|f| f.code.as_str()), taken);
+    fences.iter().map(|f| f.start_line).zip(ids).collect()
+}
+
+/// Render a fence, write its assets (the `.mmd` with `newline` line endings),
+/// and build the edit that replaces it
+fn render_fence_edit(
+    uri: &Url,
+    lines: &[&str],
+    fence: &MermaidFence,
+    anchor: Option<&str>,
+    newline: &str,
+    config: &Config,
+    encoding: PositionEncoding,
+) -> ServerResult<TextEdit> {
+    verify_fence_range(lines, fence)?;
+    let base_dir = doc_base_dir(uri)?;
+    let mermaid_dir = ensure_mermaid_dir(&base_dir, config)?;
+
+    let requested = backend::requested(&fence.info).ok().flatten();
+    let backend = backend::select(&fence.info, config)?;
+    let started = Instant::now();
+    let rendered = fence_source(fence, config).and_then(|code| {
+        let (svg, cache_hit) = render_cached(&mermaid_dir, &code, backend, config)?;
+        Ok((code, svg, cache_hit))
+    });
+    let (code, svg) = match rendered {
+        Ok((code, svg, cache_hit)) => {
+            info!(
+                document = uri.as_str(),
+                fence_line = fence.start_line,
+                requested_backend = requested.map_or("default", Backend::name),
+                backend = backend.name(),
+                duration_ms = started.elapsed().as_millis() as u64,
+                cache_hit = cache_hit;
+                "Rendered mermaid diagram"
+            );
+            (code, svg)
+        }
+        Err(e) => {
+            error!(document = uri.as_str(), fence_line = fence.start_line; "Rendering failed: {e}");
+            return Err(e);
+        }
+    };
+
+    let name = rendered_file_name(uri, lines, fence, &code, config)?;
+    let svg_path = mermaid_dir.join(format!("{name}.svg"));
+    let mmd_path = mermaid_dir.join(format!("{name}.mmd"));
+
+    // Save files
+    fs::write(&svg_path, &svg).map_err(ServerError::io("Failed to write SVG file"))?;
+    fs::write(&mmd_path, stored_source(&code, newline, config))
+        .map_err(ServerError::io("Failed to write .mmd file"))?;
+
+    // Build the replacement text
+    let relative_svg = paths::relative_link(&base_dir, &svg_path);
+    let relative_mmd = paths::relative_link(&base_dir, &mmd_path);
+    let image = match fence.syntax {
+        Syntax::Markdown => format!("![Mermaid Diagram]({relative_svg})"),
+        Syntax::Component => mdx::image(&relative_svg),
+    };
+    let mut replacement = format!("{}\n\n{image}", source_comment(&relative_mmd, &fence.info, mdx::is_mdx(uri)));
+    if let Some(anchor) = anchor {
+        replacement = format!("{}\n{replacement}", anchors::anchor_line(anchor));
+    }
+
+    // Create text edit replacing the code fence
+    let range = line_range(lines, fence.start_line, fence.end_line, encoding);
+    Ok(TextEdit::new(range, replacement))
+}
+
+/// Name of a rendered fence's files (without extension), from `fileNameTemplate`
+fn rendered_file_name(
+    uri: &Url,
+    lines: &[&str],
+    fence: &MermaidFence,
+    code: &str,
+    config: &Config,
+) -> ServerResult<String> {
+    let template = &config.file_name_template;
+    // Only count the fences above when the name needs it
+    let index = if template.contains("{index}") {
+        find_all_mermaid_fences(lines)
+            .iter()
+            .position(|f| f.start_line == fence.start_line)
+            .map_or(1, |i| i + 1)
+    } else {
+        0
+    };
+    let parts = naming::NameParts {
+        doc: &doc_short_name(uri),
+        code,
+        index,
+        timestamp: &Local::now().format("%Y%m%d_%H%M%S").to_string(),
+        hash: &format!("{:016x}", code_hash(code)),
+    };
+    naming::expand(template, &parts).map_err(|e| ServerError::InvalidParams(format!("`fileNameTemplate`: {e}")))
+}
+
+/// The code a fence renders: its body, or the remote source its `url=`
+/// attribute points at
+fn fence_source(fence: &MermaidFence, config: &Config) -> ServerResult<String> {
+    match remote::source_url(&fence.info) {
+        Some(url) => remote::fetch_source(url, config),
+        None => Ok(fence.code.clone()),
+    }
+}
+
+/// Line of the fence body (0-based) that the renderer's failure message names,
+/// mapped back through the configured preamble and mermaid's preprocessing to
+/// the fence source. None for an error in the preamble itself.
+fn failure_line(fence: &MermaidFence, message: &str, config: &Config) -> Option<usize> {
+    let (sent, map) = render::with_preamble(&fence.code, config);
+    source_map::reported_line(message)
+        .and_then(|reported| map.then(&source_map::mermaid_view(&sent)).original_line(reported))
+}
+
+/// Where to show a fence's render failure: the line the renderer's message
+/// names, mapped back through mermaid's preprocessing to the fence source, or
+/// the whole fence when it names none
+fn failure_range(
+    lines: &[&str],
+    fence: &MermaidFence,
+    message: &str,
+    encoding: PositionEncoding,
+    config: &Config,
+) -> Range {
+    match failure_line(fence, message, config) {
+        Some(line) => line_range(lines, fence.start_line + 1 + line, fence.start_line + 1 + line, encoding),
+        None => line_range(lines, fence.start_line, fence.end_line, encoding),
+    }
+}
+
+/// Range covering whole lines `start_line..=end_line`, measured in `encoding`
+fn line_range(
+    lines: &[&str],
+    start_line: usize,
+    end_line: usize,
+    encoding: PositionEncoding,
+) -> Range {
+    let end_char = lines.get(end_line).map(|l| encoding.line_len(l)).unwrap_or(0);
+    Range::new(
+        Position::new(start_line as u32, 0),
+        Position::new(end_line as u32, end_char),
+    )
+}
+
+/// Create a workspace edit that replaces a fence with its SVG as raw inline
+/// markup. The source is still written to a `.mmd` file for restoring.
+fn create_inline_svg_edit(
+    uri: &Url,
+    lines: &[&str],
+    fence: &MermaidFence,
+    newline: &str,
+    config: &Config,
+    encoding: PositionEncoding,
+) -> ServerResult<WorkspaceEdit> {
+    if fence.syntax == Syntax::Component {
+        return Err(ServerError::InvalidParams(
+            "inline SVG isn't valid JSX, so MDX mermaid components can't be embedded".to_string(),
+        ));
+    }
+    let base_dir = doc_base_dir(uri)?;
+    let mermaid_dir = ensure_mermaid_dir(&base_dir, config)?;
+    let backend = backend::select(&fence.info, config)?;
+    let code = fence_source(fence, config)?;
+    let (svg, _) = render_cached(&mermaid_dir, &code, backend, config)?;
+
+    let mmd_path = mermaid_dir.join(format!("{}.mmd", rendered_file_name(uri, lines, fence, &code, config)?));
+    fs::write(&mmd_path, stored_source(&code, newline, config))
+        .map_err(ServerError::io("Failed to write .mmd file"))?;
+
+    let replacement = format!(
+        "{}\n\n{}",
+        source_comment(&paths::relative_link(&base_dir, &mmd_path), &fence.info, mdx::is_mdx(uri)),
+        inline_svg_markup(&svg)
+    );
+    let range = line_range(lines, fence.start_line, fence.end_line, encoding);
+
+    let mut changes = HashMap::new();
+    changes.insert(uri.clone(), vec![TextEdit::new(range, replacement)]);
+    Ok(WorkspaceEdit::new(changes))
+}
+
+/// The `.mmd` source kept for fence code: the code as written, or with the
+/// preamble when `preambleInSource` is set
+fn stored_source(code: &str, newline: &str, config: &Config) -> String {
+    if config.preamble_in_source {
+        with_newlines(&render::with_preamble(code, config).0, newline)
+    } else {
+        with_newlines(code, newline)
+    }
+}
+
+/// Fence code (joined with `\n`) with the given line endings
+fn with_newlines(code: &str, newline: &str) -> String {
+    if newline == "\n" {
+        code.to_string()
+    } else {
+        code.replace('\n', newline)
+    }
+}
+
+/// SVG markup that stays a single markdown HTML block: the XML prolog is
+/// dropped and blank lines, which would end the block, are removed
+fn inline_svg_markup(svg: &str) -> String {
+    let start = svg.find("<svg").unwrap_or(0);
+    svg[start..]
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Markdown image embedding the SVG as a base64 data URI
+fn inline_markdown_image(svg: &str) -> String {
+    format!(
+        "![Mermaid Diagram](data:image/svg+xml;base64,{})",
+        BASE64_STANDARD.encode(svg)
+    )
+}
+
+/// The fence containing the line given as a command argument, or the first fence
+fn fence_for_argument(lines: &[&str], line: Option<&Value>) -> ServerResult<MermaidFence> {
+    Ok(match line {
+        Some(value) => {
+            let line = value.as_u64().ok_or_else(|| {
+                ServerError::InvalidParams(format!("Expected a line number, got {value}"))
+            })? as usize;
+            find_mermaid_fence(lines, line).ok_or_else(|| {
+                ServerError::InvalidParams(format!("No mermaid block at line {}", line + 1))
+            })?
+        }
+        None => find_all_mermaid_fences(lines)
+            .into_iter()
+            .next()
+            .ok_or_else(|| ServerError::InvalidParams("No mermaid block in document".to_string()))?,
+    })
+}
+
+/// Why `mermaid.renderSingle` rendered nothing
+const NOT_IN_FENCE: &str = "cursor is not inside a mermaid block.";
+
+/// The fence `mermaid.renderSingle` renders: the one at the cursor line (its
+/// second argument), else the first one when its third argument is `true`.
+/// Ignored fences are never rendered, and empty ones only when at the cursor,
+/// to say why; None when there's nothing to render.
+fn single_render_fence(lines: &[&str], arguments: &[Value]) -> ServerResult<Option<MermaidFence>> {
+    let at_cursor = match arguments.get(1).filter(|value| !value.is_null()) {
+        Some(value) => {
+            let line = value.as_u64().ok_or_else(|| {
+                ServerError::InvalidParams(format!("Expected a cursor line, got {value}"))
+            })? as usize;
+            find_mermaid_fence(lines, line).filter(|fence| !fence.ignored)
+        }
+        None => None,
+    };
+    let fallback_to_first = arguments.get(2).and_then(Value::as_bool).unwrap_or(false);
+    Ok(at_cursor.or_else(|| {
+        fallback_to_first
+            .then(|| {
+                find_all_mermaid_fences(lines)
+                    .into_iter()
+                    .find(|fence| !fence.ignored && fence.empty_reason().is_none())
+            })
+            .flatten()
+    }))
+}
+
+/// Render `fences` to self-contained markdown images, for documents that
+/// can't be rendered in place. Nothing is written to disk.
+fn preview_fences(fences: &[MermaidFence], config: &Config) -> Vec<Value> {
+    fences
+        .iter()
+        .map(|fence| {
+            let rendered = backend::select(&fence.info, config)
+                .and_then(|backend| render::render_with(backend, &fence_source(fence, config)?, config));
+            match rendered {
+                Ok(svg) => serde_json::json!({ "line": fence.start_line, "markdown": inline_markdown_image(&svg) }),
+                Err(e) if config.error_placeholder => {
+                    let message = e.to_string();
+                    let line = failure_line(fence, &message, config).map(|line| line + 1);
+                    let svg = placeholder::error_svg(&fence.code, &message, line);
+                    serde_json::json!({
+                        "line": fence.start_line,
+                        "error": message,
+                        "markdown": inline_markdown_image(&svg),
+                        "placeholder": true,
+                    })
+                }
+                Err(e) => serde_json::json!({ "line": fence.start_line, "error": e.to_string() }),
+            }
+        })
+        .collect()
+}
+
+/// Render the fence at the given line (or the first one) to a self-contained
+/// markdown image. Nothing is written to disk.
+fn copy_as_markdown(lines: &[&str], line: Option<&Value>, config: &Config) -> ServerResult<String> {
+    let fence = fence_for_argument(lines, line)?;
+    let backend = backend::select(&fence.info, config)?;
+    let svg = render::render_with(backend, &fence_source(&fence, config)?, config)?;
+    Ok(inline_markdown_image(&svg))
+}
+
+/// Render each `%% step` of the fence at the given line (or the first one) to
+/// `<name>_step<n>.svg` and return the files plus markdown linking all of them
+fn render_steps(uri: &Url, lines: &[&str], line: Option<&Value>, config: &Config) -> ServerResult<Value> {
+    let fence = fence_for_argument(lines, line)?;
+    let steps = steps::cumulative_steps(&fence.code);
+    if steps.is_empty() {
+        return Err(ServerError::InvalidParams(format!(
+            "The mermaid block at line {} has no `%% step N` markers",
+            fence.start_line + 1
+        )));
+    }
+
+    let base_dir = doc_base_dir(uri)?;
+    let mermaid_dir = ensure_mermaid_dir(&base_dir, config)?;
+    let timestamp = Local::now().format("%Y%m%d_%H%M%S");
+    let name = format!("{}_diagram_{timestamp}", doc_short_name(uri));
+
+    let backend = backend::select(&fence.info, config)?;
+    let mut files = Vec::new();
+    let mut images = Vec::new();
+    for step in &steps {
+        let (svg, _) = render_cached(&mermaid_dir, &step.code, backend, config)?;
+        let path = mermaid_dir.join(format!("{name}_step{}.svg", step.number));
+        fs::write(&path, &svg).map_err(ServerError::io("Failed to write step SVG"))?;
+        let link = paths::relative_link(&base_dir, &path);
+        images.push(format!("![Step {}]({link})", step.number));
+        files.push(link);
+    }
+
+    Ok(serde_json::json!({ "files": files, "markdown": images.join("\n\n") }))
+}
+
+/// Resolve a comparison operand: a fence index into the document, or literal mermaid code
+fn comparison_source(lines: &[&str], arg: &Value) -> ServerResult<String> {
+    match arg {
+        Value::Number(n) => {
+            let index = n
+                .as_u64()
+                .ok_or_else(|| ServerError::InvalidParams(format!("Invalid fence index: {n}")))?
+                as usize;
+            find_all_mermaid_fences(lines)
+                .get(index)
+                .map(|fence| fence.code.clone())
+                .ok_or_else(|| {
+                    ServerError::InvalidParams(format!("No mermaid block at index {index}"))
+                })
+        }
+        Value::String(code) => Ok(code.clone()),
+        other => Err(ServerError::InvalidParams(format!(
+            "Expected a fence index or mermaid source, got {other}"
+        ))),
+    }
+}
+
+/// Render two diagrams side by side into the output directory and return the
+/// written file
+fn render_comparison(uri: &Url, doc: &str, args: &[Value], config: &Config) -> ServerResult<Value> {
+    let [old, new] = args else {
+        return Err(ServerError::InvalidParams(
+            "Expected two fence indices or mermaid sources".to_string(),
+        ));
+    };
+    let lines: Vec<&str> = doc.lines().collect();
+    let old = comparison_source(&lines, old)?;
+    let new = comparison_source(&lines, new)?;
+
+    let base_dir = doc_base_dir(uri)?;
+    let mermaid_dir = ensure_mermaid_dir(&base_dir, config)?;
+    let (old_svg, _) = render_cached(&mermaid_dir, &old, Backend::Mmdc, config)?;
+    let (new_svg, _) = render_cached(&mermaid_dir, &new, Backend::Mmdc, config)?;
+    let svg = compose::compose_horizontal(&[&old_svg, &new_svg])?;
+
+    let timestamp = Local::now().format("%Y%m%d_%H%M%S");
+    let filename = format!("{}_comparison_{timestamp}.svg", doc_short_name(uri));
+    let path = mermaid_dir.join(&filename);
+    fs::write(&path, &svg).map_err(ServerError::io("Failed to write comparison SVG"))?;
+
+    Ok(serde_json::json!({ "file": paths::relative_link(&base_dir, &path) }))
+}
+
+/// Render the fence at the line given first (or the first fence) and compare
+/// it pixel by pixel with the reference image given next, a path relative to
+/// the document. Then come the percentage of pixels that may differ for the
+/// comparison to pass (default 0) and `true` to write an image of the
+/// difference. An SVG reference is compared with the diagram's SVG, both
+/// rasterized; a PNG one with the diagram rendered to PNG by mmdc.
+fn compare_to_reference(
+    uri: &Url,
+    lines: &[&str],
+    args: &[Value],
+    workspace_root: Option<&Path>,
+    config: &Config,
+) -> ServerResult<outcome::Compared> {
+    let fence = fence_for_argument(lines, args.first().filter(|value| !value.is_null()))?;
+    let reference = args
+        .get(1)
+        .and_then(Value::as_str)
+        .ok_or_else(|| ServerError::InvalidParams("Expected the path of a reference image".to_string()))?;
+    let threshold = match args.get(2).filter(|value| !value.is_null()) {
+        Some(value) => value
+            .as_f64()
+            .filter(|threshold| (0.0..=100.0).contains(threshold))
+            .ok_or_else(|| ServerError::InvalidParams(format!("Expected a threshold from 0 to 100 percent, got {value}")))?,
+        None => 0.0,
+    };
+    let write_diff = args.get(3).and_then(Value::as_bool).unwrap_or(false);
+
+    let base_dir = doc_base_dir(uri)?;
+    let path = paths::logical_join(&base_dir, reference);
+    if !paths::is_within(&path, workspace_root.unwrap_or(&base_dir)) {
+        return Err(ServerError::InvalidParams(format!("{} is outside the workspace", path.display())));
+    }
+
+    let mermaid_dir = ensure_mermaid_dir(&base_dir, config)?;
+    let code = fence_source(&fence, config)?;
+    let rendered = if visual_diff::is_svg(&path) {
+        let backend = backend::select(&fence.info, config)?;
+        let (svg, _) = render_cached(&mermaid_dir, &code, backend, config)?;
+        visual_diff::rasterize_svg(&svg)?
+    } else {
+        visual_diff::decode_png(&render::render_png(&code, config)?)?
+    };
+    let difference = visual_diff::compare(&rendered, &visual_diff::load(&path)?)?;
+
+    let diff_image = if write_diff {
+        let timestamp = Local::now().format("%Y%m%d_%H%M%S");
+        let path = mermaid_dir.join(format!("{}_diff_{timestamp}.png", doc_short_name(uri)));
+        let png = difference
+            .diff
+            .encode_png()
+            .map_err(|e| ServerError::RenderFailed(format!("cannot encode the diff image: {e}")))?;
+        fs::write(&path, png).map_err(ServerError::io("Failed to write diff image"))?;
+        Some(paths::relative_link(&base_dir, &path))
+    } else {
+        None
+    };
+    Ok(outcome::Compared {
+        difference: difference.percent(),
+        differing_pixels: difference.differing_pixels,
+        total_pixels: difference.total_pixels,
+        threshold,
+        passed: difference.percent() <= threshold,
+        diff_image,
+    })
+}
+
+/// Create a workspace edit that renders all mermaid fences, plus a diagnostic for
+/// every fence that failed to render (keyed by code hash).
+///
+/// Fences are rendered in chunks of `config.render_chunk_size`; `on_chunk` is called
+/// with the number of fences processed so far and returning false cancels.
+fn create_render_all_edit(
+    uri: &Url,
+    lines: &[&str],
+    newline: &str,
+    config: &Config,
+    encoding: PositionEncoding,
+    on_chunk: impl FnMut(usize, usize) -> bool,
+) -> ServerResult<(Option<WorkspaceEdit>, HashMap<u64, Diagnostic>)> {
+    let fences: Vec<MermaidFence> = find_all_mermaid_fences(lines)
+        .into_iter()
+        .filter(|fence| !fence.ignored && fence.empty_reason().is_none())
+        .collect();
+    create_render_fences_edit(uri, lines, &fences, newline, config, encoding, on_chunk)
+}
+
+/// [`create_render_all_edit`] for some of the document's fences
+fn create_render_fences_edit(
+    uri: &Url,
+    lines: &[&str],
+    fences: &[MermaidFence],
+    newline: &str,
+    config: &Config,
+    encoding: PositionEncoding,
+    on_chunk: impl FnMut(usize, usize) -> bool,
+) -> ServerResult<(Option<WorkspaceEdit>, HashMap<u64, Diagnostic>)> {
+    let anchors = fence_anchors(lines, config);
+    let mut failures = HashMap::new();
+    let all_edits = render_in_chunks(fences, config.render_chunk_size, on_chunk, |fence| {
+        let anchor = anchors.get(&fence.start_line).map(String::as_str);
+        match render_fence_edit(uri, lines, fence, anchor, newline, config, encoding) {
+            Ok(edit) => Some(edit),
+            Err(e) => {
+                let range = failure_range(lines, fence, &e.to_string(), encoding, config);
+                failures.insert(code_hash(&fence.code), e.to_diagnostic(range));
+                None
+            }
+        }
+    })
+    .ok_or(ServerError::Cancelled)?;
+
+    if all_edits.is_empty() {
+        return Ok((None, failures));
+    }
+
+    let mut changes = HashMap::new();
+    changes.insert(uri.clone(), all_edits);
+    Ok((Some(WorkspaceEdit::new(changes)), failures))
+}
+
+/// A block rendered by another tool, as converted (or to be converted, in a
+/// dry run) by `mermaid.adoptRenderedBlocks`
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Adoption {
+    line: usize,
+    format: String,
+    /// The `.mmd` copy of the source in the output directory
+    source_file: String,
+    /// Image the converted block links to
+    image: String,
+    /// The image is missing, so it is rendered from the source
+    rerender: bool,
+    /// Why the block is left as it is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Convert diagrams rendered by other tools (see [`adopt`]) to this plugin's
+/// blocks: the source is copied to a `.mmd` in the output directory, the image
+/// is kept, or rendered when it is missing, and each block is rewritten to a
+/// source comment and image link. A dry run writes nothing and builds no edit.
+fn adopt_rendered_blocks(
+    uri: &Url,
+    lines: &[&str],
+    dry_run: bool,
+    newline: &str,
+    config: &Config,
+    encoding: PositionEncoding,
+) -> ServerResult<(Vec<Adoption>, Option<WorkspaceEdit>)> {
+    let patterns = adopt::patterns(&config.adopt_patterns).map_err(ServerError::InvalidParams)?;
+    let blocks = adopt::find_foreign_blocks(lines, &patterns);
+    let base_dir = doc_base_dir(uri)?;
+    let mermaid_dir = if dry_run || blocks.is_empty() {
+        output_dir(&base_dir, config)
+    } else {
+        ensure_mermaid_dir(&base_dir, config)?
+    };
+    let doc_name = doc_short_name(uri);
+    let timestamp = Local::now().format("%Y%m%d_%H%M%S");
+
+    let mut adopted = Vec::new();
+    let mut edits = Vec::new();
+    for (n, block) in blocks.iter().enumerate() {
+        let mmd_path = mermaid_dir.join(format!("{doc_name}_adopted_{timestamp}_{n}.mmd"));
+        let existing_image = block
+            .image
+            .as_deref()
+            .map(|target| paths::logical_join(&base_dir, target))
+            .filter(|path| path.is_file());
+        let image_path = existing_image
+            .clone()
+            .unwrap_or_else(|| mermaid_dir.join(format!("{doc_name}_diagram_{timestamp}_{n}.svg")));
+        let mut adoption = Adoption {
+            line: block.start_line,
+            format: block.format.clone(),
+            source_file: paths::relative_link(&base_dir, &mmd_path),
+            image: paths::relative_link(&base_dir, &image_path),
+            rerender: existing_image.is_none(),
+            error: None,
+        };
+
+        let render_to = existing_image.is_none().then_some(image_path.as_path());
+        let converted = adopt_block(block, &base_dir, &mmd_path, render_to, dry_run, newline, config);
+        match converted {
+            Ok(()) if !dry_run => {
+                let mut replacement = format!(
+                    "<!-- mermaid-source-file:{} -->\n\n![Mermaid Diagram]({})",
+                    adoption.source_file, adoption.image
+                );
+                if newline != "\n" {
+                    replacement = with_newlines(&replacement, newline);
+                }
+                let range = line_range(lines, block.start_line, block.end_line, encoding);
+                edits.push(TextEdit::new(range, replacement));
+            }
+            Ok(()) => {}
+            Err(e) => {
+                warn!("Not adopting the {} block at line {}: {e}", block.format, block.start_line + 1);
+                adoption.error = Some(e.to_string());
+            }
+        }
+        adopted.push(adoption);
+    }
+
+    if edits.is_empty() {
+        return Ok((adopted, None));
+    }
+    edits.reverse();
+    Ok((adopted, Some(WorkspaceEdit::new(HashMap::from([(uri.clone(), edits)])))))
+}
+
+/// Read a foreign block's source and, unless this is a dry run, write its
+/// `.mmd` copy and render it to `render_to` when the image is missing
+fn adopt_block(
+    block: &adopt::ForeignBlock,
+    base_dir: &Path,
+    mmd_path: &Path,
+    render_to: Option<&Path>,
+    dry_run: bool,
+    newline: &str,
+    config: &Config,
+) -> ServerResult<()> {
+    let code = match &block.source {
+        adopt::ForeignSource::Inline(code) => code.clone(),
+        adopt::ForeignSource::File(path) => fs::read_to_string(paths::logical_join(base_dir, path))
+            .map_err(ServerError::io(format!("Failed to read source {path}")))?
+            .replace("\r\n", "\n"),
+    };
+    if dry_run {
+        return Ok(());
+    }
+    if let Some(image_path) = render_to {
+        let mermaid_dir = mmd_path.parent().unwrap_or(base_dir);
+        let (svg, _) = render_cached(mermaid_dir, &code, Backend::Mmdc, config)?;
+        fs::write(image_path, svg).map_err(ServerError::io("Failed to write SVG file"))?;
+    }
+    fs::write(mmd_path, with_newlines(code.trim_end(), newline)).map_err(ServerError::io("Failed to write .mmd file"))
+}
+
+/// Outcome of rendering one file with `mermaid.renderFiles`
+#[derive(Debug, Default, serde::Serialize)]
+struct FileSummary {
+    uri: String,
+    /// Fences replaced by rendered diagrams
+    rendered: usize,
+    /// Ignored fences, left as they are
+    ignored: usize,
+    /// Fences that failed to render, with the reason
+    failed: Vec<FailedFence>,
+    /// Why the file wasn't rendered at all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct FailedFence {
+    line: u32,
+    message: String,
+}
+
+/// Render every fence of each file in `arguments` (file URIs) on disk and
+/// write the files back. Each file is summarized on its own, so one missing,
+/// open or out-of-workspace file doesn't stop the others. Nothing is written
+/// without a workspace root.
+fn render_files(
+    arguments: &[Value],
+    workspace_root: Option<&Path>,
+    documents: &HashMap<Url, String>,
+    config: &Config,
+    rc_files: &mut RcFiles,
+) -> ServerResult<Vec<FileSummary>> {
+    if workspace_root.is_none() {
+        return Err(ServerError::InvalidParams("no workspace folder to write files in".to_string()));
+    }
+    let summaries = arguments
+        .iter()
+        .map(|argument| {
+            let uri = argument.as_str().unwrap_or_default().to_string();
+            render_file(argument, workspace_root, documents, config, rc_files).unwrap_or_else(|e| FileSummary {
+                uri,
+                error: Some(e.to_string()),
+                ..Default::default()
+            })
+        })
+        .collect();
+    Ok(summaries)
+}
+
+fn render_file(
+    argument: &Value,
+    workspace_root: Option<&Path>,
+    documents: &HashMap<Url, String>,
+    config: &Config,
+    rc_files: &mut RcFiles,
+) -> ServerResult<FileSummary> {
+    let uri: Url = serde_json::from_value(argument.clone())?;
+    if !matches!(scheme::classify(&uri), DocumentLocation::Local(_)) {
+        return Err(ServerError::NotLocalFile(uri));
+    }
+    let config = &*document_config(rc_files, config, &uri, workspace_root);
+    let (doc, file) = DocumentSource::new(documents, workspace_root).read_from_disk(&uri)?;
+    // The editor's buffer may differ from the file; render it there instead
+    if documents.contains_key(&uri) {
+        return Err(ServerError::InvalidParams(format!(
+            "{uri} is open in the editor; use mermaid.renderAllLightweight"
+        )));
+    }
+
+    if let Some(reason) = read_only_reason(&uri, config) {
+        return Err(ServerError::ReadOnly(reason));
+    }
+
+    let (rendered, summary) = render_document(&uri, &doc, config)?;
+    if rendered != doc {
+        file.write(&rendered)?;
+    }
+    Ok(summary)
+}
+
+/// Render every fence of a document that isn't open in the editor, writing
+/// the assets, and return the new text with its summary
+fn render_document(uri: &Url, doc: &str, config: &Config) -> ServerResult<(String, FileSummary)> {
+    let lines: Vec<&str> = doc.lines().collect();
+    let newline = config.mmd_line_ending.newline(doc);
+    let (edit, failures) = create_render_all_edit(uri, &lines, newline, config, PositionEncoding::Utf8, |_, _| true)?;
+    let edits = edit
+        .and_then(|edit| edit.changes)
+        .and_then(|mut changes| changes.remove(uri))
+        .unwrap_or_default();
+    let rendered = if edits.is_empty() { doc.to_string() } else { apply_line_edits(doc, &edits) };
+
+    let mut failed: Vec<FailedFence> = failures
+        .into_values()
+        .map(|diagnostic| FailedFence {
+            line: diagnostic.range.start.line,
+            message: diagnostic.message,
+        })
+        .collect();
+    failed.sort_by_key(|failure| failure.line);
+    let summary = FileSummary {
+        uri: uri.to_string(),
+        rendered: edits.len(),
+        ignored: find_all_mermaid_fences(&lines).iter().filter(|fence| fence.ignored).count(),
+        failed,
+        error: None,
+    };
+    Ok((rendered, summary))
+}
+
+/// Apply edits that each replace whole lines, keeping the document's line
+/// endings
+fn apply_line_edits(doc: &str, edits: &[TextEdit]) -> String {
+    let newline = if doc.contains("\r\n") { "\r\n" } else { "\n" };
+    let mut lines: Vec<&str> = doc.lines().collect();
+    let mut edits: Vec<&TextEdit> = edits.iter().collect();
+    edits.sort_by_key(|edit| std::cmp::Reverse(edit.range.start.line));
+    for edit in edits {
+        let start = edit.range.start.line as usize;
+        let end = (edit.range.end.line as usize + 1).min(lines.len());
+        // Unlike `lines()`, keeps the empty line after a trailing newline
+        let new_lines = edit.new_text.split('\n').map(|line| line.strip_suffix('\r').unwrap_or(line));
+        lines.splice(start..end, new_lines);
+    }
+    let mut out = lines.join(newline);
+    if doc.ends_with('\n') {
+        out.push_str(newline);
+    }
+    out
+}
+
+/// Render fences chunk by chunk, in reverse order so line numbers remain valid.
+/// Returns None when `on_chunk` asks to stop.
+fn render_in_chunks(
+    fences: &[MermaidFence],
+    chunk_size: usize,
+    mut on_chunk: impl FnMut(usize, usize) -> bool,
+    mut render: impl FnMut(&MermaidFence) -> Option<TextEdit>,
+) -> Option<Vec<TextEdit>> {
+    let total = fences.len();
+    let mut edits = Vec::new();
+    let mut done = 0;
+
+    for chunk in fences.rchunks(chunk_size.max(1)) {
+        edits.extend(chunk.iter().rev().filter_map(&mut render));
+        done += chunk.len();
+        if !on_chunk(done, total) {
+            return None;
+        }
+    }
+
+    Some(edits)
+}
+
+// ─── Source editing (restore code blocks) ───────────────────────────────────
+
+/// Create a workspace edit that restores a rendered block to its mermaid source
+fn create_source_edit(
+    uri: &Url,
+    _doc: &str,
+    lines: &[&str],
+    block: &RenderedBlock,
+    encoding: PositionEncoding,
+) -> ServerResult<WorkspaceEdit> {
+    let base_dir = doc_base_dir(uri)?;
+    let mmd_path = paths::logical_join(&base_dir, &block.source_file);
+
+    // Read the original mermaid source
+    // Sources written for CRLF documents go back as plain fence lines
+    let mermaid_code = fs::read_to_string(&mmd_path)
+        .map_err(ServerError::io(format!("Failed to read {}", block.source_file)))?
+        .replace("\r\n", "\n");
+    let replacement = match block.syntax {
+        Syntax::Markdown => {
+            let ticks = fence_ticks(&mermaid_code);
+            format!("{}\n{mermaid_code}\n{ticks}", fence_opening(&ticks, &block.info))
+        }
+        Syntax::Component => mdx::component(&mermaid_code),
+    };
+
+    let range = line_range(lines, block.start_line, block.end_line, encoding);
+    let text_edit = TextEdit::new(range, replacement);
+
+    let mut changes = HashMap::new();
+    changes.insert(uri.clone(), vec![text_edit]);
+
+    Ok(WorkspaceEdit::new(changes))
+}
+
+/// Lines of a `.mmd` file read at most for a [`SourceCandidate`] snippet
+const SNIPPET_LINES: usize = 5;
+/// Longest [`SourceCandidate`] snippet, in characters
+const SNIPPET_CHARS: usize = 80;
+
+/// A rendered block `mermaid.editSingleSource` could restore, for a client's
+/// picker to re-invoke the command with `line`
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SourceCandidate {
+    /// The block's source comment
+    line: usize,
+    source_file: String,
+    /// First code line of the `.mmd`; None when it can't be read or the
+    /// first lines are blank or comments
+    snippet: Option<String>,
+}
+
+fn source_candidates(base_dir: &Path, blocks: &[RenderedBlock]) -> Vec<SourceCandidate> {
+    blocks
+        .iter()
+        .map(|block| SourceCandidate {
+            line: block.comment_line,
+            source_file: block.source_file.clone(),
+            snippet: source_snippet(&paths::logical_join(base_dir, &block.source_file)),
+        })
+        .collect()
+}
+
+/// The first code line among the first [`SNIPPET_LINES`] of `mmd_path`,
+/// shortened to [`SNIPPET_CHARS`]
+fn source_snippet(mmd_path: &Path) -> Option<String> {
+    let file = fs::File::open(mmd_path).ok()?;
+    let line = BufReader::new(file)
+        .lines()
+        .take(SNIPPET_LINES)
+        .map_while(Result::ok)
+        .map(|line| line.trim().to_string())
+        .find(|line| !line.is_empty() && !line.starts_with("%%") && line != "---")?;
+    Some(match line.char_indices().nth(SNIPPET_CHARS) {
+        Some((cut, _)) => format!("{}…", &line[..cut]),
+        None => line,
+    })
+}
+
+/// The warning for a block whose image and source comment name different
+/// diagrams; remote images are left alone
+fn mismatched_block(
+    lines: &[&str],
+    block: &RenderedBlock,
+    encoding: PositionEncoding,
+    config: &Config,
+) -> Option<Diagnostic> {
+    let (line, image) = block.image.as_ref()?;
+    if Url::parse(image).is_ok() {
+        return None;
+    }
+    diagnostics::check_rendered_block(&block.source_file, image, *line, lines[*line], encoding, config)
+}
+
+/// Re-render a mismatched block from its source, the file "Edit Mermaid
+/// Source" reads: the SVG is written next to the `.mmd` under its base name
+/// and the image is pointed at it
+fn create_realign_edit(
+    uri: &Url,
+    lines: &[&str],
+    block: &RenderedBlock,
+    config: &Config,
+    encoding: PositionEncoding,
+) -> ServerResult<WorkspaceEdit> {
+    let Some((line, image)) = &block.image else {
+        return Err(ServerError::InvalidParams("the rendered block has no image".to_string()));
+    };
+    let base_dir = doc_base_dir(uri)?;
+    let mmd_path = paths::logical_join(&base_dir, &block.source_file);
+    let code = fs::read_to_string(&mmd_path)
+        .map_err(ServerError::io(format!("Failed to read {}", block.source_file)))?;
+    let mermaid_dir = ensure_mermaid_dir(&base_dir, config)?;
+    let (svg, _) = render_cached(&mermaid_dir, &code, Backend::Mmdc, config)?;
+    let svg_path = mmd_path.with_extension("svg");
+    fs::write(&svg_path, svg).map_err(ServerError::io(format!("Failed to write {}", svg_path.display())))?;
+
+    let target = paths::relative_link(&base_dir, &svg_path);
+    let replacement = lines[*line].replacen(image.as_str(), &target, 1);
+    let text_edit = TextEdit::new(line_range(lines, *line, *line, encoding), replacement);
+
+    let mut changes = HashMap::new();
+    changes.insert(uri.clone(), vec![text_edit]);
+    Ok(WorkspaceEdit::new(changes))
+}
+
+/// Create a workspace edit that restores all rendered blocks to mermaid source.
+/// Blocks whose source is missing are skipped; the error is returned only if none
+/// could be restored.
+/// Create a workspace edit that reformats every fence in the canonical style
+/// of [`format::format_diagram`]. Ignored fences, remote sources, diagram
+/// types the formatter doesn't know and fences already formatted are left as
+/// they are; None when that is all of them.
+fn create_format_all_edit(uri: &Url, lines: &[&str], encoding: PositionEncoding) -> Option<WorkspaceEdit> {
+    let edits: Vec<TextEdit> = find_all_mermaid_fences(lines)
+        .iter()
+        .filter(|fence| !fence.ignored && remote::source_url(&fence.info).is_none())
+        .filter(|fence| fence.end_line > fence.start_line + 1)
+        .filter_map(|fence| {
+            // The body keeps the opener's indent, as in a list item
+            let opener = lines[fence.start_line];
+            let indent = &opener[..opener.len() - opener.trim_start().len()];
+            let body = &lines[fence.start_line + 1..fence.end_line];
+            let code: Vec<&str> = body.iter().map(|line| line.strip_prefix(indent).unwrap_or(line)).collect();
+            let code = match fence.syntax {
+                Syntax::Markdown => code.join("\n"),
+                Syntax::Component => mdx::unescape_template(&code.join("\n")),
+            };
+            let formatted: Vec<String> = fence
+                .syntax
+                .body(&format::format_diagram(&code)?)
+                .lines()
+                .map(|line| if line.is_empty() { String::new() } else { format!("{indent}{line}") })
+                .collect();
+            (formatted != body).then(|| {
+                let range = line_range(lines, fence.start_line + 1, fence.end_line - 1, encoding);
+                TextEdit::new(range, formatted.join("\n"))
+            })
+        })
+        .collect();
+    if edits.is_empty() {
+        return None;
+    }
+    let mut changes = HashMap::new();
+    changes.insert(uri.clone(), edits);
+    Some(WorkspaceEdit::new(changes))
+}
+
+fn create_edit_all_sources(
+    uri: &Url,
+    doc: &str,
+    lines: &[&str],
+    encoding: PositionEncoding,
+) -> ServerResult<Option<WorkspaceEdit>> {
+    let blocks = find_all_rendered_blocks(lines);
+    if blocks.is_empty() {
+        return Ok(None);
+    }
+
+    let mut all_edits = Vec::new();
+    let mut last_error = None;
+
+    // Process in reverse order
+    for block in blocks.iter().rev() {
+        match create_source_edit(uri, doc, lines, block, encoding) {
+            Ok(edit) => {
+                if let Some(mut changes) = edit.changes {
+                    all_edits.extend(changes.remove(uri).unwrap_or_default());
+                }
+            }
+            Err(e) => {
+                warn!("Skipping rendered block at line {}: {e}", block.comment_line + 1);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    if all_edits.is_empty() {
+        return match last_error {
+            Some(e) => Err(e),
+            None => Ok(None),
+        };
+    }
+
+    let mut changes = HashMap::new();
+    changes.insert(uri.clone(), all_edits);
+    Ok(Some(WorkspaceEdit::new(changes)))
+}
+
+// ─── Link checking ──────────────────────────────────────────────────────────
+
+/// A rendered block referring to a file that doesn't exist
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+struct BrokenLink {
+    /// 1-based line of the image or source comment
+    line: usize,
+    /// `svg` for the image, `source` for the `.mmd` file
+    kind: &'static str,
+    /// The reference as written in the document
+    path: String,
+}
+
+/// Missing SVGs and `.mmd` sources referenced by the rendered blocks.
+/// Images with a URL (`data:`, `https:`) are not checked.
+fn broken_links(base_dir: &Path, lines: &[&str]) -> Vec<BrokenLink> {
+    let mut broken = Vec::new();
+    for block in find_all_rendered_blocks(lines) {
+        if let Some((line, target)) = &block.image {
+            if Url::parse(target).is_err() && !paths::logical_join(base_dir, target).is_file() {
+                broken.push(BrokenLink {
+                    line: line + 1,
+                    kind: "svg",
+                    path: target.clone(),
+                });
+            }
+        }
+        if !paths::logical_join(base_dir, &block.source_file).is_file() {
+            broken.push(BrokenLink {
+                line: block.comment_line + 1,
+                kind: "source",
+                path: block.source_file.clone(),
+            });
+        }
+    }
+    broken.sort_by_key(|b| b.line);
+    broken
+}
+
+/// A diagram type as `mermaid.renderByType` compares them, ignoring case:
+/// `graph` is a flowchart and `stateDiagram-v2` a state diagram
+fn normalized_type(keyword: &str) -> String {
+    let keyword = keyword.to_ascii_lowercase();
+    match keyword.strip_suffix("-v2").unwrap_or(&keyword) {
+        "graph" => "flowchart".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// The diagram types a `mermaid.renderByType` argument names, as written: one,
+/// or a list
+fn requested_types(argument: Option<&Value>) -> ServerResult<Vec<String>> {
+    let names: Vec<&str> = match argument {
+        Some(Value::String(name)) => vec![name.as_str()],
+        Some(Value::Array(names)) => names.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+    let types: Vec<String> = names
+        .iter()
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect();
+    if types.is_empty() {
+        return Err(ServerError::InvalidParams(
+            "mermaid.renderByType expects a diagram type or a list of them, e.g. \"sequenceDiagram\"".to_string(),
+        ));
+    }
+    Ok(types)
+}
+
+/// A rendered block whose source didn't render; its SVG is left as it was
+#[derive(Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RerenderFailure {
+    source: String,
+    svg: String,
+    message: String,
+}
+
+/// Already rendered diagrams rendered again from their `.mmd` sources
+#[derive(Debug, Default, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BlockRerenders {
+    /// SVGs written
+    rerendered: Vec<String>,
+    skipped: usize,
+    failed: Vec<RerenderFailure>,
+}
+
+/// Render the SVG of each rendered block whose `.mmd` source is `wanted`
+/// again, in place; the document doesn't change. Blocks with inline SVG or an
+/// unreadable source are skipped. An SVG is only replaced once its source
+/// rendered, so a source edited into something invalid keeps the last good
+/// image and is reported instead.
+fn rerender_blocks(
+    uri: &Url,
+    lines: &[&str],
+    wanted: impl Fn(&str) -> bool,
+    config: &Config,
+) -> ServerResult<BlockRerenders> {
+    let base_dir = doc_base_dir(uri)?;
+    let mut rerenders = BlockRerenders::default();
+    for block in find_all_rendered_blocks(lines) {
+        let code = fs::read_to_string(paths::logical_join(&base_dir, &block.source_file)).ok();
+        let (Some(code), Some((_, svg))) = (code, block.image) else {
+            rerenders.skipped += 1;
+            continue;
+        };
+        if !wanted(&code) {
+            rerenders.skipped += 1;
+            continue;
+        }
+        let rendered = render::validate_code(&code)
+            .and_then(|()| backend::select(&block.info, config))
+            .and_then(|backend| {
+                let (svg, _) = render_cached(&ensure_mermaid_dir(&base_dir, config)?, &code, backend, config)?;
+                Ok(svg)
+            });
+        // Through a temp file, so a failed write can't truncate the old SVG either
+        let svg_path = paths::logical_join(&base_dir, &svg);
+        let tmp = cache::temp_path(&svg_path);
+        match rendered.and_then(|rendered| {
+            fs::write(&tmp, rendered)
+                .and_then(|()| fs::rename(&tmp, &svg_path))
+                .inspect_err(|_| {
+                    let _ = fs::remove_file(&tmp);
+                })
+                .map_err(ServerError::io(format!("Failed to write {svg}")))
+        }) {
+            Ok(()) => rerenders.rerendered.push(svg),
+            Err(e) => {
+                warn!("Not re-rendering {svg}: {e}");
+                rerenders.failed.push(RerenderFailure {
+                    source: block.source_file,
+                    svg,
+                    message: e.to_string(),
+                });
+            }
+        }
+    }
+    Ok(rerenders)
+}
+
+/// Summary of `mermaid.rerenderFromSources` for the user
+fn rerender_summary(rerenders: &BlockRerenders) -> String {
+    let mut summary = format!("Mermaid: re-rendered {} diagrams from their sources", rerenders.rerendered.len());
+    if !rerenders.failed.is_empty() {
+        let sources: Vec<&str> = rerenders.failed.iter().map(|f| f.source.as_str()).collect();
+        summary.push_str(&format!(
+            ", {} failed and kept their previous image: {}",
+            rerenders.failed.len(),
+            sources.join(", ")
+        ));
+    }
+    summary
+}
+
+/// Report the document's broken references. With `rerender`, missing SVGs whose
+/// `.mmd` source still exists are rendered again first; otherwise the result
+/// offers that as a command when any can be.
+fn check_links(uri: &Url, lines: &[&str], rerender: bool, config: &Config) -> ServerResult<Value> {
+    let base_dir = doc_base_dir(uri)?;
+    let mut broken = broken_links(&base_dir, lines);
+
+    // Missing images that can be rebuilt from their source
+    let fixable: Vec<(String, String)> = find_all_rendered_blocks(lines)
+        .into_iter()
+        .filter_map(|block| Some((block.image?.1, block.source_file)))
+        .filter(|(svg, mmd)| {
+            broken.iter().any(|b| b.kind == "svg" && &b.path == svg)
+                && !broken.iter().any(|b| b.kind == "source" && &b.path == mmd)
+        })
+        .collect();
+
+    let mut rerendered = Vec::new();
+    if rerender && !fixable.is_empty() {
+        let mermaid_dir = ensure_mermaid_dir(&base_dir, config)?;
+        for (svg, mmd) in &fixable {
+            let code = fs::read_to_string(paths::logical_join(&base_dir, mmd))
+                .map_err(ServerError::io(format!("Failed to read {mmd}")))?;
+            let (rendered, _) = render_cached(&mermaid_dir, &code, Backend::Mmdc, config)?;
+            let svg_path = paths::logical_join(&base_dir, svg);
+            if let Some(parent) = svg_path.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(ServerError::io(format!("Failed to create {}", parent.display())))?;
+            }
+            fs::write(&svg_path, rendered).map_err(ServerError::io(format!("Failed to write {svg}")))?;
+            rerendered.push(svg.clone());
+        }
+        broken = broken_links(&base_dir, lines);
+    }
+
+    let mut result = serde_json::json!({ "broken": broken });
+    if rerender {
+        result["rerendered"] = serde_json::json!(rerendered);
+    } else if !fixable.is_empty() {
+        let command = Command::new(
+            format!("Re-render {} missing Mermaid diagram(s)", fixable.len()),
+            "mermaid.checkLinks".to_string(),
+            Some(vec![serde_json::json!(uri), Value::Bool(true)]),
+        );
+        result["rerender"] = serde_json::to_value(command)?;
+    }
+    Ok(result)
+}
+
+// ─── Figure index ───────────────────────────────────────────────────────────
+
+/// Number the rendered diagrams as figures, in document order, and build the
+/// edit that adds an anchor above each one without and writes the index: over
+/// the previous one, else above line `at`, else at the end of the document.
+/// No edit when the document already has both.
+fn generate_index(
+    uri: &Url,
+    lines: &[&str],
+    at: Option<usize>,
+    encoding: PositionEncoding,
+) -> (Vec<Figure>, Option<WorkspaceEdit>) {
+    let base_dir = doc_base_dir(uri).ok();
+    let blocks = find_all_rendered_blocks(lines);
+    // Titles come from the sources; a missing one leaves the figure untitled
+    let codes: Vec<String> = blocks
+        .iter()
+        .map(|block| {
+            base_dir
+                .as_ref()
+                .and_then(|dir| fs::read_to_string(paths::logical_join(dir, &block.source_file)).ok())
+                .map(|code| code.replace("\r\n", "\n"))
+                .unwrap_or_default()
+        })
+        .collect();
+    let existing_anchor = |block: &RenderedBlock| {
+        block
+            .comment_line
+            .checked_sub(1)
+            .and_then(|prev| anchors::parse_anchor_line(lines[prev]))
+            .map(str::to_string)
+    };
+    let taken = lines
+        .iter()
+        .filter_map(|line| anchors::parse_anchor_line(line))
+        .map(str::to_string)
+        .collect();
+    let unanchored = blocks.iter().zip(&codes).filter(|(block, _)| existing_anchor(block).is_none());
+    let mut new_ids = anchors::assign_anchors(unanchored.map(|(_, code)| code.as_str()), taken).into_iter();
+
+    // New text of whole lines, so an anchor and the index can share a line
+    let mut replaced: BTreeMap<usize, String> = BTreeMap::new();
+    let mut figures = Vec::new();
+    for (i, (block, code)) in blocks.iter().zip(&codes).enumerate() {
+        let anchor = match existing_anchor(block) {
+            Some(id) => id,
+            None => {
+                let id = new_ids.next().unwrap_or_default();
+                let comment = lines[block.comment_line];
+                replaced.insert(block.comment_line, format!("{}\n{comment}", anchors::anchor_line(&id)));
+                id
+            }
+        };
+        figures.push(Figure {
+            number: i + 1,
+            anchor,
+            title: anchors::diagram_title(code),
+        });
+    }
+
+    let section = figures::index_section(&figures);
+    let mut edits = Vec::new();
+    match figures::find_index_section(lines) {
+        Some((start, end)) => {
+            if lines[start..=end].join("\n") != section {
+                edits.push(TextEdit::new(line_range(lines, start, end, encoding), section));
+            }
+        }
+        None if lines.is_empty() => edits.push(TextEdit::new(Range::default(), section)),
+        None => {
+            let (line, text) = match at.filter(|&line| line < lines.len()) {
+                Some(line) => {
+                    let current = replaced.remove(&line).unwrap_or_else(|| lines[line].to_string());
+                    if current.trim().is_empty() {
+                        (line, format!("{current}\n{section}\n"))
+                    } else {
+                        (line, format!("{section}\n\n{current}"))
+                    }
+                }
+                None => {
+                    let last = lines.len() - 1;
+                    let current = replaced.remove(&last).unwrap_or_else(|| lines[last].to_string());
+                    (last, format!("{current}\n\n{section}"))
+                }
+            };
+            replaced.insert(line, text);
+        }
+    }
+    edits.extend(
+        replaced
+            .into_iter()
+            .map(|(line, text)| TextEdit::new(line_range(lines, line, line, encoding), text)),
+    );
+
+    if edits.is_empty() {
+        return (figures, None);
+    }
+    (figures, Some(WorkspaceEdit::new(HashMap::from([(uri.clone(), edits)]))))
+}
+
+// ─── Tests ──────────────────────────────────────────────────────────────────
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::LineEnding;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn finds_mermaid_fences() {
+        let doc = "# Hello\n\n```mermaid\ngraph TD\n  A --> B\n```\n\nSome text\n";
+        let lines: Vec<&str> = doc.lines().collect();
+        let fences = find_all_mermaid_fences(&lines);
+
+        assert_eq!(fences.len(), 1);
+        assert_eq!(fences[0].start_line, 2);
+        assert_eq!(fences[0].end_line, 5);
+        assert_eq!(fences[0].code, "graph TD\n  A --> B");
+    }
+
+    #[test]
+    fn finds_multiple_fences() {
+        let doc = "```mermaid\ngraph TD\n  A-->B\n```\n\n```mermaid\nsequenceDiagram\n  A->>B: Hi\n```\n";
+        let lines: Vec<&str> = doc.lines().collect();
+        let fences = find_all_mermaid_fences(&lines);
+
+        assert_eq!(fences.len(), 2);
+        assert_eq!(fences[0].code, "graph TD\n  A-->B");
+        assert_eq!(fences[1].code, "sequenceDiagram\n  A->>B: Hi");
+    }
+
+    #[test]
+    fn ignores_non_mermaid_fences() {
+        let doc = "```rust\nfn main() {}\n```\n\n```mermaid\ngraph TD\n```\n";
+        let lines: Vec<&str> = doc.lines().collect();
+        let fences = find_all_mermaid_fences(&lines);
+
+        assert_eq!(fences.len(), 1);
+        assert!(fences[0].code.contains("graph TD"));
+    }
+
+    #[test]
+    fn closing_fences_may_only_have_trailing_spaces() {
+        let doc = "```mermaid\ngraph TD\n```   \n\n```mermaid\ngraph LR\n``` <!-- end -->\n  A-->B\n  ````\r\n";
+        let lines: Vec<&str> = doc.split('\n').collect();
+        let fences = find_all_mermaid_fences(&lines);
+
+        assert_eq!(fences.len(), 2);
+        assert_eq!((fences[0].start_line, fences[0].end_line), (0, 2));
+        assert_eq!((fences[1].start_line, fences[1].end_line), (4, 8));
+        assert_eq!(fences[1].code, "graph LR\n``` <!-- end -->\n  A-->B");
+    }
+
+    #[test]
+    fn closing_fences_need_the_openers_backticks_and_indent() {
+        // A longer fence shows a ``` line as code
+        let doc = "````mermaid\ngraph TD\n```\n````\n";
+        let lines: Vec<&str> = doc.lines().collect();
+        let fences = find_all_mermaid_fences(&lines);
+        assert_eq!(fences.len(), 1);
+        assert_eq!((fences[0].end_line, fences[0].code.as_str()), (3, "graph TD\n```"));
+        assert!(verify_fence_range(&lines, &fences[0]).is_ok());
+
+        // Four spaces of indent make a ``` line code; in a list item the
+        // opener's indent counts too
+        let doc = "```mermaid\ngraph TD\n    ```\n```\n\n- item\n  ```mermaid\n  graph LR\n     ```\n";
+        let lines: Vec<&str> = doc.lines().collect();
+        let fences = find_all_mermaid_fences(&lines);
+        assert_eq!(fences.len(), 2);
+        assert_eq!((fences[0].end_line, fences[0].code.as_str()), (3, "graph TD\n    ```"));
+        assert_eq!((fences[1].start_line, fences[1].end_line), (6, 8));
+        let stretched = MermaidFence {
+            end_line: 2,
+            ..fences[0].clone()
+        };
+        assert!(verify_fence_range(&lines, &stretched).is_err());
+    }
+
+    #[test]
+    fn render_edits_never_cover_prose() {
+        // The first fence used to run on to the ``` closing the js fence,
+        // replacing the paragraph between them
+        let doc = "```mermaid\ngraph TD\n```  \n\nProse that must survive.\n\n```js\nx()\n```\n";
+        let lines: Vec<&str> = doc.lines().collect();
+        let fences = find_all_mermaid_fences(&lines);
+        assert_eq!(fences.len(), 1);
+        assert_eq!(fences[0].end_line, 2);
+        assert!(verify_fence_range(&lines, &fences[0]).is_ok());
+
+        let swallowing = MermaidFence {
+            end_line: 8,
+            ..fences[0].clone()
+        };
+        let err = verify_fence_range(&lines, &swallowing).unwrap_err();
+        assert!(err.to_string().contains("lines 1-9"), "{err}");
+        let uri = Url::parse("file:///tmp/prose.md").unwrap();
+        assert!(create_render_edit(&uri, doc, &lines, &swallowing, &Config::default(), PositionEncoding::Utf16).is_err());
+
+        let moved = MermaidFence {
+            start_line: 4,
+            end_line: 8,
+            ..fences[0].clone()
+        };
+        assert!(verify_fence_range(&lines, &moved).is_err());
+    }
+
+    #[test]
+    fn finds_fence_at_cursor() {
+        let doc = "Text\n```mermaid\ngraph TD\n  A-->B\n```\nMore text\n";
+        let lines: Vec<&str> = doc.lines().collect();
+
+        assert!(find_mermaid_fence(&lines, 0).is_none());
+        assert!(find_mermaid_fence(&lines, 1).is_some());
+        assert!(find_mermaid_fence(&lines, 2).is_some());
+        assert!(find_mermaid_fence(&lines, 3).is_some());
+        assert!(find_mermaid_fence(&lines, 4).is_some());
+        assert!(find_mermaid_fence(&lines, 5).is_none());
+    }
+
+    #[test]
+    fn extracts_source_file_path() {
+        assert_eq!(
+            extract_source_comment("<!-- mermaid-source-file:.mermaid/doc_20240101.mmd -->"),
+            Some((".mermaid/doc_20240101.mmd".to_string(), String::new()))
+        );
+        assert_eq!(
+            extract_source_comment("{/* mermaid-source-file:.mermaid/doc.mmd */}"),
+            Some((".mermaid/doc.mmd".to_string(), String::new()))
+        );
+        assert_eq!(
+            extract_source_comment("Some random text"),
+            None
+        );
+        assert_eq!(
+            extract_source_comment("<!-- other comment -->"),
+            None
+        );
+    }
+
+    #[test]
+    fn source_comments_keep_the_fence_info() {
+        let comment = source_comment(".mermaid/doc.mmd", "{theme=dark format=png}", false);
+        assert_eq!(comment, "<!-- mermaid-source-file:.mermaid/doc.mmd fence-info:{theme=dark format=png} -->");
+        assert_eq!(
+            extract_source_comment(&comment),
+            Some((".mermaid/doc.mmd".to_string(), "{theme=dark format=png}".to_string()))
+        );
+        assert_eq!(fence_opening("```", "{theme=dark format=png}"), "```mermaid {theme=dark format=png}");
+        assert_eq!(fence_ticks("graph TD\n  A-->B"), "```");
+        assert_eq!(fence_ticks("graph TD\n```\n  `````  "), "``````");
+        assert_eq!(source_comment("a.mmd", "", false), "<!-- mermaid-source-file:a.mmd -->");
+        assert_eq!(source_comment("a.mmd", "{title=a--b}", false), "<!-- mermaid-source-file:a.mmd -->");
+        assert_eq!(source_comment("a.mmd", "", true), "{/* mermaid-source-file:a.mmd */}");
+    }
+
+    #[test]
+    fn render_and_edit_cycles_give_back_the_document() {
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        let code = "graph TD\n  A-->B";
+        let plain = Config::default();
+        let anchored = Config {
+            diagram_anchors: true,
+            ..Config::default()
+        };
+        for config in [&plain, &anchored] {
+            let key = ContentHash::new(code, &render::backend_cache_version(Backend::Mmdc, config)).to_string();
+            open_cache(&ensure_mermaid_dir(dir.path(), config).unwrap(), config)
+                .unwrap()
+                .put(&key, "<svg xmlns=\"http://www.w3.org/2000/svg\"/>")
+                .unwrap();
+        }
+
+        let documents = [
+            format!("# Title\n\n```mermaid {{theme=dark format=png}}\n{code}\n```\n\nAfter\n"),
+            format!("```mermaid flowchart-alias\n{code}\n```"),
+            format!("Before\n```mermaid\n{code}\n```\nAfter\n"),
+            format!("# Title\n\n\n\n```mermaid\n{code}\n```\n\n\n"),
+            format!("- item\n\n```mermaid {{backend=mmdc}}\n{code}\n```\n- next\n"),
+            format!("# Title\r\n\r\n```mermaid {{theme=dark}}\r\n{}\r\n```\r\n\r\nAfter\r\n", code.replace('\n', "\r\n")),
+        ];
+        for (config, original) in documents.iter().flat_map(|doc| [(&plain, doc), (&anchored, doc)]) {
+            let mut doc = original.clone();
+            for cycle in 1..=3 {
+                let lines: Vec<&str> = doc.lines().collect();
+                let (edit, failures) =
+                    create_render_all_edit(&uri, &lines, "\n", config, PositionEncoding::Utf16, |_, _| true).unwrap();
+                assert!(failures.is_empty(), "{failures:?}");
+                let rendered = apply_line_edits(&doc, &edit.unwrap().changes.unwrap()[&uri]);
+                assert!(!rendered.contains("```"), "cycle {cycle}: {rendered:?}");
+
+                let lines: Vec<&str> = rendered.lines().collect();
+                let edit = create_edit_all_sources(&uri, &rendered, &lines, PositionEncoding::Utf16).unwrap().unwrap();
+                doc = apply_line_edits(&rendered, &edit.changes.unwrap()[&uri]);
+                assert_eq!(&doc, original, "cycle {cycle}");
+            }
+        }
+    }
+
+    #[test]
+    fn finds_rendered_blocks() {
+        let doc = "<!-- mermaid-source-file:.mermaid/doc.mmd -->\n\n![Mermaid Diagram](.mermaid/doc.svg)\n";
+        let lines: Vec<&str> = doc.lines().collect();
+        let blocks = find_all_rendered_blocks(&lines);
+
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].comment_line, 0);
+        assert_eq!(blocks[0].end_line, 2);
+        assert_eq!(blocks[0].source_file, ".mermaid/doc.mmd");
+    }
+
+    #[test]
+    fn finds_rendered_blocks_in_other_output_dirs() {
+        let doc = "<!-- mermaid-source-file:../../out/doc.mmd -->\n\n![Mermaid Diagram](../../out/doc.svg)\n";
+        let lines: Vec<&str> = doc.lines().collect();
+        let blocks = find_all_rendered_blocks(&lines);
+
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].end_line, 2);
+        assert_eq!(blocks[0].source_file, "../../out/doc.mmd");
+    }
+
+    /// Apply a whole-line edit (as produced for fences and rendered blocks)
+    fn apply_line_edit(doc: &str, edit: &TextEdit) -> String {
+        let lines: Vec<&str> = doc.lines().collect();
+        let (start, end) = (edit.range.start.line as usize, edit.range.end.line as usize);
+        let mut out: Vec<&str> = lines[..start].to_vec();
+        out.push(&edit.new_text);
+        out.extend(&lines[end + 1..]);
+        out.join("\n") + "\n"
+    }
+
+    #[test]
+    fn adopts_blocks_rendered_by_other_tools() {
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        fs::create_dir_all(dir.path().join("diagrams")).unwrap();
+        fs::create_dir_all(dir.path().join("img")).unwrap();
+        fs::write(dir.path().join("diagrams/flow.mmd"), "graph TD\r\n  A-->B\r\n").unwrap();
+        fs::write(dir.path().join("diagrams/seq.mmd"), "sequenceDiagram\n  A->>B: hi\n").unwrap();
+        fs::write(dir.path().join("img/flow.svg"), "<svg/>").unwrap();
+        fs::write(dir.path().join("img/seq.png"), "png").unwrap();
+        // The commented-out fence has no image yet; seed the cache so it renders without mmdc
+        let inline = "graph LR\n  C-->D";
+        let config = Config::default();
+        let key = ContentHash::new(inline, &render::backend_cache_version(Backend::Mmdc, &config)).to_string();
+        open_cache(&output_dir(dir.path(), &config), &config).unwrap().put(&key, "<svg xmlns=\"http://www.w3.org/2000/svg\"/>").unwrap();
+
+        let doc = "# Doc\n\
+            <!-- mermaid source: diagrams/flow.mmd -->\n\n![flow](img/flow.svg)\n\n\
+            [mermaid]: # (diagrams/seq.mmd)\n![](img/seq.png)\n\n\
+            <!--\n```mermaid\ngraph LR\n  C-->D\n```\n-->\n\n\
+            <!-- mermaid src=\"gone.mmd\" -->\n![](img/gone.svg)\n";
+        let lines: Vec<&str> = doc.lines().collect();
+
+        let (report, edit) = adopt_rendered_blocks(&uri, &lines, true, "\n", &config, PositionEncoding::Utf16).unwrap();
+        assert!(edit.is_none());
+        let report = serde_json::to_value(&report).unwrap();
+        let formats: Vec<&str> = report.as_array().unwrap().iter().map(|b| b["format"].as_str().unwrap()).collect();
+        assert_eq!(formats, ["mermaid-source-comment", "link-reference", adopt::INLINE_FORMAT, "mermaid-src-attribute"]);
+        assert_eq!(report[0]["image"], "img/flow.svg");
+        assert_eq!(report[0]["rerender"], false);
+        assert_eq!(report[2]["rerender"], true);
+        assert!(report[3]["error"].as_str().unwrap().contains("gone.mmd"));
+        assert!(fs::read_dir(output_dir(dir.path(), &config)).unwrap().all(|e| e.unwrap().file_name() == ".cache"));
+
+        let (report, edit) = adopt_rendered_blocks(&uri, &lines, false, "\n", &config, PositionEncoding::Utf16).unwrap();
+        let edits = &edit.unwrap().changes.unwrap()[&uri];
+        assert_eq!(edits.len(), 3);
+        let adopted = apply_line_edits(doc, edits);
+        let blocks = find_all_rendered_blocks(&adopted.lines().collect::<Vec<_>>());
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0].image.as_ref().unwrap().1, "img/flow.svg");
+        assert_eq!(blocks[1].image.as_ref().unwrap().1, "img/seq.png");
+        assert!(adopted.contains("<!-- mermaid src=\"gone.mmd\" -->"));
+        for (block, code) in blocks.iter().zip(["graph TD\n  A-->B", "sequenceDiagram\n  A->>B: hi", inline]) {
+            assert_eq!(fs::read_to_string(dir.path().join(&block.source_file)).unwrap(), code);
+        }
+        let rendered = dir.path().join(&report[2].image);
+        assert!(fs::read_to_string(rendered).unwrap().starts_with("<svg"));
+    }
+
+    #[test]
+    fn documents_get_the_nearest_mermaidrc() {
+        let root = tempfile::tempdir().unwrap();
+        let nested = root.path().join("docs/api");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(root.path().join(mermaidrc::RC_FILE), r#"{ "theme": "forest", "diagramAnchors": true }"#).unwrap();
+        fs::write(nested.join(mermaidrc::RC_FILE), "theme = \"dark\"\n").unwrap();
+
+        let session = Config::default();
+        let mut rc_files = RcFiles::default();
+        let nested_doc = Url::from_file_path(nested.join("spec.md")).unwrap();
+        let config = document_config(&mut rc_files, &session, &nested_doc, Some(root.path()));
+        assert_eq!(config.theme, "dark");
+        assert!(config.diagram_anchors);
+
+        let root_doc = Url::from_file_path(root.path().join("README.md")).unwrap();
+        assert_eq!(document_config(&mut rc_files, &session, &root_doc, Some(root.path())).theme, "forest");
+        let untitled = Url::parse("untitled:Untitled-1").unwrap();
+        assert_eq!(document_config(&mut rc_files, &session, &untitled, Some(root.path())).theme, "default");
+    }
+
+    #[test]
+    fn render_files_summarizes_each_file() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let docs = root.path().join("docs");
+        fs::create_dir_all(&docs).unwrap();
+        let code = "graph TD\n  A-->B";
+        let svg = "<svg xmlns=\"http://www.w3.org/2000/svg\"><g/></svg>";
+        let config = Config::default();
+        let key = ContentHash::new(code, &render::backend_cache_version(Backend::Mmdc, &config)).to_string();
+        open_cache(&ensure_mermaid_dir(&docs, &config).unwrap(), &config)
+            .unwrap()
+            .put(&key, svg)
+            .unwrap();
+
+        fs::write(docs.join("a.md"), format!("# A\r\n\r\n```mermaid\r\n{}\r\n```\r\n", code.replace('\n', "\r\n"))).unwrap();
+        fs::write(docs.join("b.md"), "# No diagrams\n").unwrap();
+        fs::write(docs.join("open.md"), "```mermaid\ngraph LR\n```\n").unwrap();
+        fs::write(outside.path().join("c.md"), "```mermaid\ngraph LR\n```\n").unwrap();
+        let uri = |path: PathBuf| serde_json::to_value(Url::from_file_path(path).unwrap()).unwrap();
+        let arguments = vec![
+            uri(docs.join("a.md")),
+            uri(docs.join("b.md")),
+            uri(docs.join("missing.md")),
+            uri(outside.path().join("c.md")),
+            uri(docs.join("open.md")),
+        ];
+        let documents = HashMap::from([(Url::from_file_path(docs.join("open.md")).unwrap(), String::new())]);
+
+        let err = render_files(&arguments, None, &documents, &config, &mut RcFiles::default()).unwrap_err();
+        assert!(err.to_string().contains("no workspace folder"), "{err}");
+
+        let summaries = render_files(&arguments, Some(root.path()), &documents, &config, &mut RcFiles::default()).unwrap();
+        let summary = serde_json::to_value(&summaries).unwrap();
+        assert_eq!(summary[0]["rendered"], 1);
+        assert_eq!(summary[0]["failed"], serde_json::json!([]));
+        assert!(summary[0].get("error").is_none());
+        assert_eq!(summary[1]["rendered"], 0);
+        assert!(summary[2]["error"].as_str().unwrap().contains("missing.md"));
+        assert!(summary[3]["error"].as_str().unwrap().contains("outside the workspace"));
+        assert!(summary[4]["error"].as_str().unwrap().contains("open in the editor"));
+
+        let rendered = fs::read_to_string(docs.join("a.md")).unwrap();
+        assert!(rendered.starts_with("# A\r\n\r\n<!-- mermaid-source-file:"), "{rendered:?}");
+        assert!(rendered.contains("![Mermaid Diagram](.mermaid/") && rendered.ends_with(".svg)\r\n"), "{rendered:?}");
+        assert!(!rendered.contains("```"));
+        assert_eq!(fs::read_to_string(docs.join("b.md")).unwrap(), "# No diagrams\n");
+    }
+
+    #[test]
+    fn inline_svg_is_embedded_and_restored_to_a_fence() {
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        let code = "graph TD\n  A-->B";
+        let svg = "<?xml version=\"1.0\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\">\n\n  <g><text>A</text></g>\n</svg>";
+        // Seed the cache so no mmdc is needed
+        let config = Config::default();
+        let key = ContentHash::new(code, &render::backend_cache_version(Backend::Mmdc, &config)).to_string();
+        open_cache(&ensure_mermaid_dir(dir.path(), &config).unwrap(), &config)
+            .unwrap()
+            .put(&key, svg)
+            .unwrap();
+
+        let doc = format!("# Title\n\n```mermaid\n{code}\n```\n\nAfter\n");
+        let lines: Vec<&str> = doc.lines().collect();
+        let fence = &find_all_mermaid_fences(&lines)[0];
+        let edit = create_inline_svg_edit(&uri, &lines, fence, "\n", &config, PositionEncoding::Utf16).unwrap();
+        let text_edit = &edit.changes.unwrap()[&uri][0];
+        let rendered = apply_line_edit(&doc, text_edit);
+
+        assert!(!rendered.contains("<?xml"));
+        assert!(!rendered.contains("![Mermaid Diagram]"));
+        assert!(rendered.contains("\n<svg xmlns=\"http://www.w3.org/2000/svg\" role=\"img\""), "{rendered}");
+        assert!(rendered.contains("</desc>\n  <g><text>A</text></g>\n</svg>\n\nAfter"), "{rendered}");
+
+        let lines: Vec<&str> = rendered.lines().collect();
+        let blocks = find_all_rendered_blocks(&lines);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!((blocks[0].comment_line, blocks[0].end_line), (2, 6));
+        assert_eq!(lines[blocks[0].end_line], "</svg>");
+
+        let restore = create_source_edit(&uri, &rendered, &lines, &blocks[0], PositionEncoding::Utf16).unwrap();
+        let restored = apply_line_edit(&rendered, &restore.changes.unwrap()[&uri][0]);
+        assert_eq!(restored, doc);
+    }
+
+    /// Serves one diagram source, counting the requests
+    struct FixedSource(&'static str, std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+    impl remote::Fetcher for FixedSource {
+        fn fetch(&self, url: &Url, _max_bytes: u64, _timeout: std::time::Duration) -> Result<Vec<u8>, String> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            match url.path() {
+                "/flow.mmd" => Ok(self.0.as_bytes().to_vec()),
+                _ => Err("HTTP 404".to_string()),
+            }
+        }
+    }
+
+    #[test]
+    fn renders_fences_with_remote_sources() {
+        const REMOTE: &str = "graph LR\n  Remote --> Source\n";
+        let requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let previous = remote::set_fetcher(Box::new(FixedSource(REMOTE, requests.clone())));
+
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        let doc = "# Doc\n\n```mermaid url=https://diagrams.example.com/flow.mmd\n```\n\n```mermaid url=https://diagrams.example.com/gone.mmd\n```\n";
+        let lines: Vec<&str> = doc.lines().collect();
+        let fences = find_all_mermaid_fences(&lines);
+        let config = Config {
+            remote_sources: true,
+            remote_source_hosts: vec!["example.com".to_string()],
+            ..Config::default()
+        };
+
+        // Cached by the fetched content, not the (empty) fence body
+        let mermaid_dir = ensure_mermaid_dir(dir.path(), &config).unwrap();
+        let svg = "<svg xmlns=\"http://www.w3.org/2000/svg\"><text>Remote</text></svg>";
+        open_cache(&mermaid_dir, &config).unwrap().put(&cache_key(REMOTE, Backend::Mmdc, &config), svg).unwrap();
+        let edit = create_render_edit(&uri, doc, &lines, &fences[0], &config, PositionEncoding::Utf16).unwrap();
+        let rendered = apply_line_edit(doc, &edit.changes.unwrap()[&uri][0]);
+        let rendered_lines: Vec<&str> = rendered.lines().collect();
+        let block = &find_all_rendered_blocks(&rendered_lines)[0];
+        assert_eq!(block.info, "url=https://diagrams.example.com/flow.mmd");
+        let (_, image) = block.image.as_ref().unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join(image)).unwrap(), accessibility::describe(svg, REMOTE));
+        assert_eq!(fs::read_to_string(dir.path().join(&block.source_file)).unwrap(), REMOTE);
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+
+        // Fetch failures name the URL, both from the command and as diagnostics
+        let err = create_render_edit(&uri, doc, &lines, &fences[1], &config, PositionEncoding::Utf16).unwrap_err();
+        assert_eq!(err.to_string(), "cannot fetch diagram source https://diagrams.example.com/gone.mmd: HTTP 404");
+        let diagnostics = document_diagnostics(doc, None, PositionEncoding::Utf16, &Config::default());
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics.iter().all(|d| d.code == Some(NumberOrString::String("fetch-failed".to_string()))));
+        assert_eq!(diagnostics[0].range.start.line, 2);
+        assert!(document_diagnostics(doc, None, PositionEncoding::Utf16, &config).is_empty());
+
+        // Without the opt-in nothing is fetched
+        assert!(create_render_edit(&uri, doc, &lines, &fences[0], &Config::default(), PositionEncoding::Utf16).is_err());
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+        remote::set_fetcher(previous);
+    }
+
+    /// The `.mmd` written when rendering the first fence of `doc`, from a seeded cache
+    fn written_mmd(doc: &str, config: &Config) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        let lines: Vec<&str> = doc.lines().collect();
+        let fence = &find_all_mermaid_fences(&lines)[0];
+        let mermaid_dir = ensure_mermaid_dir(dir.path(), config).unwrap();
+        open_cache(&mermaid_dir, config)
+            .unwrap()
+            .put(&cache_key(&fence.code, Backend::Mmdc, config), "<svg></svg>")
+            .unwrap();
+
+        create_render_edit(&uri, doc, &lines, fence, config, PositionEncoding::Utf16).unwrap();
+        let mmd = fs::read_dir(&mermaid_dir)
+            .unwrap()
+            .flatten()
+            .find(|e| e.path().extension().is_some_and(|ext| ext == "mmd"))
+            .unwrap();
+        fs::read_to_string(mmd.path()).unwrap()
+    }
+
+    #[test]
+    fn mmd_files_follow_the_configured_line_ending() {
+        let crlf_doc = "# Doc\r\n\r\n```mermaid\r\ngraph TD\r\n  A-->B\r\n```\r\n";
+        let lf_doc = crlf_doc.replace("\r\n", "\n");
+        let config = |mmd_line_ending| Config {
+            mmd_line_ending,
+            ..Config::default()
+        };
+
+        assert_eq!(written_mmd(crlf_doc, &Config::default()), "graph TD\r\n  A-->B");
+        assert_eq!(written_mmd(&lf_doc, &Config::default()), "graph TD\n  A-->B");
+        assert_eq!(written_mmd(&lf_doc, &config(LineEnding::Crlf)), "graph TD\r\n  A-->B");
+        assert_eq!(written_mmd(crlf_doc, &config(LineEnding::Lf)), "graph TD\n  A-->B");
+    }
+
+    #[test]
+    fn rendered_files_are_named_by_the_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("guide.md")).unwrap();
+        let doc = "```mermaid\ngraph TD\n```\n\n```mermaid\n---\ntitle: Auth Flow\n---\ngraph TD\n  A-->B\n```\n";
+        let lines: Vec<&str> = doc.lines().collect();
+        let fence = &find_all_mermaid_fences(&lines)[1];
+        let render = |template: &str| {
+            let config = Config {
+                file_name_template: template.to_string(),
+                ..Config::default()
+            };
+            let mermaid_dir = ensure_mermaid_dir(dir.path(), &config).unwrap();
+            open_cache(&mermaid_dir, &config)
+                .unwrap()
+                .put(&cache_key(&fence.code, Backend::Mmdc, &config), "<svg></svg>")
+                .unwrap();
+            create_render_edit(&uri, doc, &lines, fence, &config, PositionEncoding::Utf16)
+                .map(|edit| edit.changes.unwrap()[&uri][0].new_text.clone())
+        };
+
+        let default = render(naming::DEFAULT_FILE_NAME_TEMPLATE).unwrap();
+        let hash = format!("{:016x}", code_hash(&fence.code));
+        assert!(default.contains(&format!("(.mermaid/guide_diagram_{hash}.svg)")), "{default}");
+        assert!(dir.path().join(format!(".mermaid/guide_diagram_{hash}.mmd")).exists());
+
+        let titled = render("{doc}-{index}-{title}").unwrap();
+        assert!(titled.contains("(.mermaid/guide-2-auth-flow.svg)"), "{titled}");
+        assert!(titled.contains("mermaid-source-file:.mermaid/guide-2-auth-flow.mmd"), "{titled}");
+
+        let err = render("../{doc}").unwrap_err();
+        assert!(matches!(err, ServerError::InvalidParams(_)), "{err}");
+        assert!(err.to_string().contains("path separator"), "{err}");
+        assert!(!dir.path().join("guide.svg").exists());
+    }
+
+    #[test]
+    fn single_line_inline_svg_is_one_block() {
+        let doc = "<!-- mermaid-source-file:.mermaid/doc.mmd -->\n<svg><g/></svg>\nText\n";
+        let lines: Vec<&str> = doc.lines().collect();
+        let blocks = find_all_rendered_blocks(&lines);
+
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].end_line, 1);
+    }
+
+    #[test]
+    fn reports_missing_svgs_and_sources_by_line() {
+        let (dir, uri) = rendered_fixture();
+        let doc = "# Doc\n\n\
+                   <!-- mermaid-source-file:.mermaid/doc.mmd -->\n\n![Mermaid Diagram](.mermaid/doc.svg)\n\n\
+                   <!-- mermaid-source-file:.mermaid/gone.mmd -->\n\n![Mermaid Diagram](.mermaid/gone.svg \"Gone\")\n\n\
+                   <!-- mermaid-source-file:.mermaid/doc.mmd -->\n\n![Inline](data:image/svg+xml;base64,PHN2Zy8+)\n";
+        let lines: Vec<&str> = doc.lines().collect();
+
+        let broken = broken_links(dir.path(), &lines);
+        assert_eq!(
+            broken,
+            vec![
+                BrokenLink { line: 5, kind: "svg", path: ".mermaid/doc.svg".to_string() },
+                BrokenLink { line: 7, kind: "source", path: ".mermaid/gone.mmd".to_string() },
+                BrokenLink { line: 9, kind: "svg", path: ".mermaid/gone.svg".to_string() },
+            ]
+        );
+
+        // Only the SVG with a surviving source is offered for re-rendering
+        let result = check_links(&uri, &lines, false, &Config::default()).unwrap();
+        assert_eq!(result["broken"].as_array().unwrap().len(), 3);
+        assert_eq!(result["rerender"]["command"], "mermaid.checkLinks");
+        assert_eq!(result["rerender"]["arguments"][1], true);
+        assert!(result["rerender"]["title"].as_str().unwrap().contains("1 missing"));
+
+        fs::write(dir.path().join(".mermaid/doc.svg"), "<svg/>").unwrap();
+        let result = check_links(&uri, &lines, false, &Config::default()).unwrap();
+        assert_eq!(result["broken"].as_array().unwrap().len(), 2);
+        assert!(result.get("rerender").is_none());
+    }
+
+    #[test]
+    fn classifies_positions_across_a_document() {
+        let doc = "# Title\n\
+                   ```mermaid\ngraph TD\n  A-->B\n```\n\
+                   Text\n\
+                   <!-- mermaid-source-file:.mermaid/a.mmd -->\n\n![Mermaid Diagram](.mermaid/a.svg)\n\
+                   ```js\nconsole.log(1)\n```\n\
+                   ```mermaid\ngraph LR\n```\n\
+                   <a id=\"diagram-b\"></a>\n\
+                   <!-- mermaid-source-file:.mermaid/b.mmd -->\n![Mermaid Diagram](.mermaid/b.svg)\n";
+        let expected = [
+            (0, PositionContext::Outside),
+            (1, PositionContext::InFence { index: 0 }),
+            (3, PositionContext::InFence { index: 0 }),
+            (4, PositionContext::InFence { index: 0 }),
+            (5, PositionContext::Outside),
+            (6, PositionContext::InRenderedBlock { index: 0 }),
+            (7, PositionContext::InRenderedBlock { index: 0 }),
+            (8, PositionContext::InRenderedBlock { index: 0 }),
+            (10, PositionContext::Outside),
+            (12, PositionContext::InFence { index: 1 }),
+            (15, PositionContext::InRenderedBlock { index: 1 }),
+            (17, PositionContext::InRenderedBlock { index: 1 }),
+            (18, PositionContext::Outside),
+        ];
+        for (line, context) in expected {
+            assert_eq!(classify_position(doc, line), context, "line {line}");
+        }
+    }
+
+    #[test]
+    fn output_dir_is_resolved_against_the_document() {
+        let base = Path::new("/work/docs");
+        assert_eq!(output_dir(base, &Config::default()), PathBuf::from("/work/docs/.mermaid"));
+
+        let config = Config {
+            output_dir: Some("../.mermaid".to_string()),
+            ..Config::default()
+        };
+        assert_eq!(output_dir(base, &config), PathBuf::from("/work/.mermaid"));
+
+        let config = Config {
+            output_dir: Some("/tmp/diagrams".to_string()),
+            ..Config::default()
+        };
+        assert_eq!(output_dir(base, &config), PathBuf::from("/tmp/diagrams"));
+    }
+
+    /// `<tmp>/content/docs` holding `doc.md`, `<tmp>/work/docs` linking to it,
+    /// and the document's URI through the link
+    #[cfg(unix)]
+    fn symlinked_document(doc: &str) -> (tempfile::TempDir, PathBuf, Url) {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("content/docs");
+        let link = dir.path().join("work/docs");
+        fs::create_dir_all(&real).unwrap();
+        fs::create_dir_all(link.parent().unwrap()).unwrap();
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+        fs::write(real.join("doc.md"), doc).unwrap();
+        (dir, link.clone(), Url::from_file_path(link.join("doc.md")).unwrap())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_documents_place_files_by_the_path_they_are_opened_with() {
+        let doc = "# Doc\n\n```mermaid\ngraph TD\n  A-->B\n```\n";
+        let (dir, link, uri) = symlinked_document(doc);
+        let config = Config {
+            output_dir: Some("../assets".to_string()),
+            ..Config::default()
+        };
+        let base_dir = doc_base_dir(&uri).unwrap();
+        assert_eq!(base_dir, link);
+        let mermaid_dir = ensure_mermaid_dir(&base_dir, &config).unwrap();
+        // Beside the link, not in its target's parent as `link/..` is to the OS
+        assert_eq!(mermaid_dir, dir.path().join("work/assets"));
+        assert!(!dir.path().join("content/assets").exists());
+
+        let code = "graph TD\n  A-->B";
+        open_cache(&mermaid_dir, &config).unwrap().put(&cache_key(code, Backend::Mmdc, &config), "<svg></svg>").unwrap();
+        let lines: Vec<&str> = doc.lines().collect();
+        let fence = find_all_mermaid_fences(&lines).remove(0);
+        let edit = create_render_edit(&uri, doc, &lines, &fence, &config, PositionEncoding::Utf16).unwrap();
+        let rendered = apply_line_edits(doc, &edit.changes.unwrap()[&uri]);
+        let rendered_lines: Vec<&str> = rendered.lines().collect();
+        let block = find_all_rendered_blocks(&rendered_lines).remove(0);
+        assert!(block.source_file.starts_with("../assets/"), "{}", block.source_file);
+        // Links are followed the same way they were written
+        assert!(broken_links(&base_dir, &rendered_lines).is_empty());
+        let restored = create_source_edit(&uri, &rendered, &rendered_lines, &block, PositionEncoding::Utf16).unwrap();
+        assert_eq!(apply_line_edits(&rendered, &restored.changes.unwrap()[&uri]), doc);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reverts_keep_files_linked_through_either_spelling_and_stay_in_the_output_dir() {
+        let doc = "# Doc\n\n```mermaid\ngraph TD\n  A-->B\n```\n";
+        let (dir, link, uri) = symlinked_document(doc);
+        let config = Config::default();
+        let mermaid_dir = ensure_mermaid_dir(&link, &config).unwrap();
+        let code = "graph TD\n  A-->B";
+        open_cache(&mermaid_dir, &config).unwrap().put(&cache_key(code, Backend::Mmdc, &config), "<svg></svg>").unwrap();
+        let lines: Vec<&str> = doc.lines().collect();
+        let fence = find_all_mermaid_fences(&lines).remove(0);
+        let render = |edit: &WorkspaceEdit| {
+            record_render(&uri, &lines, edit, &config);
+            apply_line_edits(doc, &edit.changes.as_ref().unwrap()[&uri])
+        };
+        let revert = |rendered: &str, documents: &HashMap<Url, String>| {
+            let rendered_lines: Vec<&str> = rendered.lines().collect();
+            let reverted = revert_last_render(&uri, rendered, &rendered_lines, documents, &config, PositionEncoding::Utf16);
+            reverted.unwrap().unwrap().1
+        };
+        let edit = create_render_edit(&uri, doc, &lines, &fence, &config, PositionEncoding::Utf16).unwrap();
+
+        // A copy opened by its real path links the same files
+        let rendered = render(&edit);
+        let copy = Url::from_file_path(dir.path().join("content/docs/copy.md")).unwrap();
+        let documents = HashMap::from([(uri.clone(), rendered.clone()), (copy, rendered.clone())]);
+        assert!(revert(&rendered, &documents).is_empty());
+        let rendered = render(&edit);
+        let files = revert(&rendered, &HashMap::from([(uri.clone(), rendered.clone())]));
+        assert_eq!(files.len(), 2, "{files:?}");
+        assert!(files.iter().all(|file| file.starts_with(&mermaid_dir)), "{files:?}");
+
+        // A journaled link out of the output directory is never deleted
+        let victim = dir.path().join("work/victim.svg");
+        fs::write(&victim, "<svg/>").unwrap();
+        let mut escaping = edit.clone();
+        for text_edit in escaping.changes.as_mut().unwrap().get_mut(&uri).unwrap() {
+            let image = find_all_rendered_blocks(&text_edit.new_text.lines().collect::<Vec<_>>())[0].image.clone().unwrap().1;
+            text_edit.new_text = text_edit.new_text.replace(&image, "../victim.svg");
+        }
+        let rendered = render(&escaping);
+        let files = revert(&rendered, &HashMap::from([(uri.clone(), rendered.clone())]));
+        assert_eq!(files.len(), 1, "{files:?}");
+        assert!(!files.contains(&victim));
+    }
+
+    #[test]
+    fn unavailable_backend_is_reported_on_the_fence_line() {
+        let doc = "```mermaid {backend=native}\ngraph TD\n  A-->B\n```\n\n```mermaid {backend=mmdc}\ngraph TD\n```\n";
+        let diagnostics = document_diagnostics(doc, None, PositionEncoding::Utf16, &Config::default());
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].range.start, Position::new(0, 0));
+        assert_eq!(diagnostics[0].range.end.line, 0);
+        assert_eq!(diagnostics[0].severity, Some(DiagnosticSeverity::WARNING));
+        assert!(diagnostics[0].message.contains("native"));
+
+        let lines: Vec<&str> = doc.lines().collect();
+        let fences = find_all_mermaid_fences(&lines);
+        assert_eq!(fences[0].info, "{backend=native}");
+        assert_eq!(fences[0].code, "graph TD\n  A-->B");
+    }
+
+    #[test]
+    fn document_diagnostics_follow_moved_fences() {
+        let failing = "graph TD\n  A-->";
+        let failure = ServerError::RenderFailed("Parse error".to_string())
+            .to_diagnostic(Range::default());
+        let failures = HashMap::from([(code_hash(failing), failure)]);
+
+        let doc = format!(
+            "# Title\n\nNew paragraph\n\n```mermaid\n{failing}\n```\n\n```mermaid\nflowchart LR\n  A[One] --> A[Two]\n```\n"
+        );
+        let diagnostics = document_diagnostics(
+            &doc,
+            Some(&failures),
+            PositionEncoding::Utf16,
+            &Config::default(),
+        );
+
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].range.start, Position::new(4, 0));
+        assert_eq!(diagnostics[0].range.end, Position::new(7, 3));
+        assert_eq!(diagnostics[0].severity, Some(DiagnosticSeverity::ERROR));
+        assert_eq!(diagnostics[1].range.start.line, 11);
+        assert_eq!(diagnostics[1].severity, Some(DiagnosticSeverity::WARNING));
+    }
+
+    #[test]
+    fn render_failures_point_at_the_reported_source_line() {
+        let failing = "---\ntitle: Flow\n---\n%%{init: {\"theme\": \"dark\"}}%%\ngraph TD\n  %% edges\n  A-->B\n  B-->";
+        // Mermaid counts from `graph TD`, without the comment: line 3 is `B-->`
+        let failure = ServerError::RenderFailed("Parse error on line 3:\n  B-->\n-----^".to_string())
+            .to_diagnostic(Range::default());
+        let failures = HashMap::from([(code_hash(failing), failure)]);
+
+        let doc = format!("# Title\n\n```mermaid\n{failing}\n```\n");
+        let diagnostics = document_diagnostics(&doc, Some(&failures), PositionEncoding::Utf16, &Config::default());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].range.start, Position::new(10, 0));
+        assert_eq!(diagnostics[0].range.end, Position::new(10, 6));
+    }
+
+    #[test]
+    fn placeholders_are_keyed_by_value_and_diagnosed_when_unresolved() {
+        let code = "graph TD\n  A[\"v{{VERSION}}\"]";
+        let mut config = Config::default();
+        config.variables.insert("VERSION".to_string(), "1.0".to_string());
+        let before = cache_key(code, Backend::Mmdc, &config);
+        config.variables.insert("VERSION".to_string(), "1.1".to_string());
+        assert_ne!(cache_key(code, Backend::Mmdc, &config), before);
+        assert_eq!(cache_key(code, Backend::Mmdc, &config), cache_key("graph TD\n  A[\"v1.1\"]", Backend::Mmdc, &config));
+
+        // The render failure the placeholder caused gives way to its diagnostic
+        let failure = variables::substitute(code, &Config::default()).unwrap_err().to_diagnostic(Range::default());
+        let failures = HashMap::from([(code_hash(code), failure)]);
+        let doc = format!("# Title\n\n```mermaid\n{code}\n```\n");
+        let diagnostics = document_diagnostics(&doc, Some(&failures), PositionEncoding::Utf16, &Config::default());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].range, Range::new(Position::new(4, 6), Position::new(4, 17)));
+        assert!(diagnostics[0].message.contains("`{{VERSION}}` on diagram line 2"), "{}", diagnostics[0].message);
+    }
+
+    #[test]
+    fn render_failures_are_not_shifted_by_the_preamble() {
+        let mut config = Config::default();
+        // A preamble mermaid parses, so the reported lines count it
+        config.set_option("preamble", serde_json::json!("graph TD\n  P-->Q")).unwrap();
+        let failing = "  A-->B\n  B-->";
+        let doc = format!("# Title\n\n```mermaid\n{failing}\n```\n");
+        let lines: Vec<&str> = doc.lines().collect();
+        let fence = &find_all_mermaid_fences(&lines)[0];
+
+        assert_eq!(failure_line(fence, "Parse error on line 4:", &config), Some(1));
+        assert_eq!(failure_line(fence, "Parse error on line 4:", &Config::default()), None);
+        // An error in the preamble is shown on the whole fence
+        assert_eq!(failure_line(fence, "Parse error on line 2:", &config), None);
+
+        let failure = ServerError::RenderFailed("Parse error on line 4:\n  B-->\n-----^".to_string())
+            .to_diagnostic(Range::default());
+        let failures = HashMap::from([(code_hash(failing), failure)]);
+        let diagnostics = document_diagnostics(&doc, Some(&failures), PositionEncoding::Utf16, &config);
+        assert_eq!(diagnostics[0].range.start, Position::new(4, 0));
+        assert_eq!(diagnostics[0].range.end, Position::new(4, 6));
+    }
+
+    const RELOCATE_DOC: &str = "# Title\n\n```mermaid\ngraph TD\n  A-->B\n```\n\n```mermaid\ngraph LR\n  C-->D\n```\n";
+
+    fn fence_edit_for(doc: &str, index: usize) -> FenceEdit {
+        let lines: Vec<&str> = doc.lines().collect();
+        let fence = &find_all_mermaid_fences(&lines)[index];
+        FenceEdit {
+            code_hash: code_hash(&fence.code),
+            start_line: fence.start_line,
+            new_text: format!("![Mermaid Diagram](.mermaid/{index}.svg)"),
+        }
+    }
+
+    #[test]
+    fn relocates_fences_that_moved_down_or_up() {
+        let edits = vec![fence_edit_for(RELOCATE_DOC, 0), fence_edit_for(RELOCATE_DOC, 1)];
+
+        let moved_down = format!("Intro\nMore intro\n{RELOCATE_DOC}");
+        let lines: Vec<&str> = moved_down.lines().collect();
+        let (fences, text_edits) =
+            relocate_fence_edits(&lines, &edits, PositionEncoding::Utf16).unwrap();
+        assert_eq!(
+            fences.iter().map(|f| f.start_line).collect::<Vec<_>>(),
+            vec![9, 4]
+        );
+        assert_eq!(text_edits[0].range.start, Position::new(9, 0));
+        assert_eq!(text_edits[0].range.end, Position::new(12, 3));
+        assert_eq!(text_edits[1].new_text, edits[0].new_text);
+
+        let moved_up = RELOCATE_DOC.replacen("# Title\n\n", "", 1);
+        let lines: Vec<&str> = moved_up.lines().collect();
+        let (fences, _) = relocate_fence_edits(&lines, &edits, PositionEncoding::Utf16).unwrap();
+        assert_eq!(
+            fences.iter().map(|f| f.start_line).collect::<Vec<_>>(),
+            vec![5, 0]
+        );
+    }
+
+    #[test]
+    fn relocation_aborts_when_fence_content_changed() {
+        let edits = vec![fence_edit_for(RELOCATE_DOC, 0), fence_edit_for(RELOCATE_DOC, 1)];
+        let edited = RELOCATE_DOC.replace("C-->D", "C-->E");
+        let lines: Vec<&str> = edited.lines().collect();
+
+        assert!(relocate_fence(&lines, edits[0].code_hash, 2).is_some());
+        assert!(relocate_fence(&lines, edits[1].code_hash, 7).is_none());
+        assert!(relocate_fence_edits(&lines, &edits, PositionEncoding::Utf16).is_none());
+    }
+
+    #[test]
+    fn relocation_prefers_the_nearest_identical_fence() {
+        let doc = "```mermaid\ngraph TD\n```\n\n```mermaid\ngraph TD\n```\n";
+        let lines: Vec<&str> = doc.lines().collect();
+        let hash = code_hash("graph TD");
+        assert_eq!(relocate_fence(&lines, hash, 0).unwrap().start_line, 0);
+        assert_eq!(relocate_fence(&lines, hash, 5).unwrap().start_line, 4);
+    }
+
+    #[test]
+    fn fence_edits_only_describe_whole_fence_replacements() {
+        let lines: Vec<&str> = RELOCATE_DOC.lines().collect();
+        let fence_edit = TextEdit::new(line_range(&lines, 7, 10, PositionEncoding::Utf16), "x".to_string());
+        let other_edit = TextEdit::new(line_range(&lines, 0, 0, PositionEncoding::Utf16), "y".to_string());
+
+        assert_eq!(fence_edits(&lines, std::slice::from_ref(&fence_edit)).len(), 1);
+        assert!(fence_edits(&lines, &[fence_edit, other_edit]).is_empty());
+    }
+
+    #[test]
+    fn rejected_stale_edit_is_retried_then_abandoned() {
+        let (server, client) = Connection::memory();
+        let uri = Url::parse("file:///tmp/retry.md").unwrap();
+        let mut state = ServerState {
+            documents: HashMap::new(),
+            versions: HashMap::new(),
+            client: ClientInfo {
+                apply_edit: true,
+                document_changes: true,
+                show_message_request: true,
+                ..Default::default()
+            },
+            config: Config::default(),
+            render_failures: HashMap::new(),
+            checked: HashMap::new(),
+            recency: Recency::default(),
+            published: Publisher::default(),
+            outgoing: OutgoingRequests::default(),
+            deferred: VecDeque::new(),
+            workspace_root: None,
+            rc_files: RcFiles::default(),
+        };
+        // The user typed two lines above the fence while the edit was in flight
+        state.documents.insert(uri.clone(), format!("typed\n\n{RELOCATE_DOC}"));
+        state.versions.insert(uri.clone(), 2);
+
+        let mut pending = PendingEdit {
+            uri: uri.clone(),
+            version: Some(1),
+            fences: vec![fence_edit_for(RELOCATE_DOC, 0)],
+            attempts: 0,
+            cleanup: Vec::new(),
+        };
+        let rejected = |id| Response::new_ok(id, ApplyWorkspaceEditResponse {
+            applied: false,
+            failure_reason: Some("version mismatch".to_string()),
+            failed_change: None,
+        });
+
+        for attempt in 1..=MAX_EDIT_RETRIES {
+            let sent = state.outgoing.request(
+                "workspace/applyEdit",
+                Value::Null,
+                Outgoing::Edit(pending.clone()),
+                CLIENT_REQUEST_TIMEOUT,
+            );
+            handle_response(&server, rejected(sent.id), &mut state).unwrap();
+
+            let req = match client.receiver.try_recv().unwrap() {
+                Message::Request(req) => req,
+                other => panic!("unexpected message: {other:?}"),
+            };
+            assert_eq!(req.method, "workspace/applyEdit");
+            let params: ApplyWorkspaceEditParams = serde_json::from_value(req.params).unwrap();
+            let Some(DocumentChanges::Edits(doc_edits)) = params.edit.document_changes else {
+                panic!("expected versioned document changes");
+            };
+            assert_eq!(doc_edits[0].text_document.version, Some(2));
+            match &doc_edits[0].edits[0] {
+                OneOf::Left(edit) => assert_eq!(edit.range.start, Position::new(4, 0)),
+                other => panic!("unexpected edit: {other:?}"),
+            }
+
+            pending = match state.outgoing.take(&req.id) {
+                Some(Outgoing::Edit(pending)) => pending,
+                _ => panic!("the retry isn't tracked"),
+            };
+            assert_eq!(pending.attempts, attempt);
+            // Pretend the user typed again before the retry landed
+            pending.version = Some(1);
+        }
+
+        let sent = state.outgoing.request("workspace/applyEdit", Value::Null, Outgoing::Edit(pending), CLIENT_REQUEST_TIMEOUT);
+        handle_response(&server, rejected(sent.id), &mut state).unwrap();
+        match client.receiver.try_recv().unwrap() {
+            Message::Notification(not) => assert_eq!(not.method, "window/showMessage"),
+            other => panic!("unexpected message: {other:?}"),
+        }
+        assert!(state.outgoing.is_empty());
+    }
+
+    #[test]
+    fn gitignore_entry_is_added_through_the_client_when_accepted() {
+        let (server, client) = Connection::memory();
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::create_dir_all(root.join("docs/.mermaid/.cache")).unwrap();
+        let mut state = ServerState {
+            documents: HashMap::new(),
+            versions: HashMap::new(),
+            client: ClientInfo {
+                apply_edit: true,
+                document_changes: true,
+                create_files: true,
+                show_message_request: true,
+                ..Default::default()
+            },
+            config: Config::default(),
+            render_failures: HashMap::new(),
+            checked: HashMap::new(),
+            recency: Recency::default(),
+            published: Publisher::default(),
+            outgoing: OutgoingRequests::default(),
+            deferred: VecDeque::new(),
+            workspace_root: Some(root.clone()),
+            rc_files: RcFiles::default(),
+        };
+        let suggestion = gitignore::suggest(&root, &root.join("docs/.mermaid"), &state.config).unwrap();
+        let answer = |id, title: &str| {
+            Response::new_ok(id, Some(MessageActionItem {
+                title: title.to_string(),
+                properties: HashMap::new(),
+            }))
+        };
+        let prompt = |state: &mut ServerState| {
+            offer_gitignore_entry(&server, state, suggestion.clone()).unwrap();
+            match client.receiver.try_recv().unwrap() {
+                Message::Request(req) if req.method == "window/showMessageRequest" => req.id,
+                other => panic!("unexpected message: {other:?}"),
+            }
+        };
+
+        // Declining leaves .gitignore alone
+        let id = prompt(&mut state);
+        handle_response(&server, answer(id, gitignore::Answer::Skip.label()), &mut state).unwrap();
+        assert!(client.receiver.try_recv().is_err());
+
+        // Accepting creates the missing file and appends the entry, as one undoable edit
+        let id = prompt(&mut state);
+        handle_response(&server, answer(id, gitignore::Answer::Add.label()), &mut state).unwrap();
+        let req = match client.receiver.try_recv().unwrap() {
+            Message::Request(req) => req,
+            other => panic!("unexpected message: {other:?}"),
+        };
+        assert_eq!(req.method, "workspace/applyEdit");
+        let params: ApplyWorkspaceEditParams = serde_json::from_value(req.params).unwrap();
+        let Some(DocumentChanges::Operations(operations)) = params.edit.document_changes else {
+            panic!("expected document change operations");
+        };
+        let gitignore_uri = Url::from_file_path(root.join(".gitignore")).unwrap();
+        assert!(matches!(
+            &operations[0],
+            DocumentChangeOperation::Op(ResourceOp::Create(create)) if create.uri == gitignore_uri
+        ));
+        let DocumentChangeOperation::Edit(edit) = &operations[1] else {
+            panic!("expected a text edit");
+        };
+        assert_eq!(
+            edit.edits,
+            vec![OneOf::Left(TextEdit::new(
+                Range::default(),
+                "# Mermaid Preview render cache\n**/.mermaid/.cache/\n".to_string()
+            ))]
+        );
+        assert!(!root.join(".gitignore").exists());
+
+        // Without applyEdit support the entry is written directly
+        fs::write(root.join(".gitignore"), "target").unwrap();
+        state.client.apply_edit = false;
+        let id = prompt(&mut state);
+        handle_response(&server, answer(id, gitignore::Answer::Add.label()), &mut state).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join(".gitignore")).unwrap(),
+            "target\n# Mermaid Preview render cache\n**/.mermaid/.cache/\n"
+        );
+        assert!(gitignore::is_ignored(&root, &suggestion.dir));
+    }
+
+    #[test]
+    fn inline_markdown_image_is_a_data_uri() {
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 10 10"><text>図</text></svg>"#;
+        let markdown = inline_markdown_image(svg);
+
+        let uri = markdown
+            .strip_prefix("![Mermaid Diagram](")
+            .and_then(|rest| rest.strip_suffix(')'))
+            .expect("markdown image");
+        let payload = uri
+            .strip_prefix("data:image/svg+xml;base64,")
+            .expect("svg data URI");
+        assert!(!payload.contains(char::is_whitespace));
+        assert_eq!(BASE64_STANDARD.decode(payload).unwrap(), svg.as_bytes());
+    }
+
+    #[test]
+    fn copy_as_markdown_rejects_lines_outside_fences() {
+        let lines = vec!["# Title", "```mermaid", "graph TD", "```"];
+        let err = copy_as_markdown(&lines, Some(&serde_json::json!(0)), &Config::default())
+            .unwrap_err();
+        assert!(matches!(err, ServerError::InvalidParams(_)));
+        let err = copy_as_markdown(&lines[..1], None, &Config::default()).unwrap_err();
+        assert!(matches!(err, ServerError::InvalidParams(_)));
+    }
+
+    #[test]
+    fn comparison_sources_from_indices_or_code() {
+        let doc = "```mermaid\ngraph TD\n```\n\n```mermaid\ngraph LR\n```\n";
+        let lines: Vec<&str> = doc.lines().collect();
+
+        assert_eq!(comparison_source(&lines, &serde_json::json!(1)).unwrap(), "graph LR");
+        assert_eq!(
+            comparison_source(&lines, &serde_json::json!("pie\n  \"A\": 1")).unwrap(),
+            "pie\n  \"A\": 1"
+        );
+        assert!(comparison_source(&lines, &serde_json::json!(2)).is_err());
+        assert!(comparison_source(&lines, &serde_json::json!(true)).is_err());
+    }
+
+    #[test]
+    fn fence_range_counts_non_ascii_per_encoding() {
+        let doc = "```mermaid\ngraph TD\n  A[図 🎉] --> B\n```\n";
+        let lines: Vec<&str> = doc.lines().collect();
+        let fence = &find_all_mermaid_fences(&lines)[0];
+
+        let range = line_range(&lines, fence.start_line, 2, PositionEncoding::Utf8);
+        assert_eq!(range.start, Position::new(0, 0));
+        assert_eq!(range.end, Position::new(2, 19));
+
+        let range = line_range(&lines, fence.start_line, 2, PositionEncoding::Utf16);
+        assert_eq!(range.end, Position::new(2, 15));
+    }
+
+    #[test]
+    fn source_edit_range_respects_encoding() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join(".mermaid")).unwrap();
+        fs::write(dir.path().join(".mermaid/図.mmd"), "graph TD\n  A[🎉]").unwrap();
+        let uri = Url::from_file_path(dir.path().join("図.md")).unwrap();
+        let doc = "<!-- mermaid-source-file:.mermaid/図.mmd -->\n\n![図 🎉](.mermaid/図.svg)\n";
+        let lines: Vec<&str> = doc.lines().collect();
+        let block = &find_all_rendered_blocks(&lines)[0];
+
+        for (encoding, end) in [(PositionEncoding::Utf8, 29), (PositionEncoding::Utf16, 23)] {
+            let edit = create_source_edit(&uri, doc, &lines, block, encoding).unwrap();
+            let text_edit = &edit.changes.unwrap()[&uri][0];
+            assert_eq!(text_edit.range.end, Position::new(2, end));
+            assert_eq!(text_edit.new_text, "```mermaid\ngraph TD\n  A[🎉]\n```");
+        }
+    }
+
+    #[test]
+    fn anchors_belong_to_their_rendered_block() {
+        let (_dir, uri) = rendered_fixture();
+        let doc = format!("# Intro\n<a id=\"diagram-graph\"></a>\n{RENDERED_DOC}");
+        let lines: Vec<&str> = doc.lines().collect();
+        let blocks = find_all_rendered_blocks(&lines);
+        assert_eq!((blocks[0].start_line, blocks[0].comment_line), (1, 2));
+
+        // Restoring removes the anchor along with the image
+        let PositionContext::InRenderedBlock { index } = classify_position(&doc, 1) else {
+            panic!("line 1 is not in a rendered block");
+        };
+        let block = &find_all_rendered_blocks(&lines)[index];
+        let edit = create_source_edit(&uri, &doc, &lines, block, PositionEncoding::Utf16).unwrap();
+        let text_edit = &edit.changes.unwrap()[&uri][0];
+        assert_eq!(text_edit.range.start, Position::new(1, 0));
+        assert_eq!(text_edit.range.end.line, 4);
+    }
+
+    #[test]
+    fn rendered_blocks_inside_details_include_the_wrapper() {
+        let (_dir, uri) = rendered_fixture();
+        let doc = format!(
+            "# Intro\n\n<details>\n<summary>Flow</summary>\n\n{RENDERED_DOC}\n</details>\n\nAfter\n"
+        );
+        let lines: Vec<&str> = doc.lines().collect();
+        let blocks = find_all_rendered_blocks(&lines);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!((blocks[0].start_line, blocks[0].comment_line, blocks[0].end_line), (2, 5, 9));
+
+        let PositionContext::InRenderedBlock { index } = classify_position(&doc, 7) else {
+            panic!("line 7 is not in a rendered block");
+        };
+        let block = &find_all_rendered_blocks(&lines)[index];
+        let edit = create_source_edit(&uri, &doc, &lines, block, PositionEncoding::Utf16).unwrap();
+        let text_edit = &edit.changes.unwrap()[&uri][0];
+        assert_eq!(text_edit.range, Range::new(Position::new(2, 0), Position::new(9, 10)));
+        assert_eq!(text_edit.new_text, "```mermaid\ngraph TD\n  A-->B\n```");
+    }
+
+    #[test]
+    fn details_with_other_content_are_left_alone() {
+        let one_line = format!("<details open><summary>Flow</summary>\n{RENDERED_DOC}</details>\n");
+        let lines: Vec<&str> = one_line.lines().collect();
+        let block = &find_all_rendered_blocks(&lines)[0];
+        assert_eq!((block.start_line, block.end_line), (0, 4));
+
+        let extra = format!("<details>\n\nSome notes\n\n{RENDERED_DOC}</details>\n");
+        let lines: Vec<&str> = extra.lines().collect();
+        let block = &find_all_rendered_blocks(&lines)[0];
+        assert_eq!((block.start_line, block.end_line), (4, 6));
+
+        let unclosed = format!("<details>\n{RENDERED_DOC}\nMore text\n");
+        let lines: Vec<&str> = unclosed.lines().collect();
+        let block = &find_all_rendered_blocks(&lines)[0];
+        assert_eq!((block.start_line, block.end_line), (1, 3));
+    }
+
+    #[test]
+    fn fence_anchors_skip_existing_ids_and_follow_document_order() {
+        let doc = "<a id=\"diagram-auth\"></a>\n\
+                   <!-- mermaid-source-file:.mermaid/a.mmd -->\n\n![Mermaid Diagram](.mermaid/a.svg)\n\n\
+                   ```mermaid\n---\ntitle: Auth\n---\ngraph TD\n```\n\n\
+                   ```mermaid\ngraph TD\n  A-->B\n```\n";
+        let lines: Vec<&str> = doc.lines().collect();
+        assert!(fence_anchors(&lines, &Config::default()).is_empty());
+
+        let config = Config {
+            diagram_anchors: true,
+            ..Config::default()
+        };
+        let anchors = fence_anchors(&lines, &config);
+        assert_eq!(anchors[&5], "diagram-auth-2");
+        assert_eq!(anchors[&12], "diagram-graph");
+    }
+
+    fn many_fences(count: usize) -> String {
+        let mut doc = String::from("# Changelog\n");
+        for i in 0..count {
+            doc.push_str(&format!("\n```mermaid\ngraph TD\n  A{i}-->B{i}\n```\n"));
+        }
+        doc
+    }
+
+    fn render_action_at(uri: &Url, doc: &str) -> CodeAction {
+        match code_actions(uri, doc, 1, &Config::default(), PositionEncoding::Utf16).remove(0) {
+            CodeActionOrCommand::CodeAction(action) => action,
+            other => panic!("unexpected action: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn render_action_title_reflects_cache_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        let code = "graph TD\n  Cached-->Title";
+        let doc = format!("```mermaid\n{code}\n```\n");
+        let config = Config::default();
+
+        let uncached = render_action_at(&uri, &doc);
+        assert_ne!(uncached.title, text(Text::RenderDiagramCached));
+        if let Some(reason) = render::backend_unavailable(Backend::Mmdc, &config) {
+            assert_eq!(uncached.title, text(Text::RenderDiagramUnavailable));
+            assert_eq!(uncached.disabled.unwrap().reason, reason);
+            assert!(uncached.edit.is_none());
+        }
+
+        let mermaid_dir = ensure_mermaid_dir(dir.path(), &config).unwrap();
+        open_cache(&mermaid_dir, &config)
+            .unwrap()
+            .put(&cache_key(code, Backend::Mmdc, &config), "<svg></svg>")
+            .unwrap();
+
+        let cached = render_action_at(&uri, &doc);
+        assert_eq!(cached.title, text(Text::RenderDiagramCached));
+        assert!(cached.disabled.is_none());
+        assert!(cached.edit.is_some());
+    }
+
+    /// The only edit of the action titled `title` at `line`, applied to `doc`
+    fn apply_action(uri: &Url, doc: &str, line: usize, title: Text) -> String {
+        let actions = code_actions(uri, doc, line, &Config::default(), PositionEncoding::Utf16);
+        let action = actions
+            .iter()
+            .find_map(|action| match action {
+                CodeActionOrCommand::CodeAction(a) if a.title == text(title) => Some(a),
+                _ => None,
+            })
+            .unwrap_or_else(|| panic!("no {title:?} action in {actions:?}"));
+        let edits = &action.edit.as_ref().unwrap().changes.as_ref().unwrap()[uri];
+        assert_eq!(edits.len(), 1);
+        apply_line_edit(doc, &edits[0])
+    }
+
+    #[test]
+    fn ignore_toggle_adds_and_removes_the_comment() {
+        let uri = Url::parse("file:///tmp/doc.md").unwrap();
+        let doc = "# Doc\n\n```mermaid\ngraph TD\n  A-->\n```\n";
+
+        let ignored = apply_action(&uri, doc, 3, Text::IgnoreDiagram);
+        assert_eq!(ignored, "# Doc\n\n<!-- mermaid-ignore -->\n```mermaid\ngraph TD\n  A-->\n```\n");
+        let lines: Vec<&str> = ignored.lines().collect();
+        assert!(find_all_mermaid_fences(&lines)[0].ignored);
+
+        // Only the toggle is offered on an ignored fence
+        let actions = code_actions(&uri, &ignored, 4, &Config::default(), PositionEncoding::Utf16);
+        assert_eq!(actions.len(), 1);
+        assert_eq!(apply_action(&uri, &ignored, 4, Text::UnignoreDiagram), doc);
+    }
+
+    #[test]
+    fn ignore_toggle_removes_the_attribute() {
+        let uri = Url::parse("file:///tmp/doc.md").unwrap();
+        let doc = "- item\n  ```mermaid {backend=mmdc ignore}\n  graph TD\n  ```\n";
+        let lines: Vec<&str> = doc.lines().collect();
+        assert!(find_all_mermaid_fences(&lines)[0].ignored);
+
+        let unignored = apply_action(&uri, doc, 2, Text::UnignoreDiagram);
+        assert_eq!(unignored, "- item\n  ```mermaid {backend=mmdc}\n  graph TD\n  ```\n");
+        assert_eq!(
+            apply_action(&uri, "```mermaid {ignore}\ngraph TD\n```\n", 1, Text::UnignoreDiagram),
+            "```mermaid\ngraph TD\n```\n"
+        );
+        // Both markers at once are removed together
+        assert_eq!(
+            apply_action(&uri, "<!-- mermaid-ignore -->\n```mermaid {ignore}\ngraph TD\n```\n", 2, Text::UnignoreDiagram),
+            "```mermaid\ngraph TD\n```\n"
+        );
+    }
+
+    #[test]
+    fn ignored_fences_are_not_checked_or_rendered() {
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        let config = Config::default();
+        let doc = "<!-- mermaid-ignore -->\n```mermaid {backend=native}\ngraph TD\n```\n\n```mermaid {ignore backend=native}\ngraph LR\n```\n";
+        assert!(document_diagnostics(doc, None, PositionEncoding::Utf16, &config).is_empty());
+        let checked = doc.replace("<!-- mermaid-ignore -->\n", "");
+        assert_eq!(document_diagnostics(&checked, None, PositionEncoding::Utf16, &config).len(), 1);
+
+        let lines: Vec<&str> = doc.lines().collect();
+        let (edit, failures) = create_render_all_edit(&uri, &lines, "\n", &config, PositionEncoding::Utf16, |_, _| true).unwrap();
+        assert!(edit.is_none() && failures.is_empty());
+        assert!(!dir.path().join(".mermaid").exists());
+        // Nothing left to render, so "Render All" isn't offered
+        assert!(code_actions(&uri, doc, 8, &config, PositionEncoding::Utf16).is_empty());
+    }
+
+    #[test]
+    fn quick_fixes_straighten_quotes_and_add_headers() {
+        let uri = Url::parse("file:///tmp/doc.md").unwrap();
+        let doc = "# Doc\n\n```mermaid\ngraph TD\n  A[\u{201C}Start\u{201D}] --> B\n```\n";
+        assert_eq!(
+            apply_action(&uri, doc, 4, Text::FixSmartQuotes),
+            "# Doc\n\n```mermaid\ngraph TD\n  A[\"Start\"] --> B\n```\n"
+        );
+
+        let headerless = "```mermaid\n  A --> B\n  B -> C\n```\n";
+        let fixed = apply_action(&uri, headerless, 1, Text::AddFlowchartHeader);
+        assert_eq!(fixed, "```mermaid\n  flowchart TD\n  A --> B\n  B -> C\n```\n");
+        // With the header in place the arrow fix is offered too
+        assert_eq!(
+            apply_action(&uri, &fixed, 1, Text::FixFlowchartArrows),
+            "```mermaid\n  flowchart TD\n  A --> B\n  B --> C\n```\n"
+        );
+
+        let clean = "```mermaid\ngraph TD\n  A --> B\n```\n";
+        let actions = code_actions(&uri, clean, 1, &Config::default(), PositionEncoding::Utf16);
+        let fixes = [Text::FixSmartQuotes, Text::FixFlowchartArrows, Text::AddFlowchartHeader].map(text);
+        assert!(!actions.iter().any(|action| matches!(
+            action,
+            CodeActionOrCommand::CodeAction(a) if fixes.contains(&a.title.as_str())
+        )));
+    }
+
+    #[test]
+    fn split_subgraph_action_appends_an_anchored_fence() {
+        let uri = Url::parse("file:///tmp/doc.md").unwrap();
+        let doc = "# Doc\n\n```mermaid\ngraph TD\n  A --> B\n  subgraph api [API]\n    B --> C\n  end\n```\n";
+        let actions = code_actions(&uri, doc, 6, &Config::default(), PositionEncoding::Utf16);
+        let split = actions
+            .iter()
+            .find_map(|action| match action {
+                CodeActionOrCommand::CodeAction(a) if a.title == text(Text::SplitSubgraph) => Some(a),
+                _ => None,
+            })
+            .expect("split action");
+
+        let edit = &split.edit.as_ref().unwrap().changes.as_ref().unwrap()[&uri][0];
+        let result = apply_line_edit(doc, edit);
+        assert_eq!(
+            result,
+            "# Doc\n\n```mermaid\ngraph TD\n  A --> api\n  api[\"API\"]\n  click api href \"#diagram-api\"\n```\n\n\
+             <a id=\"diagram-api\"></a>\n```mermaid\ngraph TD\n  accTitle: API\n  B --> C\n```\n"
+        );
+
+        // Rendering with diagramAnchors keeps the anchor the link points at
+        let lines: Vec<&str> = result.lines().collect();
+        let config = Config {
+            diagram_anchors: true,
+            ..Config::default()
+        };
+        let anchors = fence_anchors(&lines, &config);
+        assert_eq!(anchors.get(&2).map(String::as_str), Some("diagram-graph"));
+        assert!(!anchors.contains_key(&10));
+
+        // No split outside a subgraph
+        let actions = code_actions(&uri, doc, 4, &Config::default(), PositionEncoding::Utf16);
+        assert!(actions.iter().all(|a| !matches!(a, CodeActionOrCommand::CodeAction(a) if a.title == text(Text::SplitSubgraph))));
+    }
+
+    #[test]
+    fn action_titles_follow_the_locale() {
+        let uri = Url::parse("file:///tmp/doc.md").unwrap();
+        let doc = "```mermaid\ngraph TD\n```\n";
+        let titles = || -> Vec<String> {
+            code_actions(&uri, doc, 1, &Config::default(), PositionEncoding::Utf16)
+                .into_iter()
+                .map(|action| match action {
+                    CodeActionOrCommand::CodeAction(action) => action.title,
+                    CodeActionOrCommand::Command(command) => command.title,
+                })
+                .collect()
+        };
+        assert!(titles().contains(&"Ignore Mermaid Diagram".to_string()), "{:?}", titles());
+
+        i18n::set_locale(i18n::Locale::from_tag("ja-JP"));
+        let japanese = titles();
+        i18n::set_locale(i18n::Locale::En);
+        assert!(japanese.contains(&"Mermaid 図を無視".to_string()), "{japanese:?}");
+        assert!(japanese.iter().all(|title| !title.contains("Ignore")), "{japanese:?}");
+    }
+
+    #[test]
+    fn mismatched_rendered_blocks_are_flagged_and_realigned() {
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        let config = Config::default();
+        let code = "graph TD\n  A-->B";
+        let mermaid_dir = ensure_mermaid_dir(dir.path(), &config).unwrap();
+        fs::write(mermaid_dir.join("doc_diagram_a.mmd"), code).unwrap();
+        open_cache(&mermaid_dir, &config).unwrap().put(&cache_key(code, Backend::Mmdc, &config), "<svg></svg>").unwrap();
+
+        // The comment was edited to another diagram than the image shows
+        let doc = "# Doc\n\n<!-- mermaid-source-file:.mermaid/doc_diagram_a.mmd -->\n\n![Flow](.mermaid/doc_diagram_b.svg)\n";
+        let diagnostics = document_diagnostics(doc, None, PositionEncoding::Utf16, &config);
+        assert_eq!(diagnostics.len(), 1, "{diagnostics:?}");
+        assert_eq!(diagnostics[0].range.start.line, 4);
+        assert_eq!(
+            diagnostics[0].code,
+            Some(NumberOrString::String(diagnostics::MISMATCHED_BLOCK_CHECK.to_string()))
+        );
+
+        let realigned = apply_action(&uri, doc, 4, Text::RealignRenderedBlock);
+        assert!(realigned.contains("\n![Flow](.mermaid/doc_diagram_a.svg)\n"), "{realigned}");
+        assert_eq!(fs::read_to_string(mermaid_dir.join("doc_diagram_a.svg")).unwrap(), accessibility::describe("<svg></svg>", code));
+        assert!(document_diagnostics(&realigned, None, PositionEncoding::Utf16, &config).is_empty());
+        let actions = code_actions(&uri, &realigned, 4, &config, PositionEncoding::Utf16);
+        assert!(actions.iter().all(|action| match action {
+            CodeActionOrCommand::CodeAction(a) => a.title != text(Text::RealignRenderedBlock),
+            CodeActionOrCommand::Command(_) => true,
+        }));
+
+        // Remote images aren't this plugin's files
+        let remote = "<!-- mermaid-source-file:.mermaid/doc_diagram_a.mmd -->\n\n![Flow](https://example.com/x.svg)\n";
+        assert!(document_diagnostics(remote, None, PositionEncoding::Utf16, &config).is_empty());
+    }
+
+    #[test]
+    fn code_actions_stay_cheap_with_many_fences() {
+        let doc = many_fences(300);
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("changelog.md")).unwrap();
+
+        // One lazy command, rendering nothing and touching no files up front
+        let actions = code_actions(&uri, &doc, 0, &Config::default(), PositionEncoding::Utf16);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+        assert_eq!(actions.len(), 1);
+        match &actions[0] {
+            CodeActionOrCommand::CodeAction(action) => {
+                assert!(action.edit.is_none());
+                let command = action.command.as_ref().unwrap();
+                assert_eq!(command.command, "mermaid.renderAllLightweight");
+                assert_eq!(action.title, "Render All Mermaid Diagrams (300)");
+            }
+            other => panic!("unexpected action: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn format_all_normalizes_every_supported_fence() {
+        let uri = Url::parse("file:///tmp/doc.md").unwrap();
+        let doc = "# Doc\n\n```mermaid\ngraph TD\nA-->B\n```\n\n\
+                   - item\n  ```mermaid\n  flowchart LR\n     B==>C\n  ```\n\n\
+                   ```mermaid\nsequenceDiagram\n  A->>B:  hi\n```\n\n\
+                   ```mermaid {ignore}\ngraph TD\nX-->Y\n```\n";
+        let lines: Vec<&str> = doc.lines().collect();
+        let edit = create_format_all_edit(&uri, &lines, PositionEncoding::Utf16).unwrap();
+        let edits = &edit.changes.as_ref().unwrap()[&uri];
+        assert_eq!(edits.len(), 2);
+        let formatted = apply_line_edits(doc, edits);
+        assert_eq!(
+            formatted,
+            "# Doc\n\n```mermaid\ngraph TD\n    A --> B\n```\n\n\
+             - item\n  ```mermaid\n  flowchart LR\n      B ==> C\n  ```\n\n\
+             ```mermaid\nsequenceDiagram\n  A->>B:  hi\n```\n\n\
+             ```mermaid {ignore}\ngraph TD\nX-->Y\n```\n"
+        );
+        // Nothing left to change
+        let lines: Vec<&str> = formatted.lines().collect();
+        assert!(create_format_all_edit(&uri, &lines, PositionEncoding::Utf16).is_none());
+    }
+
+    #[test]
+    fn bulk_actions_need_more_than_the_block_at_the_cursor() {
+        let uri = Url::parse("file:///tmp/doc.md").unwrap();
+        let fence = "```mermaid\ngraph TD\n```\n";
+        let block = "<!-- mermaid-source-file:.mermaid/a.mmd -->\n\n![Mermaid Diagram](.mermaid/a.svg)\n";
+        let bulk_titles = |doc: &str, line: usize| -> Vec<String> {
+            code_actions(&uri, doc, line, &Config::default(), PositionEncoding::Utf16)
+                .into_iter()
+                .filter_map(|action| match action {
+                    CodeActionOrCommand::CodeAction(a) if a.edit.is_none() && a.command.is_some() => Some(a.title),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // (fences, blocks, cursor line, expected bulk titles); line 0 is prose
+        let cases: &[(usize, usize, usize, &[&str])] = &[
+            (0, 0, 0, &[]),
+            (1, 0, 0, &["Render All Mermaid Diagrams (1)"]),
+            (1, 0, 2, &[]),
+            (3, 0, 2, &["Render All Mermaid Diagrams (3)"]),
+            (0, 1, 0, &["Edit All Mermaid Sources (1)"]),
+            (0, 1, 2, &[]),
+            (0, 3, 2, &["Edit All Mermaid Sources (3)"]),
+            (1, 1, 2, &["Edit All Mermaid Sources (1)"]),
+            (2, 1, 2, &["Render All Mermaid Diagrams (2)", "Edit All Mermaid Sources (1)"]),
+            (1, 2, 2, &["Edit All Mermaid Sources (2)"]),
+        ];
+        for &(fences, blocks, line, expected) in cases {
+            let doc = format!("# Doc\n\n{}{}", fence.repeat(fences), block.repeat(blocks));
+            assert_eq!(bulk_titles(&doc, line), expected, "{fences} fences, {blocks} blocks, line {line}");
+        }
+    }
+
+    #[test]
+    fn renders_in_chunks_in_reverse_order() {
+        let doc = many_fences(500);
+        let lines: Vec<&str> = doc.lines().collect();
+        let fences = find_all_mermaid_fences(&lines);
+
+        let mut reports = Vec::new();
+        let edits = render_in_chunks(
+            &fences,
+            64,
+            |done, total| {
+                reports.push((done, total));
+                true
+            },
+            |fence| {
+                Some(TextEdit::new(
+                    Range::new(Position::new(fence.start_line as u32, 0), Position::new(fence.end_line as u32, 0)),
+                    String::new(),
+                ))
+            },
+        )
+        .unwrap();
+
+        assert_eq!(edits.len(), 500);
+        assert!(edits
+            .windows(2)
+            .all(|w| w[0].range.start.line > w[1].range.start.line));
+        assert_eq!(reports.len(), 8);
+        assert_eq!(reports.last(), Some(&(500, 500)));
+    }
+
+    #[test]
+    fn render_in_chunks_stops_when_cancelled() {
+        let doc = many_fences(50);
+        let lines: Vec<&str> = doc.lines().collect();
+        let fences = find_all_mermaid_fences(&lines);
+
+        let mut rendered = 0;
+        let mut chunks = 0;
+        let result = render_in_chunks(
+            &fences,
+            10,
+            |_, _| {
+                chunks += 1;
+                chunks < 2
+            },
+            |_| {
+                rendered += 1;
+                None
+            },
+        );
+
+        assert!(result.is_none());
+        assert_eq!(rendered, 20);
+    }
+
+    #[test]
+    fn code_hash_deterministic() {
+        let code = "graph TD\n  A --> B";
+        assert_eq!(code_hash(code), code_hash(code));
+    }
+
+    #[test]
+    fn failures_are_counted_per_fence() {
+        let doc = "```mermaid\ngraph TD\n  A-->\n```\n\n```mermaid\ngraph TD\n  A-->\n```\n\n```mermaid\ngraph LR\n```\n";
+        let lines: Vec<&str> = doc.lines().collect();
+        let fences = find_all_mermaid_fences(&lines);
+        let failures = HashMap::from([(code_hash(&fences[0].code), Diagnostic::default())]);
+        assert_eq!(failed_fences(&fences, &failures), 2);
+        assert_eq!(failed_fences(&fences, &HashMap::new()), 0);
+    }
 
-    // Check cache
-    let cache_dir = mermaid_dir.join(".cache");
-    let _ = fs::create_dir_all(&cache_dir);
-    let cache_path = cache_dir.join(format!("mermaid_{hash}.svg"));
+    #[test]
+    fn code_hash_different_for_different_code() {
+        assert_ne!(code_hash("graph TD"), code_hash("graph LR"));
+    }
 
-    let svg = if cache_path.is_file() {
-        info!("Using cached SVG for hash {hash}");
-        fs::read_to_string(&cache_path).ok()?
-    } else {
-        info!("Rendering mermaid diagram...");
-        match render::render_mermaid(&fence.code) {
-            Ok(svg) => {
-                // Save to cache
-                let _ = fs::write(&cache_path, &svg);
-                svg
+    // ─── Protocol flow over an in-memory connection ─────────────────────────
+
+    /// Start a server on an in-memory connection and complete the handshake
+    fn start_server(capabilities: ClientCapabilities) -> (Connection, std::thread::JoinHandle<()>) {
+        let (server, client) = Connection::memory();
+        let handle = std::thread::spawn(move || serve(server).unwrap());
+
+        #[allow(deprecated)]
+        let params = InitializeParams {
+            capabilities,
+            ..Default::default()
+        };
+        let req = Request::new(1.into(), "initialize".to_string(), params);
+        client.sender.send(Message::Request(req)).unwrap();
+        match client.receiver.recv().unwrap() {
+            Message::Response(resp) => assert!(resp.error.is_none()),
+            other => panic!("unexpected message: {other:?}"),
+        }
+        let initialized = Notification::new("initialized".to_string(), serde_json::json!({}));
+        client.sender.send(Message::Notification(initialized)).unwrap();
+
+        (client, handle)
+    }
+
+    fn stop_server(client: Connection, handle: std::thread::JoinHandle<()>) {
+        let req = Request::new(999.into(), "shutdown".to_string(), Value::Null);
+        client.sender.send(Message::Request(req)).unwrap();
+        while let Ok(msg) = client.receiver.recv() {
+            if matches!(msg, Message::Response(ref r) if r.id == 999.into()) {
+                break;
             }
-            Err(e) => {
-                error!("Rendering failed: {e}");
-                return None;
+        }
+        let exit = Notification::new("exit".to_string(), Value::Null);
+        client.sender.send(Message::Notification(exit)).unwrap();
+        handle.join().unwrap();
+    }
+
+    fn open_document(client: &Connection, uri: &Url, text: &str) {
+        let params = DidOpenTextDocumentParams {
+            text_document: TextDocumentItem::new(uri.clone(), "markdown".to_string(), 1, text.to_string()),
+        };
+        let not = Notification::new("textDocument/didOpen".to_string(), params);
+        client.sender.send(Message::Notification(not)).unwrap();
+    }
+
+    /// Execute a command and collect every message up to and including its response
+    fn execute_command(client: &Connection, command: &str, uri: &Url) -> Vec<Message> {
+        execute_command_with(client, command, uri, &[])
+    }
+
+    /// [`execute_command`] with arguments after the document URI
+    fn execute_command_with(client: &Connection, command: &str, uri: &Url, arguments: &[Value]) -> Vec<Message> {
+        let mut all_arguments = vec![serde_json::to_value(uri).unwrap()];
+        all_arguments.extend_from_slice(arguments);
+        let params = ExecuteCommandParams {
+            command: command.to_string(),
+            arguments: all_arguments,
+            work_done_progress_params: Default::default(),
+        };
+        let req = Request::new(2.into(), "workspace/executeCommand".to_string(), params);
+        client.sender.send(Message::Request(req)).unwrap();
+
+        let mut received = Vec::new();
+        loop {
+            let msg = client
+                .receiver
+                .recv_timeout(std::time::Duration::from_secs(5))
+                .unwrap();
+            let done = matches!(msg, Message::Response(ref r) if r.id == 2.into());
+            received.push(msg);
+            if done {
+                return received;
             }
         }
-    };
+    }
 
-    // Generate unique file names
-    let timestamp = Local::now().format("%Y%m%d_%H%M%S");
-    let svg_filename = format!("{doc_name}_diagram_{timestamp}.svg");
-    let mmd_filename = format!("{doc_name}_{timestamp}.mmd");
+    #[test]
+    fn revert_last_render_restores_fences_and_removes_unlinked_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        let flow = "graph TD\n  A-->B";
+        let doc = format!("# Doc\n\n```mermaid\n{flow}\n```\n\nText\n\n```mermaid\n{flow}\n```\n\n```mermaid\ngraph LR\n  C-->D\n```\n");
+        let config = Config::default();
+        let mermaid_dir = ensure_mermaid_dir(dir.path(), &config).unwrap();
+        let mut cache = open_cache(&mermaid_dir, &config).unwrap();
+        for code in [flow, "graph LR\n  C-->D"] {
+            cache.put(&cache_key(code, Backend::Mmdc, &config), "<svg></svg>").unwrap();
+        }
 
-    let svg_path = mermaid_dir.join(&svg_filename);
-    let mmd_path = mermaid_dir.join(&mmd_filename);
+        let (client, handle) = start_server(ClientCapabilities::default());
+        let mut version = 1;
+        let mut current = doc.clone();
+        open_document(&client, &uri, &current);
+        // Sync the text, run the command and apply the edit it returns, if any
+        let mut run = |command: &str, current: &mut String| {
+            version += 1;
+            let params = DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier::new(uri.clone(), version),
+                content_changes: vec![TextDocumentContentChangeEvent {
+                    range: None,
+                    range_length: None,
+                    text: current.clone(),
+                }],
+            };
+            client.sender.send(Message::Notification(Notification::new("textDocument/didChange".to_string(), params))).unwrap();
+            // Single renders take the first fence
+            let arguments = match command {
+                "mermaid.renderSingle" => vec![Value::Null, Value::Bool(true)],
+                _ => Vec::new(),
+            };
+            let messages = execute_command_with(&client, command, &uri, &arguments);
+            let Some(Message::Response(response)) = messages.last() else { panic!("no response") };
+            let result = response.result.clone().unwrap();
+            if result["status"] == "unchanged" {
+                return false;
+            }
+            let edit: WorkspaceEdit = serde_json::from_value(result).unwrap();
+            *current = apply_line_edits(current, &edit.changes.unwrap()[&uri]);
+            true
+        };
+        let rendered_files = || {
+            let mut names: Vec<String> = fs::read_dir(&mermaid_dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+                .filter(|name| name != ".cache")
+                .collect();
+            names.sort();
+            names
+        };
 
-    // Save files
-    if fs::write(&svg_path, &svg).is_err() {
-        error!("Failed to write SVG file");
-        return None;
+        // Both renders of the same code link the same files
+        assert!(run("mermaid.renderSingle", &mut current));
+        let after_first = current.clone();
+        assert!(run("mermaid.renderSingle", &mut current));
+        assert_eq!(find_all_rendered_blocks(&current.lines().collect::<Vec<_>>()).len(), 2);
+        let shared = rendered_files();
+        assert_eq!(shared.len(), 2);
+
+        // Reverting the second keeps the files the first still links to
+        assert!(run("mermaid.revertLastRender", &mut current));
+        assert_eq!(current, after_first);
+        assert_eq!(rendered_files(), shared);
+
+        // Render All is reverted as one operation, found again after edits above it
+        assert!(run("mermaid.renderAllLightweight", &mut current));
+        assert_eq!(rendered_files().len(), 4);
+        current = format!("Intro\n{current}");
+        assert!(run("mermaid.revertLastRender", &mut current));
+        assert!(run("mermaid.revertLastRender", &mut current));
+        assert_eq!(current, format!("Intro\n{doc}"));
+        assert!(rendered_files().is_empty());
+        assert!(!run("mermaid.revertLastRender", &mut current));
+
+        // A corrupt journal has nothing to revert
+        assert!(run("mermaid.renderSingle", &mut current));
+        fs::write(project_cache_dir(&mermaid_dir).join("render-journal.json"), "{ \"records\": [").unwrap();
+        let rendered = current.clone();
+        assert!(!run("mermaid.revertLastRender", &mut current));
+        assert_eq!(current, rendered);
+
+        stop_server(client, handle);
     }
-    if fs::write(&mmd_path, &fence.code).is_err() {
-        error!("Failed to write .mmd file");
-        return None;
+
+    /// A document with one rendered block whose .mmd source exists on disk
+    #[test]
+    fn index_lists_rendered_diagrams_in_document_order() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join(".mermaid")).unwrap();
+        for (name, code) in [
+            ("auth", "---\ntitle: Auth flow\n---\nsequenceDiagram\n  A->>B: login"),
+            ("plan", "gantt\n  title Release plan"),
+            ("graph", "graph TD\n  A-->B"),
+        ] {
+            fs::write(dir.path().join(format!(".mermaid/{name}.mmd")), code).unwrap();
+        }
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        let block = |name: &str| format!("<!-- mermaid-source-file:.mermaid/{name}.mmd -->\n\n![Mermaid Diagram](.mermaid/{name}.svg)");
+        let generate = |doc: &str, at: Option<usize>| {
+            let lines: Vec<&str> = doc.lines().collect();
+            let (figures, edit) = generate_index(&uri, &lines, at, PositionEncoding::Utf16);
+            let doc = edit.map_or(doc.to_string(), |edit| apply_line_edits(doc, &edit.changes.unwrap()[&uri]));
+            (figures, doc)
+        };
+
+        let doc = format!(
+            "# Doc\n\n{}\n\nText\n\n<a id=\"diagram-kept\"></a>\n{}\n\n{}\n",
+            block("auth"),
+            block("graph"),
+            block("plan")
+        );
+        let (figures, indexed) = generate(&doc, Some(1));
+        let anchors: Vec<&str> = figures.iter().map(|f| f.anchor.as_str()).collect();
+        assert_eq!(anchors, ["diagram-auth-flow", "diagram-kept", "diagram-release-plan"]);
+        let labels: Vec<String> = figures.iter().map(Figure::label).collect();
+        assert_eq!(labels, ["Figure 1: Auth flow", "Figure 2", "Figure 3: Release plan"]);
+        assert!(
+            indexed.starts_with(
+                "# Doc\n\n<!-- mermaid-index -->\n## List of Figures\n\n\
+                 1. [Figure 1: Auth flow](#diagram-auth-flow)\n\
+                 2. [Figure 2](#diagram-kept)\n\
+                 3. [Figure 3: Release plan](#diagram-release-plan)\n\
+                 <!-- /mermaid-index -->\n\n<a id=\"diagram-auth-flow\"></a>\n<!-- mermaid-source-file:.mermaid/auth.mmd -->"
+            ),
+            "{indexed}"
+        );
+        assert_eq!(indexed.matches("<a id=").count(), 3);
+        // Nothing changes when generated again
+        assert_eq!(generate(&indexed, Some(1)).1, indexed);
+
+        // Removing a diagram and adding one renumbers the rest
+        let edited = indexed.replacen(&block("auth"), "Removed", 1).replace("Text", &block("graph"));
+        let (figures, reindexed) = generate(&edited, None);
+        let anchors: Vec<&str> = figures.iter().map(|f| f.anchor.as_str()).collect();
+        assert_eq!(anchors, ["diagram-graph", "diagram-kept", "diagram-release-plan"]);
+        assert!(reindexed.contains("1. [Figure 1](#diagram-graph)\n2. [Figure 2](#diagram-kept)\n3. [Figure 3: Release plan]"), "{reindexed}");
+        assert_eq!(reindexed.matches("<!-- mermaid-index -->").count(), 1);
+
+        // Without an index or a line, the index goes at the end
+        let appended = generate(&format!("{}\n", block("plan")), None).1;
+        assert!(appended.ends_with("![Mermaid Diagram](.mermaid/plan.svg)\n\n<!-- mermaid-index -->\n## List of Figures\n\n1. [Figure 1: Release plan](#diagram-release-plan)\n<!-- /mermaid-index -->\n"), "{appended}");
     }
 
-    // Build the replacement text
-    let relative_svg = format!(".mermaid/{svg_filename}");
-    let relative_mmd = format!(".mermaid/{mmd_filename}");
-    let replacement = format!(
-        "<!-- mermaid-source-file:{relative_mmd} -->\n\n![Mermaid Diagram]({relative_svg})"
-    );
+    fn rendered_fixture() -> (tempfile::TempDir, Url) {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join(".mermaid")).unwrap();
+        fs::write(dir.path().join(".mermaid/doc.mmd"), "graph TD\n  A-->B").unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        (dir, uri)
+    }
 
-    // Create text edit replacing the code fence
-    let start_pos = Position::new(fence.start_line as u32, 0);
-    let end_line = fence.end_line;
-    let end_char = lines.get(end_line).map(|l| l.len()).unwrap_or(0) as u32;
-    let end_pos = Position::new(end_line as u32, end_char);
+    const RENDERED_DOC: &str =
+        "<!-- mermaid-source-file:.mermaid/doc.mmd -->\n\n![Mermaid Diagram](.mermaid/doc.svg)\n";
 
-    let text_edit = TextEdit::new(Range::new(start_pos, end_pos), replacement);
+    fn full_capabilities() -> ClientCapabilities {
+        ClientCapabilities {
+            workspace: Some(WorkspaceClientCapabilities {
+                apply_edit: Some(true),
+                ..Default::default()
+            }),
+            window: Some(WindowClientCapabilities {
+                work_done_progress: Some(true),
+                show_message: Some(ShowMessageRequestClientCapabilities::default()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
 
-    let mut changes = HashMap::new();
-    changes.insert(uri.clone(), vec![text_edit]);
+    #[test]
+    fn client_info_reads_capabilities() {
+        let full = ClientInfo::from_capabilities(&full_capabilities());
+        assert!(full.apply_edit && full.show_message_request && full.work_done_progress);
 
-    Some(WorkspaceEdit::new(changes))
-}
+        let minimal = ClientInfo::from_capabilities(&ClientCapabilities::default());
+        assert!(!minimal.apply_edit && !minimal.show_message_request && !minimal.work_done_progress);
+    }
 
-/// Create a workspace edit that renders all mermaid fences
-fn create_render_all_edit(
-    uri: &Url,
-    doc: &str,
-    lines: &[&str],
-) -> Option<WorkspaceEdit> {
-    let fences = find_all_mermaid_fences(lines);
-    if fences.is_empty() {
-        return None;
+    #[test]
+    #[allow(deprecated)]
+    fn workspace_root_falls_back_to_root_uri_then_root_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let (folder, root_uri, root_path) = (dir.path().join("folder"), dir.path().join("uri"), dir.path().join("path"));
+        let mut init = InitializeParams {
+            workspace_folders: Some(vec![WorkspaceFolder {
+                uri: Url::from_file_path(&folder).unwrap(),
+                name: "folder".to_string(),
+            }]),
+            root_uri: Some(Url::from_file_path(&root_uri).unwrap()),
+            root_path: Some(root_path.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        assert_eq!(workspace_root(&init), Some(folder));
+        init.workspace_folders = None;
+        assert_eq!(workspace_root(&init), Some(root_uri));
+        init.root_uri = None;
+        assert_eq!(workspace_root(&init), Some(root_path));
+        // Never the server's current directory
+        init.root_path = None;
+        assert_eq!(workspace_root(&init), None);
     }
 
-    let mut all_edits = Vec::new();
+    #[test]
+    fn execute_command_pushes_edit_when_apply_edit_supported() {
+        let (_dir, uri) = rendered_fixture();
+        let (client, handle) = start_server(full_capabilities());
+        open_document(&client, &uri, RENDERED_DOC);
 
-    // Process in reverse order so line numbers remain valid
-    for fence in fences.iter().rev() {
-        if let Some(edit) = create_render_edit(uri, doc, lines, fence) {
-            if let Some(changes) = &edit.changes {
-                if let Some(edits) = changes.get(uri) {
-                    all_edits.extend(edits.clone());
-                }
+        let messages = execute_command(&client, "mermaid.editSingleSource", &uri);
+        let apply = messages.iter().find_map(|m| match m {
+            Message::Request(r) if r.method == "workspace/applyEdit" => Some(r),
+            _ => None,
+        });
+        let params: ApplyWorkspaceEditParams =
+            serde_json::from_value(apply.expect("applyEdit request").params.clone()).unwrap();
+        let edits = &params.edit.changes.unwrap()[&uri];
+        assert_eq!(edits[0].new_text, "```mermaid\ngraph TD\n  A-->B\n```");
+
+        match messages.last().unwrap() {
+            Message::Response(r) => {
+                let result = r.result.clone().unwrap();
+                assert_eq!((&result["status"], &result["restored"]), (&"ok".into(), &1.into()));
+                assert!(result.get("changes").is_none(), "{result}");
             }
+            other => panic!("unexpected message: {other:?}"),
         }
+        stop_server(client, handle);
     }
 
-    if all_edits.is_empty() {
-        return None;
+    #[test]
+    fn execute_command_returns_edit_without_apply_edit() {
+        let (_dir, uri) = rendered_fixture();
+        let (client, handle) = start_server(ClientCapabilities::default());
+        open_document(&client, &uri, RENDERED_DOC);
+
+        let messages = execute_command(&client, "mermaid.editSingleSource", &uri);
+        assert!(!messages
+            .iter()
+            .any(|m| matches!(m, Message::Request(r) if r.method == "workspace/applyEdit")));
+
+        let result = match messages.last().unwrap() {
+            Message::Response(r) => r.result.clone().unwrap(),
+            other => panic!("unexpected message: {other:?}"),
+        };
+        let edit: WorkspaceEdit = serde_json::from_value(result).unwrap();
+        assert_eq!(
+            edit.changes.unwrap()[&uri][0].new_text,
+            "```mermaid\ngraph TD\n  A-->B\n```"
+        );
+        stop_server(client, handle);
     }
 
-    let mut changes = HashMap::new();
-    changes.insert(uri.clone(), all_edits);
-    Some(WorkspaceEdit::new(changes))
-}
+    #[test]
+    fn edit_single_source_lists_candidates_when_several_blocks_match() {
+        let (dir, uri) = rendered_fixture();
+        let long = format!("%% generated\n\nsequenceDiagram {}\n", "x".repeat(200));
+        fs::write(dir.path().join(".mermaid/seq.mmd"), long + &"  A->>B: hi\n".repeat(10_000)).unwrap();
+        let doc = format!(
+            "{RENDERED_DOC}\n<!-- mermaid-source-file:.mermaid/seq.mmd -->\n\n![Mermaid Diagram](.mermaid/seq.svg)\n\n\
+             <!-- mermaid-source-file:.mermaid/gone.mmd -->\n\n![Mermaid Diagram](.mermaid/gone.svg)\n"
+        );
+        let (client, handle) = start_server(ClientCapabilities::default());
+        open_document(&client, &uri, &doc);
+        let result = |messages: Vec<Message>| match messages.last() {
+            Some(Message::Response(r)) => r.clone(),
+            other => panic!("unexpected message: {other:?}"),
+        };
 
-// ─── Source editing (restore code blocks) ───────────────────────────────────
+        let listed = result(execute_command(&client, "mermaid.editSingleSource", &uri)).result.unwrap();
+        let candidates = listed["candidates"].as_array().unwrap();
+        assert_eq!(candidates.len(), 3);
+        assert_eq!(candidates[0], serde_json::json!({ "line": 0, "sourceFile": ".mermaid/doc.mmd", "snippet": "graph TD" }));
+        assert_eq!(candidates[1]["line"], 4);
+        let snippet = candidates[1]["snippet"].as_str().unwrap();
+        assert!(snippet.starts_with("sequenceDiagram xxx") && snippet.ends_with('…'), "{snippet}");
+        assert_eq!(snippet.chars().count(), SNIPPET_CHARS + 1);
+        assert_eq!(candidates[2]["snippet"], Value::Null);
 
-/// Find a rendered block at the cursor position and create an edit to restore source
-fn find_source_edit_at_cursor(
-    uri: &Url,
-    doc: &str,
-    lines: &[&str],
-    cursor_line: usize,
-) -> Option<WorkspaceEdit> {
-    find_all_rendered_blocks(lines)
-        .iter()
-        .find(|rb| cursor_line >= rb.comment_line && cursor_line <= rb.end_line)
-        .and_then(|rb| create_source_edit(uri, doc, lines, rb))
-}
+        // The picker's choice, by any line of the block
+        let chosen = result(execute_command_with(&client, "mermaid.editSingleSource", &uri, &[Value::from(6)]));
+        let edit: WorkspaceEdit = serde_json::from_value(chosen.result.unwrap()).unwrap();
+        let edits = &edit.changes.unwrap()[&uri];
+        assert_eq!(edits[0].range.start.line, 4);
+        assert!(edits[0].new_text.starts_with("```mermaid\n%% generated\n"));
+        let outside = result(execute_command_with(&client, "mermaid.editSingleSource", &uri, &[Value::from(3)]));
+        assert!(outside.error.unwrap().message.contains("No rendered diagram at line 4"));
+        stop_server(client, handle);
+    }
 
-/// Create a workspace edit that restores a rendered block to its mermaid source
-fn create_source_edit(
-    uri: &Url,
-    _doc: &str,
-    lines: &[&str],
-    block: &RenderedBlock,
-) -> Option<WorkspaceEdit> {
-    let base_dir = doc_base_dir(uri)?;
-    let mmd_path = base_dir.join(&block.source_file);
+    #[test]
+    fn render_single_takes_the_fence_at_the_cursor() {
+        let doc = "# Doc\n\n```mermaid\ngraph TD\n  A-->B\n```\n\n<!-- mermaid-ignore -->\n```mermaid\ngraph TD\n```\n\n```mermaid\ngraph LR\n  C-->D\n```\n";
+        let lines: Vec<&str> = doc.lines().collect();
+        let pick = |arguments: Value| {
+            let arguments = arguments.as_array().unwrap().clone();
+            single_render_fence(&lines, &arguments).unwrap().map(|fence| fence.start_line)
+        };
+        // Cursor in a fence, with or without the fallback
+        assert_eq!(pick(serde_json::json!(["uri", 14])), Some(12));
+        assert_eq!(pick(serde_json::json!(["uri", 13, true])), Some(12));
+        // Cursor outside every fence (or on an ignored one): nothing, unless asked for the first
+        assert_eq!(pick(serde_json::json!(["uri", 0])), None);
+        assert_eq!(pick(serde_json::json!(["uri", 9])), None);
+        assert_eq!(pick(serde_json::json!(["uri"])), None);
+        assert_eq!(pick(serde_json::json!(["uri", 0, true])), Some(2));
+        assert_eq!(pick(serde_json::json!(["uri", null, true])), Some(2));
+        assert!(single_render_fence(&lines, &[Value::Null, serde_json::json!("top")]).is_err());
 
-    // Read the original mermaid source
-    let mermaid_code = fs::read_to_string(&mmd_path).ok()?;
-    let replacement = format!("```mermaid\n{mermaid_code}\n```");
+        // Over the protocol, a cursor outside says so and changes nothing
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        let (client, handle) = start_server(full_capabilities());
+        open_document(&client, &uri, doc);
+        let messages = execute_command_with(&client, "mermaid.renderSingle", &uri, &[serde_json::json!(0)]);
+        assert!(messages.iter().any(|m| matches!(m, Message::Notification(n) if n.method == "window/showMessage"
+            && n.params["message"] == "Mermaid: cursor is not inside a mermaid block.")));
+        assert!(!messages.iter().any(|m| matches!(m, Message::Request(r) if r.method == "workspace/applyEdit")));
+        match messages.last().unwrap() {
+            Message::Response(r) => assert_eq!(
+                r.result,
+                Some(serde_json::json!({
+                    "status": "unchanged",
+                    "message": "Mermaid: cursor is not inside a mermaid block.",
+                    "rendered": 0,
+                    "failed": 0,
+                    "files": [],
+                }))
+            ),
+            other => panic!("unexpected message: {other:?}"),
+        }
+        assert!(!dir.path().join(".mermaid").exists());
+        stop_server(client, handle);
+    }
 
-    let start_pos = Position::new(block.comment_line as u32, 0);
-    let end_char = lines.get(block.end_line).map(|l| l.len()).unwrap_or(0) as u32;
-    let end_pos = Position::new(block.end_line as u32, end_char);
+    #[test]
+    fn render_by_type_renders_only_the_named_types() {
+        let sequence = "sequenceDiagram\n  Alice->>Bob: Hi";
+        let state = "stateDiagram-v2\n  [*] --> Idle";
+        let rendered_sequence = "sequenceDiagram\n  Bob->>Alice: Bye";
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        let config = Config::default();
+        let mermaid_dir = ensure_mermaid_dir(dir.path(), &config).unwrap();
+        let mut cache = open_cache(&mermaid_dir, &config).unwrap();
+        // Only the wanted diagrams can render: mmdc isn't there for the others
+        for code in [sequence, state, rendered_sequence] {
+            cache.put(&cache_key(code, Backend::Mmdc, &config), "<svg>cached</svg>").unwrap();
+        }
+        fs::write(mermaid_dir.join("seq.mmd"), rendered_sequence).unwrap();
+        fs::write(mermaid_dir.join("flow.mmd"), "graph TD\n  X-->Y").unwrap();
+        let doc = format!(
+            "```mermaid\ngraph TD\n  A-->B\n```\n\n```mermaid\n{sequence}\n```\n\n```mermaid\n{state}\n```\n\n\
+             <!-- mermaid-source-file:.mermaid/seq.mmd -->\n\n![Mermaid Diagram](.mermaid/seq.svg)\n\n\
+             <!-- mermaid-source-file:.mermaid/flow.mmd -->\n\n![Mermaid Diagram](.mermaid/flow.svg)\n"
+        );
 
-    let text_edit = TextEdit::new(Range::new(start_pos, end_pos), replacement);
+        let (client, handle) = start_server(full_capabilities());
+        open_document(&client, &uri, &doc);
+        let messages =
+            execute_command_with(&client, "mermaid.renderByType", &uri, &[serde_json::json!("sequenceDiagram")]);
+        let edit = messages
+            .iter()
+            .find_map(|m| match m {
+                Message::Request(r) if r.method == "workspace/applyEdit" => {
+                    serde_json::from_value::<ApplyWorkspaceEditParams>(r.params.clone()).ok()
+                }
+                _ => None,
+            })
+            .unwrap();
+        let edits = &edit.edit.changes.unwrap()[&uri];
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].range.start.line, 5);
+        assert!(messages.iter().any(|m| matches!(m, Message::Notification(n) if n.method == "window/showMessage"
+            && n.params["message"] == "Mermaid: rendered 2 sequenceDiagram diagrams, skipped 3 of other types")));
+        // The rendered sequence diagram's SVG was rewritten, the flowchart's left alone
+        assert_eq!(
+            fs::read_to_string(mermaid_dir.join("seq.svg")).unwrap(),
+            accessibility::describe("<svg>cached</svg>", rendered_sequence)
+        );
+        assert!(!mermaid_dir.join("flow.svg").exists());
 
-    let mut changes = HashMap::new();
-    changes.insert(uri.clone(), vec![text_edit]);
+        // Type names are matched loosely, and several may be given
+        let types = serde_json::json!(["stateDiagram", "SEQUENCEDIAGRAM"]);
+        let messages = execute_command_with(&client, "mermaid.renderByType", &uri, &[types]);
+        let summary = messages.iter().find_map(|m| match m {
+            Message::Notification(n) if n.method == "window/showMessage" => n.params["message"].as_str(),
+            _ => None,
+        });
+        assert_eq!(
+            summary,
+            Some("Mermaid: rendered 3 stateDiagram/SEQUENCEDIAGRAM diagrams, skipped 2 of other types")
+        );
+        let missing = execute_command_with(&client, "mermaid.renderByType", &uri, &[]);
+        assert!(matches!(missing.last(), Some(Message::Response(r)) if r.error.is_some()));
+        stop_server(client, handle);
+    }
 
-    Some(WorkspaceEdit::new(changes))
-}
+    #[test]
+    fn rerender_from_sources_keeps_the_image_of_a_broken_source() {
+        let valid = "graph TD\n  A-->B";
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        let config = Config::default();
+        let mermaid_dir = ensure_mermaid_dir(dir.path(), &config).unwrap();
+        open_cache(&mermaid_dir, &config).unwrap().put(&cache_key(valid, Backend::Mmdc, &config), "<svg>new</svg>").unwrap();
+        fs::write(mermaid_dir.join("good.mmd"), valid).unwrap();
+        fs::write(mermaid_dir.join("good.svg"), "<svg>old</svg>").unwrap();
+        // Edited into something mermaid can't parse
+        fs::write(mermaid_dir.join("bad.mmd"), "graph TD\n  A -->").unwrap();
+        fs::write(mermaid_dir.join("bad.svg"), "<svg>last good</svg>").unwrap();
+        let doc = "<!-- mermaid-source-file:.mermaid/good.mmd -->\n\n![Mermaid Diagram](.mermaid/good.svg)\n\n\
+                   <!-- mermaid-source-file:.mermaid/bad.mmd -->\n\n![Mermaid Diagram](.mermaid/bad.svg)\n";
 
-/// Create a workspace edit that restores all rendered blocks to mermaid source
-fn create_edit_all_sources(
-    uri: &Url,
-    doc: &str,
-    lines: &[&str],
-) -> Option<WorkspaceEdit> {
-    let blocks = find_all_rendered_blocks(lines);
-    if blocks.is_empty() {
-        return None;
+        let (client, handle) = start_server(full_capabilities());
+        open_document(&client, &uri, doc);
+        let messages = execute_command(&client, "mermaid.rerenderFromSources", &uri);
+        let Some(Message::Response(r)) = messages.last() else {
+            panic!("no response in {messages:?}");
+        };
+        let result = r.result.clone().unwrap();
+        assert_eq!(result["rerendered"], serde_json::json!([".mermaid/good.svg"]));
+        assert_eq!(result["failed"].as_array().unwrap().len(), 1);
+        assert_eq!(result["failed"][0]["source"], ".mermaid/bad.mmd");
+        assert_eq!(result["failed"][0]["svg"], ".mermaid/bad.svg");
+        assert!(messages.iter().any(|m| matches!(m, Message::Notification(n) if n.method == "window/showMessage"
+            && n.params["message"].as_str().unwrap().ends_with("1 failed and kept their previous image: .mermaid/bad.mmd"))));
+        assert!(!messages.iter().any(|m| matches!(m, Message::Request(r) if r.method == "workspace/applyEdit")));
+        assert_eq!(fs::read_to_string(mermaid_dir.join("good.svg")).unwrap(), accessibility::describe("<svg>new</svg>", valid));
+        assert_eq!(fs::read_to_string(mermaid_dir.join("bad.svg")).unwrap(), "<svg>last good</svg>");
+        stop_server(client, handle);
     }
 
-    let mut all_edits = Vec::new();
+    #[test]
+    fn empty_fences_are_skipped_and_explained_when_asked_for() {
+        let flow = "graph TD\n  A-->B";
+        let doc = format!("```mermaid\n  \n```\n\n```mermaid\n{flow}\n```\n\n```mermaid\n```\n");
+        let lines: Vec<&str> = doc.lines().collect();
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        let config = Config::default();
+        let mermaid_dir = ensure_mermaid_dir(dir.path(), &config).unwrap();
+        open_cache(&mermaid_dir, &config).unwrap().put(&cache_key(flow, Backend::Mmdc, &config), "<svg></svg>").unwrap();
 
-    // Process in reverse order
-    for block in blocks.iter().rev() {
-        if let Some(edit) = create_source_edit(uri, doc, lines, block) {
-            if let Some(changes) = &edit.changes {
-                if let Some(edits) = changes.get(uri) {
-                    all_edits.extend(edits.clone());
-                }
-            }
+        let titles: Vec<String> = code_actions(&uri, &doc, 1, &config, PositionEncoding::Utf16)
+            .into_iter()
+            .map(|action| match action {
+                CodeActionOrCommand::CodeAction(action) => action.title,
+                CodeActionOrCommand::Command(command) => command.title,
+            })
+            .collect();
+        assert!(!titles.iter().any(|title| title.starts_with("Render Mermaid Diagram")), "{titles:?}");
+        assert!(titles.contains(&"Render All Mermaid Diagrams (1)".to_string()), "{titles:?}");
+
+        let (edit, failures) =
+            create_render_all_edit(&uri, &lines, "\n", &config, PositionEncoding::Utf16, |_, _| true).unwrap();
+        assert_eq!(edit.unwrap().changes.unwrap()[&uri].len(), 1);
+        assert!(failures.is_empty(), "{failures:?}");
+        let first = single_render_fence(&lines, &[Value::Null, Value::Null, Value::Bool(true)]).unwrap();
+        assert_eq!(first.unwrap().start_line, 4);
+
+        let (client, handle) = start_server(full_capabilities());
+        open_document(&client, &uri, &doc);
+        let messages = execute_command_with(&client, "mermaid.renderSingle", &uri, &[serde_json::json!(1)]);
+        assert!(messages.iter().any(|m| matches!(m, Message::Notification(n) if n.method == "window/showMessage"
+            && n.params["message"] == "Mermaid: invalid diagram: empty mermaid block")));
+        assert!(!messages.iter().any(|m| matches!(m, Message::Request(r) if r.method == "workspace/applyEdit")));
+        match messages.last().unwrap() {
+            Message::Response(r) => assert_eq!(r.result.as_ref().unwrap()["status"], "unchanged"),
+            other => panic!("unexpected message: {other:?}"),
         }
+        stop_server(client, handle);
     }
 
-    if all_edits.is_empty() {
-        return None;
+    #[test]
+    fn failed_previews_show_a_placeholder_when_enabled() {
+        let doc = "```mermaid {backend=native}\nflowchart LR\n  A-->B\n```\n";
+        let lines: Vec<&str> = doc.lines().collect();
+        let fences = find_all_mermaid_fences(&lines);
+        let config = Config {
+            unavailable_backend: backend::BackendFallback::Error,
+            ..Config::default()
+        };
+
+        let plain = &preview_fences(&fences, &config)[0];
+        assert!(plain["error"].as_str().unwrap().contains("native"));
+        assert!(plain.get("markdown").is_none());
+
+        let config = Config {
+            error_placeholder: true,
+            ..config
+        };
+        let preview = &preview_fences(&fences, &config)[0];
+        assert_eq!(preview["error"], plain["error"]);
+        assert_eq!(preview["placeholder"], true);
+        let markdown = preview["markdown"].as_str().unwrap();
+        let encoded = markdown.split("base64,").nth(1).unwrap().trim_end_matches(')');
+        let svg = String::from_utf8(BASE64_STANDARD.decode(encoded).unwrap()).unwrap();
+        assert!(placeholder::is_placeholder(&svg));
+        assert!(svg.contains("flowchart diagram"), "{svg}");
     }
 
-    let mut changes = HashMap::new();
-    changes.insert(uri.clone(), all_edits);
-    Some(WorkspaceEdit::new(changes))
-}
+    #[cfg(unix)]
+    #[test]
+    fn read_only_locations_get_previews_and_nothing_is_written() {
+        use std::os::unix::fs::PermissionsExt;
 
-// ─── Tests ──────────────────────────────────────────────────────────────────
+        let dir = tempfile::tempdir().unwrap();
+        let doc = "# Doc\n\n```mermaid\ngraph TD\n  A-->B\n```\n\n```mermaid\ngraph LR\n  C-->D\n```\n";
+        fs::write(dir.path().join("doc.md"), doc).unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o555)).unwrap();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let (client, handle) = start_server(full_capabilities());
+        open_document(&client, &uri, doc);
+        let result = |messages: &[Message]| match messages.last().unwrap() {
+            Message::Response(r) => r.clone(),
+            other => panic!("unexpected message: {other:?}"),
+        };
+
+        let single = execute_command_with(&client, "mermaid.renderSingle", &uri, &[serde_json::json!(8)]);
+        assert!(single.iter().any(|m| matches!(m, Message::Notification(n) if n.method == "window/showMessage"
+            && n.params["message"].as_str().unwrap().contains("read-only"))));
+        assert!(!single.iter().any(|m| matches!(m, Message::Request(r) if r.method == "workspace/applyEdit")));
+        let preview = result(&single).result.unwrap();
+        assert!(preview["readOnly"].as_str().unwrap().contains("is read-only"));
+        assert_eq!(preview["previews"].as_array().unwrap().len(), 1);
+        assert_eq!(preview["previews"][0]["line"], 7);
+
+        let all = result(&execute_command(&client, "mermaid.renderAllLightweight", &uri)).result.unwrap();
+        let lines: Vec<&Value> = all["previews"].as_array().unwrap().iter().map(|p| &p["line"]).collect();
+        assert_eq!(lines, [2, 7]);
+
+        let steps = result(&execute_command(&client, "mermaid.renderSteps", &uri)).error.unwrap();
+        assert!(steps.message.starts_with("cannot write rendered files:"), "{}", steps.message);
+
+        stop_server(client, handle);
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o755)).unwrap();
+        let left: Vec<_> = fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(left, ["doc.md"]);
+        assert_eq!(fs::read_to_string(dir.path().join("doc.md")).unwrap(), doc);
+    }
 
     #[test]
-    fn finds_mermaid_fences() {
-        let doc = "# Hello\n\n```mermaid\ngraph TD\n  A --> B\n```\n\nSome text\n";
-        let lines: Vec<&str> = doc.lines().collect();
-        let fences = find_all_mermaid_fences(&lines);
+    fn set_option_updates_the_session_config() {
+        let (client, handle) = start_server(ClientCapabilities::default());
+        let send = |id: i32, command: &str, arguments: Vec<Value>| {
+            let params = ExecuteCommandParams {
+                command: command.to_string(),
+                arguments,
+                work_done_progress_params: Default::default(),
+            };
+            let req = Request::new(id.into(), "workspace/executeCommand".to_string(), params);
+            client.sender.send(Message::Request(req)).unwrap();
+            // A rejected option is also shown to the user
+            loop {
+                match client.receiver.recv_timeout(std::time::Duration::from_secs(5)).unwrap() {
+                    Message::Response(r) => break r,
+                    Message::Notification(n) if n.method == "window/showMessage" => {}
+                    other => panic!("unexpected message: {other:?}"),
+                }
+            }
+        };
 
-        assert_eq!(fences.len(), 1);
-        assert_eq!(fences[0].start_line, 2);
-        assert_eq!(fences[0].end_line, 5);
-        assert_eq!(fences[0].code, "graph TD\n  A --> B");
+        let set = send(2, "mermaid.setOption", vec![serde_json::json!({ "key": "theme", "value": "forest" })]);
+        assert_eq!(set.result.unwrap()["theme"], "forest");
+        let options = send(3, "mermaid.getOptions", Vec::new());
+        assert_eq!(options.result.unwrap()["theme"], "forest");
+
+        let rejected = send(4, "mermaid.setOption", vec![serde_json::json!({ "key": "colour", "value": "red" })]);
+        assert!(rejected.error.unwrap().message.contains("unknown option `colour`"));
+        stop_server(client, handle);
     }
 
     #[test]
-    fn finds_multiple_fences() {
-        let doc = "```mermaid\ngraph TD\n  A-->B\n```\n\n```mermaid\nsequenceDiagram\n  A->>B: Hi\n```\n";
-        let lines: Vec<&str> = doc.lines().collect();
-        let fences = find_all_mermaid_fences(&lines);
+    fn renders_stop_at_the_session_limit_until_reset() {
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        let cached = "graph TD\n  Limit-->Cached";
+        let config = Config::default();
+        open_cache(&ensure_mermaid_dir(dir.path(), &config).unwrap(), &config)
+            .unwrap()
+            .put(&cache_key(cached, Backend::Mmdc, &config), "<svg></svg>")
+            .unwrap();
 
-        assert_eq!(fences.len(), 2);
-        assert_eq!(fences[0].code, "graph TD\n  A-->B");
-        assert_eq!(fences[1].code, "sequenceDiagram\n  A->>B: Hi");
+        let (client, handle) = start_server(ClientCapabilities::default());
+        let params = ExecuteCommandParams {
+            command: "mermaid.setOption".to_string(),
+            arguments: vec![serde_json::json!({ "key": "maxRendersPerSession", "value": 0 })],
+            work_done_progress_params: Default::default(),
+        };
+        client
+            .sender
+            .send(Message::Request(Request::new(3.into(), "workspace/executeCommand".to_string(), params)))
+            .unwrap();
+        assert!(matches!(client.receiver.recv().unwrap(), Message::Response(r) if r.error.is_none()));
+
+        let doc = format!("```mermaid\n{cached}\n```\n\n```mermaid\ngraph TD\n  Limit-->Fresh\n```\n");
+        open_document(&client, &uri, &doc);
+        let result = |messages: &[Message]| match messages.last() {
+            Some(Message::Response(r)) => r.clone(),
+            other => panic!("unexpected message: {other:?}"),
+        };
+
+        // Cache hits render nothing, so they go through at any limit
+        let hit = execute_command_with(&client, "mermaid.renderSingle", &uri, &[Value::from(1)]);
+        assert!(result(&hit).error.is_none(), "{hit:?}");
+        let refused = execute_command_with(&client, "mermaid.renderSingle", &uri, &[Value::from(5)]);
+        let error = result(&refused).error.unwrap();
+        assert!(error.message.starts_with("render limit reached:"), "{}", error.message);
+
+        let reset = execute_command(&client, "mermaid.resetLimits", &uri);
+        assert_eq!(result(&reset).result.unwrap()["status"], "ok");
+        stop_server(client, handle);
     }
 
     #[test]
-    fn ignores_non_mermaid_fences() {
-        let doc = "```rust\nfn main() {}\n```\n\n```mermaid\ngraph TD\n```\n";
-        let lines: Vec<&str> = doc.lines().collect();
-        let fences = find_all_mermaid_fences(&lines);
+    fn fence_status_follows_open_edit_and_render() {
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        let cached = "graph TD\n  Status-->Cached";
+        let config = Config::default();
+        let key = ContentHash::new(cached, &render::backend_cache_version(Backend::Mmdc, &config)).to_string();
+        open_cache(&ensure_mermaid_dir(dir.path(), &config).unwrap(), &config)
+            .unwrap()
+            .put(&key, "<svg xmlns=\"http://www.w3.org/2000/svg\"/>")
+            .unwrap();
 
-        assert_eq!(fences.len(), 1);
-        assert!(fences[0].code.contains("graph TD"));
+        let capabilities = ClientCapabilities {
+            experimental: Some(serde_json::json!({ "fenceStatus": true })),
+            ..Default::default()
+        };
+        let (client, handle) = start_server(capabilities);
+        let next_status = || match client.receiver.recv_timeout(std::time::Duration::from_secs(5)).unwrap() {
+            Message::Notification(not) if not.method == status::METHOD => not.params,
+            other => panic!("unexpected message: {other:?}"),
+        };
+        let states = |params: &Value| -> Vec<String> {
+            params["fences"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|fence| fence["status"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        open_document(&client, &uri, "# Doc\n\n```mermaid\ngraph TD\n  Not-->Cached\n```\n");
+        let opened = next_status();
+        assert_eq!(states(&opened), ["unrendered"]);
+        assert_eq!(opened["fences"][0]["range"]["start"]["line"], 2);
+        assert_eq!(opened["version"], 1);
+
+        let params = DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier::new(uri.clone(), 2),
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: format!("# Doc\n\n```mermaid\n{cached}\n```\n"),
+            }],
+        };
+        let not = Notification::new("textDocument/didChange".to_string(), params);
+        client.sender.send(Message::Notification(not)).unwrap();
+        let edited = next_status();
+        assert_eq!(states(&edited), ["cached"]);
+        assert_eq!(edited["version"], 2);
+        assert_ne!(edited["fences"][0]["hash"], opened["fences"][0]["hash"]);
+
+        let messages = execute_command(&client, "mermaid.renderAllLightweight", &uri);
+        let sequence: Vec<Vec<String>> = messages
+            .iter()
+            .filter_map(|m| match m {
+                Message::Notification(not) if not.method == status::METHOD => Some(states(&not.params)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sequence, [["rendering"], ["rendered"]]);
+        assert!(matches!(messages.last(), Some(Message::Response(r)) if r.error.is_none()));
+        stop_server(client, handle);
     }
 
     #[test]
-    fn finds_fence_at_cursor() {
-        let doc = "Text\n```mermaid\ngraph TD\n  A-->B\n```\nMore text\n";
-        let lines: Vec<&str> = doc.lines().collect();
+    fn prose_edits_trigger_no_checks() {
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        let capabilities = ClientCapabilities {
+            text_document: Some(TextDocumentClientCapabilities {
+                publish_diagnostics: Some(PublishDiagnosticsClientCapabilities::default()),
+                ..Default::default()
+            }),
+            experimental: Some(serde_json::json!({ "fenceStatus": true })),
+            ..Default::default()
+        };
+        let (client, handle) = start_server(capabilities);
+        let change = |version: i32, text: &str| {
+            let params = DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier::new(uri.clone(), version),
+                content_changes: vec![TextDocumentContentChangeEvent {
+                    range: None,
+                    range_length: None,
+                    text: text.to_string(),
+                }],
+            };
+            let not = Notification::new("textDocument/didChange".to_string(), params);
+            client.sender.send(Message::Notification(not)).unwrap();
+            // Everything the change produced arrives before the command's response
+            execute_command(&client, "mermaid.getOptions", &uri)
+                .into_iter()
+                .filter_map(|m| match m {
+                    Message::Notification(not) => Some(not),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let diagnostic_lines = |not: &Notification| -> Vec<u64> {
+            not.params["diagnostics"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|d| d["range"]["start"]["line"].as_u64().unwrap())
+                .collect()
+        };
 
-        assert!(find_mermaid_fence(&lines, 0).is_none());
-        assert!(find_mermaid_fence(&lines, 1).is_some());
-        assert!(find_mermaid_fence(&lines, 2).is_some());
-        assert!(find_mermaid_fence(&lines, 3).is_some());
-        assert!(find_mermaid_fence(&lines, 4).is_some());
-        assert!(find_mermaid_fence(&lines, 5).is_none());
+        let fence = "```mermaid {backend=native}\ngraph TD\n  A-->B\n```\n";
+        open_document(&client, &uri, &format!("# Doc\n\nSome prose.\n\n{fence}"));
+        let opened = execute_command(&client, "mermaid.getOptions", &uri);
+        assert!(opened.iter().any(|m| matches!(m, Message::Notification(not) if not.method == "textDocument/publishDiagnostics")));
+
+        // Typing in the prose changes no fence
+        let typed = change(2, &format!("# Doc\n\nSome more prose.\n\n{fence}"));
+        assert!(typed.is_empty(), "{typed:?}");
+
+        // New lines above move the diagnostics along without a new check
+        let moved = change(3, &format!("# Doc\n\nSome more prose.\nAnd a line.\n\n{fence}"));
+        let methods: Vec<&str> = moved.iter().map(|not| not.method.as_str()).collect();
+        assert_eq!(methods, ["textDocument/publishDiagnostics", status::METHOD]);
+        assert_eq!(diagnostic_lines(&moved[0]), [5]);
+
+        // Editing the fence checks it again
+        let edited = change(4, "# Doc\n\n```mermaid\ngraph TD\n  A-->B\n```\n");
+        assert_eq!(edited[0].method, "textDocument/publishDiagnostics");
+        assert!(diagnostic_lines(&edited[0]).is_empty());
+        stop_server(client, handle);
     }
 
     #[test]
-    fn extracts_source_file_path() {
-        assert_eq!(
-            extract_source_file_path("<!-- mermaid-source-file:.mermaid/doc_20240101.mmd -->"),
-            Some(".mermaid/doc_20240101.mmd".to_string())
-        );
-        assert_eq!(
-            extract_source_file_path("Some random text"),
-            None
-        );
-        assert_eq!(
-            extract_source_file_path("<!-- other comment -->"),
-            None
-        );
+    fn live_editor_link_needs_no_file() {
+        let uri = Url::parse("untitled:Untitled-1").unwrap();
+        let (client, handle) = start_server(ClientCapabilities::default());
+        open_document(&client, &uri, "# Doc\n\n```mermaid\ngraph TD\n  A-->B\n```\n");
+        let messages = execute_command(&client, "mermaid.liveEditorLink", &uri);
+        let link = match messages.last().unwrap() {
+            Message::Response(r) => r.result.clone().unwrap(),
+            other => panic!("unexpected message: {other:?}"),
+        };
+        assert_eq!(link["url"], live::editor_link("graph TD\n  A-->B", "default"));
+        stop_server(client, handle);
     }
 
     #[test]
-    fn finds_rendered_blocks() {
-        let doc = "<!-- mermaid-source-file:.mermaid/doc.mmd -->\n\n![Mermaid Diagram](.mermaid/doc.svg)\n";
-        let lines: Vec<&str> = doc.lines().collect();
-        let blocks = find_all_rendered_blocks(&lines);
+    fn derived_state_of_least_recent_documents_is_dropped() {
+        let capabilities = ClientCapabilities {
+            text_document: Some(TextDocumentClientCapabilities {
+                publish_diagnostics: Some(PublishDiagnosticsClientCapabilities::default()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let (client, handle) = start_server(capabilities);
+        let params = ExecuteCommandParams {
+            command: "mermaid.setOption".to_string(),
+            arguments: vec![serde_json::json!({ "key": "derivedStateCapBytes", "value": 1 })],
+            work_done_progress_params: Default::default(),
+        };
+        let req = Request::new(3.into(), "workspace/executeCommand".to_string(), params);
+        client.sender.send(Message::Request(req)).unwrap();
+        assert!(matches!(client.receiver.recv_timeout(std::time::Duration::from_secs(5)).unwrap(), Message::Response(r) if r.error.is_none()));
 
-        assert_eq!(blocks.len(), 1);
-        assert_eq!(blocks[0].comment_line, 0);
-        assert_eq!(blocks[0].end_line, 2);
-        assert_eq!(blocks[0].source_file, ".mermaid/doc.mmd");
+        let doc = "# Doc\n\n```mermaid {backend=native}\ngraph TD\n```\n";
+        let uris: Vec<Url> = ["a", "b", "c"].iter().map(|name| Url::parse(&format!("file:///docs/{name}.md")).unwrap()).collect();
+        for uri in &uris {
+            open_document(&client, uri, doc);
+        }
+        let stats = |uri: &Url| {
+            let messages = execute_command(&client, "mermaid.stats", uri);
+            let Some(Message::Response(r)) = messages.last() else {
+                panic!("no response in {messages:?}");
+            };
+            (r.result.clone().unwrap(), messages.len() - 1)
+        };
+        let (opened, _) = stats(&uris[0]);
+        assert_eq!(opened["documents"], 3);
+        assert_eq!(opened["documentsWithDerivedState"], 1);
+        assert_eq!(opened["retainedBytes"]["text"], 3 * doc.len());
+        assert!(opened["retainedBytes"]["diagnostics"].as_u64().unwrap() > 0);
+        assert_eq!(opened["cache"]["location"], "project");
+        assert_eq!(opened["cache"]["dir"], serde_json::json!(Path::new("/docs/.mermaid/.cache")));
+        assert_eq!(opened["cache"]["sizeBytes"], 0);
+
+        // A dropped document is checked again on its next change, even a
+        // prose-only one, but its unchanged diagnostics aren't sent again
+        let params = DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier::new(uris[0].clone(), 2),
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: doc.replace("# Doc", "# Docs"),
+            }],
+        };
+        client.sender.send(Message::Notification(Notification::new("textDocument/didChange".to_string(), params))).unwrap();
+        let (changed, notifications) = stats(&uris[0]);
+        assert_eq!(notifications, 0);
+        assert_eq!(changed["documentsWithDerivedState"], 1);
+        stop_server(client, handle);
     }
 
     #[test]
-    fn code_hash_deterministic() {
-        let code = "graph TD\n  A --> B";
-        assert_eq!(code_hash(code), code_hash(code));
+    fn fence_status_needs_the_client_to_ask() {
+        let dir = tempfile::tempdir().unwrap();
+        let uri = Url::from_file_path(dir.path().join("doc.md")).unwrap();
+        let (client, handle) = start_server(ClientCapabilities::default());
+        open_document(&client, &uri, "```mermaid\ngraph TD\n```\n");
+        let messages = execute_command(&client, "mermaid.verifyCache", &uri);
+        assert!(!messages
+            .iter()
+            .any(|m| matches!(m, Message::Notification(not) if not.method == status::METHOD)));
+        stop_server(client, handle);
     }
 
     #[test]
-    fn code_hash_different_for_different_code() {
-        assert_ne!(code_hash("graph TD"), code_hash("graph LR"));
+    fn show_message_needs_no_request_capability() {
+        let uri = Url::parse("file:///not/open.md").unwrap();
+        for capabilities in [full_capabilities(), ClientCapabilities::default()] {
+            let (client, handle) = start_server(capabilities);
+            let messages = execute_command(&client, "mermaid.renderSingle", &uri);
+            assert!(messages
+                .iter()
+                .any(|m| matches!(m, Message::Notification(n) if n.method == "window/showMessage")));
+            stop_server(client, handle);
+        }
+    }
+
+    #[test]
+    fn non_file_documents_keep_read_only_features() {
+        let doc = "```mermaid {backend=native}\ngraph TD\n  A-->B\n```\n";
+        let capabilities = ClientCapabilities {
+            text_document: Some(TextDocumentClientCapabilities {
+                publish_diagnostics: Some(PublishDiagnosticsClientCapabilities::default()),
+                ..Default::default()
+            }),
+            ..full_capabilities()
+        };
+        let (client, handle) = start_server(capabilities);
+
+        for (uri, scheme) in [
+            ("untitled:Untitled-1", "`untitled:`"),
+            ("zed://remote/project/doc.md", "`zed:`"),
+            ("git:/docs/doc.md?HEAD", "`git:`"),
+        ] {
+            let uri = Url::parse(uri).unwrap();
+            open_document(&client, &uri, doc);
+
+            // Diagnostics are published as for local files
+            match client.receiver.recv_timeout(std::time::Duration::from_secs(5)).unwrap() {
+                Message::Notification(not) => {
+                    assert_eq!(not.method, "textDocument/publishDiagnostics");
+                    let params: PublishDiagnosticsParams = serde_json::from_value(not.params).unwrap();
+                    assert_eq!(params.uri, uri);
+                    assert_eq!(params.diagnostics.len(), 1);
+                }
+                other => panic!("unexpected message: {other:?}"),
+            }
+
+            // Code actions answer, but offer nothing that writes files
+            let params = CodeActionParams {
+                text_document: TextDocumentIdentifier::new(uri.clone()),
+                range: Range::new(Position::new(1, 0), Position::new(1, 0)),
+                context: CodeActionContext::default(),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            };
+            let req = Request::new(3.into(), "textDocument/codeAction".to_string(), params);
+            client.sender.send(Message::Request(req)).unwrap();
+            match client.receiver.recv_timeout(std::time::Duration::from_secs(5)).unwrap() {
+                Message::Response(r) => assert_eq!(r.result, Some(serde_json::json!([]))),
+                other => panic!("unexpected message: {other:?}"),
+            }
+
+            // Commands that write files explain why they can't
+            for command in ["mermaid.renderSingle", "mermaid.renderAllLightweight", "mermaid.editAllSources"] {
+                match execute_command(&client, command, &uri).last().unwrap() {
+                    Message::Response(r) => {
+                        let error = r.error.as_ref().expect("error response");
+                        assert_eq!(error.code, lsp_server::ErrorCode::InvalidParams as i32);
+                        assert!(error.message.contains(scheme), "{command}: {}", error.message);
+                    }
+                    other => panic!("unexpected message: {other:?}"),
+                }
+            }
+        }
+        stop_server(client, handle);
+    }
+
+    #[test]
+    fn failed_command_responds_with_error_code() {
+        let uri = Url::parse("file:///not/open.md").unwrap();
+        let (client, handle) = start_server(ClientCapabilities::default());
+
+        let messages = execute_command(&client, "mermaid.editAllSources", &uri);
+        match messages.last().unwrap() {
+            Message::Response(r) => {
+                let error = r.error.as_ref().expect("error response");
+                assert_eq!(error.code, lsp_server::ErrorCode::InvalidParams as i32);
+                assert!(error.message.contains("not open"));
+            }
+            other => panic!("unexpected message: {other:?}"),
+        }
+        stop_server(client, handle);
+    }
+
+    #[test]
+    fn progress_requires_client_support() {
+        let doc = "<!-- no mermaid here -->\n";
+        let uri = Url::parse("file:///tmp/progress.md").unwrap();
+
+        let (client, handle) = start_server(full_capabilities());
+        open_document(&client, &uri, doc);
+        let messages = execute_command(&client, "mermaid.renderAllLightweight", &uri);
+        assert!(messages.iter().any(
+            |m| matches!(m, Message::Request(r) if r.method == "window/workDoneProgress/create")
+        ));
+        stop_server(client, handle);
+
+        let (client, handle) = start_server(ClientCapabilities::default());
+        open_document(&client, &uri, doc);
+        let messages = execute_command(&client, "mermaid.renderAllLightweight", &uri);
+        assert_eq!(messages.len(), 1);
+        stop_server(client, handle);
     }
 }
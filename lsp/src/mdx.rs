@@ -0,0 +1,139 @@
+//! Mermaid in MDX: the `<Mermaid chart={`...`} />` component, the `<img>`
+//! it is rendered as (a markdown image isn't valid everywhere a component
+//! is), and the JSX comments MDX has instead of HTML ones
+
+use std::{borrow::Cow, path::Path};
+use url::Url;
+
+/// Start of a component's opening line, up to its attributes
+const COMPONENT_TAG: &str = "<Mermaid";
+/// End of a component's opening line: its diagram is a template literal
+/// starting on the next line
+const CHART_OPENER: &str = "chart={`";
+/// Start of a component's closing line, where the template literal ends
+const CHART_CLOSER: &str = "`}";
+
+const COMMENT_OPEN: &str = "{/*";
+const COMMENT_CLOSE: &str = "*/}";
+
+/// Whether the document is MDX, judging by its extension
+pub fn is_mdx(uri: &Url) -> bool {
+    Path::new(uri.path()).extension().is_some_and(|ext| ext.eq_ignore_ascii_case("mdx"))
+}
+
+/// How a diagram block is written in its document
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Syntax {
+    /// A ```` ```mermaid ```` fence, rendered as a markdown image
+    #[default]
+    Markdown,
+    /// A `<Mermaid chart={`...`} />` component in MDX, rendered as an `<img>`
+    Component,
+}
+
+impl Syntax {
+    /// Diagram code as written between the block's first and last lines
+    pub fn body(self, code: &str) -> Cow<'_, str> {
+        match self {
+            Self::Markdown => Cow::Borrowed(code),
+            Self::Component => Cow::Owned(escape_template(code)),
+        }
+    }
+}
+
+/// Whether `line` opens a mermaid component whose chart runs to a later line,
+/// like `<Mermaid chart={`` ` ``
+pub fn is_component_opener(line: &str) -> bool {
+    let trimmed = line.trim();
+    trimmed
+        .strip_prefix(COMPONENT_TAG)
+        .is_some_and(|rest| rest.starts_with(char::is_whitespace) && trimmed.ends_with(CHART_OPENER))
+}
+
+/// Whether `line` closes a component's chart, like `` `} /> ``
+pub fn is_component_closer(line: &str) -> bool {
+    let trimmed = line.trim();
+    trimmed.starts_with(CHART_CLOSER) && trimmed.ends_with("/>")
+}
+
+/// A component showing `code`
+pub fn component(code: &str) -> String {
+    format!("{COMPONENT_TAG} {CHART_OPENER}\n{}\n{CHART_CLOSER} />", escape_template(code))
+}
+
+/// `code` as it must be written inside a template literal
+fn escape_template(code: &str) -> String {
+    code.replace('\\', "\\\\").replace('`', "\\`").replace("${", "\\${")
+}
+
+/// The text a template literal's body stands for
+pub fn unescape_template(body: &str) -> String {
+    let mut code = String::with_capacity(body.len());
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => code.extend(chars.next()),
+            c => code.push(c),
+        }
+    }
+    code
+}
+
+/// `text` in a JSX comment
+pub fn comment(text: &str) -> String {
+    format!("{COMMENT_OPEN} {text} {COMMENT_CLOSE}")
+}
+
+/// The text of a JSX comment line
+pub fn comment_text(line: &str) -> Option<&str> {
+    let trimmed = line.trim();
+    Some(trimmed.strip_prefix(COMMENT_OPEN)?.strip_suffix(COMMENT_CLOSE)?.trim())
+}
+
+/// An `<img>` showing the diagram at `src`
+pub fn image(src: &str) -> String {
+    format!("<img src=\"{}\" alt=\"Mermaid Diagram\" />", html_escape::encode_double_quoted_attribute(src))
+}
+
+/// The `src` of an `<img ... />` line
+pub fn image_src(line: &str) -> Option<String> {
+    let trimmed = line.trim();
+    let attributes = trimmed.strip_prefix("<img")?.strip_suffix("/>")?;
+    let (_, rest) = attributes.split_once(" src=\"")?;
+    let src = &rest[..rest.find('"')?];
+    (!src.is_empty()).then(|| html_escape::decode_html_entities(src).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_component_lines() {
+        assert!(is_component_opener("<Mermaid chart={`"));
+        assert!(is_component_opener("  <Mermaid caption=\"Flow\" chart={`"));
+        assert!(!is_component_opener("<Mermaid chart={`graph TD`} />"));
+        assert!(!is_component_opener("<MermaidLive chart={`"));
+        assert!(is_component_closer("`} />"));
+        assert!(is_component_closer("  `} caption=\"Flow\" />"));
+        assert!(!is_component_closer("`}"));
+    }
+
+    #[test]
+    fn template_literals_round_trip() {
+        let code = "graph TD\n  A[\"`**md** label`\"] --> B[\"${x} \\ y\"]";
+        let written = component(code);
+        let body: Vec<&str> = written.lines().skip(1).collect();
+        assert_eq!(unescape_template(&body[..body.len() - 1].join("\n")), code);
+        assert!(written.contains("\\`**md** label\\`") && written.contains("\\${x} \\\\ y"), "{written}");
+    }
+
+    #[test]
+    fn parses_comments_and_images() {
+        assert_eq!(comment_text(&comment("mermaid-source-file:a.mmd")), Some("mermaid-source-file:a.mmd"));
+        assert_eq!(comment_text("<!-- a -->"), None);
+        assert_eq!(image_src(&image(".mermaid/a&b.svg")).as_deref(), Some(".mermaid/a&b.svg"));
+        assert_eq!(image_src("<img alt=\"x\" src=\"b.svg\"/>").as_deref(), Some("b.svg"));
+        assert_eq!(image_src("![x](b.svg)"), None);
+    }
+}
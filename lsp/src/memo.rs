@@ -0,0 +1,137 @@
+use lsp_types::{Diagnostic, Position};
+
+use crate::memory::diagnostic_bytes;
+
+/// What a fence's diagnostics depend on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FenceKey {
+    pub hash: u64,
+    pub info: String,
+    pub ignored: bool,
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+/// A document's fences as last checked, with the diagnostics published for
+/// them, so that an edit to the prose around them needs no checks
+#[derive(Debug, Clone)]
+pub struct CheckedDocument {
+    fences: Vec<FenceKey>,
+    /// Line of the unclosed ```mermaid opener, if any
+    unclosed: Option<usize>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// How a document's fences changed since they were last checked
+#[derive(Debug, Clone, PartialEq)]
+pub enum Recheck {
+    /// Every fence is the same and in the same place: nothing to do
+    Unchanged,
+    /// The same fences moved to other lines: the last diagnostics, moved along
+    Moved(Vec<Diagnostic>),
+    /// A fence was added, removed or edited: check the document again
+    Changed,
+}
+
+impl CheckedDocument {
+    pub fn new(fences: Vec<FenceKey>, unclosed: Option<usize>, diagnostics: Vec<Diagnostic>) -> Self {
+        Self {
+            fences,
+            unclosed,
+            diagnostics,
+        }
+    }
+
+    /// Bytes held for the fence index and for the diagnostics
+    pub fn retained_bytes(&self) -> (usize, usize) {
+        let fence_index = std::mem::size_of::<Self>()
+            + self.fences.iter().map(|fence| std::mem::size_of::<FenceKey>() + fence.info.len()).sum::<usize>();
+        (fence_index, self.diagnostics.iter().map(diagnostic_bytes).sum())
+    }
+
+    /// Compare the fences of the edited document with the checked ones
+    pub fn recheck(&self, fences: &[FenceKey], unclosed: Option<usize>) -> Recheck {
+        let same_fences = self.fences.len() == fences.len()
+            && self.fences.iter().zip(fences).all(|(old, new)| {
+                old.hash == new.hash
+                    && old.info == new.info
+                    && old.ignored == new.ignored
+                    && old.end_line - old.start_line == new.end_line - new.start_line
+            });
+        // The unclosed-fence diagnostic isn't tied to a fence, so it can't move
+        if !same_fences || self.unclosed != unclosed {
+            return Recheck::Changed;
+        }
+        if self.fences == fences {
+            return Recheck::Unchanged;
+        }
+
+        let moved = self
+            .diagnostics
+            .iter()
+            .map(|diagnostic| {
+                let line = diagnostic.range.start.line as usize;
+                let delta = self
+                    .fences
+                    .iter()
+                    .zip(fences)
+                    .find(|(old, _)| (old.start_line..=old.end_line).contains(&line))
+                    .map_or(0, |(old, new)| new.start_line as i64 - old.start_line as i64);
+                let shift = |p: Position| Position::new((p.line as i64 + delta) as u32, p.character);
+                let mut diagnostic = diagnostic.clone();
+                diagnostic.range.start = shift(diagnostic.range.start);
+                diagnostic.range.end = shift(diagnostic.range.end);
+                diagnostic
+            })
+            .collect();
+        Recheck::Moved(moved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_types::Range;
+
+    fn fence(hash: u64, start_line: usize) -> FenceKey {
+        FenceKey {
+            hash,
+            info: String::new(),
+            ignored: false,
+            start_line,
+            end_line: start_line + 3,
+        }
+    }
+
+    fn diagnostic(line: u32) -> Diagnostic {
+        Diagnostic {
+            range: Range::new(Position::new(line, 2), Position::new(line, 8)),
+            message: format!("line {line}"),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn unchanged_fences_need_nothing() {
+        let checked = CheckedDocument::new(vec![fence(1, 2), fence(2, 10)], None, vec![diagnostic(3)]);
+        assert_eq!(checked.recheck(&[fence(1, 2), fence(2, 10)], None), Recheck::Unchanged);
+    }
+
+    #[test]
+    fn moved_fences_carry_their_diagnostics() {
+        let checked = CheckedDocument::new(vec![fence(1, 2), fence(2, 10)], None, vec![diagnostic(3), diagnostic(11)]);
+        let Recheck::Moved(moved) = checked.recheck(&[fence(1, 2), fence(2, 12)], None) else {
+            panic!("expected moved diagnostics");
+        };
+        assert_eq!(moved, [diagnostic(3), Diagnostic { message: "line 11".to_string(), ..diagnostic(13) }]);
+    }
+
+    #[test]
+    fn edited_fences_are_checked_again() {
+        let checked = CheckedDocument::new(vec![fence(1, 2)], None, Vec::new());
+        assert_eq!(checked.recheck(&[fence(3, 2)], None), Recheck::Changed);
+        assert_eq!(checked.recheck(&[fence(1, 2), fence(2, 10)], None), Recheck::Changed);
+        assert_eq!(checked.recheck(&[FenceKey { ignored: true, ..fence(1, 2) }], None), Recheck::Changed);
+        assert_eq!(checked.recheck(&[fence(1, 2)], Some(8)), Recheck::Changed);
+    }
+}
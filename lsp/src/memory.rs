@@ -0,0 +1,94 @@
+use lsp_types::{Diagnostic, NumberOrString, Url};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Bytes held for open documents, by category
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetainedBytes {
+    /// Document text, kept for as long as the document is open
+    pub text: usize,
+    /// Fences of each document as last checked
+    pub fence_index: usize,
+    /// Diagnostics kept to republish after edits that only move fences
+    pub diagnostics: usize,
+    /// Render failures of the last "Render All"
+    pub render_failures: usize,
+}
+
+impl RetainedBytes {
+    /// State that is recomputed when dropped
+    pub fn derived(&self) -> usize {
+        self.fence_index + self.diagnostics
+    }
+
+    pub fn total(&self) -> usize {
+        self.text + self.derived() + self.render_failures
+    }
+}
+
+/// Approximate heap and inline size of a diagnostic
+pub fn diagnostic_bytes(diagnostic: &Diagnostic) -> usize {
+    let code = match &diagnostic.code {
+        Some(NumberOrString::String(code)) => code.len(),
+        _ => 0,
+    };
+    std::mem::size_of::<Diagnostic>()
+        + diagnostic.message.len()
+        + diagnostic.source.as_ref().map_or(0, String::len)
+        + code
+}
+
+/// When each document was last used, to find the least recently used one
+#[derive(Debug, Default)]
+pub struct Recency {
+    clock: u64,
+    last_used: HashMap<Url, u64>,
+}
+
+impl Recency {
+    pub fn touch(&mut self, uri: &Url) {
+        self.clock += 1;
+        self.last_used.insert(uri.clone(), self.clock);
+    }
+
+    pub fn forget(&mut self, uri: &Url) {
+        self.last_used.remove(uri);
+    }
+
+    /// The least recently used of `uris`; never used ones come first
+    pub fn least_recent<'a>(&self, uris: impl IntoIterator<Item = &'a Url>) -> Option<&'a Url> {
+        uris.into_iter()
+            .min_by_key(|uri| self.last_used.get(*uri).copied().unwrap_or(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_least_recently_used_document() {
+        let [a, b, c] = ["a", "b", "c"].map(|name| Url::parse(&format!("file:///{name}.md")).unwrap());
+        let mut recency = Recency::default();
+        recency.touch(&a);
+        recency.touch(&b);
+        recency.touch(&a);
+        assert_eq!(recency.least_recent([&a, &b]), Some(&b));
+        assert_eq!(recency.least_recent([&a, &b, &c]), Some(&c));
+        recency.forget(&a);
+        assert_eq!(recency.least_recent([&a, &b]), Some(&a));
+        assert_eq!(recency.least_recent([]), None);
+    }
+
+    #[test]
+    fn sums_categories() {
+        let bytes = RetainedBytes {
+            text: 100,
+            fence_index: 20,
+            diagnostics: 30,
+            render_failures: 5,
+        };
+        assert_eq!((bytes.derived(), bytes.total()), (50, 155));
+    }
+}
@@ -0,0 +1,208 @@
+use log::warn;
+use serde_json::{Map, Value};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::config::Config;
+
+/// Options file looked up from a document's directory up to the workspace root
+pub const RC_FILE: &str = ".mermaidrc";
+
+/// Options a `.mermaidrc` may set: how diagrams look and are named. The file
+/// comes with the project, so nothing that runs programs, reaches the network,
+/// reads the environment or writes outside the document's directory.
+const RC_OPTIONS: &[&str] = &[
+    "theme",
+    "background",
+    "preamble",
+    "preambleInSource",
+    "variables",
+    "watermark",
+    "diagramAnchors",
+    "optimizeSvg",
+    "fileNameTemplate",
+    "mmdLineEnding",
+    "disabledChecks",
+    "errorPlaceholder",
+];
+
+/// What a directory's `.mermaidrc` was read from: its modification time and
+/// size, or None when there is none
+type Stamp = Option<(Option<SystemTime>, u64)>;
+
+/// One directory's `.mermaidrc`
+struct Layer {
+    stamp: Stamp,
+    options: Map<String, Value>,
+}
+
+/// `.mermaidrc` files read so far, by directory. A file is read again only
+/// once its modification time or size changes.
+#[derive(Default)]
+pub struct RcFiles {
+    layers: HashMap<PathBuf, Layer>,
+}
+
+impl RcFiles {
+    /// `config` with the options of the `.mermaidrc` files from `root` down to
+    /// `dir` set on it, the nearest file's value of each option winning. A
+    /// `dir` outside `root` (or without one) only gets its own file's.
+    pub fn config_for<'a>(&mut self, config: &'a Config, dir: &Path, root: Option<&Path>) -> Cow<'a, Config> {
+        let options = self.options(dir, root);
+        if options.is_empty() {
+            return Cow::Borrowed(config);
+        }
+        let mut config = config.clone();
+        for (key, value) in options {
+            // Values were checked when read
+            if let Err(e) = config.set_option(&key, value) {
+                warn!("Ignoring {RC_FILE} option: {e}");
+            }
+        }
+        Cow::Owned(config)
+    }
+
+    /// The `.mermaidrc` options from `root` down to `dir`, merged shallowly
+    fn options(&mut self, dir: &Path, root: Option<&Path>) -> Map<String, Value> {
+        let dirs: Vec<&Path> = match root.filter(|root| dir.starts_with(root)) {
+            Some(root) => dir.ancestors().take_while(|d| d.starts_with(root)).collect(),
+            None => vec![dir],
+        };
+        let mut merged = Map::new();
+        for dir in dirs.into_iter().rev() {
+            merged.extend(self.layer(dir).options.clone());
+        }
+        merged
+    }
+
+    fn layer(&mut self, dir: &Path) -> &Layer {
+        let path = dir.join(RC_FILE);
+        let stamp = fs::metadata(&path)
+            .ok()
+            .filter(|meta| meta.is_file())
+            .map(|meta| (meta.modified().ok(), meta.len()));
+        let fresh = self.layers.get(dir).is_some_and(|layer| layer.stamp == stamp);
+        if !fresh {
+            let options = if stamp.is_some() { read(&path) } else { Map::new() };
+            self.layers.insert(dir.to_path_buf(), Layer { stamp, options });
+        }
+        &self.layers[dir]
+    }
+}
+
+/// The options a `.mermaidrc` sets. Options it may not set and invalid values
+/// are dropped with a warning; an unreadable file sets none.
+fn read(path: &Path) -> Map<String, Value> {
+    let parsed = fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| parse(&text));
+    let options = match parsed {
+        Ok(options) => options,
+        Err(e) => {
+            warn!("Ignoring {}: {e}", path.display());
+            return Map::new();
+        }
+    };
+    options
+        .into_iter()
+        .filter(|(key, value)| {
+            if !RC_OPTIONS.contains(&key.as_str()) {
+                warn!("Ignoring `{key}` in {}: only {} can be set there", path.display(), RC_OPTIONS.join(", "));
+                return false;
+            }
+            match Config::default().set_option(key, value.clone()) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Ignoring {}: {e}", path.display());
+                    false
+                }
+            }
+        })
+        .collect()
+}
+
+/// A `.mermaidrc`'s table of options: JSON when it starts with `{`, else TOML
+fn parse(text: &str) -> Result<Map<String, Value>, String> {
+    if text.trim_start().starts_with('{') {
+        return serde_json::from_str(text).map_err(|e| format!("invalid JSON: {e}"));
+    }
+    let table: toml::Table = toml::from_str(text).map_err(|e| format!("invalid TOML: {e}"))?;
+    match serde_json::to_value(table) {
+        Ok(Value::Object(options)) => Ok(options),
+        Ok(_) => Err("not a table of options".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearer_files_override_the_root_one() {
+        let root = tempfile::tempdir().unwrap();
+        let nested = root.path().join("docs/guide");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(
+            root.path().join(RC_FILE),
+            r#"{ "theme": "forest", "background": "transparent", "variables": { "A": "1", "B": "2" } }"#,
+        )
+        .unwrap();
+        fs::write(nested.join(RC_FILE), "theme = \"dark\"\n\n[variables]\nA = \"3\"\n").unwrap();
+
+        let session = Config::default();
+        let mut rc_files = RcFiles::default();
+        let config = rc_files.config_for(&session, &nested, Some(root.path()));
+        assert_eq!(config.theme, "dark");
+        assert_eq!(config.background, "transparent");
+        // Shallow: the nearer file's table replaces the root one's
+        assert_eq!(config.variables, [("A".to_string(), "3".to_string())].into());
+
+        let docs = rc_files.config_for(&session, &root.path().join("docs"), Some(root.path()));
+        assert_eq!(docs.theme, "forest");
+        assert_eq!(docs.variables.len(), 2);
+    }
+
+    #[test]
+    fn stops_at_the_root_and_rereads_changed_files() {
+        let outer = tempfile::tempdir().unwrap();
+        let root = outer.path().join("project");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(outer.path().join(RC_FILE), r#"{ "theme": "forest" }"#).unwrap();
+
+        let session = Config::default();
+        let mut rc_files = RcFiles::default();
+        assert!(matches!(rc_files.config_for(&session, &root, Some(&root)), Cow::Borrowed(_)));
+
+        fs::write(root.join(RC_FILE), r#"{ "theme": "neutral" }"#).unwrap();
+        assert_eq!(rc_files.config_for(&session, &root, Some(&root)).theme, "neutral");
+        fs::write(root.join(RC_FILE), r#"{ "theme": "dark", "background": "black" }"#).unwrap();
+        assert_eq!(rc_files.config_for(&session, &root, Some(&root)).theme, "dark");
+        fs::remove_file(root.join(RC_FILE)).unwrap();
+        assert_eq!(rc_files.config_for(&session, &root, Some(&root)).theme, "default");
+    }
+
+    #[test]
+    fn drops_options_that_run_programs_and_invalid_values() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(
+            root.path().join(RC_FILE),
+            r#"{ "postProcessCommand": ["sh", "-c", "true"], "outputDir": "/tmp", "diagramAnchors": "yes", "theme": "base" }"#,
+        )
+        .unwrap();
+        let session = Config::default();
+        let config = RcFiles::default().config_for(&session, root.path(), Some(root.path())).into_owned();
+        assert_eq!(config.theme, "base");
+        assert!(config.post_process_command.is_empty());
+        assert_eq!(config.output_dir, None);
+        assert!(!config.diagram_anchors);
+
+        fs::write(root.path().join(RC_FILE), "{ not json").unwrap();
+        assert_eq!(RcFiles::default().config_for(&session, root.path(), None).theme, "default");
+    }
+}
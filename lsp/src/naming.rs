@@ -0,0 +1,248 @@
+use crate::{anchors, stable_hash};
+
+/// Name (without extension) of a rendered diagram's SVG and `.mmd` files when
+/// `fileNameTemplate` is not set: the same code always gets the same name
+pub const DEFAULT_FILE_NAME_TEMPLATE: &str = "{doc}_diagram_{hash}";
+
+/// Longest file name a template may expand to, leaving room for the extension
+const MAX_NAME_LEN: usize = 200;
+
+/// Longest `{doc}` stem, in bytes, before its hash suffix, so the rest of the
+/// template still fits in [`MAX_NAME_LEN`]
+const MAX_STEM_LEN: usize = 48;
+
+/// Stem used for documents without one
+const FALLBACK_STEM: &str = "document";
+
+/// Names Windows reserves for devices, whatever the extension
+const WINDOWS_DEVICE_NAMES: &[&str] = &[
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8", "com9", "lpt1",
+    "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// A document's file stem made safe to start generated file names with, on
+/// Unix, macOS and Windows alike: lowercase, without separators, control or
+/// reserved characters, leading dots or trailing dots, and at most
+/// [`MAX_STEM_LEN`] bytes. A stem that had to change gets a short hash of the
+/// original appended, so stems that differ only in case or in stripped
+/// characters still get different names on case-insensitive file systems.
+pub fn safe_stem(stem: &str) -> String {
+    let mapped: String = stem
+        .chars()
+        .map(|c| {
+            if c.is_control() || c.is_whitespace() || matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') {
+                '_'
+            } else {
+                c
+            }
+        })
+        .flat_map(char::to_lowercase)
+        .collect();
+    let trimmed = mapped.trim_matches('.');
+    if trimmed == stem && !trimmed.is_empty() && !is_device_name(trimmed) && trimmed.len() <= MAX_STEM_LEN {
+        return trimmed.to_string();
+    }
+
+    let mut end = trimmed.len().min(MAX_STEM_LEN);
+    while !trimmed.is_char_boundary(end) {
+        end -= 1;
+    }
+    let base = trimmed[..end].trim_end_matches('.');
+    let base = if base.is_empty() { FALLBACK_STEM } else { base };
+    // `con.md-1234abcd` is still the device `con` to Windows
+    let prefix = if is_device_name(base) { "_" } else { "" };
+    format!("{prefix}{base}-{:08x}", stable_hash::hash(stem) as u32)
+}
+
+/// Whether Windows takes `name` for a device, as it does `nul` and `nul.txt`
+fn is_device_name(name: &str) -> bool {
+    WINDOWS_DEVICE_NAMES.contains(&name.split('.').next().unwrap_or(name))
+}
+
+/// What the `fileNameTemplate` placeholders stand for, for one diagram
+pub struct NameParts<'a> {
+    /// Document file name without its extension
+    pub doc: &'a str,
+    /// The diagram code, for `{hash}` and `{title}`
+    pub code: &'a str,
+    /// 1-based position of the fence among the document's fences
+    pub index: usize,
+    /// Render time as `%Y%m%d_%H%M%S`
+    pub timestamp: &'a str,
+    /// Hex hash of the code
+    pub hash: &'a str,
+}
+
+/// Expand `template`'s `{doc}`, `{hash}`, `{index}`, `{title}` and
+/// `{timestamp}` placeholders into a file name, rejecting unknown placeholders
+/// and names that aren't a single safe path component
+pub fn expand(template: &str, parts: &NameParts) -> Result<String, String> {
+    let mut name = String::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        name.push_str(&rest[..open]);
+        let close = rest[open..]
+            .find('}')
+            .map(|close| open + close)
+            .ok_or_else(|| format!("unclosed `{{` in file name template `{template}`"))?;
+        match &rest[open + 1..close] {
+            "doc" => name.push_str(parts.doc),
+            "hash" => name.push_str(parts.hash),
+            "index" => name.push_str(&parts.index.to_string()),
+            "timestamp" => name.push_str(parts.timestamp),
+            "title" => {
+                let title = anchors::diagram_title(parts.code).map(|title| anchors::slugify(&title));
+                name.push_str(title.as_deref().filter(|slug| !slug.is_empty()).unwrap_or("diagram"));
+            }
+            other => {
+                return Err(format!(
+                    "unknown placeholder `{{{other}}}` in file name template `{template}` \
+                     (expected doc, hash, index, title or timestamp)"
+                ))
+            }
+        }
+        rest = &rest[close + 1..];
+    }
+    name.push_str(rest);
+    check_file_name(&name).map_err(|reason| format!("file name template `{template}` gives `{name}`, {reason}"))?;
+    Ok(name)
+}
+
+/// Why `name` can't be used as a file name in the output directory, if it can't
+fn check_file_name(name: &str) -> Result<(), &'static str> {
+    if name.trim().is_empty() {
+        Err("which is empty")
+    } else if name.contains(['/', '\\']) {
+        Err("which contains a path separator")
+    } else if name.starts_with('.') {
+        Err("which is hidden or leaves the output directory")
+    } else if name.chars().any(|c| c.is_control() || matches!(c, ':' | '*' | '?' | '"' | '<' | '>' | '|')) {
+        Err("which contains characters not allowed in file names")
+    } else if name.len() > MAX_NAME_LEN {
+        Err("which is too long")
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(code: &str) -> NameParts<'_> {
+        NameParts {
+            doc: "README",
+            code,
+            index: 3,
+            timestamp: "20240102_030405",
+            hash: "00c0ffee00c0ffee",
+        }
+    }
+
+    #[test]
+    fn expands_placeholders() {
+        let code = "---\ntitle: Auth Flow\n---\ngraph TD\n  A-->B";
+        assert_eq!(expand(DEFAULT_FILE_NAME_TEMPLATE, &parts(code)).unwrap(), "README_diagram_00c0ffee00c0ffee");
+        assert_eq!(expand("{doc}-{index}", &parts(code)).unwrap(), "README-3");
+        assert_eq!(expand("{title}", &parts(code)).unwrap(), "auth-flow");
+        assert_eq!(expand("{title}", &parts("graph TD")).unwrap(), "diagram");
+        assert_eq!(expand("{doc}_{timestamp}", &parts(code)).unwrap(), "README_20240102_030405");
+        assert_eq!(expand("diagram", &parts(code)).unwrap(), "diagram");
+    }
+
+    #[test]
+    fn rejects_unsafe_or_unknown_templates() {
+        let rejected = [
+            ("../{doc}", "path separator"),
+            ("{doc}/{index}", "path separator"),
+            ("..\\{hash}", "path separator"),
+            ("..", "leaves the output directory"),
+            (".{doc}", "hidden"),
+            ("", "empty"),
+            ("{doc}:{index}", "not allowed"),
+            ("{name}", "unknown placeholder `{name}`"),
+            ("{doc", "unclosed"),
+        ];
+        for (template, reason) in rejected {
+            let err = expand(template, &parts("graph TD")).unwrap_err();
+            assert!(err.contains(reason), "{template}: {err}");
+        }
+        let traversal = parts("---\ntitle: ../../etc\n---\ngraph TD");
+        assert_eq!(expand("{title}", &traversal).unwrap(), "etc");
+        let doc = NameParts { doc: "..", ..parts("graph TD") };
+        assert!(expand("{doc}", &doc).is_err());
+    }
+
+    /// Why `name` can't be a file name on Unix, macOS or Windows, if it can't
+    fn invalid_anywhere(name: &str) -> Option<&'static str> {
+        let device = name.split('.').next().unwrap_or(name).to_ascii_lowercase();
+        if name.is_empty() || name == "." || name == ".." {
+            Some("empty or a directory reference")
+        } else if name.len() > 255 {
+            Some("too long")
+        } else if name.chars().any(|c| c.is_control() || "/\\:*?\"<>|".contains(c)) {
+            Some("reserved character")
+        } else if name.ends_with(['.', ' ']) || name.starts_with('.') {
+            Some("leading or trailing dot or space")
+        } else if WINDOWS_DEVICE_NAMES.contains(&device.as_str()) {
+            Some("Windows device name")
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn safe_stems_keep_plain_names() {
+        assert_eq!(safe_stem("guide"), "guide");
+        assert_eq!(safe_stem("release-notes_v2.1"), "release-notes_v2.1");
+        assert_eq!(safe_stem("設計"), "設計");
+    }
+
+    #[test]
+    fn safe_stems_strip_and_bound_unsafe_names() {
+        for stem in ["../weird", "README", "a\u{0}b", "con", "Aux.notes", "..", "", "trailing. ", &"long title ".repeat(20)] {
+            let safe = safe_stem(stem);
+            assert_eq!(invalid_anywhere(&safe), None, "{stem:?} -> {safe:?}");
+            assert!(safe.len() <= MAX_STEM_LEN + 9, "{safe}");
+            // Changed stems carry a hash of the original
+            assert!(safe.rsplit_once('-').is_some_and(|(_, hash)| hash.len() == 8), "{safe}");
+        }
+        assert!(safe_stem("../weird").starts_with("_weird-"));
+        assert!(safe_stem("README").starts_with("readme-"));
+        assert_ne!(safe_stem("README"), safe_stem("Readme"));
+        assert_eq!(safe_stem("README"), safe_stem("README"));
+    }
+
+    #[test]
+    fn adversarial_stems_always_give_valid_distinct_names() {
+        // Every string of up to three characters from an alphabet of the
+        // troublesome ones, plus long and multi-byte variants of each
+        let alphabet = ['.', '/', '\\', ':', ' ', '\u{0}', '\u{7f}', '?', 'A', 'a', 'é', '語', '_'];
+        let mut stems = vec![String::new()];
+        for _ in 0..3 {
+            let longer: Vec<String> = stems
+                .iter()
+                .flat_map(|stem| alphabet.iter().map(move |c| format!("{stem}{c}")))
+                .collect();
+            stems.extend(longer);
+        }
+        stems.sort();
+        stems.dedup();
+        let long: Vec<String> = stems.iter().map(|stem| format!("{stem}{}{stem}", "語x".repeat(40))).collect();
+        stems.extend(long);
+        stems.extend(WINDOWS_DEVICE_NAMES.iter().flat_map(|name| [name.to_string(), name.to_uppercase(), format!("{name}.md")]));
+
+        let mut seen = std::collections::HashMap::new();
+        for stem in &stems {
+            let safe = safe_stem(stem);
+            assert_eq!(invalid_anywhere(&safe), None, "{stem:?} -> {safe:?}");
+            assert!(safe.len() <= MAX_STEM_LEN + 9, "{stem:?} -> {safe:?}");
+            let full = format!("{safe}_diagram_00c0ffee00c0ffee.svg");
+            assert_eq!(invalid_anywhere(&full), None, "{full:?}");
+            // Distinct stems stay distinct even where case is ignored
+            if let Some(other) = seen.insert(safe.to_lowercase(), stem) {
+                panic!("{other:?} and {stem:?} both give {safe:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,408 @@
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use std::collections::{HashMap, HashSet};
+
+use crate::error::{ServerError, ServerResult};
+
+/// Decimal numbers with more than two fractional digits
+static LONG_DECIMAL: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"-?(?:\d+)?\.\d{3,}(?:[eE][-+]?\d+)?").expect("long decimal regex")
+});
+
+/// `url(#id)` references in attributes and stylesheets
+static URL_REFERENCE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"url\(\s*['"]?#([^)'"\s]+)['"]?\s*\)"#).expect("url reference regex")
+});
+
+/// Attributes holding coordinates, lengths or path data
+const NUMERIC_ATTRS: &[&str] = &[
+    "x", "y", "dx", "dy", "width", "height", "cx", "cy", "r", "rx", "ry", "x1", "y1", "x2", "y2",
+    "d", "points", "transform", "viewBox", "stroke-width", "font-size", "refX", "refY",
+    "markerWidth", "markerHeight",
+];
+
+/// Definitions that are compared for duplicates
+const DEDUPED_DEFS: &[&str] = &["linearGradient", "radialGradient", "marker"];
+
+/// Elements whose whitespace-only text is significant
+const TEXT_ELEMENTS: &[&str] = &["text", "tspan", "textPath", "style"];
+
+#[derive(Debug, Clone, PartialEq)]
+enum XmlNode {
+    Element(XmlElement),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct XmlElement {
+    /// Qualified name (`prefix:local`)
+    name: String,
+    attrs: Vec<(String, String)>,
+    children: Vec<XmlNode>,
+}
+
+impl XmlElement {
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn local_name(&self) -> &str {
+        self.name.rsplit(':').next().unwrap_or(&self.name)
+    }
+}
+
+/// Shrink a sanitized SVG: drop comments and insignificant whitespace, round
+/// numbers to two decimals, merge identical gradients/markers and remove unused
+/// definitions.
+pub fn optimize_svg(svg: &str) -> ServerResult<String> {
+    let mut root = parse(svg)?;
+
+    round_numbers(&mut root);
+    let duplicates = dedupe_defs(&mut root);
+    if !duplicates.is_empty() {
+        rewrite_references(&mut root, &duplicates);
+    }
+    let mut referenced = HashSet::new();
+    collect_references(&root, &mut referenced);
+    remove_unused_defs(&mut root, &referenced);
+
+    let mut out = String::with_capacity(svg.len());
+    write_element(&root, &mut out);
+    Ok(out)
+}
+
+fn parse(svg: &str) -> ServerResult<XmlElement> {
+    let options = roxmltree::ParsingOptions {
+        allow_dtd: true,
+        ..Default::default()
+    };
+    let doc = roxmltree::Document::parse_with_options(svg, options)
+        .map_err(|e| ServerError::RenderFailed(format!("SVG is not well-formed XML: {e}")))?;
+    Ok(convert(doc.root_element(), None))
+}
+
+/// Copy a roxmltree element into our mutable tree, dropping comments and
+/// whitespace between tags
+fn convert(node: roxmltree::Node, parent: Option<roxmltree::Node>) -> XmlElement {
+    let qualified = |prefix: Option<&str>, local: &str| match prefix {
+        Some(prefix) if !prefix.is_empty() => format!("{prefix}:{local}"),
+        _ => local.to_string(),
+    };
+
+    let mut attrs = Vec::new();
+    // Namespace declarations first, only where they come into scope
+    for ns in node.namespaces() {
+        let inherited = parent.is_some_and(|p| {
+            p.namespaces()
+                .any(|pns| pns.name() == ns.name() && pns.uri() == ns.uri())
+        });
+        if !inherited {
+            let name = match ns.name() {
+                Some(prefix) => format!("xmlns:{prefix}"),
+                None => "xmlns".to_string(),
+            };
+            attrs.push((name, ns.uri().to_string()));
+        }
+    }
+    for attr in node.attributes() {
+        let prefix = attr.namespace().and_then(|uri| node.lookup_prefix(uri));
+        attrs.push((qualified(prefix, attr.name()), attr.value().to_string()));
+    }
+
+    let name = node.tag_name();
+    let prefix = name.namespace().and_then(|uri| node.lookup_prefix(uri));
+    let keeps_whitespace = TEXT_ELEMENTS.contains(&name.name());
+
+    let children = node
+        .children()
+        .filter_map(|child| {
+            if child.is_element() {
+                Some(XmlNode::Element(convert(child, Some(node))))
+            } else if child.is_text() {
+                let text = child.text().unwrap_or_default();
+                (keeps_whitespace || !text.trim().is_empty()).then(|| XmlNode::Text(text.to_string()))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    XmlElement {
+        name: qualified(prefix, name.name()),
+        attrs,
+        children,
+    }
+}
+
+fn round_number(caps: &Captures) -> String {
+    let value: f64 = caps[0].parse().unwrap_or_default();
+    let rounded = format!("{value:.2}");
+    let trimmed = rounded.trim_end_matches('0').trim_end_matches('.');
+    match trimmed {
+        "-0" | "" => "0".to_string(),
+        other => other.to_string(),
+    }
+}
+
+fn round_numbers(element: &mut XmlElement) {
+    for (name, value) in &mut element.attrs {
+        let local = name.rsplit(':').next().unwrap_or(name);
+        if NUMERIC_ATTRS.contains(&local) && LONG_DECIMAL.is_match(value) {
+            *value = LONG_DECIMAL.replace_all(value, round_number).into_owned();
+        }
+    }
+    for child in &mut element.children {
+        if let XmlNode::Element(child) = child {
+            round_numbers(child);
+        }
+    }
+}
+
+/// Remove gradients/markers identical to an earlier one (ignoring their id).
+/// Returns the removed ids mapped to the id that replaces them.
+fn dedupe_defs(element: &mut XmlElement) -> HashMap<String, String> {
+    let mut duplicates = HashMap::new();
+    if element.local_name() == "defs" {
+        let mut seen: HashMap<String, String> = HashMap::new();
+        element.children.retain(|child| {
+            let XmlNode::Element(def) = child else {
+                return true;
+            };
+            let Some(id) = def.attr("id") else {
+                return true;
+            };
+            if !DEDUPED_DEFS.contains(&def.local_name()) {
+                return true;
+            }
+
+            let mut anonymous = def.clone();
+            anonymous.attrs.retain(|(n, _)| n != "id");
+            let mut key = String::new();
+            write_element(&anonymous, &mut key);
+            match seen.get(&key) {
+                Some(kept) => {
+                    duplicates.insert(id.to_string(), kept.clone());
+                    false
+                }
+                None => {
+                    seen.insert(key, id.to_string());
+                    true
+                }
+            }
+        });
+    }
+    for child in &mut element.children {
+        if let XmlNode::Element(child) = child {
+            duplicates.extend(dedupe_defs(child));
+        }
+    }
+    duplicates
+}
+
+fn is_href(name: &str) -> bool {
+    name == "href" || name.ends_with(":href")
+}
+
+fn rewrite_references(element: &mut XmlElement, replacements: &HashMap<String, String>) {
+    let rewrite_urls = |text: &str| {
+        URL_REFERENCE
+            .replace_all(text, |caps: &Captures| match replacements.get(&caps[1]) {
+                Some(kept) => format!("url(#{kept})"),
+                None => caps[0].to_string(),
+            })
+            .into_owned()
+    };
+
+    for (name, value) in &mut element.attrs {
+        if is_href(name) {
+            if let Some(kept) = value.strip_prefix('#').and_then(|id| replacements.get(id)) {
+                *value = format!("#{kept}");
+            }
+        } else if value.contains("url(") {
+            *value = rewrite_urls(value);
+        }
+    }
+    for child in &mut element.children {
+        match child {
+            XmlNode::Element(child) => rewrite_references(child, replacements),
+            XmlNode::Text(text) if text.contains("url(") => *text = rewrite_urls(text),
+            XmlNode::Text(_) => {}
+        }
+    }
+}
+
+fn collect_references(element: &XmlElement, referenced: &mut HashSet<String>) {
+    for (name, value) in &element.attrs {
+        if is_href(name) {
+            if let Some(id) = value.strip_prefix('#') {
+                referenced.insert(id.to_string());
+            }
+        }
+        for caps in URL_REFERENCE.captures_iter(value) {
+            referenced.insert(caps[1].to_string());
+        }
+    }
+    for child in &element.children {
+        match child {
+            XmlNode::Element(child) => collect_references(child, referenced),
+            XmlNode::Text(text) => {
+                for caps in URL_REFERENCE.captures_iter(text) {
+                    referenced.insert(caps[1].to_string());
+                }
+            }
+        }
+    }
+}
+
+/// Drop `<defs>` children with an id nothing points to, then empty `<defs>`
+fn remove_unused_defs(element: &mut XmlElement, referenced: &HashSet<String>) {
+    if element.local_name() == "defs" {
+        element.children.retain(|child| match child {
+            XmlNode::Element(def) => def.attr("id").is_none_or(|id| referenced.contains(id)),
+            XmlNode::Text(_) => true,
+        });
+    }
+    for child in &mut element.children {
+        if let XmlNode::Element(child) = child {
+            remove_unused_defs(child, referenced);
+        }
+    }
+    element.children.retain(|child| {
+        !matches!(child, XmlNode::Element(e) if e.local_name() == "defs" && e.children.is_empty())
+    });
+}
+
+fn write_element(element: &XmlElement, out: &mut String) {
+    out.push('<');
+    out.push_str(&element.name);
+    for (name, value) in &element.attrs {
+        out.push(' ');
+        out.push_str(name);
+        out.push_str("=\"");
+        out.push_str(&html_escape::encode_double_quoted_attribute(value));
+        out.push('"');
+    }
+    if element.children.is_empty() {
+        out.push_str("/>");
+        return;
+    }
+    out.push('>');
+    for child in &element.children {
+        match child {
+            XmlNode::Element(child) => write_element(child, out),
+            XmlNode::Text(text) => out.push_str(&html_escape::encode_text(text)),
+        }
+    }
+    out.push_str("</");
+    out.push_str(&element.name);
+    out.push('>');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = include_str!("../testdata/flowchart.svg");
+
+    /// Rendering-relevant view of an SVG: elements outside `<defs>` with their
+    /// attributes, where references are replaced by the referenced definition
+    fn normalized(svg: &str) -> String {
+        let root = parse(svg).unwrap();
+
+        fn defs_by_id(element: &XmlElement, defs: &mut HashMap<String, String>) {
+            for child in &element.children {
+                if let XmlNode::Element(child) = child {
+                    if element.local_name() == "defs" {
+                        if let Some(id) = child.attr("id") {
+                            let mut anonymous = child.clone();
+                            anonymous.attrs.retain(|(n, _)| n != "id");
+                            round_numbers(&mut anonymous);
+                            let mut key = String::new();
+                            write_element(&anonymous, &mut key);
+                            defs.insert(id.to_string(), key);
+                        }
+                    }
+                    defs_by_id(child, defs);
+                }
+            }
+        }
+
+        fn render(element: &XmlElement, defs: &HashMap<String, String>, out: &mut String) {
+            if element.local_name() == "defs" {
+                return;
+            }
+            let mut element = element.clone();
+            element.children.clear();
+            round_numbers(&mut element);
+            for (_, value) in &mut element.attrs {
+                *value = URL_REFERENCE
+                    .replace_all(value, |caps: &Captures| {
+                        format!("url({})", defs.get(&caps[1]).cloned().unwrap_or_default())
+                    })
+                    .into_owned();
+            }
+            write_element(&element, out);
+        }
+
+        fn walk(element: &XmlElement, defs: &HashMap<String, String>, out: &mut String) {
+            render(element, defs, out);
+            for child in &element.children {
+                match child {
+                    XmlNode::Element(child) if child.local_name() != "defs" => walk(child, defs, out),
+                    XmlNode::Element(_) => {}
+                    XmlNode::Text(text) => out.push_str(text.trim()),
+                }
+            }
+        }
+
+        let mut defs = HashMap::new();
+        defs_by_id(&root, &mut defs);
+        let mut out = String::new();
+        walk(&root, &defs, &mut out);
+        out
+    }
+
+    #[test]
+    fn fixture_shrinks_and_renders_the_same() {
+        let optimized = optimize_svg(FIXTURE).unwrap();
+
+        let saved = 1.0 - optimized.len() as f64 / FIXTURE.len() as f64;
+        assert!(saved > 0.3, "only saved {:.0}%", saved * 100.0);
+        assert_eq!(normalized(&optimized), normalized(FIXTURE));
+    }
+
+    #[test]
+    fn strips_comments_and_whitespace_but_not_text() {
+        let svg = "<svg xmlns=\"http://www.w3.org/2000/svg\">\n  <!-- generated -->\n  <g>\n    <text> a  b </text>\n  </g>\n</svg>";
+        assert_eq!(
+            optimize_svg(svg).unwrap(),
+            r#"<svg xmlns="http://www.w3.org/2000/svg"><g><text> a  b </text></g></svg>"#
+        );
+    }
+
+    #[test]
+    fn rounds_numeric_attributes_only() {
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg"><path d="M10.123456,20.5L-0.0001,3.999" data-value="1.23456"/></svg>"#;
+        assert_eq!(
+            optimize_svg(svg).unwrap(),
+            r#"<svg xmlns="http://www.w3.org/2000/svg"><path d="M10.12,20.5L0,4" data-value="1.23456"/></svg>"#
+        );
+    }
+
+    #[test]
+    fn merges_duplicate_markers_and_drops_unused_defs() {
+        let svg = r##"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink"><defs><marker id="a"><path d="M0,0"/></marker><marker id="b"><path d="M0,0"/></marker><linearGradient id="unused"/></defs><path marker-end="url(#b)"/><use xlink:href="#a"/></svg>"##;
+        assert_eq!(
+            optimize_svg(svg).unwrap(),
+            r##"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink"><defs><marker id="a"><path d="M0,0"/></marker></defs><path marker-end="url(#a)"/><use xlink:href="#a"/></svg>"##
+        );
+    }
+
+    #[test]
+    fn malformed_svg_is_an_error() {
+        assert!(optimize_svg("<svg><g></svg>").is_err());
+    }
+}
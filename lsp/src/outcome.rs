@@ -0,0 +1,291 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::error::ServerResult;
+
+/// Whether a command did what it was asked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Status {
+    /// Everything asked for was done
+    Ok,
+    /// Some of it was done; the command's fields say what failed
+    Partial,
+    /// None of it was done, e.g. for want of mermaid-cli, or a check failed
+    Failed,
+    /// There was nothing to do, or nothing was changed, e.g. with the cursor
+    /// outside every fence or in a read-only location
+    Unchanged,
+}
+
+impl Status {
+    /// The status of a command that did `done` things and failed `failed`
+    pub fn from_counts(done: usize, failed: usize) -> Self {
+        match (done, failed) {
+            (0, 0) => Status::Unchanged,
+            (_, 0) => Status::Ok,
+            (0, _) => Status::Failed,
+            _ => Status::Partial,
+        }
+    }
+}
+
+/// The result of every `workspace/executeCommand`. Stable for tooling:
+/// `status` and `message` are always there, and the command's own fields
+/// (`T`, always an object) sit beside them. A request the server can't run at
+/// all, such as one with invalid arguments, gets a JSON-RPC error instead.
+#[derive(Debug, Serialize)]
+pub struct CommandOutcome<T = Value> {
+    pub status: Status,
+    /// What happened, for people
+    pub message: String,
+    #[serde(flatten)]
+    pub result: T,
+}
+
+impl<T: Serialize> CommandOutcome<T> {
+    pub fn new(status: Status, message: impl Into<String>, result: T) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            result,
+        }
+    }
+
+    pub fn ok(message: impl Into<String>, result: T) -> Self {
+        Self::new(Status::Ok, message, result)
+    }
+
+    /// The outcome with its fields as a JSON object, so more can be added
+    pub fn erase(self) -> CommandOutcome {
+        let result = match serde_json::to_value(self.result) {
+            Ok(result @ Value::Object(_)) => result,
+            _ => Value::Object(Default::default()),
+        };
+        CommandOutcome::new(self.status, self.message, result)
+    }
+
+    pub fn to_value(&self) -> ServerResult<Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+}
+
+impl CommandOutcome {
+    /// Add the fields of `extra`, an object, replacing ones of the same name
+    pub fn extend(&mut self, extra: impl Serialize) {
+        if let (Ok(Value::Object(extra)), Value::Object(fields)) = (serde_json::to_value(extra), &mut self.result) {
+            fields.extend(extra);
+        }
+    }
+}
+
+/// No fields beyond the envelope's
+#[derive(Debug, Default, Serialize)]
+pub struct Empty {}
+
+/// Render commands: diagrams rendered and failed, and the files rendered
+/// blocks link to, as the document links them
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Rendered {
+    pub rendered: usize,
+    pub failed: usize,
+    pub files: Vec<String>,
+}
+
+/// `mermaid.editSingleSource` and `mermaid.editAllSources`: rendered
+/// diagrams turned back into fences
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Restored {
+    pub restored: usize,
+}
+
+/// `mermaid.revertLastRender`: fences restored, and the files nothing links
+/// to any more, deleted once the edit is applied
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Reverted {
+    pub reverted: usize,
+    pub deleted_files: usize,
+    pub freed_bytes: u64,
+}
+
+/// `mermaid.formatAll`: fences whose code changed
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Formatted {
+    pub formatted: usize,
+}
+
+/// `mermaid.generateIndex`: figures listed
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Indexed {
+    pub figures: usize,
+}
+
+/// `mermaid.adoptRenderedBlocks`, with each block's adoption
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Adopted<T> {
+    pub dry_run: bool,
+    pub adopted: usize,
+    pub failed: usize,
+    pub blocks: Vec<T>,
+}
+
+/// `mermaid.editSingleSource` when the client has to pick a block
+#[derive(Debug, Serialize)]
+pub struct Candidates<T> {
+    pub candidates: Vec<T>,
+}
+
+/// `mermaid.renderFiles`, one summary per file
+#[derive(Debug, Serialize)]
+pub struct Files<T> {
+    pub files: Vec<T>,
+}
+
+/// Render commands in a read-only location: self-contained images instead
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Previews {
+    pub read_only: String,
+    pub previews: Vec<Value>,
+}
+
+/// `mermaid.compareToReference`: the share of differing pixels, in percent,
+/// and whether it is within the threshold
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Compared {
+    pub difference: f64,
+    pub differing_pixels: u64,
+    pub total_pixels: u64,
+    pub threshold: f64,
+    pub passed: bool,
+    /// Link to the image of the difference, when one was asked for
+    pub diff_image: Option<String>,
+}
+
+/// `mermaid.copyAsMarkdown`
+#[derive(Debug, Serialize)]
+pub struct Markdown {
+    pub markdown: String,
+}
+
+/// `mermaid.liveEditorLink`
+#[derive(Debug, Serialize)]
+pub struct Link {
+    pub url: String,
+}
+
+/// Render commands when no mermaid-cli is installed: how to install it
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ToolNotFound {
+    pub error: &'static str,
+    pub install_hint: String,
+    pub docs_url: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn fields_sit_beside_status_and_message() {
+        let outcome = CommandOutcome::new(
+            Status::Partial,
+            "rendered 2 diagrams, 1 failed",
+            Rendered {
+                rendered: 2,
+                failed: 1,
+                files: vec![".mermaid/a.svg".to_string(), ".mermaid/b.svg".to_string()],
+            },
+        );
+        assert_eq!(
+            outcome.to_value().unwrap(),
+            json!({
+                "status": "partial",
+                "message": "rendered 2 diagrams, 1 failed",
+                "rendered": 2,
+                "failed": 1,
+                "files": [".mermaid/a.svg", ".mermaid/b.svg"],
+            })
+        );
+    }
+
+    #[test]
+    fn serializes_each_result_shape() {
+        let shape = |outcome: CommandOutcome| outcome.to_value().unwrap();
+        assert_eq!(
+            shape(CommandOutcome::ok("", Restored { restored: 3 }).erase()),
+            json!({ "status": "ok", "message": "", "restored": 3 })
+        );
+        assert_eq!(
+            shape(
+                CommandOutcome::ok(
+                    "",
+                    Reverted {
+                        reverted: 1,
+                        deleted_files: 2,
+                        freed_bytes: 512
+                    }
+                )
+                .erase()
+            ),
+            json!({ "status": "ok", "message": "", "reverted": 1, "deletedFiles": 2, "freedBytes": 512 })
+        );
+        assert_eq!(
+            shape(CommandOutcome::new(Status::Unchanged, "nothing to format", Formatted::default()).erase()),
+            json!({ "status": "unchanged", "message": "nothing to format", "formatted": 0 })
+        );
+        assert_eq!(
+            shape(CommandOutcome::ok("", Empty {}).erase()),
+            json!({ "status": "ok", "message": "" })
+        );
+        let tool = ToolNotFound {
+            error: "tool-not-found",
+            install_hint: "npm install -g @mermaid-js/mermaid-cli".to_string(),
+            docs_url: "https://example.com".to_string(),
+        };
+        assert_eq!(
+            shape(CommandOutcome::new(Status::Failed, "mmdc not found", tool).erase()),
+            json!({
+                "status": "failed",
+                "message": "mmdc not found",
+                "error": "tool-not-found",
+                "installHint": "npm install -g @mermaid-js/mermaid-cli",
+                "docsUrl": "https://example.com",
+            })
+        );
+        let adopted = Adopted {
+            dry_run: true,
+            adopted: 0,
+            failed: 0,
+            blocks: vec![json!({ "line": 3 })],
+        };
+        assert_eq!(
+            shape(CommandOutcome::ok("", adopted).erase()),
+            json!({ "status": "ok", "message": "", "dryRun": true, "adopted": 0, "failed": 0, "blocks": [{ "line": 3 }] })
+        );
+    }
+
+    #[test]
+    fn extends_with_more_fields() {
+        let mut outcome = CommandOutcome::ok("formatted", Formatted { formatted: 1 }).erase();
+        outcome.extend(json!({ "uri": "file:///a.md", "edits": 1 }));
+        outcome.extend("not an object");
+        assert_eq!(
+            outcome.to_value().unwrap(),
+            json!({ "status": "ok", "message": "formatted", "formatted": 1, "uri": "file:///a.md", "edits": 1 })
+        );
+    }
+
+    #[test]
+    fn status_follows_the_counts() {
+        assert_eq!(Status::from_counts(0, 0), Status::Unchanged);
+        assert_eq!(Status::from_counts(2, 0), Status::Ok);
+        assert_eq!(Status::from_counts(2, 1), Status::Partial);
+        assert_eq!(Status::from_counts(0, 1), Status::Failed);
+    }
+}
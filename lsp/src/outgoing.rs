@@ -0,0 +1,143 @@
+use log::warn;
+use lsp_server::{Request, RequestId};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Numbers every request the server sends, so no two share an id in a
+/// session, however close together they go out
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A number no other request or token of this session has
+pub fn next_number() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A fresh id for a request to the client
+pub fn next_id() -> RequestId {
+    RequestId::from(format!("mermaid-{}", next_number()))
+}
+
+struct Entry<T> {
+    method: String,
+    deadline: Instant,
+    context: T,
+}
+
+/// Requests sent to the client, each with what its answer is for, until the
+/// answer arrives or the request times out
+pub struct OutgoingRequests<T> {
+    pending: HashMap<RequestId, Entry<T>>,
+}
+
+impl<T> Default for OutgoingRequests<T> {
+    fn default() -> Self {
+        Self {
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl<T> OutgoingRequests<T> {
+    /// A `method` request to send, remembering `context` for its answer for
+    /// up to `timeout`
+    pub fn request(&mut self, method: &str, params: Value, context: T, timeout: Duration) -> Request {
+        let id = next_id();
+        self.pending.insert(
+            id.clone(),
+            Entry {
+                method: method.to_string(),
+                deadline: Instant::now() + timeout,
+                context,
+            },
+        );
+        Request::new(id, method.to_string(), params)
+    }
+
+    /// The context of the request `id` answers, which is then forgotten. None
+    /// for unknown, already answered and timed out requests.
+    pub fn take(&mut self, id: &RequestId) -> Option<T> {
+        self.pending.remove(id).map(|entry| entry.context)
+    }
+
+    /// Forget the requests whose time ran out by `now`, with a warning for
+    /// each, and return their contexts. A late answer to one is ignored.
+    pub fn expire(&mut self, now: Instant) -> Vec<T> {
+        let expired: Vec<RequestId> = self
+            .pending
+            .iter()
+            .filter(|(_, entry)| entry.deadline <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|id| {
+                let entry = self.pending.remove(&id)?;
+                warn!("The client never answered {} request {id}; giving up on it", entry.method);
+                Some(entry.context)
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn ids_are_unique_across_registries_and_threads() {
+        let mut first = OutgoingRequests::default();
+        let mut second = OutgoingRequests::default();
+        let mut ids: Vec<RequestId> = (0..100)
+            .flat_map(|i| {
+                [
+                    first.request("workspace/applyEdit", Value::Null, i, Duration::from_secs(60)).id,
+                    second.request("window/showMessageRequest", Value::Null, i, Duration::from_secs(60)).id,
+                ]
+            })
+            .collect();
+        let threads: Vec<_> = (0..4).map(|_| std::thread::spawn(|| (0..100).map(|_| next_id()).collect::<Vec<_>>())).collect();
+        for thread in threads {
+            ids.extend(thread.join().unwrap());
+        }
+        let unique: HashSet<&RequestId> = ids.iter().collect();
+        assert_eq!(unique.len(), ids.len());
+        assert_eq!(first.pending.len(), 100);
+    }
+
+    #[test]
+    fn answers_match_their_request_once() {
+        let mut outgoing = OutgoingRequests::default();
+        let edit = outgoing.request("workspace/applyEdit", Value::Null, "edit", Duration::from_secs(60));
+        let prompt = outgoing.request("window/showMessageRequest", Value::Null, "prompt", Duration::from_secs(60));
+        assert_eq!(outgoing.take(&prompt.id), Some("prompt"));
+        assert_eq!(outgoing.take(&prompt.id), None);
+        assert_eq!(outgoing.take(&RequestId::from(1)), None);
+        assert_eq!(outgoing.take(&edit.id), Some("edit"));
+        assert!(outgoing.is_empty());
+    }
+
+    #[test]
+    fn unanswered_requests_time_out() {
+        let mut outgoing = OutgoingRequests::default();
+        let quick = outgoing.request("workspace/applyEdit", Value::Null, "edit", Duration::from_secs(60));
+        let slow = outgoing.request("window/showMessageRequest", Value::Null, "prompt", Duration::from_secs(3600));
+
+        assert!(outgoing.expire(Instant::now()).is_empty());
+        let later = Instant::now() + Duration::from_secs(120);
+        assert_eq!(outgoing.expire(later), ["edit"]);
+        assert_eq!(outgoing.take(&quick.id), None);
+        assert_eq!(outgoing.pending.len(), 1);
+        assert_eq!(outgoing.expire(later + Duration::from_secs(3600)), ["prompt"]);
+        assert_eq!(outgoing.take(&slow.id), None);
+        assert!(outgoing.is_empty());
+    }
+}
@@ -0,0 +1,382 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+/// Resolve `.` and `..` without touching the filesystem
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                // Nothing is above the root
+                Some(Component::RootDir | Component::Prefix(_)) => {}
+                // Leading `..` of a relative path are kept
+                _ => normalized.push(".."),
+            },
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+/// Path syntax of a platform. Paths are handled as strings in either syntax,
+/// so Windows paths (drives, UNC shares, `\\?\` long paths) are handled and
+/// tested the same on every platform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathStyle {
+    Unix,
+    Windows,
+}
+
+impl PathStyle {
+    /// The syntax of this platform's paths
+    pub const NATIVE: Self = if cfg!(windows) { Self::Windows } else { Self::Unix };
+
+    fn is_separator(self, c: char) -> bool {
+        c == '/' || (self == Self::Windows && c == '\\')
+    }
+}
+
+/// `path` without the Windows verbatim prefix that `canonicalize` adds:
+/// `\\?\C:\docs` becomes `C:\docs` and `\\?\UNC\server\share` becomes
+/// `\\server\share`. Other paths, including verbatim ones that have no plain
+/// form (e.g. `\\?\Volume{..}`), are returned as they are.
+pub fn strip_verbatim(path: &str) -> String {
+    let Some(rest) = path.strip_prefix(r"\\?\").or_else(|| path.strip_prefix("//?/")) else {
+        return path.to_string();
+    };
+    if let Some(share) = rest.strip_prefix(r"UNC\").or_else(|| rest.strip_prefix("UNC/")) {
+        return format!(r"\\{share}");
+    }
+    if drive_prefix(rest).is_some() {
+        return rest.to_string();
+    }
+    path.to_string()
+}
+
+/// The `C:` drive prefix of a Windows path, if it starts with one
+fn drive_prefix(path: &str) -> Option<&str> {
+    let bytes = path.as_bytes();
+    (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':').then(|| &path[..2])
+}
+
+/// Split `path` into its root, with `/` separators and an upper-case drive
+/// letter (`/`, `C:/`, `//server/share/`, or empty when relative), and its
+/// components with `.` and `..` resolved
+fn split_root(path: &str, style: PathStyle) -> (String, Vec<String>) {
+    let path = match style {
+        PathStyle::Windows => strip_verbatim(path),
+        PathStyle::Unix => path.to_string(),
+    };
+    let sep = |c: char| style.is_separator(c);
+    let (root, rest) = match style {
+        PathStyle::Windows if path.starts_with(sep) && path[1..].starts_with(sep) => {
+            // `\\server\share` roots the path at the share
+            let mut parts = path[2..].splitn(3, sep);
+            let server = parts.next().unwrap_or_default();
+            let share = parts.next().unwrap_or_default();
+            (format!("//{server}/{share}/"), parts.next().unwrap_or_default().to_string())
+        }
+        PathStyle::Windows if drive_prefix(&path).is_some() => {
+            let drive = path[..2].to_ascii_uppercase();
+            match path[2..].strip_prefix(sep) {
+                Some(rest) => (format!("{drive}/"), rest.to_string()),
+                // `C:docs` is relative to the drive's current directory
+                None => (drive, path[2..].to_string()),
+            }
+        }
+        _ => match path.strip_prefix(sep) {
+            Some(rest) => ("/".to_string(), rest.to_string()),
+            None => (String::new(), path),
+        },
+    };
+
+    let mut components: Vec<String> = Vec::new();
+    for component in rest.split(sep) {
+        match component {
+            "" | "." => {}
+            ".." => match components.last() {
+                Some(last) if last != ".." => {
+                    components.pop();
+                }
+                // Nothing is above the root; leading `..` of a relative path are kept
+                _ if !root.is_empty() => {}
+                _ => components.push("..".to_string()),
+            },
+            other => components.push(other.to_string()),
+        }
+    }
+    (root, components)
+}
+
+/// `path` as written in markdown: `.` and `..` resolved, `/` separators, no
+/// verbatim prefix, and UNC shares as `//server/share/...`
+pub fn markdown_path(path: &str, style: PathStyle) -> String {
+    let (root, components) = split_root(path, style);
+    let joined = format!("{root}{}", components.join("/"));
+    if joined.is_empty() {
+        ".".to_string()
+    } else {
+        joined
+    }
+}
+
+/// `path` as shown to users and used from here on: without the Windows
+/// verbatim prefix, which std adds back by itself for paths too long for the
+/// plain form
+pub fn simplify(path: &Path) -> PathBuf {
+    match (PathStyle::NATIVE, path.to_str()) {
+        (PathStyle::Windows, Some(text)) => PathBuf::from(strip_verbatim(text)),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// `path` with symlinks resolved as far as it exists. The part that doesn't
+/// exist yet (e.g. an output directory before the first render) is appended
+/// to its nearest existing ancestor rather than failing `canonicalize`.
+pub fn resolve(path: &Path) -> PathBuf {
+    let path = normalize(path);
+    for ancestor in path.ancestors().filter(|a| !a.as_os_str().is_empty()) {
+        if let Ok(real) = ancestor.canonicalize() {
+            let rest = path.strip_prefix(ancestor).unwrap_or(Path::new(""));
+            let resolved = if rest.as_os_str().is_empty() { real } else { real.join(rest) };
+            return simplify(&resolved);
+        }
+    }
+    simplify(&path)
+}
+
+/// `link` (relative to `base_dir`, or absolute) as a path, with `..` leaving
+/// directories as they are named: a symlinked directory's `..` is the
+/// directory holding the link, not its target's parent as the OS would have
+/// it. Rendered files are placed and looked up this way, so they are where
+/// the document's links point.
+pub fn logical_join(base_dir: &Path, link: impl AsRef<Path>) -> PathBuf {
+    simplify(&normalize(&base_dir.join(link)))
+}
+
+/// Whether `path` is inside `root`, compared with symlinks resolved in both:
+/// a symlink out of `root` leads outside it, and a `root` named through a
+/// symlink still contains the files under its target
+pub fn is_within(path: &Path, root: &Path) -> bool {
+    resolve(path).starts_with(resolve(root))
+}
+
+/// Markdown link from a document in `from_dir` to `target`, e.g.
+/// `../../.mermaid/doc.svg`.
+///
+/// Always uses `/`. Falls back to the absolute target when the two paths have
+/// different roots (e.g. other Windows drives) and no relative link exists.
+pub fn relative_link(from_dir: &Path, target: &Path) -> String {
+    relative_link_in(PathStyle::NATIVE, &from_dir.to_string_lossy(), &target.to_string_lossy())
+}
+
+/// [`relative_link`] between paths in `style`'s syntax
+pub fn relative_link_in(style: PathStyle, from_dir: &str, target: &str) -> String {
+    let (from_root, from) = split_root(from_dir, style);
+    let (target_root, target_parts) = split_root(target, style);
+    let same_root = match style {
+        // Drive letters are upper-cased already; server and share names are
+        // case-insensitive too
+        PathStyle::Windows => from_root.eq_ignore_ascii_case(&target_root),
+        PathStyle::Unix => from_root == target_root,
+    };
+    if !same_root {
+        return markdown_path(target, style);
+    }
+
+    let common = from.iter().zip(&target_parts).take_while(|(a, b)| a == b).count();
+    let mut parts: Vec<&str> = vec![".."; from.len() - common];
+    parts.extend(target_parts[common..].iter().map(String::as_str));
+    if parts.is_empty() {
+        return ".".to_string();
+    }
+    parts.join("/")
+}
+
+/// Why `document` can't be rendered in place with its files in `output_dir`:
+/// the document is read-only, or so is the output directory (or, when it
+/// doesn't exist yet, the directory it would be created in). The check writes
+/// and removes a probe file, so a read-only mount is caught as well, and leaves
+/// nothing behind.
+pub fn read_only_reason(document: &Path, output_dir: &Path) -> Option<String> {
+    if fs::metadata(document).is_ok_and(|m| m.permissions().readonly()) {
+        return Some(format!("{} is read-only", document.display()));
+    }
+    let dir = output_dir.ancestors().find(|dir| dir.is_dir())?;
+    (!is_writable_dir(dir)).then(|| format!("{} is read-only", dir.display()))
+}
+
+fn is_writable_dir(dir: &Path) -> bool {
+    if fs::metadata(dir).is_ok_and(|m| m.permissions().readonly()) {
+        return false;
+    }
+    let probe = dir.join(format!(".mermaid-write-probe-{}", std::process::id()));
+    match fs::OpenOptions::new().write(true).create_new(true).open(&probe) {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+            true
+        }
+        // A leftover probe from a crashed server still proves the directory writable
+        Err(e) => e.kind() == std::io::ErrorKind::AlreadyExists,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(from_dir: &str, target: &str) -> String {
+        relative_link(Path::new(from_dir), Path::new(target))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn detects_read_only_locations() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let document = dir.path().join("doc.md");
+        fs::write(&document, "# Doc").unwrap();
+        let output = dir.path().join(".mermaid");
+        assert_eq!(read_only_reason(&document, &output), None);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        fs::set_permissions(&document, fs::Permissions::from_mode(0o444)).unwrap();
+        assert!(read_only_reason(&document, &output).unwrap().contains("doc.md"));
+        fs::set_permissions(&document, fs::Permissions::from_mode(0o644)).unwrap();
+
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o555)).unwrap();
+        let reason = read_only_reason(&document, &output);
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(reason, Some(format!("{} is read-only", dir.path().display())));
+    }
+
+    #[test]
+    fn output_below_the_document() {
+        assert_eq!(link("/work/docs", "/work/docs/.mermaid/a.svg"), ".mermaid/a.svg");
+    }
+
+    #[test]
+    fn output_at_the_worktree_root() {
+        assert_eq!(link("/work/docs/guide", "/work/.mermaid/a.svg"), "../../.mermaid/a.svg");
+        assert_eq!(link("/work", "/work/.mermaid/a.svg"), ".mermaid/a.svg");
+    }
+
+    #[test]
+    fn output_in_a_sibling_tree() {
+        assert_eq!(link("/work/docs/api", "/work/assets/diagrams/a.svg"), "../../assets/diagrams/a.svg");
+    }
+
+    #[test]
+    fn only_the_root_in_common() {
+        assert_eq!(link("/home/me/notes", "/tmp/mermaid/a.svg"), "../../../tmp/mermaid/a.svg");
+    }
+
+    #[test]
+    fn dot_segments_are_resolved() {
+        assert_eq!(link("/work/docs/./guide/..", "/work/docs/../out/a.svg"), "../out/a.svg");
+        assert_eq!(normalize(Path::new("../a/./b/../c")), PathBuf::from("../a/c"));
+        assert_eq!(normalize(Path::new("/../a")), PathBuf::from("/a"));
+    }
+
+    #[test]
+    fn relative_inputs() {
+        assert_eq!(link("docs", "out/a.svg"), "../out/a.svg");
+    }
+
+    fn windows_link(from_dir: &str, target: &str) -> String {
+        relative_link_in(PathStyle::Windows, from_dir, target)
+    }
+
+    #[test]
+    fn windows_paths_use_forward_slashes() {
+        assert_eq!(windows_link(r"C:\work\docs", r"C:\work\.mermaid\a.svg"), "../.mermaid/a.svg");
+        assert_eq!(windows_link(r"C:\work\docs", r"D:\out\a.svg"), "D:/out/a.svg");
+        assert_eq!(windows_link(r"c:\work\docs", r"C:/work/docs/.mermaid/a.svg"), ".mermaid/a.svg");
+    }
+
+    #[test]
+    fn verbatim_prefixes_are_stripped() {
+        assert_eq!(strip_verbatim(r"\\?\C:\work\docs"), r"C:\work\docs");
+        assert_eq!(strip_verbatim(r"\\?\UNC\server\share\docs"), r"\\server\share\docs");
+        assert_eq!(strip_verbatim(r"\\?\Volume{1234}\docs"), r"\\?\Volume{1234}\docs");
+        assert_eq!(strip_verbatim(r"\\server\share"), r"\\server\share");
+        assert_eq!(strip_verbatim("/work/docs"), "/work/docs");
+    }
+
+    #[test]
+    fn windows_paths_in_markdown() {
+        let markdown = |path| markdown_path(path, PathStyle::Windows);
+        assert_eq!(markdown(r"\\?\C:\work\docs\..\a.svg"), "C:/work/a.svg");
+        assert_eq!(markdown(r"\\?\UNC\server\share\docs\a.svg"), "//server/share/docs/a.svg");
+        assert_eq!(markdown(r"\\server\share\..\..\a.svg"), "//server/share/a.svg");
+        assert_eq!(markdown(r".mermaid\a.svg"), ".mermaid/a.svg");
+        // Backslashes are file name characters on unix
+        assert_eq!(markdown_path(r"/work/a\b.svg", PathStyle::Unix), r"/work/a\b.svg");
+    }
+
+    #[test]
+    fn links_on_unc_shares_and_long_paths() {
+        let share = r"\\server\share\docs";
+        assert_eq!(windows_link(share, r"\\server\share\docs\.mermaid\a.svg"), ".mermaid/a.svg");
+        assert_eq!(windows_link(share, r"\\?\UNC\server\share\.mermaid\a.svg"), "../.mermaid/a.svg");
+        assert_eq!(windows_link(share, r"\\SERVER\Share\out\a.svg"), "../out/a.svg");
+        // Another share has no relative link
+        assert_eq!(windows_link(share, r"\\server\other\a.svg"), "//server/other/a.svg");
+        assert_eq!(windows_link(share, r"C:\out\a.svg"), "C:/out/a.svg");
+
+        assert_eq!(windows_link(r"\\?\C:\work\docs", r"C:\work\docs\.mermaid\a.svg"), ".mermaid/a.svg");
+        assert_eq!(windows_link(r"C:\work\docs", r"\\?\C:\work\.mermaid\a.svg"), "../.mermaid/a.svg");
+    }
+
+    #[test]
+    fn resolves_paths_that_do_not_exist_yet() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().canonicalize().unwrap();
+        assert_eq!(resolve(dir.path()), simplify(&real));
+        assert_eq!(resolve(&dir.path().join("docs/../.mermaid/.cache")), simplify(&real.join(".mermaid/.cache")));
+    }
+
+    /// `<tmp>/content/docs`, and `<tmp>/work/docs` linking to it
+    #[cfg(unix)]
+    fn symlinked_docs() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("content/docs");
+        let link = dir.path().join("work/docs");
+        fs::create_dir_all(&real).unwrap();
+        fs::create_dir_all(link.parent().unwrap()).unwrap();
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+        (dir, real, link)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_leave_symlinked_dirs_as_named() {
+        let (dir, _, link) = symlinked_docs();
+        let work = dir.path().join("work");
+        assert_eq!(logical_join(&link, ".mermaid/a.svg"), link.join(".mermaid/a.svg"));
+        // The OS would go to `content/assets`
+        assert_eq!(logical_join(&link, "../assets/a.svg"), work.join("assets/a.svg"));
+        assert_eq!(logical_join(&link, "/abs/a.svg"), PathBuf::from("/abs/a.svg"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn containment_compares_resolved_paths() {
+        let (dir, real, link) = symlinked_docs();
+        let content = dir.path().join("content");
+        // Either spelling of the document is in the content tree
+        assert!(is_within(&link.join("a.md"), &content));
+        assert!(is_within(&real.join("a.md"), &link));
+        assert!(is_within(&link.join(".mermaid/new.svg"), &real));
+        // The link leads out of `work`, and `..` can't climb out of the root
+        assert!(!is_within(&link.join("a.md"), &dir.path().join("work")));
+        assert!(!is_within(&real.join("../../a.md"), &content));
+    }
+}
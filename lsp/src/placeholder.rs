@@ -0,0 +1,121 @@
+use crate::diagnostics::diagram_keyword;
+
+/// Width of the placeholder image
+const WIDTH: usize = 480;
+const PADDING: usize = 16;
+/// Height of one line of the wrapped error message
+const LINE_HEIGHT: usize = 16;
+/// Characters per line of the wrapped error message, at its monospace size
+const WRAP_COLUMNS: usize = 64;
+/// Message lines shown before the rest is cut off with an ellipsis
+const MAX_MESSAGE_LINES: usize = 12;
+
+/// Value of the `data-mermaid-placeholder` attribute on the root element, so
+/// tools can tell a placeholder from a rendered diagram
+const PLACEHOLDER_MARKER: &str = "render-failed";
+
+/// A small SVG standing in for a diagram that failed to render: a red-bordered
+/// card headed "Render failed" with the diagram type, the failing line of the
+/// fence (1-based) when known, and the error message wrapped to fit
+pub fn error_svg(code: &str, message: &str, line: Option<usize>) -> String {
+    let mut message_lines = wrap(message, WRAP_COLUMNS);
+    if message_lines.len() > MAX_MESSAGE_LINES {
+        message_lines.truncate(MAX_MESSAGE_LINES);
+        message_lines[MAX_MESSAGE_LINES - 1].push('…');
+    }
+    let subtitle = match line {
+        Some(line) => format!("{} diagram, line {line}", diagram_keyword(code)),
+        None => format!("{} diagram", diagram_keyword(code)),
+    };
+
+    let message_top = PADDING + 56;
+    let height = message_top + message_lines.len() * LINE_HEIGHT + PADDING;
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{WIDTH}\" height=\"{height}\" \
+         viewBox=\"0 0 {WIDTH} {height}\" data-mermaid-placeholder=\"{PLACEHOLDER_MARKER}\">\
+         <rect x=\"1.5\" y=\"1.5\" width=\"{}\" height=\"{}\" rx=\"6\" fill=\"#fff5f5\" stroke=\"#d32f2f\" stroke-width=\"3\"/>\
+         <text x=\"{PADDING}\" y=\"{}\" font-family=\"sans-serif\" font-size=\"18\" font-weight=\"bold\" fill=\"#b71c1c\">Render failed</text>\
+         <text x=\"{PADDING}\" y=\"{}\" font-family=\"sans-serif\" font-size=\"13\" fill=\"#5f2120\">{}</text>",
+        WIDTH - 3,
+        height - 3,
+        PADDING + 18,
+        PADDING + 40,
+        escape(&subtitle),
+    );
+    for (i, text) in message_lines.iter().enumerate() {
+        svg.push_str(&format!(
+            "<text x=\"{PADDING}\" y=\"{}\" font-family=\"monospace\" font-size=\"12\" fill=\"#212121\" xml:space=\"preserve\">{}</text>",
+            message_top + i * LINE_HEIGHT,
+            escape(text)
+        ));
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+/// Whether `svg` is an error placeholder rather than a rendered diagram
+#[cfg(test)]
+pub fn is_placeholder(svg: &str) -> bool {
+    svg.contains(&format!("data-mermaid-placeholder=\"{PLACEHOLDER_MARKER}\""))
+}
+
+/// `message` broken into lines of at most `columns` characters, at spaces
+/// where possible; its own line breaks are kept
+fn wrap(message: &str, columns: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in message.lines() {
+        // Keep the indent, which lines up the `^` under mermaid's error excerpts
+        let words = paragraph.trim_start();
+        let mut line = paragraph[..paragraph.len() - words.len()].to_string();
+        for word in words.split(' ') {
+            let mut word = word;
+            while word.chars().count() > columns {
+                if !line.trim().is_empty() {
+                    lines.push(std::mem::take(&mut line));
+                }
+                let split = word.char_indices().nth(columns).map_or(word.len(), |(i, _)| i);
+                lines.push(word[..split].to_string());
+                word = &word[split..];
+            }
+            if !line.trim().is_empty() && line.chars().count() + 1 + word.chars().count() > columns {
+                lines.push(std::mem::take(&mut line));
+            } else if !line.trim().is_empty() {
+                line.push(' ');
+            }
+            line.push_str(word);
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_long_messages_at_spaces() {
+        assert_eq!(wrap("Parse error on line 2:\n  A-->", 12), ["Parse error", "on line 2:", "  A-->"]);
+        assert_eq!(wrap("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+        assert!(wrap(&"word ".repeat(100), WRAP_COLUMNS).iter().all(|line| line.chars().count() <= WRAP_COLUMNS));
+    }
+
+    #[test]
+    fn placeholder_names_the_failure() {
+        let svg = error_svg("---\ntitle: x\n---\n%% c\nsequenceDiagram\n  A->>", "Parse error <on> line 2", Some(6));
+        assert!(is_placeholder(&svg));
+        assert!(svg.contains("Render failed"));
+        assert!(svg.contains("stroke=\"#d32f2f\""));
+        assert!(svg.contains("sequenceDiagram diagram, line 6"), "{svg}");
+        assert!(svg.contains("Parse error &lt;on&gt; line 2"));
+        assert!(roxmltree::Document::parse(&svg).is_ok());
+
+        let long = error_svg("graph TD", &"error\n".repeat(40), None);
+        assert_eq!(long.matches("font-family=\"monospace\"").count(), MAX_MESSAGE_LINES);
+        assert!(long.contains("graph diagram<") && long.contains("error…"));
+    }
+}
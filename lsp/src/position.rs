@@ -0,0 +1,82 @@
+use lsp_types::{ClientCapabilities, PositionEncodingKind};
+
+/// Unit used for the `character` offsets of LSP positions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PositionEncoding {
+    /// Byte offsets
+    Utf8,
+    /// UTF-16 code units (the LSP default)
+    #[default]
+    Utf16,
+}
+
+impl PositionEncoding {
+    /// Prefer UTF-8 (our native string offsets) when the client offers it
+    pub fn negotiate(caps: &ClientCapabilities) -> Self {
+        let offered = caps
+            .general
+            .as_ref()
+            .and_then(|g| g.position_encodings.as_ref());
+        match offered {
+            Some(kinds) if kinds.contains(&PositionEncodingKind::UTF8) => Self::Utf8,
+            _ => Self::Utf16,
+        }
+    }
+
+    pub fn kind(self) -> PositionEncodingKind {
+        match self {
+            Self::Utf8 => PositionEncodingKind::UTF8,
+            Self::Utf16 => PositionEncodingKind::UTF16,
+        }
+    }
+
+    /// Length of a line in this encoding, i.e. the character offset of its end
+    pub fn line_len(self, line: &str) -> u32 {
+        match self {
+            Self::Utf8 => line.len() as u32,
+            Self::Utf16 => line.encode_utf16().count() as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_types::GeneralClientCapabilities;
+
+    fn offering(kinds: Vec<PositionEncodingKind>) -> ClientCapabilities {
+        ClientCapabilities {
+            general: Some(GeneralClientCapabilities {
+                position_encodings: Some(kinds),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn negotiates_utf8_only_when_offered() {
+        assert_eq!(
+            PositionEncoding::negotiate(&offering(vec![
+                PositionEncodingKind::UTF16,
+                PositionEncodingKind::UTF8
+            ])),
+            PositionEncoding::Utf8
+        );
+        assert_eq!(
+            PositionEncoding::negotiate(&offering(vec![PositionEncodingKind::UTF16])),
+            PositionEncoding::Utf16
+        );
+        assert_eq!(
+            PositionEncoding::negotiate(&ClientCapabilities::default()),
+            PositionEncoding::Utf16
+        );
+    }
+
+    #[test]
+    fn measures_lines_per_encoding() {
+        let line = "A[図 🎉]";
+        assert_eq!(PositionEncoding::Utf8.line_len(line), 11);
+        assert_eq!(PositionEncoding::Utf16.line_len(line), 7);
+    }
+}
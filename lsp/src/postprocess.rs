@@ -0,0 +1,174 @@
+use std::{
+    io::{Read, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::config::Config;
+use crate::error::{ServerError, ServerResult};
+
+/// Largest SVG accepted from the hook
+pub const MAX_OUTPUT_BYTES: usize = 10 * 1024 * 1024;
+
+/// User command that rewrites rendered SVGs (e.g. `["svgo", "-i", "-", "-o", "-"]`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct PostProcess {
+    pub program: String,
+    pub args: Vec<String>,
+    pub timeout: Duration,
+    pub max_output_bytes: usize,
+}
+
+impl PostProcess {
+    /// The configured hook, if `postProcessCommand` is set
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let (program, args) = config.post_process_command.split_first()?;
+        Some(Self {
+            program: program.clone(),
+            args: args.to_vec(),
+            timeout: Duration::from_millis(config.post_process_timeout_ms),
+            max_output_bytes: MAX_OUTPUT_BYTES,
+        })
+    }
+
+    /// Pipe `svg` through the command and return its stdout
+    pub fn run(&self, svg: &str) -> ServerResult<String> {
+        let program = self.resolve()?;
+        let mut child = Command::new(program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(ServerError::io(format!("Failed to run {}", self.program)))?;
+
+        let mut stdin = child.stdin.take().expect("piped stdin");
+        let input = svg.to_string();
+        let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+
+        // Read one byte past the cap so oversized output can be detected
+        let stdout = child.stdout.take().expect("piped stdout");
+        let limit = self.max_output_bytes as u64 + 1;
+        let reader = thread::spawn(move || {
+            let mut output = Vec::new();
+            stdout.take(limit).read_to_end(&mut output).map(|_| output)
+        });
+
+        let started = Instant::now();
+        let status = loop {
+            if let Some(status) = child
+                .try_wait()
+                .map_err(ServerError::io(format!("Failed to wait for {}", self.program)))?
+            {
+                break status;
+            }
+            if started.elapsed() > self.timeout {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(ServerError::RenderFailed(format!(
+                    "{} timed out after {} ms",
+                    self.program,
+                    self.timeout.as_millis()
+                )));
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        // A hook that exits without reading its input is not an error by itself
+        let _ = writer.join();
+        let output = reader
+            .join()
+            .map_err(|_| ServerError::RenderFailed(format!("Failed to read output of {}", self.program)))?
+            .map_err(ServerError::io(format!("Failed to read output of {}", self.program)))?;
+
+        if !status.success() {
+            return Err(ServerError::RenderFailed(format!("{} exited with {status}", self.program)));
+        }
+        if output.len() > self.max_output_bytes {
+            return Err(ServerError::RenderFailed(format!(
+                "{} produced more than {} bytes",
+                self.program, self.max_output_bytes
+            )));
+        }
+        let svg = String::from_utf8(output).map_err(|_| {
+            ServerError::RenderFailed(format!("{} produced output that is not UTF-8", self.program))
+        })?;
+        if svg.trim().is_empty() {
+            return Err(ServerError::RenderFailed(format!("{} produced no output", self.program)));
+        }
+        Ok(svg)
+    }
+
+    /// Check the program exists before running it
+    fn resolve(&self) -> ServerResult<PathBuf> {
+        which::which(&self.program).map_err(|_| {
+            ServerError::ToolNotFound(format!("postProcessCommand `{}` not found", self.program))
+        })
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    const SVG: &str = r#"<svg xmlns="http://www.w3.org/2000/svg"><rect/></svg>"#;
+
+    fn hook(program: &str, args: &[&str]) -> PostProcess {
+        PostProcess {
+            program: program.to_string(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            timeout: Duration::from_secs(5),
+            max_output_bytes: MAX_OUTPUT_BYTES,
+        }
+    }
+
+    #[test]
+    fn passthrough_command_returns_input() {
+        assert_eq!(hook("cat", &[]).run(SVG).unwrap(), SVG);
+    }
+
+    #[test]
+    fn arguments_are_not_interpreted_by_a_shell() {
+        let output = hook("echo", &["<svg>$(whoami)</svg>"]).run(SVG).unwrap();
+        assert_eq!(output.trim(), "<svg>$(whoami)</svg>");
+    }
+
+    #[test]
+    fn missing_command_is_reported() {
+        let err = hook("mermaid-lsp-no-such-optimizer", &[]).run(SVG).unwrap_err();
+        assert!(matches!(err, ServerError::ToolNotFound(_)));
+    }
+
+    #[test]
+    fn failing_slow_or_oversized_commands_are_errors() {
+        assert!(hook("false", &[]).run(SVG).is_err());
+
+        let slow = PostProcess {
+            timeout: Duration::from_millis(100),
+            ..hook("sleep", &["5"])
+        };
+        let started = Instant::now();
+        assert!(slow.run(SVG).is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        let capped = PostProcess {
+            max_output_bytes: 8,
+            ..hook("cat", &[])
+        };
+        assert!(capped.run(SVG).is_err());
+    }
+
+    #[test]
+    fn reads_command_from_config() {
+        let config = Config {
+            post_process_command: vec!["svgo".to_string(), "-".to_string()],
+            ..Config::default()
+        };
+        let hook = PostProcess::from_config(&config).unwrap();
+        assert_eq!(hook.program, "svgo");
+        assert_eq!(hook.args, vec!["-"]);
+        assert!(PostProcess::from_config(&Config::default()).is_none());
+    }
+}
@@ -0,0 +1,76 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+};
+
+use serde::Serialize;
+use url::Url;
+
+/// What a notification about a document reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Channel {
+    Diagnostics,
+    FenceStatus,
+}
+
+/// Fingerprints of the last payload sent on each channel for each document,
+/// so that an edit leaving them as they were sends nothing
+#[derive(Debug, Default)]
+pub struct Publisher {
+    last: HashMap<(Url, Channel), u64>,
+}
+
+impl Publisher {
+    /// Whether `payload` differs from the last one sent for `uri` on `channel`,
+    /// remembering it as sent if so. Payloads that can't be serialized always go.
+    pub fn should_send(&mut self, uri: &Url, channel: Channel, payload: &impl Serialize) -> bool {
+        let Ok(json) = serde_json::to_string(payload) else {
+            return true;
+        };
+        let mut hasher = DefaultHasher::new();
+        json.hash(&mut hasher);
+        let fingerprint = hasher.finish();
+        self.last.insert((uri.clone(), channel), fingerprint) != Some(fingerprint)
+    }
+
+    /// Forget what was sent for a closed document
+    pub fn forget(&mut self, uri: &Url) {
+        self.last.retain(|(sent, _), _| sent != uri);
+    }
+
+    /// Forget everything, so the next payloads go out even if unchanged
+    pub fn clear(&mut self) {
+        self.last.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_identical_payloads_once() {
+        let mut publisher = Publisher::default();
+        let uri = Url::parse("file:///tmp/doc.md").unwrap();
+        let other = Url::parse("file:///tmp/other.md").unwrap();
+        assert!(publisher.should_send(&uri, Channel::Diagnostics, &["unknown node"]));
+        assert!(!publisher.should_send(&uri, Channel::Diagnostics, &["unknown node"]));
+        // Other channels and documents are tracked apart
+        assert!(publisher.should_send(&uri, Channel::FenceStatus, &["unknown node"]));
+        assert!(publisher.should_send(&other, Channel::Diagnostics, &["unknown node"]));
+    }
+
+    #[test]
+    fn changes_and_resets_always_go_through() {
+        let mut publisher = Publisher::default();
+        let uri = Url::parse("file:///tmp/doc.md").unwrap();
+        assert!(publisher.should_send(&uri, Channel::Diagnostics, &["a"]));
+        assert!(publisher.should_send(&uri, Channel::Diagnostics, &["b"]));
+        assert!(publisher.should_send(&uri, Channel::Diagnostics, &["a"]));
+
+        publisher.forget(&uri);
+        assert!(publisher.should_send(&uri, Channel::Diagnostics, &["a"]));
+        publisher.clear();
+        assert!(publisher.should_send(&uri, Channel::Diagnostics, &["a"]));
+    }
+}
@@ -0,0 +1,228 @@
+use crate::diagnostics::DiagramType;
+use crate::i18n::Text;
+
+/// Typographic quotes that editors and docs substitute for straight ones,
+/// with their replacement
+const SMART_QUOTES: &[(char, char)] = &[
+    ('\u{201C}', '"'),
+    ('\u{201D}', '"'),
+    ('\u{201E}', '"'),
+    ('\u{201F}', '"'),
+    ('\u{2018}', '\''),
+    ('\u{2019}', '\''),
+    ('\u{201A}', '\''),
+    ('\u{201B}', '\''),
+];
+
+/// Keywords that open a diagram; a body starting with anything else has no
+/// header and mermaid can't tell what to draw
+const DIAGRAM_KEYWORDS: &[&str] = &[
+    "graph",
+    "flowchart",
+    "sequenceDiagram",
+    "classDiagram",
+    "classDiagram-v2",
+    "stateDiagram",
+    "stateDiagram-v2",
+    "erDiagram",
+    "journey",
+    "gantt",
+    "pie",
+    "quadrantChart",
+    "requirementDiagram",
+    "gitGraph",
+    "mindmap",
+    "timeline",
+    "sankey-beta",
+    "xychart-beta",
+    "block-beta",
+    "packet-beta",
+    "architecture-beta",
+    "kanban",
+    "zenuml",
+    "C4Context",
+    "C4Container",
+    "C4Component",
+    "C4Dynamic",
+    "C4Deployment",
+];
+
+/// Links that mark a headerless body as a flowchart
+const FLOWCHART_LINKS: &[&str] = &["-->", "---", "==>", "-.->"];
+
+/// Header inserted above a headerless flowchart
+const FLOWCHART_HEADER: &str = "flowchart TD";
+
+/// A fix for a common mistake in a diagram: lines `first_line..=last_line` of
+/// the fence body are replaced by `replacement`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuickFix {
+    pub title: Text,
+    pub first_line: usize,
+    pub last_line: usize,
+    pub replacement: String,
+}
+
+/// The fixes that apply to a fence body. Each only touches what it is sure
+/// of: comments, labels and other diagram types are left alone.
+pub fn quick_fixes(code: &str) -> Vec<QuickFix> {
+    let lines: Vec<&str> = code.lines().collect();
+    let mut fixes = Vec::new();
+    fixes.extend(fix_lines(&lines, Text::FixSmartQuotes, straighten_quotes));
+    if DiagramType::detect(code) == Some(DiagramType::Flowchart) {
+        fixes.extend(fix_lines(&lines, Text::FixFlowchartArrows, fix_arrows));
+    }
+    fixes.extend(header_fix(&lines));
+    fixes
+}
+
+/// One fix covering every line `fix_line` changes, from the first to the last
+fn fix_lines(lines: &[&str], title: Text, fix_line: fn(&str) -> Option<String>) -> Option<QuickFix> {
+    let fixed: Vec<(usize, String)> = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| !is_comment(line))
+        .filter_map(|(i, line)| Some((i, fix_line(line)?)))
+        .collect();
+    let first_line = fixed.first()?.0;
+    let last_line = fixed.last()?.0;
+    let replacement = (first_line..=last_line)
+        .map(|i| match fixed.iter().find(|(line, _)| *line == i) {
+            Some((_, text)) => text.as_str(),
+            None => lines[i],
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    Some(QuickFix {
+        title,
+        first_line,
+        last_line,
+        replacement,
+    })
+}
+
+fn is_comment(line: &str) -> bool {
+    line.trim_start().starts_with("%%")
+}
+
+/// `line` with typographic quotes made straight, if it has any
+pub fn straighten_quotes(line: &str) -> Option<String> {
+    let straight = |c: char| SMART_QUOTES.iter().find(|(smart, _)| *smart == c).map(|&(_, s)| s);
+    line.chars().any(|c| straight(c).is_some()).then(|| {
+        line.chars().map(|c| straight(c).unwrap_or(c)).collect()
+    })
+}
+
+/// `line` with each bare `->` link made a flowchart `-->`. Arrows inside
+/// labels (`[...]`, `"..."`, `|...|`) and the tails of longer links such as
+/// `-->` or `-.->` are kept.
+fn fix_arrows(line: &str) -> Option<String> {
+    let bytes = line.as_bytes();
+    let mut depth = 0usize;
+    let mut quoted = false;
+    let mut edge_label = false;
+    let mut inserts = Vec::new();
+    for (i, &b) in bytes.iter().enumerate() {
+        match b {
+            b'"' => quoted = !quoted,
+            _ if quoted => {}
+            b'[' | b'(' | b'{' => depth += 1,
+            b']' | b')' | b'}' => depth = depth.saturating_sub(1),
+            b'|' if depth == 0 => edge_label = !edge_label,
+            b'-' if depth == 0 && !edge_label && bytes.get(i + 1) == Some(&b'>') => {
+                let prev = i.checked_sub(1).map(|p| bytes[p]);
+                if !matches!(prev, Some(b'-' | b'.' | b'=' | b'<')) {
+                    inserts.push(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    if inserts.is_empty() {
+        return None;
+    }
+    let mut fixed = line.to_string();
+    for &i in inserts.iter().rev() {
+        fixed.insert(i, '-');
+    }
+    Some(fixed)
+}
+
+/// Insert `flowchart TD` above a body that starts without a diagram keyword
+/// but links nodes like a flowchart
+fn header_fix(lines: &[&str]) -> Option<QuickFix> {
+    let first = first_statement(lines)?;
+    let line = lines[first];
+    let keyword = line.split_whitespace().next()?;
+    if DIAGRAM_KEYWORDS.contains(&keyword) || keyword.starts_with("stateDiagram") {
+        return None;
+    }
+    let links = lines[first..]
+        .iter()
+        .filter(|line| !is_comment(line))
+        .any(|line| FLOWCHART_LINKS.iter().any(|link| line.contains(link)));
+    if !links {
+        return None;
+    }
+    let indent = &line[..line.len() - line.trim_start().len()];
+    Some(QuickFix {
+        title: Text::AddFlowchartHeader,
+        first_line: first,
+        last_line: first,
+        replacement: format!("{indent}{FLOWCHART_HEADER}\n{line}"),
+    })
+}
+
+/// Index of the first line that isn't blank, a comment or front matter
+fn first_statement(lines: &[&str]) -> Option<usize> {
+    let mut start = 0;
+    if lines.first().is_some_and(|line| line.trim() == "---") {
+        start = 1 + lines.iter().skip(1).position(|line| line.trim() == "---")? + 1;
+    }
+    (start..lines.len()).find(|&i| {
+        let line = lines[i].trim();
+        !line.is_empty() && !line.starts_with("%%")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fix_for(code: &str, title: Text) -> Option<QuickFix> {
+        quick_fixes(code).into_iter().find(|fix| fix.title == title)
+    }
+
+    #[test]
+    fn straightens_smart_quotes_outside_comments() {
+        let code = "graph TD\n  A[\u{201C}Start\u{201D}] --> B\n  %% \u{2018}kept\u{2019}\n  B --> C[\u{2018}End\u{2019}]";
+        let fix = fix_for(code, Text::FixSmartQuotes).unwrap();
+        assert_eq!((fix.first_line, fix.last_line), (1, 3));
+        assert_eq!(fix.replacement, "  A[\"Start\"] --> B\n  %% \u{2018}kept\u{2019}\n  B --> C['End']");
+        assert_eq!(fix_for("graph TD\n  A[\"Start\"] --> B", Text::FixSmartQuotes), None);
+    }
+
+    #[test]
+    fn fixes_bare_arrows_only_in_flowcharts() {
+        let code = "graph TD\n  A -> B\n  B --> C\n  C-.->D\n  D[a -> b] -->|x -> y| E\n  E<->F";
+        let fix = fix_for(code, Text::FixFlowchartArrows).unwrap();
+        assert_eq!((fix.first_line, fix.last_line), (1, 1));
+        assert_eq!(fix.replacement, "  A --> B");
+
+        // `->` is a valid message arrow in sequence diagrams
+        assert_eq!(fix_for("sequenceDiagram\n  A->B: hi", Text::FixFlowchartArrows), None);
+    }
+
+    #[test]
+    fn adds_a_header_only_to_headerless_flowcharts() {
+        let fix = fix_for("%% deps\n  A --> B\n  B --> C", Text::AddFlowchartHeader).unwrap();
+        assert_eq!((fix.first_line, fix.last_line), (1, 1));
+        assert_eq!(fix.replacement, "  flowchart TD\n  A --> B");
+
+        for code in ["graph LR\n  A --> B", "stateDiagram-v2\n  A --> B", "pie\n  \"A\" : 1", "just prose"] {
+            assert_eq!(fix_for(code, Text::AddFlowchartHeader), None, "{code}");
+        }
+        let front_matter = "---\ntitle: Deps\n---\nA --> B";
+        assert_eq!(fix_for(front_matter, Text::AddFlowchartHeader).unwrap().first_line, 3);
+    }
+}
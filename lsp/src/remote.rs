@@ -0,0 +1,214 @@
+use log::info;
+use once_cell::sync::Lazy;
+use std::{
+    process::{Command, Stdio},
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+use url::Url;
+
+use crate::config::Config;
+use crate::error::{ServerError, ServerResult};
+use crate::render;
+
+/// Fence attribute naming where the diagram source lives instead of the
+/// fence body: ```` ```mermaid url=https://example.com/flow.mmd ````
+const URL_ATTRIBUTE: &str = "url=";
+
+/// Downloads remote diagram sources
+pub trait Fetcher: Send {
+    /// The body at `url`, or why it couldn't be fetched. Gives up after
+    /// `timeout`, and may stop reading past `max_bytes`.
+    fn fetch(&self, url: &Url, max_bytes: u64, timeout: Duration) -> Result<Vec<u8>, String>;
+}
+
+/// Fetches with the `curl` command, https only and without following redirects
+/// (which could leave the allowed hosts)
+pub struct Curl;
+
+impl Fetcher for Curl {
+    fn fetch(&self, url: &Url, max_bytes: u64, timeout: Duration) -> Result<Vec<u8>, String> {
+        let curl = which::which("curl").map_err(|_| "curl was not found on PATH".to_string())?;
+        let output = Command::new(curl)
+            .args(["--silent", "--show-error", "--proto", "=https"])
+            .args(["--max-time", &format!("{:.3}", timeout.as_secs_f64())])
+            .args(["--max-filesize", &max_bytes.to_string()])
+            .args(["--write-out", "\n%{http_code}", "--"])
+            .arg(url.as_str())
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("failed to run curl: {e}"))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(stderr.trim().trim_start_matches("curl: ").to_string());
+        }
+        let mut body = output.stdout;
+        let split = body.iter().rposition(|&b| b == b'\n').unwrap_or(0);
+        let status = String::from_utf8_lossy(&body[split..]).trim().to_string();
+        body.truncate(split);
+        match status.as_str() {
+            "200" => Ok(body),
+            _ => Err(format!("HTTP {status}")),
+        }
+    }
+}
+
+/// The fetcher used for remote sources: curl, or a stand-in under test
+static FETCHER: Lazy<Mutex<Box<dyn Fetcher>>> = Lazy::new(|| Mutex::new(Box::new(Curl)));
+
+fn fetcher() -> MutexGuard<'static, Box<dyn Fetcher>> {
+    FETCHER.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Replace the fetcher, returning the previous one
+#[cfg(test)]
+pub fn set_fetcher(fetcher: Box<dyn Fetcher>) -> Box<dyn Fetcher> {
+    std::mem::replace(&mut *self::fetcher(), fetcher)
+}
+
+/// The remote source a fence's info string points at with `url=...`, bare or
+/// inside its `{...}` attributes
+pub fn source_url(info: &str) -> Option<&str> {
+    info.split(|c: char| c.is_whitespace() || matches!(c, ',' | '{' | '}'))
+        .find_map(|token| token.strip_prefix(URL_ATTRIBUTE))
+        .map(|url| url.trim_matches(|c| c == '"' || c == '\''))
+        .filter(|url| !url.is_empty())
+}
+
+/// Why the fence's remote source may not be fetched, if it has one and it
+/// may not. Checked without touching the network.
+pub fn check(info: &str, config: &Config) -> Option<ServerError> {
+    allowed_url(source_url(info)?, config).err()
+}
+
+/// Fetch the diagram source at `url` with the session's fetcher
+pub fn fetch_source(url: &str, config: &Config) -> ServerResult<String> {
+    fetch_source_with(url, config, fetcher().as_ref())
+}
+
+/// Fetch the diagram source at `url` if `config` allows it: opted in with
+/// `remoteSources`, https, an allowed host, in time and within the size cap
+pub fn fetch_source_with(url: &str, config: &Config, fetcher: &dyn Fetcher) -> ServerResult<String> {
+    let parsed = allowed_url(url, config)?;
+    let max_bytes = config.remote_source_max_bytes;
+    let body = fetcher
+        .fetch(&parsed, max_bytes, Duration::from_millis(config.remote_source_timeout_ms))
+        .map_err(|reason| failure(url, reason))?;
+    if body.len() as u64 > max_bytes {
+        return Err(failure(url, format!("larger than {max_bytes} bytes (`remoteSourceMaxBytes`)")));
+    }
+    let code = String::from_utf8(body).map_err(|_| failure(url, "not UTF-8 text"))?;
+    if code.trim().is_empty() {
+        return Err(failure(url, "the response is empty"));
+    }
+    info!("Fetched {} bytes of diagram source from {url}", code.len());
+    Ok(code)
+}
+
+/// `url` parsed, if `config` allows fetching it
+fn allowed_url(url: &str, config: &Config) -> ServerResult<Url> {
+    if !config.remote_sources {
+        return Err(failure(url, "remote diagram sources are disabled (enable `remoteSources`)"));
+    }
+    let parsed = Url::parse(url).map_err(|e| failure(url, e.to_string()))?;
+    if parsed.scheme() != "https" {
+        return Err(failure(url, "only https URLs are fetched"));
+    }
+    let host = parsed.host_str().unwrap_or_default();
+    if !render::host_matches(host, &config.remote_source_hosts) {
+        return Err(failure(url, format!("{host} is not in `remoteSourceHosts`")));
+    }
+    Ok(parsed)
+}
+
+fn failure(url: &str, reason: impl Into<String>) -> ServerError {
+    ServerError::FetchFailed {
+        url: url.to_string(),
+        reason: reason.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Serves a fixed body and counts the requests
+    struct Mock {
+        body: Result<&'static str, &'static str>,
+        requests: AtomicUsize,
+    }
+
+    impl Fetcher for Mock {
+        fn fetch(&self, _url: &Url, _max_bytes: u64, _timeout: Duration) -> Result<Vec<u8>, String> {
+            self.requests.fetch_add(1, Ordering::Relaxed);
+            self.body.map(|body| body.as_bytes().to_vec()).map_err(str::to_string)
+        }
+    }
+
+    fn mock(body: Result<&'static str, &'static str>) -> Mock {
+        Mock {
+            body,
+            requests: AtomicUsize::new(0),
+        }
+    }
+
+    fn enabled() -> Config {
+        Config {
+            remote_sources: true,
+            remote_source_hosts: vec!["example.com".to_string()],
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn reads_the_url_attribute() {
+        assert_eq!(source_url("url=https://example.com/a.mmd"), Some("https://example.com/a.mmd"));
+        assert_eq!(
+            source_url("{theme=dark url=\"https://example.com/a.mmd\"}"),
+            Some("https://example.com/a.mmd")
+        );
+        assert_eq!(source_url("{backend=mmdc, url=https://example.com/a.mmd}"), Some("https://example.com/a.mmd"));
+        assert_eq!(source_url("{backend=mmdc}"), None);
+        assert_eq!(source_url("url="), None);
+    }
+
+    #[test]
+    fn fetches_only_what_the_config_allows() {
+        let fetcher = mock(Ok("graph TD\n  A --> B\n"));
+        let url = "https://docs.example.com/flow.mmd";
+        assert_eq!(fetch_source_with(url, &enabled(), &fetcher).unwrap(), "graph TD\n  A --> B\n");
+
+        let refused = [
+            (url, Config::default(), "remoteSources"),
+            ("http://example.com/flow.mmd", enabled(), "only https"),
+            ("https://example.org/flow.mmd", enabled(), "example.org is not in `remoteSourceHosts`"),
+            ("https://evil-example.com/flow.mmd", enabled(), "is not in"),
+            ("not a url", enabled(), "relative URL"),
+        ];
+        for (url, config, reason) in refused {
+            let err = fetch_source_with(url, &config, &fetcher).unwrap_err();
+            assert!(matches!(&err, ServerError::FetchFailed { .. }), "{err}");
+            assert!(err.to_string().contains(reason), "{err}");
+            assert!(check(&format!("url={url}"), &config).is_some());
+        }
+        assert_eq!(fetcher.requests.load(Ordering::Relaxed), 1);
+        assert!(check(&format!("url={url}"), &enabled()).is_none());
+    }
+
+    #[test]
+    fn fetch_failures_are_reported() {
+        let url = "https://example.com/flow.mmd";
+        let err = fetch_source_with(url, &enabled(), &mock(Err("HTTP 404"))).unwrap_err();
+        assert_eq!(err.to_string(), "cannot fetch diagram source https://example.com/flow.mmd: HTTP 404");
+
+        let small = Config {
+            remote_source_max_bytes: 4,
+            ..enabled()
+        };
+        let err = fetch_source_with(url, &small, &mock(Ok("graph TD"))).unwrap_err();
+        assert!(err.to_string().contains("larger than 4 bytes"), "{err}");
+        let err = fetch_source_with(url, &enabled(), &mock(Ok("  \n"))).unwrap_err();
+        assert!(err.to_string().contains("empty"), "{err}");
+    }
+}
@@ -1,14 +1,38 @@
-use anyhow::{anyhow, Result};
-use html_escape;
+use crate::error::{ServerError, ServerResult};
 use once_cell::sync::Lazy;
 use regex::Regex;
+use log::{info, warn};
 use std::{
-    env, fs,
-    path::PathBuf,
-    process::{Command, Stdio},
+    borrow::Cow,
+    collections::HashMap,
+    env,
+    ffi::OsString,
+    fs,
+    io,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tempfile::tempdir;
 
+use crate::accessibility;
+use crate::backend::Backend;
+use crate::cache::UNKNOWN_MMDC_VERSION;
+use crate::config::{Config, SecurityLevel};
+use crate::icons::{self, IconPack};
+use crate::optimize::optimize_svg;
+use crate::postprocess::PostProcess;
+use crate::source_map::{self, SourceMap};
+use crate::stable_hash::{self, StableHasher};
+use crate::trust::{self, Trust};
+use crate::variables;
+use crate::watermark::add_watermark;
+
 // Precompiled regex patterns for security sanitization
 static EVENT_HANDLER_ATTR: Lazy<Regex> = Lazy::new(|| {
     Regex::new(r#"(?i)\s+on[a-z0-9_.:-]+\s*=\s*(?:"[^"]*"|'[^']*'|[^\s>]+)"#)
@@ -20,156 +44,1043 @@ static JAVASCRIPT_HREF_ATTR: Lazy<Regex> = Lazy::new(|| {
         .expect("javascript href regex")
 });
 
+static EXTERNAL_LINK_ATTR: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?i)\s+(?:xlink:)?(?:href|src)\s*=\s*(?:"([^"]*)"|'([^']*)')"#)
+        .expect("external link regex")
+});
+
 static FOREIGN_OBJECT_REGEX: Lazy<Regex> = Lazy::new(|| {
     Regex::new(r#"<foreignObject[^>]*>(.*?)</foreignObject>"#).expect("foreignObject regex")
 });
 
+/// A DOCTYPE, including an internal subset that may declare entities
+static DOCTYPE_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?is)<!DOCTYPE\b(?:[^\[>]*\[.*?\]\s*)?[^>]*>").expect("doctype regex")
+});
+
+/// Everything the sanitizer drops or rewrites after the XML declaration, in
+/// one alternation so the document is scanned once: event handlers,
+/// `javascript:` links, other links (kept when the policy allows them),
+/// `<foreignObject>` elements and the DOCTYPE. Earlier branches win where
+/// several match, as when they ran one after another.
+static SANITIZE_PASS: Lazy<Regex> = Lazy::new(|| {
+    let link = EXTERNAL_LINK_ATTR
+        .as_str()
+        .replacen(r#""([^"]*)""#, r#""(?P<double>[^"]*)""#, 1)
+        .replacen("'([^']*)'", "'(?P<single>[^']*)'", 1);
+    let foreign = FOREIGN_OBJECT_REGEX.as_str().replace("(.*?)", "(?:.*?)");
+    Regex::new(&format!(
+        "(?P<event>{})|(?P<javascript>{})|(?P<link>{link})|(?P<foreign>{foreign})|(?P<doctype>{})",
+        EVENT_HANDLER_ATTR.as_str(),
+        JAVASCRIPT_HREF_ATTR.as_str(),
+        DOCTYPE_REGEX.as_str(),
+    ))
+    .expect("sanitize pass regex")
+});
+
+/// An XML declaration at the start of the document
+static XML_DECLARATION: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?s)^\s*<\?xml\b.*?\?>").expect("xml declaration regex"));
+
+/// A declaration with nothing but `version`, `encoding` and `standalone`
+static BENIGN_XML_DECLARATION: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"^\s*<\?xml(?:\s+(?:version|encoding|standalone)\s*=\s*(?:"[^"<>]*"|'[^'<>]*'))*\s*\?>$"#)
+        .expect("benign xml declaration regex")
+});
+
+/// Numeric character references and the named ones that spell markup
+static CHARACTER_REFERENCE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)&#x([0-9a-f]+);?|&#([0-9]+);?|&(lt|gt|quot|apos|amp);").expect("character reference regex")
+});
+
 static HTML_TAG_REGEX: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"<[^>]*>").expect("HTML tag regex"));
 
+/// Resolved mmdc invocations, keyed by the pinned mermaid-cli version
+static RESOLVED_MMDC: Lazy<Mutex<HashMap<Option<String>, MmdcCommand>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+const MERMAID_CLI_PACKAGE: &str = "@mermaid-js/mermaid-cli";
+
+/// mermaid-cli's installation instructions
+const MERMAID_CLI_DOCS_URL: &str = "https://github.com/mermaid-js/mermaid-cli#installation";
+
+/// How to invoke mermaid-cli: the program plus any arguments preceding mmdc's own flags
+#[derive(Debug, Clone, PartialEq)]
+struct MmdcCommand {
+    program: PathBuf,
+    prefix_args: Vec<String>,
+    /// Effective mermaid-cli version, when known
+    version: Option<String>,
+}
+
+impl MmdcCommand {
+    /// Run a pinned mermaid-cli through `npx -y @mermaid-js/mermaid-cli@<version>`
+    fn npx(npx_path: PathBuf, version: &str) -> Self {
+        Self {
+            program: npx_path,
+            prefix_args: vec!["-y".to_string(), format!("{MERMAID_CLI_PACKAGE}@{version}")],
+            version: Some(version.to_string()),
+        }
+    }
+
+    /// Short name of the render backend, for logs
+    fn backend(&self) -> &'static str {
+        if self.prefix_args.is_empty() {
+            "mmdc"
+        } else {
+            "npx"
+        }
+    }
+
+    fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.args(&self.prefix_args);
+        cmd
+    }
+}
+
+/// Which external (http/https) links and images may survive sanitization
+#[derive(Debug, Clone, Default)]
+pub struct SanitizePolicy {
+    /// Hosts (and their subdomains) allowed in links; empty allows every host
+    pub allowed_link_hosts: Vec<String>,
+    /// Strip every external link regardless of the allowlist
+    pub block_external_links: bool,
+}
+
+impl SanitizePolicy {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            allowed_link_hosts: config.allowed_link_hosts.clone(),
+            block_external_links: config.block_external_links,
+        }
+    }
+
+    /// Whether an attribute value pointing at `target` may be kept
+    fn allows_link(&self, target: &str) -> bool {
+        let Ok(url) = url::Url::parse(target.trim()) else {
+            // Relative references and fragments stay
+            return true;
+        };
+        if !matches!(url.scheme(), "http" | "https") {
+            return true;
+        }
+        if self.block_external_links {
+            return false;
+        }
+        if self.allowed_link_hosts.is_empty() {
+            return true;
+        }
+
+        url.host_str().is_some_and(|host| host_matches(host, &self.allowed_link_hosts))
+    }
+}
+
+/// Whether `host` is one of `allowed` or a subdomain of one (`*.` and `.`
+/// prefixes are accepted and mean the same)
+pub fn host_matches(host: &str, allowed: &[String]) -> bool {
+    let host = host.to_ascii_lowercase();
+    allowed.iter().any(|allowed| {
+        let allowed = allowed.trim().trim_start_matches("*.").trim_start_matches('.');
+        let allowed = allowed.to_ascii_lowercase();
+        !allowed.is_empty() && (host == allowed || host.ends_with(&format!(".{allowed}")))
+    })
+}
+
+/// Version of the mermaid-cli that `config` renders with, for cache keys.
+/// Detected once per session; falls back to [`UNKNOWN_MMDC_VERSION`].
+pub fn mmdc_cache_version(config: &Config) -> String {
+    resolve_mmdc(config.mermaid_cli_version.as_deref())
+        .ok()
+        .and_then(|cmd| cmd.version)
+        .map(|version| normalize_version(&version).to_string())
+        .unwrap_or_else(|| UNKNOWN_MMDC_VERSION.to_string())
+}
+
+/// Renderer version for cache keys of diagrams rendered by `backend`
+pub fn backend_cache_version(backend: Backend, config: &Config) -> String {
+    match backend {
+        Backend::Mmdc => {
+            let mut version = format!(
+                "{backend}-{}+{}-{}",
+                mmdc_cache_version(config),
+                config.theme,
+                config.background
+            );
+            if !config.icon_packs.is_empty() {
+                let fingerprint = icons::fingerprint(&config.icon_packs, &icon_pack_root());
+                version.push_str(&format!("+icons-{fingerprint:x}"));
+            }
+            if config.security_level != SecurityLevel::Strict {
+                version.push_str(&format!("+{}", config.security_level.as_str()));
+            }
+            if !config.extra_mmdc_args.is_empty() {
+                let mut hasher = StableHasher::new();
+                for arg in &config.extra_mmdc_args {
+                    hasher.write(arg);
+                }
+                version.push_str(&format!("+args-{:x}", hasher.finish()));
+            }
+            if let Some(watermark) = &config.watermark {
+                let hash = StableHasher::new()
+                    .write(&watermark.text)
+                    .write_u64(watermark.opacity.to_bits())
+                    .write_u64(watermark.angle.to_bits())
+                    .finish();
+                version.push_str(&format!("+watermark-{hash:x}"));
+            }
+            if let Some(preamble) = preamble(config) {
+                version.push_str(&format!("+preamble-{:x}", stable_hash::hash(preamble)));
+            }
+            version
+        }
+        Backend::Native => format!("{backend}-{}", env!("CARGO_PKG_VERSION")),
+    }
+}
+
+/// Why `backend` can't render right now, if it can't. Resolving mmdc is
+/// remembered for the session, so this is cheap after the first call.
+pub fn backend_unavailable(backend: Backend, config: &Config) -> Option<String> {
+    match backend {
+        Backend::Mmdc => resolve_mmdc(config.mermaid_cli_version.as_deref())
+            .err()
+            .map(|e| e.to_string()),
+        Backend::Native => Some("the native render backend is not available in this build".to_string()),
+    }
+}
+
+/// Renders run this session, against `maxRendersPerSession`
+static SESSION_RENDERS: RenderBudget = RenderBudget::new();
+
+/// A count of renders that refuses to go past a limit
+struct RenderBudget {
+    used: AtomicUsize,
+}
+
+impl RenderBudget {
+    const fn new() -> Self {
+        Self {
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// Count one render, or refuse it if `max` were already run
+    fn take(&self, max: Option<usize>) -> ServerResult<()> {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| match max {
+                Some(max) if used >= max => None,
+                _ => Some(used.saturating_add(1)),
+            })
+            .map(|_| ())
+            .map_err(ServerError::RenderLimitReached)
+    }
+
+    fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    fn reset(&self) {
+        self.used.store(0, Ordering::SeqCst);
+    }
+}
+
+/// Renders run this session
+pub fn session_renders() -> usize {
+    SESSION_RENDERS.used()
+}
+
+/// Let `maxRendersPerSession` more diagrams render
+pub fn reset_render_limits() {
+    SESSION_RENDERS.reset();
+}
+
+/// Render Mermaid code to SVG with the given backend
+pub fn render_with(backend: Backend, mermaid_code: &str, config: &Config) -> ServerResult<String> {
+    let mermaid_code = variables::substitute(mermaid_code, config)?;
+    SESSION_RENDERS.take(config.max_renders_per_session)?;
+    match backend {
+        Backend::Mmdc => render_mermaid(&mermaid_code, config),
+        Backend::Native => Err(ServerError::ToolNotFound(
+            "the native render backend is not available in this build".to_string(),
+        )),
+    }
+}
+
+/// Render Mermaid code to SVG with `backend` without leaving anything on disk:
+/// no cache is read or written, and mmdc's temp dir is removed whether the
+/// render succeeds, fails or panics (`MERMAID_KEEP_TEMP` is ignored). Safe to
+/// call in a tight loop, e.g. to benchmark or property-test rendering.
+pub fn render_in_memory(source: &str, backend: Backend, config: &Config) -> ServerResult<String> {
+    match backend {
+        Backend::Mmdc => {
+            let source = variables::substitute(source, config)?;
+            SESSION_RENDERS.take(config.max_renders_per_session)?;
+            validate_code(&source)?;
+            let mmdc = resolve_mmdc(config.mermaid_cli_version.as_deref())?;
+            render_svg_with(&mmdc, &source, config, None)
+        }
+        Backend::Native => render_with(backend, source, config),
+    }
+}
+
 /// Render Mermaid code to SVG using mmdc CLI
-pub fn render_mermaid(mermaid_code: &str) -> Result<String> {
+pub fn render_mermaid(mermaid_code: &str, config: &Config) -> ServerResult<String> {
+    validate_code(mermaid_code)?;
+    let mmdc = resolve_mmdc(config.mermaid_cli_version.as_deref())?;
+    render_svg_with(&mmdc, mermaid_code, config, keep_failed_root().as_deref())
+}
+
+/// Render with `mmdc`, then sanitize, optimize and post-process the SVG
+fn render_svg_with(
+    mmdc: &MmdcCommand,
+    mermaid_code: &str,
+    config: &Config,
+    keep_failed: Option<&Path>,
+) -> ServerResult<String> {
+    let svg = run_mmdc_with(mmdc, mermaid_code, "svg", config, keep_failed)?;
+    let svg = String::from_utf8(svg).map_err(|e| ServerError::RenderFailed(format!("mmdc wrote an SVG that is not UTF-8: {e}")))?;
+
+    let policy = SanitizePolicy::from_config(config);
+    let mut svg = sanitize_svg(&svg, &policy)?;
+    if config.optimize_svg {
+        match optimize_svg(&svg) {
+            Ok(optimized) => svg = optimized,
+            Err(e) => warn!("Skipping optimizeSvg: {e}"),
+        }
+    }
+    if let Some(hook) = PostProcess::from_config(config) {
+        svg = post_process(svg, &hook, &policy);
+    }
+    if let Some(watermark) = &config.watermark {
+        svg = add_watermark(&svg, watermark);
+    }
+    Ok(accessibility::describe(&svg, mermaid_code))
+}
+
+/// Render Mermaid code to a PNG image using mmdc CLI. Raster output has no
+/// markup to sanitize, and is not cached.
+pub fn render_png(mermaid_code: &str, config: &Config) -> ServerResult<Vec<u8>> {
+    run_mmdc(&variables::substitute(mermaid_code, config)?, "png", config)
+}
+
+/// Run mmdc on `mermaid_code` and return the output file, in `format` (its extension)
+fn run_mmdc(mermaid_code: &str, format: &str, config: &Config) -> ServerResult<Vec<u8>> {
+    validate_code(mermaid_code)?;
+    let mmdc = resolve_mmdc(config.mermaid_cli_version.as_deref())?;
+    run_mmdc_with(&mmdc, mermaid_code, format, config, keep_failed_root().as_deref())
+}
+
+/// The configured preamble, without the line breaks around it
+fn preamble(config: &Config) -> Option<&str> {
+    let preamble = config.preamble.as_deref()?.trim_matches(['\r', '\n']);
+    (!preamble.trim().is_empty()).then_some(preamble)
+}
+
+/// `code` as the renderer gets it: with the configured preamble after its
+/// frontmatter (which mermaid only reads on the first line), unless the code
+/// already has it. The map takes the renderer's lines back to `code`'s.
+pub fn with_preamble<'a>(code: &'a str, config: &Config) -> (Cow<'a, str>, SourceMap) {
+    let line_count = code.lines().count();
+    let Some(preamble) = preamble(config).filter(|preamble| !code.contains(preamble)) else {
+        return (Cow::Borrowed(code), SourceMap::identity(line_count));
+    };
+    let lines: Vec<&str> = code.lines().collect();
+    let at = source_map::frontmatter_len(&lines);
+    let mut sent: Vec<&str> = lines[..at].to_vec();
+    sent.extend(preamble.lines());
+    sent.extend(&lines[at..]);
+    let map = SourceMap::spliced(line_count, at, preamble.lines().count());
+    (Cow::Owned(sent.join("\n")), map)
+}
+
+/// Fail for code that is empty or only whitespace, which there's nothing to render of
+pub fn validate_code(mermaid_code: &str) -> ServerResult<()> {
     if mermaid_code.trim().is_empty() {
-        return Err(anyhow!("Mermaid code is empty"));
+        return Err(ServerError::ValidationFailed("empty mermaid block".to_string()));
+    }
+    Ok(())
+}
+
+/// Directory `iconPacks` entries are relative to and must stay inside: the
+/// workspace, or the current directory without one
+fn icon_pack_root() -> PathBuf {
+    trust::store()
+        .root()
+        .map(Path::to_path_buf)
+        .or_else(|| env::current_dir().ok())
+        .unwrap_or_default()
+}
+
+/// The icon packs `iconPacks` configures, loaded and checked
+fn icon_packs(config: &Config) -> ServerResult<Vec<IconPack>> {
+    if config.icon_packs.is_empty() {
+        return Ok(Vec::new());
     }
+    icons::load_packs(&config.icon_packs, &icon_pack_root())
+        .map_err(|e| ServerError::InvalidParams(format!("`iconPacks`: {e}")))
+}
 
-    let mmdc_path = find_mmdc()?;
+/// Where failed renders are kept, when [`KEEP_TEMP_ENV`] asks for it
+fn keep_failed_root() -> Option<PathBuf> {
+    flag_set(env::var(KEEP_TEMP_ENV).ok()).then(|| env::temp_dir().join(KEPT_RENDERS_DIR))
+}
 
-    let temp_dir = tempdir().map_err(|e| anyhow!("Failed to create temp dir: {e}"))?;
+/// Run `mmdc` on `mermaid_code`. When it fails and `keep_failed` is set, its
+/// files are copied to a new directory in there before the temp dir goes.
+fn run_mmdc_with(
+    mmdc: &MmdcCommand,
+    mermaid_code: &str,
+    format: &str,
+    config: &Config,
+    keep_failed: Option<&Path>,
+) -> ServerResult<Vec<u8>> {
+    let (mermaid_code, _) = with_preamble(mermaid_code, config);
+    let packs = icon_packs(config)?;
+    icons::check_icons(&mermaid_code, &packs)?;
+    check_extra_args(&config.extra_mmdc_args)?;
+    let temp_dir = tempdir().map_err(ServerError::io("Failed to create temp dir"))?;
     let input_path = temp_dir.path().join("diagram.mmd");
-    let output_path = temp_dir.path().join("diagram.svg");
+    let output_path = temp_dir.path().join(format!("diagram.{format}"));
     let config_path = temp_dir.path().join("mermaid-config.json");
 
     // Write mermaid code and config to temp files
-    fs::write(&input_path, mermaid_code)
-        .map_err(|e| anyhow!("Failed to write temp Mermaid file: {e}"))?;
-    fs::write(&config_path, include_str!("mermaid-config.json"))
-        .map_err(|e| anyhow!("Failed to write temp config file: {e}"))?;
+    fs::write(&input_path, mermaid_code.as_ref())
+        .map_err(ServerError::io("Failed to write temp Mermaid file"))?;
+    fs::write(&config_path, mermaid_config_json(config))
+        .map_err(ServerError::io("Failed to write temp config file"))?;
 
     // Execute mmdc (argument-based, no shell injection)
-    let output = Command::new(&mmdc_path)
-        .arg("-i")
-        .arg(&input_path)
-        .arg("-o")
-        .arg(&output_path)
-        .arg("-c")
-        .arg(&config_path)
-        .arg("-b")
-        .arg("white")
-        .stdout(Stdio::piped())
+    let started = Instant::now();
+    let child = mmdc
+        .command()
+        .args(command_args(&input_path, &output_path, &config_path, config, &packs))
+        .stdout(Stdio::null())
         .stderr(Stdio::piped())
-        .output()
-        .map_err(|e| anyhow!("Failed to execute mmdc: {e}"))?;
+        .spawn()
+        .map_err(ServerError::io("Failed to execute mmdc"))?;
+    let timeout = (config.fence_render_timeout_ms > 0).then(|| Duration::from_millis(config.fence_render_timeout_ms));
+    let (status, stderr) = wait_for_mmdc(child, timeout)?;
+    info!(
+        backend = mmdc.backend(),
+        duration_ms = started.elapsed().as_millis() as u64,
+        success = status.as_ref().is_some_and(ExitStatus::success);
+        "mmdc finished"
+    );
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(anyhow!("mmdc error: {}", stderr.trim()));
+    let result = match status {
+        Some(status) if status.success() => {
+            fs::read(&output_path).map_err(ServerError::io(format!("Failed to read {} output", format.to_uppercase())))
+        }
+        Some(_) => Err(ServerError::RenderFailed(String::from_utf8_lossy(&stderr).trim().to_string())),
+        None => Err(ServerError::RenderFailed(format!(
+            "mmdc took longer than {} ms (fenceRenderTimeoutMs) and was stopped",
+            config.fence_render_timeout_ms
+        ))),
+    };
+    if let (Err(_), Some(root)) = (&result, keep_failed) {
+        match keep_failed_render(temp_dir.path(), root, mmdc, &stderr, format, config, &packs) {
+            Ok(kept) => warn!(
+                "Kept the files of the failed render in {}; rerun it with the command in {}",
+                kept.display(),
+                kept.join(KEPT_COMMAND_FILE).display()
+            ),
+            Err(e) => warn!("Failed to keep the files of the failed render: {e}"),
+        }
+    }
+    result
+}
+
+/// Wait for mmdc to exit, collecting its stderr, and kill it once `timeout`
+/// passes. The exit status is None for a render that was killed.
+fn wait_for_mmdc(mut child: Child, timeout: Option<Duration>) -> ServerResult<(Option<ExitStatus>, Vec<u8>)> {
+    let mut stderr = child.stderr.take().expect("piped stderr");
+    // Not joined when mmdc is killed: the browser it started may keep the
+    // pipe open for a while
+    let reader = thread::spawn(move || {
+        let mut output = Vec::new();
+        io::Read::read_to_end(&mut stderr, &mut output).map(|_| output)
+    });
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait().map_err(ServerError::io("Failed to wait for mmdc"))? {
+            let stderr = reader.join().ok().and_then(Result::ok).unwrap_or_default();
+            return Ok((Some(status), stderr));
+        }
+        if timeout.is_some_and(|timeout| started.elapsed() > timeout) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok((None, Vec::new()));
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Set (to `1`, `true` or `yes`) to keep the input, config and output of
+/// failed renders in `<temp dir>/mermaid-lsp-failed-renders`
+pub const KEEP_TEMP_ENV: &str = "MERMAID_KEEP_TEMP";
+const KEPT_RENDERS_DIR: &str = "mermaid-lsp-failed-renders";
+/// The mmdc command line of a kept render, pointing at the kept files
+const KEPT_COMMAND_FILE: &str = "command.txt";
+const KEPT_STDERR_FILE: &str = "stderr.txt";
+
+/// Numbers the failed renders kept by this process
+static KEPT_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn flag_set(value: Option<String>) -> bool {
+    value.is_some_and(|value| matches!(value.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes"))
+}
+
+/// Copy the files of a failed render from `temp_dir` to a new directory under
+/// `root`, with mmdc's stderr and the command that reproduces the run
+fn keep_failed_render(
+    temp_dir: &Path,
+    root: &Path,
+    mmdc: &MmdcCommand,
+    stderr: &[u8],
+    format: &str,
+    config: &Config,
+    packs: &[IconPack],
+) -> io::Result<PathBuf> {
+    let stamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    let kept = root.join(format!(
+        "{stamp}-{}-{}",
+        std::process::id(),
+        KEPT_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&kept)?;
+    for entry in fs::read_dir(temp_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            fs::copy(entry.path(), kept.join(entry.file_name()))?;
+        }
+    }
+    fs::write(kept.join(KEPT_STDERR_FILE), stderr)?;
+
+    let args = command_args(
+        &kept.join("diagram.mmd"),
+        &kept.join(format!("diagram.{format}")),
+        &kept.join("mermaid-config.json"),
+        config,
+        packs,
+    );
+    let command: Vec<String> = std::iter::once(mmdc.program.clone().into_os_string())
+        .chain(mmdc.prefix_args.iter().map(OsString::from))
+        .chain(args)
+        .map(|arg| shell_quote(&arg.to_string_lossy()))
+        .collect();
+    fs::write(kept.join(KEPT_COMMAND_FILE), command.join(" ") + "\n")?;
+    Ok(kept)
+}
+
+/// `arg` quoted for a POSIX shell, when it needs to be
+fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:@+,".contains(c));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// The bundled mermaid config with the configured theme and background
+fn mermaid_config_json(config: &Config) -> String {
+    let mut mermaid_config: serde_json::Value =
+        serde_json::from_str(include_str!("mermaid-config.json")).expect("bundled mermaid config");
+    mermaid_config["theme"] = config.theme.clone().into();
+    mermaid_config["backgroundColor"] = config.background.clone().into();
+    mermaid_config["securityLevel"] = config.security_level.as_str().into();
+    mermaid_config.to_string()
+}
+
+/// mmdc's own arguments for one render. The output format (`-e`) is passed
+/// explicitly, from `output`'s extension, unless `mmdcOutputFormatFlag` is off:
+/// some mmdc setups fail to infer it and write nothing.
+fn mmdc_args(input: &Path, output: &Path, mermaid_config: &Path, config: &Config) -> Vec<OsString> {
+    let mut args: Vec<OsString> = Vec::new();
+    for (flag, value) in [("-i", input), ("-o", output), ("-c", mermaid_config)] {
+        args.push(flag.into());
+        args.push(value.into());
     }
+    if config.mmdc_output_format_flag {
+        if let Some(format) = output.extension() {
+            args.push("-e".into());
+            args.push(format.to_ascii_lowercase());
+        }
+    }
+    for (flag, value) in [("-t", &config.theme), ("-b", &config.background)] {
+        args.push(flag.into());
+        args.push(value.into());
+    }
+    args
+}
+
+/// Everything after the program (and its npx prefix) on mmdc's command line:
+/// the managed flags, the icon packs, then `extraMmdcArgs`
+fn command_args(
+    input: &Path,
+    output: &Path,
+    mermaid_config: &Path,
+    config: &Config,
+    packs: &[IconPack],
+) -> Vec<OsString> {
+    let mut args = mmdc_args(input, output, mermaid_config, config);
+    args.extend(icons::mmdc_args(packs));
+    args.extend(config.extra_mmdc_args.iter().map(OsString::from));
+    args
+}
 
-    let svg = fs::read_to_string(&output_path)
-        .map_err(|e| anyhow!("Failed to read SVG output: {e}"))?;
+/// mmdc's flags for the files the server hands it, short and long
+const MANAGED_FLAGS: &[(&str, &str)] = &[("-i", "--input"), ("-o", "--output"), ("-c", "--configFile")];
 
-    sanitize_svg(&svg)
+/// Fail for `extraMmdcArgs` that can't be passed on, or would make mmdc read
+/// or write other files than the server's
+fn check_extra_args(args: &[String]) -> ServerResult<()> {
+    for arg in args {
+        if arg.contains('\0') {
+            return Err(ServerError::InvalidParams(format!("`extraMmdcArgs`: {arg:?} contains a NUL byte")));
+        }
+        let overrides = MANAGED_FLAGS.iter().find(|(short, long)| {
+            // `-i x`, `-ix`, `--input x` and `--input=x`
+            (arg.starts_with(short) && !arg.starts_with("--"))
+                || arg == long
+                || arg.strip_prefix(long).is_some_and(|rest| rest.starts_with('='))
+        });
+        if let Some((short, long)) = overrides {
+            return Err(ServerError::InvalidParams(format!(
+                "`extraMmdcArgs`: `{arg}` would replace the server's {short}/{long}"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// `arg` with the path in it cut to its file name, for showing command lines
+/// without the user's directories: `--cssFile=/home/me/style.css` becomes
+/// `--cssFile=style.css`, and an icon pack's `logos#file:///...` `logos#logos.json`
+fn redact_path(arg: &str) -> String {
+    let Some(last) = arg.rfind(['/', '\\']) else {
+        return arg.to_string();
+    };
+    let head = arg.find(['=', '#']).filter(|&i| i < last).map_or("", |i| &arg[..=i]);
+    format!("{head}{}", &arg[last + 1..])
+}
+
+/// The command line renders run mmdc with, paths cut to file names
+fn effective_command(mmdc: &MmdcCommand, config: &Config) -> ServerResult<String> {
+    check_extra_args(&config.extra_mmdc_args)?;
+    let packs = icon_packs(config)?;
+    let args = command_args(
+        Path::new("diagram.mmd"),
+        Path::new("diagram.svg"),
+        Path::new("mermaid-config.json"),
+        config,
+        &packs,
+    );
+    // npx's arguments name a package, not a path
+    let command: Vec<String> = std::iter::once(redact_path(&mmdc.program.to_string_lossy()))
+        .chain(mmdc.prefix_args.iter().cloned())
+        .chain(args.iter().map(|arg| redact_path(&arg.to_string_lossy())))
+        .map(|arg| shell_quote(&arg))
+        .collect();
+    Ok(command.join(" "))
+}
+
+/// Run the user's post-processing hook on a sanitized SVG and sanitize its output
+/// again. Any failure keeps the unprocessed SVG.
+fn post_process(svg: String, hook: &PostProcess, policy: &SanitizePolicy) -> String {
+    match hook.run(&svg).and_then(|processed| sanitize_svg(&processed, policy)) {
+        Ok(processed) => processed,
+        Err(e) => {
+            warn!("Skipping postProcessCommand: {e}");
+            svg
+        }
+    }
+}
+
+/// Resolve (and cache) the mmdc invocation for the pinned version, if any
+fn resolve_mmdc(pinned: Option<&str>) -> ServerResult<MmdcCommand> {
+    let key = pinned.map(str::to_string);
+    let mut resolved = RESOLVED_MMDC.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(cmd) = resolved.get(&key) {
+        return Ok(cmd.clone());
+    }
+
+    let installed = find_mmdc();
+    let cmd = match pinned {
+        Some(version) => {
+            let installed = installed
+                .ok()
+                .map(|path| (path.clone(), mmdc_version(&path)));
+            choose_pinned_mmdc(installed, which::which("npx").ok(), version)?
+        }
+        None => {
+            let path = installed?;
+            let version = mmdc_version(&path);
+            MmdcCommand {
+                program: path,
+                prefix_args: Vec::new(),
+                version,
+            }
+        }
+    };
+
+    info!(
+        "Using mermaid-cli {} ({})",
+        cmd.version.as_deref().unwrap_or("unknown version"),
+        cmd.program.display()
+    );
+    resolved.insert(key, cmd.clone());
+    Ok(cmd)
+}
+
+/// Pick the installed mmdc if it matches the pinned version, otherwise npx
+fn choose_pinned_mmdc(
+    installed: Option<(PathBuf, Option<String>)>,
+    npx: Option<PathBuf>,
+    pinned: &str,
+) -> ServerResult<MmdcCommand> {
+    let pinned = normalize_version(pinned);
+
+    if let Some((path, Some(version))) = installed {
+        if normalize_version(&version) == pinned {
+            return Ok(MmdcCommand {
+                program: path,
+                prefix_args: Vec::new(),
+                version: Some(version),
+            });
+        }
+    }
+
+    npx.map(|npx| MmdcCommand::npx(npx, pinned)).ok_or_else(|| {
+        ServerError::ToolNotFound(format!(
+            "mermaid-cli {pinned} requested, but the installed mmdc differs and npx was not found"
+        ))
+    })
+}
+
+fn normalize_version(version: &str) -> &str {
+    let version = version.trim();
+    version.strip_prefix('v').unwrap_or(version)
+}
+
+/// Ask mmdc for its version (`mmdc --version`)
+fn mmdc_version(path: &PathBuf) -> Option<String> {
+    let output = Command::new(path)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!version.is_empty()).then_some(version)
 }
 
 /// Find mmdc binary path
-fn find_mmdc() -> Result<PathBuf> {
+fn find_mmdc() -> ServerResult<PathBuf> {
+    let mut trust = trust::store();
+    let project = trust.root().map(project_mmdc);
+    find_mmdc_in(
+        env::var("MMDC_PATH").ok(),
+        project,
+        which::which("mmdc").ok(),
+        |path| trust.check(path),
+    )
+}
+
+/// mmdc installed by the workspace's own `npm install`
+fn project_mmdc(root: &Path) -> PathBuf {
+    let name = if cfg!(windows) { "mmdc.cmd" } else { "mmdc" };
+    root.join("node_modules").join(".bin").join(name)
+}
+
+/// Pick mmdc from `MMDC_PATH` if set, then the workspace's `node_modules`, then
+/// the `PATH` lookup, skipping candidates `trust` doesn't allow to run
+fn find_mmdc_in(
+    mmdc_path: Option<String>,
+    project: Option<PathBuf>,
+    on_path: Option<PathBuf>,
+    mut trust: impl FnMut(&Path) -> Trust,
+) -> ServerResult<PathBuf> {
+    let mut candidates = Vec::new();
+
     // Check MMDC_PATH environment variable
-    if let Ok(path) = env::var("MMDC_PATH") {
+    if let Some(path) = mmdc_path {
         let candidate = PathBuf::from(&path);
-        if candidate.is_file() {
-            return Ok(candidate);
+        if !candidate.is_file() {
+            return Err(ServerError::ToolNotFound(format!(
+                "MMDC_PATH points to '{}', but it is not a file",
+                candidate.display()
+            )));
         }
-        return Err(anyhow!(
-            "MMDC_PATH points to '{}', but it is not a file",
-            candidate.display()
-        ));
+        if !is_executable(&candidate) {
+            return Err(ServerError::ToolNotFound(format!(
+                "MMDC_PATH points to a non-executable file: '{}'",
+                candidate.display()
+            )));
+        }
+        candidates.push(candidate);
     }
 
+    candidates.extend(project.filter(|path| path.is_file() && is_executable(path)));
+
     // Search PATH
-    if let Ok(path) = which::which("mmdc") {
-        return Ok(path);
+    if let Some(path) = on_path {
+        if !is_executable(&path) {
+            return Err(ServerError::ToolNotFound(format!(
+                "mmdc on PATH is a non-executable file: '{}'",
+                path.display()
+            )));
+        }
+        candidates.push(path);
     }
 
-    Err(anyhow!(
-        "mmdc not found. Install it with: npm install -g @mermaid-js/mermaid-cli"
-    ))
+    let mut untrusted = Vec::new();
+    for candidate in candidates {
+        match trust(&candidate) {
+            trust if trust.is_trusted() => return Ok(candidate),
+            trust => {
+                info!("Skipping mmdc at {} ({trust:?})", candidate.display());
+                untrusted.push(candidate);
+            }
+        }
+    }
+    if let Some(candidate) = untrusted.first() {
+        return Err(ServerError::ToolNotFound(format!(
+            "mmdc at '{}' belongs to the workspace and has not been allowed to run",
+            candidate.display()
+        )));
+    }
+
+    Err(ServerError::ToolNotFound(format!(
+        "mmdc not found. Install it with: {}",
+        install_command(None)
+    )))
 }
 
-/// Sanitize SVG to prevent XSS attacks
-fn sanitize_svg(svg: &str) -> Result<String> {
-    // Reject SVGs containing script tags (case-insensitive)
-    if svg.to_lowercase().contains("<script") {
-        return Err(anyhow!("SVG contains <script> elements - blocked for security"));
+/// Every mmdc the server could run, with the trust decision in effect for
+/// each, and what renders use now
+pub fn doctor(config: &Config) -> serde_json::Value {
+    let trust = trust::store();
+    let mut candidates = Vec::new();
+    if let Ok(path) = env::var("MMDC_PATH") {
+        candidates.push(("MMDC_PATH", PathBuf::from(path)));
+    }
+    if let Some(path) = trust.root().map(project_mmdc).filter(|path| path.is_file()) {
+        candidates.push(("node_modules", path));
     }
+    if let Ok(path) = which::which("mmdc") {
+        candidates.push(("PATH", path));
+    }
+    let candidates: Vec<_> = candidates
+        .into_iter()
+        .map(|(source, path)| {
+            serde_json::json!({ "source": source, "path": path, "trust": trust.peek(&path) })
+        })
+        .collect();
+    drop(trust);
 
-    let mut sanitized = svg.to_string();
+    let mmdc = match resolve_mmdc(config.mermaid_cli_version.as_deref()) {
+        Ok(cmd) => {
+            let mut mmdc = serde_json::json!({ "program": cmd.program, "version": cmd.version });
+            match effective_command(&cmd, config) {
+                Ok(command) => mmdc["command"] = command.into(),
+                Err(e) => mmdc["commandError"] = e.to_string().into(),
+            }
+            mmdc
+        }
+        Err(e) => serde_json::json!({ "error": e.to_string() }),
+    };
+    serde_json::json!({ "mmdc": mmdc, "candidates": candidates })
+}
 
-    // Remove event handler attributes (onclick, onmouseover, etc.)
-    sanitized = EVENT_HANDLER_ATTR
-        .replace_all(&sanitized, "")
-        .into_owned();
+/// How to get mermaid-cli, for editors to offer when there is none to run
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstallGuidance {
+    /// Why renders fail
+    pub message: String,
+    /// The command that installs mermaid-cli
+    pub install_hint: String,
+    pub docs_url: String,
+}
 
-    // Remove javascript: protocol in href attributes
-    sanitized = JAVASCRIPT_HREF_ATTR
-        .replace_all(&sanitized, "")
-        .into_owned();
+/// The npm command installing mermaid-cli, at the `pinned` version if any
+pub fn install_command(pinned: Option<&str>) -> String {
+    match pinned {
+        Some(version) => format!("npm install -g {MERMAID_CLI_PACKAGE}@{}", normalize_version(version)),
+        None => format!("npm install -g {MERMAID_CLI_PACKAGE}"),
+    }
+}
 
-    // Convert <foreignObject> to native SVG <text>
-    sanitized = convert_foreign_objects(&sanitized)?;
+/// Install guidance when renders fail because no mmdc is installed, or none
+/// of the pinned version and no npx to fetch it. None when mmdc resolves, and
+/// when a candidate exists but can't or may not run: that wants fixing, not
+/// installing.
+pub fn install_guidance(config: &Config) -> Option<InstallGuidance> {
+    let pinned = config.mermaid_cli_version.as_deref();
+    let error = resolve_mmdc(pinned).err()?;
+    if env::var_os("MMDC_PATH").is_some() || trust::store().root().map(project_mmdc).is_some_and(|path| path.is_file()) {
+        return None;
+    }
+    if pinned.is_none() && which::which("mmdc").is_ok() {
+        return None;
+    }
+    Some(InstallGuidance {
+        message: error.to_string(),
+        install_hint: install_command(pinned),
+        docs_url: MERMAID_CLI_DOCS_URL.to_string(),
+    })
+}
 
-    Ok(sanitized)
+/// Forget the resolved mmdc so the next render looks again, e.g. after a
+/// trust decision changed which candidates may run
+pub fn forget_resolved_mmdc() {
+    RESOLVED_MMDC.lock().unwrap_or_else(|e| e.into_inner()).clear();
 }
 
-/// Convert <foreignObject> elements to native SVG <text> elements
-fn convert_foreign_objects(svg: &str) -> Result<String> {
-    let mut result = svg.to_string();
+/// Whether `path` can be run: any execute permission bit on Unix
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).is_ok_and(|meta| meta.permissions().mode() & 0o111 != 0)
+}
+
+/// Whether `path` can be run: an `.exe`, `.cmd`, `.bat` or `.com` file on Windows
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ["exe", "cmd", "bat", "com"].contains(&ext.to_ascii_lowercase().as_str()))
+}
 
-    while let Some(caps) = FOREIGN_OBJECT_REGEX.captures(&result) {
-        let full_match = caps.get(0).unwrap().as_str();
-        let content = caps.get(1).unwrap().as_str();
-        let text = extract_text_from_html(content);
+/// Sanitize SVG to prevent XSS attacks.
+///
+/// After the script check the document is scanned once by [`SANITIZE_PASS`]
+/// and written to a single buffer, so the cost stays linear however many
+/// `<foreignObject>` labels a diagram has.
+fn sanitize_svg(svg: &str, policy: &SanitizePolicy) -> ServerResult<String> {
+    // Reject SVGs containing script tags (case-insensitive), also when spelled
+    // with character references, e.g. in an entity declared by the DOCTYPE
+    if contains_script(svg) {
+        return Err(ServerError::UnsafeSvg(
+            "SVG contains <script> elements - blocked for security".to_string(),
+        ));
+    }
 
-        if text.trim().is_empty() {
-            result = result.replace(full_match, "");
-            continue;
+    let mut sanitized = String::with_capacity(svg.len());
+    let mut rest = svg;
+    // Keep a plain XML declaration, drop one carrying anything else
+    if let Some(declaration) = XML_DECLARATION.find(svg) {
+        if BENIGN_XML_DECLARATION.is_match(declaration.as_str()) {
+            sanitized.push_str(declaration.as_str());
         }
+        rest = &svg[declaration.end()..];
+    }
 
-        let fill = "#333";
-        let text_element = if let Some(transform) = extract_attr(full_match, "transform") {
-            format!(
-                r#"<text transform="{transform}" text-anchor="start" dominant-baseline="hanging" font-family="Arial, sans-serif" font-size="14" fill="{fill}">{text}</text>"#
-            )
-        } else {
-            let x = extract_attr(full_match, "x")
-                .and_then(|v| v.parse::<f64>().ok())
-                .unwrap_or(0.0);
-            let y = extract_attr(full_match, "y")
-                .and_then(|v| v.parse::<f64>().ok())
-                .unwrap_or(0.0);
-            let w = extract_attr(full_match, "width")
-                .and_then(|v| v.parse::<f64>().ok())
-                .unwrap_or(0.0);
-            let h = extract_attr(full_match, "height")
-                .and_then(|v| v.parse::<f64>().ok())
-                .unwrap_or(0.0);
-
-            if w <= 0.0 || h <= 0.0 {
-                result = result.replace(full_match, "");
-                continue;
+    let mut copied = 0;
+    for caps in SANITIZE_PASS.captures_iter(rest) {
+        let found = caps.get(0).expect("whole match");
+        sanitized.push_str(&rest[copied..found.start()]);
+        copied = found.end();
+        if caps.name("link").is_some() && policy.allows_link(link_target(&caps)) {
+            sanitized.push_str(found.as_str());
+        } else if caps.name("foreign").is_some() {
+            if let Some(text) = convert_foreign_object(found.as_str(), policy) {
+                sanitized.push_str(&text);
+            }
+        }
+        // Event handlers, `javascript:` links and the DOCTYPE (so no external
+        // entity or DTD is ever resolved) are dropped
+    }
+    sanitized.push_str(&rest[copied..]);
+    Ok(sanitized)
+}
+
+/// The target of an [`EXTERNAL_LINK_ATTR`] match, in double or single quotes
+fn link_target<'a>(caps: &regex::Captures<'a>) -> &'a str {
+    caps.name("double").or_else(|| caps.name("single")).map_or("", |m| m.as_str())
+}
+
+/// `element` without the attributes the sanitizer drops elsewhere, so a
+/// `<foreignObject>` is converted as if they had never been there
+fn strip_unsafe_attrs(element: &str, policy: &SanitizePolicy) -> String {
+    let element = EVENT_HANDLER_ATTR.replace_all(element, "");
+    let element = JAVASCRIPT_HREF_ATTR.replace_all(&element, "");
+    EXTERNAL_LINK_ATTR
+        .replace_all(&element, |caps: &regex::Captures| {
+            let target = caps.get(1).or_else(|| caps.get(2)).map_or("", |m| m.as_str());
+            if policy.allows_link(target) {
+                caps[0].to_string()
+            } else {
+                String::new()
             }
+        })
+        .into_owned()
+}
 
-            let cx = x + w / 2.0;
-            let cy = y + h / 2.0;
-            format!(
-                r#"<text x="{cx:.2}" y="{cy:.2}" text-anchor="middle" dominant-baseline="middle" font-family="Arial, sans-serif" font-size="14" fill="{fill}">{text}</text>"#
-            )
+/// Whether `svg` has a `<script` tag, as written or after decoding character references
+fn contains_script(svg: &str) -> bool {
+    let decoded = CHARACTER_REFERENCE.replace_all(svg, |caps: &regex::Captures| {
+        let code = match (caps.get(1), caps.get(2)) {
+            (Some(hex), _) => u32::from_str_radix(hex.as_str(), 16).ok(),
+            (_, Some(decimal)) => decimal.as_str().parse().ok(),
+            _ => None,
         };
+        match code {
+            Some(code) => char::from_u32(code).map_or(String::new(), String::from),
+            None => match caps[3].to_ascii_lowercase().as_str() {
+                "lt" => "<",
+                "gt" => ">",
+                "quot" => "\"",
+                "apos" => "'",
+                _ => "&",
+            }
+            .to_string(),
+        }
+    });
+    [svg, &decoded].iter().any(|text| text.to_lowercase().contains("<script"))
+}
 
-        result = result.replace(full_match, &text_element);
+/// Convert a `<foreignObject>` element to a native SVG `<text>` element; None
+/// when it has no text or no area to place it in
+fn convert_foreign_object(element: &str, policy: &SanitizePolicy) -> Option<String> {
+    let element = strip_unsafe_attrs(element, policy);
+    let caps = FOREIGN_OBJECT_REGEX.captures(&element)?;
+    let full_match = caps.get(0).unwrap().as_str();
+    let text = extract_text_from_html(caps.get(1).unwrap().as_str());
+    if text.trim().is_empty() {
+        return None;
+    }
+    // Decoded entities go back in escaped, so a label can't turn into markup
+    let text = html_escape::encode_text(&text);
+
+    let fill = "#333";
+    if let Some(transform) = extract_attr(full_match, "transform") {
+        return Some(format!(
+            r#"<text transform="{transform}" text-anchor="start" dominant-baseline="hanging" font-family="Arial, sans-serif" font-size="14" fill="{fill}">{text}</text>"#
+        ));
+    }
+    let number = |attr: &str| {
+        extract_attr(full_match, attr)
+            .and_then(|v| v.parse::<f64>().ok())
+            .unwrap_or(0.0)
+    };
+    let (x, y, w, h) = (number("x"), number("y"), number("width"), number("height"));
+    if w <= 0.0 || h <= 0.0 {
+        return None;
     }
 
-    Ok(result)
+    let cx = x + w / 2.0;
+    let cy = y + h / 2.0;
+    Some(format!(
+        r#"<text x="{cx:.2}" y="{cy:.2}" text-anchor="middle" dominant-baseline="middle" font-family="Arial, sans-serif" font-size="14" fill="{fill}">{text}</text>"#
+    ))
 }
 
 /// Extract visible text from HTML content, stripping tags
@@ -179,21 +1090,442 @@ fn extract_text_from_html(html: &str) -> String {
     decoded.trim().to_string()
 }
 
-/// Extract an attribute value from an HTML/XML tag
-fn extract_attr(tag: &str, attr: &str) -> Option<String> {
-    let pattern = format!(r#"{}="([^"]*)""#, regex::escape(attr));
-    let re = Regex::new(&pattern).ok()?;
-    re.captures(tag).map(|c| c[1].to_string())
+/// Extract an attribute value from an HTML/XML tag: the first `attr="..."`
+fn extract_attr<'a>(tag: &'a str, attr: &str) -> Option<&'a str> {
+    let needle = format!("{attr}=\"");
+    let start = tag.find(&needle)? + needle.len();
+    let len = tag[start..].find('"')?;
+    Some(&tag[start..start + len])
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn pinned_version_uses_npx_when_installed_differs() {
+        let installed = Some((PathBuf::from("/usr/bin/mmdc"), Some("10.6.0".to_string())));
+        let cmd =
+            choose_pinned_mmdc(installed, Some(PathBuf::from("/usr/bin/npx")), "11.4.2").unwrap();
+
+        assert_eq!(cmd.program, PathBuf::from("/usr/bin/npx"));
+        assert_eq!(cmd.prefix_args, vec!["-y", "@mermaid-js/mermaid-cli@11.4.2"]);
+        assert_eq!(cmd.version.as_deref(), Some("11.4.2"));
+
+        let command = cmd.command();
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args, vec!["-y", "@mermaid-js/mermaid-cli@11.4.2"]);
+    }
+
+    #[test]
+    fn pinned_version_prefers_matching_installed_mmdc() {
+        let installed = Some((PathBuf::from("/usr/bin/mmdc"), Some("11.4.2".to_string())));
+        let cmd =
+            choose_pinned_mmdc(installed, Some(PathBuf::from("/usr/bin/npx")), "v11.4.2").unwrap();
+
+        assert_eq!(cmd.program, PathBuf::from("/usr/bin/mmdc"));
+        assert!(cmd.prefix_args.is_empty());
+    }
+
+    #[test]
+    fn pinned_version_without_npx_is_an_error() {
+        let installed = Some((PathBuf::from("/usr/bin/mmdc"), None));
+        assert!(choose_pinned_mmdc(installed, None, "11.4.2").is_err());
+        // What fixes it is installing that version
+        assert_eq!(install_command(Some("v11.4.2")), "npm install -g @mermaid-js/mermaid-cli@11.4.2");
+    }
+
+    #[cfg(unix)]
+    #[cfg(unix)]
+    #[test]
+    fn non_executable_mmdc_is_rejected_up_front() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("mmdc");
+        fs::write(&script, "not a program").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let from_env = find_mmdc_in(Some(script.display().to_string()), None, None, |_| Trust::Global).unwrap_err();
+        assert_eq!(
+            from_env.to_string(),
+            ServerError::ToolNotFound(format!("MMDC_PATH points to a non-executable file: '{}'", script.display()))
+                .to_string()
+        );
+        let from_path = find_mmdc_in(None, None, Some(script.clone()), |_| Trust::Global).unwrap_err();
+        assert!(matches!(&from_path, ServerError::ToolNotFound(m) if m.contains("non-executable")), "{from_path}");
+
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(find_mmdc_in(Some(script.display().to_string()), None, None, |_| Trust::Global).unwrap(), script);
+        assert_eq!(find_mmdc_in(None, None, Some(script.clone()), |_| Trust::Global).unwrap(), script);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn untrusted_project_mmdc_falls_back_to_the_next_candidate() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let project = project_mmdc(dir.path());
+        let global = dir.path().join("mmdc");
+        fs::create_dir_all(project.parent().unwrap()).unwrap();
+        for path in [&project, &global] {
+            fs::write(path, "#!/bin/sh\n").unwrap();
+            fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let trust_of = |allowed: Trust| {
+            let global = global.clone();
+            move |path: &Path| if path == global { Trust::Global } else { allowed }
+        };
+
+        let found = find_mmdc_in(None, Some(project.clone()), Some(global.clone()), trust_of(Trust::Allowed));
+        assert_eq!(found.unwrap(), project);
+        for skipped in [Trust::Pending, Trust::Denied] {
+            let found = find_mmdc_in(None, Some(project.clone()), Some(global.clone()), trust_of(skipped));
+            assert_eq!(found.unwrap(), global);
+        }
+        let only_project = find_mmdc_in(None, Some(project.clone()), None, trust_of(Trust::Pending)).unwrap_err();
+        assert!(only_project.to_string().contains("not been allowed"), "{only_project}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_renders_are_kept_when_asked() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("mmdc");
+        fs::write(&script, "#!/bin/sh\necho 'Parse error on line 2' >&2\nexit 1\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let mmdc = MmdcCommand {
+            program: script,
+            prefix_args: Vec::new(),
+            version: None,
+        };
+        let config = Config::default();
+        let kept_root = dir.path().join("kept");
+
+        let error = run_mmdc_with(&mmdc, "graph TD\n  A-->", "svg", &config, Some(&kept_root)).unwrap_err();
+        assert!(matches!(error, ServerError::RenderFailed(ref m) if m == "Parse error on line 2"), "{error}");
+        let kept: Vec<PathBuf> = fs::read_dir(&kept_root).unwrap().map(|e| e.unwrap().path()).collect();
+        assert_eq!(kept.len(), 1);
+        let kept = &kept[0];
+        assert_eq!(fs::read_to_string(kept.join("diagram.mmd")).unwrap(), "graph TD\n  A-->");
+        assert!(fs::read_to_string(kept.join("mermaid-config.json")).unwrap().contains("\"theme\""));
+        assert_eq!(fs::read_to_string(kept.join(KEPT_STDERR_FILE)).unwrap(), "Parse error on line 2\n");
+        let command = fs::read_to_string(kept.join(KEPT_COMMAND_FILE)).unwrap();
+        assert!(command.contains(&format!("-i {}", kept.join("diagram.mmd").display())), "{command}");
+
+        // Without the option nothing is kept
+        assert!(run_mmdc_with(&mmdc, "graph TD", "svg", &config, None).is_err());
+        assert_eq!(fs::read_dir(&kept_root).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn in_memory_renders_leave_nothing_behind() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("mmdc");
+        let runs = dir.path().join("runs.txt");
+        // Records the temp dir of each run, and fails on sources containing FAIL
+        fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\nwhile [ $# -gt 0 ]; do case \"$1\" in -i) input=\"$2\";; -o) output=\"$2\";; esac; shift; done\n\
+                 dirname \"$output\" >> {}\n\
+                 if grep -q FAIL \"$input\"; then echo 'Parse error' >&2; exit 1; fi\n\
+                 echo '<svg onload=\"x()\"><rect/></svg>' > \"$output\"\n",
+                runs.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let mmdc = MmdcCommand {
+            program: script,
+            prefix_args: Vec::new(),
+            version: None,
+        };
+        let config = Config::default();
+
+        for _ in 0..3 {
+            let svg = render_svg_with(&mmdc, "graph TD\n  A-->B", &config, None).unwrap();
+            assert_eq!(svg.trim(), accessibility::describe("<svg><rect/></svg>", "graph TD\n  A-->B"));
+        }
+        assert!(render_svg_with(&mmdc, "graph TD\n  FAIL", &config, None).is_err());
+
+        let temp_dirs = fs::read_to_string(&runs).unwrap();
+        assert_eq!(temp_dirs.lines().count(), 4);
+        assert!(temp_dirs.lines().all(|temp_dir| !Path::new(temp_dir).exists()), "{temp_dirs}");
+        // No cache (or anything else) appears next to the renderer
+        let mut left: Vec<_> = fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+        left.sort();
+        assert_eq!(left, ["mmdc", "runs.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn watermark_is_laid_over_the_sanitized_svg() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("mmdc");
+        fs::write(
+            &script,
+            "#!/bin/sh\nwhile [ $# -gt 0 ]; do case \"$1\" in -o) output=\"$2\";; esac; shift; done\n\
+             echo '<svg viewBox=\"0 0 100 50\" onload=\"x()\"><rect/></svg>' > \"$output\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let mmdc = MmdcCommand {
+            program: script,
+            prefix_args: Vec::new(),
+            version: None,
+        };
+        let config = Config {
+            watermark: Some(crate::config::Watermark {
+                text: "DRAFT <v2>".to_string(),
+                ..Default::default()
+            }),
+            ..Config::default()
+        };
+
+        let svg = render_svg_with(&mmdc, "graph TD\n  A-->B", &config, None).unwrap();
+        assert!(!svg.contains("onload"), "{svg}");
+        assert!(svg.contains(r#"<text x="50.00" y="25.00""#), "{svg}");
+        assert!(svg.contains(">DRAFT &lt;v2&gt;</text></svg>"), "{svg}");
+        // Renders with another watermark aren't served from the plain ones' cache
+        assert_ne!(backend_cache_version(Backend::Mmdc, &config), backend_cache_version(Backend::Mmdc, &Config::default()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn extra_args_reach_mmdc_after_the_managed_ones() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("mmdc");
+        let seen = dir.path().join("args.txt");
+        fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"$@\" > {}\nwhile [ $# -gt 0 ]; do case \"$1\" in -o) output=\"$2\";; esac; shift; done\n\
+                 echo '<svg></svg>' > \"$output\"\n",
+                seen.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let mmdc = MmdcCommand {
+            program: script,
+            prefix_args: Vec::new(),
+            version: None,
+        };
+        let config = Config {
+            extra_mmdc_args: vec!["--svgId".to_string(), "main".to_string(), "--cssFile=/home/me/style.css".to_string()],
+            ..Config::default()
+        };
+
+        run_mmdc_with(&mmdc, "graph TD", "svg", &config, None).unwrap();
+        let args = fs::read_to_string(&seen).unwrap();
+        assert!(args.trim_end().ends_with("-b white --svgId main --cssFile=/home/me/style.css"), "{args}");
+        assert_eq!(
+            effective_command(&mmdc, &config).unwrap(),
+            "mmdc -i diagram.mmd -o diagram.svg -c mermaid-config.json -e svg -t default -b white --svgId main --cssFile=style.css"
+        );
+        assert_ne!(backend_cache_version(Backend::Mmdc, &config), backend_cache_version(Backend::Mmdc, &Config::default()));
+
+        let overriding = Config {
+            extra_mmdc_args: vec!["-o".to_string(), "/tmp/elsewhere.svg".to_string()],
+            ..Config::default()
+        };
+        assert!(matches!(run_mmdc_with(&mmdc, "graph TD", "svg", &overriding, None), Err(ServerError::InvalidParams(_))));
+        assert!(effective_command(&mmdc, &overriding).is_err());
+    }
+
+    #[test]
+    fn extra_args_may_not_take_over_the_managed_files() {
+        let check = |arg: &str| check_extra_args(&[arg.to_string()]);
+        for allowed in ["--svgId", "--cssFile", "-C", "-q", "--iconPacks", "--width=800"] {
+            check(allowed).unwrap();
+        }
+        for rejected in ["-i", "-ifile.mmd", "--input", "-o", "--output=out.svg", "-c", "--configFile=c.json"] {
+            let error = check(rejected).unwrap_err();
+            assert!(error.to_string().contains(&format!("`{rejected}` would replace")), "{error}");
+        }
+        assert!(check("--svgId=a\0b").unwrap_err().to_string().contains("NUL"));
+
+        assert_eq!(redact_path("/usr/local/bin/mmdc"), "mmdc");
+        assert_eq!(redact_path("C:\\tools\\mmdc.cmd"), "mmdc.cmd");
+        assert_eq!(redact_path("logos#file:///home/me/icons/logos.json"), "logos#logos.json");
+        assert_eq!(redact_path("--svgId"), "--svgId");
+    }
+
+    #[test]
+    fn missing_icons_fail_before_mmdc_runs() {
+        let mmdc = MmdcCommand {
+            program: PathBuf::from("/nonexistent/mmdc"),
+            prefix_args: Vec::new(),
+            version: None,
+        };
+        let code = "architecture-beta\n  service db(logos:postgresql)[Database]";
+        let error = render_svg_with(&mmdc, code, &Config::default(), None).unwrap_err();
+        assert!(matches!(error, ServerError::ValidationFailed(_)), "{error}");
+        assert!(error.to_string().contains("icon `logos:postgresql`"), "{error}");
+    }
+
+    #[test]
+    fn render_budget_refuses_past_its_limit_until_reset() {
+        let budget = RenderBudget::new();
+        for _ in 0..3 {
+            budget.take(Some(3)).unwrap();
+        }
+        assert!(matches!(budget.take(Some(3)), Err(ServerError::RenderLimitReached(3))));
+        assert_eq!(budget.used(), 3);
+        // A higher limit, or none, lets more through
+        budget.take(Some(4)).unwrap();
+        budget.take(None).unwrap();
+        assert_eq!(budget.used(), 5);
+
+        budget.reset();
+        budget.take(Some(1)).unwrap();
+        assert!(budget.take(Some(1)).is_err());
+        assert!(budget.take(Some(0)).is_err());
+    }
+
+    #[test]
+    fn keep_temp_flag_and_quoting() {
+        assert!(flag_set(Some("1".to_string())) && flag_set(Some("TRUE".to_string())));
+        assert!(!flag_set(Some("0".to_string())) && !flag_set(None));
+        assert_eq!(shell_quote("/tmp/a-b/diagram.mmd"), "/tmp/a-b/diagram.mmd");
+        assert_eq!(shell_quote("it's here"), "'it'\\''s here'");
+    }
+
+    #[test]
+    fn output_format_is_passed_explicitly() {
+        let args_for = |output: &str, config: &Config| -> Vec<String> {
+            mmdc_args(Path::new("in.mmd"), Path::new(output), Path::new("config.json"), config)
+                .into_iter()
+                .map(|arg| arg.into_string().unwrap())
+                .collect()
+        };
+        let config = Config::default();
+        for (output, format) in [("out.svg", "svg"), ("out.png", "png"), ("out.PDF", "pdf")] {
+            let args = args_for(output, &config);
+            assert!(args.windows(2).any(|w| w == ["-e", format]), "{args:?}");
+        }
+
+        let inferred = Config {
+            mmdc_output_format_flag: false,
+            ..Config::default()
+        };
+        assert!(!args_for("out.svg", &inferred).contains(&"-e".to_string()));
+    }
+
+    #[test]
+    fn theme_option_changes_render_args() {
+        let args_for = |config: &Config| -> Vec<String> {
+            mmdc_args(Path::new("in.mmd"), Path::new("out.svg"), Path::new("config.json"), config)
+                .into_iter()
+                .map(|arg| arg.into_string().unwrap())
+                .collect()
+        };
+        let mut config = Config::default();
+        assert!(args_for(&config).windows(2).any(|w| w == ["-t", "default"]));
+        assert!(args_for(&config).windows(2).any(|w| w == ["-b", "white"]));
+
+        let before = backend_cache_version(Backend::Mmdc, &config);
+        config.set_option("theme", serde_json::json!("dark")).unwrap();
+        config.set_option("background", serde_json::json!("transparent")).unwrap();
+        let args = args_for(&config);
+        assert!(args.windows(2).any(|w| w == ["-t", "dark"]), "{args:?}");
+        assert!(args.windows(2).any(|w| w == ["-b", "transparent"]), "{args:?}");
+        let mermaid_config: serde_json::Value = serde_json::from_str(&mermaid_config_json(&config)).unwrap();
+        assert_eq!(mermaid_config["theme"], "dark");
+        assert_eq!(mermaid_config["backgroundColor"], "transparent");
+        assert_ne!(backend_cache_version(Backend::Mmdc, &config), before);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn preamble_is_rendered_after_the_frontmatter() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("mmdc");
+        fs::write(&script, "#!/bin/sh\nexit 1\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let mmdc = MmdcCommand {
+            program: script,
+            prefix_args: Vec::new(),
+            version: None,
+        };
+        let mut config = Config::default();
+        let before = backend_cache_version(Backend::Mmdc, &config);
+        config.set_option("preamble", serde_json::json!("%%{init: {\"theme\": \"forest\"}}%%\n")).unwrap();
+        assert_ne!(backend_cache_version(Backend::Mmdc, &config), before);
+
+        let kept_root = dir.path().join("kept");
+        let code = "---\ntitle: Flow\n---\ngraph TD\n  A-->B";
+        assert!(run_mmdc_with(&mmdc, code, "svg", &config, Some(&kept_root)).is_err());
+        let kept = fs::read_dir(&kept_root).unwrap().next().unwrap().unwrap().path();
+        assert_eq!(
+            fs::read_to_string(kept.join("diagram.mmd")).unwrap(),
+            "---\ntitle: Flow\n---\n%%{init: {\"theme\": \"forest\"}}%%\ngraph TD\n  A-->B"
+        );
+
+        // Code that already has it (a `.mmd` written with it) isn't given it twice
+        let (sent, map) = with_preamble("%%{init: {\"theme\": \"forest\"}}%%\ngraph TD", &config);
+        assert!(matches!(sent, Cow::Borrowed(_)));
+        assert_eq!(map, SourceMap::identity(2));
+        let (sent, map) = with_preamble("graph TD\n  A-->B", &config);
+        assert_eq!(sent, "%%{init: {\"theme\": \"forest\"}}%%\ngraph TD\n  A-->B");
+        assert_eq!(map.original_line(1), None);
+        assert_eq!(map.original_line(3), Some(1));
+    }
+
+    #[test]
+    fn effective_config_is_strict_unless_loose_is_chosen() {
+        let level = |config: &Config| -> serde_json::Value {
+            serde_json::from_str::<serde_json::Value>(&mermaid_config_json(config)).unwrap()["securityLevel"].clone()
+        };
+        let strict = Config::default();
+        assert_eq!(level(&strict), "strict");
+
+        let mut loose = Config::default();
+        loose.set_option("securityLevel", serde_json::json!("loose")).unwrap();
+        assert_eq!(level(&loose), "loose");
+        // Renders at another level aren't served from the strict ones' cache
+        assert_ne!(backend_cache_version(Backend::Mmdc, &loose), backend_cache_version(Backend::Mmdc, &strict));
+    }
+
+    #[test]
+    fn post_process_output_is_sanitized_again() {
+        let policy = SanitizePolicy::default();
+        let svg = r#"<svg><rect/></svg>"#.to_string();
+        let passthrough = PostProcess::from_config(&Config {
+            post_process_command: vec!["cat".to_string()],
+            ..Config::default()
+        })
+        .unwrap();
+        assert_eq!(post_process(svg.clone(), &passthrough, &policy), svg);
+
+        let injecting = PostProcess::from_config(&Config {
+            post_process_command: vec![
+                "echo".to_string(),
+                r#"<svg onload="x()"><rect/></svg>"#.to_string(),
+            ],
+            ..Config::default()
+        })
+        .unwrap();
+        assert_eq!(post_process(svg.clone(), &injecting, &policy).trim(), "<svg><rect/></svg>");
+
+        let scripting = PostProcess::from_config(&Config {
+            post_process_command: vec!["echo".to_string(), "<svg><script/></svg>".to_string()],
+            ..Config::default()
+        })
+        .unwrap();
+        assert_eq!(post_process(svg.clone(), &scripting, &policy), svg);
+    }
+
     #[test]
     fn rejects_script_tags() {
         let svg = "<svg><script>alert('xss')</script></svg>";
-        assert!(sanitize_svg(svg).is_err());
+        assert!(sanitize_svg(svg, &SanitizePolicy::default()).is_err());
     }
 
     #[test]
@@ -203,14 +1535,49 @@ mod tests {
             "<svg><Script>alert('xss')</Script></svg>",
             "<svg><ScRiPt>alert('xss')</ScRiPt></svg>",
         ] {
-            assert!(sanitize_svg(svg).is_err());
+            assert!(sanitize_svg(svg, &SanitizePolicy::default()).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_script_tags_behind_character_references() {
+        for svg in [
+            "<svg>&#60;script&#62;alert(1)&#60;/script&#62;</svg>",
+            "<svg>&#x3C;SCRIPT>alert(1)</svg>",
+            r#"<!DOCTYPE svg [<!ENTITY s "&lt;script&gt;alert(1)&lt;/script&gt;">]><svg>&s;</svg>"#,
+        ] {
+            assert!(sanitize_svg(svg, &SanitizePolicy::default()).is_err(), "{svg}");
         }
+        // Other escaped markup is just text
+        assert!(sanitize_svg("<svg><text>&lt;b&gt; &#38; scripts</text></svg>", &SanitizePolicy::default()).is_ok());
+    }
+
+    #[test]
+    fn removes_doctype_with_external_entities() {
+        let svg = "<?xml version=\"1.0\"?>\n<!DOCTYPE svg [\n  <!ENTITY xxe SYSTEM \"file:///etc/passwd\">\n]>\n<svg><text>&xxe;</text></svg>";
+        let result = sanitize_svg(svg, &SanitizePolicy::default()).unwrap();
+        assert!(!result.contains("DOCTYPE"));
+        assert!(!result.contains("ENTITY"));
+        assert!(!result.contains("/etc/passwd"));
+        assert!(result.starts_with("<?xml version=\"1.0\"?>"));
+
+        let public = r#"<!DOCTYPE svg PUBLIC "-//W3C//DTD SVG 1.1//EN" "http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd"><svg/>"#;
+        assert_eq!(sanitize_svg(public, &SanitizePolicy::default()).unwrap(), "<svg/>");
+    }
+
+    #[test]
+    fn keeps_plain_xml_declaration() {
+        let svg = "<?xml version='1.0' encoding=\"UTF-8\" standalone=\"no\"?>\n<svg/>";
+        assert_eq!(sanitize_svg(svg, &SanitizePolicy::default()).unwrap(), svg);
+
+        let odd = "<?xml version=\"1.0\" href=\"http://evil.example/x.xsl\"?><svg/>";
+        assert_eq!(sanitize_svg(odd, &SanitizePolicy::default()).unwrap(), "<svg/>");
     }
 
     #[test]
     fn removes_event_handlers() {
         let svg = r#"<svg><rect onclick="alert()" width="10" /></svg>"#;
-        let result = sanitize_svg(svg).unwrap();
+        let result = sanitize_svg(svg, &SanitizePolicy::default()).unwrap();
         assert!(!result.contains("onclick"));
         assert!(!result.contains("alert()"));
         assert!(result.contains("<rect"));
@@ -219,28 +1586,199 @@ mod tests {
     #[test]
     fn removes_event_handlers_single_quotes() {
         let svg = r#"<svg><rect onmouseover='doSomething()' width="10" /></svg>"#;
-        let result = sanitize_svg(svg).unwrap();
+        let result = sanitize_svg(svg, &SanitizePolicy::default()).unwrap();
         assert!(!result.contains("onmouseover"));
     }
 
     #[test]
     fn removes_javascript_hrefs() {
         let svg = r#"<svg><a href="javascript:alert('xss')">link</a></svg>"#;
-        let result = sanitize_svg(svg).unwrap();
+        let result = sanitize_svg(svg, &SanitizePolicy::default()).unwrap();
         assert!(!result.contains("javascript:"));
     }
 
     #[test]
     fn removes_xlink_javascript_hrefs() {
         let svg = r#"<svg><a xlink:href='javascript:malicious()'>link</a></svg>"#;
-        let result = sanitize_svg(svg).unwrap();
+        let result = sanitize_svg(svg, &SanitizePolicy::default()).unwrap();
         assert!(!result.contains("javascript:"));
     }
 
+    fn allowlist(hosts: &[&str]) -> SanitizePolicy {
+        SanitizePolicy {
+            allowed_link_hosts: hosts.iter().map(|h| h.to_string()).collect(),
+            block_external_links: false,
+        }
+    }
+
+    #[test]
+    fn keeps_all_https_links_by_default() {
+        let svg = r#"<svg><a href="https://anywhere.example/x">a</a></svg>"#;
+        let result = sanitize_svg(svg, &SanitizePolicy::default()).unwrap();
+        assert!(result.contains("https://anywhere.example/x"));
+    }
+
+    #[test]
+    fn allowlist_matches_host_and_subdomains() {
+        let policy = allowlist(&["example.com"]);
+        assert!(policy.allows_link("https://example.com/a"));
+        assert!(policy.allows_link("https://docs.Example.com/a"));
+        assert!(!policy.allows_link("https://badexample.com/a"));
+        assert!(!policy.allows_link("https://example.com.evil.net/a"));
+        assert!(policy.allows_link("#node-1"));
+        assert!(policy.allows_link("diagram.svg"));
+    }
+
+    #[test]
+    fn removes_links_to_hosts_outside_allowlist() {
+        let svg = r#"<svg><a xlink:href="https://evil.net/x">a</a><a href='https://cdn.example.com/i.png'>b</a><image href="https://tracker.io/p.gif"/></svg>"#;
+        let result = sanitize_svg(svg, &allowlist(&["*.example.com"])).unwrap();
+        assert!(!result.contains("evil.net"));
+        assert!(!result.contains("tracker.io"));
+        assert!(result.contains("https://cdn.example.com/i.png"));
+    }
+
+    #[test]
+    fn strict_policy_removes_every_external_link() {
+        let policy = SanitizePolicy {
+            allowed_link_hosts: vec!["example.com".to_string()],
+            block_external_links: true,
+        };
+        let svg = r##"<svg><a href="https://example.com/x">a</a><a href="#local">b</a></svg>"##;
+        let result = sanitize_svg(svg, &policy).unwrap();
+        assert!(!result.contains("https://"));
+        assert!(result.contains("#local"));
+    }
+
+    /// The sanitizer as it was before the single pass: one full-document
+    /// rewrite per step, and a rescan plus `replace` per `<foreignObject>`
+    fn legacy_sanitize_svg(svg: &str, policy: &SanitizePolicy) -> String {
+        let mut sanitized = DOCTYPE_REGEX.replace_all(svg, "").into_owned();
+        if let Some(declaration) = XML_DECLARATION.find(&sanitized) {
+            if !BENIGN_XML_DECLARATION.is_match(declaration.as_str()) {
+                sanitized.replace_range(declaration.range(), "");
+            }
+        }
+        sanitized = EVENT_HANDLER_ATTR.replace_all(&sanitized, "").into_owned();
+        sanitized = JAVASCRIPT_HREF_ATTR.replace_all(&sanitized, "").into_owned();
+        sanitized = strip_unsafe_attrs(&sanitized, policy);
+
+        let attr = |tag: &str, attr: &str| {
+            Regex::new(&format!(r#"{}="([^"]*)""#, regex::escape(attr)))
+                .unwrap()
+                .captures(tag)
+                .map(|c| c[1].to_string())
+        };
+        while let Some(caps) = FOREIGN_OBJECT_REGEX.captures(&sanitized) {
+            let full_match = caps.get(0).unwrap().as_str().to_string();
+            let text = extract_text_from_html(caps.get(1).unwrap().as_str());
+            let number = |name: &str| attr(&full_match, name).and_then(|v| v.parse::<f64>().ok()).unwrap_or(0.0);
+            let replacement = if text.trim().is_empty() {
+                String::new()
+            } else if let Some(transform) = attr(&full_match, "transform") {
+                format!(
+                    r##"<text transform="{transform}" text-anchor="start" dominant-baseline="hanging" font-family="Arial, sans-serif" font-size="14" fill="#333">{text}</text>"##
+                )
+            } else if number("width") <= 0.0 || number("height") <= 0.0 {
+                String::new()
+            } else {
+                let cx = number("x") + number("width") / 2.0;
+                let cy = number("y") + number("height") / 2.0;
+                format!(
+                    r##"<text x="{cx:.2}" y="{cy:.2}" text-anchor="middle" dominant-baseline="middle" font-family="Arial, sans-serif" font-size="14" fill="#333">{text}</text>"##
+                )
+            };
+            sanitized = sanitized.replace(&full_match, &replacement);
+        }
+        sanitized
+    }
+
+    /// The SVGs the tests above and below sanitize
+    const FIXTURES: &[&str] = &[
+        r#"<svg><text>&lt;b&gt; &#38; scripts</text></svg>"#,
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE svg [\n  <!ENTITY xxe SYSTEM \"file:///etc/passwd\">\n]>\n<svg><text>&xxe;</text></svg>",
+        r#"<!DOCTYPE svg PUBLIC "-//W3C//DTD SVG 1.1//EN" "http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd"><svg/>"#,
+        "<?xml version='1.0' encoding=\"UTF-8\" standalone=\"no\"?>\n<svg/>",
+        "<?xml version=\"1.0\" href=\"http://evil.example/x.xsl\"?><svg/>",
+        r#"<svg><rect onclick="alert()" width="10" /></svg>"#,
+        r#"<svg><rect onmouseover='doSomething()' width="10" /></svg>"#,
+        r#"<svg><a href="javascript:alert('xss')">link</a></svg>"#,
+        r#"<svg><a xlink:href='javascript:malicious()'>link</a></svg>"#,
+        r#"<svg><a href="https://anywhere.example/x">a</a></svg>"#,
+        r#"<svg><a xlink:href="https://evil.net/x">a</a><a href='https://cdn.example.com/i.png'>b</a><image href="https://tracker.io/p.gif"/></svg>"#,
+        r##"<svg><a href="https://example.com/x">a</a><a href="#local">b</a></svg>"##,
+        r#"<svg width="100" height="50"><foreignObject x="10" y="10" width="80" height="30"><div>Hello</div></foreignObject></svg>"#,
+        r#"<svg><foreignObject x="0" y="0" width="0" height="0"><div></div></foreignObject></svg>"#,
+        r#"<svg><foreignObject x="20" y="30" width="160" height="40"><p>Label</p></foreignObject></svg>"#,
+        r#"<svg><foreignObject x="10" y="10" width="80" height="30"><div><p>Label</p></div></foreignObject></svg>"#,
+        r#"<svg><g transform="translate(5, 5)"><foreignObject transform="translate(1, 2)" width="9" height="9"><span onclick="x()">A</span></foreignObject><foreignObject onload="y()" x="1" y="1" width="8" height="8"><a href="https://evil.net/">B</a></foreignObject></g></svg>"#,
+    ];
+
+    #[test]
+    fn single_pass_matches_the_previous_sanitizer() {
+        let policies = [
+            SanitizePolicy::default(),
+            allowlist(&["*.example.com"]),
+            SanitizePolicy {
+                allowed_link_hosts: vec!["example.com".to_string()],
+                block_external_links: true,
+            },
+        ];
+        for svg in FIXTURES {
+            for policy in &policies {
+                assert_eq!(sanitize_svg(svg, policy).unwrap(), legacy_sanitize_svg(svg, policy), "{svg}");
+            }
+        }
+    }
+
+    #[test]
+    fn many_foreign_objects_stay_linear() {
+        let svg = |count: usize| {
+            let labels: String = (0..count)
+                .map(|i| {
+                    format!(
+                        r#"<g class="node"><rect width="80" height="30"/><foreignObject x="{i}" y="10" width="80" height="30"><div xmlns="http://www.w3.org/1999/xhtml"><span class="nodeLabel">Node {i}</span></div></foreignObject></g>"#
+                    )
+                })
+                .collect();
+            format!(r#"<svg xmlns="http://www.w3.org/2000/svg">{labels}</svg>"#)
+        };
+        // Fastest of a few runs, so a busy machine doesn't skew the ratio
+        let fastest = |svg: &str| {
+            (0..3)
+                .map(|_| {
+                    let started = Instant::now();
+                    sanitize_svg(svg, &SanitizePolicy::default()).unwrap();
+                    started.elapsed()
+                })
+                .min()
+                .unwrap()
+        };
+
+        let (small, large) = (svg(250), svg(1000));
+        let (small_time, large_time) = (fastest(&small), fastest(&large));
+        // Four times the labels: about four times the time, where quadratic
+        // work would take sixteen
+        assert!(large_time < small_time * 10, "{small_time:?} for 250 labels, {large_time:?} for 1000");
+
+        let sanitized = sanitize_svg(&large, &SanitizePolicy::default()).unwrap();
+        assert!(!sanitized.contains("foreignObject"));
+        assert_eq!(sanitized.matches("<text ").count(), 1000);
+        assert!(sanitized.contains(">Node 999</text>"));
+    }
+
+    #[test]
+    fn decoded_labels_stay_text() {
+        let svg = r#"<svg><foreignObject x="0" y="0" width="10" height="10"><div>a &amp; &lt;image href="x"/&gt;</div></foreignObject></svg>"#;
+        let sanitized = sanitize_svg(svg, &SanitizePolicy::default()).unwrap();
+        assert!(sanitized.contains(">a &amp; &lt;image href=\"x\"/&gt;</text>"), "{sanitized}");
+        assert!(roxmltree::Document::parse(&sanitized).is_ok());
+    }
+
     #[test]
     fn converts_foreign_objects() {
         let svg = r#"<svg width="100" height="50"><foreignObject x="10" y="10" width="80" height="30"><div>Hello</div></foreignObject></svg>"#;
-        let result = sanitize_svg(svg).unwrap();
+        let result = sanitize_svg(svg, &SanitizePolicy::default()).unwrap();
         assert!(!result.contains("foreignObject"));
         assert!(result.contains("<text"));
         assert!(result.contains("Hello"));
@@ -249,7 +1787,7 @@ mod tests {
     #[test]
     fn skips_empty_foreign_objects() {
         let svg = r#"<svg><foreignObject x="0" y="0" width="0" height="0"><div></div></foreignObject></svg>"#;
-        let result = sanitize_svg(svg).unwrap();
+        let result = sanitize_svg(svg, &SanitizePolicy::default()).unwrap();
         assert!(!result.contains("foreignObject"));
         assert!(!result.contains("<text"));
     }
@@ -257,7 +1795,7 @@ mod tests {
     #[test]
     fn centers_text_in_foreign_object() {
         let svg = r#"<svg><foreignObject x="20" y="30" width="160" height="40"><p>Label</p></foreignObject></svg>"#;
-        let result = sanitize_svg(svg).unwrap();
+        let result = sanitize_svg(svg, &SanitizePolicy::default()).unwrap();
         assert!(result.contains(r#"x="100.00""#));
         assert!(result.contains(r#"y="50.00""#));
         assert!(result.contains("Label"));
@@ -266,7 +1804,7 @@ mod tests {
     #[test]
     fn strips_html_tags_from_foreign_object() {
         let svg = r#"<svg><foreignObject x="10" y="10" width="80" height="30"><div><p>Label</p></div></foreignObject></svg>"#;
-        let result = sanitize_svg(svg).unwrap();
+        let result = sanitize_svg(svg, &SanitizePolicy::default()).unwrap();
         assert!(result.contains("Label"));
         assert!(!result.contains("<p>"));
         assert!(!result.contains("<div>"));
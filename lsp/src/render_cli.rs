@@ -0,0 +1,392 @@
+use lsp_types::{Diagnostic, DiagnosticSeverity};
+use std::{
+    env, fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+use url::Url;
+
+use crate::backend;
+use crate::config::Config;
+use crate::error::{ServerError, ServerResult};
+use crate::lint::LintDiagnostic;
+use crate::paths;
+use crate::position::PositionEncoding;
+use crate::render;
+
+/// Exit codes of `mermaid-lsp --render` and `--render-doc`
+pub const EXIT_OK: i32 = 0;
+/// A diagram failed the checks run before rendering
+pub const EXIT_INVALID: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+/// mmdc is missing, failed, or produced an unsafe SVG
+pub const EXIT_RENDER: i32 = 3;
+/// Reading the input or writing the output failed
+pub const EXIT_IO: i32 = 4;
+
+const USAGE: &str = "usage: mermaid-lsp --render <INPUT.mmd|-> [-o <OUTPUT|->] [--format svg|png] [--no-cache] [--theme THEME] [--config FILE.json]\n       mermaid-lsp --render-doc <INPUT.md> [--in-place] [--theme THEME] [--config FILE.json]";
+
+/// Stands for stdin as the input and stdout as the output
+const STDIO: &str = "-";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImageFormat {
+    #[default]
+    Svg,
+    Png,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RenderMode {
+    /// Render one diagram source to an image
+    Diagram {
+        input: PathBuf,
+        output: PathBuf,
+        format: ImageFormat,
+        /// Render in memory instead of through the output directory's cache
+        no_cache: bool,
+    },
+    /// Replace every fence of a markdown file, as "Render All" does
+    Document { input: PathBuf, in_place: bool },
+}
+
+/// Command line of `mermaid-lsp --render ...` / `mermaid-lsp --render-doc ...`
+#[derive(Debug, PartialEq)]
+pub struct RenderArgs {
+    pub mode: RenderMode,
+    /// Overrides the theme of `config`
+    pub theme: Option<String>,
+    /// Server options, in the same JSON as `initialization_options`
+    pub config: Option<PathBuf>,
+}
+
+impl RenderArgs {
+    /// Parse the arguments following the program name
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut diagram = None;
+        let mut document = None;
+        let mut output = None;
+        let mut format = None;
+        let mut theme = None;
+        let mut config = None;
+        let mut in_place = false;
+        let mut no_cache = false;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+                _ => (arg.as_str(), None),
+            };
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next().cloned())
+                    .ok_or_else(|| format!("{name} expects a value"))
+            };
+            match name {
+                "--render" => diagram = Some(PathBuf::from(value()?)),
+                "--render-doc" => document = Some(PathBuf::from(value()?)),
+                "-o" | "--output" => output = Some(PathBuf::from(value()?)),
+                "--format" => {
+                    format = Some(match value()?.as_str() {
+                        "svg" => ImageFormat::Svg,
+                        "png" => ImageFormat::Png,
+                        other => return Err(format!("Unknown format `{other}` (expected svg or png)")),
+                    })
+                }
+                "--theme" => theme = Some(value()?),
+                "--config" => config = Some(PathBuf::from(value()?)),
+                "--in-place" => in_place = true,
+                "--no-cache" => no_cache = true,
+                other => return Err(format!("Unexpected argument `{other}`")),
+            }
+        }
+
+        let mode = match (diagram, document) {
+            (Some(_), Some(_)) => return Err("--render and --render-doc can't be combined".to_string()),
+            (Some(input), None) => {
+                if in_place {
+                    return Err("--in-place only applies to --render-doc".to_string());
+                }
+                let output = output.unwrap_or_else(|| PathBuf::from(STDIO));
+                let from_extension = output
+                    .extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("png"))
+                    .then_some(ImageFormat::Png);
+                RenderMode::Diagram {
+                    input,
+                    format: format.or(from_extension).unwrap_or_default(),
+                    output,
+                    no_cache,
+                }
+            }
+            (None, Some(input)) => {
+                if output.is_some() || format.is_some() || no_cache {
+                    return Err("-o, --format and --no-cache only apply to --render".to_string());
+                }
+                RenderMode::Document { input, in_place }
+            }
+            (None, None) => return Err("Expected --render or --render-doc".to_string()),
+        };
+        Ok(Self { mode, theme, config })
+    }
+}
+
+/// Render as the command line asks and return the process exit code. Only
+/// the rendered output goes to stdout; messages go to stderr.
+pub fn run(args: &[String]) -> i32 {
+    let args = match RenderArgs::parse(args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("mermaid-lsp: {e}");
+            eprintln!("{USAGE}");
+            return EXIT_USAGE;
+        }
+    };
+
+    let result = load_config(&args).and_then(|config| match &args.mode {
+        RenderMode::Diagram {
+            input,
+            output,
+            format,
+            no_cache,
+        } => render_diagram(input, output, *format, *no_cache, &config),
+        RenderMode::Document { input, in_place } => render_document(input, *in_place, &config),
+    });
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("mermaid-lsp: {e}");
+            exit_code(&e)
+        }
+    }
+}
+
+/// Exit code for a failure, by its kind
+pub fn exit_code(error: &ServerError) -> i32 {
+    match error {
+        ServerError::ValidationFailed(_) => EXIT_INVALID,
+        ServerError::InvalidParams(_) => EXIT_USAGE,
+        ServerError::Io { .. } | ServerError::ReadOnly(_) | ServerError::NotLocalFile(_) => EXIT_IO,
+        _ => EXIT_RENDER,
+    }
+}
+
+/// The defaults, then `--config`, then `--theme`, each validated as the
+/// server validates its options
+fn load_config(args: &RenderArgs) -> ServerResult<Config> {
+    let mut config = match &args.config {
+        Some(path) => read_config(path)?,
+        None => Config::default(),
+    };
+    if let Some(theme) = &args.theme {
+        config.set_option("theme", theme.clone().into()).map_err(ServerError::InvalidParams)?;
+    }
+    Ok(config)
+}
+
+/// Server options from a JSON file like `initialization_options`
+pub fn read_config(path: &Path) -> ServerResult<Config> {
+    let json = fs::read_to_string(path).map_err(ServerError::io(format!("Failed to read {}", path.display())))?;
+    serde_json::from_str(&json).map_err(|e| ServerError::InvalidParams(format!("{}: {e}", path.display())))
+}
+
+/// Render one diagram source. SVGs go through the document's render cache,
+/// in the output directory next to the input (or the current directory for
+/// stdin), unless `no_cache` renders them without writing anything.
+fn render_diagram(
+    input: &Path,
+    output: &Path,
+    format: ImageFormat,
+    no_cache: bool,
+    config: &Config,
+) -> ServerResult<i32> {
+    let code = if input == Path::new(STDIO) {
+        let mut code = String::new();
+        io::stdin().read_to_string(&mut code).map_err(ServerError::io("Failed to read stdin"))?;
+        code
+    } else {
+        fs::read_to_string(input).map_err(ServerError::io(format!("Failed to read {}", input.display())))?
+    };
+    // Fence code is joined with `\n`; a source file gets the same treatment
+    let code = code.replace("\r\n", "\n");
+    let code = code.trim_end();
+
+    let diagnostics = crate::diagnostics::check_diagram(code, 0, PositionEncoding::Utf16, config);
+    report(input, &diagnostics)?;
+
+    let image = match format {
+        ImageFormat::Svg if no_cache => {
+            let backend = backend::select("", config)?;
+            render::render_in_memory(code, backend, config)?.into_bytes()
+        }
+        ImageFormat::Svg => {
+            let base_dir = match input.parent() {
+                _ if input == Path::new(STDIO) => env::current_dir().map_err(ServerError::io("Failed to read the current directory"))?,
+                Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+                _ => PathBuf::from("."),
+            };
+            let mermaid_dir = crate::ensure_mermaid_dir(&base_dir, config)?;
+            let backend = backend::select("", config)?;
+            crate::render_cached(&mermaid_dir, code, backend, config)?.0.into_bytes()
+        }
+        ImageFormat::Png => render::render_png(code, config)?,
+    };
+
+    if output == Path::new(STDIO) {
+        let mut stdout = io::stdout().lock();
+        stdout
+            .write_all(&image)
+            .and_then(|()| stdout.flush())
+            .map_err(ServerError::io("Failed to write to stdout"))?;
+    } else {
+        fs::write(output, image).map_err(ServerError::io(format!("Failed to write {}", output.display())))?;
+    }
+    Ok(EXIT_OK)
+}
+
+/// Render every fence of a markdown file, writing it back with `in_place` or
+/// printing the new text otherwise. Fences that fail are reported and left as
+/// they are.
+fn render_document(input: &Path, in_place: bool, config: &Config) -> ServerResult<i32> {
+    // Not canonicalized: rendered files go where the server would put them,
+    // beside a symlinked directory as it is named
+    let path = std::path::absolute(input)
+        .map(|path| paths::simplify(&paths::normalize(&path)))
+        .map_err(ServerError::io(format!("Failed to read {}", input.display())))?;
+    let uri = Url::from_file_path(&path)
+        .map_err(|()| ServerError::InvalidParams(format!("{} is not a file path", path.display())))?;
+    let doc = fs::read_to_string(&path).map_err(ServerError::io(format!("Failed to read {}", input.display())))?;
+
+    let diagnostics = crate::document_diagnostics(&doc, None, PositionEncoding::Utf16, config);
+    report(input, &diagnostics)?;
+    if let Some(reason) = crate::read_only_reason(&uri, config) {
+        return Err(ServerError::ReadOnly(reason));
+    }
+
+    let (rendered, summary) = crate::render_document(&uri, &doc, config)?;
+    for failure in &summary.failed {
+        eprintln!("{}:{}: error: {}", input.display(), failure.line + 1, failure.message);
+    }
+    if in_place {
+        if rendered != doc {
+            fs::write(&path, &rendered).map_err(ServerError::io(format!("Failed to write {}", input.display())))?;
+        }
+    } else {
+        print!("{rendered}");
+    }
+    eprintln!(
+        "{}: rendered {}, failed {}, ignored {}",
+        input.display(),
+        summary.rendered,
+        summary.failed.len(),
+        summary.ignored
+    );
+    Ok(if summary.failed.is_empty() { EXIT_OK } else { EXIT_RENDER })
+}
+
+/// Print the checks' findings to stderr, as `--lint` does, and fail if any is an error
+fn report(input: &Path, diagnostics: &[Diagnostic]) -> ServerResult<()> {
+    for diagnostic in diagnostics {
+        let d = LintDiagnostic::from(diagnostic);
+        eprintln!("{}:{}:{}: {}: {}", input.display(), d.line, d.col, d.severity, d.message);
+    }
+    let errors = diagnostics
+        .iter()
+        .filter(|d| d.severity == Some(DiagnosticSeverity::ERROR))
+        .count();
+    if errors > 0 {
+        return Err(ServerError::ValidationFailed(format!("{} has {errors} error(s)", input.display())));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(list: &[&str]) -> Result<RenderArgs, String> {
+        RenderArgs::parse(&list.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn parses_arguments() {
+        assert_eq!(
+            parse(&["--render", "in.mmd", "-o", "out.png", "--theme=dark"]).unwrap(),
+            RenderArgs {
+                mode: RenderMode::Diagram {
+                    input: PathBuf::from("in.mmd"),
+                    output: PathBuf::from("out.png"),
+                    format: ImageFormat::Png,
+                    no_cache: false,
+                },
+                theme: Some("dark".to_string()),
+                config: None,
+            }
+        );
+        let streamed = parse(&["--render", "-", "--format", "svg", "--config", "c.json", "--no-cache"]).unwrap();
+        assert_eq!(
+            streamed.mode,
+            RenderMode::Diagram {
+                input: PathBuf::from("-"),
+                output: PathBuf::from("-"),
+                format: ImageFormat::Svg,
+                no_cache: true,
+            }
+        );
+        assert_eq!(streamed.config, Some(PathBuf::from("c.json")));
+        assert_eq!(
+            parse(&["--render-doc", "README.md", "--in-place"]).unwrap().mode,
+            RenderMode::Document {
+                input: PathBuf::from("README.md"),
+                in_place: true,
+            }
+        );
+
+        for invalid in [
+            &["--render"][..],
+            &["--render", "a.mmd", "--format", "pdf"],
+            &["--render", "a.mmd", "--render-doc", "b.md"],
+            &["--render", "a.mmd", "--in-place"],
+            &["--render-doc", "b.md", "-o", "c.md"],
+            &["--render-doc", "b.md", "--no-cache"],
+            &["--render-doc", "b.md", "--fix"],
+            &["--theme", "dark"],
+        ] {
+            assert!(parse(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn failures_map_to_distinct_exit_codes() {
+        assert_eq!(exit_code(&ServerError::ValidationFailed("x".to_string())), EXIT_INVALID);
+        assert_eq!(exit_code(&ServerError::RenderFailed("x".to_string())), EXIT_RENDER);
+        assert_eq!(exit_code(&ServerError::ToolNotFound("x".to_string())), EXIT_RENDER);
+        assert_eq!(exit_code(&ServerError::io("x")(io::Error::other("y"))), EXIT_IO);
+        assert_eq!(exit_code(&ServerError::InvalidParams("x".to_string())), EXIT_USAGE);
+    }
+
+    #[test]
+    fn config_layers_file_then_flags() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("config.json");
+        fs::write(&file, r#"{ "theme": "forest", "background": "transparent" }"#).unwrap();
+        let args = |theme: Option<&str>, config: &Path| RenderArgs {
+            mode: RenderMode::Document {
+                input: PathBuf::from("a.md"),
+                in_place: false,
+            },
+            theme: theme.map(str::to_string),
+            config: Some(config.to_path_buf()),
+        };
+
+        let config = load_config(&args(None, &file)).unwrap();
+        assert_eq!((config.theme.as_str(), config.background.as_str()), ("forest", "transparent"));
+        assert_eq!(load_config(&args(Some("dark"), &file)).unwrap().theme, "dark");
+
+        fs::write(&file, r#"{ "renderChunkSize": "many" }"#).unwrap();
+        assert!(matches!(load_config(&args(None, &file)), Err(ServerError::InvalidParams(_))));
+        assert!(matches!(load_config(&args(None, &dir.path().join("missing.json"))), Err(ServerError::Io { .. })));
+    }
+}
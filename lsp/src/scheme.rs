@@ -0,0 +1,76 @@
+use std::path::PathBuf;
+
+use url::Url;
+
+/// Where an open document lives, from its URI
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DocumentLocation {
+    /// A file on this machine
+    Local(PathBuf),
+    /// A buffer that was never saved (`untitled:`)
+    Untitled,
+    /// A remote, diff or other virtual buffer (any other scheme, or a `file:`
+    /// URI on another host)
+    Virtual,
+}
+
+/// Classify a document URI. Every location supports the read-only features
+/// (diagnostics, code actions, `copyAsMarkdown`); only local files can have
+/// rendered files written next to them.
+pub fn classify(uri: &Url) -> DocumentLocation {
+    match uri.scheme() {
+        "file" => uri
+            .to_file_path()
+            .map_or(DocumentLocation::Virtual, DocumentLocation::Local),
+        "untitled" => DocumentLocation::Untitled,
+        _ => DocumentLocation::Virtual,
+    }
+}
+
+/// Why files can't be written for the document at `uri`
+pub fn unsupported_reason(uri: &Url) -> String {
+    let scheme = uri.scheme();
+    match classify(uri) {
+        DocumentLocation::Local(_) => format!("{uri} has no parent directory"),
+        DocumentLocation::Untitled => format!(
+            "`{scheme}:` documents are not saved yet, so there is no directory to write \
+             rendered files to; save the document first"
+        ),
+        DocumentLocation::Virtual => format!(
+            "`{scheme}:` documents are remote or virtual buffers without a local directory \
+             to write rendered files to; use mermaid.copyAsMarkdown to render without files"
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_each_scheme() {
+        let cases = [
+            ("file:///docs/a.md", DocumentLocation::Local(PathBuf::from("/docs/a.md"))),
+            ("file://server/share/a.md", DocumentLocation::Virtual),
+            ("untitled:Untitled-1", DocumentLocation::Untitled),
+            ("zed://remote/project/a.md", DocumentLocation::Virtual),
+            ("ssh://host/home/me/a.md", DocumentLocation::Virtual),
+            ("git:/docs/a.md?HEAD", DocumentLocation::Virtual),
+        ];
+        for (uri, expected) in cases {
+            #[cfg(windows)]
+            if uri.starts_with("file:") {
+                continue;
+            }
+            assert_eq!(classify(&Url::parse(uri).unwrap()), expected, "{uri}");
+        }
+    }
+
+    #[test]
+    fn reasons_name_the_scheme() {
+        let untitled = unsupported_reason(&Url::parse("untitled:Untitled-1").unwrap());
+        assert!(untitled.contains("`untitled:`") && untitled.contains("save"), "{untitled}");
+        let remote = unsupported_reason(&Url::parse("zed://remote/a.md").unwrap());
+        assert!(remote.contains("`zed:`") && remote.contains("copyAsMarkdown"), "{remote}");
+    }
+}
@@ -0,0 +1,184 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+/// `Parse error on line 3:` / `Lexical error on line 3.` in mermaid's messages
+static REPORTED_LINE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?i)\berror on line (\d+)").expect("reported line regex"));
+
+/// Maps the lines of a preprocessed diagram back to the fence lines they came
+/// from, so errors reported against the preprocessed text point at the source
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    /// Fence line (0-based) of each preprocessed line; None for lines that
+    /// came from no fence line, like the configured preamble
+    origins: Vec<Option<usize>>,
+}
+
+impl SourceMap {
+    /// The map of text passed through unchanged
+    pub fn identity(line_count: usize) -> Self {
+        Self {
+            origins: (0..line_count).map(Some).collect(),
+        }
+    }
+
+    /// The map of text with `inserted` lines of its own spliced in before
+    /// line `at` of the `line_count` passed through
+    pub fn spliced(line_count: usize, at: usize, inserted: usize) -> Self {
+        let at = at.min(line_count);
+        Self {
+            origins: (0..at)
+                .map(Some)
+                .chain(std::iter::repeat_n(None, inserted))
+                .chain((at..line_count).map(Some))
+                .collect(),
+        }
+    }
+
+    /// Append a line that came from fence line `original`. Lines expanded from
+    /// one source line (an included file) all push that line.
+    pub fn push(&mut self, original: usize) {
+        self.origins.push(Some(original));
+    }
+
+    /// The map of a second preprocessing step applied to this one's output
+    pub fn then(&self, next: &SourceMap) -> SourceMap {
+        SourceMap {
+            origins: next
+                .origins
+                .iter()
+                .map(|&line| line.and_then(|line| self.origins.get(line).copied().flatten()))
+                .collect(),
+        }
+    }
+
+    /// Fence line (0-based) of a 1-based line number reported by the renderer
+    pub fn original_line(&self, reported: usize) -> Option<usize> {
+        self.origins.get(reported.checked_sub(1)?).copied().flatten()
+    }
+}
+
+/// Line number named in a renderer error message
+pub fn reported_line(message: &str) -> Option<usize> {
+    REPORTED_LINE.captures(message)?[1].parse().ok()
+}
+
+/// The lines mermaid's parser sees for `code`. Before parsing, mermaid drops
+/// the `---` frontmatter and `%%` comment lines, blanks `%%{...}%%` directives
+/// and trims the blank lines this leaves at the start, so the line numbers in
+/// its errors skip all of these.
+pub fn mermaid_view(code: &str) -> SourceMap {
+    let lines: Vec<&str> = code.lines().collect();
+    let start = frontmatter_len(&lines);
+
+    let mut map = SourceMap::default();
+    let mut i = start;
+    while i < lines.len() {
+        let line = lines[i].trim();
+        let original = i;
+        i += 1;
+        let blank = if line.starts_with("%%{") {
+            // A directive spanning several lines collapses into one blank line
+            if !line.ends_with("}%%") {
+                while i < lines.len() && !lines[i - 1].trim_end().ends_with("}%%") {
+                    i += 1;
+                }
+            }
+            true
+        } else if line.starts_with("%%") {
+            continue;
+        } else {
+            line.is_empty()
+        };
+        if blank && map.origins.is_empty() {
+            continue;
+        }
+        map.push(original);
+    }
+    map
+}
+
+/// Lines taken by the `---` frontmatter at the start of `lines`, 0 if none
+pub fn frontmatter_len(lines: &[&str]) -> usize {
+    if lines.first().is_some_and(|line| line.trim_end() == "---") {
+        if let Some(end) = lines[1..].iter().position(|line| line.trim_end() == "---") {
+            return end + 2;
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_reported_line() {
+        assert_eq!(reported_line("Error: Parse error on line 3:\n...A-->\n---^"), Some(3));
+        assert_eq!(reported_line("Lexical error on line 12. Unrecognized text."), Some(12));
+        assert_eq!(reported_line("No diagram type detected"), None);
+    }
+
+    #[test]
+    fn skips_leading_directives_and_comments() {
+        let code = "%%{init: {\"theme\": \"dark\"}}%%\n%% a comment\ngraph TD\n  A-->B\n  B-->";
+        let map = mermaid_view(code);
+        assert_eq!(map.original_line(1), Some(2));
+        assert_eq!(map.original_line(3), Some(4));
+        assert_eq!(map.original_line(4), None);
+
+        let multi_line = "%%{\n  init: {\"theme\": \"dark\"}\n}%%\ngraph TD\n  A-->";
+        assert_eq!(mermaid_view(multi_line).original_line(2), Some(4));
+    }
+
+    #[test]
+    fn skips_frontmatter() {
+        let code = "---\ntitle: Auth flow\nconfig:\n  theme: dark\n---\nsequenceDiagram\n  A->>B: hi\n  A->>";
+        let map = mermaid_view(code);
+        assert_eq!(map.original_line(1), Some(5));
+        assert_eq!(map.original_line(3), Some(7));
+    }
+
+    #[test]
+    fn comments_and_directives_after_the_header() {
+        let code = "graph TD\n  %% edges\n  A-->B\n  %%{init: {}}%%\n  B-->";
+        let map = mermaid_view(code);
+        // The comment line is dropped, the directive leaves a blank line
+        assert_eq!(map.original_line(2), Some(2));
+        assert_eq!(map.original_line(3), Some(3));
+        assert_eq!(map.original_line(4), Some(4));
+    }
+
+    #[test]
+    fn included_lines_point_at_the_include() {
+        // Fence: header, an include replaced by three lines, then a final edge
+        let mut expanded = SourceMap::default();
+        expanded.push(0);
+        for _ in 0..3 {
+            expanded.push(1);
+        }
+        expanded.push(2);
+        assert_eq!(expanded.original_line(3), Some(1));
+        assert_eq!(expanded.original_line(5), Some(2));
+
+        // Mermaid then drops a comment on the second expanded line
+        let expanded_text = "graph TD\n  A-->B\n  %% from the include\n  B-->C\n  C-->";
+        let map = expanded.then(&mermaid_view(expanded_text));
+        assert_eq!(map.original_line(2), Some(1));
+        assert_eq!(map.original_line(3), Some(1));
+        assert_eq!(map.original_line(4), Some(2));
+        assert_eq!(SourceMap::identity(2).then(&mermaid_view("a\nb")), SourceMap::identity(2));
+    }
+
+    #[test]
+    fn spliced_lines_point_nowhere() {
+        // Two preamble lines after a three-line frontmatter
+        let map = SourceMap::spliced(5, 3, 2);
+        assert_eq!(map.original_line(3), Some(2));
+        assert_eq!(map.original_line(4), None);
+        assert_eq!(map.original_line(5), None);
+        assert_eq!(map.original_line(6), Some(3));
+        assert_eq!(map.original_line(7), Some(4));
+        assert_eq!(map.original_line(8), None);
+    }
+}
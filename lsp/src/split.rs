@@ -0,0 +1,497 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::HashSet;
+
+use crate::anchors;
+use crate::diagnostics::{node_declarations, DiagramType, NON_DECLARATION_KEYWORDS};
+
+/// A bare node id in a flowchart statement
+static NODE_ID: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"[\p{L}_][\p{L}\p{N}_]*").expect("node id regex"));
+
+/// Text that looks like node ids but isn't: edge labels (`|text|`,
+/// `-- text -->`) and `:::class` suffixes
+static NOT_NODES: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"\|[^|]*\||(?:^|[^-=.])(?:--|==|-\.)\s+[^|>]+?\s+(?:-->|==>|\.->|---|===|-\.-|--[ox]|==[ox])|:::[\p{L}\p{N}_-]+",
+    )
+    .expect("edge label regex")
+});
+
+/// A diagram split in two by [`split_subgraph`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Split {
+    /// The diagram with the subgraph replaced by a linked placeholder node
+    pub original: String,
+    /// The subgraph as a diagram of its own
+    pub extracted: String,
+    /// Anchor id the placeholder links to
+    pub anchor: String,
+}
+
+/// A `subgraph ... end` block
+struct Subgraph {
+    start: usize,
+    end: usize,
+    id: String,
+    title: String,
+}
+
+/// A node mentioned in a statement, with its byte range in the line
+struct NodeRef {
+    id: String,
+    start: usize,
+    end: usize,
+    /// Whether the range is a declaration with a shape, e.g. `B[Label]`
+    declared: bool,
+}
+
+/// Move the innermost subgraph around `line` (an index into the lines of
+/// `code`) into a diagram of its own. In the original, the subgraph becomes a
+/// node with its title that links to `#<anchor>`; edges crossing the boundary
+/// are pointed at that node. The anchor is named after the subgraph, avoiding
+/// the ids in `taken`.
+///
+/// None unless `code` is a flowchart and `line` is inside a subgraph.
+pub fn split_subgraph(code: &str, line: usize, taken: HashSet<String>) -> Option<Split> {
+    if DiagramType::detect(code) != Some(DiagramType::Flowchart) {
+        return None;
+    }
+    let lines: Vec<&str> = code.lines().collect();
+    let header = lines
+        .iter()
+        .position(|l| matches!(l.split_whitespace().next(), Some("graph" | "flowchart")))?;
+    let subgraph = subgraphs(&lines)
+        .into_iter()
+        .filter(|s| s.start > header && (s.start..=s.end).contains(&line))
+        .min_by_key(|s| s.end - s.start)?;
+    let placeholder = node_id(&subgraph.id);
+
+    // Everything mentioned inside the subgraph moves with it
+    let body = subgraph.start + 1..subgraph.end;
+    let mut moved = HashSet::new();
+    for line in &lines[body.clone()] {
+        match statement(line) {
+            Statement::Subgraph(header) => {
+                moved.insert(parse_header(header).0);
+            }
+            Statement::Nodes => moved.extend(node_refs(line).into_iter().map(|r| r.id)),
+            _ => {}
+        }
+    }
+    moved.remove(&subgraph.id);
+
+    let mut direction = None;
+    let mut extracted_body = Vec::new();
+    let mut crossing_inside = Vec::new();
+    let mut declarations = Vec::new();
+    let body_indent = lines[body.clone()]
+        .iter()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| indent(l).len())
+        .min()
+        .unwrap_or(0);
+    let mut depth = 0;
+    for line in &lines[body] {
+        let relative = line.get(body_indent..).unwrap_or(line.trim_start());
+        match statement(line) {
+            Statement::Subgraph(_) => depth += 1,
+            Statement::End => depth -= 1,
+            Statement::Keyword("direction", args) if depth == 0 => {
+                direction = args.first().map(|d| d.to_string());
+                continue;
+            }
+            Statement::Nodes => {
+                let refs = node_refs(line);
+                if refs.iter().any(|r| !moved.contains(&r.id)) {
+                    crossing_inside.push(rewrite(line, &refs, &moved, &placeholder, &mut declarations));
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        extracted_body.push(relative.trim_end().to_string());
+    }
+
+    // Statements elsewhere that only concern moved nodes move too; mixed ones are rewritten
+    let mut outside = Vec::new();
+    let mut class_defs = Vec::new();
+    let mut moved_statements = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        if (subgraph.start..=subgraph.end).contains(&i) {
+            if i == subgraph.start {
+                outside.push(None);
+            }
+            continue;
+        }
+        let keep = match statement(line) {
+            _ if i <= header => true,
+            Statement::Nodes => {
+                let refs = node_refs(line);
+                let inside = refs.iter().filter(|r| moved.contains(&r.id)).count();
+                if inside == 0 {
+                    true
+                } else if inside == refs.len() {
+                    moved_statements.push(line.trim().to_string());
+                    false
+                } else {
+                    let rewritten = rewrite(line, &refs, &moved, &placeholder, &mut declarations);
+                    outside.push(Some(rewritten));
+                    continue;
+                }
+            }
+            Statement::Keyword("classDef", _) => {
+                class_defs.push(line.trim().to_string());
+                true
+            }
+            Statement::Keyword("style" | "class" | "click", args) => {
+                let targets: Vec<&str> = args.first().map_or(Vec::new(), |t| t.split(',').collect());
+                if !targets.is_empty() && targets.iter().all(|t| moved.contains(*t)) {
+                    moved_statements.push(line.trim().to_string());
+                    false
+                } else {
+                    true
+                }
+            }
+            _ => true,
+        };
+        if keep {
+            outside.push(Some(line.to_string()));
+        }
+    }
+
+    let mut extracted = Vec::new();
+    extracted.extend(
+        lines[..header]
+            .iter()
+            .filter(|l| l.trim_start().starts_with("%%{"))
+            .map(|l| l.to_string()),
+    );
+    let keyword = lines[header].split_whitespace().next().unwrap_or("flowchart");
+    extracted.push(match &direction {
+        Some(direction) => format!("{keyword} {direction}"),
+        None => lines[header].trim().to_string(),
+    });
+    extracted.push(format!("  accTitle: {}", subgraph.title));
+    let statements = class_defs
+        .iter()
+        .chain(&extracted_body)
+        .chain(&moved_statements)
+        .chain(&declarations);
+    extracted.extend(statements.map(|s| if s.is_empty() { String::new() } else { format!("  {s}") }));
+    let extracted = extracted.join("\n");
+
+    let anchor = anchors::assign_anchors([extracted.as_str()], taken).remove(0);
+    let ind = indent(lines[subgraph.start]);
+    let mut original = Vec::new();
+    for line in outside {
+        match line {
+            Some(line) => original.push(line),
+            None => {
+                original.push(format!("{ind}{placeholder}[\"{}\"]", subgraph.title.replace('"', "#quot;")));
+                original.push(format!("{ind}click {placeholder} href \"#{anchor}\""));
+                original.append(&mut crossing_inside);
+            }
+        }
+    }
+
+    Some(Split {
+        original: original.join("\n"),
+        extracted,
+        anchor,
+    })
+}
+
+/// How a flowchart line is read
+enum Statement<'a> {
+    Blank,
+    /// `subgraph <header>`
+    Subgraph(&'a str),
+    End,
+    /// `style`, `class`, `classDef`, `click`, `linkStyle`, `direction` or an
+    /// accessibility title/description, with its arguments
+    Keyword(&'a str, Vec<&'a str>),
+    /// Node declarations and edges
+    Nodes,
+}
+
+fn statement(line: &str) -> Statement<'_> {
+    let code = line.find("%%").map_or(line, |i| &line[..i]).trim();
+    let mut words = code.split_whitespace();
+    match words.next() {
+        None => Statement::Blank,
+        Some("end") if code == "end" => Statement::End,
+        Some("subgraph") => Statement::Subgraph(code["subgraph".len()..].trim()),
+        Some(keyword)
+            if NON_DECLARATION_KEYWORDS.contains(&keyword)
+                || keyword.starts_with("accTitle")
+                || keyword.starts_with("accDescr") =>
+        {
+            Statement::Keyword(keyword, words.collect())
+        }
+        Some(_) => Statement::Nodes,
+    }
+}
+
+/// Every `subgraph ... end` block, innermost first
+fn subgraphs(lines: &[&str]) -> Vec<Subgraph> {
+    let mut open = Vec::new();
+    let mut found = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        match statement(line) {
+            Statement::Subgraph(header) => open.push((i, header)),
+            Statement::End => {
+                if let Some((start, header)) = open.pop() {
+                    let (id, title) = parse_header(header);
+                    found.push(Subgraph {
+                        start,
+                        end: i,
+                        id,
+                        title,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    found
+}
+
+/// Id and title of `subgraph id [Title]`, `subgraph id`, or `subgraph "Title"`
+fn parse_header(header: &str) -> (String, String) {
+    if let Some((id, rest)) = header.split_once('[') {
+        if let Some(title) = rest.trim_end().strip_suffix(']') {
+            if !id.trim().is_empty() {
+                return (id.trim().to_string(), title.trim().trim_matches('"').to_string());
+            }
+        }
+    }
+    let title = header.trim_matches('"').to_string();
+    (title.clone(), title)
+}
+
+/// A usable node id for a subgraph id (which may be a title with spaces)
+fn node_id(id: &str) -> String {
+    let id: String = id
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == '_' { c } else { '_' })
+        .collect();
+    match id.chars().next() {
+        Some(c) if !c.is_numeric() => id,
+        _ => format!("sub_{id}"),
+    }
+}
+
+/// The nodes a statement line mentions, in order
+fn node_refs(line: &str) -> Vec<NodeRef> {
+    let code = line.find("%%").map_or(line, |i| &line[..i]);
+    let mut refs: Vec<NodeRef> = node_declarations(code)
+        .into_iter()
+        .map(|d| NodeRef {
+            id: d.id.to_string(),
+            start: d.start,
+            end: d.end,
+            declared: true,
+        })
+        .collect();
+
+    let mut masked: Vec<(usize, usize)> = refs.iter().map(|r| (r.start, r.end)).collect();
+    masked.extend(NOT_NODES.find_iter(code).map(|m| (m.start(), m.end())));
+    for m in NODE_ID.find_iter(code) {
+        if masked.iter().any(|&(start, end)| m.start() < end && m.end() > start) {
+            continue;
+        }
+        // `--o` and `--x` arrow heads
+        if matches!(m.as_str(), "o" | "x") && code[..m.start()].ends_with(['-', '=', '.']) {
+            continue;
+        }
+        refs.push(NodeRef {
+            id: m.as_str().to_string(),
+            start: m.start(),
+            end: m.end(),
+            declared: false,
+        });
+    }
+    refs.sort_by_key(|r| r.start);
+    refs
+}
+
+/// Nodes of a flowchart (their labels, or their ids when they have none) in
+/// the order they first appear, and how many edges join them. `A & B --> C`
+/// counts as two edges.
+pub fn flowchart_graph(code: &str) -> (Vec<String>, usize) {
+    let mut seen = HashSet::new();
+    let mut nodes = Vec::new();
+    let mut edges = 0;
+    let body = code
+        .lines()
+        .skip_while(|line| !matches!(line.split_whitespace().next(), Some("graph" | "flowchart")))
+        .skip(1);
+    for line in body {
+        if !matches!(statement(line), Statement::Nodes) {
+            continue;
+        }
+        let code = line.find("%%").map_or(line, |i| &line[..i]);
+        let labels: Vec<(usize, &str)> = node_declarations(code).iter().map(|d| (d.start, d.label)).collect();
+        let refs = node_refs(line);
+        // Nodes joined by `&` form a group; a link joins every node of the
+        // group before it to every node of the group after it
+        let (mut before, mut group) = (0, 0);
+        for (i, r) in refs.iter().enumerate() {
+            if i > 0 && ["--", "==", "-.", "~~~"].iter().any(|link| code[refs[i - 1].end..r.start].contains(link)) {
+                edges += before * group;
+                before = std::mem::take(&mut group);
+            }
+            group += 1;
+            if seen.insert(r.id.clone()) {
+                let label = labels.iter().find(|(start, _)| *start == r.start).map(|(_, label)| label.trim_matches('"'));
+                nodes.push(label.filter(|label| !label.trim().is_empty()).unwrap_or(&r.id).to_string());
+            }
+        }
+        edges += before * group;
+    }
+    (nodes, edges)
+}
+
+/// `line` with moved nodes replaced by the placeholder. Their shape declarations
+/// are collected so the extracted diagram keeps the labels.
+fn rewrite(
+    line: &str,
+    refs: &[NodeRef],
+    moved: &HashSet<String>,
+    placeholder: &str,
+    declarations: &mut Vec<String>,
+) -> String {
+    let mut out = String::new();
+    let mut last = 0;
+    for r in refs.iter().filter(|r| moved.contains(&r.id)) {
+        out.push_str(&line[last..r.start]);
+        out.push_str(placeholder);
+        if r.declared {
+            declarations.push(line[r.start..r.end].to_string());
+        }
+        last = r.end;
+    }
+    out.push_str(&line[last..]);
+    out
+}
+
+fn indent(line: &str) -> &str {
+    &line[..line.len() - line.trim_start().len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NESTED: &str = "flowchart TD
+    A[Start] --> B
+    subgraph outer [Outer]
+        B --> C
+        subgraph inner [Inner Part]
+            direction LR
+            D[Dee] --> E
+        end
+        C --> D
+    end
+    E --> F[Finish]
+    style E fill:#f9f
+    classDef hot fill:#f00";
+
+    /// Node ids mentioned anywhere in a diagram
+    fn all_nodes(code: &str) -> HashSet<String> {
+        code.lines()
+            .filter(|l| matches!(statement(l), Statement::Nodes))
+            .flat_map(node_refs)
+            .map(|r| r.id)
+            .collect()
+    }
+
+    #[test]
+    fn splits_the_innermost_subgraph() {
+        let split = split_subgraph(NESTED, 6, HashSet::new()).unwrap();
+
+        assert_eq!(split.anchor, "diagram-inner-part");
+        assert_eq!(
+            split.extracted,
+            "flowchart LR\n  accTitle: Inner Part\n  classDef hot fill:#f00\n  D[Dee] --> E\n  style E fill:#f9f"
+        );
+        assert_eq!(
+            split.original,
+            "flowchart TD
+    A[Start] --> B
+    subgraph outer [Outer]
+        B --> C
+        inner[\"Inner Part\"]
+        click inner href \"#diagram-inner-part\"
+        C --> inner
+    end
+    inner --> F[Finish]
+    classDef hot fill:#f00"
+        );
+    }
+
+    #[test]
+    fn splits_an_outer_subgraph_with_its_nested_ones() {
+        let split = split_subgraph(NESTED, 3, HashSet::new()).unwrap();
+
+        assert_eq!(
+            split.extracted,
+            "flowchart TD
+  accTitle: Outer
+  classDef hot fill:#f00
+  B --> C
+  subgraph inner [Inner Part]
+      direction LR
+      D[Dee] --> E
+  end
+  C --> D
+  style E fill:#f9f"
+        );
+        assert_eq!(
+            split.original,
+            "flowchart TD
+    A[Start] --> outer
+    outer[\"Outer\"]
+    click outer href \"#diagram-outer\"
+    outer --> F[Finish]
+    classDef hot fill:#f00"
+        );
+
+        // No node is lost, and the extracted diagram can be split again
+        let mut nodes = all_nodes(&split.original);
+        nodes.extend(all_nodes(&split.extracted));
+        assert!(all_nodes(NESTED).is_subset(&nodes));
+        let again = split_subgraph(&split.extracted, 6, HashSet::new()).unwrap();
+        assert!(again.original.contains("inner[\"Inner Part\"]"));
+    }
+
+    #[test]
+    fn crossing_edges_keep_labels_and_edge_text() {
+        let code = "graph LR
+  X -->|uses| B[Bee]
+  subgraph \"Back End\"
+    B -- calls --> C
+  end
+  C -.-> Y";
+        let taken = HashSet::from(["diagram-back-end".to_string()]);
+        let split = split_subgraph(code, 3, taken).unwrap();
+
+        assert_eq!(split.anchor, "diagram-back-end-2");
+        assert_eq!(
+            split.original,
+            "graph LR
+  X -->|uses| Back_End
+  Back_End[\"Back End\"]
+  click Back_End href \"#diagram-back-end-2\"
+  Back_End -.-> Y"
+        );
+        assert_eq!(split.extracted, "graph LR\n  accTitle: Back End\n  B -- calls --> C\n  B[Bee]");
+    }
+
+    #[test]
+    fn only_flowchart_subgraphs_split() {
+        assert!(split_subgraph(NESTED, 1, HashSet::new()).is_none());
+        assert!(split_subgraph("sequenceDiagram\n  A->>B: hi", 1, HashSet::new()).is_none());
+    }
+}
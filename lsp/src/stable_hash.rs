@@ -0,0 +1,73 @@
+const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const PRIME: u64 = 0x0100_0000_01b3;
+
+/// 64-bit FNV-1a over length-prefixed fields. Unlike `DefaultHasher`, its
+/// output is the same on every Rust release and platform, so it is what
+/// names and keys kept on disk are hashed with.
+#[derive(Debug, Clone)]
+pub struct StableHasher(u64);
+
+impl StableHasher {
+    pub fn new() -> Self {
+        Self(OFFSET_BASIS)
+    }
+
+    /// Add a field: its length, then its bytes, so `("ab", "c")` and
+    /// `("a", "bc")` hash differently
+    pub fn write(&mut self, bytes: impl AsRef<[u8]>) -> &mut Self {
+        let bytes = bytes.as_ref();
+        self.write_u64(bytes.len() as u64);
+        self.0 = fnv1a(self.0, bytes);
+        self
+    }
+
+    pub fn write_u64(&mut self, n: u64) -> &mut Self {
+        self.0 = fnv1a(self.0, &n.to_le_bytes());
+        self
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Stable hash of a single field
+pub fn hash(bytes: impl AsRef<[u8]>) -> u64 {
+    StableHasher::new().write(bytes).finish()
+}
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(PRIME);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_fnv1a_reference_values() {
+        assert_eq!(fnv1a(OFFSET_BASIS, b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(OFFSET_BASIS, b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(OFFSET_BASIS, b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn fields_are_kept_apart() {
+        let split = |a: &str, b: &str| StableHasher::new().write(a).write(b).finish();
+        assert_ne!(split("ab", "c"), split("a", "bc"));
+        assert_eq!(split("ab", "c"), split("ab", "c"));
+        // Pinned, so a change to the definition is caught here rather than as
+        // a silently emptied cache
+        assert_eq!(hash("graph TD"), 0x3791_874a_540e_50fd);
+    }
+}
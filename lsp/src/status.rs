@@ -0,0 +1,42 @@
+use lsp_types::{Range, Url};
+use serde::Serialize;
+
+/// Notification with the render state of every fence in a document, sent to
+/// clients whose capabilities include `experimental.fenceStatus: true`
+pub const METHOD: &str = "mermaid/fenceStatus";
+
+/// Render state of one fence
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum FenceState {
+    /// Not rendered, and rendering needs the backend
+    Unrendered,
+    /// Not rendered, but the SVG is in the render cache
+    Cached,
+    /// A render command is working on it
+    Rendering,
+    /// Rendered by the last command; the edit replacing it is on its way
+    Rendered,
+    /// The last render failed
+    Error { message: String },
+    /// Marked as ignored, so it is neither checked nor rendered
+    Ignored,
+}
+
+/// One fence's entry in the notification
+#[derive(Debug, Clone, Serialize)]
+pub struct FenceStatus {
+    pub range: Range,
+    /// Hash of the fence's code, to match entries across notifications
+    pub hash: String,
+    #[serde(flatten)]
+    pub state: FenceState,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FenceStatusParams {
+    pub uri: Url,
+    /// Document version the statuses describe
+    pub version: Option<i32>,
+    pub fences: Vec<FenceStatus>,
+}
@@ -0,0 +1,89 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+/// `%% step 2` starts the lines revealed in step 2; `%% end step` goes back to
+/// lines shown in every step
+static STEP_MARKER: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)^\s*%%\s*(?:step\s+(\d+)|end\s*step)\s*$").expect("step marker regex")
+});
+
+/// One frame of a step-by-step diagram
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub number: u32,
+    /// Lines shown in every step plus those of steps up to `number`
+    pub code: String,
+}
+
+/// Split annotated mermaid code into cumulative steps, in step order.
+/// Empty when the code has no `%% step` markers.
+pub fn cumulative_steps(code: &str) -> Vec<Step> {
+    // Step of each line (None: shown in every step); markers are dropped
+    let mut current = None;
+    let mut lines = Vec::new();
+    for line in code.lines() {
+        match STEP_MARKER.captures(line) {
+            Some(caps) => current = caps.get(1).and_then(|n| n.as_str().parse::<u32>().ok()),
+            None => lines.push((current, line)),
+        }
+    }
+
+    let mut numbers: Vec<u32> = lines.iter().filter_map(|(step, _)| *step).collect();
+    numbers.sort_unstable();
+    numbers.dedup();
+
+    numbers
+        .into_iter()
+        .map(|number| Step {
+            number,
+            code: lines
+                .iter()
+                .filter(|(step, _)| step.is_none_or(|s| s <= number))
+                .map(|(_, line)| *line)
+                .collect::<Vec<_>>()
+                .join("\n"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_reveal_lines_cumulatively() {
+        let code = "flowchart TD\n  A[Start]\n%% step 1\n  A --> B\n%% step 2\n  B --> C\n%% end step\n  style A fill:#f9f";
+        let steps = cumulative_steps(code);
+
+        assert_eq!(
+            steps,
+            vec![
+                Step {
+                    number: 1,
+                    code: "flowchart TD\n  A[Start]\n  A --> B\n  style A fill:#f9f".to_string(),
+                },
+                Step {
+                    number: 2,
+                    code: "flowchart TD\n  A[Start]\n  A --> B\n  B --> C\n  style A fill:#f9f"
+                        .to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn steps_are_ordered_by_number_not_position() {
+        let code = "graph LR\n  %% step 3\n  C\n  %% STEP 1\n  A\n  %% step 3\n  D";
+        let steps = cumulative_steps(code);
+
+        let numbers: Vec<u32> = steps.iter().map(|s| s.number).collect();
+        assert_eq!(numbers, vec![1, 3]);
+        assert_eq!(steps[0].code, "graph LR\n  A");
+        assert_eq!(steps[1].code, "graph LR\n  C\n  A\n  D");
+    }
+
+    #[test]
+    fn unannotated_code_has_no_steps() {
+        assert!(cumulative_steps("graph TD\n  %% a comment\n  A --> B").is_empty());
+    }
+}
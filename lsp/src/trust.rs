@@ -0,0 +1,286 @@
+use log::warn;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+use crate::i18n::{text, Text};
+use crate::paths;
+use crate::stable_hash;
+
+/// Directory the extension gives the server for remembered trust decisions
+pub const TRUST_DIR_ENV: &str = "MERMAID_LSP_TRUST_DIR";
+const STORE_FILE: &str = "trusted-binaries.json";
+const STORE_VERSION: u32 = 2;
+
+/// Trust decisions for this session's workspace
+static STORE: Lazy<Mutex<TrustStore>> = Lazy::new(|| Mutex::new(TrustStore::new(None, None)));
+
+/// The session's trust store
+pub fn store() -> MutexGuard<'static, TrustStore> {
+    STORE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Answer to the prompt asking whether a project-local binary may run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Run it for the rest of the session
+    Allow,
+    /// Don't run it this session, and don't ask again
+    Deny,
+    /// Run it from now on, until the binary changes
+    AlwaysAllow,
+}
+
+impl Decision {
+    pub const ALL: [Decision; 3] = [Decision::Allow, Decision::Deny, Decision::AlwaysAllow];
+
+    /// Button text in the prompt
+    pub fn label(self) -> &'static str {
+        text(match self {
+            Decision::Allow => Text::TrustAllow,
+            Decision::Deny => Text::TrustDeny,
+            Decision::AlwaysAllow => Text::TrustAlwaysAllow,
+        })
+    }
+
+    pub fn from_label(label: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|decision| decision.label() == label)
+    }
+}
+
+/// Whether a binary may be run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Trust {
+    /// Installed outside the workspace (globally, or by the extension)
+    Global,
+    /// Inside the workspace, allowed for this session
+    Allowed,
+    /// Inside the workspace, allowed with "Always Allow"
+    AlwaysAllowed,
+    /// Inside the workspace, denied for this session
+    Denied,
+    /// Inside the workspace, waiting for the user's answer
+    Pending,
+}
+
+impl Trust {
+    pub fn is_trusted(self) -> bool {
+        matches!(self, Trust::Global | Trust::Allowed | Trust::AlwaysAllowed)
+    }
+}
+
+/// Contents of the store file
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StoreFile {
+    version: u32,
+    /// `<workspace root>#<binary hash>` of every binary that was always allowed
+    always_allowed: BTreeSet<String>,
+}
+
+/// Which binaries inside the workspace may run. Binaries elsewhere are trusted;
+/// one inside it (`node_modules/.bin/mmdc`, a repo-relative `MMDC_PATH`) needs
+/// the user's consent, remembered per workspace root and binary content.
+#[derive(Debug)]
+pub struct TrustStore {
+    root: Option<PathBuf>,
+    /// Where "Always Allow" decisions are saved; None keeps them for the session
+    file: Option<PathBuf>,
+    always_allowed: BTreeSet<String>,
+    /// Allow/Deny answers of this session, by key
+    session: HashMap<String, bool>,
+    /// Binaries to ask about, and the keys already asked for
+    prompts: Vec<PathBuf>,
+    asked: HashSet<String>,
+}
+
+impl TrustStore {
+    /// Trust store for the workspace at `root`, saving decisions in `dir`
+    pub fn new(root: Option<PathBuf>, dir: Option<PathBuf>) -> Self {
+        let root = root.map(|root| paths::resolve(&root));
+        let file = dir.map(|dir| dir.join(STORE_FILE));
+        let always_allowed = file
+            .as_ref()
+            .and_then(|file| fs::read_to_string(file).ok())
+            .and_then(|json| serde_json::from_str::<StoreFile>(&json).ok())
+            .filter(|store| store.version == STORE_VERSION)
+            .map(|store| store.always_allowed)
+            .unwrap_or_default();
+        Self {
+            root,
+            file,
+            always_allowed,
+            session: HashMap::new(),
+            prompts: Vec::new(),
+            asked: HashSet::new(),
+        }
+    }
+
+    pub fn root(&self) -> Option<&Path> {
+        self.root.as_deref()
+    }
+
+    /// Trust in `binary`, queuing a prompt the first time an undecided
+    /// project-local binary is seen
+    pub fn check(&mut self, binary: &Path) -> Trust {
+        let trust = self.peek(binary);
+        if trust == Trust::Pending {
+            if let Some(key) = self.key(binary) {
+                if self.asked.insert(key) {
+                    self.prompts.push(binary.to_path_buf());
+                }
+            }
+        }
+        trust
+    }
+
+    /// Trust in `binary`, without asking
+    pub fn peek(&self, binary: &Path) -> Trust {
+        if !self.is_project_local(binary) {
+            return Trust::Global;
+        }
+        let Some(key) = self.key(binary) else {
+            return Trust::Denied;
+        };
+        match self.session.get(&key) {
+            Some(true) => Trust::Allowed,
+            Some(false) => Trust::Denied,
+            None if self.always_allowed.contains(&key) => Trust::AlwaysAllowed,
+            None => Trust::Pending,
+        }
+    }
+
+    /// Binaries waiting for a prompt to be sent
+    pub fn take_prompts(&mut self) -> Vec<PathBuf> {
+        std::mem::take(&mut self.prompts)
+    }
+
+    /// Record the user's answer for `binary`
+    pub fn decide(&mut self, binary: &Path, decision: Decision) {
+        let Some(key) = self.key(binary) else {
+            return;
+        };
+        match decision {
+            Decision::Allow => {
+                self.session.insert(key, true);
+            }
+            Decision::Deny => {
+                self.session.insert(key, false);
+            }
+            Decision::AlwaysAllow => {
+                self.session.remove(&key);
+                self.always_allowed.insert(key);
+                self.save();
+            }
+        }
+    }
+
+    /// Whether `binary` lives inside the workspace
+    fn is_project_local(&self, binary: &Path) -> bool {
+        let Some(root) = &self.root else {
+            return false;
+        };
+        let binary = paths::resolve(binary);
+        binary.starts_with(root)
+    }
+
+    /// Decision key of `binary`: the workspace root and a hash of the binary's
+    /// content, so a changed binary is asked about again
+    fn key(&self, binary: &Path) -> Option<String> {
+        let content = fs::read(binary).ok()?;
+        let root = self.root.as_deref().unwrap_or(Path::new(""));
+        Some(format!("{}#{:016x}", root.display(), stable_hash::hash(content)))
+    }
+
+    fn save(&self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let store = StoreFile {
+            version: STORE_VERSION,
+            always_allowed: self.always_allowed.clone(),
+        };
+        let saved = serde_json::to_string_pretty(&store)
+            .map_err(|e| e.to_string())
+            .and_then(|json| {
+                if let Some(dir) = file.parent() {
+                    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+                }
+                fs::write(file, json).map_err(|e| e.to_string())
+            });
+        if let Err(e) = saved {
+            warn!("Failed to save trust decisions to {}: {e}", file.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A workspace with `node_modules/.bin/mmdc`, plus a directory outside it
+    fn workspace() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        let local = root.join("node_modules/.bin/mmdc");
+        fs::create_dir_all(local.parent().unwrap()).unwrap();
+        fs::write(&local, "#!/bin/sh\n").unwrap();
+        let global = dir.path().join("global/mmdc");
+        fs::create_dir_all(global.parent().unwrap()).unwrap();
+        fs::write(&global, "#!/bin/sh\n").unwrap();
+        (dir, local, global)
+    }
+
+    #[test]
+    fn project_local_binaries_wait_for_consent() {
+        let (dir, local, global) = workspace();
+        let mut store = TrustStore::new(Some(dir.path().join("project")), None);
+
+        assert_eq!(store.check(&global), Trust::Global);
+        assert_eq!(store.check(&local), Trust::Pending);
+        assert_eq!(store.check(&local), Trust::Pending);
+        assert_eq!(store.take_prompts(), vec![local.clone()]);
+        assert!(store.take_prompts().is_empty());
+
+        store.decide(&local, Decision::Deny);
+        assert_eq!(store.check(&local), Trust::Denied);
+        store.decide(&local, Decision::Allow);
+        assert_eq!(store.check(&local), Trust::Allowed);
+        assert!(store.take_prompts().is_empty());
+    }
+
+    #[test]
+    fn always_allow_persists_until_the_binary_changes() {
+        let (dir, local, _) = workspace();
+        let root = dir.path().join("project");
+        let cache = dir.path().join("cache");
+
+        let mut store = TrustStore::new(Some(root.clone()), Some(cache.clone()));
+        store.check(&local);
+        store.decide(&local, Decision::AlwaysAllow);
+
+        let mut reopened = TrustStore::new(Some(root.clone()), Some(cache.clone()));
+        assert_eq!(reopened.check(&local), Trust::AlwaysAllowed);
+        // Another workspace with the same binary is asked again
+        let other = TrustStore::new(Some(dir.path().to_path_buf()), Some(cache.clone()));
+        assert_eq!(other.peek(&local), Trust::Pending);
+
+        fs::write(&local, "#!/bin/sh\necho changed\n").unwrap();
+        let mut changed = TrustStore::new(Some(root), Some(cache));
+        assert_eq!(changed.check(&local), Trust::Pending);
+    }
+
+    #[test]
+    fn labels_round_trip() {
+        for decision in Decision::ALL {
+            assert_eq!(Decision::from_label(decision.label()), Some(decision));
+        }
+        assert_eq!(Decision::from_label("Maybe"), None);
+    }
+}
@@ -0,0 +1,188 @@
+//! `{{NAME}}` placeholders in diagram code, filled in from the `variables`
+//! option (or `{{env:NAME}}` from the environment variables `envVariables`
+//! lists) just before rendering. Sources keep the placeholders.
+//!
+//! A placeholder right after what could be a node id is left alone: that's a
+//! hexagon node like `A{{Label}}`.
+
+use lsp_types::{Diagnostic, Position, Range};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::{borrow::Cow, env};
+
+use crate::config::Config;
+use crate::error::{ServerError, ServerResult};
+use crate::position::PositionEncoding;
+
+static PLACEHOLDER: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"\{\{\s*((?:env:)?[A-Za-z_][A-Za-z0-9_]*)\s*\}\}").expect("placeholder regex")
+});
+
+/// Text ending in a node id, as before the `{{` of a hexagon node
+static NODE_ID_BEFORE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?:^|[\s;&>|-])[\p{L}_][\p{L}\p{N}_]*$").expect("node id regex"));
+
+/// Prefix of placeholders read from the environment
+const ENV_PREFIX: &str = "env:";
+
+/// A placeholder that can't be filled in
+#[derive(Debug)]
+struct Problem {
+    /// Line of the code (0-based)
+    line: usize,
+    /// Byte range of the placeholder within its line
+    start: usize,
+    end: usize,
+    message: String,
+}
+
+impl Problem {
+    fn to_error(&self) -> ServerError {
+        ServerError::ValidationFailed(self.message.clone())
+    }
+}
+
+/// `code` with its placeholders filled in, or the first that can't be
+pub fn substitute<'a>(code: &'a str, config: &Config) -> ServerResult<Cow<'a, str>> {
+    let (resolved, problems) = resolve(code, config, |name| env::var(name).ok());
+    match problems.first() {
+        Some(problem) => Err(problem.to_error()),
+        None => Ok(resolved),
+    }
+}
+
+/// An error for each placeholder of the diagram that can't be filled in.
+///
+/// `first_line` is the document line of the first line of `code`.
+pub fn check(code: &str, first_line: usize, encoding: PositionEncoding, config: &Config) -> Vec<Diagnostic> {
+    let (_, problems) = resolve(code, config, |name| env::var(name).ok());
+    let lines: Vec<&str> = code.lines().collect();
+    problems
+        .iter()
+        .map(|problem| {
+            let line = lines[problem.line];
+            let line_no = (first_line + problem.line) as u32;
+            problem.to_error().to_diagnostic(Range::new(
+                Position::new(line_no, encoding.line_len(&line[..problem.start])),
+                Position::new(line_no, encoding.line_len(&line[..problem.end])),
+            ))
+        })
+        .collect()
+}
+
+/// `code` with the placeholders that can be filled in replaced, and what's
+/// wrong with the others. Code without placeholders is borrowed back.
+fn resolve<'a>(code: &'a str, config: &Config, var: impl Fn(&str) -> Option<String>) -> (Cow<'a, str>, Vec<Problem>) {
+    if !code.contains("{{") {
+        return (Cow::Borrowed(code), Vec::new());
+    }
+    let mut resolved = String::with_capacity(code.len());
+    let mut problems = Vec::new();
+    let mut replaced = false;
+    for (i, line) in code.split('\n').enumerate() {
+        if i > 0 {
+            resolved.push('\n');
+        }
+        let mut copied = 0;
+        for caps in PLACEHOLDER.captures_iter(line) {
+            let whole = caps.get(0).expect("placeholder match");
+            if NODE_ID_BEFORE.is_match(&line[..whole.start()]) {
+                continue;
+            }
+            match value(&caps[1], config, &var) {
+                Ok(value) => {
+                    resolved.push_str(&line[copied..whole.start()]);
+                    resolved.push_str(&value);
+                    copied = whole.end();
+                    replaced = true;
+                }
+                Err(reason) => problems.push(Problem {
+                    line: i,
+                    start: whole.start(),
+                    end: whole.end(),
+                    message: format!("`{}` on diagram line {}: {reason}", whole.as_str(), i + 1),
+                }),
+            }
+        }
+        resolved.push_str(&line[copied..]);
+    }
+    let resolved = if replaced { Cow::Owned(resolved) } else { Cow::Borrowed(code) };
+    (resolved, problems)
+}
+
+/// The value of placeholder `name`, or why there is none
+fn value(name: &str, config: &Config, var: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let value = match name.strip_prefix(ENV_PREFIX) {
+        Some(env_name) if !config.env_variables.iter().any(|allowed| allowed == env_name) => {
+            return Err(format!("environment variable `{env_name}` is not listed in `envVariables`"));
+        }
+        Some(env_name) => var(env_name).ok_or_else(|| format!("environment variable `{env_name}` is not set"))?,
+        None => config
+            .variables
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("`{name}` is not defined in `variables`"))?,
+    };
+    if PLACEHOLDER.is_match(&value) {
+        return Err("its value has a placeholder of its own, and placeholders are not substituted recursively".to_string());
+    }
+    if value.contains('\n') {
+        return Err("its value spans several lines, which would move the diagram's lines".to_string());
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        config.variables.insert("VERSION".to_string(), "2.1".to_string());
+        config.variables.insert("LOOP".to_string(), "v{{VERSION}}".to_string());
+        config.env_variables.push("BUILD".to_string());
+        config
+    }
+
+    fn env(name: &str) -> Option<String> {
+        ["BUILD", "HOME"].contains(&name).then(|| format!("<{name}>"))
+    }
+
+    #[test]
+    fn fills_in_variables_and_allowed_environment() {
+        let code = "graph TD\n  A[\"v{{VERSION}}\"] --> B[\"{{ env:BUILD }}\"]\n  C{{Hexagon}} --> D{{VERSION}}";
+        let (resolved, problems) = resolve(code, &config(), env);
+        assert!(problems.is_empty(), "{problems:?}");
+        assert_eq!(resolved, "graph TD\n  A[\"v2.1\"] --> B[\"<BUILD>\"]\n  C{{Hexagon}} --> D{{VERSION}}");
+
+        let plain = "graph TD\n  A{{Hexagon}}";
+        assert!(matches!(resolve(plain, &config(), env).0, Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn names_each_placeholder_that_cant_be_filled_in() {
+        let code = "graph TD\n  A[{{MISSING}}] --> B[{{env:HOME}}]\n  C[{{LOOP}}] --> D[{{env:BUILD}}]";
+        let (_, problems) = resolve(code, &config(), env);
+        let messages: Vec<&str> = problems.iter().map(|p| p.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "`{{MISSING}}` on diagram line 2: `MISSING` is not defined in `variables`",
+                "`{{env:HOME}}` on diagram line 2: environment variable `HOME` is not listed in `envVariables`",
+                "`{{LOOP}}` on diagram line 3: its value has a placeholder of its own, and placeholders are not substituted recursively",
+            ]
+        );
+        assert_eq!((problems[0].line, problems[0].start, problems[0].end), (1, 4, 15));
+
+        let (_, unset) = resolve("graph TD\n  A[{{env:BUILD}}]", &config(), |_| None);
+        assert_eq!(unset[0].message, "`{{env:BUILD}}` on diagram line 2: environment variable `BUILD` is not set");
+    }
+
+    #[test]
+    fn diagnostics_cover_the_placeholder() {
+        let diagnostics = check("graph TD\n  A[\"é {{NOPE}}\"]", 5, PositionEncoding::Utf16, &config());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].range, Range::new(Position::new(6, 7), Position::new(6, 15)));
+        assert!(diagnostics[0].message.contains("`{{NOPE}}` on diagram line 2"), "{}", diagnostics[0].message);
+    }
+}
@@ -0,0 +1,326 @@
+//! `mermaid-lsp --verify`: whether the SVGs of rendered blocks are what their
+//! `.mmd` sources render to with the current toolchain, as a CI gate against
+//! committed diagrams drifting from their sources
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::backend;
+use crate::config::Config;
+use crate::error::{ServerError, ServerResult};
+use crate::lint::collect_markdown_files;
+use crate::paths;
+use crate::render;
+use crate::render_cli::read_config;
+
+/// Exit codes of `mermaid-lsp --verify`
+pub const EXIT_OK: i32 = 0;
+/// An SVG differs from its source's render, or is missing
+pub const EXIT_STALE: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+/// A source couldn't be read or rendered, so its SVG couldn't be checked
+pub const EXIT_RENDER: i32 = 3;
+
+const USAGE: &str = "usage: mermaid-lsp --verify <DIR|FILE.md> [--structural] [--config FILE.json]";
+
+/// Runs of characters an id is made of, as found in `url(#id)`, `href="#id"`
+/// or the `#id` selectors of a `<style>`
+static ID_TOKEN: Lazy<Regex> = Lazy::new(|| Regex::new(r"[\w.:-]+").expect("id token regex"));
+
+/// How a committed SVG is compared to a fresh render
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Comparison {
+    /// Byte for byte
+    #[default]
+    Exact,
+    /// The same elements, attributes and text, ignoring whitespace between
+    /// and inside them and what the ids are named
+    Structural,
+}
+
+/// Command line of `mermaid-lsp --verify <PATH> [--structural] [--config FILE.json]`
+#[derive(Debug, PartialEq)]
+pub struct VerifyArgs {
+    /// Markdown file, or directory searched for them
+    pub path: PathBuf,
+    pub comparison: Comparison,
+    /// Server options, in the same JSON as `initialization_options`
+    pub config: Option<PathBuf>,
+}
+
+impl VerifyArgs {
+    /// Parse the arguments following the program name
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut path = None;
+        let mut comparison = Comparison::default();
+        let mut config = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+                _ => (arg.as_str(), None),
+            };
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next().cloned())
+                    .ok_or_else(|| format!("{name} expects a value"))
+            };
+            match name {
+                "--verify" => path = Some(PathBuf::from(value()?)),
+                "--structural" => comparison = Comparison::Structural,
+                "--config" => config = Some(PathBuf::from(value()?)),
+                other => return Err(format!("Unexpected argument `{other}`")),
+            }
+        }
+        let path = path.ok_or("--verify expects a directory or file")?;
+        Ok(Self { path, comparison, config })
+    }
+}
+
+/// What became of one rendered block
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    UpToDate,
+    /// The SVG differs from the source's render
+    Stale,
+    /// The SVG the block shows doesn't exist
+    Missing,
+    /// The source couldn't be read or rendered
+    Failed(String),
+    /// Not an SVG of this workspace (a PNG, a remote image, inline markup)
+    Skipped,
+}
+
+/// A rendered block and its outcome
+#[derive(Debug)]
+pub struct Verified {
+    pub file: PathBuf,
+    /// Line of the block's source comment (0-based)
+    pub line: usize,
+    pub source: String,
+    pub svg: Option<String>,
+    pub outcome: Outcome,
+}
+
+/// Verify as the command line asks and return the process exit code. Blocks
+/// that need attention are listed on stdout, the summary goes to stderr.
+pub fn run(args: &[String]) -> i32 {
+    let args = match VerifyArgs::parse(args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("mermaid-lsp: {e}");
+            eprintln!("{USAGE}");
+            return EXIT_USAGE;
+        }
+    };
+    let config = match args.config.as_deref().map(read_config).transpose() {
+        Ok(config) => config.unwrap_or_default(),
+        Err(e) => {
+            eprintln!("mermaid-lsp: {e}");
+            return EXIT_USAGE;
+        }
+    };
+    let mut files = Vec::new();
+    if let Err(e) = collect_markdown_files(&args.path, &mut files) {
+        eprintln!("mermaid-lsp: {}: {e}", args.path.display());
+        return EXIT_USAGE;
+    }
+
+    let mut results = Vec::new();
+    for file in &files {
+        match verify_file(file, args.comparison, &config) {
+            Ok(verified) => results.extend(verified),
+            Err(e) => {
+                eprintln!("mermaid-lsp: {e}");
+                return EXIT_USAGE;
+            }
+        }
+    }
+    for result in &results {
+        let location = format!("{}:{}", result.file.display(), result.line + 1);
+        let svg = result.svg.as_deref().unwrap_or_default();
+        match &result.outcome {
+            Outcome::Stale => println!("{location}: {svg} is out of date with {}", result.source),
+            Outcome::Missing => println!("{location}: {svg} is missing"),
+            Outcome::Failed(message) => println!("{location}: error: {}: {message}", result.source),
+            Outcome::UpToDate | Outcome::Skipped => {}
+        }
+    }
+
+    let count = |wanted: fn(&Outcome) -> bool| results.iter().filter(|r| wanted(&r.outcome)).count();
+    let stale = count(|o| matches!(o, Outcome::Stale | Outcome::Missing));
+    let failed = count(|o| matches!(o, Outcome::Failed(_)));
+    eprintln!(
+        "verified {} diagrams: {} up to date, {stale} out of date, {failed} failed, {} skipped",
+        results.len() - count(|o| *o == Outcome::Skipped),
+        count(|o| *o == Outcome::UpToDate),
+        count(|o| *o == Outcome::Skipped),
+    );
+    if failed > 0 {
+        EXIT_RENDER
+    } else if stale > 0 {
+        EXIT_STALE
+    } else {
+        EXIT_OK
+    }
+}
+
+/// Render the source of every rendered block of the markdown file at `path`
+/// and compare it to the block's SVG. Nothing is written.
+pub fn verify_file(path: &Path, comparison: Comparison, config: &Config) -> ServerResult<Vec<Verified>> {
+    let doc = fs::read_to_string(path).map_err(ServerError::io(format!("Failed to read {}", path.display())))?;
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+    let lines: Vec<&str> = doc.lines().collect();
+    Ok(crate::find_all_rendered_blocks(&lines)
+        .into_iter()
+        .map(|block| {
+            let svg = block.image.map(|(_, image)| image);
+            let outcome = match &svg {
+                Some(svg) if is_local_svg(svg) => {
+                    verify_block(base_dir, &block.source_file, svg, &block.info, comparison, config)
+                }
+                _ => Outcome::Skipped,
+            };
+            Verified {
+                file: path.to_path_buf(),
+                line: block.comment_line,
+                source: block.source_file,
+                svg,
+                outcome,
+            }
+        })
+        .collect())
+}
+
+fn is_local_svg(image: &str) -> bool {
+    !image.contains("://") && Path::new(image).extension().is_some_and(|ext| ext.eq_ignore_ascii_case("svg"))
+}
+
+fn verify_block(
+    base_dir: &Path,
+    source: &str,
+    svg: &str,
+    info: &str,
+    comparison: Comparison,
+    config: &Config,
+) -> Outcome {
+    let Ok(committed) = fs::read_to_string(paths::logical_join(base_dir, svg)) else {
+        return Outcome::Missing;
+    };
+    // Sources written for CRLF documents were rendered from `\n` fence lines
+    let rendered = fs::read_to_string(paths::logical_join(base_dir, source))
+        .map_err(ServerError::io(format!("Failed to read {source}")))
+        .and_then(|code| {
+            let code = code.replace("\r\n", "\n");
+            render::render_in_memory(&code, backend::select(info, config)?, config)
+        });
+    match rendered {
+        Ok(rendered) if same(&committed, &rendered, comparison) => Outcome::UpToDate,
+        Ok(_) => Outcome::Stale,
+        Err(e) => Outcome::Failed(e.to_string()),
+    }
+}
+
+/// Whether `committed` matches `rendered` by `comparison`
+pub fn same(committed: &str, rendered: &str, comparison: Comparison) -> bool {
+    match comparison {
+        Comparison::Exact => committed == rendered,
+        Comparison::Structural => match (canonical(committed), canonical(rendered)) {
+            (Some(committed), Some(rendered)) => committed == rendered,
+            // Markup that doesn't parse can only match exactly
+            _ => committed == rendered,
+        },
+    }
+}
+
+/// The elements, attributes and text of `svg` in document order, with
+/// whitespace collapsed and each id replaced by its position among the ids
+fn canonical(svg: &str) -> Option<Vec<String>> {
+    let doc = roxmltree::Document::parse(svg).ok()?;
+    let ids: HashMap<&str, usize> = doc
+        .descendants()
+        .filter_map(|node| node.attribute("id"))
+        .enumerate()
+        .map(|(n, id)| (id, n))
+        .collect();
+    let normalize = |text: &str| {
+        let renamed = ID_TOKEN.replace_all(text, |caps: &regex::Captures| match ids.get(&caps[0]) {
+            Some(n) => format!("#id{n}"),
+            None => caps[0].to_string(),
+        });
+        renamed.split_whitespace().collect::<Vec<_>>().join(" ")
+    };
+
+    let mut tokens = Vec::new();
+    push_tokens(doc.root_element(), &normalize, &mut tokens);
+    Some(tokens)
+}
+
+/// `node` and everything in it as [`canonical`] tokens
+fn push_tokens(node: roxmltree::Node, normalize: &impl Fn(&str) -> String, tokens: &mut Vec<String>) {
+    if node.is_text() {
+        let text = normalize(node.text().unwrap_or_default());
+        if !text.is_empty() {
+            tokens.push(text);
+        }
+        return;
+    }
+    if !node.is_element() {
+        return;
+    }
+    let mut attributes: Vec<String> = node
+        .attributes()
+        .map(|a| format!("{}={}", a.name(), normalize(a.value())))
+        .collect();
+    attributes.sort();
+    tokens.push(format!("<{} {}>", node.tag_name().name(), attributes.join(" ")));
+    for child in node.children() {
+        push_tokens(child, normalize, tokens);
+    }
+    tokens.push("</>".to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_arguments() {
+        let parse = |list: &[&str]| VerifyArgs::parse(&list.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+        assert_eq!(
+            parse(&["--verify", "docs", "--structural", "--config=c.json"]).unwrap(),
+            VerifyArgs {
+                path: PathBuf::from("docs"),
+                comparison: Comparison::Structural,
+                config: Some(PathBuf::from("c.json")),
+            }
+        );
+        assert_eq!(parse(&["--verify=."]).unwrap().comparison, Comparison::Exact);
+        assert!(parse(&["--verify"]).is_err());
+        assert!(parse(&["--verify", "docs", "--fix"]).is_err());
+    }
+
+    #[test]
+    fn structural_comparison_ignores_whitespace_and_id_names() {
+        let committed = "<svg id=\"my-svg\">\n  <style>#my-svg .node{fill:red}</style>\n  <g id=\"a\" class=\"node\"><text>Start  here</text></g>\n  <use href=\"#a\"/>\n</svg>";
+        let renamed = "<svg id=\"svg-2\"><style>#svg-2 .node{fill:red}</style><g class=\"node\" id=\"b\"><text>Start here</text></g><use href=\"#b\"/></svg>";
+        assert!(!same(committed, renamed, Comparison::Exact));
+        assert!(same(committed, renamed, Comparison::Structural));
+
+        for changed in [
+            renamed.replace("Start here", "Start there"),
+            renamed.replace("fill:red", "fill:blue"),
+            renamed.replace("href=\"#b\"", "href=\"#svg-2\""),
+            renamed.replace("<use href=\"#b\"/>", ""),
+        ] {
+            assert!(!same(committed, &changed, Comparison::Structural), "{changed}");
+        }
+        assert!(!same("<svg>", "<svg/>", Comparison::Structural));
+    }
+}
@@ -0,0 +1,183 @@
+use once_cell::sync::Lazy;
+use resvg::{
+    tiny_skia::{Pixmap, Transform},
+    usvg,
+};
+use std::{fs, path::Path, sync::Arc};
+
+use crate::error::{ServerError, ServerResult};
+
+/// How far a color channel may differ before the pixel counts as changed, so
+/// anti-aliasing that varies between renderers isn't a difference
+const CHANNEL_TOLERANCE: u8 = 24;
+
+/// Largest image compared, in pixels
+const MAX_PIXELS: u64 = 40_000_000;
+
+/// Color of the changed pixels in a diff image
+const CHANGED: [u8; 4] = [230, 30, 30, 255];
+
+/// System fonts, loaded on the first comparison with text in it
+static FONTS: Lazy<Arc<usvg::fontdb::Database>> = Lazy::new(|| {
+    let mut fonts = usvg::fontdb::Database::new();
+    fonts.load_system_fonts();
+    Arc::new(fonts)
+});
+
+/// Pixel difference between a rendered diagram and its reference image
+pub struct Difference {
+    pub differing_pixels: u64,
+    /// Pixels of the area compared, the larger of the two widths and heights
+    pub total_pixels: u64,
+    /// The reference faded, with the changed pixels in red
+    pub diff: Pixmap,
+}
+
+impl Difference {
+    /// Share of the pixels that differ, in percent
+    pub fn percent(&self) -> f64 {
+        if self.total_pixels == 0 {
+            return 0.0;
+        }
+        self.differing_pixels as f64 * 100.0 / self.total_pixels as f64
+    }
+}
+
+/// Rasterize an SVG at its own size. Images it links to are not loaded.
+pub fn rasterize_svg(svg: &str) -> ServerResult<Pixmap> {
+    let mut options = usvg::Options {
+        fontdb: FONTS.clone(),
+        ..Default::default()
+    };
+    options.image_href_resolver.resolve_string = Box::new(|_, _| None);
+    let tree = usvg::Tree::from_str(svg, &options).map_err(|e| ServerError::RenderFailed(format!("cannot read SVG: {e}")))?;
+    let size = tree.size().to_int_size();
+    let mut pixmap = new_pixmap(size.width(), size.height())?;
+    resvg::render(&tree, Transform::default(), &mut pixmap.as_mut());
+    Ok(pixmap)
+}
+
+/// Decode a PNG
+pub fn decode_png(png: &[u8]) -> ServerResult<Pixmap> {
+    Pixmap::decode_png(png).map_err(|e| ServerError::RenderFailed(format!("cannot read PNG: {e}")))
+}
+
+/// The image at `path`: an SVG (rasterized) or a PNG, by its extension
+pub fn load(path: &Path) -> ServerResult<Pixmap> {
+    let context = format!("Failed to read {}", path.display());
+    if is_svg(path) {
+        rasterize_svg(&fs::read_to_string(path).map_err(ServerError::io(context))?)
+    } else {
+        decode_png(&fs::read(path).map_err(ServerError::io(context))?)
+    }
+}
+
+/// Whether `path` names an SVG, which is compared rasterized rather than as a PNG
+pub fn is_svg(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("svg"))
+}
+
+/// Compare two images pixel by pixel, both on a white background: a
+/// transparent and a white pixel are the same. Where one image is smaller,
+/// the missing pixels count as white.
+pub fn compare(rendered: &Pixmap, reference: &Pixmap) -> ServerResult<Difference> {
+    let width = rendered.width().max(reference.width());
+    let height = rendered.height().max(reference.height());
+    let mut diff = new_pixmap(width, height)?;
+    let mut differing_pixels = 0;
+    for (i, out) in diff.data_mut().chunks_exact_mut(4).enumerate() {
+        let (x, y) = (i as u32 % width, i as u32 / width);
+        let (a, b) = (on_white(rendered, x, y), on_white(reference, x, y));
+        if a.iter().zip(b).any(|(a, b)| a.abs_diff(b) > CHANNEL_TOLERANCE) {
+            differing_pixels += 1;
+            out.copy_from_slice(&CHANGED);
+        } else {
+            // Gray at a quarter of the reference's contrast
+            let luma = (b[0] as u32 * 3 + b[1] as u32 * 6 + b[2] as u32) / 10;
+            let faded = (255 - (255 - luma) / 4) as u8;
+            out.copy_from_slice(&[faded, faded, faded, 255]);
+        }
+    }
+    Ok(Difference {
+        differing_pixels,
+        total_pixels: u64::from(width) * u64::from(height),
+        diff,
+    })
+}
+
+/// The pixel at (x, y) composited on white, or white outside the image
+fn on_white(image: &Pixmap, x: u32, y: u32) -> [u8; 3] {
+    match image.pixel(x, y) {
+        // Premultiplied, so adding the uncovered share of white composites it
+        Some(pixel) => {
+            let white = 255 - pixel.alpha();
+            [pixel.red() + white, pixel.green() + white, pixel.blue() + white]
+        }
+        None => [255; 3],
+    }
+}
+
+fn new_pixmap(width: u32, height: u32) -> ServerResult<Pixmap> {
+    if u64::from(width) * u64::from(height) > MAX_PIXELS {
+        return Err(ServerError::RenderFailed(format!(
+            "{width}x{height} image is too large to compare (at most {MAX_PIXELS} pixels)"
+        )));
+    }
+    Pixmap::new(width.max(1), height.max(1))
+        .ok_or_else(|| ServerError::RenderFailed(format!("cannot make a {width}x{height} image")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOX: &str = r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 100 40"><rect x="10" y="5" width="80" height="30" fill="navy"/></svg>"#;
+
+    #[test]
+    fn identical_images_do_not_differ() {
+        let a = rasterize_svg(BOX).unwrap();
+        assert_eq!((a.width(), a.height()), (100, 40));
+        let difference = compare(&a, &rasterize_svg(BOX).unwrap()).unwrap();
+        assert_eq!(difference.differing_pixels, 0);
+        assert_eq!(difference.total_pixels, 4000);
+        assert_eq!(difference.percent(), 0.0);
+
+        // A PNG of the same image is the same too
+        let png = decode_png(&a.encode_png().unwrap()).unwrap();
+        assert_eq!(compare(&a, &png).unwrap().differing_pixels, 0);
+    }
+
+    #[test]
+    fn clearly_different_images_score_the_changed_share() {
+        let rendered = rasterize_svg(BOX).unwrap();
+        // Left half of the box missing: 40x30 of 100x40 pixels
+        let reference = rasterize_svg(&BOX.replace(r#"x="10" y="5" width="80""#, r#"x="50" y="5" width="40""#)).unwrap();
+        let difference = compare(&rendered, &reference).unwrap();
+        assert_eq!(difference.differing_pixels, 1200);
+        assert_eq!(difference.percent(), 30.0);
+        assert_eq!(&difference.diff.data()[..4], &[255, 255, 255, 255]);
+        let changed = (20 * 100 + 30) * 4;
+        assert_eq!(&difference.diff.data()[changed..changed + 4], &CHANGED);
+    }
+
+    #[test]
+    fn transparent_is_white_and_sizes_may_differ() {
+        let transparent = Pixmap::new(10, 10).unwrap();
+        let mut white = Pixmap::new(10, 20).unwrap();
+        white.fill(resvg::tiny_skia::Color::WHITE);
+        let difference = compare(&transparent, &white).unwrap();
+        assert_eq!((difference.differing_pixels, difference.total_pixels), (0, 200));
+
+        let mut black = Pixmap::new(10, 20).unwrap();
+        black.fill(resvg::tiny_skia::Color::BLACK);
+        // The lower half exists only in the reference
+        assert_eq!(compare(&transparent, &black).unwrap().percent(), 100.0);
+    }
+
+    #[test]
+    fn refuses_huge_images() {
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" width="20000" height="20000"/>"#;
+        let err = rasterize_svg(svg).err().unwrap();
+        assert!(err.to_string().contains("too large"), "{err}");
+    }
+}
@@ -0,0 +1,91 @@
+use crate::compose::svg_bounds;
+use crate::config::Watermark;
+
+/// `svg` with `watermark` drawn over it, centered in its bounds. SVGs whose
+/// size can't be read, and empty watermarks, are returned unchanged.
+pub fn add_watermark(svg: &str, watermark: &Watermark) -> String {
+    let text = escape(&watermark.text);
+    let (Some(bounds), Some(close)) = (svg_bounds(svg), svg.rfind("</svg>")) else {
+        return svg.to_string();
+    };
+    if text.trim().is_empty() {
+        return svg.to_string();
+    }
+    let cx = bounds.x + bounds.width / 2.0;
+    let cy = bounds.y + bounds.height / 2.0;
+    // Sized so the text spans about three quarters of the shorter side
+    let chars = watermark.text.chars().count().max(1) as f64;
+    let font_size = (bounds.width.min(bounds.height) * 1.2 / chars).clamp(8.0, bounds.height.max(8.0));
+    let element = format!(
+        r##"<text x="{cx:.2}" y="{cy:.2}" transform="rotate({angle:.2} {cx:.2} {cy:.2})" text-anchor="middle" dominant-baseline="middle" font-family="sans-serif" font-weight="bold" font-size="{font_size:.2}" fill="#808080" fill-opacity="{opacity:.3}" pointer-events="none" data-mermaid-watermark="true">{text}</text>"##,
+        angle = watermark.angle,
+        opacity = watermark.opacity.clamp(0.0, 1.0),
+    );
+    format!("{}{element}{}", &svg[..close], &svg[close..])
+}
+
+/// `text` as character data: markup characters escaped, and characters XML
+/// doesn't allow dropped
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(' '),
+            c if c.is_control() || matches!(c, '\u{fffe}' | '\u{ffff}') => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draft() -> Watermark {
+        Watermark {
+            text: "DRAFT".to_string(),
+            opacity: 0.2,
+            angle: -45.0,
+        }
+    }
+
+    #[test]
+    fn centers_the_text_in_the_view_box() {
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="-10 -20 200 100"><g/></svg>"#;
+        let marked = add_watermark(svg, &draft());
+        assert!(marked.starts_with(r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="-10 -20 200 100"><g/><text x="90.00" y="30.00" transform="rotate(-45.00 90.00 30.00)""#), "{marked}");
+        assert!(marked.contains(r#"fill-opacity="0.200""#), "{marked}");
+        assert!(marked.ends_with(">DRAFT</text></svg>"), "{marked}");
+
+        let sized = add_watermark(r#"<svg width="40px" height="20"></svg>"#, &draft());
+        assert!(sized.contains(r#"x="20.00" y="10.00""#), "{sized}");
+        assert_eq!(add_watermark("<svg><g/></svg>", &draft()), "<svg><g/></svg>");
+    }
+
+    #[test]
+    fn escapes_the_text_so_it_cannot_add_markup() {
+        let svg = r#"<svg viewBox="0 0 100 100"></svg>"#;
+        let hostile = Watermark {
+            text: "</text><script>alert(1)</script>&\"'\u{0}".to_string(),
+            opacity: 7.0,
+            ..draft()
+        };
+        let marked = add_watermark(svg, &hostile);
+        assert!(!marked.contains("<script"), "{marked}");
+        assert!(marked.contains(">&lt;/text&gt;&lt;script&gt;alert(1)&lt;/script&gt;&amp;&quot;&apos;</text>"), "{marked}");
+        assert!(marked.contains(r#"fill-opacity="1.000""#), "{marked}");
+        assert_eq!(marked.matches("<text").count(), 1);
+
+        let blank = Watermark {
+            text: " \u{1} ".to_string(),
+            ..draft()
+        };
+        assert_eq!(add_watermark(svg, &blank), svg);
+    }
+}
@@ -0,0 +1,484 @@
+//! Test utilities driving a `mermaid-lsp` session the way an editor would:
+//! temp workspaces seeded from fixtures, a JSON-RPC client over the server's
+//! stdio, and the client side of `workspace/applyEdit`.
+#![allow(dead_code)]
+
+use lsp_server::{Message, Notification, Request, RequestId, Response};
+use lsp_types::{
+    ApplyWorkspaceEditParams, ClientCapabilities, DocumentChangeOperation, DocumentChanges,
+    InitializeParams, InitializeResult, Position, PositionEncodingKind, ResourceOp,
+    ShowMessageRequestParams, TextEdit, Url, WorkspaceEdit,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::mpsc::{self, Receiver},
+    time::Duration,
+};
+
+/// How long to wait for any one message from the server
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn testdata() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata")
+}
+
+/// The contents of `testdata/<name>`
+pub fn fixture(name: &str) -> String {
+    fs::read_to_string(testdata().join(name)).unwrap_or_else(|e| panic!("fixture {name}: {e}"))
+}
+
+/// A scratch directory holding copies of `testdata/<dir>/<name>` for each name
+pub fn workspace(dir: &str, names: &[&str]) -> tempfile::TempDir {
+    let workspace = tempfile::tempdir().unwrap();
+    for name in names {
+        fs::copy(testdata().join(dir).join(name), workspace.path().join(name)).unwrap();
+    }
+    workspace
+}
+
+/// Every file under `dir`, relative to it, sorted
+pub fn files_in(dir: &Path) -> Vec<String> {
+    fn walk(root: &Path, dir: &Path, out: &mut Vec<String>) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                walk(root, &path, out);
+            } else {
+                out.push(
+                    path.strip_prefix(root)
+                        .unwrap()
+                        .to_string_lossy()
+                        .replace('\\', "/"),
+                );
+            }
+        }
+    }
+    let mut out = Vec::new();
+    walk(dir, dir, &mut out);
+    out.sort();
+    out
+}
+
+/// Byte offset of an LSP position in `text`, counting characters in bytes
+/// when `utf8` and in UTF-16 code units otherwise
+pub fn offset(text: &str, position: Position, utf8: bool) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match text[line_start..].find('\n') {
+            Some(newline) => line_start += newline + 1,
+            None => return text.len(),
+        }
+    }
+    let line_end = text[line_start..]
+        .find('\n')
+        .map_or(text.len(), |newline| line_start + newline);
+    let mut units = 0;
+    for (index, c) in text[line_start..line_end].char_indices() {
+        if units >= position.character as usize {
+            return line_start + index;
+        }
+        units += if utf8 { c.len_utf8() } else { c.len_utf16() };
+    }
+    line_end
+}
+
+/// `text` with `edits` applied, as an editor applies a batch of them: every
+/// range refers to the text before any edit
+pub fn apply_text_edits(text: &str, edits: &[TextEdit], utf8: bool) -> String {
+    let mut sorted: Vec<&TextEdit> = edits.iter().collect();
+    sorted.sort_by_key(|edit| (edit.range.start.line, edit.range.start.character));
+    let mut out = text.to_string();
+    for edit in sorted.into_iter().rev() {
+        let start = offset(text, edit.range.start, utf8);
+        let end = offset(text, edit.range.end, utf8);
+        out.replace_range(start..end, &edit.new_text);
+    }
+    out
+}
+
+/// Documents held by the simulated editor, by URI
+#[derive(Debug, Default)]
+pub struct Editor {
+    pub documents: HashMap<Url, String>,
+    /// Files created by the edits' resource operations
+    pub created: Vec<Url>,
+    /// Positions count bytes rather than UTF-16 code units
+    pub utf8: bool,
+}
+
+impl Editor {
+    /// Apply `edit` to the open documents, creating files its resource
+    /// operations ask for. Returns the URIs of the documents it changed.
+    pub fn apply(&mut self, edit: &WorkspaceEdit) -> Vec<Url> {
+        let mut changed = Vec::new();
+        if let Some(changes) = &edit.changes {
+            for (uri, edits) in changes {
+                self.edit(uri, edits);
+                changed.push(uri.clone());
+            }
+        }
+        let operations = match &edit.document_changes {
+            Some(DocumentChanges::Edits(edits)) => edits
+                .iter()
+                .cloned()
+                .map(DocumentChangeOperation::Edit)
+                .collect(),
+            Some(DocumentChanges::Operations(operations)) => operations.clone(),
+            None => Vec::new(),
+        };
+        for operation in operations {
+            match operation {
+                DocumentChangeOperation::Edit(edit) => {
+                    let edits: Vec<TextEdit> = edit
+                        .edits
+                        .into_iter()
+                        .map(|edit| match edit {
+                            lsp_types::OneOf::Left(edit) => edit,
+                            lsp_types::OneOf::Right(annotated) => annotated.text_edit,
+                        })
+                        .collect();
+                    self.edit(&edit.text_document.uri, &edits);
+                    changed.push(edit.text_document.uri);
+                }
+                DocumentChangeOperation::Op(ResourceOp::Create(create)) => {
+                    let path = create.uri.to_file_path().unwrap();
+                    if !path.exists() {
+                        fs::write(&path, "").unwrap();
+                    }
+                    self.created.push(create.uri);
+                }
+                DocumentChangeOperation::Op(other) => {
+                    panic!("unexpected resource operation: {other:?}")
+                }
+            }
+        }
+        changed.dedup();
+        changed
+    }
+
+    /// Edit a document, reading it from disk if it isn't open
+    fn edit(&mut self, uri: &Url, edits: &[TextEdit]) {
+        let text = self
+            .documents
+            .entry(uri.clone())
+            .or_insert_with(|| fs::read_to_string(uri.to_file_path().unwrap()).unwrap_or_default());
+        *text = apply_text_edits(text, edits, self.utf8);
+    }
+}
+
+/// An LSP session with a `mermaid-lsp` process, speaking JSON-RPC over its
+/// stdio with the stand-in mmdc from `testdata/cli` on its path
+pub struct Session {
+    child: Child,
+    stdin: BufWriter<ChildStdin>,
+    incoming: Receiver<Message>,
+    next_id: i32,
+    versions: HashMap<Url, i32>,
+    pub editor: Editor,
+    /// Whether `workspace/applyEdit` requests are answered as applied
+    pub accept_edits: bool,
+    /// The action prompts are answered with when they offer it, rather than
+    /// being dismissed
+    pub prompt_answer: Option<String>,
+    /// Every message the server sent, in order
+    pub received: Vec<Message>,
+    /// The result of `initialize`
+    pub capabilities: InitializeResult,
+}
+
+impl Session {
+    /// Start the server in `dir` and complete the handshake
+    pub fn start(dir: &Path, capabilities: ClientCapabilities) -> Self {
+        Self::start_with(dir, capabilities, None)
+    }
+
+    /// [`Session::start`] with `initializationOptions`
+    pub fn start_with(
+        dir: &Path,
+        capabilities: ClientCapabilities,
+        options: Option<Value>,
+    ) -> Self {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_mermaid-lsp"));
+        command.env("MMDC_PATH", testdata().join("cli/mmdc"));
+        Self::launch(command, dir, capabilities, options)
+    }
+
+    /// [`Session::start`] on a machine without mermaid-cli: no `MMDC_PATH`,
+    /// and nothing on `PATH`
+    /// Start a server the extension put on probation, which creates `started`
+    /// once initialized
+    pub fn start_on_probation(dir: &Path, started: &Path) -> Self {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_mermaid-lsp"));
+        command
+            .env("MMDC_PATH", testdata().join("cli/mmdc"))
+            .env("MERMAID_LSP_STARTED_FILE", started);
+        Self::launch(command, dir, ClientCapabilities::default(), None)
+    }
+
+    pub fn start_without_mmdc(dir: &Path, capabilities: ClientCapabilities) -> Self {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_mermaid-lsp"));
+        command.env_remove("MMDC_PATH").env("PATH", dir);
+        Self::launch(command, dir, capabilities, None)
+    }
+
+    fn launch(
+        mut command: Command,
+        dir: &Path,
+        capabilities: ClientCapabilities,
+        options: Option<Value>,
+    ) -> Self {
+        let mut child = command
+            .current_dir(dir)
+            .env("RUST_LOG", "off")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let stdin = BufWriter::new(child.stdin.take().unwrap());
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let (sender, incoming) = mpsc::channel();
+        std::thread::spawn(move || {
+            while let Ok(Some(msg)) = Message::read(&mut stdout) {
+                if sender.send(msg).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut session = Self {
+            child,
+            stdin,
+            incoming,
+            next_id: 1,
+            versions: HashMap::new(),
+            editor: Editor::default(),
+            accept_edits: true,
+            prompt_answer: None,
+            received: Vec::new(),
+            capabilities: InitializeResult::default(),
+        };
+        #[allow(deprecated)]
+        let params = InitializeParams {
+            capabilities,
+            initialization_options: options,
+            root_uri: Some(Url::from_directory_path(dir).unwrap()),
+            ..Default::default()
+        };
+        let result = session
+            .request("initialize", serde_json::to_value(params).unwrap())
+            .unwrap();
+        session.capabilities = serde_json::from_value(result).unwrap();
+        session.editor.utf8 =
+            session.capabilities.capabilities.position_encoding == Some(PositionEncodingKind::UTF8);
+        session.notify("initialized", json!({}));
+        session
+    }
+
+    pub fn notify(&mut self, method: &str, params: Value) {
+        self.send(Message::Notification(Notification::new(
+            method.to_string(),
+            params,
+        )));
+    }
+
+    /// Send a request and wait for its response, answering the server's own
+    /// requests meanwhile. Returns the result, or the error's message.
+    pub fn request(&mut self, method: &str, params: Value) -> Result<Value, String> {
+        let id = RequestId::from(self.next_id);
+        self.next_id += 1;
+        self.send(Message::Request(Request::new(
+            id.clone(),
+            method.to_string(),
+            params,
+        )));
+        let resp = self.wait_for(|msg| matches!(msg, Message::Response(r) if r.id == id));
+        let Message::Response(resp) = resp else {
+            unreachable!()
+        };
+        match resp.error {
+            Some(error) => Err(error.message),
+            None => Ok(resp.result.unwrap_or(Value::Null)),
+        }
+    }
+
+    /// Open `path` with its contents on disk
+    pub fn open(&mut self, path: &Path) -> Url {
+        let uri = Url::from_file_path(path).unwrap();
+        let text = fs::read_to_string(path).unwrap();
+        self.versions.insert(uri.clone(), 1);
+        self.editor.documents.insert(uri.clone(), text.clone());
+        self.notify(
+            "textDocument/didOpen",
+            json!({ "textDocument": { "uri": uri, "languageId": "markdown", "version": 1, "text": text } }),
+        );
+        uri
+    }
+
+    /// The simulated editor's text of `uri`
+    pub fn text(&self, uri: &Url) -> &str {
+        self.editor
+            .documents
+            .get(uri)
+            .map(String::as_str)
+            .unwrap_or_default()
+    }
+
+    /// Tell the server `uri` now has the editor's text, as after an edit
+    pub fn sync(&mut self, uri: &Url) {
+        let version = self.versions.entry(uri.clone()).or_insert(1);
+        *version += 1;
+        let version = *version;
+        let text = self.text(uri).to_string();
+        self.notify(
+            "textDocument/didChange",
+            json!({ "textDocument": { "uri": uri, "version": version }, "contentChanges": [{ "text": text }] }),
+        );
+    }
+
+    /// Apply an edit a command returned, as a client without `applyEdit` does
+    pub fn apply(&mut self, edit: &WorkspaceEdit) {
+        for uri in self.editor.apply(edit) {
+            if self.versions.contains_key(&uri) {
+                self.sync(&uri);
+            }
+        }
+    }
+
+    /// Titles of the code actions offered at `line`
+    pub fn code_action_titles(&mut self, uri: &Url, line: u32) -> Vec<String> {
+        let params = json!({
+            "textDocument": { "uri": uri },
+            "range": { "start": { "line": line, "character": 0 }, "end": { "line": line, "character": 0 } },
+            "context": { "diagnostics": [] },
+        });
+        let actions = self.request("textDocument/codeAction", params).unwrap();
+        actions
+            .as_array()
+            .map(|actions| {
+                actions
+                    .iter()
+                    .filter_map(|a| a["title"].as_str().map(String::from))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Run a command on `uri` with `arguments` after it
+    pub fn execute(
+        &mut self,
+        command: &str,
+        uri: &Url,
+        arguments: &[Value],
+    ) -> Result<Value, String> {
+        let mut all = vec![json!(uri)];
+        all.extend_from_slice(arguments);
+        self.request(
+            "workspace/executeCommand",
+            json!({ "command": command, "arguments": all }),
+        )
+    }
+
+    /// The `workspace/applyEdit` requests received so far
+    pub fn applied_edits(&self) -> Vec<ApplyWorkspaceEditParams> {
+        self.received
+            .iter()
+            .filter_map(|msg| match msg {
+                Message::Request(r) if r.method == "workspace/applyEdit" => {
+                    serde_json::from_value(r.params.clone()).ok()
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The params of the requests with `method` received so far
+    pub fn requests(&self, method: &str) -> Vec<Value> {
+        self.received
+            .iter()
+            .filter_map(|msg| match msg {
+                Message::Request(r) if r.method == method => Some(r.params.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Notifications with `method` received so far
+    pub fn notifications(&self, method: &str) -> Vec<Value> {
+        self.received
+            .iter()
+            .filter_map(|msg| match msg {
+                Message::Notification(n) if n.method == method => Some(n.params.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Shut the server down and wait for it to exit
+    pub fn shutdown(mut self) {
+        self.request("shutdown", Value::Null).unwrap();
+        self.notify("exit", Value::Null);
+        let status = self.child.wait().unwrap();
+        assert!(status.success(), "server exited with {status}");
+    }
+
+    fn send(&mut self, msg: Message) {
+        msg.write(&mut self.stdin).unwrap();
+        self.stdin.flush().unwrap();
+    }
+
+    /// Read messages until one matches `done`, answering server requests:
+    /// edits are applied to the editor and reported as `accept_edits` says,
+    /// prompts are answered with `prompt_answer` or dismissed
+    fn wait_for(&mut self, done: impl Fn(&Message) -> bool) -> Message {
+        loop {
+            let msg = self
+                .incoming
+                .recv_timeout(TIMEOUT)
+                .unwrap_or_else(|_| panic!("no message from the server within {TIMEOUT:?}"));
+            self.received.push(msg.clone());
+            if done(&msg) {
+                return msg;
+            }
+            let Message::Request(req) = msg else {
+                continue;
+            };
+            let result = match req.method.as_str() {
+                "workspace/applyEdit" => {
+                    let params: ApplyWorkspaceEditParams =
+                        serde_json::from_value(req.params).unwrap();
+                    if self.accept_edits {
+                        self.apply(&params.edit);
+                    }
+                    json!({ "applied": self.accept_edits })
+                }
+                "window/showMessageRequest" => {
+                    let params: ShowMessageRequestParams =
+                        serde_json::from_value(req.params).unwrap();
+                    let answer = params.actions.unwrap_or_default().into_iter().find(|action| {
+                        self.prompt_answer.as_deref() == Some(action.title.as_str())
+                    });
+                    json!(answer)
+                }
+                _ => Value::Null,
+            };
+            self.send(Message::Response(Response::new_ok(req.id, result)));
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // A failed test mustn't leave the server running
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,468 @@
+//! Whole editor sessions against `mermaid-lsp`: the handshake, documents,
+//! code actions and commands, with the client side of each edit simulated
+//! and the results compared with the golden files in `testdata/e2e`.
+#![cfg(unix)]
+
+mod common;
+
+use common::{files_in, fixture, workspace, Session};
+use lsp_types::{
+    ClientCapabilities, GeneralClientCapabilities, PositionEncodingKind,
+    ShowMessageRequestClientCapabilities, TextDocumentClientCapabilities, WindowClientCapabilities,
+    WorkspaceClientCapabilities, WorkspaceEdit,
+};
+use regex::Regex;
+use std::fs;
+
+/// An editor that applies edits itself and can show prompts and progress
+fn full_capabilities() -> ClientCapabilities {
+    ClientCapabilities {
+        workspace: Some(WorkspaceClientCapabilities {
+            apply_edit: Some(true),
+            ..Default::default()
+        }),
+        window: Some(WindowClientCapabilities {
+            work_done_progress: Some(true),
+            show_message: Some(ShowMessageRequestClientCapabilities::default()),
+            ..Default::default()
+        }),
+        general: Some(GeneralClientCapabilities {
+            position_encodings: Some(vec![
+                PositionEncodingKind::UTF8,
+                PositionEncodingKind::UTF16,
+            ]),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// `text` with the code hashes in generated file names replaced, so goldens
+/// don't depend on the hasher
+fn without_hashes(text: &str) -> String {
+    Regex::new(r"_[0-9a-f]{16}\b")
+        .unwrap()
+        .replace_all(text, "_HASH")
+        .into_owned()
+}
+
+/// Generated files next to the document, leaving out the cache's internals
+fn generated_files(dir: &std::path::Path) -> Vec<String> {
+    files_in(dir)
+        .into_iter()
+        .filter(|file| !file.starts_with(".mermaid/.cache/"))
+        .map(|file| without_hashes(&file))
+        .collect()
+}
+
+#[test]
+fn advertises_commands_and_negotiates_the_position_encoding() {
+    let dir = workspace("e2e", &["guide.md"]);
+    let session = Session::start(dir.path(), full_capabilities());
+    let capabilities = &session.capabilities.capabilities;
+    assert_eq!(
+        capabilities.position_encoding,
+        Some(PositionEncodingKind::UTF8)
+    );
+    let commands = &capabilities
+        .execute_command_provider
+        .as_ref()
+        .unwrap()
+        .commands;
+    for command in [
+        "mermaid.renderSingle",
+        "mermaid.renderAllLightweight",
+        "mermaid.editAllSources",
+        "mermaid.formatAll",
+    ] {
+        assert!(
+            commands.iter().any(|c| c == command),
+            "{command} missing from {commands:?}"
+        );
+    }
+    assert!(capabilities.code_action_provider.is_some());
+    session.shutdown();
+
+    let minimal = Session::start(dir.path(), ClientCapabilities::default());
+    assert_eq!(
+        minimal.capabilities.capabilities.position_encoding,
+        Some(PositionEncodingKind::UTF16)
+    );
+    minimal.shutdown();
+}
+
+#[test]
+fn passes_the_extensions_smoke_tests() {
+    let version = std::process::Command::new(env!("CARGO_BIN_EXE_mermaid-lsp"))
+        .arg("--version")
+        .output()
+        .unwrap();
+    assert!(version.status.success());
+    assert_eq!(
+        String::from_utf8(version.stdout).unwrap(),
+        format!("mermaid-lsp {}\n", env!("CARGO_PKG_VERSION"))
+    );
+
+    let dir = workspace("e2e", &["guide.md"]);
+    let started = dir.path().join(".started");
+    let session = Session::start_on_probation(dir.path(), &started);
+    assert!(started.is_file());
+    session.shutdown();
+}
+
+#[test]
+fn offers_render_actions_at_and_around_fences() {
+    let dir = workspace("e2e", &["guide.md"]);
+    let mut session = Session::start(dir.path(), full_capabilities());
+    let uri = session.open(&dir.path().join("guide.md"));
+
+    let in_fence = session.code_action_titles(&uri, 3);
+    assert!(
+        in_fence.iter().any(|t| t == "Render Mermaid Diagram"),
+        "{in_fence:?}"
+    );
+    let outside = session.code_action_titles(&uri, 0);
+    assert!(
+        !outside.iter().any(|t| t == "Render Mermaid Diagram"),
+        "{outside:?}"
+    );
+    assert!(
+        outside
+            .iter()
+            .any(|t| t == "Render All Mermaid Diagrams (2)"),
+        "{outside:?}"
+    );
+    session.shutdown();
+}
+
+#[test]
+fn render_single_goes_through_apply_edit() {
+    let dir = workspace("e2e", &["guide.md"]);
+    let mut session = Session::start(dir.path(), full_capabilities());
+    let uri = session.open(&dir.path().join("guide.md"));
+
+    let result = session
+        .execute("mermaid.renderSingle", &uri, &[serde_json::json!(3)])
+        .unwrap();
+    assert_eq!(result["status"], "ok", "{result}");
+    assert_eq!(result["rendered"], 1, "{result}");
+    assert_eq!(session.applied_edits().len(), 1);
+    assert_eq!(
+        without_hashes(session.text(&uri)),
+        fixture("e2e/guide.single.md")
+    );
+    assert_eq!(
+        generated_files(dir.path()),
+        [
+            ".mermaid/guide_diagram_HASH.mmd",
+            ".mermaid/guide_diagram_HASH.svg",
+            "guide.md"
+        ]
+    );
+    let source = fs::read_dir(dir.path().join(".mermaid"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "mmd"))
+        .unwrap();
+    assert_eq!(
+        fs::read_to_string(source).unwrap(),
+        "graph TD\n  A[Start] --> B[End]"
+    );
+    session.shutdown();
+}
+
+#[test]
+fn render_all_returns_the_edit_to_clients_without_apply_edit_and_edit_all_reverses_it() {
+    let dir = workspace("e2e", &["guide.md"]);
+    let mut session = Session::start(dir.path(), ClientCapabilities::default());
+    let uri = session.open(&dir.path().join("guide.md"));
+
+    let result = session
+        .execute("mermaid.renderAllLightweight", &uri, &[])
+        .unwrap();
+    let edit: WorkspaceEdit = serde_json::from_value(result).unwrap();
+    assert!(session.applied_edits().is_empty());
+    session.apply(&edit);
+    assert_eq!(
+        without_hashes(session.text(&uri)),
+        fixture("e2e/guide.rendered.md")
+    );
+    assert_eq!(
+        generated_files(dir.path())
+            .iter()
+            .filter(|file| file.ends_with(".svg"))
+            .count(),
+        2
+    );
+
+    let result = session
+        .execute("mermaid.editAllSources", &uri, &[])
+        .unwrap();
+    let edit: WorkspaceEdit = serde_json::from_value(result).unwrap();
+    session.apply(&edit);
+    assert_eq!(session.text(&uri), fixture("e2e/guide.md"));
+    session.shutdown();
+}
+
+#[test]
+fn mdx_fences_and_components_render_and_round_trip() {
+    let dir = workspace("e2e", &["guide.mdx"]);
+    let mut session = Session::start(dir.path(), ClientCapabilities::default());
+    let uri = session.open(&dir.path().join("guide.mdx"));
+
+    for line in [5, 12] {
+        let titles = session.code_action_titles(&uri, line);
+        assert!(
+            titles.iter().any(|t| t == "Render Mermaid Diagram"),
+            "line {line}: {titles:?}"
+        );
+    }
+
+    let result = session
+        .execute("mermaid.renderAllLightweight", &uri, &[])
+        .unwrap();
+    let edit: WorkspaceEdit = serde_json::from_value(result).unwrap();
+    session.apply(&edit);
+    assert_eq!(
+        without_hashes(session.text(&uri)),
+        fixture("e2e/guide.rendered.mdx")
+    );
+    // The component's source is the diagram, not its escaped template literal
+    let sources: Vec<String> = fs::read_dir(dir.path().join(".mermaid"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "mmd"))
+        .map(|path| fs::read_to_string(path).unwrap())
+        .collect();
+    assert!(
+        sources.iter().any(|s| s == "sequenceDiagram\n  Alice->>Bob: `Hello`"),
+        "{sources:?}"
+    );
+
+    let in_block = session.code_action_titles(&uri, 12);
+    assert!(
+        in_block.iter().any(|t| t == "Edit Mermaid Source"),
+        "{in_block:?}"
+    );
+    let result = session
+        .execute("mermaid.editAllSources", &uri, &[])
+        .unwrap();
+    let edit: WorkspaceEdit = serde_json::from_value(result).unwrap();
+    session.apply(&edit);
+    assert_eq!(session.text(&uri), fixture("e2e/guide.mdx"));
+    session.shutdown();
+}
+
+#[test]
+fn rejected_edits_leave_the_document_alone() {
+    let dir = workspace("e2e", &["guide.md"]);
+    let mut session = Session::start(dir.path(), full_capabilities());
+    session.accept_edits = false;
+    let uri = session.open(&dir.path().join("guide.md"));
+
+    session
+        .execute("mermaid.renderSingle", &uri, &[serde_json::json!(3)])
+        .unwrap();
+    assert_eq!(session.applied_edits().len(), 1);
+    assert_eq!(session.text(&uri), fixture("e2e/guide.md"));
+    // The server hears the answer and says so
+    session.execute("mermaid.stats", &uri, &[]).unwrap();
+    let warnings = session.notifications("window/showMessage");
+    assert!(
+        warnings.iter().any(|w| w["message"]
+            .as_str()
+            .unwrap_or_default()
+            .contains("run the command again")),
+        "{warnings:?}"
+    );
+    session.shutdown();
+}
+
+#[test]
+fn renders_without_mermaid_cli_answer_with_install_guidance() {
+    let dir = workspace("e2e", &["guide.md"]);
+    let mut session = Session::start_without_mmdc(dir.path(), full_capabilities());
+    session.prompt_answer = Some("Copy install command".to_string());
+    let uri = session.open(&dir.path().join("guide.md"));
+
+    let install = "npm install -g @mermaid-js/mermaid-cli";
+    let result = session
+        .execute("mermaid.renderSingle", &uri, &[serde_json::json!(3)])
+        .unwrap();
+    assert_eq!(result["error"], "tool-not-found");
+    assert_eq!(result["installHint"], install);
+    assert!(
+        result["docsUrl"].as_str().unwrap().starts_with("https://"),
+        "{result}"
+    );
+    assert!(
+        result["message"].as_str().unwrap().contains("mmdc not found"),
+        "{result}"
+    );
+    let prompts = session.requests("window/showMessageRequest");
+    assert_eq!(prompts.len(), 1);
+    assert!(
+        prompts[0]["message"].as_str().unwrap().contains(install),
+        "{prompts:?}"
+    );
+    assert!(session.applied_edits().is_empty());
+    assert_eq!(session.text(&uri), fixture("e2e/guide.md"));
+
+    // Render all fails the same way; the copied command follows the first answer
+    let result = session
+        .execute("mermaid.renderAllLightweight", &uri, &[])
+        .unwrap();
+    assert_eq!(result["installHint"], install);
+    let messages: Vec<String> = session
+        .notifications("window/showMessage")
+        .iter()
+        .map(|m| m["message"].as_str().unwrap_or_default().to_string())
+        .collect();
+    assert!(messages.iter().any(|m| m == install), "{messages:?}");
+    assert_eq!(generated_files(dir.path()), ["guide.md"]);
+    session.shutdown();
+}
+
+#[test]
+fn compares_a_fence_with_reference_images() {
+    let dir = workspace("e2e", &["guide.md"]);
+    fs::copy(
+        common::testdata().join("cli/mmdc-output.svg"),
+        dir.path().join("same.svg"),
+    )
+    .unwrap();
+    fs::copy(
+        common::testdata().join("flowchart.svg"),
+        dir.path().join("other.svg"),
+    )
+    .unwrap();
+    let mut session = Session::start(dir.path(), full_capabilities());
+    let uri = session.open(&dir.path().join("guide.md"));
+
+    let same = session
+        .execute(
+            "mermaid.compareToReference",
+            &uri,
+            &[serde_json::json!(3), serde_json::json!("same.svg")],
+        )
+        .unwrap();
+    assert_eq!(same["status"], "ok", "{same}");
+    assert_eq!(same["difference"], 0.0, "{same}");
+    assert_eq!(same["passed"], true, "{same}");
+    assert!(same["diffImage"].is_null(), "{same}");
+
+    let other = session
+        .execute(
+            "mermaid.compareToReference",
+            &uri,
+            &[
+                serde_json::Value::Null,
+                serde_json::json!("other.svg"),
+                serde_json::json!(5),
+                serde_json::json!(true),
+            ],
+        )
+        .unwrap();
+    assert_eq!(other["status"], "failed", "{other}");
+    assert_eq!(other["passed"], false, "{other}");
+    assert!(other["difference"].as_f64().unwrap() > 5.0, "{other}");
+    let diff = other["diffImage"].as_str().unwrap();
+    assert!(diff.starts_with(".mermaid/guide_diff_"), "{diff}");
+    assert!(fs::read(dir.path().join(diff))
+        .unwrap()
+        .starts_with(b"\x89PNG"));
+
+    // References are only read inside the workspace
+    let outside = common::testdata().join("flowchart.svg");
+    let error = session
+        .execute(
+            "mermaid.compareToReference",
+            &uri,
+            &[serde_json::json!(3), serde_json::json!(outside)],
+        )
+        .unwrap_err();
+    assert!(error.contains("outside the workspace"), "{error}");
+    session.shutdown();
+}
+
+#[test]
+fn commands_edit_files_that_are_not_open_on_disk() {
+    let dir = workspace("e2e", &["guide.md"]);
+    let path = dir.path().join("flow.md");
+    fs::write(&path, "\u{feff}# Flow\n\n```mermaid\ngraph TD\nA-->B\n```\n").unwrap();
+    let mut session = Session::start(dir.path(), full_capabilities());
+    let uri = lsp_types::Url::from_file_path(&path).unwrap();
+
+    let result = session.execute("mermaid.formatAll", &uri, &[]).unwrap();
+    assert_eq!(result["edits"], 1, "{result}");
+    assert!(result.get("error").is_none(), "{result}");
+    assert!(session.applied_edits().is_empty());
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "\u{feff}# Flow\n\n```mermaid\ngraph TD\n    A --> B\n```\n"
+    );
+
+    // Only some commands read files from disk, and only text ones
+    let error = session
+        .execute("mermaid.renderSingle", &uri, &[serde_json::json!(3)])
+        .unwrap_err();
+    assert!(error.contains("document is not open"), "{error}");
+    let binary = dir.path().join("latin1.md");
+    fs::write(&binary, b"caf\xe9\n").unwrap();
+    let error = session
+        .execute(
+            "mermaid.generateIndex",
+            &lsp_types::Url::from_file_path(&binary).unwrap(),
+            &[],
+        )
+        .unwrap_err();
+    assert!(error.contains("not UTF-8"), "{error}");
+    session.shutdown();
+}
+
+#[test]
+fn a_fence_over_its_time_budget_fails_alone() {
+    let dir = workspace("e2e", &[]);
+    let path = dir.path().join("batch.md");
+    fs::write(
+        &path,
+        "```mermaid\ngraph TD\n  A --> B\n```\n\n```mermaid\ngraph TD\n  %% SLOW\n  C --> D\n```\n\n```mermaid\ngraph TD\n  E --> F\n```\n",
+    )
+    .unwrap();
+    let capabilities = ClientCapabilities {
+        text_document: Some(TextDocumentClientCapabilities {
+            publish_diagnostics: Some(Default::default()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let mut session = Session::start_with(
+        dir.path(),
+        capabilities,
+        Some(serde_json::json!({ "fenceRenderTimeoutMs": 500 })),
+    );
+    let uri = session.open(&path);
+
+    let started = std::time::Instant::now();
+    let result = session
+        .execute("mermaid.renderAllLightweight", &uri, &[])
+        .unwrap();
+    assert!(started.elapsed() < std::time::Duration::from_secs(4));
+    let edit: WorkspaceEdit = serde_json::from_value(result).unwrap();
+    let edits = &edit.changes.as_ref().unwrap()[&uri];
+    let mut replaced: Vec<u32> = edits.iter().map(|edit| edit.range.start.line).collect();
+    replaced.sort_unstable();
+    assert_eq!(replaced, [0, 11]);
+
+    let diagnostics = session.notifications("textDocument/publishDiagnostics");
+    let messages: Vec<&str> = diagnostics
+        .iter()
+        .flat_map(|d| d["diagnostics"].as_array().unwrap())
+        .map(|d| d["message"].as_str().unwrap())
+        .collect();
+    assert!(
+        messages.iter().any(|m| m.contains("fenceRenderTimeoutMs")),
+        "{messages:?}"
+    );
+    session.shutdown();
+}
@@ -0,0 +1,220 @@
+//! Drives `mermaid-lsp --render` / `--render-doc` / `--verify` against the fixtures in
+//! `testdata/cli`, with a stand-in mmdc script rendering them.
+#![cfg(unix)]
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+// Exit codes, as documented in the README
+const EXIT_INVALID: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_RENDER: i32 = 3;
+const EXIT_IO: i32 = 4;
+
+fn fixtures() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/cli")
+}
+
+/// A scratch directory holding copies of the fixtures
+fn workspace() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["flow.mmd", "guide.md"] {
+        fs::copy(fixtures().join(name), dir.path().join(name)).unwrap();
+    }
+    dir
+}
+
+/// Run the CLI in `dir` with `stdin` as its input
+fn run(dir: &Path, args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mermaid-lsp"))
+        .args(args)
+        .current_dir(dir)
+        .env("MMDC_PATH", fixtures().join("mmdc"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn renders_a_diagram_to_a_sanitized_svg() {
+    let dir = workspace();
+    let output = run(dir.path(), &["--render", "flow.mmd", "-o", "flow.svg"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(output.stdout.is_empty());
+
+    let expected = fs::read_to_string(fixtures().join("flow.svg")).unwrap();
+    assert_eq!(fs::read_to_string(dir.path().join("flow.svg")).unwrap(), expected);
+    // Rendered through the same cache as the editor
+    assert!(dir.path().join(".mermaid/.cache/index.json").is_file());
+}
+
+#[test]
+fn renders_without_the_cache_when_asked() {
+    let dir = workspace();
+    let output = run(dir.path(), &["--render", "flow.mmd", "-o", "flow.svg", "--no-cache"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let expected = fs::read_to_string(fixtures().join("flow.svg")).unwrap();
+    assert_eq!(fs::read_to_string(dir.path().join("flow.svg")).unwrap(), expected);
+    assert!(!dir.path().join(".mermaid").exists());
+}
+
+#[test]
+fn streams_stdin_to_stdout() {
+    let dir = workspace();
+    let code = fs::read_to_string(fixtures().join("flow.mmd")).unwrap();
+    let output = run(dir.path(), &["--render", "-", "--theme", "dark"], &code);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let expected = fs::read_to_string(fixtures().join("flow.svg")).unwrap().replace("\"default\"", "\"dark\"");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+}
+
+#[test]
+fn theme_flag_overrides_the_config_file() {
+    let dir = workspace();
+    fs::write(dir.path().join("config.json"), r#"{ "theme": "forest" }"#).unwrap();
+    let from_file = run(dir.path(), &["--render", "flow.mmd", "--config", "config.json"], "");
+    assert!(String::from_utf8_lossy(&from_file.stdout).contains("data-theme=\"forest\""));
+
+    let overridden = run(dir.path(), &["--render", "flow.mmd", "--config", "config.json", "--theme=neutral"], "");
+    assert!(String::from_utf8_lossy(&overridden.stdout).contains("data-theme=\"neutral\""));
+}
+
+#[test]
+fn renders_png_by_extension_or_flag() {
+    let dir = workspace();
+    let output = run(dir.path(), &["--render", "flow.mmd", "-o", "flow.png"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(fs::read(dir.path().join("flow.png")).unwrap().starts_with(b"\x89PNG"));
+
+    let streamed = run(dir.path(), &["--render", "flow.mmd", "--format", "png"], "");
+    assert!(streamed.stdout.starts_with(b"\x89PNG"));
+}
+
+#[test]
+fn renders_a_document_in_place() {
+    let dir = workspace();
+    let output = run(dir.path(), &["--render-doc", "guide.md", "--in-place"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(output.stdout.is_empty());
+    assert!(stderr(&output).contains("rendered 1, failed 0, ignored 1"), "{}", stderr(&output));
+
+    let doc = fs::read_to_string(dir.path().join("guide.md")).unwrap();
+    let lines: Vec<&str> = doc.lines().collect();
+    assert_eq!(lines[..2], ["# Guide", ""]);
+    assert!(lines[2].starts_with("<!-- mermaid-source-file:.mermaid/guide_"), "{doc}");
+    assert!(lines[2].ends_with(".mmd fence-info:{theme=dark} -->"), "{doc}");
+    let image = lines[4].strip_prefix("![Mermaid Diagram](").and_then(|l| l.strip_suffix(')')).unwrap();
+    assert_eq!(
+        fs::read_to_string(dir.path().join(image)).unwrap(),
+        fs::read_to_string(fixtures().join("flow.svg")).unwrap()
+    );
+    // The ignored fence is left as it is
+    assert_eq!(lines[5..], ["", "Text", "", "```mermaid {ignore}", "graph LR", "  X --> Y", "```"]);
+}
+
+#[test]
+fn prints_the_rendered_document_without_in_place() {
+    let dir = workspace();
+    let original = fs::read_to_string(dir.path().join("guide.md")).unwrap();
+    let output = run(dir.path(), &["--render-doc", "guide.md"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(String::from_utf8(output.stdout).unwrap().contains("![Mermaid Diagram](.mermaid/guide_diagram_"));
+    assert_eq!(fs::read_to_string(dir.path().join("guide.md")).unwrap(), original);
+}
+
+#[test]
+fn exit_codes_tell_failures_apart() {
+    let dir = workspace();
+    fs::write(dir.path().join("broken.md"), "# Broken\n\n```mermaid\ngraph TD\n").unwrap();
+    fs::write(dir.path().join("failing.md"), "```mermaid\ngraph TD\n  FAIL\n```\n").unwrap();
+
+    let empty = run(dir.path(), &["--render", "-"], "\n");
+    assert_eq!(empty.status.code(), Some(EXIT_INVALID), "{}", stderr(&empty));
+    let unclosed = run(dir.path(), &["--render-doc", "broken.md", "--in-place"], "");
+    assert_eq!(unclosed.status.code(), Some(EXIT_INVALID), "{}", stderr(&unclosed));
+    assert!(stderr(&unclosed).contains("broken.md:3:1: error: "), "{}", stderr(&unclosed));
+
+    let failed = run(dir.path(), &["--render", "-"], "graph TD\n  FAIL\n");
+    assert_eq!(failed.status.code(), Some(EXIT_RENDER), "{}", stderr(&failed));
+    assert!(failed.stdout.is_empty());
+    let failed_fence = run(dir.path(), &["--render-doc", "failing.md", "--in-place"], "");
+    assert_eq!(failed_fence.status.code(), Some(EXIT_RENDER), "{}", stderr(&failed_fence));
+    assert!(stderr(&failed_fence).contains("failing.md:3: error: "), "{}", stderr(&failed_fence));
+
+    let missing = run(dir.path(), &["--render", "missing.mmd"], "");
+    assert_eq!(missing.status.code(), Some(EXIT_IO), "{}", stderr(&missing));
+    let usage = run(dir.path(), &["--render", "flow.mmd", "--format", "gif"], "");
+    assert_eq!(usage.status.code(), Some(EXIT_USAGE));
+    assert!(stderr(&usage).contains("usage: mermaid-lsp --render"));
+}
+
+/// `guide.md` and a second document rendered in place, and the SVG of `guide.md`'s diagram
+fn rendered_workspace() -> (tempfile::TempDir, PathBuf) {
+    let dir = workspace();
+    fs::write(dir.path().join("other.md"), "```mermaid\ngraph LR\n  X --> Y\n```\n").unwrap();
+    for doc in ["guide.md", "other.md"] {
+        let output = run(dir.path(), &["--render-doc", doc, "--in-place"], "");
+        assert!(output.status.success(), "{}", stderr(&output));
+    }
+    let doc = fs::read_to_string(dir.path().join("guide.md")).unwrap();
+    let image = doc.lines().find_map(|l| l.strip_prefix("![Mermaid Diagram](")).unwrap().trim_end_matches(')');
+    let svg = dir.path().join(image);
+    (dir, svg)
+}
+
+#[test]
+fn verify_reports_svgs_that_differ_from_their_source() {
+    let (dir, svg) = rendered_workspace();
+    let up_to_date = run(dir.path(), &["--verify", "."], "");
+    assert_eq!(up_to_date.status.code(), Some(0), "{}", stderr(&up_to_date));
+    assert!(up_to_date.stdout.is_empty());
+    assert!(stderr(&up_to_date).contains("verified 2 diagrams: 2 up to date"), "{}", stderr(&up_to_date));
+
+    // The committed SVG was rendered by an older toolchain
+    let committed = fs::read_to_string(&svg).unwrap();
+    fs::write(&svg, committed.replace(">Start<", ">Begin<")).unwrap();
+    let stale = run(dir.path(), &["--verify", "."], "");
+    assert_eq!(stale.status.code(), Some(EXIT_INVALID), "{}", stderr(&stale));
+    let report = String::from_utf8_lossy(&stale.stdout);
+    assert_eq!(report.lines().count(), 1, "{report}");
+    assert!(report.starts_with("./guide.md:3: .mermaid/guide_diagram_"), "{report}");
+    assert!(report.contains(".svg is out of date with .mermaid/guide_diagram_"), "{report}");
+    assert!(stderr(&stale).contains("1 up to date, 1 out of date, 0 failed"), "{}", stderr(&stale));
+}
+
+#[test]
+fn structural_verify_ignores_formatting_and_id_names() {
+    let (dir, svg) = rendered_workspace();
+    let committed = fs::read_to_string(&svg).unwrap();
+    fs::write(&svg, committed.replace("><", ">\n  <").replace("my-svg", "diagram-1")).unwrap();
+
+    let exact = run(dir.path(), &["--verify", "guide.md"], "");
+    assert_eq!(exact.status.code(), Some(EXIT_INVALID), "{}", stderr(&exact));
+    let structural = run(dir.path(), &["--verify", "guide.md", "--structural"], "");
+    assert_eq!(structural.status.code(), Some(0), "{}", stderr(&structural));
+
+    // A source that no longer renders can't be verified
+    let source = svg.with_extension("mmd");
+    fs::write(&source, "graph TD\n  FAIL").unwrap();
+    let failed = run(dir.path(), &["--verify", "guide.md", "--structural"], "");
+    assert_eq!(failed.status.code(), Some(EXIT_RENDER), "{}", stderr(&failed));
+    assert!(String::from_utf8_lossy(&failed.stdout).contains("guide.md:3: error: .mermaid/guide_diagram_"));
+
+    let usage = run(dir.path(), &["--verify"], "");
+    assert_eq!(usage.status.code(), Some(EXIT_USAGE));
+    assert!(stderr(&usage).contains("usage: mermaid-lsp --verify"));
+}
@@ -0,0 +1,152 @@
+use zed_extension_api::{Architecture, GithubRelease, GithubReleaseAsset, Os, Result};
+
+/// Picks one of several builds for this platform, e.g. `musl` or `bundled`
+pub const ASSET_VARIANT_ENV: &str = "MERMAID_LSP_ASSET_VARIANT";
+
+const PREFIX: &str = "mermaid-lsp-";
+const EXTENSION: &str = ".zip";
+
+/// Target triple parts of the default build: `(arch, os)`
+pub fn platform_parts(os: Os, arch: Architecture) -> (&'static str, &'static str) {
+    let arch = match arch {
+        Architecture::Aarch64 => "aarch64",
+        Architecture::X86 => "x86",
+        Architecture::X8664 => "x86_64",
+    };
+    let os = match os {
+        Os::Mac => "apple-darwin",
+        Os::Linux => "unknown-linux-gnu",
+        Os::Windows => "pc-windows-msvc",
+    };
+    (arch, os)
+}
+
+/// The asset of `release` to download for `arch` and `os`. Without a
+/// `variant` it is the one named exactly `mermaid-lsp-<arch>-<os>.zip`; with
+/// one, the build for the platform whose name has `variant` as a `-`
+/// separated part (the shortest name when several do).
+pub fn select_asset(
+    release: &GithubRelease,
+    arch: &str,
+    os: &str,
+    variant: Option<&str>,
+) -> Result<GithubReleaseAsset> {
+    let expected = format!("{PREFIX}{arch}-{os}{EXTENSION}");
+    let variant = variant.map(str::trim).filter(|v| !v.is_empty());
+    let Some(variant) = variant else {
+        return release
+            .assets
+            .iter()
+            .find(|a| a.name == expected)
+            .cloned()
+            .ok_or_else(|| {
+                let available: Vec<_> = release.assets.iter().map(|a| a.name.as_str()).collect();
+                format!("No asset '{expected}' found. Available: {available:?}")
+            });
+    };
+
+    let builds: Vec<(&GithubReleaseAsset, Vec<&str>)> = release
+        .assets
+        .iter()
+        .filter_map(|asset| Some((asset, variant_parts(&asset.name, arch, os)?)))
+        .collect();
+    builds
+        .iter()
+        .filter(|(_, parts)| parts.iter().any(|part| part.eq_ignore_ascii_case(variant)))
+        .min_by_key(|(asset, _)| (asset.name.len(), &asset.name))
+        .map(|(asset, _)| (*asset).clone())
+        .ok_or_else(|| {
+            let mut variants: Vec<&str> = builds.iter().flat_map(|(_, parts)| parts.iter().copied()).collect();
+            variants.sort_unstable();
+            variants.dedup();
+            format!(
+                "No '{arch}' '{os}' asset for {ASSET_VARIANT_ENV}={variant}. Variants in {}: {variants:?}",
+                release.version
+            )
+        })
+}
+
+/// Parts of `name` that set it apart from the default build, if it is a build
+/// for `arch` and the OS family of `os`: `["musl"]` for
+/// `mermaid-lsp-x86_64-unknown-linux-musl.zip`
+fn variant_parts<'a>(name: &'a str, arch: &str, os: &str) -> Option<Vec<&'a str>> {
+    let rest = name.strip_prefix(PREFIX)?.strip_prefix(arch)?.strip_prefix('-')?;
+    let rest = rest.strip_suffix(EXTENSION)?;
+    // The vendor and OS, e.g. `unknown-linux` of `unknown-linux-gnu`
+    let family: Vec<&str> = os.split('-').take(2).collect();
+    let parts: Vec<&str> = rest.split('-').collect();
+    if parts.len() < family.len() || parts[..family.len()] != family[..] {
+        return None;
+    }
+    let default: Vec<&str> = os.split('-').collect();
+    Some(parts[family.len()..].iter().copied().filter(|part| !default.contains(part)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A release with two Linux x86_64 builds besides the default one
+    fn release() -> GithubRelease {
+        let names = [
+            "mermaid-lsp-x86_64-unknown-linux-gnu.zip",
+            "mermaid-lsp-x86_64-unknown-linux-musl.zip",
+            "mermaid-lsp-x86_64-unknown-linux-gnu-bundled.zip",
+            "mermaid-lsp-aarch64-unknown-linux-musl.zip",
+            "mermaid-lsp-x86_64-apple-darwin.zip",
+            "mermaid-lsp-x86_64-pc-windows-msvc.zip",
+            "checksums.txt",
+        ];
+        GithubRelease {
+            version: "v0.4.0".to_string(),
+            assets: names
+                .iter()
+                .map(|name| GithubReleaseAsset {
+                    name: name.to_string(),
+                    download_url: format!("https://example.com/v0.4.0/{name}"),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn picks_the_exact_name_without_a_variant() {
+        let (arch, os) = platform_parts(Os::Linux, Architecture::X8664);
+        for variant in [None, Some(""), Some("  ")] {
+            let asset = select_asset(&release(), arch, os, variant).unwrap();
+            assert_eq!(asset.name, "mermaid-lsp-x86_64-unknown-linux-gnu.zip");
+        }
+        let (arch, os) = platform_parts(Os::Windows, Architecture::Aarch64);
+        let err = select_asset(&release(), arch, os, None).unwrap_err();
+        assert!(err.contains("No asset 'mermaid-lsp-aarch64-pc-windows-msvc.zip' found"), "{err}");
+    }
+
+    #[test]
+    fn picks_the_build_naming_the_variant() {
+        let (arch, os) = platform_parts(Os::Linux, Architecture::X8664);
+        let musl = select_asset(&release(), arch, os, Some("musl")).unwrap();
+        assert_eq!(musl.name, "mermaid-lsp-x86_64-unknown-linux-musl.zip");
+        assert_eq!(musl.download_url, "https://example.com/v0.4.0/mermaid-lsp-x86_64-unknown-linux-musl.zip");
+        let bundled = select_asset(&release(), arch, os, Some("Bundled")).unwrap();
+        assert_eq!(bundled.name, "mermaid-lsp-x86_64-unknown-linux-gnu-bundled.zip");
+
+        let (arch, os) = platform_parts(Os::Linux, Architecture::Aarch64);
+        let musl = select_asset(&release(), arch, os, Some("musl")).unwrap();
+        assert_eq!(musl.name, "mermaid-lsp-aarch64-unknown-linux-musl.zip");
+    }
+
+    #[test]
+    fn lists_the_variants_when_none_matches() {
+        let (arch, os) = platform_parts(Os::Linux, Architecture::X8664);
+        let err = select_asset(&release(), arch, os, Some("static")).unwrap_err();
+        assert_eq!(
+            err,
+            "No 'x86_64' 'unknown-linux-gnu' asset for MERMAID_LSP_ASSET_VARIANT=static. \
+             Variants in v0.4.0: [\"bundled\", \"musl\"]"
+        );
+        // Other platforms' variants aren't offered
+        let (arch, os) = platform_parts(Os::Mac, Architecture::X8664);
+        let err = select_asset(&release(), arch, os, Some("musl")).unwrap_err();
+        assert!(err.ends_with("Variants in v0.4.0: []"), "{err}");
+    }
+}
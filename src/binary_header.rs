@@ -0,0 +1,274 @@
+use std::{fmt, fs::File, io::Read, path::Path};
+
+use zed_extension_api::{Architecture, Os};
+
+/// Bytes read from the start of a binary; enough for the ELF, Mach-O and
+/// usual PE headers
+const HEADER_LEN: usize = 4096;
+
+/// Executable format, from the file's magic bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Elf,
+    MachO,
+    Pe,
+}
+
+/// CPU architecture of a binary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arch {
+    X86,
+    X8664,
+    Aarch64,
+    Other(u32),
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arch::X86 => write!(f, "x86"),
+            Arch::X8664 => write!(f, "x86_64"),
+            Arch::Aarch64 => write!(f, "aarch64"),
+            Arch::Other(id) => write!(f, "unknown architecture {id:#x}"),
+        }
+    }
+}
+
+impl From<Architecture> for Arch {
+    fn from(arch: Architecture) -> Self {
+        match arch {
+            Architecture::Aarch64 => Arch::Aarch64,
+            Architecture::X86 => Arch::X86,
+            Architecture::X8664 => Arch::X8664,
+        }
+    }
+}
+
+/// Format and architectures (several for universal Mach-O) of a binary header.
+/// None when the bytes are not a recognized executable.
+pub fn parse(header: &[u8]) -> Option<(Format, Vec<Arch>)> {
+    match header.get(..4)? {
+        [0x7f, b'E', b'L', b'F'] => parse_elf(header),
+        [0xcf, 0xfa, 0xed, 0xfe] | [0xce, 0xfa, 0xed, 0xfe] => {
+            let cpu = u32::from_le_bytes(header.get(4..8)?.try_into().ok()?);
+            Some((Format::MachO, vec![mach_arch(cpu)]))
+        }
+        [0xfe, 0xed, 0xfa, 0xcf] | [0xfe, 0xed, 0xfa, 0xce] => {
+            let cpu = u32::from_be_bytes(header.get(4..8)?.try_into().ok()?);
+            Some((Format::MachO, vec![mach_arch(cpu)]))
+        }
+        [0xca, 0xfe, 0xba, 0xbe] => parse_fat(header),
+        [b'M', b'Z', ..] => parse_pe(header),
+        _ => None,
+    }
+}
+
+fn parse_elf(header: &[u8]) -> Option<(Format, Vec<Arch>)> {
+    let machine: [u8; 2] = header.get(18..20)?.try_into().ok()?;
+    let machine = match header.get(5)? {
+        2 => u16::from_be_bytes(machine),
+        _ => u16::from_le_bytes(machine),
+    };
+    let arch = match machine {
+        3 => Arch::X86,
+        62 => Arch::X8664,
+        183 => Arch::Aarch64,
+        other => Arch::Other(other.into()),
+    };
+    Some((Format::Elf, vec![arch]))
+}
+
+fn mach_arch(cpu: u32) -> Arch {
+    match cpu {
+        0x0000_0007 => Arch::X86,
+        0x0100_0007 => Arch::X8664,
+        0x0100_000c => Arch::Aarch64,
+        other => Arch::Other(other),
+    }
+}
+
+/// Universal Mach-O: a big-endian list of (cputype, ...) 20-byte entries
+fn parse_fat(header: &[u8]) -> Option<(Format, Vec<Arch>)> {
+    let count = u32::from_be_bytes(header.get(4..8)?.try_into().ok()?) as usize;
+    // Java class files share the magic; they have a large "count" (the version)
+    if count == 0 || count > 32 {
+        return None;
+    }
+    let archs = (0..count)
+        .map(|i| {
+            let offset = 8 + i * 20;
+            let cpu = u32::from_be_bytes(header.get(offset..offset + 4)?.try_into().ok()?);
+            Some(mach_arch(cpu))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some((Format::MachO, archs))
+}
+
+fn parse_pe(header: &[u8]) -> Option<(Format, Vec<Arch>)> {
+    let pe_offset = u32::from_le_bytes(header.get(0x3c..0x40)?.try_into().ok()?) as usize;
+    if header.get(pe_offset..pe_offset + 4)? != b"PE\0\0" {
+        return None;
+    }
+    let machine = u16::from_le_bytes(header.get(pe_offset + 4..pe_offset + 6)?.try_into().ok()?);
+    let arch = match machine {
+        0x014c => Arch::X86,
+        0x8664 => Arch::X8664,
+        0xaa64 => Arch::Aarch64,
+        other => Arch::Other(other.into()),
+    };
+    Some((Format::Pe, vec![arch]))
+}
+
+/// Check a header against the platform. Unrecognized files (e.g. wrapper
+/// scripts) pass; x86_64 binaries are accepted on Apple Silicon via Rosetta.
+pub fn check(header: &[u8], os: Os, arch: Architecture) -> Result<(), String> {
+    let Some((format, archs)) = parse(header) else {
+        return Ok(());
+    };
+
+    let expected_format = match os {
+        Os::Mac => Format::MachO,
+        Os::Linux => Format::Elf,
+        Os::Windows => Format::Pe,
+    };
+    if format != expected_format {
+        return Err(format!(
+            "it is a {format:?} executable, but this platform uses {expected_format:?}"
+        ));
+    }
+
+    let expected = Arch::from(arch);
+    let rosetta = matches!(os, Os::Mac) && expected == Arch::Aarch64;
+    if archs
+        .iter()
+        .any(|&a| a == expected || (rosetta && a == Arch::X8664))
+    {
+        return Ok(());
+    }
+
+    let found: Vec<String> = archs.iter().map(Arch::to_string).collect();
+    Err(format!(
+        "binary architecture mismatch: expected {expected}, found {}",
+        found.join("/")
+    ))
+}
+
+/// Read the header of the binary at `path` and [`check`] it. Files that cannot
+/// be read are not rejected here; launching them will report the problem.
+pub fn check_file(path: &Path, os: Os, arch: Architecture) -> Result<(), String> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    let read = File::open(path).and_then(|f| f.take(HEADER_LEN as u64).read_to_end(&mut header));
+    if read.is_err() {
+        return Ok(());
+    }
+    check(&header, os, arch)
+        .map_err(|reason| format!("Rejected mermaid-lsp at {}: {reason}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn elf(machine: u16) -> Vec<u8> {
+        let mut header = vec![0u8; 64];
+        header[..4].copy_from_slice(b"\x7fELF");
+        header[4] = 2; // 64-bit
+        header[5] = 1; // little endian
+        header[18..20].copy_from_slice(&machine.to_le_bytes());
+        header
+    }
+
+    fn macho(cpu: u32) -> Vec<u8> {
+        let mut header = vec![0u8; 32];
+        header[..4].copy_from_slice(&[0xcf, 0xfa, 0xed, 0xfe]);
+        header[4..8].copy_from_slice(&cpu.to_le_bytes());
+        header
+    }
+
+    fn universal(cpus: &[u32]) -> Vec<u8> {
+        let mut header = vec![0xca, 0xfe, 0xba, 0xbe];
+        header.extend((cpus.len() as u32).to_be_bytes());
+        for cpu in cpus {
+            let mut entry = [0u8; 20];
+            entry[..4].copy_from_slice(&cpu.to_be_bytes());
+            header.extend(entry);
+        }
+        header
+    }
+
+    fn pe(machine: u16) -> Vec<u8> {
+        let mut header = vec![0u8; 0x100];
+        header[..2].copy_from_slice(b"MZ");
+        header[0x3c..0x40].copy_from_slice(&0x80u32.to_le_bytes());
+        header[0x80..0x84].copy_from_slice(b"PE\0\0");
+        header[0x84..0x86].copy_from_slice(&machine.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn parses_each_format() {
+        assert_eq!(parse(&elf(62)), Some((Format::Elf, vec![Arch::X8664])));
+        assert_eq!(parse(&elf(183)), Some((Format::Elf, vec![Arch::Aarch64])));
+        assert_eq!(parse(&macho(0x0100_000c)), Some((Format::MachO, vec![Arch::Aarch64])));
+        assert_eq!(
+            parse(&universal(&[0x0100_0007, 0x0100_000c])),
+            Some((Format::MachO, vec![Arch::X8664, Arch::Aarch64]))
+        );
+        assert_eq!(parse(&pe(0x8664)), Some((Format::Pe, vec![Arch::X8664])));
+        assert_eq!(parse(&pe(0xaa64)), Some((Format::Pe, vec![Arch::Aarch64])));
+    }
+
+    #[test]
+    fn unknown_or_truncated_headers_are_not_executables() {
+        assert_eq!(parse(b"#!/bin/sh\nexec mermaid-lsp"), None);
+        assert_eq!(parse(&elf(62)[..10]), None);
+        assert_eq!(parse(b"MZ"), None);
+        assert!(check(b"#!/bin/sh", Os::Linux, Architecture::X8664).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_architectures_naming_both() {
+        let err = check(&elf(62), Os::Linux, Architecture::Aarch64).unwrap_err();
+        assert_eq!(err, "binary architecture mismatch: expected aarch64, found x86_64");
+        assert!(check(&elf(183), Os::Linux, Architecture::Aarch64).is_ok());
+        assert!(check(&elf(3), Os::Linux, Architecture::X86).is_ok());
+        assert!(check(&elf(3), Os::Linux, Architecture::X8664).is_err());
+
+        let err = check(&pe(0x8664), Os::Windows, Architecture::Aarch64).unwrap_err();
+        assert_eq!(err, "binary architecture mismatch: expected aarch64, found x86_64");
+        assert!(check(&pe(0xaa64), Os::Windows, Architecture::Aarch64).is_ok());
+        assert!(check(&pe(0x014c), Os::Windows, Architecture::X86).is_ok());
+
+        let err = check(&macho(0x0100_000c), Os::Mac, Architecture::X8664).unwrap_err();
+        assert_eq!(err, "binary architecture mismatch: expected x86_64, found aarch64");
+        let err = check(&universal(&[0x0000_0007, 0x0100_000c]), Os::Mac, Architecture::X8664).unwrap_err();
+        assert!(err.ends_with("found x86/aarch64"), "{err}");
+    }
+
+    #[test]
+    fn checks_header_files_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = dir.path().join("mermaid-lsp");
+        std::fs::write(&binary, elf(62)).unwrap();
+        let err = check_file(&binary, Os::Linux, Architecture::Aarch64).unwrap_err();
+        assert!(err.contains(&binary.display().to_string()), "{err}");
+        assert!(err.ends_with("expected aarch64, found x86_64"), "{err}");
+        assert!(check_file(&binary, Os::Linux, Architecture::X8664).is_ok());
+
+        // Missing files are left for the launch to report
+        assert!(check_file(&dir.path().join("missing"), Os::Linux, Architecture::X8664).is_ok());
+    }
+
+    #[test]
+    fn rejects_binaries_for_another_os() {
+        assert!(check(&elf(183), Os::Mac, Architecture::Aarch64).is_err());
+        assert!(check(&macho(0x0100_0007), Os::Linux, Architecture::X8664).is_err());
+    }
+
+    #[test]
+    fn accepts_x86_64_on_apple_silicon_and_universal_binaries() {
+        assert!(check(&macho(0x0100_0007), Os::Mac, Architecture::Aarch64).is_ok());
+        assert!(check(&macho(0x0100_000c), Os::Mac, Architecture::X8664).is_err());
+        assert!(check(&universal(&[0x0100_0007, 0x0100_000c]), Os::Mac, Architecture::X8664).is_ok());
+    }
+}
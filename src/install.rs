@@ -0,0 +1,423 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, SystemTime},
+};
+
+/// Held while one server downloads into the cache; others wait for it
+const LOCK_FILE: &str = ".download.lock";
+/// A lock older than this was left by a crashed download
+const STALE_LOCK: Duration = Duration::from_secs(10 * 60);
+const LOCK_POLL: Duration = Duration::from_millis(200);
+/// Suffix of a version directory that is still being downloaded
+const PARTIAL_SUFFIX: &str = ".partial";
+/// Names the validated version in use and, on the next line, the one it
+/// replaced, which is kept for rolling back
+const CURRENT_FILE: &str = ".current-version";
+/// Created next to a binary on probation once it has started as the server
+const STARTED_FILE: &str = ".started";
+
+/// Removes the lock file when dropped
+struct DownloadLock(PathBuf);
+
+impl Drop for DownloadLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Wait for the cache lock, breaking it only once it is stale. Fails after
+/// `timeout`, leaving the lock to the server that holds it.
+fn acquire_lock(cache_root: &Path, timeout: Duration) -> Result<DownloadLock, String> {
+    let lock_path = cache_root.join(LOCK_FILE);
+    let started = SystemTime::now();
+    loop {
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+        {
+            Ok(_) => return Ok(DownloadLock(lock_path)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let age = fs::metadata(&lock_path)
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .and_then(|modified| modified.elapsed().ok());
+                if age.is_some_and(|age| age > STALE_LOCK) {
+                    eprintln!("Removing stale download lock {}", lock_path.display());
+                    let _ = fs::remove_file(&lock_path);
+                    continue;
+                }
+                if started.elapsed().unwrap_or_default() > timeout {
+                    return Err(format!(
+                        "Timed out waiting for another download into the cache ({} is held)",
+                        lock_path.display()
+                    ));
+                }
+                thread::sleep(LOCK_POLL);
+            }
+            Err(e) => return Err(format!("Failed to create {}: {e}", lock_path.display())),
+        }
+    }
+}
+
+/// Path of `binary_name` for `version` in the shared cache, downloading it with
+/// `download` if no server has yet.
+///
+/// `download` fills the directory it is given; the result only becomes visible
+/// once it contains the binary. Concurrent callers serialize on a lock file so
+/// the first downloads and the rest reuse its result; a caller that waits
+/// longer than `lock_timeout` uses the binary already there, or fails.
+pub fn install_version(
+    cache_root: &Path,
+    version: &str,
+    binary_name: &str,
+    lock_timeout: Duration,
+    download: impl FnOnce(&Path) -> Result<(), String>,
+) -> Result<PathBuf, String> {
+    let version_dir = cache_root.join(version);
+    let binary_path = version_dir.join(binary_name);
+
+    fs::create_dir_all(cache_root)
+        .map_err(|e| format!("Failed to create cache directory: {e}"))?;
+    let _lock = match acquire_lock(cache_root, lock_timeout) {
+        Ok(lock) => lock,
+        Err(_) if binary_path.is_file() => return Ok(binary_path),
+        Err(e) => return Err(e),
+    };
+    if binary_path.is_file() {
+        return Ok(binary_path);
+    }
+
+    let partial_dir = cache_root.join(format!("{version}{PARTIAL_SUFFIX}"));
+    let _ = fs::remove_dir_all(&partial_dir);
+    fs::create_dir_all(&partial_dir)
+        .map_err(|e| format!("Failed to create cache directory: {e}"))?;
+
+    let result = download(&partial_dir).and_then(|()| {
+        if !partial_dir.join(binary_name).is_file() {
+            return Err(format!("Download did not contain expected binary '{binary_name}'"));
+        }
+        let _ = fs::remove_dir_all(&version_dir);
+        fs::rename(&partial_dir, &version_dir)
+            .map_err(|e| format!("Failed to move {} into place: {e}", partial_dir.display()))
+    });
+    if result.is_err() {
+        let _ = fs::remove_dir_all(&partial_dir);
+    }
+    result.map(|()| binary_path)
+}
+
+/// The version recorded as in use and the one kept to roll back to
+#[derive(Debug, Default, PartialEq)]
+pub struct CurrentVersions {
+    pub current: Option<String>,
+    pub previous: Option<String>,
+}
+
+/// What the cache's marker file records; nothing when it has none
+pub fn current_versions(cache_root: &Path) -> CurrentVersions {
+    let text = fs::read_to_string(cache_root.join(CURRENT_FILE)).unwrap_or_default();
+    let mut lines = text.lines().map(str::trim);
+    let mut next = || lines.next().filter(|line| !line.is_empty()).map(str::to_string);
+    CurrentVersions {
+        current: next(),
+        previous: next(),
+    }
+}
+
+/// Record `version`, which has been validated, as the one in use. The version
+/// it replaces (`running`, or else the one recorded before) becomes the
+/// rollback copy. The marker is replaced in one rename, so a reader never
+/// sees half of it.
+pub fn record_current(cache_root: &Path, version: &str, running: Option<&str>) -> Result<(), String> {
+    let recorded = current_versions(cache_root);
+    if recorded.current.as_deref() == Some(version) {
+        return Ok(());
+    }
+    let previous = running
+        .filter(|running| *running != version)
+        .or(recorded.current.as_deref())
+        .unwrap_or_default();
+
+    let marker = cache_root.join(CURRENT_FILE);
+    let temp = cache_root.join(format!("{CURRENT_FILE}.tmp"));
+    fs::write(&temp, format!("{version}\n{previous}\n"))
+        .and_then(|()| fs::rename(&temp, &marker))
+        .map_err(|e| format!("Failed to record {version} in {}: {e}", marker.display()))
+}
+
+/// Binary of the version kept for rolling back, for when the current one
+/// keeps failing to launch
+pub fn rollback_binary(cache_root: &Path, binary_name: &str) -> Result<PathBuf, String> {
+    let previous = current_versions(cache_root)
+        .previous
+        .ok_or_else(|| "No earlier mermaid-lsp version is kept to roll back to".to_string())?;
+    let binary = cache_root.join(&previous).join(binary_name);
+    if !binary.is_file() {
+        return Err(format!("Cannot roll back to {previous}: {} is missing", binary.display()));
+    }
+    Ok(binary)
+}
+
+/// What running a downloaded binary once showed
+#[derive(Debug, PartialEq)]
+pub enum SmokeTest {
+    /// It ran and answered as mermaid-lsp
+    Passed,
+    /// This host can't run processes (Zed's WASI sandbox), so the binary has
+    /// to show it works by starting as the server
+    Unsupported,
+}
+
+/// Run `binary --version` and check that it answers as mermaid-lsp
+pub fn smoke_test(binary: &Path) -> Result<SmokeTest, String> {
+    let output = match process::Command::new(binary).arg("--version").output() {
+        Ok(output) => output,
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(SmokeTest::Unsupported),
+        Err(e) => return Err(format!("Failed to run {}: {e}", binary.display())),
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() || !stdout.starts_with("mermaid-lsp ") {
+        return Err(format!(
+            "`{} --version` failed ({}): {}",
+            binary.display(),
+            output.status,
+            stdout.trim()
+        ));
+    }
+    Ok(SmokeTest::Passed)
+}
+
+/// File the server of `version` creates once it has started, for a version
+/// on probation
+pub fn started_file(cache_root: &Path, version: &str) -> PathBuf {
+    cache_root.join(version).join(STARTED_FILE)
+}
+
+/// Remove cached versions other than the recorded current one and its
+/// rollback copy. Nothing is removed before a version has been recorded, and
+/// a version another running server still holds open (a sharing violation on
+/// Windows) is left for a later purge.
+pub fn purge_old_versions(cache_root: &Path) {
+    let recorded = current_versions(cache_root);
+    if recorded.current.is_none() {
+        return;
+    }
+    let Ok(entries) = fs::read_dir(cache_root) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        let kept = [&recorded.current, &recorded.previous].into_iter().any(|keep| keep.as_deref() == Some(name));
+        if !path.is_dir() || kept || name.ends_with(PARTIAL_SUFFIX) {
+            continue;
+        }
+        if let Err(e) = fs::remove_dir_all(&path) {
+            eprintln!("Deferring removal of {} (still in use?): {e}", path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    const BINARY: &str = "mermaid-lsp";
+
+    /// Stand-in for a release download: slow enough for callers to overlap
+    fn mock_download(downloads: &AtomicUsize) -> impl FnOnce(&Path) -> Result<(), String> + '_ {
+        move |dir| {
+            downloads.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(300));
+            fs::write(dir.join(BINARY), b"binary").map_err(|e| e.to_string())
+        }
+    }
+
+    #[test]
+    fn concurrent_installs_download_once() {
+        let cache = tempfile::tempdir().unwrap();
+        let downloads = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let root = cache.path().to_path_buf();
+                let downloads = Arc::clone(&downloads);
+                thread::spawn(move || {
+                    install_version(&root, "v1.0.0", BINARY, Duration::from_secs(30), mock_download(&downloads))
+                })
+            })
+            .collect();
+        let paths: Vec<PathBuf> = handles.into_iter().map(|h| h.join().unwrap().unwrap()).collect();
+
+        assert_eq!(downloads.load(Ordering::SeqCst), 1);
+        assert_eq!(paths[0], paths[1]);
+        assert!(paths[0].is_file());
+        assert!(!cache.path().join(LOCK_FILE).exists());
+    }
+
+    #[test]
+    fn failed_download_leaves_nothing_behind() {
+        let cache = tempfile::tempdir().unwrap();
+        let result = install_version(cache.path(), "v1.0.0", BINARY, Duration::from_secs(1), |_| Ok(()));
+        assert!(result.is_err());
+        assert!(!cache.path().join("v1.0.0").exists());
+        assert!(!cache.path().join("v1.0.0.partial").exists());
+        assert!(!cache.path().join(LOCK_FILE).exists());
+    }
+
+    #[test]
+    fn only_stale_locks_are_broken() {
+        let cache = tempfile::tempdir().unwrap();
+        let lock = cache.path().join(LOCK_FILE);
+        fs::write(&lock, b"").unwrap();
+        let downloads = AtomicUsize::new(0);
+        let install = || {
+            install_version(cache.path(), "v1.0.0", BINARY, Duration::from_millis(100), mock_download(&downloads))
+        };
+
+        // A fresh lock is another server's download in progress
+        let err = install().unwrap_err();
+        assert!(err.contains("Timed out"), "{err}");
+        assert!(lock.exists());
+        assert_eq!(downloads.load(Ordering::SeqCst), 0);
+
+        let abandoned = SystemTime::now() - STALE_LOCK - Duration::from_secs(1);
+        fs::File::options().write(true).open(&lock).unwrap().set_modified(abandoned).unwrap();
+        assert!(install().unwrap().is_file());
+        assert_eq!(downloads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn waiters_never_break_a_held_lock() {
+        let cache = tempfile::tempdir().unwrap();
+        let downloads = Arc::new(AtomicUsize::new(0));
+        let active = Arc::new(AtomicUsize::new(0));
+        let overlapped = Arc::new(AtomicUsize::new(0));
+        // Each download holds the lock for longer than the others wait
+        let slow_download = |downloads: &Arc<AtomicUsize>| {
+            let (downloads, active, overlapped) = (Arc::clone(downloads), Arc::clone(&active), Arc::clone(&overlapped));
+            move |dir: &Path| {
+                if active.fetch_add(1, Ordering::SeqCst) > 0 {
+                    overlapped.fetch_add(1, Ordering::SeqCst);
+                }
+                downloads.fetch_add(1, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(800));
+                active.fetch_sub(1, Ordering::SeqCst);
+                fs::write(dir.join(BINARY), b"binary").map_err(|e| e.to_string())
+            }
+        };
+
+        let handles: Vec<_> = (0..3)
+            .map(|_| {
+                let root = cache.path().to_path_buf();
+                let download = slow_download(&downloads);
+                thread::spawn(move || install_version(&root, "v1.0.0", BINARY, Duration::from_millis(300), download))
+            })
+            .collect();
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert_eq!(overlapped.load(Ordering::SeqCst), 0);
+        assert_eq!(downloads.load(Ordering::SeqCst), 1);
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1, "{results:?}");
+        assert!(results.iter().flatten().all(|path| path.is_file()));
+    }
+
+    #[test]
+    fn purge_keeps_current_and_rollback_versions_and_other_files() {
+        let cache = tempfile::tempdir().unwrap();
+        for dir in ["v1.0.0", "v1.1.0", "v1.2.0", "v1.3.0.partial"] {
+            fs::create_dir_all(cache.path().join(dir)).unwrap();
+        }
+        fs::write(cache.path().join(".last-update-check"), b"0").unwrap();
+
+        // Nothing is known to work yet
+        purge_old_versions(cache.path());
+        assert!(cache.path().join("v1.0.0").exists());
+
+        record_current(cache.path(), "v1.1.0", None).unwrap();
+        record_current(cache.path(), "v1.2.0", None).unwrap();
+        purge_old_versions(cache.path());
+
+        assert!(!cache.path().join("v1.0.0").exists());
+        assert!(cache.path().join("v1.1.0").exists());
+        assert!(cache.path().join("v1.2.0").exists());
+        assert!(cache.path().join("v1.3.0.partial").exists());
+        assert!(cache.path().join(".last-update-check").exists());
+    }
+
+    #[test]
+    fn recording_a_version_keeps_the_one_it_replaces() {
+        let cache = tempfile::tempdir().unwrap();
+        assert_eq!(current_versions(cache.path()), CurrentVersions::default());
+
+        record_current(cache.path(), "v1.0.0", None).unwrap();
+        assert_eq!(current_versions(cache.path()).current.as_deref(), Some("v1.0.0"));
+        assert_eq!(current_versions(cache.path()).previous, None);
+
+        // Recording the current version again keeps its rollback copy
+        record_current(cache.path(), "v1.1.0", None).unwrap();
+        record_current(cache.path(), "v1.1.0", Some("v0.9.0")).unwrap();
+        assert_eq!(
+            current_versions(cache.path()),
+            CurrentVersions {
+                current: Some("v1.1.0".to_string()),
+                previous: Some("v1.0.0".to_string()),
+            }
+        );
+
+        // The binary that was running is what a rollback goes back to
+        record_current(cache.path(), "v1.2.0", Some("v0.9.0")).unwrap();
+        assert_eq!(current_versions(cache.path()).previous.as_deref(), Some("v0.9.0"));
+        assert!(!cache.path().join(format!("{CURRENT_FILE}.tmp")).exists());
+    }
+
+    #[test]
+    fn rolls_back_to_the_previous_version() {
+        let cache = tempfile::tempdir().unwrap();
+        assert!(rollback_binary(cache.path(), BINARY).is_err());
+
+        record_current(cache.path(), "v1.0.0", None).unwrap();
+        record_current(cache.path(), "v1.1.0", None).unwrap();
+        let err = rollback_binary(cache.path(), BINARY).unwrap_err();
+        assert!(err.contains("v1.0.0"), "{err}");
+
+        fs::create_dir_all(cache.path().join("v1.0.0")).unwrap();
+        fs::write(cache.path().join("v1.0.0").join(BINARY), b"").unwrap();
+        assert_eq!(
+            rollback_binary(cache.path(), BINARY).unwrap(),
+            cache.path().join("v1.0.0").join(BINARY)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn smoke_test_runs_the_binary() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let script = |name: &str, body: &str| {
+            let path = dir.path().join(name);
+            fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            path
+        };
+
+        let good = script("good", "echo mermaid-lsp 1.1.0");
+        assert_eq!(smoke_test(&good), Ok(SmokeTest::Passed));
+        let crashing = script("crashing", "echo mermaid-lsp 1.1.0; exit 3");
+        assert!(smoke_test(&crashing).is_err());
+        let impostor = script("impostor", "echo hello");
+        let err = smoke_test(&impostor).unwrap_err();
+        assert!(err.contains("hello"), "{err}");
+        assert!(smoke_test(&dir.path().join("missing")).is_err());
+    }
+}
@@ -1,18 +1,63 @@
-use std::{env, fs, path::PathBuf};
+mod asset;
+mod binary_header;
+mod install;
+mod paths;
+mod release_cache;
+
+use std::{
+    env, fs,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use zed_extension_api::{
-    self as zed, Architecture, DownloadedFileType, LanguageServerId, Os, Result,
+    self as zed, settings::LspSettings, DownloadedFileType, LanguageServerId, Result,
 };
 
 const GITHUB_REPOSITORY: &str = "dawsh2/zed-mermaid-preview";
 const CACHE_ROOT: &str = "mermaid-lsp-cache";
+const NO_DOWNLOAD_ENV: &str = "MERMAID_LSP_NO_DOWNLOAD";
+/// Starts the version kept for rolling back instead of the current one
+const ROLLBACK_ENV: &str = "MERMAID_LSP_ROLLBACK";
+/// Where the server saves trust decisions; must match the server's `TRUST_DIR_ENV`
+const TRUST_DIR_ENV: &str = "MERMAID_LSP_TRUST_DIR";
+/// File a server on probation creates once initialized; must match the
+/// server's `STARTED_FILE_ENV`
+const STARTED_FILE_ENV: &str = "MERMAID_LSP_STARTED_FILE";
+/// File in the cache recording when releases were last checked (unix seconds)
+const UPDATE_CHECK_STAMP: &str = ".last-update-check";
+const UPDATE_CHECK_INTERVAL_SECS: u64 = 24 * 60 * 60;
+/// How long a server waits for another one's download before giving up
+const DOWNLOAD_LOCK_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// How long an update check waits for another server's download; the
+/// running binary is fine meanwhile, so it gives up early
+const UPDATE_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
 
 struct MermaidPreviewExtension {
     lsp_path: Option<String>,
+    /// Newer binary installed by the update check, started once it passes the smoke test
+    pending_lsp_path: Option<String>,
+    /// Version running when an update check came due; checked on the next
+    /// start so the one that found it due isn't held up
+    update_due_for: Option<String>,
+    /// Update started without a smoke test, not yet recorded as current
+    probation: Option<Probation>,
+}
+
+/// An update that couldn't be smoke-tested here, kept until it has started
+struct Probation {
+    version: String,
+    /// Binary it replaced, started again if it doesn't get that far
+    previous: Option<String>,
 }
 
 impl zed::Extension for MermaidPreviewExtension {
     fn new() -> Self {
-        Self { lsp_path: None }
+        Self {
+            lsp_path: None,
+            pending_lsp_path: None,
+            update_due_for: None,
+            probation: None,
+        }
     }
 
     fn language_server_command(
@@ -23,10 +68,25 @@ impl zed::Extension for MermaidPreviewExtension {
         let lsp_path = self.get_lsp_path(worktree, language_server_id)?;
         eprintln!("Starting Mermaid LSP at: {lsp_path}");
 
+        // The server remembers which project-local mmdc binaries the user trusts here
+        let mut env = Vec::new();
+        if let Ok(extension_dir) = env::current_dir() {
+            let trust_dir = paths::simplify(&extension_dir).join(CACHE_ROOT);
+            if let Ok(trust_dir) = path_str(&trust_dir) {
+                env.push((TRUST_DIR_ENV.to_string(), trust_dir.to_string()));
+            }
+            if let Some(probation) = &self.probation {
+                let started = install::started_file(&Self::cache_root(&paths::simplify(&extension_dir)), &probation.version);
+                if let Ok(started) = path_str(&started) {
+                    env.push((STARTED_FILE_ENV.to_string(), started.to_string()));
+                }
+            }
+        }
+
         Ok(zed::Command {
             command: lsp_path,
             args: vec![],
-            env: Default::default(),
+            env,
         })
     }
 }
@@ -37,13 +97,27 @@ impl MermaidPreviewExtension {
         worktree: &zed::Worktree,
         language_server_id: &LanguageServerId,
     ) -> Result<String> {
+        let extension_dir = env::current_dir()
+            .map(|dir| paths::simplify(&dir))
+            .map_err(|e| format!("Failed to get current directory: {e}"))?;
+
+        self.settle_probation(&extension_dir);
+        if let Some(current) = self.update_due_for.take() {
+            self.check_for_update(language_server_id, &extension_dir, Self::binary_name(), &current);
+        }
+        if let Some(path) = self.pending_lsp_path.take() {
+            self.start_update(&extension_dir, path);
+        }
+
         if let Some(ref path) = self.lsp_path {
+            // Clears what the update check showed, however it ended
+            zed::set_language_server_installation_status(
+                language_server_id,
+                &zed::LanguageServerInstallationStatus::None,
+            );
             return Ok(path.clone());
         }
 
-        let extension_dir = env::current_dir()
-            .map_err(|e| format!("Failed to get current directory: {e}"))?;
-
         self.resolve_lsp_path(language_server_id, worktree, &extension_dir)
     }
 
@@ -70,37 +144,143 @@ impl MermaidPreviewExtension {
             );
         }
 
-        // 3. Check local candidate paths (bundled binaries)
+        // 3. Check local candidate paths (bundled binaries), skipping ones built
+        //    for another platform. A requested rollback starts the version the
+        //    current one replaced.
         let binary_name = Self::binary_name();
+        if Self::flag_setting(language_server_id, worktree, ROLLBACK_ENV, "rollback") {
+            let rollback = install::rollback_binary(&Self::cache_root(extension_dir), binary_name)
+                .and_then(|path| Self::check_platform(&path).map(|()| path));
+            match rollback {
+                Ok(path) => {
+                    eprintln!("Rolling back to Mermaid LSP at {}", path.display());
+                    return Self::finalize_path(language_server_id, path, &mut self.lsp_path);
+                }
+                Err(e) => eprintln!("{e}"),
+            }
+        }
         if let Some(path) = Self::candidate_paths(extension_dir, binary_name)
             .into_iter()
-            .find(|p| p.is_file())
+            .filter(|p| p.is_file())
+            .find(|p| match Self::check_platform(p) {
+                Ok(()) => true,
+                Err(e) => {
+                    eprintln!("{e}");
+                    false
+                }
+            })
         {
+            // A previously downloaded binary: look for a newer release at most
+            // daily, starting this one without waiting for that
+            if let Some(version) = Self::cached_version(extension_dir, &path) {
+                if let Err(e) = install::record_current(&Self::cache_root(extension_dir), &version, None) {
+                    eprintln!("{e}");
+                }
+                if !Self::downloads_disabled(language_server_id, worktree)
+                    && Self::update_check_due(extension_dir)
+                {
+                    self.update_due_for = Some(version);
+                }
+            }
             return Self::finalize_path(language_server_id, path, &mut self.lsp_path);
         }
 
-        // 4. Download from GitHub Releases
-        match self.download_lsp(language_server_id, extension_dir, binary_name) {
+        // 4. Download from GitHub Releases, unless network access is forbidden
+        let disabled = Self::downloads_disabled(language_server_id, worktree);
+        let downloaded = Self::download_unless_disabled(disabled, extension_dir, binary_name, || {
+            self.download_lsp(language_server_id, extension_dir, binary_name)
+        });
+        match downloaded {
             Ok(path) if path.is_file() => {
                 Self::finalize_path(language_server_id, path, &mut self.lsp_path)
             }
-            Err(e) => Err(format!("Failed to download LSP binary: {e}")),
+            Err(DownloadError::Disabled(message)) => {
+                zed::set_language_server_installation_status(
+                    language_server_id,
+                    &zed::LanguageServerInstallationStatus::Failed(message.clone()),
+                );
+                Err(message)
+            }
+            Err(DownloadError::Failed(e)) => Err(format!("Failed to download LSP binary: {e}")),
             _ => Err(format!(
                 "LSP binary '{binary_name}' not found. Set MERMAID_LSP_PATH or publish a GitHub release."
             )),
         }
     }
 
+    /// Run `download`, or, when downloads are disabled, fail without touching
+    /// the network and name every place the binary was looked for
+    fn download_unless_disabled(
+        disabled: bool,
+        extension_dir: &std::path::Path,
+        binary_name: &str,
+        download: impl FnOnce() -> Result<PathBuf>,
+    ) -> std::result::Result<PathBuf, DownloadError> {
+        if disabled {
+            return Err(DownloadError::Disabled(Self::no_download_message(extension_dir, binary_name)));
+        }
+        download().map_err(DownloadError::Failed)
+    }
+
+    /// `MERMAID_LSP_NO_DOWNLOAD=1` or `"settings": { "noDownload": true }` under
+    /// `lsp.mermaid` turns off every GitHub request, including update checks
+    fn downloads_disabled(language_server_id: &LanguageServerId, worktree: &zed::Worktree) -> bool {
+        Self::flag_setting(language_server_id, worktree, NO_DOWNLOAD_ENV, "noDownload")
+    }
+
+    /// Whether environment flag `env_name` or boolean setting `key` under
+    /// `lsp.mermaid.settings` is on
+    fn flag_setting(
+        language_server_id: &LanguageServerId,
+        worktree: &zed::Worktree,
+        env_name: &str,
+        key: &str,
+    ) -> bool {
+        let env_flag = env::var(env_name).is_ok_and(|v| flag_enabled(&v));
+        let setting = LspSettings::for_worktree(language_server_id.as_ref(), worktree)
+            .ok()
+            .and_then(|s| s.settings)
+            .and_then(|s| s.get(key).and_then(|v| v.as_bool()))
+            .unwrap_or(false);
+        env_flag || setting
+    }
+
+    fn no_download_message(extension_dir: &std::path::Path, binary_name: &str) -> String {
+        let mut checked = Vec::new();
+        match env::var("MERMAID_LSP_PATH") {
+            Ok(path) => checked.push(format!("MERMAID_LSP_PATH ({path})")),
+            Err(_) => checked.push("MERMAID_LSP_PATH (not set)".to_string()),
+        }
+        checked.push(format!("'{binary_name}' on the worktree PATH"));
+        checked.extend(
+            Self::candidate_paths(extension_dir, binary_name)
+                .iter()
+                .map(|p| p.display().to_string()),
+        );
+        format!(
+            "mermaid-lsp binary not found and downloads are disabled; place it at one of: {}. \
+             Install it manually (e.g. `cargo install --path lsp`) and put it on PATH, \
+             or set MERMAID_LSP_PATH to the binary.",
+            checked.join(", ")
+        )
+    }
+
+    /// Resolve `path` for launching and remember it. A binary whose header
+    /// names another OS or CPU fails here with both named, instead of with the
+    /// OS's exec error when Zed starts it.
     fn finalize_path(
         language_server_id: &LanguageServerId,
         path: PathBuf,
         cache: &mut Option<String>,
     ) -> Result<String> {
-        let resolved = path
-            .canonicalize()
-            .unwrap_or(path)
-            .to_string_lossy()
-            .to_string();
+        if let Err(e) = Self::check_platform(&path) {
+            zed::set_language_server_installation_status(
+                language_server_id,
+                &zed::LanguageServerInstallationStatus::Failed(e.clone()),
+            );
+            return Err(e);
+        }
+        let resolved = path_str(&paths::resolve(&path))?.to_string();
         *cache = Some(resolved.clone());
 
         zed::set_language_server_installation_status(
@@ -121,11 +301,17 @@ impl MermaidPreviewExtension {
         ];
 
         // Check cached versions
-        let cache_root = extension_dir.join(CACHE_ROOT);
-        if let Ok(entries) = fs::read_dir(cache_root) {
-            for entry in entries.flatten() {
-                candidates.push(entry.path().join(binary_name));
-            }
+        if let Ok(entries) = fs::read_dir(Self::cache_root(extension_dir)) {
+            let mut versions: Vec<PathBuf> = entries
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.is_dir() && path.extension().is_none_or(|e| e != "partial"))
+                .collect();
+            // Newest first, so an installed update wins over the version it replaces
+            versions.sort_by_key(|path| {
+                std::cmp::Reverse(version_key(&path.file_name().unwrap_or_default().to_string_lossy()))
+            });
+            candidates.extend(versions.into_iter().map(|dir| dir.join(binary_name)));
         }
 
         candidates
@@ -142,111 +328,250 @@ impl MermaidPreviewExtension {
             &zed::LanguageServerInstallationStatus::CheckingForUpdate,
         );
 
-        let release = zed::latest_github_release(
-            GITHUB_REPOSITORY,
-            zed::GithubReleaseOptions {
-                require_assets: true,
-                pre_release: false,
-            },
+        let release = Self::latest_release(extension_dir)?;
+
+        let binary_path = Self::install_release(
+            language_server_id,
+            extension_dir,
+            binary_name,
+            &release,
+            DOWNLOAD_LOCK_TIMEOUT,
         )?;
+        Self::record_update_check(extension_dir);
 
-        let asset = Self::match_asset(&release)?;
-        let version_dir = extension_dir.join(CACHE_ROOT).join(&release.version);
-        let binary_path = version_dir.join(binary_name);
+        Self::record_and_purge(extension_dir, &release.version, None);
+        Ok(binary_path)
+    }
 
-        // Already have this version
-        if binary_path.is_file() {
-            zed::set_language_server_installation_status(
-                language_server_id,
-                &zed::LanguageServerInstallationStatus::None,
-            );
-            return Ok(binary_path);
+    /// Download `release` into its own version directory under the cache and check
+    /// the binary is usable here, waiting up to `lock_timeout` for another
+    /// server's download. Other versions are left in place.
+    fn install_release(
+        language_server_id: &LanguageServerId,
+        extension_dir: &std::path::Path,
+        binary_name: &str,
+        release: &zed::GithubRelease,
+        lock_timeout: Duration,
+    ) -> Result<PathBuf> {
+        let asset = Self::match_asset(release)?;
+        let cache_root = Self::cache_root(extension_dir);
+
+        // A copy for another platform is replaced
+        let existing = cache_root.join(&release.version).join(binary_name);
+        if existing.is_file() {
+            if let Err(e) = Self::check_platform(&existing) {
+                eprintln!("{e}");
+                let _ = fs::remove_dir_all(cache_root.join(&release.version));
+            }
         }
 
-        // Create cache directory
-        fs::create_dir_all(&version_dir)
-            .map_err(|e| format!("Failed to create cache directory: {e}"))?;
+        let binary_path = install::install_version(
+            &cache_root,
+            &release.version,
+            binary_name,
+            lock_timeout,
+            |dir| {
+                zed::set_language_server_installation_status(
+                    language_server_id,
+                    &zed::LanguageServerInstallationStatus::Downloading,
+                );
+                zed::download_file(&asset.download_url, path_str(dir)?, DownloadedFileType::Zip)
+                .map_err(|e| format!("Failed to download mermaid-lsp: {e}"))?;
+
+                let binary = dir.join(binary_name);
+                if binary.is_file() {
+                    Self::check_platform(&binary)?;
+                    zed::make_file_executable(path_str(&binary)?)?;
+                }
+                Ok(())
+            },
+        )
+        .map_err(|e| format!("{e} (asset '{}')", asset.name))?;
+
+        eprintln!("Mermaid LSP v{} installed", release.version);
+        Ok(binary_path)
+    }
 
-        // Download
+    /// Install a release newer than `current` for [`Self::start_update`]. The
+    /// extension API has no background tasks, so this runs inline on the start
+    /// after the one that found the check due, and gives up on another
+    /// server's download after `UPDATE_LOCK_TIMEOUT`. The previous binary
+    /// stays cached for a rollback.
+    fn check_for_update(
+        &mut self,
+        language_server_id: &LanguageServerId,
+        extension_dir: &std::path::Path,
+        binary_name: &str,
+        current: &str,
+    ) {
         zed::set_language_server_installation_status(
             language_server_id,
-            &zed::LanguageServerInstallationStatus::Downloading,
+            &zed::LanguageServerInstallationStatus::CheckingForUpdate,
         );
 
-        zed::download_file(
-            &asset.download_url,
-            version_dir
-                .to_str()
-                .ok_or_else(|| "Failed to stringify cache path".to_string())?,
-            DownloadedFileType::Zip,
-        )
-        .map_err(|e| format!("Failed to download mermaid-lsp: {e}"))?;
+        // Stamped only once GitHub (or the release cache) answered, so a
+        // session that ends before this start still checks next time
+        let release = Self::latest_release(extension_dir);
+        Self::record_update_check(extension_dir);
+        let release = match release {
+            Ok(release) => release,
+            Err(e) => {
+                eprintln!("Mermaid LSP update check failed: {e}");
+                return;
+            }
+        };
+        if !is_newer_version(&release.version, current) {
+            return;
+        }
 
-        if !binary_path.is_file() {
-            return Err(format!(
-                "Downloaded asset '{}' did not contain expected binary '{binary_name}'",
-                asset.name
-            ));
+        let installed =
+            Self::install_release(language_server_id, extension_dir, binary_name, &release, UPDATE_LOCK_TIMEOUT);
+        match installed {
+            Ok(path) => {
+                let path = paths::resolve(&path);
+                match path_str(&path) {
+                    Ok(path) => self.pending_lsp_path = Some(path.to_string()),
+                    Err(e) => eprintln!("{e}"),
+                }
+            }
+            Err(e) => eprintln!("Mermaid LSP update to {} failed: {e}", release.version),
         }
+    }
 
-        zed::make_file_executable(
-            binary_path
-                .to_str()
-                .ok_or_else(|| "Failed to stringify binary path".to_string())?,
-        )?;
+    /// Switch to the update at `path` once it passes the smoke test, recording
+    /// it as current and keeping the binary it replaces for a rollback. Where
+    /// the test can't run, the update starts on probation instead.
+    fn start_update(&mut self, extension_dir: &std::path::Path, path: String) {
+        let Some(version) = Self::cached_version(extension_dir, std::path::Path::new(&path)) else {
+            return;
+        };
+        let running = self
+            .lsp_path
+            .as_deref()
+            .and_then(|p| Self::cached_version(extension_dir, std::path::Path::new(p)));
+        match install::smoke_test(std::path::Path::new(&path)) {
+            Ok(install::SmokeTest::Passed) => {
+                Self::record_and_purge(extension_dir, &version, running.as_deref());
+                self.lsp_path = Some(path);
+            }
+            Ok(install::SmokeTest::Unsupported) => {
+                let _ = fs::remove_file(install::started_file(&Self::cache_root(extension_dir), &version));
+                eprintln!("Mermaid LSP v{version} can't be smoke-tested here; starting it on probation");
+                self.probation = Some(Probation {
+                    version,
+                    previous: self.lsp_path.replace(path),
+                });
+            }
+            Err(e) => Self::reject_update(extension_dir, &version, &e),
+        }
+    }
 
-        // Purge old versions
-        Self::purge_old_cache_versions(extension_dir, &release.version);
+    /// Record the update on probation as current if its server got through
+    /// initialize since the last start, or else go back to the binary it
+    /// replaced
+    fn settle_probation(&mut self, extension_dir: &std::path::Path) {
+        let Some(probation) = self.probation.take() else {
+            return;
+        };
+        if install::started_file(&Self::cache_root(extension_dir), &probation.version).is_file() {
+            let previous = probation
+                .previous
+                .as_deref()
+                .and_then(|p| Self::cached_version(extension_dir, std::path::Path::new(p)));
+            Self::record_and_purge(extension_dir, &probation.version, previous.as_deref());
+        } else {
+            Self::reject_update(extension_dir, &probation.version, "its server never finished starting");
+            self.lsp_path = probation.previous;
+        }
+    }
 
-        eprintln!("Mermaid LSP v{} installed", release.version);
-        Ok(binary_path)
+    /// Report an update that failed its smoke test and remove it, so no later
+    /// start picks it up
+    fn reject_update(extension_dir: &std::path::Path, version: &str, reason: &str) {
+        eprintln!("Mermaid LSP update to {version} failed its smoke test, keeping the running version: {reason}");
+        let _ = fs::remove_dir_all(Self::cache_root(extension_dir).join(version));
     }
 
-    fn match_asset(release: &zed::GithubRelease) -> Result<zed::GithubReleaseAsset> {
-        let (os, arch) = zed::current_platform();
+    /// The latest release, through the metadata cached for an hour so frequent
+    /// cold starts don't run into GitHub's rate limit
+    fn latest_release(extension_dir: &std::path::Path) -> Result<zed::GithubRelease> {
+        release_cache::latest_release(&Self::cache_root(extension_dir), unix_now().unwrap_or(0), || {
+            zed::latest_github_release(
+                GITHUB_REPOSITORY,
+                zed::GithubReleaseOptions {
+                    require_assets: true,
+                    pre_release: false,
+                },
+            )
+        })
+    }
 
-        let arch_str = match arch {
-            Architecture::Aarch64 => "aarch64",
-            Architecture::X86 => "x86",
-            Architecture::X8664 => "x86_64",
-        };
+    /// Version directory name if `path` is a binary in the download cache
+    fn cached_version(extension_dir: &std::path::Path, path: &std::path::Path) -> Option<String> {
+        let version_dir = path.parent()?;
+        let parent = version_dir.parent()?;
+        let cache_root = Self::cache_root(extension_dir);
+        let in_cache = paths::simplify(parent) == paths::simplify(&cache_root)
+            || matches!(
+                (parent.canonicalize(), cache_root.canonicalize()),
+                (Ok(a), Ok(b)) if paths::simplify(&a) == paths::simplify(&b)
+            );
+        if !in_cache {
+            return None;
+        }
+        version_dir.file_name()?.to_str().map(str::to_string)
+    }
 
-        let os_str = match os {
-            Os::Mac => "apple-darwin",
-            Os::Linux => "unknown-linux-gnu",
-            Os::Windows => "pc-windows-msvc",
-        };
+    fn update_check_due(extension_dir: &std::path::Path) -> bool {
+        let last_check = fs::read_to_string(Self::cache_root(extension_dir).join(UPDATE_CHECK_STAMP))
+            .ok()
+            .and_then(|s| s.trim().parse::<u64>().ok());
+        match (last_check, unix_now()) {
+            (Some(last), Some(now)) => now.saturating_sub(last) >= UPDATE_CHECK_INTERVAL_SECS,
+            _ => true,
+        }
+    }
 
-        let expected = format!("mermaid-lsp-{arch_str}-{os_str}.zip");
+    fn record_update_check(extension_dir: &std::path::Path) {
+        if let Some(now) = unix_now() {
+            let _ = fs::write(
+                Self::cache_root(extension_dir).join(UPDATE_CHECK_STAMP),
+                now.to_string(),
+            );
+        }
+    }
 
-        release
-            .assets
-            .iter()
-            .find(|a| a.name == expected)
-            .cloned()
-            .ok_or_else(|| {
-                let available: Vec<_> = release.assets.iter().map(|a| a.name.as_str()).collect();
-                format!("No asset '{expected}' found. Available: {available:?}")
-            })
+    /// Reject binaries whose header is for another OS or CPU
+    fn check_platform(path: &std::path::Path) -> Result<()> {
+        let (os, arch) = zed::current_platform();
+        binary_header::check_file(path, os, arch)
     }
 
-    fn purge_old_cache_versions(extension_dir: &std::path::Path, keep_version: &str) {
-        let cache_root = extension_dir.join(CACHE_ROOT);
-        if let Ok(entries) = fs::read_dir(&cache_root) {
-            for entry in entries.flatten() {
-                if entry
-                    .path()
-                    .file_name()
-                    .and_then(|n| n.to_str())
-                    .map(|v| v != keep_version)
-                    .unwrap_or(false)
-                {
-                    let _ = fs::remove_dir_all(entry.path());
-                }
-            }
+    /// This platform's asset, or the variant `MERMAID_LSP_ASSET_VARIANT` names
+    fn match_asset(release: &zed::GithubRelease) -> Result<zed::GithubReleaseAsset> {
+        let (os, arch) = zed::current_platform();
+        let (arch_str, os_str) = asset::platform_parts(os, arch);
+        let variant = env::var(asset::ASSET_VARIANT_ENV).ok();
+        asset::select_asset(release, arch_str, os_str, variant.as_deref())
+    }
+
+    /// Record the validated `version` as current, then purge versions other
+    /// than it and the one it replaced. Nothing is purged if it can't be
+    /// recorded, since the purge goes by the record.
+    fn record_and_purge(extension_dir: &std::path::Path, version: &str, running: Option<&str>) {
+        let cache_root = Self::cache_root(extension_dir);
+        match install::record_current(&cache_root, version, running) {
+            Ok(()) => install::purge_old_versions(&cache_root),
+            Err(e) => eprintln!("{e}"),
         }
     }
 
+    /// Download cache shared by every worktree. `current_dir` is the extension's
+    /// own work directory, not the worktree's.
+    fn cache_root(extension_dir: &std::path::Path) -> PathBuf {
+        extension_dir.join(CACHE_ROOT)
+    }
+
     fn binary_name() -> &'static str {
         if cfg!(target_os = "windows") {
             "mermaid-lsp.exe"
@@ -256,4 +581,163 @@ impl MermaidPreviewExtension {
     }
 }
 
+/// `path` as a string for the Zed APIs that take one. Paths with spaces pass
+/// through unchanged; ones that aren't valid UTF-8 (or wouldn't round-trip)
+/// are rejected by name instead of being mangled.
+fn path_str(path: &std::path::Path) -> Result<&str> {
+    match path.to_str() {
+        Some(s) if std::path::Path::new(s) == path => Ok(s),
+        _ => Err(format!(
+            "Path {} is not valid UTF-8; move the Zed extensions directory to a path \
+             without such characters or set MERMAID_LSP_PATH",
+            path.display()
+        )),
+    }
+}
+
+/// Why step 4 of `resolve_lsp_path` produced no binary
+#[derive(Debug)]
+enum DownloadError {
+    /// Downloads are turned off; the message lists the searched locations
+    Disabled(String),
+    Failed(String),
+}
+
+/// Whether an environment flag such as `MERMAID_LSP_NO_DOWNLOAD` is set
+fn flag_enabled(value: &str) -> bool {
+    matches!(value.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes")
+}
+
+fn unix_now() -> Option<u64> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|d| d.as_secs())
+}
+
+/// Numeric components of a release tag (`v1.2.10` -> `[1, 2, 10]`)
+fn version_key(version: &str) -> Vec<u64> {
+    version
+        .trim()
+        .trim_start_matches('v')
+        .split(['.', '-', '+'])
+        .map_while(|part| part.parse().ok())
+        .collect()
+}
+
+/// Whether release tag `latest` is newer than the cached `current` version
+fn is_newer_version(latest: &str, current: &str) -> bool {
+    let (latest_key, current_key) = (version_key(latest), version_key(current));
+    if latest_key.is_empty() || current_key.is_empty() {
+        return latest != current;
+    }
+    latest_key > current_key
+}
+
 zed_extension_api::register_extension!(MermaidPreviewExtension);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_release_versions() {
+        assert!(is_newer_version("v0.3.0", "v0.2.9"));
+        assert!(is_newer_version("0.10.0", "v0.9.1"));
+        assert!(!is_newer_version("v0.2.0", "v0.2.0"));
+        assert!(!is_newer_version("v0.1.9", "v0.2.0"));
+        assert!(is_newer_version("nightly-2", "nightly-1"));
+        assert!(!is_newer_version("nightly", "nightly"));
+    }
+
+    #[test]
+    fn finds_the_version_of_cached_binaries() {
+        let extension_dir = std::path::Path::new("/ext");
+        let cached = extension_dir.join(CACHE_ROOT).join("v0.2.0").join("mermaid-lsp");
+        assert_eq!(
+            MermaidPreviewExtension::cached_version(extension_dir, &cached).as_deref(),
+            Some("v0.2.0")
+        );
+        let bundled = extension_dir.join("target/release/mermaid-lsp");
+        assert_eq!(MermaidPreviewExtension::cached_version(extension_dir, &bundled), None);
+
+        // A long-path spelling of the same cache
+        let extension_dir = std::path::Path::new(r"C:\zed\ext");
+        let cached = std::path::Path::new(r"\\?\C:\zed\ext").join(CACHE_ROOT).join("v0.2.0").join("mermaid-lsp");
+        assert_eq!(
+            MermaidPreviewExtension::cached_version(extension_dir, &cached).as_deref(),
+            Some("v0.2.0")
+        );
+    }
+
+    #[test]
+    fn cache_paths_work_in_directories_with_spaces() {
+        let root = tempfile::tempdir().unwrap();
+        let extension_dir = root.path().join("Application Support/Zed/extensions/work/mermaid preview");
+        let cache = MermaidPreviewExtension::cache_root(&extension_dir);
+        for version in ["v0.1.0", "v0.2.0", "v0.3.0.partial"] {
+            fs::create_dir_all(cache.join(version)).unwrap();
+        }
+
+        let candidates = MermaidPreviewExtension::candidate_paths(&extension_dir, "mermaid-lsp");
+        let cached: Vec<&PathBuf> = candidates.iter().filter(|p| p.starts_with(&cache)).collect();
+        assert_eq!(
+            cached,
+            [cache.join("v0.2.0/mermaid-lsp"), cache.join("v0.1.0/mermaid-lsp")].iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            MermaidPreviewExtension::cached_version(&extension_dir, cached[0]).as_deref(),
+            Some("v0.2.0")
+        );
+
+        let dir = cache.join("v0.2.0");
+        assert_eq!(std::path::Path::new(path_str(&dir).unwrap()), dir);
+        assert!(path_str(&dir).unwrap().contains("mermaid preview"));
+    }
+
+    #[test]
+    fn disabled_downloads_fail_without_downloading() {
+        let extension_dir = tempfile::tempdir().unwrap();
+        let result = MermaidPreviewExtension::download_unless_disabled(
+            true,
+            extension_dir.path(),
+            "mermaid-lsp",
+            || panic!("download attempted while downloads are disabled"),
+        );
+        let Err(DownloadError::Disabled(message)) = result else {
+            panic!("expected a downloads-disabled error, got {result:?}");
+        };
+        assert!(message.contains("not found and downloads are disabled"), "{message}");
+        for candidate in MermaidPreviewExtension::candidate_paths(extension_dir.path(), "mermaid-lsp") {
+            assert!(message.contains(&candidate.display().to_string()), "{message}");
+        }
+
+        let downloaded = MermaidPreviewExtension::download_unless_disabled(
+            false,
+            extension_dir.path(),
+            "mermaid-lsp",
+            || Ok(PathBuf::from("/downloaded/mermaid-lsp")),
+        );
+        assert_eq!(downloaded.unwrap(), PathBuf::from("/downloaded/mermaid-lsp"));
+    }
+
+    #[test]
+    fn reads_the_no_download_flag() {
+        for value in ["1", "true", " YES "] {
+            assert!(flag_enabled(value), "{value}");
+        }
+        for value in ["", "0", "false", "no"] {
+            assert!(!flag_enabled(value), "{value}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_paths_are_rejected_by_name() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let path = std::path::Path::new("/ext").join(OsStr::from_bytes(b"caf\xe9"));
+        let err = path_str(&path).unwrap_err();
+        assert!(err.contains("/ext/caf"), "{err}");
+    }
+}
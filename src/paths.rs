@@ -0,0 +1,47 @@
+use std::path::{Path, PathBuf};
+
+/// `path` without the Windows verbatim prefix that `canonicalize` adds:
+/// `\\?\C:\zed` becomes `C:\zed` and `\\?\UNC\server\share` becomes
+/// `\\server\share`. Same rules as the server's `paths::strip_verbatim`.
+pub fn strip_verbatim(path: &str) -> String {
+    let Some(rest) = path.strip_prefix(r"\\?\").or_else(|| path.strip_prefix("//?/")) else {
+        return path.to_string();
+    };
+    if let Some(share) = rest.strip_prefix(r"UNC\").or_else(|| rest.strip_prefix("UNC/")) {
+        return format!(r"\\{share}");
+    }
+    let bytes = rest.as_bytes();
+    if bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' {
+        return rest.to_string();
+    }
+    path.to_string()
+}
+
+/// `path` in its plain form, without a verbatim prefix
+pub fn simplify(path: &Path) -> PathBuf {
+    match path.to_str() {
+        Some(text) => PathBuf::from(strip_verbatim(text)),
+        None => path.to_path_buf(),
+    }
+}
+
+/// `path` with symlinks resolved when it exists, in its plain form. Paths that
+/// don't exist (yet) are kept as they are instead of failing.
+pub fn resolve(path: &Path) -> PathBuf {
+    simplify(&path.canonicalize().unwrap_or_else(|_| path.to_path_buf()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_verbatim_prefixes() {
+        assert_eq!(strip_verbatim(r"\\?\C:\Users\me\zed\mermaid-lsp.exe"), r"C:\Users\me\zed\mermaid-lsp.exe");
+        assert_eq!(strip_verbatim(r"\\?\UNC\server\share\zed"), r"\\server\share\zed");
+        assert_eq!(strip_verbatim(r"\\?\Volume{1234}\zed"), r"\\?\Volume{1234}\zed");
+        assert_eq!(strip_verbatim(r"\\server\share\zed"), r"\\server\share\zed");
+        assert_eq!(strip_verbatim("/home/me/zed"), "/home/me/zed");
+        assert_eq!(simplify(Path::new(r"\\?\D:\ext")), PathBuf::from(r"D:\ext"));
+    }
+}
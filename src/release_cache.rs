@@ -0,0 +1,148 @@
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+use zed_extension_api::{GithubRelease, GithubReleaseAsset};
+
+/// Metadata of the latest release as last fetched, in the cache root
+const RELEASE_CACHE_FILE: &str = ".latest-release.json";
+/// How long the cached metadata is used without asking GitHub again
+pub const RELEASE_CACHE_TTL_SECS: u64 = 60 * 60;
+
+/// Contents of the cache file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CachedRelease {
+    /// When the metadata was fetched (unix seconds)
+    fetched_at: u64,
+    version: String,
+    assets: Vec<CachedAsset>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CachedAsset {
+    name: String,
+    download_url: String,
+}
+
+impl CachedRelease {
+    fn new(release: &GithubRelease, fetched_at: u64) -> Self {
+        Self {
+            fetched_at,
+            version: release.version.clone(),
+            assets: release
+                .assets
+                .iter()
+                .map(|asset| CachedAsset {
+                    name: asset.name.clone(),
+                    download_url: asset.download_url.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    fn to_release(&self) -> GithubRelease {
+        GithubRelease {
+            version: self.version.clone(),
+            assets: self
+                .assets
+                .iter()
+                .map(|asset| GithubReleaseAsset {
+                    name: asset.name.clone(),
+                    download_url: asset.download_url.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The latest release: from the cache while it is younger than the TTL, else
+/// from `fetch` (the GitHub API), falling back to the cache of any age when
+/// that fails. Removing the cache root makes the next call ask GitHub.
+pub fn latest_release(
+    cache_root: &Path,
+    now: u64,
+    fetch: impl FnOnce() -> Result<GithubRelease, String>,
+) -> Result<GithubRelease, String> {
+    let path = cache_root.join(RELEASE_CACHE_FILE);
+    let cached = fs::read_to_string(&path)
+        .ok()
+        .and_then(|json| serde_json::from_str::<CachedRelease>(&json).ok());
+    if let Some(cached) = &cached {
+        if now.saturating_sub(cached.fetched_at) < RELEASE_CACHE_TTL_SECS {
+            return Ok(cached.to_release());
+        }
+    }
+
+    match fetch() {
+        Ok(release) => {
+            let saved = fs::create_dir_all(cache_root)
+                .and_then(|()| fs::write(&path, serde_json::to_string_pretty(&CachedRelease::new(&release, now))?));
+            if let Err(e) = saved {
+                eprintln!("Failed to cache release metadata in {}: {e}", path.display());
+            }
+            Ok(release)
+        }
+        Err(e) => match cached {
+            Some(cached) => {
+                eprintln!("GitHub release lookup failed ({e}); using the metadata cached for {}", cached.version);
+                Ok(cached.to_release())
+            }
+            None => Err(format!(
+                "GitHub release lookup failed ({e}) and no release metadata is cached at {}",
+                path.display()
+            )),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn release(version: &str) -> GithubRelease {
+        GithubRelease {
+            version: version.to_string(),
+            assets: vec![GithubReleaseAsset {
+                name: "mermaid-lsp-x86_64-unknown-linux-gnu.zip".to_string(),
+                download_url: format!("https://example.com/{version}/mermaid-lsp.zip"),
+            }],
+        }
+    }
+
+    fn version(result: Result<GithubRelease, String>) -> String {
+        result.unwrap().version
+    }
+
+    #[test]
+    fn reuses_fresh_metadata_without_asking_github() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_root = dir.path().join("cache");
+        assert_eq!(version(latest_release(&cache_root, 1000, || Ok(release("v0.2.0")))), "v0.2.0");
+
+        let within_ttl = 1000 + RELEASE_CACHE_TTL_SECS - 1;
+        let cached = latest_release(&cache_root, within_ttl, || panic!("asked GitHub within the TTL")).unwrap();
+        assert_eq!(cached.assets[0].download_url, "https://example.com/v0.2.0/mermaid-lsp.zip");
+
+        let expired = 1000 + RELEASE_CACHE_TTL_SECS;
+        assert_eq!(version(latest_release(&cache_root, expired, || Ok(release("v0.3.0")))), "v0.3.0");
+        assert_eq!(version(latest_release(&cache_root, expired + 1, || panic!("cached"))), "v0.3.0");
+    }
+
+    #[test]
+    fn falls_back_to_stale_metadata_when_github_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let rate_limited = || Err("403 rate limit exceeded".to_string());
+
+        let err = latest_release(dir.path(), 1000, rate_limited).unwrap_err();
+        assert!(err.contains("403 rate limit exceeded"), "{err}");
+        assert!(err.contains(&dir.path().join(RELEASE_CACHE_FILE).display().to_string()), "{err}");
+
+        latest_release(dir.path(), 1000, || Ok(release("v0.2.0"))).unwrap();
+        let much_later = 1000 + 30 * RELEASE_CACHE_TTL_SECS;
+        assert_eq!(version(latest_release(dir.path(), much_later, rate_limited)), "v0.2.0");
+
+        // Clearing the cache directory forgets it
+        fs::remove_dir_all(dir.path()).unwrap();
+        assert!(latest_release(dir.path(), much_later, rate_limited).is_err());
+    }
+}